@@ -0,0 +1,54 @@
+use rustic_insights::metrics::query::Expr;
+use rustic_insights::sinks::samples;
+use rustic_insights::{
+    AppConfig, GaugeOperation, Metric, MetricType, MetricValue, MetricsBatch, MetricsCollector,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Example application running the ingestion engine in-process, without the
+/// HTTP server, using only what the crate offers without the `server` feature.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut config = AppConfig::default();
+    config.metrics.metrics_prefix = "embedded".to_string();
+    let collector = MetricsCollector::from_config(&config)?;
+
+    let metrics = ["eu", "us", "ap"]
+        .into_iter()
+        .map(|region| Metric {
+            name: "orders".to_string(),
+            metric_type: MetricType::Counter,
+            help: "Orders placed".to_string(),
+            labels: HashMap::from([("region".to_string(), region.to_string())]),
+            value: MetricValue {
+                value: 1.0,
+                timestamp: None,
+                enum_value: None,
+                histogram: None,
+            }
+            .into(),
+            operation: GaugeOperation::Set,
+        })
+        .collect();
+    let batch = MetricsBatch {
+        metrics,
+        source: "checkout".to_string(),
+    };
+
+    // The same validation, normalization and aggregation as a push over HTTP
+    let response = collector.ingest(batch).await?;
+    println!("Processed {} metrics", response.processed);
+
+    let expr = Expr::parse("sum(embedded_metrics_server_orders)")?;
+    let result = expr.evaluate(&samples(&collector.gather_families()));
+    println!("Orders: {:?}", result);
+
+    println!("{}", collector.get_metrics()?);
+
+    // Without the server nothing compacts in the background
+    let stats = collector.compact().await;
+    println!("Compaction: {:?}", stats);
+
+    Ok(())
+}
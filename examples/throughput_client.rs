@@ -0,0 +1,182 @@
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use futures::future::join_all;
+use reqwest::Client;
+use serde_json::{Value, json};
+use std::error::Error;
+use std::io::Write;
+use std::time::Instant;
+
+/// How each request body is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    /// A `MetricsBatch` posted to `/api/metrics`.
+    Json,
+    /// The same batch with `Content-Encoding: gzip`.
+    Gzip,
+    /// One metric per line, gzipped and streamed to `/api/ingest/ndjson`.
+    Ndjson,
+}
+
+struct Options {
+    server: String,
+    mode: Mode,
+    concurrency: usize,
+    requests: usize,
+    batch_size: usize,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, Box<dyn Error>> {
+        let mut options = Options {
+            server: "http://localhost:8080".to_string(),
+            mode: Mode::Ndjson,
+            concurrency: 8,
+            requests: 200,
+            batch_size: 500,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--server" => options.server = value,
+                "--mode" => {
+                    options.mode = match value.as_str() {
+                        "json" => Mode::Json,
+                        "gzip" => Mode::Gzip,
+                        "ndjson" => Mode::Ndjson,
+                        other => return Err(format!("unknown mode '{}'", other).into()),
+                    }
+                }
+                "--concurrency" => options.concurrency = value.parse()?,
+                "--requests" => options.requests = value.parse()?,
+                "--batch-size" => options.batch_size = value.parse()?,
+                other => return Err(format!("unknown flag '{}'", other).into()),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// High-throughput client pushing synthetic metrics to the server as JSON,
+/// gzipped JSON or a gzipped NDJSON stream, and reporting the achieved rate.
+///
+/// cargo run --example throughput_client -- --mode ndjson --concurrency 16 --requests 1000
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let options = Options::from_args()?;
+    let client = Client::new();
+
+    println!(
+        "Pushing {} requests of {} metrics in {:?} mode with {} concurrent workers",
+        options.requests, options.batch_size, options.mode, options.concurrency
+    );
+
+    let started = Instant::now();
+    let workers = (0..options.concurrency).map(|worker| {
+        let client = client.clone();
+        let options = &options;
+        async move {
+            let mut accepted = 0;
+            let mut failed = 0;
+            for request in (worker..options.requests).step_by(options.concurrency) {
+                match push(&client, options, worker, request).await {
+                    Ok(processed) => accepted += processed,
+                    Err(e) => {
+                        failed += 1;
+                        eprintln!("Request {} failed: {}", request, e);
+                    }
+                }
+            }
+            (accepted, failed)
+        }
+    });
+    let results = join_all(workers).await;
+    let elapsed = started.elapsed();
+
+    let accepted: u64 = results.iter().map(|(accepted, _)| accepted).sum();
+    let failed: usize = results.iter().map(|(_, failed)| failed).sum();
+    println!(
+        "Accepted {} metrics in {:.2?} ({:.0} metrics/sec), {} requests failed",
+        accepted,
+        elapsed,
+        accepted as f64 / elapsed.as_secs_f64(),
+        failed
+    );
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Sends one request and returns how many metrics the server processed.
+async fn push(
+    client: &Client,
+    options: &Options,
+    worker: usize,
+    request: usize,
+) -> Result<u64, Box<dyn Error>> {
+    let source = format!("throughput_client_{}", worker);
+    let metrics: Vec<Value> = (0..options.batch_size)
+        .map(|i| synthetic_metric(request, i))
+        .collect();
+
+    let builder = match options.mode {
+        Mode::Json => client
+            .post(format!("{}/api/metrics", options.server))
+            .json(&json!({ "metrics": metrics, "source": source })),
+        Mode::Gzip => {
+            let body = serde_json::to_vec(&json!({ "metrics": metrics, "source": source }))?;
+            client
+                .post(format!("{}/api/metrics", options.server))
+                .header("Content-Type", "application/json")
+                .header("Content-Encoding", "gzip")
+                .body(gzip(&body)?)
+        }
+        Mode::Ndjson => {
+            let mut body = Vec::new();
+            for metric in &metrics {
+                serde_json::to_writer(&mut body, metric)?;
+                body.push(b'\n');
+            }
+            client
+                .post(format!("{}/api/ingest/ndjson", options.server))
+                .query(&[("source", &source)])
+                .header("Content-Type", "application/x-ndjson")
+                .header("Content-Encoding", "gzip")
+                .body(gzip(&body)?)
+        }
+    };
+
+    let response = builder.send().await?;
+    let status = response.status();
+    let body: Value = response.json().await?;
+    if !status.is_success() {
+        return Err(format!("HTTP error {}: {}", status, body).into());
+    }
+
+    Ok(body["processed"].as_u64().unwrap_or_default())
+}
+
+/// A gauge per series slot, so batches never repeat a series and the
+/// registry cardinality stays bounded by `--batch-size`.
+fn synthetic_metric(request: usize, index: usize) -> Value {
+    json!({
+        "name": "throughput_client_value",
+        "metric_type": "gauge",
+        "help": "Synthetic value pushed by the throughput client",
+        "labels": { "slot": index.to_string() },
+        "value": { "value": (request * index) as f64, "timestamp": null }
+    })
+}
+
+fn gzip(body: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
@@ -0,0 +1,32 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustic_insights::ingest::PrometheusTextAdapter;
+use rustic_insights::ingest::prometheus::{ParseMode, TextFamily, parse_text};
+
+fuzz_target!(|data: &[u8]| {
+    let lenient = parse_text(data, ParseMode::Lenient).expect("lenient parsing never fails");
+    let lines = data.split(|b| *b == b'\n').count();
+    assert!(lenient.errors.iter().all(|e| e.line >= 1 && e.line <= lines));
+
+    // Strict parsing fails at the first line lenient parsing skipped
+    match parse_text(data, ParseMode::Strict) {
+        Ok(strict) => {
+            assert!(lenient.errors.is_empty());
+            // NaN samples aren't equal to themselves, compare the shapes
+            let shape = |families: &[_]| -> Vec<(String, usize)> {
+                families
+                    .iter()
+                    .map(|f: &TextFamily| (f.name.clone(), f.samples.len()))
+                    .collect()
+            };
+            assert_eq!(shape(&strict.families), shape(&lenient.families));
+        }
+        Err(e) => assert_eq!(Some(&e), lenient.errors.first()),
+    }
+
+    let adapter = PrometheusTextAdapter::new();
+    let (batch, _, pending) = adapter.to_batch("fuzz", lenient.families.clone(), None);
+    adapter.commit("fuzz", pending, &batch.metrics);
+    let _ = adapter.to_batch("fuzz", lenient.families, None);
+});
@@ -1,5 +1,32 @@
+#[cfg(feature = "server")]
+pub mod auth;
+#[cfg(feature = "server")]
+pub mod basic_auth;
+#[cfg(feature = "server")]
+pub mod format;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "server")]
 pub mod handlers;
+#[cfg(feature = "server")]
+pub mod json;
+#[cfg(feature = "server")]
+pub mod lockout;
 pub mod models;
+#[cfg(feature = "pprof")]
+pub mod pprof;
+#[cfg(feature = "server")]
+pub mod request_id;
+#[cfg(feature = "server")]
 pub mod routes;
+#[cfg(feature = "server")]
+pub mod scrape;
+#[cfg(feature = "server")]
+pub mod shedding;
+#[cfg(feature = "server")]
+pub mod signing;
+#[cfg(feature = "server")]
+pub mod timeout;
 
+#[cfg(feature = "server")]
 pub use routes::configure_routes;
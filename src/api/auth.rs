@@ -0,0 +1,363 @@
+//! Role-based access control: requests are authenticated by API key, JWT or
+//! TLS client certificate, and their roles must grant the permission of the
+//! route they're for.
+
+use crate::api::handlers::AppState;
+use crate::api::shedding::{RouteClass, is_exposition, routed_path};
+use crate::config::{AuthConfig, JwtAlgorithm, JwtConfig, Role};
+use crate::errors::ServerError;
+use crate::metrics::registrations::constant_time_eq;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, header};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, web};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use tracing::{debug, error};
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Pushing metrics
+    Push,
+    /// Scraping and reading the API
+    Read,
+    /// `/api/admin` and changes to schemas and metadata
+    Admin,
+}
+
+impl Permission {
+    /// The permission a request needs, `None` for health checks, which
+    /// orchestrators probe without credentials.
+    pub fn required(method: &Method, path: &str) -> Option<Self> {
+        let api_path = path.strip_prefix("/api").unwrap_or(path);
+        let api_path = ["/v1", "/v2"]
+            .iter()
+            .find_map(|version| api_path.strip_prefix(version))
+            .unwrap_or(api_path);
+        if api_path == "/health" || api_path.starts_with("/health/") {
+            return None;
+        }
+        // Queries are posted, but only read
+        if api_path == "/graphql" || api_path == "/grafana/annotations" {
+            return Some(Permission::Read);
+        }
+        // Deploy markers come from the same CI jobs as pushes
+        if api_path == "/annotations" && method == Method::POST {
+            return Some(Permission::Push);
+        }
+
+        match RouteClass::of(method, path) {
+            Some(RouteClass::Ingest) => Some(Permission::Push),
+            Some(RouteClass::Scrape) => Some(Permission::Read),
+            Some(RouteClass::Admin) => Some(Permission::Admin),
+            None if method == Method::GET || method == Method::HEAD => Some(Permission::Read),
+            None => Some(Permission::Admin),
+        }
+    }
+
+    /// The permission needed by the route a request reaches.
+    pub fn of_request(req: &ServiceRequest) -> Option<Self> {
+        Self::required(req.method(), routed_path(req))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Push => "push",
+            Permission::Read => "read",
+            Permission::Admin => "admin",
+        }
+    }
+
+    fn granted_by(self, role: Role) -> bool {
+        match role {
+            Role::Admin => true,
+            Role::Pusher => self == Permission::Push,
+            Role::Reader => self == Permission::Read,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Who a request was authenticated as, added to its extensions.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub roles: Vec<Role>,
+}
+
+impl Identity {
+    pub fn can(&self, permission: Permission) -> bool {
+        self.roles.iter().any(|role| permission.granted_by(*role))
+    }
+}
+
+/// The DER certificate a client presented during the TLS handshake, added to
+/// the connection data.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub Vec<u8>);
+
+struct JwtVerifier {
+    /// `None` when the public key failed to load, refusing every token
+    key: Option<DecodingKey>,
+    validation: Validation,
+    roles_claim: String,
+}
+
+impl JwtVerifier {
+    fn new(config: &JwtConfig) -> Self {
+        let algorithm = match config.algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::HS384 => Algorithm::HS384,
+            JwtAlgorithm::HS512 => Algorithm::HS512,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+            JwtAlgorithm::RS384 => Algorithm::RS384,
+            JwtAlgorithm::RS512 => Algorithm::RS512,
+            JwtAlgorithm::ES256 => Algorithm::ES256,
+            JwtAlgorithm::ES384 => Algorithm::ES384,
+        };
+        let key = if config.algorithm.is_symmetric() {
+            Some(DecodingKey::from_secret(
+                config.secret.as_deref().unwrap_or_default().as_bytes(),
+            ))
+        } else {
+            let path = config.public_key_path.as_deref().unwrap_or_default();
+            let key = std::fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|pem| {
+                    match algorithm {
+                        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                        _ => DecodingKey::from_rsa_pem(&pem),
+                    }
+                    .map_err(|e| e.to_string())
+                });
+            key.inspect_err(|e| {
+                error!(
+                    "Failed to load the JWT public key {}, refusing every token: {}",
+                    path, e
+                )
+            })
+            .ok()
+        };
+
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Self {
+            key,
+            validation,
+            roles_claim: config.roles_claim.clone(),
+        }
+    }
+
+    fn verify(&self, token: &str) -> Result<Identity, ServerError> {
+        let Some(key) = &self.key else {
+            return Err(ServerError::Unauthorized(
+                "invalid token: no key to verify it".to_string(),
+            ));
+        };
+        let claims = jsonwebtoken::decode::<Value>(token, key, &self.validation)
+            .map_err(|e| ServerError::Unauthorized(format!("invalid token: {}", e)))?
+            .claims;
+        let roles = match &claims[&self.roles_claim] {
+            Value::Array(roles) => roles.iter().filter_map(Value::as_str).collect(),
+            Value::String(roles) => roles.split_whitespace().collect(),
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            name: claims["sub"].as_str().unwrap_or("jwt").to_string(),
+            roles: roles.into_iter().filter_map(parse_role).collect(),
+        })
+    }
+}
+
+fn parse_role(role: &str) -> Option<Role> {
+    serde_json::from_value(Value::String(role.to_string())).ok()
+}
+
+/// The identities of `auth`.
+pub struct Authenticator {
+    api_keys: Vec<(String, Identity)>,
+    jwt: Option<JwtVerifier>,
+    client_certificates: BTreeMap<String, Vec<Role>>,
+    anonymous_roles: Vec<Role>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            api_keys: config
+                .api_keys
+                .iter()
+                .map(|api_key| {
+                    let identity = Identity {
+                        name: api_key.name.clone(),
+                        roles: api_key.roles.clone(),
+                    };
+                    (api_key.key.clone(), identity)
+                })
+                .collect(),
+            jwt: config.jwt.as_ref().map(JwtVerifier::new),
+            client_certificates: config.client_certificates.clone(),
+            anonymous_roles: config.anonymous_roles.clone(),
+        }
+    }
+
+    /// Who the request comes from, `None` when it presents no credentials.
+    pub fn identify(&self, req: &HttpRequest) -> Result<Option<Identity>, ServerError> {
+        if let Some(key) = req.headers().get(API_KEY_HEADER) {
+            let key = key.as_bytes();
+            // Every key is compared, so response times don't tell which matched
+            let identity = self
+                .api_keys
+                .iter()
+                .fold(None, |found, (candidate, identity)| {
+                    if constant_time_eq(candidate.as_bytes(), key) {
+                        Some(identity)
+                    } else {
+                        found
+                    }
+                });
+            return identity
+                .map(|identity| Some(identity.clone()))
+                .ok_or_else(|| ServerError::Unauthorized("unknown API key".to_string()));
+        }
+
+        // Registered sources present their own, opaque bearer tokens
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .filter(|token| token.split('.').count() == 3);
+        if let (Some(jwt), Some(token)) = (&self.jwt, bearer) {
+            return jwt.verify(token.trim()).map(Some);
+        }
+
+        if let Some(certificate) = req.conn_data::<ClientCertificate>() {
+            return Ok(self.certificate_identity(&certificate.0));
+        }
+        Ok(None)
+    }
+
+    /// The identity of a verified client certificate, by its common name.
+    pub fn certificate_identity(&self, der: &[u8]) -> Option<Identity> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+        let name = certificate
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()?;
+        let roles = self.client_certificates.get(name)?;
+        Some(Identity {
+            name: name.to_string(),
+            roles: roles.clone(),
+        })
+    }
+
+    /// Checks the request may be served, returning who it comes from.
+    fn authorize(
+        &self,
+        req: &HttpRequest,
+        permission: Permission,
+        delegate_basic_auth: bool,
+    ) -> Result<Option<Identity>, ServerError> {
+        match self.identify(req)? {
+            Some(identity) if identity.can(permission) => Ok(Some(identity)),
+            Some(identity) => Err(ServerError::Forbidden(format!(
+                "'{}' lacks the '{}' permission",
+                identity.name, permission
+            ))),
+            None if self
+                .anonymous_roles
+                .iter()
+                .any(|role| permission.granted_by(*role)) =>
+            {
+                Ok(None)
+            }
+            // Scrapers authenticating with basic auth are checked by the
+            // exposition handlers against `server.scrape.basic_auth_users`
+            None if delegate_basic_auth && is_basic_auth(req) => Ok(None),
+            None => Err(ServerError::Unauthorized(format!(
+                "credentials with the '{}' permission required",
+                permission
+            ))),
+        }
+    }
+}
+
+/// Checks that a request was authenticated as an identity with the `admin`
+/// permission, for the routes no anonymous request may reach whatever
+/// `anonymous_roles` grants: without `auth` they aren't served.
+pub fn require_admin_identity(req: &HttpRequest) -> Result<(), ServerError> {
+    match req.extensions().get::<Identity>() {
+        Some(identity) if identity.can(Permission::Admin) => Ok(()),
+        Some(identity) => Err(ServerError::Forbidden(format!(
+            "'{}' lacks the 'admin' permission",
+            identity.name
+        ))),
+        None => Err(ServerError::Forbidden(
+            "only served to credentials with the 'admin' permission, which needs `auth`"
+                .to_string(),
+        )),
+    }
+}
+
+fn is_basic_auth(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.len() > 6 && value[..6].eq_ignore_ascii_case("basic "))
+}
+
+/// Middleware enforcing `auth`, answering with a 401 without valid
+/// credentials and a 403 naming the permission the identity lacks.
+/// Wrap it inside `propagate_request_id` so refusals carry the request ID.
+pub async fn authorize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<Arc<AppState>>>().cloned() else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(authenticator) = &state.authenticator else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(permission) = Permission::of_request(&req) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let delegate_basic_auth = permission == Permission::Read
+        && is_exposition(routed_path(&req))
+        && !state.config.server.scrape.basic_auth_users.is_empty();
+    match authenticator.authorize(req.request(), permission, delegate_basic_auth) {
+        Ok(Some(identity)) => {
+            debug!(
+                "{} {} authorized for {}",
+                req.method(),
+                req.path(),
+                identity.name
+            );
+            req.extensions_mut().insert(identity);
+        }
+        Ok(None) => {}
+        Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+    }
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
@@ -0,0 +1,96 @@
+//! HTTP basic authentication of scrapers against bcrypt hashes, the scheme
+//! of Prometheus' `web.config.yml`.
+
+use crate::errors::ServerError;
+use crate::metrics::registrations::constant_time_eq;
+use actix_web::http::header;
+use actix_web::{HttpRequest, web};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Verified against for unknown users, so response times don't reveal which
+/// users exist. No password matches it.
+const UNKNOWN_USER_HASH: &str = "$2y$10$.....................................................";
+
+pub struct BasicAuth {
+    /// bcrypt hashes by user
+    users: BTreeMap<String, String>,
+    /// A digest of the password last verified per user, sparing a bcrypt
+    /// verification, tens of milliseconds by design, on every scrape
+    verified: Mutex<HashMap<String, hmac::Tag>>,
+    /// Keys the digests, so the passwords can't be recovered from memory.
+    /// `None` without randomness, leaving every scrape to bcrypt
+    digest_key: Option<hmac::Key>,
+}
+
+impl BasicAuth {
+    /// `None` when there are no users, leaving scrapes unauthenticated.
+    pub fn new(users: &BTreeMap<String, String>) -> Option<Self> {
+        (!users.is_empty()).then(|| Self {
+            users: users.clone(),
+            verified: Mutex::new(HashMap::new()),
+            digest_key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).ok(),
+        })
+    }
+
+    /// Checks the request's credentials, asking for some when they're
+    /// missing or wrong.
+    pub async fn authenticate(&self, req: &HttpRequest) -> Result<(), ServerError> {
+        let (user, password) = credentials(req)
+            .ok_or_else(|| ServerError::CredentialsRequired("credentials required".to_string()))?;
+
+        let digest = self
+            .digest_key
+            .as_ref()
+            .map(|key| hmac::sign(key, password.as_bytes()));
+        let cached = self
+            .verified
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&user)
+            .copied();
+        if let (Some(cached), Some(digest)) = (cached, digest)
+            && constant_time_eq(cached.as_ref(), digest.as_ref())
+        {
+            return Ok(());
+        }
+
+        let known = self.users.contains_key(&user);
+        let hash = self
+            .users
+            .get(&user)
+            .cloned()
+            .unwrap_or_else(|| UNKNOWN_USER_HASH.to_string());
+        let matches = web::block(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?
+            .unwrap_or(false);
+        let mut verified = self.verified.lock().unwrap_or_else(|e| e.into_inner());
+        if !(known && matches) {
+            verified.remove(&user);
+            return Err(ServerError::CredentialsRequired(
+                "invalid credentials".to_string(),
+            ));
+        }
+        if let Some(digest) = digest {
+            verified.insert(user, digest);
+        }
+        Ok(())
+    }
+}
+
+/// The user and password of a `Basic` authorization header.
+fn credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
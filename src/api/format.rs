@@ -0,0 +1,72 @@
+//! The body formats of the ingestion endpoints, checked against the
+//! request's `Content-Type` so a payload sent as the wrong format is refused
+//! with a 415 listing the types the endpoint takes, rather than failing to
+//! parse with a misleading error.
+
+use crate::errors::ServerError;
+use actix_web::error::{self, JsonPayloadError};
+use actix_web::http::header;
+use actix_web::{HttpMessage, HttpRequest};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFormat {
+    /// A JSON document, `application/json` or any other `json` or `+json` type.
+    Json,
+    /// Newline-delimited JSON objects.
+    Ndjson,
+    /// The Prometheus text exposition, or OpenMetrics.
+    PrometheusText,
+}
+
+impl RequestFormat {
+    /// The media types accepted for the format, the preferred one first.
+    pub fn media_types(&self) -> &'static [&'static str] {
+        match self {
+            RequestFormat::Json => &["application/json"],
+            RequestFormat::Ndjson => &["application/x-ndjson", "application/jsonl"],
+            RequestFormat::PrometheusText => &["text/plain", "application/openmetrics-text"],
+        }
+    }
+
+    /// Whether `mime` is one of the format's types; parameters such as
+    /// `charset` or the exposition `version` are ignored.
+    pub fn matches(&self, mime: &mime::Mime) -> bool {
+        if *self == RequestFormat::Json
+            && (mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON))
+        {
+            return true;
+        }
+        self.media_types()
+            .iter()
+            .any(|media_type| mime.essence_str().eq_ignore_ascii_case(media_type))
+    }
+
+    /// The error for a request whose content type isn't the format's.
+    pub fn unsupported(&self, req: &HttpRequest) -> ServerError {
+        let received = match req.headers().get(header::CONTENT_TYPE) {
+            Some(value) => format!("'{}'", String::from_utf8_lossy(value.as_bytes())),
+            None => "(no Content-Type)".to_string(),
+        };
+        ServerError::UnsupportedMediaType {
+            received,
+            supported: self.media_types().to_vec(),
+        }
+    }
+}
+
+/// Checks that the request's `Content-Type` is one of `format`'s types.
+pub fn require_format(req: &HttpRequest, format: RequestFormat) -> Result<(), ServerError> {
+    match req.mime_type() {
+        Ok(Some(mime)) if format.matches(&mime) => Ok(()),
+        _ => Err(format.unsupported(req)),
+    }
+}
+
+/// `JsonConfig` error handler answering a wrong content type with a 415,
+/// other JSON errors as actix-web does.
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> error::Error {
+    match err {
+        JsonPayloadError::ContentType => RequestFormat::Json.unsupported(req).into(),
+        other => other.into(),
+    }
+}
@@ -0,0 +1,428 @@
+//! A read-only GraphQL view of the registry at `/api/graphql`, for internal
+//! tooling that would rather write one nested query than combine the REST
+//! endpoints: families with their series, sources with what they pushed,
+//! metadata and the restart history.
+
+use crate::api::handlers::AppState;
+use crate::errors::ServerError;
+use crate::metrics::catalog::MetricMetadata;
+use crate::metrics::listing::{self, DEFAULT_PAGE_SIZE, SeriesEntry};
+use crate::metrics::registrations::SourceStatus;
+use crate::metrics::selector::{SeriesSelector, filter_families};
+use crate::uptime::{PastRun, StopReason};
+use actix_web::{HttpResponse, web};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, Object, Result, Schema, SimpleObject,
+};
+use chrono::{DateTime, Utc};
+use prometheus::proto::MetricFamily;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+
+/// Deep enough for sources → families → series → labels, not for abuse.
+const MAX_DEPTH: usize = 8;
+/// Fields a query may select, counting each alias, so one query can't ask
+/// for the same listing hundreds of times.
+const MAX_COMPLEXITY: usize = 200;
+
+pub type MetricsSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// The schema; the `AppState` is attached to every request.
+pub fn schema() -> MetricsSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// The exposed families, gathered by the first field of a request that
+/// needs them and shared by the others.
+#[derive(Default)]
+struct Exposed(OnceCell<Vec<MetricFamily>>);
+
+/// Executes a GraphQL request posted as JSON. Errors are reported in the
+/// response's `errors`, as GraphQL clients expect, with a 200.
+#[instrument(skip(state, request))]
+pub async fn graphql(
+    state: web::Data<Arc<AppState>>,
+    web::Json(request): web::Json<async_graphql::Request>,
+) -> HttpResponse {
+    let state = state.get_ref().clone();
+    let request = request.data(state.clone()).data(Exposed::default());
+    let response = state.graphql.execute(request).await;
+    HttpResponse::Ok().json(response)
+}
+
+fn state<'a>(ctx: &Context<'a>) -> &'a AppState {
+    ctx.data_unchecked::<Arc<AppState>>()
+}
+
+fn selectors(matchers: &[String]) -> Result<Vec<SeriesSelector>, ServerError> {
+    matchers.iter().map(|m| SeriesSelector::parse(m)).collect()
+}
+
+async fn families(
+    ctx: &Context<'_>,
+    prefix: Option<&str>,
+    matchers: &[String],
+) -> Result<Vec<Family>> {
+    let selectors = selectors(matchers)?;
+    let exposed = ctx
+        .data_unchecked::<Exposed>()
+        .0
+        .get_or_try_init(|| async {
+            let snapshot = state(ctx).metrics_collector.gather_exposed().await?;
+            Ok::<_, ServerError>(snapshot.families)
+        })
+        .await?;
+    let matching = exposed
+        .iter()
+        .filter(|family| prefix.is_none_or(|prefix| family.get_name().starts_with(prefix)))
+        .cloned()
+        .collect();
+    Ok(filter_families(matching, &[], &selectors)
+        .into_iter()
+        .map(Family)
+        .collect())
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// The exposed families, by name prefix and series selectors.
+    async fn families(
+        &self,
+        ctx: &Context<'_>,
+        prefix: Option<String>,
+        #[graphql(name = "match", default)] matchers: Vec<String>,
+    ) -> Result<Vec<Family>> {
+        families(ctx, prefix.as_deref(), &matchers).await
+    }
+
+    /// One page of the exposed series, as `GET /api/metrics`.
+    async fn series(
+        &self,
+        ctx: &Context<'_>,
+        prefix: Option<String>,
+        #[graphql(name = "match", default)] matchers: Vec<String>,
+        #[graphql(default_with = "DEFAULT_PAGE_SIZE")] limit: usize,
+        cursor: Option<String>,
+    ) -> Result<SeriesPage> {
+        let families: Vec<MetricFamily> = families(ctx, None, &matchers)
+            .await?
+            .into_iter()
+            .map(|family| family.0)
+            .collect();
+        let page = listing::list_series(&families, prefix.as_deref(), cursor.as_deref(), limit)?;
+        Ok(SeriesPage {
+            total: page.total,
+            next_cursor: page.next_cursor,
+            series: page.series.into_iter().map(Series).collect(),
+        })
+    }
+
+    /// Registered sources and those that pushed series, registered or not.
+    async fn sources(&self, ctx: &Context<'_>) -> Result<Vec<Source>> {
+        let collector = &state(ctx).metrics_collector;
+        let registrations = collector.registrations().list();
+        let pushed = collector.cardinality(0).await?.sources;
+
+        let names: BTreeSet<&String> = registrations
+            .iter()
+            .map(|status| &status.source)
+            .chain(pushed.keys())
+            .collect();
+        Ok(names
+            .into_iter()
+            .map(|name| Source {
+                name: name.clone(),
+                series_count: pushed.get(name).copied().unwrap_or_default(),
+                registration: registrations
+                    .iter()
+                    .find(|status| status.source == *name)
+                    .cloned(),
+            })
+            .collect())
+    }
+
+    /// Metadata of the metrics seen since startup, or of the one named,
+    /// keyed by metric name without the configured prefix.
+    async fn metadata(&self, ctx: &Context<'_>, name: Option<String>) -> Vec<Metadata> {
+        let catalog = state(ctx).metrics_collector.catalog();
+        match name {
+            Some(name) => catalog
+                .get(&name)
+                .map(|metadata| Metadata { name, metadata })
+                .into_iter()
+                .collect(),
+            None => catalog
+                .list()
+                .into_iter()
+                .map(|(name, metadata)| Metadata { name, metadata })
+                .collect(),
+        }
+    }
+
+    /// The current run and the past ones, most recent first.
+    async fn history(&self, ctx: &Context<'_>) -> History {
+        let state = state(ctx);
+        let uptime_seconds = SystemTime::now()
+            .duration_since(state.start_time)
+            .map(|uptime| uptime.as_secs())
+            .unwrap_or_default();
+        History {
+            started_at: state.uptime.started_at(),
+            uptime_seconds,
+            persisted: state.uptime.is_persisted(),
+            restarts: state.uptime.runs().into_iter().rev().map(Restart).collect(),
+        }
+    }
+}
+
+pub struct Family(MetricFamily);
+
+#[Object]
+impl Family {
+    /// The name in the exposition, with the prefix and namespace.
+    async fn name(&self) -> &str {
+        self.0.get_name()
+    }
+
+    #[graphql(name = "type")]
+    async fn metric_type(&self) -> String {
+        format!("{:?}", self.0.get_field_type()).to_lowercase()
+    }
+
+    async fn help(&self) -> &str {
+        self.0.get_help()
+    }
+
+    async fn series_count(&self) -> usize {
+        self.0.get_metric().len()
+    }
+
+    /// The family's series matching any of the selectors, in label order.
+    async fn series(
+        &self,
+        #[graphql(name = "match", default)] matchers: Vec<String>,
+        #[graphql(default_with = "DEFAULT_PAGE_SIZE")] limit: usize,
+    ) -> Result<Vec<Series>> {
+        let families = filter_families(vec![self.0.clone()], &[], &selectors(&matchers)?);
+        let page = listing::list_series(&families, None, None, limit)?;
+        Ok(page.series.into_iter().map(Series).collect())
+    }
+}
+
+pub struct Series(SeriesEntry);
+
+#[Object]
+impl Series {
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    #[graphql(name = "type")]
+    async fn metric_type(&self) -> &str {
+        &self.0.metric_type
+    }
+
+    async fn labels(&self) -> Vec<Label> {
+        self.0
+            .labels
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect()
+    }
+
+    /// The value of one label, null when the series doesn't have it.
+    async fn label(&self, name: String) -> Option<&str> {
+        self.0.labels.get(&name).map(String::as_str)
+    }
+
+    /// The value of a counter or gauge.
+    async fn value(&self) -> Option<f64> {
+        self.0.value
+    }
+
+    /// The observations of a histogram or summary.
+    async fn sample_count(&self) -> Option<u64> {
+        self.0.sample_count
+    }
+
+    async fn sample_sum(&self) -> Option<f64> {
+        self.0.sample_sum
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Label {
+    name: String,
+    value: String,
+}
+
+#[derive(SimpleObject)]
+pub struct SeriesPage {
+    /// Series matching the filters, across all pages.
+    total: usize,
+    /// Passed as `cursor` to get the next page; null on the last one.
+    next_cursor: Option<String>,
+    series: Vec<Series>,
+}
+
+pub struct Source {
+    name: String,
+    series_count: usize,
+    registration: Option<SourceStatus>,
+}
+
+#[Object]
+impl Source {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Series currently held from the source.
+    async fn series_count(&self) -> usize {
+        self.series_count
+    }
+
+    async fn registered(&self) -> bool {
+        self.registration.is_some()
+    }
+
+    async fn push_interval_secs(&self) -> Option<u64> {
+        self.registration.as_ref()?.push_interval_secs
+    }
+
+    async fn last_push(&self) -> Option<String> {
+        Some(self.registration.as_ref()?.last_push?.to_rfc3339())
+    }
+
+    /// No push within the registered interval.
+    async fn overdue(&self) -> bool {
+        self.registration
+            .as_ref()
+            .is_some_and(|registration| registration.overdue)
+    }
+
+    /// The families the source pushed, with only its own series.
+    async fn families(&self, ctx: &Context<'_>) -> Result<Vec<Family>> {
+        Ok(state(ctx)
+            .metrics_collector
+            .gather_source_families(&self.name)
+            .await?
+            .unwrap_or_default()
+            .into_iter()
+            .map(Family)
+            .collect())
+    }
+}
+
+pub struct Metadata {
+    name: String,
+    metadata: MetricMetadata,
+}
+
+#[Object]
+impl Metadata {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn help(&self) -> Option<&str> {
+        self.metadata.help.as_deref()
+    }
+
+    #[graphql(name = "type")]
+    async fn metric_type(&self) -> Option<String> {
+        let metric_type = self.metadata.metric_type.as_ref()?;
+        Some(format!("{:?}", metric_type).to_lowercase())
+    }
+
+    async fn owner(&self) -> Option<&str> {
+        self.metadata.ownership.owner.as_deref()
+    }
+
+    async fn team(&self) -> Option<&str> {
+        self.metadata.ownership.team.as_deref()
+    }
+
+    async fn runbook_url(&self) -> Option<&str> {
+        self.metadata.ownership.runbook_url.as_deref()
+    }
+}
+
+pub struct History {
+    started_at: DateTime<Utc>,
+    uptime_seconds: u64,
+    persisted: bool,
+    restarts: Vec<Restart>,
+}
+
+#[Object]
+impl History {
+    async fn started_at(&self) -> String {
+        self.started_at.to_rfc3339()
+    }
+
+    async fn uptime_seconds(&self) -> u64 {
+        self.uptime_seconds
+    }
+
+    /// The uptime of this run and of the past ones.
+    async fn cumulative_uptime_seconds(&self) -> u64 {
+        self.uptime_seconds
+            + self
+                .restarts
+                .iter()
+                .map(|run| run.0.uptime_seconds())
+                .sum::<u64>()
+    }
+
+    /// Whether past runs are kept, with `uptime` configured.
+    async fn persisted(&self) -> bool {
+        self.persisted
+    }
+
+    async fn restarts(&self) -> &[Restart] {
+        &self.restarts
+    }
+}
+
+pub struct Restart(PastRun);
+
+#[Object]
+impl Restart {
+    async fn started_at(&self) -> String {
+        self.0.started_at.to_rfc3339()
+    }
+
+    async fn stopped_at(&self) -> String {
+        self.0.stopped_at.to_rfc3339()
+    }
+
+    async fn uptime_seconds(&self) -> u64 {
+        self.0.uptime_seconds()
+    }
+
+    async fn reason(&self) -> StopKind {
+        match self.0.reason {
+            StopReason::CleanShutdown => StopKind::CleanShutdown,
+            StopReason::Crash => StopKind::Crash,
+        }
+    }
+}
+
+/// How a past run ended.
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+pub enum StopKind {
+    CleanShutdown,
+    Crash,
+}
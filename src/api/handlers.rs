@@ -1,16 +1,187 @@
-use crate::api::models::{HealthResponse, StatusResponse, Validate};
+use crate::api::auth::{Authenticator, require_admin_identity};
+use crate::api::format::{RequestFormat, require_format};
+#[cfg(feature = "graphql")]
+use crate::api::graphql::{self, MetricsSchema};
+use crate::api::json::{BatchJson, CapturedBatch};
+use crate::api::lockout::AuthLockout;
+use crate::api::models::{
+    AnnotationQuery, BatchQueued, CardinalityQuery, CollectdQuery, DashboardQuery, DeliveryQuery,
+    EffectiveConfigResponse, ExportQuery, GrafanaAnnotation, GrafanaAnnotationRequest,
+    HealthResponse, IngestQuery, JobAccepted, ModeRequest, ModeResponse, ReadinessResponse,
+    RestartRecord, SeriesQuery, SilenceRequest, StatusHistoryResponse, StatusResponse, StreamQuery,
+    TargetGroup, TextQuery, TombstoneRequest,
+};
+use crate::api::request_id::current_request_id;
+use crate::api::scrape::{ScrapeGuard, ScrapeSlot};
+use crate::api::shedding::LoadShedder;
+use crate::api::signing::SignatureVerifier;
+use crate::build_info::BuildInfo;
+use crate::capture::CaptureRecorder;
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, Chaos, ChaosSettings};
+use crate::config::redact::redact;
+use crate::config::{AppConfig, MetricSchema, ReportConfig, run_mode};
 use crate::errors::ServerError;
-use crate::metrics::{MetricsBatch, MetricsCollector};
-use actix_web::{HttpResponse, web};
+use crate::health::HealthChecks;
+use crate::ingest::collectd::CollectdValueList;
+use crate::ingest::prometheus::{ParseMode, ingest_text};
+use crate::ingest::{CollectdAdapter, PrometheusTextAdapter, ndjson};
+use crate::jobs::JobTracker;
+use crate::metrics::annotations::{
+    AnnotationFilter, Annotations, DEFAULT_RETENTION_SECS, NewAnnotation,
+};
+use crate::metrics::catalog::Ownership;
+use crate::metrics::dashboard::grafana_dashboard;
+use crate::metrics::export::{ChunkWriter, ExportFormat, write_history};
+use crate::metrics::exposition::attach_labels;
+use crate::metrics::groups::ExpositionGroups;
+use crate::metrics::history::SampleHistory;
+use crate::metrics::listing;
+use crate::metrics::registrations::SourceRequest;
+use crate::metrics::selector::{SeriesSelector, filter_families};
+use crate::metrics::slo::SloTracker;
+use crate::metrics::target::{IpNetwork, TargetLabels};
+use crate::metrics::{Metric, MetricsBatch, MetricsBatchV2, MetricsCollector, MetricsResponse};
+use crate::mode::{ModeSwitch, OperatingMode};
+use crate::reports::{self, Report};
+use crate::sinks::fanout::BatchFanout;
+use crate::uptime::UptimeHistory;
+use actix_web::dev::Decompress;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, http::header, web};
 use chrono::{DateTime, Utc};
-use std::sync::Arc;
+use prometheus::proto::MetricFamily;
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
 use std::time::SystemTime;
-use tracing::{debug, error, field, instrument};
+use tracing::{debug, error, field, instrument, warn};
 
 pub struct AppState {
+    pub config: AppConfig,
     pub metrics_collector: MetricsCollector,
     pub start_time: SystemTime,
     pub version: String,
+    pub health: Arc<HealthChecks>,
+    pub collectd: CollectdAdapter,
+    pub prometheus_text: PrometheusTextAdapter,
+    pub jobs: JobTracker,
+    pub slos: SloTracker,
+    pub mode: ModeSwitch,
+    /// Records incoming batches as fixtures when `capture` is configured.
+    pub capture: Option<CaptureRecorder>,
+    /// Delivers accepted batches to the `fanout` destinations, once spawned.
+    pub fanout: Option<Arc<BatchFanout>>,
+    /// Per-route-class concurrency limits of `server.load_shedding`.
+    pub load_shedder: LoadShedder,
+    /// The `server.scrape` limits of the exposition endpoints.
+    pub scrape: ScrapeGuard,
+    /// The identities and roles of `auth`, checked on every request.
+    pub authenticator: Option<Authenticator>,
+    /// The secrets and recent nonces of `server.signing`.
+    pub signatures: Option<SignatureVerifier>,
+    /// The clients failing to authenticate, with `server.lockout`.
+    pub lockout: Option<AuthLockout>,
+    /// The current run and, when `uptime` is configured, the past ones.
+    pub uptime: Arc<UptimeHistory>,
+    /// Snapshots of the exposed samples when `history` is configured.
+    pub history: Option<SampleHistory>,
+    /// Events posted to `/api/annotations`, kept as long as the history.
+    pub annotations: Annotations,
+    /// The `exposition_groups` served at `/metrics/group/{name}`.
+    pub exposition_groups: ExpositionGroups,
+    /// Set once the HTTP server is listening; empty without a server.
+    pub listen_addresses: OnceLock<Vec<SocketAddr>>,
+    #[cfg(feature = "chaos")]
+    pub chaos: Chaos,
+    #[cfg(feature = "graphql")]
+    pub graphql: MetricsSchema,
+}
+
+impl AppState {
+    /// The state of a server over `metrics_collector`. Its uptime history
+    /// only knows the current run until one is given with `with_uptime`.
+    pub fn new(
+        config: AppConfig,
+        metrics_collector: MetricsCollector,
+        version: impl Into<String>,
+    ) -> Self {
+        let health = Arc::new(HealthChecks::new());
+        let start_time = SystemTime::now();
+        let exposition_groups =
+            ExpositionGroups::new(&config.exposition_groups).unwrap_or_else(|e| {
+                error!("Failed to compile the exposition groups: {}", e);
+                ExpositionGroups::default()
+            });
+        let fanout = (!config.fanout.is_empty()).then(|| {
+            Arc::new(BatchFanout::new(
+                &config.fanout,
+                &config.metrics,
+                health.clone(),
+                metrics_collector.self_metrics().clone(),
+                metrics_collector.keyring().clone(),
+            ))
+        });
+        let metrics_collector = match &fanout {
+            Some(fanout) => metrics_collector.with_fanout(fanout.clone()),
+            None => metrics_collector,
+        };
+        let retention = config
+            .history
+            .as_ref()
+            .map_or(DEFAULT_RETENTION_SECS, |history| history.retention_secs);
+        let annotations = match config
+            .history
+            .as_ref()
+            .and_then(|history| history.annotations_path.as_ref())
+        {
+            Some(path) => Annotations::open(retention, path).unwrap_or_else(|e| {
+                error!("Failed to load the annotations from {}: {}", path, e);
+                Annotations::new(retention)
+            }),
+            None => Annotations::new(retention),
+        };
+        let capture = config.capture.as_ref().map(|capture| {
+            CaptureRecorder::new(capture).with_keyring(metrics_collector.keyring().clone())
+        });
+
+        Self {
+            metrics_collector,
+            start_time,
+            version: version.into(),
+            health,
+            collectd: CollectdAdapter::new(),
+            prometheus_text: PrometheusTextAdapter::new(),
+            jobs: JobTracker::new(),
+            slos: SloTracker::new(&config.slos),
+            mode: ModeSwitch::new(),
+            capture,
+            fanout,
+            load_shedder: LoadShedder::new(&config.server.load_shedding),
+            scrape: ScrapeGuard::new(&config.server.scrape),
+            authenticator: config.auth.as_ref().map(Authenticator::new),
+            signatures: config.server.signing.as_ref().map(SignatureVerifier::new),
+            lockout: config.server.lockout.as_ref().map(AuthLockout::new),
+            uptime: Arc::new(UptimeHistory::new(start_time.into())),
+            history: config.history.as_ref().map(SampleHistory::new),
+            annotations,
+            exposition_groups,
+            listen_addresses: OnceLock::new(),
+            #[cfg(feature = "chaos")]
+            chaos: Chaos::new(),
+            #[cfg(feature = "graphql")]
+            graphql: graphql::schema(),
+            config,
+        }
+    }
+
+    /// Replaces the history knowing only the current run with `uptime`, e.g.
+    /// one opened from `config.uptime` with `UptimeHistory::open`.
+    pub fn with_uptime(mut self, uptime: UptimeHistory) -> Self {
+        self.uptime = Arc::new(uptime);
+        self
+    }
 }
 
 #[instrument(skip(state))]
@@ -25,13 +196,36 @@ pub async fn health_check(state: web::Data<Arc<AppState>>) -> Result<HttpRespons
     Ok(HttpResponse::Ok().json(response))
 }
 
+/// Liveness probe: the process is up and serving requests.
+#[instrument]
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness probe: every background component the server depends on is healthy.
+#[instrument(skip(state))]
+pub async fn readiness(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let ready = state.health.is_ready() && state.mode.get() != OperatingMode::Drain;
+    let response = ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" }.to_string(),
+        components: state.health.snapshot(),
+    };
+
+    if ready {
+        HttpResponse::Ok().json(response)
+    } else {
+        debug!("Readiness check failed");
+        HttpResponse::ServiceUnavailable().json(response)
+    }
+}
+
 #[instrument(skip(state))]
 pub async fn status(state: web::Data<Arc<AppState>>) -> Result<HttpResponse, ServerError> {
     let uptime = SystemTime::now()
         .duration_since(state.start_time)
         .map_err(|e| ServerError::InternalError(Box::new(e)))?;
 
-    let start_time: DateTime<Utc> = state.start_time.clone().into();
+    let start_time: DateTime<Utc> = state.start_time.into();
 
     let metrics_count = state.metrics_collector.get_metrics_count().await?;
 
@@ -40,46 +234,1180 @@ pub async fn status(state: web::Data<Arc<AppState>>) -> Result<HttpResponse, Ser
         metrics_count,
         uptime_seconds: uptime.as_secs(),
         start_time: start_time.to_rfc3339(),
+        version: state.version.clone(),
+        build: BuildInfo::current(),
+        config_profile: run_mode(),
+        listen_addresses: state
+            .listen_addresses
+            .get()
+            .map(|addresses| addresses.iter().map(ToString::to_string).collect())
+            .unwrap_or_default(),
     };
 
     debug!("Status check performed");
     Ok(HttpResponse::Ok().json(response))
 }
 
+/// Past runs with how they ended, and the uptime summed over them.
 #[instrument(skip(state))]
-pub async fn metrics(state: web::Data<Arc<AppState>>) -> Result<HttpResponse, ServerError> {
-    let metrics_data = state.metrics_collector.get_metrics()?;
+pub async fn status_history(state: web::Data<Arc<AppState>>) -> Result<HttpResponse, ServerError> {
+    let uptime = SystemTime::now()
+        .duration_since(state.start_time)
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?
+        .as_secs();
+
+    let restarts: Vec<RestartRecord> = state
+        .uptime
+        .runs()
+        .into_iter()
+        .rev()
+        .map(|run| RestartRecord {
+            started_at: run.started_at,
+            stopped_at: run.stopped_at,
+            uptime_seconds: run.uptime_seconds(),
+            reason: run.reason,
+        })
+        .collect();
+
+    let response = StatusHistoryResponse {
+        start_time: state.uptime.started_at().to_rfc3339(),
+        uptime_seconds: uptime,
+        cumulative_uptime_seconds: uptime
+            + restarts.iter().map(|run| run.uptime_seconds).sum::<u64>(),
+        persisted: state.uptime.is_persisted(),
+        restarts,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[instrument(skip(req, state))]
+pub async fn metrics(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, ServerError> {
+    state.scrape.authenticate(&req).await?;
+    let slot = state.scrape.acquire()?;
 
     debug!("Metrics endpoint called");
+    let snapshot = state.metrics_collector.gather_exposed().await?;
+    render_exposition(&req, &state, slot, snapshot.families)
+}
+
+/// The exposition restricted to the series pushed by one source.
+#[instrument(skip(req, state))]
+pub async fn source_metrics(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let collector = &state.metrics_collector;
+    let source = path.into_inner();
+    state.scrape.authenticate(&req).await?;
+    let slot = state.scrape.acquire()?;
+
+    let families = collector
+        .gather_source_families(&source)
+        .await?
+        .ok_or_else(|| ServerError::NotFound(format!("source '{}'", source)))?;
+    render_exposition(&req, &state, slot, families)
+}
+
+/// The exposition restricted to the families of one of the
+/// `exposition_groups`.
+#[instrument(skip(req, state))]
+pub async fn group_metrics(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let name = path.into_inner();
+    state.scrape.authenticate(&req).await?;
+    let slot = state.scrape.acquire()?;
+
+    let snapshot = state.metrics_collector.gather_exposed().await?;
+    let families = state
+        .exposition_groups
+        .filter(&name, snapshot.families)
+        .ok_or_else(|| ServerError::NotFound(format!("exposition group '{}'", name)))?;
+    render_exposition(&req, &state, slot, families)
+}
+
+/// A Grafana dashboard with a panel per family pushed by the source.
+#[instrument(skip(state))]
+pub async fn source_dashboard(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+    query: web::Query<DashboardQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let source = path.into_inner();
+    let families = state
+        .metrics_collector
+        .gather_source_families(&source)
+        .await?
+        .ok_or_else(|| ServerError::NotFound(format!("source '{}'", source)))?;
+    Ok(HttpResponse::Ok().json(grafana_dashboard(&source, &families, query.job.as_deref())))
+}
+
+/// Records an event such as a deploy, to be laid over the metrics.
+#[instrument(skip(state, req, annotation))]
+pub async fn create_annotation(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    annotation: web::Json<NewAnnotation>,
+) -> Result<HttpResponse, ServerError> {
+    let annotation = annotation.into_inner();
+    if let Some(source) = &annotation.source {
+        authorize_source(&state, &req, source)?;
+    }
+    let annotation = state.annotations.add(annotation).await?;
+    debug!("Recorded annotation {}", annotation.title);
+    Ok(HttpResponse::Created().json(annotation))
+}
+
+#[instrument(skip(state))]
+pub async fn list_annotations(
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<AnnotationQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let filter = AnnotationFilter {
+        from: query.start,
+        to: query.end,
+        tags: query
+            .tags
+            .iter()
+            .flat_map(|tags| tags.split(','))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+        source: query.source,
+    };
+    HttpResponse::Ok().json(state.annotations.query(&filter))
+}
+
+/// The annotation query of Grafana's JSON data source, pointed at `/api/grafana`.
+#[instrument(skip(state, request))]
+pub async fn grafana_annotations(
+    state: web::Data<Arc<AppState>>,
+    request: web::Json<GrafanaAnnotationRequest>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let mut filter = AnnotationFilter {
+        from: Some(request.range.from),
+        to: Some(request.range.to),
+        ..AnnotationFilter::default()
+    };
+    let query = request.annotation["query"].as_str().unwrap_or_default();
+    for term in query.split([',', ' ']).filter(|term| !term.is_empty()) {
+        match term.strip_prefix("source:") {
+            Some(source) => filter.source = Some(source.to_string()),
+            None => filter.tags.push(term.to_string()),
+        }
+    }
+
+    let annotations: Vec<GrafanaAnnotation> = state
+        .annotations
+        .query(&filter)
+        .into_iter()
+        .map(|annotation| GrafanaAnnotation {
+            annotation: request.annotation.clone(),
+            time: annotation.time.timestamp_millis(),
+            time_end: annotation
+                .time_end
+                .unwrap_or(annotation.time)
+                .timestamp_millis(),
+            is_region: annotation.time_end.is_some(),
+            title: annotation.title,
+            text: annotation.text.unwrap_or_default(),
+            tags: annotation.tags,
+        })
+        .collect();
+    HttpResponse::Ok().json(annotations)
+}
+
+/// The series matched by the `match[]` selectors, for upstream Prometheus
+/// servers to federate.
+#[instrument(skip(req, state))]
+pub async fn federate(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, ServerError> {
+    let (names, selectors) = exposition_filters(&req)?;
+    if selectors.is_empty() {
+        return Err(ServerError::ValidationError(
+            "federation needs at least one match[] selector".to_string(),
+        ));
+    }
+    state.scrape.authenticate(&req).await?;
+    let slot = state.scrape.acquire()?;
+
+    // Selectors match the series as stored, before the external labels
+    let snapshot = state.metrics_collector.gather_exposed().await?;
+    let families = filter_families(snapshot.families, &names, &selectors);
+    encode_exposition(&req, &state, slot, families)
+}
+
+/// Header of `GET /api/metrics` with the number of series matching the
+/// filters, across all pages.
+pub const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+/// The exposed series as JSON, filtered like `/metrics` by `name[]` and
+/// `match[]` and by name `prefix`, a page of `limit` at a time.
+#[instrument(skip(req, state))]
+pub async fn list_series(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<SeriesQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let (names, selectors) = exposition_filters(&req)?;
+    let snapshot = state.metrics_collector.gather_exposed().await?;
+    let families = filter_families(snapshot.families, &names, &selectors);
+    let page = listing::list_series(
+        &families,
+        query.prefix.as_deref(),
+        query.cursor.as_deref(),
+        query.limit,
+    )?;
+
     Ok(HttpResponse::Ok()
-        .content_type("text/plain; version=0.0.4")
-        .body(metrics_data))
+        .insert_header((TOTAL_COUNT_HEADER, page.total.to_string()))
+        .json(page))
+}
+
+/// Streams the sample history of `name` between `start` and `end` as CSV or
+/// Parquet, written on a blocking thread as the response is sent.
+#[instrument(skip(state))]
+pub async fn export_history(
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let history = state.history.as_ref().ok_or_else(|| {
+        ServerError::NotFound("the sample history is off, configure `history`".to_string())
+    })?;
+    if let (Some(start), Some(end)) = (query.start, query.end)
+        && start > end
+    {
+        return Err(ServerError::ValidationError(
+            "start must not be after end".to_string(),
+        ));
+    }
+    if cfg!(not(feature = "csv")) && query.format == ExportFormat::Csv {
+        return Err(ServerError::ValidationError(
+            "CSV export requires the `csv` feature".to_string(),
+        ));
+    }
+    if cfg!(not(feature = "parquet")) && query.format == ExportFormat::Parquet {
+        return Err(ServerError::ValidationError(
+            "Parquet export requires the `parquet` feature".to_string(),
+        ));
+    }
+
+    let snapshots = history.range(query.start, query.end);
+    let ExportQuery { format, name, .. } = query.into_inner();
+    let file_name = format!(
+        "{}.{}",
+        name.as_deref().unwrap_or("metrics"),
+        format.extension()
+    );
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter::new(sender);
+        let written =
+            write_history(format, &snapshots, name.as_deref(), &mut writer).and_then(|()| {
+                writer
+                    .flush()
+                    .map_err(|e| ServerError::InternalError(Box::new(e)))
+            });
+        if let Err(e) = written {
+            warn!("Failed to export the sample history: {}", e);
+            writer.fail(e);
+        }
+    });
+
+    let body = futures::stream::poll_fn(move |cx| {
+        receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(web::Bytes::from)))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        ))
+        .streaming(body))
+}
+
+/// Encodes families for a scrape, applying the request's filters, name
+/// escaping negotiation and conditional GET headers.
+fn render_exposition(
+    req: &HttpRequest,
+    state: &AppState,
+    slot: ScrapeSlot,
+    families: Vec<MetricFamily>,
+) -> Result<HttpResponse, ServerError> {
+    let (names, selectors) = exposition_filters(req)?;
+    let families = filter_families(families, &names, &selectors);
+    encode_exposition(req, state, slot, families)
+}
+
+/// Encodes families with the configured external labels attached. The body
+/// keeps the scrape's slot until it has been sent.
+fn encode_exposition(
+    req: &HttpRequest,
+    state: &AppState,
+    slot: ScrapeSlot,
+    mut families: Vec<MetricFamily>,
+) -> Result<HttpResponse, ServerError> {
+    let collector = &state.metrics_collector;
+    attach_labels(&mut families, &collector.config().external_labels);
+    let utf8 = collector.config().utf8_names && accepts_utf8_names(req);
+    let content_type = if utf8 {
+        "text/plain; version=1.0.0; charset=utf-8; escaping=allow-utf-8"
+    } else if collector.config().utf8_names {
+        "text/plain; version=0.0.4; escaping=values"
+    } else {
+        "text/plain; version=0.0.4"
+    };
+    let body = collector.encode(&families, utf8)?;
+
+    let etag = exposition_etag(&body);
+
+    if is_not_modified(req, &etag) {
+        debug!("Exposition unchanged, answering 304");
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .finish());
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(content_type);
+    let body = if state.scrape.wants_gzip(req) {
+        let compressed = state.scrape.gzip(etag.tag(), body.as_bytes())?;
+        response
+            .insert_header(header::ContentEncoding::Gzip)
+            .insert_header((header::VARY, "accept-encoding"));
+        compressed
+    } else {
+        body.into()
+    };
+    Ok(response
+        .insert_header(header::ETag(etag))
+        .body(state.scrape.body(body, slot)))
+}
+
+/// Reads the federation-style `name[]` and `match[]` query parameters
+/// (also accepted without the brackets).
+fn exposition_filters(
+    req: &HttpRequest,
+) -> Result<(Vec<String>, Vec<SeriesSelector>), ServerError> {
+    let params = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(|e| ServerError::ValidationError(e.to_string()))?;
+
+    let mut names = Vec::new();
+    let mut selectors = Vec::new();
+    for (key, value) in params.into_inner() {
+        match key.as_str() {
+            "name[]" | "name" => names.push(value),
+            "match[]" | "match" => selectors.push(SeriesSelector::parse(&value)?),
+            _ => {}
+        }
+    }
+
+    Ok((names, selectors))
+}
+
+/// Weak, since the compression middleware may re-encode the body.
+fn exposition_etag(body: &str) -> header::EntityTag {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    header::EntityTag::new_weak(format!("{:016x}", hasher.finish()))
+}
+
+/// Only `If-None-Match` is honoured: self metrics and derived rates change
+/// the body without any write, so no modification time would be accurate.
+fn is_not_modified(req: &HttpRequest, etag: &header::EntityTag) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
 }
 
-#[instrument(skip(state, batch), fields(source = field::Empty, count = field::Empty))]
+fn accepts_utf8_names(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.replace(' ', "").contains("escaping=allow-utf-8"))
+}
+
+#[instrument(skip(state, req, batch), fields(source = field::Empty, count = field::Empty))]
 pub async fn ingest_metrics(
     state: web::Data<Arc<AppState>>,
-    web::Json(batch): web::Json<MetricsBatch>,
+    req: HttpRequest,
+    query: web::Query<IngestQuery>,
+    CapturedBatch(batch): CapturedBatch<MetricsBatch>,
+) -> Result<HttpResponse, ServerError> {
+    receive_batch(&state, &req, &query, batch).await
+}
+
+/// `/api/v2` ingestion, accepting the inlined `MetricsBatchV2` format.
+#[instrument(skip(state, req, batch), fields(source = field::Empty, count = field::Empty))]
+pub async fn ingest_metrics_v2(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<IngestQuery>,
+    CapturedBatch(batch): CapturedBatch<MetricsBatchV2>,
+) -> Result<HttpResponse, ServerError> {
+    receive_batch(&state, &req, &query, batch.into()).await
+}
+
+/// Answers with the batch as it would be ingested, after renaming, unit
+/// conversion and the other ingestion policies, without writing anything.
+pub async fn normalize_metrics(
+    state: web::Data<Arc<AppState>>,
+    BatchJson(batch): BatchJson<MetricsBatch>,
+) -> Result<HttpResponse, ServerError> {
+    let response = state.metrics_collector.normalize(&batch).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// `normalize_metrics` for the `MetricsBatchV2` format.
+pub async fn normalize_metrics_v2(
+    state: web::Data<Arc<AppState>>,
+    BatchJson(batch): BatchJson<MetricsBatchV2>,
+) -> Result<HttpResponse, ServerError> {
+    let response = state.metrics_collector.normalize(&batch.into()).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+async fn receive_batch(
+    state: &Arc<AppState>,
+    req: &HttpRequest,
+    query: &IngestQuery,
+    mut batch: MetricsBatch,
 ) -> Result<HttpResponse, ServerError> {
     tracing::Span::current()
-        .record("source", &batch.source.as_str())
-        .record("count", &batch.metrics.len());
+        .record("source", batch.source.as_str())
+        .record("count", batch.metrics.len());
+    ensure_ingesting(state)?;
+    authorize_source(state, req, &batch.source)?;
+
+    if let Some(target) = target_labels(state, req, &batch.source) {
+        batch
+            .metrics
+            .iter_mut()
+            .for_each(|metric| target.apply(metric));
+    }
 
     debug!(
         "Received metrics batch with {} metrics",
         batch.metrics.len()
     );
 
-    batch.validate()?;
+    if query.async_processing {
+        #[cfg(feature = "chaos")]
+        state.chaos.disrupt().await?;
+        return submit_job(state.clone(), batch).await;
+    }
+
+    ingest_batch(state, batch).await
+}
+
+/// Refuses ingestion outside of the normal operating mode.
+fn ensure_ingesting(state: &AppState) -> Result<(), ServerError> {
+    if state.mode.accepts_ingestion() {
+        return Ok(());
+    }
+    Err(ServerError::Unavailable {
+        message: format!("ingestion is paused in {:?} mode", state.mode.get()),
+        retry_after_secs: state.config.server.maintenance_retry_after_secs,
+    })
+}
+
+/// Checks the `Authorization: Bearer` token of a registered source, and
+/// that the push was signed for it when `server.signing` requires it.
+fn authorize_source(state: &AppState, req: &HttpRequest, source: &str) -> Result<(), ServerError> {
+    if let Some(signatures) = &state.signatures {
+        signatures.check_source(req, source)?;
+    }
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    state
+        .metrics_collector
+        .registrations()
+        .authorize(source, token)
+}
+
+/// The `job` and `instance` labels of a push, when `metrics.target_labels`
+/// is configured.
+fn target_labels(state: &AppState, req: &HttpRequest, source: &str) -> Option<TargetLabels> {
+    let config = state.config.metrics.target_labels.as_ref()?;
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    // Anyone can send the header, only the configured proxies are believed
+    let proxied = peer.is_some_and(|peer| {
+        config
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| IpNetwork::parse(proxy))
+            .any(|proxy| proxy.contains(peer))
+    });
+    let instance = config
+        .instance_header
+        .as_ref()
+        .filter(|_| proxied)
+        .and_then(|name| req.headers().get(name.as_str()))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .or_else(|| peer.map(|peer| peer.to_string()));
+    Some(TargetLabels::new(config, source, instance))
+}
+
+/// Validates the batch up front, then processes it in the background so large
+/// batches don't hold the request open.
+async fn submit_job(
+    state: Arc<AppState>,
+    batch: MetricsBatch,
+) -> Result<HttpResponse, ServerError> {
+    state.metrics_collector.validate(&batch).await?;
+    if let Some(fanout) = &state.fanout
+        && !fanout.writes_registry()
+    {
+        return Ok(batch_queued(fanout, batch));
+    }
+
+    let job_id = state.jobs.start(&batch.source, batch.metrics.len());
+    debug!("Accepted batch from {} as job {}", batch.source, job_id);
+
+    let id = job_id.clone();
+    tokio::spawn(async move {
+        let response = state.metrics_collector.process_batch_report(batch).await;
+        state.jobs.finish(&id, response);
+    });
+
+    Ok(HttpResponse::Accepted().json(JobAccepted {
+        status_url: format!("/api/jobs/{}", job_id),
+        job_id,
+    }))
+}
+
+#[instrument(skip(state))]
+pub async fn job_status(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let id = path.into_inner();
+    let job = state
+        .jobs
+        .get(&id)
+        .ok_or_else(|| ServerError::NotFound(format!("job '{}'", id)))?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+/// Streams newline-delimited `Metric` objects, optionally compressed with
+/// `Content-Encoding`, processing them in chunks as they arrive.
+#[instrument(skip(state, req, payload), fields(source = %query.source))]
+pub async fn ingest_ndjson(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<StreamQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse, ServerError> {
+    if query.source.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Source cannot be empty".to_string(),
+        ));
+    }
+
+    require_format(&req, RequestFormat::Ndjson)?;
+    ensure_ingesting(&state)?;
+    authorize_source(&state, &req, &query.source)?;
+
+    #[cfg(feature = "chaos")]
+    state.chaos.disrupt().await?;
+
+    let target = target_labels(&state, &req, &query.source);
+    let payload = Decompress::from_headers(payload, req.headers());
+    let response = ndjson::ingest_stream(
+        &state.metrics_collector,
+        &query.source,
+        target.as_ref(),
+        payload,
+    )
+    .await?;
+    let response = MetricsResponse {
+        request_id: current_request_id(),
+        ..response
+    };
+
+    debug!("Processed {} streamed metrics", response.processed);
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Prometheus text exposition pushed by agents, e.g. the output of an
+/// exporter they scraped. `?lenient=true` skips malformed lines, reporting
+/// them by line number, instead of rejecting the payload.
+#[instrument(skip(state, req, body), fields(source = %query.source))]
+pub async fn ingest_prometheus(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<TextQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ServerError> {
+    if query.source.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Source cannot be empty".to_string(),
+        ));
+    }
+
+    require_format(&req, RequestFormat::PrometheusText)?;
+    ensure_ingesting(&state)?;
+    authorize_source(&state, &req, &query.source)?;
+
+    #[cfg(feature = "chaos")]
+    state.chaos.disrupt().await?;
+
+    let target = target_labels(&state, &req, &query.source);
+    let mode = match query.lenient {
+        true => ParseMode::Lenient,
+        false => ParseMode::Strict,
+    };
+    let response = ingest_text(
+        &state.metrics_collector,
+        &state.prometheus_text,
+        &query.source,
+        target.as_ref(),
+        &body,
+        mode,
+    )
+    .await?;
+    let response = MetricsResponse {
+        request_id: current_request_id(),
+        ..response
+    };
+
+    debug!("Processed {} metrics from text", response.processed);
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Adapter for collectd's `write_http` plugin with `Format "JSON"`.
+#[instrument(skip(state, req, value_lists), fields(source = %query.source, count = value_lists.len()))]
+pub async fn ingest_collectd(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    query: web::Query<CollectdQuery>,
+    web::Json(value_lists): web::Json<Vec<CollectdValueList>>,
+) -> Result<HttpResponse, ServerError> {
+    ensure_ingesting(&state)?;
+    authorize_source(&state, &req, &query.source)?;
+    let target = target_labels(&state, &req, &query.source);
+    let (batch, pending) = state
+        .collectd
+        .to_batch(&query.source, value_lists, target.as_ref());
+    if batch.metrics.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Payload contains no readable collectd values".to_string(),
+        ));
+    }
+
+    debug!(
+        "Converted collectd payload into {} metrics",
+        batch.metrics.len()
+    );
+    let (response, accepted) = ingest_batch_accepted(&state, batch).await?;
+    state.collectd.commit(&query.source, pending, &accepted);
+    Ok(response)
+}
+
+async fn ingest_batch(state: &AppState, batch: MetricsBatch) -> Result<HttpResponse, ServerError> {
+    #[cfg(feature = "chaos")]
+    let (batch, injected) = state.chaos.inject(batch).await?;
+
+    if let Some(fanout) = &state.fanout
+        && !fanout.writes_registry()
+    {
+        state.metrics_collector.validate(&batch).await?;
+        return Ok(batch_queued(fanout, batch));
+    }
+    let response = match state.metrics_collector.ingest(batch).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Failed to process metrics batch: {}", e);
+            return Err(e);
+        }
+    };
+    #[cfg(feature = "chaos")]
+    let response = chaos::with_injected_errors(response, injected);
+    Ok(ingested(response))
+}
+
+/// Like `ingest_batch`, also returning the metrics that were written, or
+/// queued for the fan-out destinations.
+async fn ingest_batch_accepted(
+    state: &AppState,
+    batch: MetricsBatch,
+) -> Result<(HttpResponse, Vec<Metric>), ServerError> {
+    #[cfg(feature = "chaos")]
+    let (batch, injected) = state.chaos.inject(batch).await?;
 
-    let response = match state.metrics_collector.process_batch(batch).await {
+    state.metrics_collector.validate(&batch).await?;
+    if let Some(fanout) = &state.fanout
+        && !fanout.writes_registry()
+    {
+        let accepted = batch.metrics.clone();
+        return Ok((batch_queued(fanout, batch), accepted));
+    }
+    let (response, accepted) = state.metrics_collector.process_batch_accepted(batch).await;
+    let response = match response.into_result() {
         Ok(response) => response,
         Err(e) => {
             error!("Failed to process metrics batch: {}", e);
             return Err(e);
         }
     };
+    #[cfg(feature = "chaos")]
+    let response = chaos::with_injected_errors(response, injected);
+    Ok((ingested(response), accepted.metrics))
+}
+
+/// Answers with the response of an ingested batch.
+fn ingested(response: MetricsResponse) -> HttpResponse {
+    let response = MetricsResponse {
+        request_id: current_request_id(),
+        ..response
+    };
 
     debug!("Processed {} metrics successfully", response.processed);
-    Ok(HttpResponse::Ok().json(response))
+    HttpResponse::Ok().json(response)
+}
+
+/// Hands a validated batch to the fan-out destinations alone, answering
+/// before any of them delivered it.
+fn batch_queued(fanout: &BatchFanout, batch: MetricsBatch) -> HttpResponse {
+    let source = batch.source.clone();
+    let sinks = fanout.deliver(batch);
+    debug!(
+        "Queued batch from {} for {} destinations",
+        source,
+        sinks.len()
+    );
+    HttpResponse::Accepted().json(BatchQueued {
+        request_id: current_request_id(),
+        sinks,
+    })
+}
+
+/// Top families and label names by series count, and series per source.
+#[instrument(skip(state))]
+pub async fn cardinality(
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<CardinalityQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let report = state.metrics_collector.cardinality(query.limit).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Heavy hitters of a metric configured in `metrics.topk`.
+#[instrument(skip(state))]
+pub async fn topk(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let metric = path.into_inner();
+    let report = state
+        .metrics_collector
+        .topk(&metric)
+        .ok_or_else(|| ServerError::NotFound(format!("top-k tracking for '{}'", metric)))?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[instrument(skip(state))]
+pub async fn anomalies(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.metrics_collector.anomalies())
+}
+
+/// Latest burn rates and remaining error budget of the configured SLOs.
+#[instrument(skip(state))]
+pub async fn slo_summary(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.slos.statuses())
+}
+
+#[instrument(skip(state))]
+pub async fn list_schemas(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.metrics_collector.schemas().list())
+}
+
+/// Declares (or replaces) the expected shape of a metric.
+#[instrument(skip(state, schema), fields(name = %schema.name))]
+pub async fn register_schema(
+    state: web::Data<Arc<AppState>>,
+    web::Json(schema): web::Json<MetricSchema>,
+) -> Result<HttpResponse, ServerError> {
+    state.metrics_collector.schemas().register(schema.clone())?;
+
+    debug!("Registered schema for {}", schema.name);
+    Ok(HttpResponse::Created().json(schema))
+}
+
+#[instrument(skip(state))]
+pub async fn list_metadata(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.metrics_collector.catalog().list())
+}
+
+#[instrument(skip(state))]
+pub async fn metric_metadata(
+    state: web::Data<Arc<AppState>>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let name = name.into_inner();
+    let metadata = state
+        .metrics_collector
+        .catalog()
+        .get(&name)
+        .ok_or_else(|| ServerError::NotFound(format!("metadata for '{}'", name)))?;
+
+    Ok(HttpResponse::Ok().json(metadata))
+}
+
+/// Sets who owns a metric and where its runbook lives.
+#[instrument(skip(state, ownership))]
+pub async fn update_metadata(
+    state: web::Data<Arc<AppState>>,
+    name: web::Path<String>,
+    web::Json(ownership): web::Json<Ownership>,
+) -> Result<HttpResponse, ServerError> {
+    let metadata = state
+        .metrics_collector
+        .catalog()
+        .set_ownership(&name, ownership)?;
+
+    debug!("Updated ownership of {}", name);
+    Ok(HttpResponse::Ok().json(metadata))
+}
+
+#[cfg(feature = "chaos")]
+#[instrument(skip(state, req))]
+pub async fn chaos_settings(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    Ok(HttpResponse::Ok().json(state.chaos.settings()))
+}
+
+/// Replaces the failures injected into ingestion; all zeroes turns them off.
+#[cfg(feature = "chaos")]
+#[instrument(skip(state, req))]
+pub async fn update_chaos(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Json(settings): web::Json<ChaosSettings>,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    state.chaos.set(settings)?;
+    Ok(HttpResponse::Ok().json(state.chaos.settings()))
+}
+
+/// Active and upcoming silences, including configured maintenance windows.
+#[instrument(skip(state))]
+pub async fn list_silences(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.metrics_collector.notifier().silences().list())
+}
+
+/// Silences the alerts matching a selector from now on, e.g. during a deployment.
+#[instrument(skip(state, request), fields(matchers = %request.matchers))]
+pub async fn create_silence(
+    state: web::Data<Arc<AppState>>,
+    web::Json(request): web::Json<SilenceRequest>,
+) -> Result<HttpResponse, ServerError> {
+    let duration = i64::try_from(request.duration_secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or_else(|| ServerError::ValidationError("duration_secs is too large".to_string()))?;
+    let silence = state.metrics_collector.notifier().silences().add(
+        &request.matchers,
+        duration,
+        &request.comment,
+    )?;
+
+    debug!("Created silence {} until {}", silence.id, silence.ends_at);
+    Ok(HttpResponse::Created().json(silence))
+}
+
+#[instrument(skip(state))]
+pub async fn delete_silence(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let id = path.into_inner();
+    if !state.metrics_collector.notifier().silences().remove(&id) {
+        return Err(ServerError::NotFound(format!("silence '{}'", id)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The clients failing to authenticate and those locked out, empty without
+/// `server.lockout`.
+#[instrument(skip(state))]
+pub async fn list_lockouts(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let lockouts = match &state.lockout {
+        Some(lockout) => lockout.list(Utc::now()),
+        None => Vec::new(),
+    };
+    HttpResponse::Ok().json(lockouts)
+}
+
+/// Lifts every lockout, e.g. after an attack from a shared address.
+#[instrument(skip(state))]
+pub async fn clear_lockouts(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if let Some(lockout) = &state.lockout {
+        let cleared = lockout.clear(None);
+        debug!("Cleared {} lockouts", cleared);
+    }
+    HttpResponse::NoContent().finish()
+}
+
+/// Lifts the lockout of one client or credential, by its key.
+#[instrument(skip(state))]
+pub async fn clear_lockout(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let key = path.into_inner();
+    let cleared = state
+        .lockout
+        .as_ref()
+        .is_some_and(|lockout| lockout.clear(Some(&key)) > 0);
+    if !cleared {
+        return Err(ServerError::NotFound(format!("lockout '{}'", key)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The effective configuration with its secrets redacted, and whether each
+/// value came from a file, the environment or the built-in defaults.
+#[instrument(skip(state))]
+pub async fn effective_config(
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, ServerError> {
+    let mut config = serde_json::to_value(&state.config)?;
+    let mut sources = BTreeMap::new();
+    collect_sources("", &config, &state.config.origins, &mut sources);
+    redact(&mut config);
+
+    Ok(HttpResponse::Ok().json(EffectiveConfigResponse {
+        profile: run_mode(),
+        config,
+        sources,
+    }))
+}
+
+fn collect_sources(
+    path: &str,
+    value: &serde_json::Value,
+    origins: &BTreeMap<String, String>,
+    sources: &mut BTreeMap<String, String>,
+) {
+    match value {
+        serde_json::Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                collect_sources(&path, value, origins, sources);
+            }
+        }
+        _ => {
+            let source = origins
+                .get(path)
+                .cloned()
+                .unwrap_or_else(|| "default".to_string());
+            sources.insert(path.to_string(), source);
+        }
+    }
+}
+
+#[instrument(skip(state))]
+pub async fn operating_mode(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(ModeResponse {
+        mode: state.mode.get(),
+        running_jobs: state.jobs.running(),
+    })
+}
+
+/// Switches between `normal`, `read_only` and `drain` for maintenance. As it
+/// can stop ingestion, only an authenticated admin may.
+#[instrument(skip(state, req))]
+pub async fn update_mode(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Json(request): web::Json<ModeRequest>,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    state.mode.set(request.mode).await;
+    Ok(HttpResponse::Ok().json(ModeResponse {
+        mode: state.mode.get(),
+        running_jobs: state.jobs.running(),
+    }))
+}
+
+/// Tombstones whose series are hidden and not purged yet.
+#[instrument(skip(state))]
+pub async fn list_tombstones(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.metrics_collector.tombstones().list())
+}
+
+/// Hides the matching series from the exposition now, and has compaction
+/// delete them once the grace period is over.
+#[instrument(skip(state, request), fields(matchers = ?request.matchers))]
+pub async fn create_tombstone(
+    state: web::Data<Arc<AppState>>,
+    web::Json(request): web::Json<TombstoneRequest>,
+) -> Result<HttpResponse, ServerError> {
+    let grace_secs = request
+        .grace_secs
+        .unwrap_or(state.metrics_collector.config().tombstone_grace_secs);
+    let grace = i64::try_from(grace_secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or_else(|| ServerError::ValidationError("grace_secs is too large".to_string()))?;
+    let tombstone = state
+        .metrics_collector
+        .tombstones()
+        .add(&request.matchers, grace)?;
+
+    debug!(
+        "Created tombstone {} purged at {}",
+        tombstone.id, tombstone.purge_at
+    );
+    Ok(HttpResponse::Created().json(tombstone))
+}
+
+/// Registered sources with their last push, flagging those overdue.
+#[instrument(skip(state))]
+pub async fn list_sources(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.metrics_collector.registrations().list())
+}
+
+/// Registers a source and issues its API token, replacing any previous one.
+/// Only an authenticated admin may, as the token is a source's credentials.
+#[instrument(skip(state, req, request), fields(source = %request.source))]
+pub async fn register_source(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    web::Json(request): web::Json<SourceRequest>,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    let issued = state.metrics_collector.register_source(request).await?;
+
+    debug!("Registered source {}", issued.status.source);
+    Ok(HttpResponse::Created().json(issued))
+}
+
+#[instrument(skip(state, req))]
+pub async fn delete_source(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    let source = path.into_inner();
+    if !state.metrics_collector.unregister_source(&source).await {
+        return Err(ServerError::NotFound(format!("source '{}'", source)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn report_config<'a>(state: &'a AppState, name: &str) -> Result<&'a ReportConfig, ServerError> {
+    state
+        .config
+        .reports
+        .iter()
+        .find(|report| report.name == name)
+        .ok_or_else(|| ServerError::NotFound(format!("report '{}'", name)))
+}
+
+/// Where the deliveries of lifecycle events to `event_webhooks` stand, most
+/// recent first.
+#[instrument(skip(state))]
+pub async fn webhook_deliveries(
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<DeliveryQuery>,
+) -> HttpResponse {
+    let deliveries = state
+        .metrics_collector
+        .events()
+        .deliveries(query.webhook.as_deref(), query.status);
+    HttpResponse::Ok().json(deliveries)
+}
+
+/// Renders a configured report now, as its channels would receive it.
+#[instrument(skip(state))]
+pub async fn preview_report(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let config = report_config(&state, &path)?;
+    let rendered = Report::collect(config, &state.metrics_collector, &state.slos)
+        .await?
+        .render(config.format)?;
+    Ok(HttpResponse::Ok()
+        .content_type(rendered.content_type())
+        .body(rendered.body))
+}
+
+/// Sends a configured report now, outside of its schedule.
+#[instrument(skip(state))]
+pub async fn send_report(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let config = report_config(&state, &path)?;
+    let rendered = Report::collect(config, &state.metrics_collector, &state.slos)
+        .await?
+        .render(config.format)?;
+    let delivered = reports::deliver(state.metrics_collector.notifier(), config, &rendered).await;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "report": config.name,
+        "channels": config.channels.len(),
+        "delivered": delivered,
+    })))
+}
+
+/// Prometheus HTTP service discovery document listing this gateway and its peers.
+#[instrument(skip(state))]
+pub async fn service_discovery(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let config = &state.config;
+    let address = config
+        .discovery
+        .advertise_address
+        .clone()
+        .unwrap_or_else(|| format!("{}:{}", config.server.host, config.server.port));
+
+    let target_group = |targets: Vec<String>, role: &str| {
+        let mut labels = config.discovery.labels.clone();
+        labels.insert(
+            "__metrics_path__".to_string(),
+            config.metrics.prometheus_endpoint.clone(),
+        );
+        labels.insert("__meta_rustic_insights_role".to_string(), role.to_string());
+        TargetGroup { targets, labels }
+    };
+
+    let mut groups = vec![target_group(vec![address], "self")];
+    if !config.discovery.peers.is_empty() {
+        groups.push(target_group(config.discovery.peers.clone(), "peer"));
+    }
+
+    debug!("Service discovery returned {} target groups", groups.len());
+    HttpResponse::Ok().json(groups)
 }
@@ -0,0 +1,187 @@
+//! JSON body extraction for the batch ingestion endpoints. Parsing large
+//! batches is the dominant ingestion cost, so with the `simd-json` feature
+//! the bodies are parsed with simd-json instead of serde_json; otherwise this
+//! is plain `web::Json`. `CapturedBatch` records the bodies with `capture`
+//! before parsing them.
+
+use crate::api::format::{RequestFormat, json_error_handler};
+use crate::api::handlers::AppState;
+use crate::errors::ServerError;
+use crate::metrics::{MetricsBatch, MetricsBatchV2};
+use actix_web::dev::{Decompress, Payload};
+use actix_web::error::JsonPayloadError;
+use actix_web::{FromRequest, HttpMessage, HttpRequest, web};
+use futures::StreamExt;
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use tracing::warn;
+
+/// `web::Json`'s default limit, so bodies read here are accepted alike.
+const LIMIT: usize = 2_097_152;
+
+#[cfg(not(feature = "simd-json"))]
+pub use actix_web::web::Json as BatchJson;
+
+#[cfg(feature = "simd-json")]
+pub use simd::BatchJson;
+
+#[cfg(feature = "simd-json")]
+mod simd {
+    use super::{LIMIT, parse};
+    use crate::api::format::{RequestFormat, require_format};
+    use actix_web::dev::{Decompress, Payload};
+    use actix_web::error::JsonPayloadError;
+    use actix_web::{FromRequest, HttpRequest, web};
+    use futures::StreamExt;
+    use futures::future::LocalBoxFuture;
+    use serde::de::DeserializeOwned;
+
+    /// Drop-in for `web::Json` parsing with simd-json. Like `web::Json`, it
+    /// requires a JSON content type, answering a 415 otherwise, and decodes
+    /// the `Content-Encoding`.
+    pub struct BatchJson<T>(pub T);
+
+    impl<T: DeserializeOwned + 'static> FromRequest for BatchJson<T> {
+        type Error = actix_web::Error;
+        type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+        fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+            let format = require_format(req, RequestFormat::Json);
+            let mut payload = Decompress::from_headers(payload.take(), req.headers());
+            let req = req.clone();
+
+            Box::pin(async move {
+                format?;
+
+                let mut body = web::BytesMut::with_capacity(8192);
+                while let Some(chunk) = payload.next().await {
+                    let chunk = chunk?;
+                    if body.len() + chunk.len() > LIMIT {
+                        return Err(JsonPayloadError::Overflow { limit: LIMIT }.into());
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+
+                parse(&req, body).map(BatchJson)
+            })
+        }
+    }
+}
+
+/// A batch format of the ingestion endpoints.
+pub trait BatchFormat: DeserializeOwned + 'static {
+    /// The API version the format belongs to
+    const VERSION: u8;
+
+    fn source(&self) -> &str;
+}
+
+impl BatchFormat for MetricsBatch {
+    const VERSION: u8 = 1;
+
+    fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+impl BatchFormat for MetricsBatchV2 {
+    const VERSION: u8 = 2;
+
+    fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+/// `BatchJson`, recording the body as received first when `capture` is
+/// configured, so bodies that don't parse, or that are refused, are captured
+/// too. Bodies that don't parse are dead-lettered, under the `source` they
+/// name if any, or `http`.
+pub struct CapturedBatch<T>(pub T);
+
+impl<T: BatchFormat> FromRequest for CapturedBatch<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let state = req
+            .app_data::<web::Data<Arc<AppState>>>()
+            .filter(|state| state.capture.is_some() || state.metrics_collector.has_dead_letter())
+            .cloned();
+        let Some(state) = state else {
+            let batch = BatchJson::<T>::from_request(req, payload);
+            return Box::pin(async move { batch.await.map(|batch| CapturedBatch(batch.0)) });
+        };
+
+        let mut payload = Decompress::from_headers(payload.take(), req.headers());
+        let req = req.clone();
+        Box::pin(async move {
+            let mut body = web::BytesMut::with_capacity(8192);
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > LIMIT {
+                    let overflow = JsonPayloadError::Overflow { limit: LIMIT };
+                    return Err(json_error_handler(overflow, &req));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let raw = body.clone().freeze();
+
+            let batch = match req.mime_type() {
+                Ok(Some(mime)) if RequestFormat::Json.matches(&mime) => {
+                    let batch = parse::<T>(&req, body);
+                    if let Err(e) = &batch {
+                        dead_letter_body(&state, &raw, e);
+                    }
+                    batch
+                }
+                _ => Err(RequestFormat::Json.unsupported(&req).into()),
+            };
+            if let Some(capture) = &state.capture {
+                let source = batch.as_ref().ok().map(BatchFormat::source);
+                let content_type = req.content_type();
+                let content_type = (!content_type.is_empty()).then_some(content_type);
+                if let Err(e) = capture.record(source, T::VERSION, content_type, &raw).await {
+                    warn!("Failed to capture a body from {:?}: {}", source, e);
+                }
+            }
+            batch.map(CapturedBatch)
+        })
+    }
+}
+
+/// Dead-letters a body that didn't parse as a batch: as JSON if it is, and
+/// as a string otherwise.
+fn dead_letter_body(state: &AppState, raw: &[u8], error: &actix_web::Error) {
+    let payload = serde_json::from_slice::<serde_json::Value>(raw)
+        .unwrap_or_else(|_| String::from_utf8_lossy(raw).into_owned().into());
+    let source = payload
+        .get("source")
+        .and_then(|source| source.as_str())
+        .unwrap_or("http")
+        .to_string();
+    let error = ServerError::ValidationError(error.to_string());
+    state
+        .metrics_collector
+        .dead_letter(&source, payload, &error);
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse<T: DeserializeOwned>(
+    req: &HttpRequest,
+    body: web::BytesMut,
+) -> Result<T, actix_web::Error> {
+    serde_json::from_slice(&body)
+        .map_err(|e| json_error_handler(JsonPayloadError::Deserialize(e), req))
+}
+
+#[cfg(feature = "simd-json")]
+fn parse<T: DeserializeOwned>(
+    _req: &HttpRequest,
+    mut body: web::BytesMut,
+) -> Result<T, actix_web::Error> {
+    // simd-json parses in place
+    simd_json::serde::from_slice(&mut body).map_err(|e| {
+        crate::errors::ServerError::ValidationError(format!("Json deserialize error: {}", e)).into()
+    })
+}
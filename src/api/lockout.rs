@@ -0,0 +1,261 @@
+//! Brute-force protection: clients failing to authenticate again and again
+//! are locked out for exponentially longer, by address and by credential.
+
+use crate::api::auth::API_KEY_HEADER;
+use crate::api::handlers::AppState;
+use crate::config::{LockoutConfig, MAX_LOCKOUT_SECS};
+use crate::errors::ServerError;
+use crate::utils::hex;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{StatusCode, header};
+use actix_web::middleware::Next;
+use actix_web::{HttpRequest, web};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Duration, Utc};
+use ring::digest::{SHA256, digest};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Clients remembered at most; beyond it the failures remembered longest
+/// and no longer locking anyone out are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+/// A client failing to authenticate, as listed by `/api/admin/lockouts`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockout {
+    /// `ip:<address>`, `user:<basic auth user>@<address>`, or
+    /// `api_key:`/`token:` followed by a fingerprint of the credential.
+    pub key: String,
+    /// Failures in a row.
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// The clients failing to authenticate recently.
+pub struct AuthLockout {
+    config: LockoutConfig,
+    clients: Mutex<HashMap<String, Lockout>>,
+}
+
+impl AuthLockout {
+    pub fn new(config: &LockoutConfig) -> Self {
+        Self {
+            config: config.clone(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refuses a request from a locked out client with a 429.
+    pub fn check(&self, keys: &[String], now: DateTime<Utc>) -> Result<(), ServerError> {
+        let clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        for key in keys {
+            if let Some(locked_until) = clients.get(key).and_then(|client| client.locked_until)
+                && locked_until > now
+            {
+                // Rounded up, so retrying after it isn't refused again
+                let retry_after_secs =
+                    ((locked_until - now).num_milliseconds() as u64).div_ceil(1000);
+                return Err(ServerError::TooManyRequests {
+                    message: format!(
+                        "{} is locked out after failing to authenticate, retry in {}s",
+                        key, retry_after_secs
+                    ),
+                    retry_after_secs,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a failure for each key, returning those it locks out.
+    pub fn record_failure(&self, keys: &[String], now: DateTime<Utc>) -> Vec<Lockout> {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if clients.len() >= MAX_CLIENTS {
+            self.evict(&mut clients, now);
+        }
+
+        let mut locked = Vec::new();
+        for key in keys {
+            let client = clients.entry(key.clone()).or_insert_with(|| Lockout {
+                key: key.clone(),
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if !self.remembered(client, now) {
+                client.failures = 0;
+                client.locked_until = None;
+            }
+            client.failures = client.failures.saturating_add(1);
+            client.last_failure = now;
+            if let Some(excess) = client.failures.checked_sub(self.config.max_failures) {
+                let secs = self
+                    .config
+                    .lockout_secs
+                    .saturating_mul(2u64.saturating_pow(excess))
+                    .min(self.max_lockout_secs());
+                client.locked_until = Some(now + Duration::seconds(secs as i64));
+                locked.push(client.clone());
+            }
+        }
+        locked
+    }
+
+    /// Forgets the clients whose failures are too old to count, then, if
+    /// that isn't enough, the tenth of the rest that failed longest ago,
+    /// so a flood of failing clients only runs this once in a while.
+    fn evict(&self, clients: &mut HashMap<String, Lockout>, now: DateTime<Utc>) {
+        clients.retain(|_, client| self.remembered(client, now));
+        if clients.len() < MAX_CLIENTS {
+            return;
+        }
+        let mut last_failures: Vec<DateTime<Utc>> =
+            clients.values().map(|client| client.last_failure).collect();
+        let cutoff = *last_failures.select_nth_unstable(MAX_CLIENTS / 10).1;
+        clients.retain(|_, client| client.last_failure > cutoff);
+    }
+
+    /// Whether a client's failures still count: it is locked out, or failed
+    /// within the longest lockout.
+    fn remembered(&self, client: &Lockout, now: DateTime<Utc>) -> bool {
+        client.last_failure + self.max_lockout() > now
+            || client.locked_until.is_some_and(|until| until > now)
+    }
+
+    /// Forgets the failures of a credential that was accepted. Those of
+    /// its address are kept, so a valid credential can't be used to keep
+    /// guessing others.
+    pub fn record_success(&self, key: &str) {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.remove(key);
+    }
+
+    /// The clients with failures, locked out or not, most failing first.
+    pub fn list(&self, now: DateTime<Utc>) -> Vec<Lockout> {
+        let clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list: Vec<Lockout> = clients
+            .values()
+            .filter(|client| self.remembered(client, now))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| b.failures.cmp(&a.failures).then(a.key.cmp(&b.key)));
+        list
+    }
+
+    /// Clears a key, or every key, returning how many were cleared.
+    pub fn clear(&self, key: Option<&str>) -> usize {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        match key {
+            Some(key) => usize::from(clients.remove(key).is_some()),
+            None => {
+                let cleared = clients.len();
+                clients.clear();
+                cleared
+            }
+        }
+    }
+
+    fn max_lockout_secs(&self) -> u64 {
+        self.config.max_lockout_secs.min(MAX_LOCKOUT_SECS)
+    }
+
+    fn max_lockout(&self) -> Duration {
+        Duration::seconds(self.max_lockout_secs() as i64)
+    }
+}
+
+/// The kind of credential a request presents, and its lockout key. Basic
+/// auth users are keyed with the client address, as anyone can name one.
+fn credential(req: &HttpRequest) -> Option<(&'static str, String)> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER) {
+        return Some((
+            "api_key",
+            format!("api_key:{}", fingerprint(key.as_bytes())),
+        ));
+    }
+    let authorization = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = authorization.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("basic") {
+        let user = STANDARD
+            .decode(value.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .map(|decoded| decoded.split(':').next().unwrap_or_default().to_string())
+            .unwrap_or_default();
+        let key = match req.peer_addr() {
+            Some(addr) => format!("user:{}@{}", user, addr.ip()),
+            None => format!("user:{}", user),
+        };
+        return Some(("basic", key));
+    }
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some((
+            "bearer",
+            format!("token:{}", fingerprint(value.trim().as_bytes())),
+        ));
+    }
+    None
+}
+
+/// Identifies a secret without keeping it.
+fn fingerprint(secret: &[u8]) -> String {
+    hex::encode(&digest(&SHA256, secret).as_ref()[..8])
+}
+
+/// Middleware counting the requests answered with a 401 and, with
+/// `server.lockout`, refusing clients that failed too often. Wrap it outside
+/// `authorize`, so it sees refusals from `auth` as well as the handlers.
+pub async fn enforce_lockouts(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<Arc<AppState>>>().cloned() else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let credential = credential(req.request());
+    let mut keys: Vec<String> = req
+        .peer_addr()
+        .map(|addr| format!("ip:{}", addr.ip()))
+        .into_iter()
+        .collect();
+    keys.extend(credential.as_ref().map(|(_, key)| key.clone()));
+    if let Some(lockout) = &state.lockout
+        && let Err(e) = lockout.check(&keys, Utc::now())
+    {
+        return Ok(req.error_response(e).map_into_right_body());
+    }
+
+    let res = next.call(req).await?;
+    if res.status() == StatusCode::UNAUTHORIZED {
+        let kind = credential.as_ref().map_or("none", |(kind, _)| kind);
+        state
+            .metrics_collector
+            .self_metrics()
+            .auth_failures_total
+            .with_label_values(&[kind])
+            .inc();
+        if let Some(lockout) = &state.lockout {
+            for locked in lockout.record_failure(&keys, Utc::now()) {
+                warn!(
+                    "Locked out {} until {} after {} failed authentications",
+                    locked.key,
+                    locked.locked_until.unwrap_or_default(),
+                    locked.failures
+                );
+            }
+        }
+    } else if let Some((_, key)) = &credential
+        && res.status().is_success()
+        && let Some(lockout) = &state.lockout
+    {
+        lockout.record_success(key);
+    }
+    Ok(res.map_into_left_body())
+}
@@ -1,7 +1,19 @@
+use crate::build_info::BuildInfo;
+use crate::config::MetricsConfig;
 use crate::errors::ServerError;
-use crate::metrics::types::{Metric, MetricsBatch};
+use crate::events::DeliveryStatus;
+use crate::health::ComponentHealth;
+use crate::ingest::collectd::COLLECTD_SOURCE;
+use crate::metrics::export::ExportFormat;
+use crate::metrics::listing::DEFAULT_PAGE_SIZE;
+use crate::metrics::types::{GaugeOperation, Metric, MetricError, MetricType, MetricsBatch};
+use crate::mode::OperatingMode;
+use crate::sinks::fanout::Delivery;
+use crate::uptime::StopReason;
+use crate::utils::validation::{is_reserved_label_name, validate_utf8_metric_name};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HealthResponse {
@@ -10,73 +22,396 @@ pub struct HealthResponse {
     pub timestamp: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ReadinessResponse {
+    pub status: String,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+/// A target group in the Prometheus HTTP SD format.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TargetGroup {
+    pub targets: Vec<String>,
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct IngestQuery {
+    /// Process the batch in the background and answer with a job ID.
+    #[serde(default, rename = "async")]
+    pub async_processing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    /// Source of every metric in the stream, as `MetricsBatch::source`.
+    pub source: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CollectdQuery {
+    /// Source of the converted metrics, `collectd` unless given.
+    #[serde(default = "default_collectd_source")]
+    pub source: String,
+}
+
+fn default_collectd_source() -> String {
+    COLLECTD_SOURCE.to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TextQuery {
+    /// Source of every metric in the payload, as `MetricsBatch::source`.
+    pub source: String,
+    /// Skip malformed lines, reporting them, instead of rejecting the payload.
+    #[serde(default)]
+    pub lenient: bool,
+}
+
+/// Query of `GET /api/metrics`, besides the `name[]` and `match[]` filters.
+#[derive(Debug, Deserialize)]
+pub struct SeriesQuery {
+    /// Only the families whose exposed name starts with it.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default = "default_series_limit")]
+    pub limit: usize,
+    /// The `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+fn default_series_limit() -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+/// Query of `GET /api/admin/webhooks/deliveries`.
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+    #[serde(default)]
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub status: Option<DeliveryStatus>,
+}
+
+/// Query of `GET /api/annotations`.
+#[derive(Debug, Deserialize)]
+pub struct AnnotationQuery {
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    /// Comma-separated tags the annotations must all carry.
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// The annotation query of Grafana's JSON data source.
+#[derive(Debug, Deserialize)]
+pub struct GrafanaAnnotationRequest {
+    pub range: GrafanaRange,
+    /// The annotation definition, echoed back with every result. Its
+    /// `query` lists the tags to match, and `source:<name>` a source.
+    pub annotation: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrafanaRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+/// An annotation as Grafana's JSON data source expects it, times in ms.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GrafanaAnnotation {
+    pub annotation: serde_json::Value,
+    pub time: i64,
+    pub time_end: i64,
+    pub is_region: bool,
+    pub title: String,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+/// Query of `GET /api/grafana/dashboard/{source}`.
+#[derive(Debug, Deserialize)]
+pub struct DashboardQuery {
+    /// Restricts every panel's query to this Prometheus job.
+    #[serde(default)]
+    pub job: Option<String>,
+}
+
+/// Query of `GET /api/export`.
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Only the series of this exposed name, all of them when unset.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// RFC 3339 bounds of the snapshots exported, both inclusive.
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CardinalityQuery {
+    #[serde(default = "default_cardinality_limit")]
+    pub limit: usize,
+}
+
+fn default_cardinality_limit() -> usize {
+    10
+}
+
+/// Body of `POST /api/admin/silences`.
+#[derive(Debug, Deserialize)]
+pub struct SilenceRequest {
+    /// Selector over the alert labels, e.g. `anomaly{service="checkout"}`.
+    pub matchers: String,
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub comment: String,
+}
+
+/// Body of `PUT /api/admin/mode`.
+#[derive(Debug, Deserialize)]
+pub struct ModeRequest {
+    pub mode: OperatingMode,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModeResponse {
+    pub mode: OperatingMode,
+    /// Asynchronous batches still being processed, which a drain waits for.
+    pub running_jobs: usize,
+}
+
+/// Body of `POST /api/admin/tombstone`.
+#[derive(Debug, Deserialize)]
+pub struct TombstoneRequest {
+    /// Series selectors over the exposed names and labels, e.g.
+    /// `app_metrics_server_requests{instance="decommissioned"}`.
+    pub matchers: Vec<String>,
+    /// Overrides `metrics.tombstone_grace_secs`.
+    #[serde(default)]
+    pub grace_secs: Option<u64>,
+}
+
+/// Answer of `POST /api/metrics/normalize`: the batch as it would be ingested.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NormalizeResponse {
+    pub source: String,
+    pub metrics: Vec<NormalizedMetric>,
+    /// Metrics that would be rejected, prefixed with their position in the batch.
+    pub errors: Vec<MetricError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NormalizedMetric {
+    /// The family name in the exposition, with the prefix and namespace.
+    pub exposed_name: String,
+    #[serde(flatten)]
+    pub metric: Metric,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobAccepted {
+    pub job_id: String,
+    pub status_url: String,
+}
+
+/// Answer to a batch only delivered to fan-out destinations, none of them
+/// the registry.
+#[derive(Debug, Serialize)]
+pub struct BatchQueued {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub sinks: BTreeMap<String, Delivery>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatusResponse {
     pub status: String,
     pub metrics_count: usize,
     pub uptime_seconds: u64,
     pub start_time: String,
+    pub version: String,
+    pub build: BuildInfo,
+    /// The `RUN_MODE` the configuration was loaded for.
+    pub config_profile: String,
+    /// Addresses the HTTP server accepts connections on.
+    pub listen_addresses: Vec<String>,
+}
+
+/// The configuration in effect, as served by `/api/admin/config`.
+#[derive(Debug, Serialize)]
+pub struct EffectiveConfigResponse {
+    /// The `RUN_MODE` the configuration was loaded for.
+    pub profile: String,
+    /// With secrets redacted.
+    pub config: serde_json::Value,
+    /// Where each value came from, keyed by dotted path: `file:<path>`, `env`
+    /// or `default`. Lists are keyed as a whole.
+    pub sources: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusHistoryResponse {
+    pub start_time: String,
+    pub uptime_seconds: u64,
+    /// The current run's uptime plus that of the past runs listed.
+    pub cumulative_uptime_seconds: u64,
+    /// Whether past runs survive restarts, i.e. `uptime` is configured.
+    pub persisted: bool,
+    /// Most recent first.
+    pub restarts: Vec<RestartRecord>,
+}
+
+/// A past run, ended by a clean shutdown or a crash.
+#[derive(Debug, Serialize)]
+pub struct RestartRecord {
+    pub started_at: DateTime<Utc>,
+    pub stopped_at: DateTime<Utc>,
+    pub uptime_seconds: u64,
+    pub reason: StopReason,
 }
 
 pub trait Validate {
-    fn validate(&self) -> Result<(), ServerError>;
+    fn validate(&self, config: &MetricsConfig) -> Result<(), ServerError>;
 }
 
 impl Validate for Metric {
-    fn validate(&self) -> Result<(), ServerError> {
-        if self.name.is_empty() {
-            return Err(ServerError::ValidationError(
-                "Metric name cannot be empty".to_string(),
-            ));
+    fn validate(&self, config: &MetricsConfig) -> Result<(), ServerError> {
+        check_metric_name(&self.name, config)?;
+        for key in self.labels.keys() {
+            check_label_name(key)?;
         }
+        check_metric_contents(self)
+    }
+}
 
-        // name should contain only alphanumeric characters, underscores, and colons
-        if !self
-            .name
-            .chars()
-            .all(|c| c.is_alphanumeric() || c == '_' || c == ':')
-        {
-            return Err(ServerError::ValidationError(
-                "Metric name must contain only alphanumeric characters, underscores, and colons"
-                    .to_string(),
-            ));
-        }
+fn check_metric_name(name: &str, config: &MetricsConfig) -> Result<(), ServerError> {
+    if name.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Metric name cannot be empty".to_string(),
+        ));
+    }
 
-        if self.help.is_empty() {
-            return Err(ServerError::ValidationError(
-                "Help text cannot be empty".to_string(),
-            ));
-        }
+    // Unless UTF-8 names are enabled, the name should contain only alphanumeric
+    // characters, underscores, and colons
+    if config.utf8_names {
+        validate_utf8_metric_name(name)?;
+    } else if !is_plain_name(name, &['_', ':']) {
+        return Err(ServerError::ValidationError(
+            "Metric name must contain only alphanumeric characters, underscores, and colons"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
 
-        for (key, _value) in &self.labels {
-            if key.is_empty() {
-                return Err(ServerError::ValidationError(
-                    "Label name cannot be empty".to_string(),
-                ));
-            }
+fn check_label_name(key: &str) -> Result<(), ServerError> {
+    if key.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Label name cannot be empty".to_string(),
+        ));
+    }
 
-            if !key.chars().all(|c| c.is_alphanumeric() || c == '_') {
-                return Err(ServerError::ValidationError(
-                    "Label names must contain only alphanumeric characters and underscores"
-                        .to_string(),
-                ));
-            }
+    if !is_plain_name(key, &['_']) {
+        return Err(ServerError::ValidationError(
+            "Label names must contain only alphanumeric characters and underscores".to_string(),
+        ));
+    }
 
-            // Prometheus has some reserved label names
-            if key == "le" || key == "quantile" {
-                return Err(ServerError::ValidationError(format!(
-                    "'{}' is a reserved label name",
-                    key
-                )));
-            }
+    // Prometheus has some reserved label names
+    if is_reserved_label_name(key) {
+        return Err(ServerError::ValidationError(format!(
+            "'{}' is a reserved label name",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the name is made of alphanumeric characters, in any script, and
+/// the `extra` characters, in any order. ASCII names are checked byte by byte.
+fn is_plain_name(name: &str, extra: &[char]) -> bool {
+    if name.is_ascii() {
+        name.bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || extra.contains(&char::from(byte)))
+    } else {
+        name.chars()
+            .all(|c| c.is_alphanumeric() || extra.contains(&c))
+    }
+}
+
+/// Everything about a metric but its name and label names.
+fn check_metric_contents(metric: &Metric) -> Result<(), ServerError> {
+    if metric.help.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Help text cannot be empty".to_string(),
+        ));
+    }
+
+    let samples = metric.value.as_slice();
+    if samples.is_empty() {
+        return Err(ServerError::ValidationError(format!(
+            "Metric '{}' must have at least one sample",
+            metric.name
+        )));
+    }
+
+    for histogram in samples.iter().filter_map(|s| s.histogram.as_ref()) {
+        if metric.metric_type.registered_as() != MetricType::Histogram {
+            return Err(ServerError::ValidationError(format!(
+                "Bucket counts can only be pushed for histograms and timers, '{}' is a {:?}",
+                metric.name, metric.metric_type
+            )));
         }
+        histogram.validate().map_err(|e| {
+            ServerError::ValidationError(format!(
+                "Invalid bucket counts for '{}': {}",
+                metric.name, e
+            ))
+        })?;
+    }
 
-        Ok(())
+    if metric.metric_type == MetricType::Timer && samples.iter().any(|s| s.value < 0.0) {
+        return Err(ServerError::ValidationError(format!(
+            "Timer '{}' cannot record a negative duration",
+            metric.name
+        )));
+    }
+
+    // A stateset pushed with a value name gets its state label from the enum mapping
+    if metric.metric_type == MetricType::StateSet
+        && samples.iter().all(|s| s.enum_value.is_none())
+        && !metric.labels.contains_key(&metric.name)
+    {
+        return Err(ServerError::ValidationError(format!(
+            "Stateset '{}' needs a '{}' label holding the current state",
+            metric.name, metric.name
+        )));
     }
+
+    if metric.operation != GaugeOperation::Set && metric.metric_type != MetricType::Gauge {
+        return Err(ServerError::ValidationError(format!(
+            "Operation '{:?}' is only supported for gauges",
+            metric.operation
+        )));
+    }
+
+    Ok(())
 }
 
 impl Validate for MetricsBatch {
-    fn validate(&self) -> Result<(), ServerError> {
+    fn validate(&self, config: &MetricsConfig) -> Result<(), ServerError> {
         if self.source.is_empty() {
             return Err(ServerError::ValidationError(
                 "Source cannot be empty".to_string(),
@@ -89,30 +424,40 @@ impl Validate for MetricsBatch {
             ));
         }
 
+        // Batches repeat a handful of names and label names, check each once
+        let mut checked_names: HashSet<&str> = HashSet::new();
+        let mut checked_labels: HashSet<&str> = HashSet::new();
         for metric in &self.metrics {
-            metric.validate()?;
+            if checked_names.insert(&metric.name) {
+                check_metric_name(&metric.name, config)?;
+            }
+            for key in metric.labels.keys() {
+                if checked_labels.insert(key) {
+                    check_label_name(key)?;
+                }
+            }
+            check_metric_contents(metric)?;
         }
 
-        // There should be no duplicate metric names within the same set of labels
-        let mut seen_metrics = HashMap::new();
-        for metric in &self.metrics {
-            // Create a unique string key for this metric by combining name and sorted labels
-            let mut key = format!("{}:", metric.name);
-
-            let mut label_pairs: Vec<(&String, &String)> = metric.labels.iter().collect();
-            label_pairs.sort_by(|a, b| a.0.cmp(b.0));
-
-            for (k, v) in label_pairs {
-                key.push_str(&format!("{}={},", k, v));
-            }
+        // There should be no duplicate metric names within the same set of labels,
+        // which a single metric can't have
+        if self.metrics.len() > 1 {
+            let mut seen_metrics = HashSet::with_capacity(self.metrics.len());
+            for metric in &self.metrics {
+                let mut label_pairs: Vec<(&str, &str)> = metric
+                    .labels
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                label_pairs.sort_unstable();
 
-            if seen_metrics.contains_key(&key) {
-                return Err(ServerError::ValidationError(format!(
-                    "Duplicate metric found: {} with the same set of labels",
-                    metric.name
-                )));
+                if !seen_metrics.insert((metric.name.as_str(), label_pairs)) {
+                    return Err(ServerError::ValidationError(format!(
+                        "Duplicate metric found: {} with the same set of labels",
+                        metric.name
+                    )));
+                }
             }
-            seen_metrics.insert(key, true);
         }
 
         Ok(())
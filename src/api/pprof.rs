@@ -0,0 +1,214 @@
+//! Profiles of the running server under `/api/admin/debug/pprof`, to see
+//! where a gateway spends its CPU or memory during an incident: a CPU
+//! profile sampled over a few seconds, and jemalloc's heap stats and heap
+//! profile when it is the allocator.
+
+use crate::api::auth::require_admin_identity;
+use crate::errors::ServerError;
+use actix_web::{HttpRequest, HttpResponse, web};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{info, instrument};
+
+const MAX_PROFILE_SECS: u64 = 300;
+const MAX_FREQUENCY: i32 = 1_000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileFormat {
+    /// The protobuf read by `go tool pprof`.
+    #[default]
+    Pprof,
+    /// An SVG flamegraph.
+    Flamegraph,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    #[serde(default = "default_profile_seconds")]
+    pub seconds: u64,
+    /// Samples taken per second.
+    #[serde(default = "default_profile_frequency")]
+    pub frequency: i32,
+    #[serde(default)]
+    pub format: ProfileFormat,
+}
+
+fn default_profile_seconds() -> u64 {
+    10
+}
+
+fn default_profile_frequency() -> i32 {
+    99
+}
+
+/// Samples the stacks of every thread for `seconds`. Only one profile is
+/// taken at a time.
+#[instrument(skip(req))]
+pub async fn cpu_profile(
+    req: HttpRequest,
+    query: web::Query<ProfileQuery>,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    let ProfileQuery {
+        seconds,
+        frequency,
+        format,
+    } = query.into_inner();
+    if !(1..=MAX_PROFILE_SECS).contains(&seconds) {
+        return Err(ServerError::ValidationError(format!(
+            "seconds must be between 1 and {}",
+            MAX_PROFILE_SECS
+        )));
+    }
+    if !(1..=MAX_FREQUENCY).contains(&frequency) {
+        return Err(ServerError::ValidationError(format!(
+            "frequency must be between 1 and {}",
+            MAX_FREQUENCY
+        )));
+    }
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| ServerError::Unavailable {
+            message: format!("Can't start a CPU profile: {}", e),
+            retry_after_secs: seconds,
+        })?;
+    info!("Taking a {}s CPU profile at {}Hz", seconds, frequency);
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    let report = guard
+        .report()
+        .build()
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+    drop(guard);
+
+    let mut body = Vec::new();
+    match format {
+        ProfileFormat::Pprof => {
+            use pprof::protos::Message;
+            let profile = report
+                .pprof()
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            profile
+                .encode(&mut body)
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header(("Content-Disposition", "attachment; filename=\"profile.pb\""))
+                .body(body))
+        }
+        ProfileFormat::Flamegraph => {
+            report
+                .flamegraph(&mut body)
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            Ok(HttpResponse::Ok().content_type("image/svg+xml").body(body))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeapFormat {
+    /// jemalloc's stats as JSON.
+    #[default]
+    Stats,
+    /// A heap profile read by `jeprof`, when jemalloc profiling is on.
+    Jeprof,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeapQuery {
+    #[serde(default)]
+    pub format: HeapFormat,
+}
+
+/// jemalloc's heap stats or heap profile, when built with the `jemalloc`
+/// feature.
+#[instrument(skip(req))]
+pub async fn heap_profile(
+    req: HttpRequest,
+    query: web::Query<HeapQuery>,
+) -> Result<HttpResponse, ServerError> {
+    require_admin_identity(&req)?;
+    #[cfg(feature = "jemalloc")]
+    match query.format {
+        HeapFormat::Stats => Ok(HttpResponse::Ok().json(jemalloc::stats()?)),
+        HeapFormat::Jeprof => {
+            let profile = web::block(jemalloc::dump_profile)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))??;
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header(("Content-Disposition", "attachment; filename=\"heap.prof\""))
+                .body(profile))
+        }
+    }
+    #[cfg(not(feature = "jemalloc"))]
+    {
+        let _ = query;
+        Err(ServerError::NotFound(
+            "Heap stats require building with the `jemalloc` feature".to_string(),
+        ))
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+mod jemalloc {
+    use super::ServerError;
+    use serde::Serialize;
+    use std::ffi::{CString, c_char};
+    use tikv_jemalloc_ctl::{epoch, profiling, raw, stats};
+
+    /// Bytes as counted by jemalloc, see its `stats.*` documentation.
+    #[derive(Debug, Serialize)]
+    pub(super) struct HeapStats {
+        allocated: usize,
+        active: usize,
+        metadata: usize,
+        resident: usize,
+        mapped: usize,
+        retained: usize,
+        /// Whether heap profiles can be dumped, i.e. jemalloc was started
+        /// with `prof:true` in `_RJEM_MALLOC_CONF`.
+        profiling: bool,
+    }
+
+    fn fail(e: tikv_jemalloc_ctl::Error) -> ServerError {
+        ServerError::InternalError(format!("jemalloc: {}", e).into())
+    }
+
+    pub(super) fn stats() -> Result<HeapStats, ServerError> {
+        // The stats are cached until the epoch advances
+        epoch::advance().map_err(fail)?;
+        Ok(HeapStats {
+            allocated: stats::allocated::read().map_err(fail)?,
+            active: stats::active::read().map_err(fail)?,
+            metadata: stats::metadata::read().map_err(fail)?,
+            resident: stats::resident::read().map_err(fail)?,
+            mapped: stats::mapped::read().map_err(fail)?,
+            retained: stats::retained::read().map_err(fail)?,
+            profiling: profiling::prof::read().map_err(fail)?,
+        })
+    }
+
+    pub(super) fn dump_profile() -> Result<Vec<u8>, ServerError> {
+        if !profiling::prof::read().map_err(fail)? {
+            return Err(ServerError::ValidationError(
+                "jemalloc profiling is off, start the server with _RJEM_MALLOC_CONF=prof:true"
+                    .to_string(),
+            ));
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("rustic-insights-{}.heap", uuid::Uuid::new_v4()));
+        let c_path = CString::new(path.to_string_lossy().into_owned())
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        // SAFETY: `prof.dump` takes a NUL-terminated path, which outlives the call
+        unsafe { raw::write(b"prof.dump\0", c_path.as_ptr() as *const c_char) }.map_err(fail)?;
+        let profile = std::fs::read(&path).map_err(|e| ServerError::InternalError(Box::new(e)));
+        let _ = std::fs::remove_file(&path);
+        profile
+    }
+}
@@ -1,12 +1,186 @@
-use crate::api::handlers::{health_check, ingest_metrics, metrics, status};
-use actix_web::web;
+use crate::api::format::{RequestFormat, json_error_handler};
+#[cfg(feature = "graphql")]
+use crate::api::graphql::graphql;
+use crate::api::handlers::{
+    anomalies, cardinality, clear_lockout, clear_lockouts, create_annotation, create_silence,
+    create_tombstone, delete_silence, delete_source, effective_config, export_history, federate,
+    grafana_annotations, group_metrics, health_check, ingest_collectd, ingest_metrics,
+    ingest_metrics_v2, ingest_ndjson, ingest_prometheus, job_status, list_annotations,
+    list_lockouts, list_metadata, list_schemas, list_series, list_silences, list_sources,
+    list_tombstones, liveness, metric_metadata, metrics, normalize_metrics, normalize_metrics_v2,
+    operating_mode, preview_report, readiness, register_schema, register_source, send_report,
+    service_discovery, slo_summary, source_dashboard, source_metrics, status, status_history, topk,
+    update_metadata, update_mode, webhook_deliveries,
+};
+#[cfg(feature = "chaos")]
+use crate::api::handlers::{chaos_settings, update_chaos};
+#[cfg(feature = "pprof")]
+use crate::api::pprof::{cpu_profile, heap_profile};
+use actix_web::{guard, middleware, web};
+
+/// Request header selecting the API version on the unversioned `/api` routes.
+pub const API_VERSION_HEADER: &str = "API-Version";
+
+/// Largest text exposition accepted, decompressed; a node exporter's output
+/// is typically a few hundred KiB.
+const TEXT_BODY_LIMIT: usize = 16 * 1024 * 1024;
 
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::JsonConfig::default()
+            .content_type(|mime| RequestFormat::Json.matches(&mime))
+            .error_handler(json_error_handler),
+    );
+
+    // Registered ahead of the `/api` scope, which would otherwise claim the path
+    #[cfg(feature = "chaos")]
+    cfg.service(
+        web::resource("/api/admin/chaos")
+            .route(web::get().to(chaos_settings))
+            .route(web::put().to(update_chaos)),
+    );
+    #[cfg(feature = "graphql")]
+    cfg.route("/api/graphql", web::post().to(graphql));
+    #[cfg(feature = "pprof")]
+    cfg.route("/api/admin/debug/pprof/profile", web::get().to(cpu_profile))
+        .route("/api/admin/debug/pprof/heap", web::get().to(heap_profile));
+
+    cfg.service(
+        web::resource("/api/admin/silences")
+            .route(web::get().to(list_silences))
+            .route(web::post().to(create_silence)),
+    )
+    .route("/api/admin/silences/{id}", web::delete().to(delete_silence));
+
+    cfg.route("/api/admin/config", web::get().to(effective_config));
+
+    cfg.service(
+        web::resource("/api/admin/lockouts")
+            .route(web::get().to(list_lockouts))
+            .route(web::delete().to(clear_lockouts)),
+    )
+    .route("/api/admin/lockouts/{key}", web::delete().to(clear_lockout));
+
+    cfg.service(
+        web::resource("/api/admin/mode")
+            .route(web::get().to(operating_mode))
+            .route(web::put().to(update_mode)),
+    );
+
+    cfg.service(
+        web::resource("/api/admin/tombstone")
+            .route(web::get().to(list_tombstones))
+            .route(web::post().to(create_tombstone)),
+    );
+
     cfg.service(
+        web::resource("/api/admin/sources")
+            .route(web::get().to(list_sources))
+            .route(web::post().to(register_source)),
+    )
+    .route(
+        "/api/admin/sources/{source}",
+        web::delete().to(delete_source),
+    );
+
+    cfg.route("/api/admin/reports/{name}", web::get().to(preview_report))
+        .route(
+            "/api/admin/reports/{name}/send",
+            web::post().to(send_report),
+        );
+
+    cfg.route(
+        "/api/admin/webhooks/deliveries",
+        web::get().to(webhook_deliveries),
+    );
+
+    cfg.service(
+        web::scope("/api/v1")
+            .configure(v1_routes)
+            .wrap(version_header("1")),
+    )
+    .service(
+        web::scope("/api/v2")
+            .configure(v2_routes)
+            .wrap(version_header("2")),
+    )
+    // Unversioned routes serve v1, unless the client asks for v2 with the header
+    .service(
         web::scope("/api")
-            .route("/health", web::get().to(health_check))
-            .route("/status", web::get().to(status))
-            .route("/metrics", web::post().to(ingest_metrics)),
+            .route(
+                "/metrics",
+                web::post()
+                    .guard(guard::Header(API_VERSION_HEADER, "2"))
+                    .to(ingest_metrics_v2),
+            )
+            .route(
+                "/metrics/normalize",
+                web::post()
+                    .guard(guard::Header(API_VERSION_HEADER, "2"))
+                    .to(normalize_metrics_v2),
+            )
+            .configure(v1_routes),
+    )
+    .service(
+        web::resource("/metrics")
+            .route(web::get().to(metrics))
+            .route(web::head().to(metrics)),
     )
-    .route("/metrics", web::get().to(metrics));
+    .route("/metrics/source/{source}", web::get().to(source_metrics))
+    .route("/metrics/group/{name}", web::get().to(group_metrics))
+    .route("/federate", web::get().to(federate));
+}
+
+fn version_header(version: &'static str) -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new().add((API_VERSION_HEADER, version))
+}
+
+fn v1_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::post().to(ingest_metrics))
+        .route("/metrics/normalize", web::post().to(normalize_metrics))
+        .configure(common_routes);
+}
+
+fn v2_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::post().to(ingest_metrics_v2))
+        .route("/metrics/normalize", web::post().to(normalize_metrics_v2))
+        .configure(common_routes);
+}
+
+/// Routes whose contract is the same in every API version.
+fn common_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(list_series))
+        .route("/health", web::get().to(health_check))
+        .route("/health/live", web::get().to(liveness))
+        .route("/health/ready", web::get().to(readiness))
+        .route("/status", web::get().to(status))
+        .route("/status/history", web::get().to(status_history))
+        .route("/export", web::get().to(export_history))
+        .route("/sd", web::get().to(service_discovery))
+        .route("/annotations", web::get().to(list_annotations))
+        .route("/annotations", web::post().to(create_annotation))
+        // Grafana's JSON data source checks its URL answers, then queries
+        .route("/grafana", web::get().to(liveness))
+        .route("/grafana/annotations", web::post().to(grafana_annotations))
+        .route(
+            "/grafana/dashboard/{source}",
+            web::get().to(source_dashboard),
+        )
+        .route("/cardinality", web::get().to(cardinality))
+        .route("/topk/{metric}", web::get().to(topk))
+        .route("/anomalies", web::get().to(anomalies))
+        .route("/slo", web::get().to(slo_summary))
+        .route("/jobs/{id}", web::get().to(job_status))
+        .route("/schemas", web::get().to(list_schemas))
+        .route("/schemas", web::post().to(register_schema))
+        .route("/metadata", web::get().to(list_metadata))
+        .route("/metadata/{name}", web::get().to(metric_metadata))
+        .route("/metadata/{name}", web::post().to(update_metadata))
+        .route("/ingest/collectd", web::post().to(ingest_collectd))
+        .route("/ingest/ndjson", web::post().to(ingest_ndjson))
+        .service(
+            web::resource("/ingest/prometheus")
+                .app_data(web::PayloadConfig::new(TEXT_BODY_LIMIT))
+                .route(web::post().to(ingest_prometheus)),
+        );
 }
@@ -0,0 +1,194 @@
+use crate::api::auth::Identity;
+use crate::api::basic_auth::BasicAuth;
+use crate::config::ScrapeConfig;
+use crate::errors::ServerError;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header::{self, Encoding};
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
+
+/// Size of the chunks a scrape body is handed to the connection in, so a
+/// stalled scraper only keeps a chunk's worth of the body buffered on top of
+/// the shared exposition.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The `server.scrape` limits, shared by every scrape.
+pub struct ScrapeGuard {
+    permits: Option<Arc<Semaphore>>,
+    write_timeout: Option<Duration>,
+    gzip: bool,
+    auth: Option<BasicAuth>,
+    /// The last gzipped body with its ETag, as most scrapes get the same one
+    compressed: Mutex<Option<(String, Bytes)>>,
+}
+
+/// A scrape's place among `max_concurrent`, released when its body is dropped.
+pub struct ScrapeSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ScrapeGuard {
+    pub fn new(config: &ScrapeConfig) -> Self {
+        Self {
+            permits: config
+                .max_concurrent
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            write_timeout: (config.write_timeout_ms > 0)
+                .then(|| Duration::from_millis(config.write_timeout_ms)),
+            gzip: config.gzip,
+            auth: BasicAuth::new(&config.basic_auth_users),
+            compressed: Mutex::new(None),
+        }
+    }
+
+    /// Checks the scraper's credentials when `basic_auth_users` are set,
+    /// unless `auth` already identified it.
+    pub async fn authenticate(&self, req: &HttpRequest) -> Result<(), ServerError> {
+        if req.extensions().contains::<Identity>() {
+            return Ok(());
+        }
+        match &self.auth {
+            Some(auth) => auth.authenticate(req).await,
+            None => Ok(()),
+        }
+    }
+
+    /// A slot for a new scrape, or a 503 when `max_concurrent` are being sent.
+    pub fn acquire(&self) -> Result<ScrapeSlot, ServerError> {
+        let Some(permits) = &self.permits else {
+            return Ok(ScrapeSlot { _permit: None });
+        };
+        permits
+            .clone()
+            .try_acquire_owned()
+            .map(|permit| ScrapeSlot {
+                _permit: Some(permit),
+            })
+            .map_err(|_| ServerError::Unavailable {
+                message: "too many concurrent scrapes".to_string(),
+                retry_after_secs: 1,
+            })
+    }
+
+    /// Whether the body is sent gzipped to this scraper.
+    pub fn wants_gzip(&self, req: &HttpRequest) -> bool {
+        self.gzip
+            && req
+                .get_header::<header::AcceptEncoding>()
+                .and_then(|accept| {
+                    accept.negotiate([Encoding::gzip(), Encoding::identity()].iter())
+                })
+                == Some(Encoding::gzip())
+    }
+
+    /// The gzipped body, compressed only when its ETag differs from the last one.
+    pub fn gzip(&self, etag: &str, body: &[u8]) -> Result<Bytes, ServerError> {
+        if let Ok(cached) = self.compressed.lock()
+            && let Some((cached_etag, compressed)) = cached.as_ref()
+            && cached_etag == etag
+        {
+            return Ok(compressed.clone());
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(body)
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        let compressed = Bytes::from(
+            encoder
+                .finish()
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?,
+        );
+        if let Ok(mut cached) = self.compressed.lock() {
+            *cached = Some((etag.to_string(), compressed.clone()));
+        }
+        Ok(compressed)
+    }
+
+    /// The body of a scrape, holding `slot` until it's sent or abandoned,
+    /// or until the write timeout has passed.
+    pub fn body(&self, body: Bytes, slot: ScrapeSlot) -> ScrapeBody {
+        let held = slot._permit.is_some();
+        let slot = Arc::new(Mutex::new(Some(slot)));
+
+        // A scraper that stopped reading stalls the connection, which then
+        // doesn't poll the body anymore, so the slot is also released on a
+        // timer of its own
+        if let Some(timeout) = self.write_timeout
+            && held
+        {
+            let timed = Arc::downgrade(&slot);
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                if let Some(slot) = timed.upgrade()
+                    && let Ok(mut slot) = slot.lock()
+                {
+                    slot.take();
+                }
+            });
+        }
+
+        ScrapeBody {
+            body,
+            deadline: self
+                .write_timeout
+                .map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            slot,
+        }
+    }
+}
+
+/// A scrape body handed out in chunks, failing once the write timeout has
+/// passed so the connection of a scraper that stopped reading is closed.
+pub struct ScrapeBody {
+    body: Bytes,
+    deadline: Option<Pin<Box<Sleep>>>,
+    /// Released when the body is dropped, or by the write timeout's timer.
+    slot: Arc<Mutex<Option<ScrapeSlot>>>,
+}
+
+impl Drop for ScrapeBody {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.slot.lock() {
+            slot.take();
+        }
+    }
+}
+
+impl MessageBody for ScrapeBody {
+    type Error = io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.body.len() as u64)
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        if this.body.is_empty() {
+            return Poll::Ready(None);
+        }
+        if let Some(deadline) = this.deadline.as_mut()
+            && deadline.as_mut().poll(cx).is_ready()
+        {
+            return Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "scraper didn't read the exposition within the write timeout",
+            ))));
+        }
+        let len = this.body.len().min(CHUNK_SIZE);
+        Poll::Ready(Some(Ok(this.body.split_to(len))))
+    }
+}
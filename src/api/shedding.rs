@@ -0,0 +1,164 @@
+use crate::api::handlers::AppState;
+use crate::config::{ConcurrencyLimit, LoadSheddingConfig};
+use crate::errors::ServerError;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+/// The classes of routes limited independently of each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    Ingest,
+    Scrape,
+    Admin,
+}
+
+impl RouteClass {
+    /// The class of a request, `None` for the routes that are never shed,
+    /// such as health checks.
+    pub fn of(method: &Method, path: &str) -> Option<Self> {
+        if is_exposition(path) {
+            return Some(RouteClass::Scrape);
+        }
+
+        let api_path = path.strip_prefix("/api")?;
+        if api_path.starts_with("/admin/") || api_path == "/admin" {
+            return Some(RouteClass::Admin);
+        }
+        // The same routes are served with and without a version prefix
+        let api_path = ["/v1", "/v2"]
+            .iter()
+            .find_map(|version| api_path.strip_prefix(version))
+            .unwrap_or(api_path);
+        // Queries are posted, but read the registry as scrapes do
+        if api_path == "/graphql" && method == Method::POST {
+            return Some(RouteClass::Scrape);
+        }
+        let ingest = api_path == "/metrics"
+            || api_path == "/metrics/normalize"
+            || api_path.starts_with("/ingest/");
+        (ingest && method == Method::POST).then_some(RouteClass::Ingest)
+    }
+
+    /// The class of the route a request reaches, see [`routed_path`].
+    pub fn of_request(req: &ServiceRequest) -> Option<Self> {
+        Self::of(req.method(), routed_path(req))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteClass::Ingest => "ingest",
+            RouteClass::Scrape => "scrape",
+            RouteClass::Admin => "admin",
+        }
+    }
+}
+
+/// Whether `path` serves the exposition to scrapers.
+pub fn is_exposition(path: &str) -> bool {
+    matches!(path, "/metrics" | "/federate")
+        || path.starts_with("/metrics/source/")
+        || path.starts_with("/metrics/group/")
+}
+
+/// The path a request is routed on: percent-decoded as the router does, so
+/// `/api/%61dmin/config` is classified as the `/api/admin/config` it reaches.
+pub fn routed_path(req: &ServiceRequest) -> &str {
+    req.match_info().as_str()
+}
+
+struct Limiter {
+    permits: Arc<Semaphore>,
+    queue_timeout: Duration,
+}
+
+impl Limiter {
+    fn new(limit: &ConcurrencyLimit) -> Option<Self> {
+        Some(Self {
+            permits: Arc::new(Semaphore::new(limit.max_concurrent?)),
+            queue_timeout: Duration::from_millis(limit.queue_timeout_ms),
+        })
+    }
+
+    /// A slot, waiting up to the queue timeout for one to free up.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if self.queue_timeout.is_zero() {
+            return self.permits.clone().try_acquire_owned().ok();
+        }
+        tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+/// The concurrency limits of `server.load_shedding`.
+pub struct LoadShedder {
+    ingest: Option<Limiter>,
+    scrape: Option<Limiter>,
+    admin: Option<Limiter>,
+}
+
+impl LoadShedder {
+    pub fn new(config: &LoadSheddingConfig) -> Self {
+        Self {
+            ingest: Limiter::new(&config.ingest),
+            scrape: Limiter::new(&config.scrape),
+            admin: Limiter::new(&config.admin),
+        }
+    }
+
+    fn limiter(&self, class: RouteClass) -> Option<&Limiter> {
+        match class {
+            RouteClass::Ingest => self.ingest.as_ref(),
+            RouteClass::Scrape => self.scrape.as_ref(),
+            RouteClass::Admin => self.admin.as_ref(),
+        }
+    }
+}
+
+/// Middleware holding each request to its route class's concurrency limit,
+/// answering with a 503 once no slot frees up within the queue timeout.
+/// Wrap it inside `propagate_request_id` so shed requests carry the request ID.
+pub async fn shed_load(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<Arc<AppState>>>().cloned() else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(class) = RouteClass::of_request(&req) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(limiter) = state.load_shedder.limiter(class) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let Some(_permit) = limiter.acquire().await else {
+        warn!(
+            "Shedding {} {}: too many concurrent {} requests",
+            req.method(),
+            req.path(),
+            class.as_str()
+        );
+        state
+            .metrics_collector
+            .self_metrics()
+            .shed_requests_total
+            .with_label_values(&[class.as_str()])
+            .inc();
+        let error = ServerError::Unavailable {
+            message: format!("too many concurrent {} requests", class.as_str()),
+            retry_after_secs: 1,
+        };
+        return Ok(req.error_response(error).map_into_right_body());
+    };
+
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
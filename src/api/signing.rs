@@ -0,0 +1,231 @@
+//! HMAC signatures of pushed batches. A signed push carries
+//! `X-Signature: source=<source>,t=<unix seconds>,nonce=<nonce>,v1=<hex>`,
+//! where `v1` is the HMAC-SHA256 of `<t>.<nonce>.<body>` under the source's
+//! secret, computed over the body as sent, before any `Content-Encoding`.
+
+use crate::api::handlers::AppState;
+use crate::api::shedding::RouteClass;
+use crate::config::SigningConfig;
+use crate::errors::ServerError;
+use crate::utils::hex;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, web};
+use futures::StreamExt;
+use ring::hmac;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Signed bodies are buffered to be verified before they're parsed, streamed
+/// ones included.
+const MAX_SIGNED_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// The source a push was signed for, added to its extensions once verified.
+#[derive(Debug, Clone)]
+pub struct SignedSource(pub String);
+
+/// The parts of an `X-Signature` header.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub source: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    pub mac: Vec<u8>,
+}
+
+impl Signature {
+    pub fn parse(header: &str) -> Result<Self, ServerError> {
+        let invalid = |message: &str| {
+            ServerError::Unauthorized(format!("invalid {} header: {}", SIGNATURE_HEADER, message))
+        };
+        let mut fields = HashMap::new();
+        for field in header.split(',') {
+            let (name, value) = field
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| invalid("expected name=value fields"))?;
+            fields.insert(name, value);
+        }
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| invalid(&format!("missing {}", name)))
+        };
+
+        Ok(Self {
+            source: field("source")?.to_string(),
+            timestamp: field("t")?
+                .parse()
+                .map_err(|_| invalid("t is not a timestamp"))?,
+            nonce: field("nonce")?.to_string(),
+            mac: hex::decode(field("v1")?).ok_or_else(|| invalid("v1 is not hex"))?,
+        })
+    }
+
+    /// The `X-Signature` header of `body`, for clients and tests.
+    pub fn sign(source: &str, secret: &str, timestamp: i64, nonce: &str, body: &[u8]) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let mac = hmac::sign(&key, &signed_message(timestamp, nonce, body));
+        format!(
+            "source={},t={},nonce={},v1={}",
+            source,
+            timestamp,
+            nonce,
+            hex::encode(mac.as_ref())
+        )
+    }
+}
+
+fn signed_message(timestamp: i64, nonce: &str, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}.{}.", timestamp, nonce).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+/// The secrets of `server.signing` and the nonces seen recently.
+pub struct SignatureVerifier {
+    keys: BTreeMap<String, hmac::Key>,
+    required: bool,
+    max_age_secs: i64,
+    /// Nonces by source, with the last second their signature's timestamp is
+    /// accepted at; they're forgotten after it, when it's too old anyway.
+    nonces: Mutex<HashMap<(String, String), i64>>,
+}
+
+impl SignatureVerifier {
+    pub fn new(config: &SigningConfig) -> Self {
+        Self {
+            keys: config
+                .secrets
+                .iter()
+                .map(|(source, secret)| {
+                    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+                    (source.clone(), key)
+                })
+                .collect(),
+            required: config.required,
+            max_age_secs: config.max_age_secs.try_into().unwrap_or(i64::MAX),
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether pushes from `source` must be signed.
+    pub fn requires(&self, source: &str) -> bool {
+        self.required || self.keys.contains_key(source)
+    }
+
+    /// Checks `body` was signed with the source's secret, recently and only
+    /// once.
+    pub fn verify(&self, signature: &Signature, body: &[u8], now: i64) -> Result<(), ServerError> {
+        let key = self.keys.get(&signature.source).ok_or_else(|| {
+            ServerError::Unauthorized(format!(
+                "no signing secret for source '{}'",
+                signature.source
+            ))
+        })?;
+        if signature.timestamp.abs_diff(now) > self.max_age_secs.unsigned_abs() {
+            return Err(ServerError::Unauthorized(format!(
+                "signature timestamp is more than {}s away from the server's clock",
+                self.max_age_secs
+            )));
+        }
+        let message = signed_message(signature.timestamp, &signature.nonce, body);
+        hmac::verify(key, &message, &signature.mac)
+            .map_err(|_| ServerError::Unauthorized("signature mismatch".to_string()))?;
+
+        // Only verified nonces are remembered, so forged pushes can't fill the map
+        let mut nonces = self.nonces.lock().unwrap_or_else(|e| e.into_inner());
+        nonces.retain(|_, accepted_until| *accepted_until >= now);
+        let nonce = (signature.source.clone(), signature.nonce.clone());
+        if nonces.contains_key(&nonce) {
+            return Err(ServerError::Unauthorized(format!(
+                "nonce '{}' was already used",
+                signature.nonce
+            )));
+        }
+        nonces.insert(nonce, signature.timestamp.saturating_add(self.max_age_secs));
+        Ok(())
+    }
+
+    /// Checks a push for `source` was signed for it when it has to be.
+    pub fn check_source(&self, req: &HttpRequest, source: &str) -> Result<(), ServerError> {
+        match req.extensions().get::<SignedSource>() {
+            Some(signed) if signed.0 == source => Ok(()),
+            Some(signed) => Err(ServerError::Unauthorized(format!(
+                "batch for source '{}' signed as '{}'",
+                source, signed.0
+            ))),
+            None if self.requires(source) => Err(ServerError::Unauthorized(format!(
+                "pushes from '{}' must be signed in {}",
+                source, SIGNATURE_HEADER
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Middleware verifying the signature of pushes before their body is
+/// parsed. The handlers then check the batch is for the source it was
+/// signed for.
+pub async fn verify_signatures(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<Arc<AppState>>>().cloned() else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let Some(verifier) = &state.signatures else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    if RouteClass::of_request(&req) != Some(RouteClass::Ingest) {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    }
+
+    let header = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().to_string());
+    let Some(header) = header else {
+        if verifier.required {
+            let e = ServerError::Unauthorized(format!("{} header required", SIGNATURE_HEADER));
+            return Ok(req.error_response(e).map_into_right_body());
+        }
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let signature = match Signature::parse(&header) {
+        Ok(signature) => signature,
+        Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+    };
+    let body = match read_body(&mut req).await {
+        Ok(body) => body,
+        Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+    };
+    if let Err(e) = verifier.verify(&signature, &body, chrono::Utc::now().timestamp()) {
+        return Ok(req.error_response(e).map_into_right_body());
+    }
+
+    req.extensions_mut().insert(SignedSource(signature.source));
+    req.set_payload(body.into());
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
+
+async fn read_body(req: &mut ServiceRequest) -> Result<web::Bytes, ServerError> {
+    let mut payload = req.parts_mut().1.take();
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ServerError::ValidationError(e.to_string()))?;
+        if body.len() + chunk.len() > MAX_SIGNED_BODY_BYTES {
+            return Err(ServerError::ValidationError(format!(
+                "signed bodies are limited to {} bytes",
+                MAX_SIGNED_BODY_BYTES
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
@@ -0,0 +1,111 @@
+use crate::api::handlers::AppState;
+use crate::api::request_id::current_request_id;
+use crate::api::shedding::{RouteClass, routed_path};
+use crate::config::TimeoutConfig;
+use crate::errors::ServerError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{ResponseError, web};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Deadline and slow-request threshold applying to a request path; `None`
+/// means the limit is disabled.
+struct RouteLimits {
+    timeout: Option<Duration>,
+    slow: Option<Duration>,
+}
+
+/// Ingestion only gets a deadline from a route override: cancelling a batch
+/// half written would answer with a retryable 503, and the retry count the
+/// written half twice.
+fn limits_for(config: &TimeoutConfig, path: &str, ingest: bool) -> RouteLimits {
+    let route = config
+        .routes
+        .iter()
+        .filter(|route| path.starts_with(&route.path))
+        .max_by_key(|route| route.path.len());
+
+    let millis = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+    let default_timeout = if ingest { 0 } else { config.request_timeout_ms };
+    RouteLimits {
+        timeout: millis(
+            route
+                .and_then(|route| route.request_timeout_ms)
+                .unwrap_or(default_timeout),
+        ),
+        slow: millis(
+            route
+                .and_then(|route| route.slow_request_ms)
+                .unwrap_or(config.slow_request_ms),
+        ),
+    }
+}
+/// Middleware answering with a 503 once a handler exceeds its route's
+/// deadline, and logging requests slower than the route's slow threshold.
+/// Wrap it inside `propagate_request_id` so timeout errors carry the request ID.
+pub async fn enforce_timeouts(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(limits) = req.app_data::<web::Data<Arc<AppState>>>().map(|state| {
+        let ingest = RouteClass::of_request(&req) == Some(RouteClass::Ingest);
+        limits_for(&state.config.server.timeouts, routed_path(&req), ingest)
+    }) else {
+        return next.call(req).await;
+    };
+
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let started = Instant::now();
+
+    let response = match limits.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, next.call(req)).await {
+            Ok(response) => response?,
+            Err(_) => {
+                warn!(
+                    "{} {} timed out after {}ms",
+                    method,
+                    path,
+                    timeout.as_millis()
+                );
+                return Err(timeout_error(timeout));
+            }
+        },
+        None => next.call(req).await?,
+    };
+
+    let elapsed = started.elapsed();
+    if limits.slow.is_some_and(|slow| elapsed > slow) {
+        warn!(
+            "Slow request: {} {} took {}ms (status {})",
+            method,
+            path,
+            elapsed.as_millis(),
+            response.status()
+        );
+    }
+
+    Ok(response)
+}
+
+/// The abandoned handler still owns the request, so no `ServiceResponse` can
+/// be built here; the rendered 503 travels as an `InternalError` instead, with
+/// the request ID header `propagate_request_id` won't get a chance to add.
+fn timeout_error(timeout: Duration) -> actix_web::Error {
+    let error = ServerError::Timeout(format!(
+        "handler did not complete within {}ms",
+        timeout.as_millis()
+    ));
+    let mut response = error.error_response();
+    if let Some(value) = current_request_id().and_then(|id| HeaderValue::from_str(&id).ok()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+    InternalError::from_response(error, response).into()
+}
@@ -0,0 +1,56 @@
+//! What exactly was built, as reported by `/api/status`.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// `unknown` when built outside a git checkout without `GIT_COMMIT` set.
+    pub git_commit: String,
+    pub build_timestamp: String,
+    /// The optional cargo features compiled in.
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let build_timestamp = env!("RUSTIC_INSIGHTS_BUILD_TIMESTAMP")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+            .map(|timestamp| timestamp.to_rfc3339())
+            .unwrap_or_default();
+
+        Self {
+            git_commit: env!("RUSTIC_INSIGHTS_GIT_COMMIT").to_string(),
+            build_timestamp,
+            features: enabled_features().into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+fn enabled_features() -> Vec<&'static str> {
+    [
+        ("acme", cfg!(feature = "acme")),
+        ("amqp", cfg!(feature = "amqp")),
+        ("chaos", cfg!(feature = "chaos")),
+        ("email", cfg!(feature = "email")),
+        ("graphql", cfg!(feature = "graphql")),
+        ("jemalloc", cfg!(feature = "jemalloc")),
+        ("kafka", cfg!(feature = "kafka")),
+        ("nats", cfg!(feature = "nats")),
+        ("parquet", cfg!(feature = "parquet")),
+        ("pprof", cfg!(feature = "pprof")),
+        ("redis", cfg!(feature = "redis")),
+        ("rhai", cfg!(feature = "rhai")),
+        ("s3", cfg!(feature = "s3")),
+        ("server", cfg!(feature = "server")),
+        ("simd-json", cfg!(feature = "simd-json")),
+        ("test_support", cfg!(feature = "test_support")),
+        ("tls", cfg!(feature = "tls")),
+        ("wasm", cfg!(feature = "wasm")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
@@ -0,0 +1,215 @@
+//! Records the bodies received from each source as fixture files, before
+//! they are parsed, so a payload reported to break the server can be
+//! replayed with `replay` or loaded into an integration test with
+//! `test_support::load_fixtures`.
+
+use crate::config::CaptureConfig;
+use crate::encryption::Keyring;
+use crate::errors::ServerError;
+use crate::metrics::{MetricsBatch, MetricsBatchV2};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::OnceCell;
+
+/// The subdirectory of the bodies that didn't parse, which have no source.
+const UNPARSED_DIR: &str = "unparsed";
+
+/// One captured batch, in the format `replay` reads.
+#[derive(Serialize)]
+struct Fixture<'a> {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    batch: &'a MetricsBatch,
+}
+
+/// A batch as a JSON line `replay` reads, stamped with the current time.
+pub fn encode_line(batch: &MetricsBatch) -> Result<Vec<u8>, ServerError> {
+    let mut line = serde_json::to_vec(&Fixture {
+        timestamp: Utc::now(),
+        batch,
+    })?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// A request body to a batch ingestion endpoint as it was received, in the
+/// format `replay` reads.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedPayload {
+    pub timestamp: DateTime<Utc>,
+    /// The source the body names, when it parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The API version of the endpoint, which selects the batch format
+    pub version: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Decompressed, with invalid UTF-8 replaced
+    pub body: String,
+}
+
+impl CapturedPayload {
+    /// The batch the body holds, in the format of its API version.
+    pub fn batch(&self) -> Result<MetricsBatch, ServerError> {
+        Ok(match self.version {
+            2 => serde_json::from_str::<MetricsBatchV2>(&self.body)?.into(),
+            _ => serde_json::from_str(&self.body)?,
+        })
+    }
+}
+
+/// Writes the first `batches_per_source` bodies each source posts to
+/// `<dir>/<source>.<n>.json`, the source percent-encoded, and those that
+/// don't parse to `<dir>/unparsed/<n>.json`. Fixtures already in the
+/// directory count towards the limit, so restarts don't overwrite them.
+pub struct CaptureRecorder {
+    dir: PathBuf,
+    limit: usize,
+    keyring: Arc<Keyring>,
+    /// The fixtures written of each file stem, counted from the directory
+    /// on first use
+    counts: OnceCell<Mutex<HashMap<String, usize>>>,
+}
+
+impl CaptureRecorder {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.dir),
+            limit: config.batches_per_source,
+            keyring: Arc::default(),
+            counts: OnceCell::new(),
+        }
+    }
+
+    /// Seals each fixture with the key of the batch's source.
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    /// Captures a body of `source`, `None` when it didn't parse, unless it
+    /// already has enough fixtures, returning the path written.
+    pub async fn record(
+        &self,
+        source: Option<&str>,
+        version: u8,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<Option<PathBuf>, ServerError> {
+        let (dir, stem) = match source {
+            Some(source) => (self.dir.clone(), file_stem(source)),
+            None => (self.dir.join(UNPARSED_DIR), unparsed_stem()),
+        };
+        let counts = self
+            .counts
+            .get_or_try_init(|| async { self.existing().await.map(Mutex::new) })
+            .await?;
+        // Only numbering happens under the lock, not the writes
+        let number = {
+            let mut counts = counts.lock().unwrap_or_else(|e| e.into_inner());
+            let count = counts.entry(stem.clone()).or_default();
+            if *count >= self.limit {
+                return Ok(None);
+            }
+            *count += 1;
+            *count
+        };
+
+        let payload = CapturedPayload {
+            timestamp: Utc::now(),
+            source: source.map(str::to_string),
+            version,
+            content_type: content_type.map(str::to_string),
+            body: String::from_utf8_lossy(body).into_owned(),
+        };
+        let mut line = serde_json::to_vec(&payload)?;
+        line.push(b'\n');
+        let line = self.keyring.seal_line(source.unwrap_or_default(), line)?;
+        let name = match source {
+            Some(_) => format!("{}.{:04}.json", stem, number),
+            None => format!("{:04}.json", number),
+        };
+        let path = dir.join(name);
+        write_new(&dir, &path, &line)
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        Ok(Some(path))
+    }
+
+    /// The fixtures already in the directory, by file stem.
+    async fn existing(&self) -> Result<HashMap<String, usize>, ServerError> {
+        let mut counts = HashMap::new();
+        for (dir, unparsed) in [
+            (self.dir.clone(), false),
+            (self.dir.join(UNPARSED_DIR), true),
+        ] {
+            for name in file_names(&dir).await? {
+                let Some(stem) = name.strip_suffix(".json") else {
+                    continue;
+                };
+                let stem = match unparsed {
+                    true => unparsed_stem(),
+                    false => match stem.rsplit_once('.') {
+                        Some((stem, _)) => stem.to_string(),
+                        None => continue,
+                    },
+                };
+                *counts.entry(stem).or_default() += 1;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+async fn file_names(dir: &Path) -> Result<Vec<String>, ServerError> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(ServerError::InternalError(Box::new(e))),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?
+    {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+/// Counts the bodies that didn't parse, and can't be the stem of a source.
+fn unparsed_stem() -> String {
+    format!("{}/", UNPARSED_DIR)
+}
+
+/// Writes a file that must not exist yet, so a fixture is never overwritten.
+async fn write_new(dir: &Path, path: &Path, content: &[u8]) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    file.write_all(content).await?;
+    file.flush().await
+}
+
+/// The source as a file name: characters other than ASCII letters, digits,
+/// `-` and `_` are percent-encoded, so no two sources share a name.
+fn file_stem(source: &str) -> String {
+    let mut stem = String::with_capacity(source.len());
+    for byte in source.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            stem.push(byte as char);
+        } else {
+            let _ = write!(stem, "%{:02X}", byte);
+        }
+    }
+    stem
+}
@@ -0,0 +1,116 @@
+use crate::errors::ServerError;
+use crate::metrics::{MetricError, MetricsBatch, MetricsResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::warn;
+
+/// Failures injected into ingestion, so client retry logic can be exercised
+/// against a real gateway. Everything is off until set through `/api/admin/chaos`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChaosSettings {
+    /// Delay added before every ingestion request.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Probability, between 0 and 1, of failing a whole request with a 500.
+    #[serde(default)]
+    pub error_rate: f64,
+    /// Probability, between 0 and 1, of rejecting each metric of a batch.
+    #[serde(default)]
+    pub partial_failure_rate: f64,
+}
+
+#[derive(Default)]
+pub struct Chaos {
+    settings: RwLock<ChaosSettings>,
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn settings(&self) -> ChaosSettings {
+        self.settings
+            .read()
+            .map(|settings| settings.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, settings: ChaosSettings) -> Result<(), ServerError> {
+        for (field, rate) in [
+            ("error_rate", settings.error_rate),
+            ("partial_failure_rate", settings.partial_failure_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(ServerError::ValidationError(format!(
+                    "{} must be between 0 and 1, got {}",
+                    field, rate
+                )));
+            }
+        }
+
+        if settings != ChaosSettings::default() {
+            warn!("Injecting ingestion failures: {:?}", settings);
+        }
+        if let Ok(mut current) = self.settings.write() {
+            *current = settings;
+        }
+        Ok(())
+    }
+
+    /// Applies the configured latency to an ingestion request, then fails it
+    /// at the configured rate, returning the settings applied.
+    pub async fn disrupt(&self) -> Result<ChaosSettings, ServerError> {
+        let settings = self.settings();
+
+        if settings.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(settings.latency_ms)).await;
+        }
+
+        if fastrand::f64() < settings.error_rate {
+            return Err(ServerError::InternalError(
+                "chaos: injected ingestion failure".into(),
+            ));
+        }
+        Ok(settings)
+    }
+
+    /// Applies the configured latency and failures to a batch about to be
+    /// ingested. Dropped metrics are removed from the batch and returned as
+    /// errors for the response.
+    pub async fn inject(
+        &self,
+        mut batch: MetricsBatch,
+    ) -> Result<(MetricsBatch, Vec<MetricError>), ServerError> {
+        let settings = self.disrupt().await?;
+
+        let mut errors = Vec::new();
+        if settings.partial_failure_rate > 0.0 {
+            batch.metrics.retain(|metric| {
+                let keep = fastrand::f64() >= settings.partial_failure_rate;
+                if !keep {
+                    errors.push(MetricError::new(
+                        format!("chaos: injected failure for {}", metric.name),
+                        true,
+                    ));
+                }
+                keep
+            });
+        }
+
+        Ok((batch, errors))
+    }
+}
+
+/// Reports the metrics dropped by `Chaos::inject` as failed.
+pub fn with_injected_errors(
+    mut response: MetricsResponse,
+    injected: Vec<MetricError>,
+) -> MetricsResponse {
+    if !injected.is_empty() {
+        response.extend_errors(injected);
+        response.status = "partial_success".to_string();
+    }
+    response
+}
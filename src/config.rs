@@ -1,31 +1,1714 @@
+pub mod redact;
+pub mod secrets;
+
 use crate::errors::ServerError;
-use config::{Config, Environment, File};
-use serde::Deserialize;
+use crate::metrics::groups::compile_pattern;
+use crate::metrics::query::Expr;
+use crate::metrics::schema::validate_schema;
+use crate::metrics::selector::SeriesSelector;
+use crate::metrics::target::IpNetwork;
+use crate::metrics::types::MetricType;
+use crate::notify::Severity;
+use crate::reports::parse_schedule;
+use crate::utils::hex;
+use crate::utils::validation::{
+    validate_histogram_buckets, validate_label_names, validate_metric_name,
+};
+use chrono::{DateTime, Utc};
+use config::{Config, Environment, File, FileFormat, Source, Value, ValueKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fmt;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub workers: usize,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+    #[serde(default)]
+    pub scrape: ScrapeConfig,
+    /// HMAC signatures of pushed batches.
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    /// Locking out clients failing to authenticate.
+    #[serde(default)]
+    pub lockout: Option<LockoutConfig>,
+    /// Serve HTTPS; requires the `tls` feature.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Bind with `SO_REUSEPORT` so a new instance can start serving before
+    /// the old one has drained.
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// How long in-flight requests get to finish after a shutdown signal.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// `Retry-After` sent with ingestion refused in `read_only` or `drain` mode.
+    #[serde(default = "default_maintenance_retry_after_secs")]
+    pub maintenance_retry_after_secs: u64,
+}
+
+fn default_maintenance_retry_after_secs() -> u64 {
+    60
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+/// Handler deadlines and slow-request logging thresholds, with per-route overrides.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimeoutConfig {
+    /// Requests taking longer are answered with a 503; 0 disables the deadline.
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+    /// Requests taking longer are logged as slow.
+    #[serde(default = "default_slow_request_ms")]
+    pub slow_request_ms: u64,
+    #[serde(default)]
+    pub routes: Vec<RouteTimeout>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout_ms: default_request_timeout_ms(),
+            slow_request_ms: default_slow_request_ms(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// Overrides for the requests whose path starts with `path`; the longest match wins.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteTimeout {
+    pub path: String,
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub slow_request_ms: Option<u64>,
+}
+
+/// Concurrency limits per class of routes, so a burst of one class (e.g. a
+/// scrape storm) can't starve the others. Health checks are never limited.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LoadSheddingConfig {
+    /// Batch and stream ingestion.
+    #[serde(default)]
+    pub ingest: ConcurrencyLimit,
+    /// `/metrics`, `/metrics/source/{source}`, `/metrics/group/{name}` and
+    /// `/federate`.
+    #[serde(default)]
+    pub scrape: ConcurrencyLimit,
+    /// `/api/admin/*`.
+    #[serde(default)]
+    pub admin: ConcurrencyLimit,
+}
+
+/// Requests of a class served at once. Requests over the limit wait up to
+/// `queue_timeout_ms` for a slot, then are answered with a 503.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConcurrencyLimit {
+    /// Unlimited when unset.
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    #[serde(default)]
+    pub queue_timeout_ms: u64,
+}
+
+/// Protection against slow scrapers on the exposition endpoints. Unlike
+/// `load_shedding.scrape`, a scrape keeps its slot until its body is sent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScrapeConfig {
+    /// Scrapes served at once, unlimited when unset; scrapes over the limit
+    /// are answered with a 503.
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    /// A scraper that hasn't read the whole body by then is disconnected;
+    /// 0 disables the timeout.
+    #[serde(default = "default_scrape_write_timeout_ms")]
+    pub write_timeout_ms: u64,
+    /// Send the exposition gzipped to scrapers accepting it, compressing each
+    /// distinct body once.
+    #[serde(default)]
+    pub gzip: bool,
+    /// Scrapers must authenticate as one of these users, mapped to their
+    /// bcrypt password hash as in Prometheus' `web.config.yml`; no
+    /// authentication when empty.
+    #[serde(default)]
+    pub basic_auth_users: BTreeMap<String, String>,
+    /// A Prometheus `web.config.yml` whose `basic_auth_users` are added to
+    /// these when the configuration loads.
+    #[serde(default)]
+    pub web_config_path: Option<String>,
+}
+
+impl ScrapeConfig {
+    /// Adds the users of `web_config_path`, those set here taking precedence.
+    pub fn load_web_config(&mut self) -> Result<(), ServerError> {
+        let Some(path) = &self.web_config_path else {
+            return Ok(());
+        };
+        let users: Option<BTreeMap<String, String>> = Config::builder()
+            .add_source(File::new(path, FileFormat::Yaml))
+            .build()
+            .and_then(|web_config| web_config.get("basic_auth_users"))
+            .map_err(|e| {
+                ServerError::ConfigurationError(format!("server.scrape.web_config_path: {}", e))
+            })?;
+        for (user, hash) in users.unwrap_or_default() {
+            self.basic_auth_users.entry(user).or_insert(hash);
+        }
+        Ok(())
+    }
+}
+
+impl Default for ScrapeConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: None,
+            write_timeout_ms: default_scrape_write_timeout_ms(),
+            gzip: false,
+            basic_auth_users: BTreeMap::new(),
+            web_config_path: None,
+        }
+    }
+}
+
+fn default_scrape_write_timeout_ms() -> u64 {
+    30_000
+}
+
+/// Pushes signed with a shared secret per source, so a batch altered or
+/// replayed on its way is rejected before it's parsed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SigningConfig {
+    /// The HMAC-SHA256 secrets, by source. Pushes for these sources must be
+    /// signed.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+    /// Reject unsigned pushes from every source, not only those above.
+    #[serde(default)]
+    pub required: bool,
+    /// How far a signature's timestamp may be from the server's clock; its
+    /// nonce is remembered for as long.
+    #[serde(default = "default_signature_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_signature_max_age_secs() -> u64 {
+    300
+}
+
+/// Clients failing to authenticate `max_failures` times in a row are
+/// answered with a 429 for `lockout_secs`, doubled with every further
+/// failure up to `max_lockout_secs`. Failures are counted per client address
+/// and per credential, basic auth users per address as well.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LockoutConfig {
+    #[serde(default = "default_lockout_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_lockout_secs")]
+    pub lockout_secs: u64,
+    /// Also how long failures are remembered.
+    #[serde(default = "default_max_lockout_secs")]
+    pub max_lockout_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: default_lockout_max_failures(),
+            lockout_secs: default_lockout_secs(),
+            max_lockout_secs: default_max_lockout_secs(),
+        }
+    }
+}
+
+fn default_lockout_max_failures() -> u32 {
+    5
+}
+
+fn default_lockout_secs() -> u64 {
+    30
+}
+
+/// The longest lockout `max_lockout_secs` may set.
+pub const MAX_LOCKOUT_SECS: u64 = 7 * 24 * 3600;
+
+fn default_max_lockout_secs() -> u64 {
+    3600
+}
+
+/// The certificate served, reloaded when its files change so rotating it,
+/// e.g. in a Kubernetes secret, needs no restart.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_path: String,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: String,
+    /// How often the files are checked for changes; 0 disables reloading.
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    /// PEM CA bundle client certificates are verified against, to
+    /// authenticate clients by `auth.client_certificates`. Clients without a
+    /// certificate are still accepted.
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+    /// Provision and renew the certificate from an ACME CA such as Let's
+    /// Encrypt, writing it to `cert_path` and `key_path`; requires the `acme`
+    /// feature.
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+}
+
+fn default_tls_reload_interval_secs() -> u64 {
+    30
+}
+
+/// ACME with HTTP-01 challenges, answered on a dedicated plain HTTP listener
+/// the CA must be able to reach on port 80.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AcmeConfig {
+    /// The hostname the certificate is issued for.
+    pub domain: String,
+    /// Contact URLs for the account, e.g. `mailto:ops@example.com`.
+    #[serde(default)]
+    pub contact: Vec<String>,
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    /// Where the account key is kept, so restarts reuse the account.
+    pub account_dir: String,
+    /// Port of the listener answering challenges, on `server.host`.
+    #[serde(default = "default_acme_challenge_port")]
+    pub challenge_port: u16,
+    /// Renew once the certificate expires within this many days.
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_acme_challenge_port() -> u16 {
+    80
+}
+
+fn default_acme_renew_before_days() -> u32 {
+    30
+}
+
+fn default_request_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_slow_request_ms() -> u64 {
+    1_000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MetricsConfig {
     pub prometheus_endpoint: String,
     pub metrics_prefix: String,
     pub metrics_namespace: String,
+    #[serde(default = "default_histogram_buckets")]
+    pub histogram_buckets: Vec<f64>,
+    /// Buckets of `timer` metrics, in seconds.
+    #[serde(default = "default_timer_buckets")]
+    pub timer_buckets: Vec<f64>,
+    #[serde(default)]
+    pub negative_counter_policy: NegativeCounterPolicy,
+    #[serde(default)]
+    pub non_finite_policy: NonFinitePolicy,
+    #[serde(default = "default_max_label_value_length")]
+    pub max_label_value_length: usize,
+    #[serde(default)]
+    pub label_value_policy: LabelValuePolicy,
+    /// Rewrite label values to Unicode NFC at ingest, so values that only
+    /// differ in their encoding (e.g. a precomposed `é` and `e` + accent)
+    /// land in the same series.
+    #[serde(default = "default_true")]
+    pub canonicalize_labels: bool,
+    #[serde(default)]
+    pub utf8_names: bool,
+    #[serde(default)]
+    pub rename_rules: Vec<RenameRule>,
+    /// Constant labels attached to the server's own `rustic_insights_*` metrics.
+    #[serde(default)]
+    pub self_metrics_labels: HashMap<String, String>,
+    /// Labels attached to every exported series (the exposition, `/federate`
+    /// and the egress sinks) but not stored with them, identifying this
+    /// gateway downstream like Prometheus external labels.
+    #[serde(default)]
+    pub external_labels: HashMap<String, String>,
+    /// Attach `job` and `instance` labels to pushed series, so they follow
+    /// the conventions of scraped ones; off when unset.
+    #[serde(default)]
+    pub target_labels: Option<TargetLabelsConfig>,
+    /// Metrics operators expect to be pushed, more can be declared at runtime.
+    #[serde(default)]
+    pub schemas: Vec<MetricSchema>,
+    #[serde(default)]
+    pub unknown_metric_policy: UnknownMetricPolicy,
+    /// Reject batches from sources not registered through `/api/admin/sources`.
+    #[serde(default)]
+    pub reject_unregistered_sources: bool,
+    /// File the source registrations are kept in across restarts; they're
+    /// only kept in memory when unset.
+    #[serde(default)]
+    pub sources_path: Option<String>,
+    #[serde(default)]
+    pub ingest_mode: IngestMode,
+    /// Per-source overrides of `ingest_mode`, keyed by the batch `source`.
+    #[serde(default)]
+    pub source_ingest_modes: HashMap<String, IngestMode>,
+    /// Samples pushed again within this many seconds, with the same name,
+    /// labels, value and timestamp, are dropped; off when unset.
+    #[serde(default)]
+    pub dedup_window_secs: Option<u64>,
+    /// Per-source overrides of `dedup_window_secs`, 0 turning it off.
+    #[serde(default)]
+    pub source_dedup_window_secs: HashMap<String, u64>,
+    /// Batches whose newest timestamp is off from the receipt time by more
+    /// than this many seconds have their timestamps shifted by the offset;
+    /// the skew is only measured when unset.
+    #[serde(default)]
+    pub max_clock_skew_secs: Option<u64>,
+    /// Per-source overrides of `max_clock_skew_secs`, 0 turning it off.
+    #[serde(default)]
+    pub source_max_clock_skew_secs: HashMap<String, u64>,
+    /// Sampling of sources too chatty to ingest in full, keyed by the batch `source`.
+    #[serde(default)]
+    pub sampling: HashMap<String, SamplingConfig>,
+    /// Series not written for this long, by their samples' timestamps when
+    /// pushed with them, are removed; they never expire when unset.
+    #[serde(default)]
+    pub series_ttl_secs: Option<u64>,
+    #[serde(default = "default_compaction_interval_secs")]
+    pub compaction_interval_secs: u64,
+    /// Estimated memory the pushed series may take before new series are
+    /// rejected; unlimited when unset.
+    #[serde(default)]
+    pub max_memory_bytes: Option<u64>,
+    /// How long tombstoned series stay hidden before compaction purges them,
+    /// unless the tombstone request sets its own grace period.
+    #[serde(default = "default_tombstone_grace_secs")]
+    pub tombstone_grace_secs: u64,
+    /// Mappings of string values pushed for a metric, keyed by the metric name as pushed.
+    #[serde(default)]
+    pub enums: HashMap<String, EnumMapping>,
+    /// Unit conversions and rounding, keyed by the metric name after renaming.
+    #[serde(default)]
+    pub value_rules: HashMap<String, ValueRule>,
+    /// Expose a `<counter>:rate_1m` gauge next to every counter.
+    #[serde(default)]
+    pub derived_rates: bool,
+    #[serde(default)]
+    pub topk: Vec<TopKConfig>,
+    #[serde(default)]
+    pub anomaly: Option<AnomalyConfig>,
+    #[serde(default)]
+    pub schema_drift: Option<SchemaDriftConfig>,
+    /// Writes to the same family arriving together are applied in runs;
+    /// each write is applied on its own when unset.
+    #[serde(default)]
+    pub write_coalescing: Option<WriteCoalescingConfig>,
+    /// A registry shared with other replicas, written through and exposed
+    /// instead of the local one; each replica keeps its own when unset.
+    #[serde(default)]
+    pub store: Option<StoreConfig>,
+}
+
+fn default_compaction_interval_secs() -> u64 {
+    300
+}
+
+fn default_tombstone_grace_secs() -> u64 {
+    300
+}
+
+/// How batches containing invalid metrics are handled.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestMode {
+    /// Accept the valid metrics and report the invalid ones.
+    #[default]
+    Lenient,
+    /// Reject the whole batch if any metric is invalid.
+    Strict,
+}
+
+/// The expected shape of a metric, matched on its name after renaming.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MetricSchema {
+    pub name: String,
+    pub metric_type: MetricType,
+    #[serde(default)]
+    pub required_labels: Vec<String>,
+    /// Bucket bounds for histograms, instead of `histogram_buckets`.
+    #[serde(default)]
+    pub buckets: Option<Vec<f64>>,
+}
+
+/// Maps the value names an agent pushes for a metric, e.g. `"degraded"`, to
+/// something Prometheus can store.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EnumMapping {
+    #[serde(default)]
+    pub kind: EnumKind,
+    /// The accepted value names, in order.
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EnumKind {
+    /// A gauge set to the position of the name in `values`.
+    #[default]
+    Gauge,
+    /// A stateset whose current state is the name.
+    #[serde(rename = "stateset")]
+    StateSet,
+}
+
+/// Keeps only part of what a source pushes, until it can be fixed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct SamplingConfig {
+    /// Keep one batch out of this many.
+    #[serde(default)]
+    pub batch_one_in: Option<u64>,
+    /// Probability of keeping each metric, keyed by the metric name as pushed.
+    #[serde(default)]
+    pub metrics: HashMap<String, f64>,
+}
+
+/// Converts and rounds the values pushed for a metric, so agents reporting
+/// it in different units or with noisy precision agree.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ValueRule {
+    /// Unit the agents push the metric in, converted to `to`.
+    #[serde(default)]
+    pub from: Option<Unit>,
+    #[serde(default)]
+    pub to: Option<Unit>,
+    /// Decimal places values are rounded to, after conversion.
+    #[serde(default)]
+    pub precision: Option<u32>,
+}
+
+impl ValueRule {
+    pub fn apply(&self, value: f64) -> f64 {
+        let mut value = self.convert(value);
+        if let Some(precision) = self.precision {
+            let factor = 10f64.powi(precision as i32);
+            value = (value * factor).round() / factor;
+        }
+        value
+    }
+
+    /// Converts `value` to the target unit, without rounding it.
+    pub fn convert(&self, value: f64) -> f64 {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => value * from.scale() / to.scale(),
+            _ => value,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    #[serde(rename = "ns")]
+    Nanoseconds,
+    #[serde(rename = "us")]
+    Microseconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "min")]
+    Minutes,
+    #[serde(rename = "h")]
+    Hours,
+    #[serde(rename = "B")]
+    Bytes,
+    #[serde(rename = "KB")]
+    Kilobytes,
+    #[serde(rename = "KiB")]
+    Kibibytes,
+    #[serde(rename = "MB")]
+    Megabytes,
+    #[serde(rename = "MiB")]
+    Mebibytes,
+    #[serde(rename = "GB")]
+    Gigabytes,
+    #[serde(rename = "GiB")]
+    Gibibytes,
+    #[serde(rename = "percent")]
+    Percent,
+    #[serde(rename = "ratio")]
+    Ratio,
+}
+
+impl Unit {
+    /// What the unit measures; only units of the same quantity convert.
+    pub fn quantity(self) -> &'static str {
+        match self {
+            Unit::Nanoseconds
+            | Unit::Microseconds
+            | Unit::Milliseconds
+            | Unit::Seconds
+            | Unit::Minutes
+            | Unit::Hours => "time",
+            Unit::Bytes
+            | Unit::Kilobytes
+            | Unit::Kibibytes
+            | Unit::Megabytes
+            | Unit::Mebibytes
+            | Unit::Gigabytes
+            | Unit::Gibibytes => "size",
+            Unit::Percent | Unit::Ratio => "ratio",
+        }
+    }
+
+    /// Size of the unit in seconds, bytes or as a ratio.
+    pub fn scale(self) -> f64 {
+        match self {
+            Unit::Nanoseconds => 1e-9,
+            Unit::Microseconds => 1e-6,
+            Unit::Milliseconds => 1e-3,
+            Unit::Seconds => 1.0,
+            Unit::Minutes => 60.0,
+            Unit::Hours => 3600.0,
+            Unit::Bytes => 1.0,
+            Unit::Kilobytes => 1e3,
+            Unit::Kibibytes => 1024.0,
+            Unit::Megabytes => 1e6,
+            Unit::Mebibytes => 1024.0 * 1024.0,
+            Unit::Gigabytes => 1e9,
+            Unit::Gibibytes => 1024.0 * 1024.0 * 1024.0,
+            Unit::Percent => 0.01,
+            Unit::Ratio => 1.0,
+        }
+    }
+}
+
+/// What to do with metrics that have no declared schema.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownMetricPolicy {
+    #[default]
+    Allow,
+    /// Accept the metric but log it and count it in `rustic_insights_unknown_metrics_total`.
+    Flag,
+    /// Reject the metric with a schema violation.
+    Reject,
+}
+
+/// Renames a metric at ingest, so dashboards can migrate to the new name while
+/// older agents still push the legacy one.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RenameRule {
+    pub from: String,
+    pub to: String,
+    /// Labels added to renamed metrics unless the client already sent them.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// Tracks the label values of a metric with the highest counts, e.g. the
+/// busiest `endpoint` of `request_count`, in a count-min sketch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TopKConfig {
+    /// The metric name as pushed.
+    pub metric: String,
+    pub label: String,
+    #[serde(default = "default_topk_k")]
+    pub k: usize,
+    /// Counters per sketch row; wider sketches overestimate less.
+    #[serde(default = "default_topk_width")]
+    pub width: usize,
+    #[serde(default = "default_topk_depth")]
+    pub depth: usize,
+}
+
+fn default_topk_k() -> usize {
+    10
+}
+
+fn default_topk_width() -> usize {
+    2048
+}
+
+fn default_topk_depth() -> usize {
+    4
+}
+
+/// Flags gauges deviating from their exponentially weighted moving average.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AnomalyConfig {
+    /// Metric names as pushed; every gauge is checked when empty.
+    #[serde(default)]
+    pub metrics: Vec<String>,
+    /// Weight of the newest value in the moving average and variance.
+    #[serde(default = "default_anomaly_alpha")]
+    pub alpha: f64,
+    /// Deviation, in standard deviations, beyond which a value is anomalous.
+    #[serde(default = "default_anomaly_threshold")]
+    pub threshold: f64,
+    /// Values a series needs before it is checked.
+    #[serde(default = "default_anomaly_min_samples")]
+    pub min_samples: usize,
+    /// Series not set for this long are forgotten, with their anomaly.
+    #[serde(default = "default_anomaly_state_ttl_secs")]
+    pub state_ttl_secs: u64,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Channels notified of each new anomaly.
+    #[serde(default)]
+    pub notify: Vec<NotifyTarget>,
+}
+
+/// The target labels Prometheus would attach to a scrape, derived for pushes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TargetLabelsConfig {
+    /// Set `job` to the batch source.
+    #[serde(default = "default_true")]
+    pub job: bool,
+    /// Set `instance` to the client IP address.
+    #[serde(default = "default_true")]
+    pub instance: bool,
+    /// Header naming the instance instead of the client address, e.g.
+    /// `X-Forwarded-For` behind a proxy (its first entry is used). Pushes
+    /// without it fall back to the client address.
+    #[serde(default)]
+    pub instance_header: Option<String>,
+    /// Addresses or CIDR ranges of the proxies `instance_header` is read
+    /// from; other clients are labelled with their own address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Keep the `job` and `instance` labels pushed by the client, like
+    /// Prometheus' `honor_labels`. Otherwise the derived ones win and the
+    /// pushed ones are always kept as `exported_job` and `exported_instance`.
+    #[serde(default)]
+    pub honor_labels: bool,
+}
+
+/// Reports sources changing the label names they push a metric with.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SchemaDriftConfig {
+    #[serde(default)]
+    pub severity: Severity,
+    /// Channels notified of each change.
+    #[serde(default)]
+    pub notify: Vec<NotifyTarget>,
+    /// Label names not pushed for this long are forgotten.
+    #[serde(default = "default_drift_state_ttl_secs")]
+    pub state_ttl_secs: u64,
+}
+
+impl Default for SchemaDriftConfig {
+    fn default() -> Self {
+        Self {
+            severity: Severity::default(),
+            notify: Vec::new(),
+            state_ttl_secs: default_drift_state_ttl_secs(),
+        }
+    }
+}
+
+fn default_drift_state_ttl_secs() -> u64 {
+    86_400
+}
+
+/// Coalescing of the writes to a family that contend for it, such as
+/// sources all pushing at the top of the minute.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WriteCoalescingConfig {
+    /// How long a burst waits for more writes before it's applied; writes
+    /// to a family closer together than this make a burst.
+    #[serde(default = "default_coalescing_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Writes queued behind which a burst is applied without waiting.
+    #[serde(default = "default_coalescing_max_batch")]
+    pub max_batch: usize,
+}
+
+impl Default for WriteCoalescingConfig {
+    fn default() -> Self {
+        Self {
+            max_delay_ms: default_coalescing_max_delay_ms(),
+            max_batch: default_coalescing_max_batch(),
+        }
+    }
+}
+
+fn default_coalescing_max_delay_ms() -> u64 {
+    2
+}
+
+fn default_coalescing_max_batch() -> usize {
+    256
+}
+
+fn default_anomaly_alpha() -> f64 {
+    0.3
+}
+
+fn default_anomaly_threshold() -> f64 {
+    3.0
+}
+
+fn default_anomaly_min_samples() -> usize {
+    10
+}
+
+fn default_anomaly_state_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_max_label_value_length() -> usize {
+    1024
+}
+
+/// What to do with counter pushes carrying a negative increment.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeCounterPolicy {
+    /// Reject the metric with a `CounterDecreaseError`.
+    #[default]
+    Reject,
+    /// Treat the increment as zero and log a warning.
+    Clamp,
+}
+
+/// What to do with NaN and infinite sample values, which would otherwise
+/// poison sums and averages over the series.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFinitePolicy {
+    /// Reject the metric with a `NonFiniteValue` error.
+    #[default]
+    Reject,
+    /// Discard the sample and count it in `rustic_insights_dropped_samples_total`.
+    Drop,
+    /// Replace infinities with the largest finite values and NaN with 0.
+    Clamp,
+}
+
+/// What to do with label values that are too long or contain control characters.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelValuePolicy {
+    /// Reject the metric with a validation error.
+    #[default]
+    Reject,
+    /// Truncate long values and escape control characters.
+    Sanitize,
+}
+
+fn default_histogram_buckets() -> Vec<f64> {
+    prometheus::DEFAULT_BUCKETS.to_vec()
+}
+
+fn default_timer_buckets() -> Vec<f64> {
+    vec![
+        0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+    ]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EnrichmentConfig {
+    /// Existing label whose value is looked up in the enrichment metadata,
+    /// unless series are looked up by source.
+    #[serde(default)]
+    pub key_label: String,
+    /// Looks series up by the sources that pushed them instead of a label.
+    #[serde(default)]
+    pub by_source: bool,
+    pub provider: EnrichmentProvider,
+    #[serde(default = "default_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+}
+
+fn default_refresh_interval_secs() -> u64 {
+    60
+}
+
+/// A WebAssembly filter run on every pushed metric before the ingestion
+/// policies; requires the `wasm` feature.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PluginConfig {
+    pub name: String,
+    /// A compiled `.wasm` module, or its `.wat` text.
+    pub path: String,
+    /// Sources whose metrics the plugin filters, all of them when empty.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Fuel a call may consume, bounding the instructions run per metric.
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    /// Linear memory the plugin may grow to.
+    #[serde(default = "default_plugin_max_memory_bytes")]
+    pub max_memory_bytes: usize,
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_plugin_max_memory_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+/// A Rhai script whose `transform(metric)` is run on every pushed metric
+/// after the plugins; requires the `rhai` feature.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScriptConfig {
+    pub name: String,
+    pub path: String,
+    /// Sources whose metrics the script transforms, all of them when empty.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Operations a call may run before it is aborted.
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+    /// Time a call may take before it is aborted.
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_script_timeout_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EnrichmentProvider {
+    /// A JSON object mapping key label values to extra labels.
+    File { path: String },
+    /// An HTTP endpoint returning the same JSON document as `File`.
+    Http { url: String },
+    /// Pod labels from the Kubernetes API, keyed by pod name.
+    Kubernetes {
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KubernetesConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_pod_name_env")]
+    pub pod_name_env: String,
+    #[serde(default = "default_namespace_env")]
+    pub namespace_env: String,
+    #[serde(default = "default_node_name_env")]
+    pub node_name_env: String,
+}
+
+fn default_pod_name_env() -> String {
+    "POD_NAME".to_string()
+}
+
+fn default_namespace_env() -> String {
+    "POD_NAMESPACE".to_string()
+}
+
+fn default_node_name_env() -> String {
+    "NODE_NAME".to_string()
+}
+
+impl Default for KubernetesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pod_name_env: default_pod_name_env(),
+            namespace_env: default_namespace_env(),
+            node_name_env: default_node_name_env(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DiscoveryConfig {
+    /// Address Prometheus should scrape, defaults to `server.host:server.port`.
+    #[serde(default)]
+    pub advertise_address: Option<String>,
+    /// Other gateway replicas listed alongside this one in cluster deployments.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Extra labels attached to every discovered target.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// A subset of the exposition served at `/metrics/group/{name}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExpositionGroupConfig {
+    pub name: String,
+    /// Patterns of the exposed family names in the group, such as
+    /// `app_metrics_server_billing_.*`, anchored at both ends.
+    pub metrics: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    #[serde(default = "default_sink_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_sink_interval_secs() -> u64 {
+    30
+}
+
+/// A destination of the batch fan-out, with its own queue and retries so a
+/// slow or failing destination doesn't hold up the others.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FanoutConfig {
+    #[serde(flatten)]
+    pub kind: FanoutKind,
+    /// Batches waiting for delivery before new ones are dropped.
+    #[serde(default = "default_fanout_queue_size")]
+    pub queue_size: usize,
+    /// Deliveries retried after a transient failure, with exponential backoff.
+    #[serde(default = "default_fanout_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_fanout_queue_size() -> usize {
+    1_000
+}
+
+fn default_fanout_max_retries() -> u32 {
+    5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FanoutKind {
+    /// The local registry, written while the request waits so the response
+    /// reports what was ingested.
+    Registry,
+    /// The series the batch wrote, aggregated like the registry does.
+    RemoteWrite { url: String },
+    /// The batch as JSON, keyed by source; requires the `kafka` feature.
+    Kafka { brokers: String, topic: String },
+    /// The batch as a JSON line appended to a local file.
+    File { path: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    Pushgateway {
+        url: String,
+        #[serde(default = "default_pushgateway_job")]
+        job: String,
+    },
+    Graphite {
+        /// Carbon plaintext listener, e.g. `carbon:2003`.
+        address: String,
+        #[serde(default)]
+        prefix: Option<String>,
+        #[serde(default = "default_true")]
+        tagged: bool,
+    },
+    RemoteWrite {
+        url: String,
+        /// Buffers payloads on disk while the endpoint is unreachable.
+        #[serde(default)]
+        spill: Option<SpillConfig>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpillConfig {
+    pub directory: String,
+    /// Total size of spilled payloads; the oldest are dropped beyond it.
+    #[serde(default = "default_spill_max_bytes")]
+    pub max_bytes: u64,
+    /// AES-256 key, as 64 hex digits, payloads are encrypted with on disk.
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    /// Still sends the payloads spilled in the clear before `encryption_key`
+    /// was set, rather than dropping them.
+    #[serde(default)]
+    pub read_plaintext: bool,
+}
+
+fn default_spill_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_pushgateway_job() -> String {
+    "rustic_insights".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A query evaluated on an interval whose results are written back as a new
+/// gauge family, e.g. `sum by (job) (http_requests_total)`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordingRule {
+    /// Name of the family the results are stored under, before the metrics prefix.
+    pub record: String,
+    pub expr: String,
+    /// Extra labels attached to every resulting series.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default = "default_rule_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_rule_interval_secs() -> u64 {
+    60
+}
+
+/// A demo gauge written on an interval, for checking dashboards and alerts
+/// without real traffic.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyntheticMetric {
+    /// Name of the gauge, before the metrics prefix.
+    pub name: String,
+    pub pattern: SyntheticPattern,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default = "default_synthetic_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_synthetic_interval_secs() -> u64 {
+    15
+}
+
+/// The values a synthetic gauge goes through, over wall clock time so every
+/// gateway generating the same pattern agrees.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyntheticPattern {
+    /// `offset + amplitude * sin(2π t / period_secs)`.
+    Sine {
+        #[serde(default = "default_sine_amplitude")]
+        amplitude: f64,
+        #[serde(default)]
+        offset: f64,
+        period_secs: u64,
+    },
+    /// `low` for `period_secs`, then `high` for `period_secs`.
+    Step {
+        low: f64,
+        high: f64,
+        period_secs: u64,
+    },
+    /// Uniformly distributed between `min` and `max`.
+    Random { min: f64, max: f64 },
+}
+
+fn default_sine_amplitude() -> f64 {
+    1.0
+}
+
+/// A service level objective whose burn rates and remaining error budget are
+/// computed on an interval.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SloConfig {
+    pub name: String,
+    /// Target ratio of good events, e.g. `0.999`.
+    pub objective: f64,
+    pub indicator: SloIndicator,
+    /// Period the error budget covers.
+    #[serde(default = "default_slo_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_rule_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub alert: Option<SloAlert>,
+}
+
+/// Notifies when both the 5 minute and 1 hour burn rates exceed `burn_rate`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SloAlert {
+    #[serde(default = "default_alert_burn_rate")]
+    pub burn_rate: f64,
+    #[serde(default)]
+    pub severity: Severity,
+    pub notify: Vec<NotifyTarget>,
+}
+
+fn default_alert_burn_rate() -> f64 {
+    // Spends 2% of a 30 day budget in an hour
+    14.4
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_slo_window_secs() -> u64 {
+    30 * 24 * 3600
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SloIndicator {
+    /// Queries over counters, summed when they return several series.
+    Ratio { good: String, total: String },
+    /// Observations of a histogram at or below `threshold` are good.
+    Latency { histogram: String, threshold: f64 },
+}
+
+/// A summary rendered on a schedule and sent to notification channels.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReportConfig {
+    pub name: String,
+    /// A cron expression in UTC, e.g. `0 8 * * MON`, with optional seconds.
+    pub schedule: String,
+    #[serde(default)]
+    pub format: ReportFormat,
+    #[serde(default = "default_report_sections")]
+    pub sections: Vec<ReportSection>,
+    /// Families listed by `top_metrics`, those with the most series first.
+    #[serde(default = "default_report_top_metrics")]
+    pub top_metrics: usize,
+    /// Names of `notification_channels` the report is sent to.
+    pub channels: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Html,
+    Csv,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportSection {
+    TopMetrics,
+    SloStatus,
+    SourceHealth,
+}
+
+fn default_report_sections() -> Vec<ReportSection> {
+    vec![
+        ReportSection::TopMetrics,
+        ReportSection::SloStatus,
+        ReportSection::SourceHealth,
+    ]
+}
+
+fn default_report_top_metrics() -> usize {
+    10
+}
+
+/// An endpoint ingestion lifecycle events are POSTed to as JSON.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventWebhookConfig {
+    pub name: String,
+    pub url: String,
+    /// Events sent, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<LifecycleEventKind>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Deliveries retried after a failure, with exponential backoff.
+    #[serde(default = "default_event_webhook_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_event_webhook_max_retries() -> u32 {
+    5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleEventKind {
+    /// A metric family pushed for the first time.
+    FamilyRegistered,
+    /// The first batch of a source since the server started.
+    SourceSeen,
+    /// New series rejected for `metrics.max_memory_bytes`.
+    SeriesLimitReached,
+    /// A batch rejected as a whole.
+    BatchRejected,
+}
+
+/// Where alert notifications are delivered, referenced by name from alert rules.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationChannelConfig {
+    Slack {
+        webhook_url: String,
+        #[serde(default)]
+        channel: Option<String>,
+    },
+    Pagerduty {
+        routing_key: String,
+        #[serde(default = "default_pagerduty_url")]
+        url: String,
+    },
+    /// Receives the notification as a JSON POST.
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Requires the `email` feature.
+    Email {
+        smtp_host: String,
+        #[serde(default = "default_smtp_port")]
+        smtp_port: u16,
+        #[serde(default = "default_true")]
+        starttls: bool,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+fn default_pagerduty_url() -> String {
+    "https://events.pagerduty.com/v2/enqueue".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+/// A channel an alert rule notifies. The message is the alert summary unless
+/// a template is given, where `{{name}}` expands to `rule`, `severity`,
+/// `summary` or a value or label of the alert.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NotifyTarget {
+    pub channel: String,
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// A planned period during which matching alerts aren't sent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MaintenanceWindow {
+    /// Selector over the alert labels, matching the rule as the name, e.g. `{service="checkout"}`.
+    pub matchers: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub comment: String,
+}
+
+/// Where rejected batches and metrics are forwarded.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeadLetterConfig {
+    /// JSON lines appended to a local file.
+    File { path: String },
+    /// Requires the `kafka` feature.
+    Kafka { brokers: String, topic: String },
+    /// One object per dead letter, requires the `s3` feature.
+    S3 {
+        bucket: String,
+        #[serde(default = "default_dead_letter_prefix")]
+        prefix: String,
+        #[serde(default)]
+        region: Option<String>,
+        #[serde(default)]
+        endpoint: Option<String>,
+        /// Credentials, instead of the usual `AWS_*` environment variables.
+        #[serde(default)]
+        access_key_id: Option<String>,
+        #[serde(default)]
+        secret_access_key: Option<String>,
+    },
+}
+
+/// Where replicas behind a load balancer keep their shared registry.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StoreConfig {
+    /// Requires the `redis` feature.
+    Redis {
+        url: String,
+        #[serde(default = "default_store_key_prefix")]
+        key_prefix: String,
+    },
+}
+
+fn default_store_key_prefix() -> String {
+    "rustic-insights".to_string()
+}
+
+fn default_dead_letter_prefix() -> String {
+    "dead-letter".to_string()
+}
+
+/// Records incoming batches as fixture files, to reproduce the payloads of
+/// an agent that breaks the server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CaptureConfig {
+    pub dir: String,
+    /// Batches recorded per source, counting the fixtures already in `dir`.
+    #[serde(default = "default_capture_batches_per_source")]
+    pub batches_per_source: usize,
+}
+
+/// Keys, as 64 hex digits, the records of each source are encrypted with on
+/// disk.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EncryptionConfig {
+    /// AES-256 key of the sources without their own.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// AES-256 keys of individual sources, so each tenant's records can only
+    /// be read with its own key.
+    #[serde(default)]
+    pub source_keys: HashMap<String, String>,
+}
+
+impl EncryptionConfig {
+    /// The `[encryption]` keys set in the environment the way the server reads
+    /// them, e.g. `APP__ENCRYPTION__KEY_FILE` or
+    /// `APP__ENCRYPTION__SOURCE_KEYS__BILLING`, for tools that open records
+    /// without loading the whole configuration.
+    pub fn from_env() -> Result<Self, ServerError> {
+        Self::from_vars(env::vars())
+    }
+
+    /// Like `from_env`, with the given variables instead of the process's.
+    pub fn from_vars(
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ServerError> {
+        let config = Config::builder()
+            .add_source(
+                Environment::with_prefix("APP")
+                    .separator("__")
+                    .source(Some(vars.into_iter().collect())),
+            )
+            .build()
+            .map_err(|e| ServerError::ConfigurationError(e.to_string()))?;
+        let (config, _) = secrets::resolve_secrets(config)?;
+        match config.get("encryption") {
+            Ok(encryption) => Ok(encryption),
+            Err(config::ConfigError::NotFound(_)) => Ok(Self::default()),
+            Err(e) => Err(ServerError::ConfigurationError(format!(
+                "encryption: {}",
+                e
+            ))),
+        }
+    }
+}
+
+fn default_capture_batches_per_source() -> usize {
+    10
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UptimeConfig {
+    /// Where the run history and the lock file of the current run are kept.
+    pub dir: String,
+    /// Past runs kept, the oldest are forgotten beyond it.
+    #[serde(default = "default_uptime_max_runs")]
+    pub max_runs: usize,
+}
+
+fn default_uptime_max_runs() -> usize {
+    100
+}
+
+/// Snapshots of the exposed samples kept in memory for `GET /api/export`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryConfig {
+    #[serde(default = "default_history_interval_secs")]
+    pub interval_secs: u64,
+    /// Snapshots older than this are forgotten.
+    #[serde(default = "default_history_retention_secs")]
+    pub retention_secs: u64,
+    /// Samples kept across snapshots, the oldest snapshots are forgotten
+    /// beyond it.
+    #[serde(default = "default_history_max_samples")]
+    pub max_samples: usize,
+    /// File the annotations are saved to, so they're kept across restarts
+    /// for as long as the snapshots; only kept in memory when unset.
+    #[serde(default)]
+    pub annotations_path: Option<String>,
+}
+
+fn default_history_interval_secs() -> u64 {
+    60
+}
+
+fn default_history_retention_secs() -> u64 {
+    86_400
+}
+
+fn default_history_max_samples() -> usize {
+    200_000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NatsConfig {
+    pub url: String,
+    pub stream: String,
+    pub subject: String,
+    /// Durable consumer name, unacknowledged messages are replayed to it after a restart.
+    #[serde(default = "default_nats_consumer")]
+    pub consumer: String,
+    #[serde(default = "default_nats_max_deliver")]
+    pub max_deliver: i64,
+}
+
+fn default_nats_consumer() -> String {
+    "rustic-insights".to_string()
+}
+
+fn default_nats_max_deliver() -> i64 {
+    10
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AmqpConfig {
+    pub url: String,
+    pub queue: String,
+    /// Unacknowledged deliveries the broker sends ahead of processing.
+    #[serde(default = "default_amqp_prefetch")]
+    pub prefetch: u16,
+    /// Declares the queue with this dead-letter exchange when set.
+    #[serde(default)]
+    pub dead_letter_exchange: Option<String>,
+    #[serde(default = "default_amqp_consumer_tag")]
+    pub consumer_tag: String,
+}
+
+fn default_amqp_prefetch() -> u16 {
+    50
+}
+
+fn default_amqp_consumer_tag() -> String {
+    "rustic-insights".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub enrichment: Option<EnrichmentConfig>,
+    /// Run in order on every pushed metric.
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    /// Run in order on every pushed metric, after the plugins.
+    #[serde(default)]
+    pub scripts: Vec<ScriptConfig>,
+    #[serde(default)]
+    pub kubernetes: KubernetesConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub exposition_groups: Vec<ExpositionGroupConfig>,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    /// Destinations every accepted batch is delivered to; batches only go
+    /// to the registry when unset.
+    #[serde(default)]
+    pub fanout: Vec<FanoutConfig>,
+    #[serde(default)]
+    pub nats: Option<NatsConfig>,
+    #[serde(default)]
+    pub amqp: Option<AmqpConfig>,
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterConfig>,
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
+    /// Encrypts the captures, dead letters and fan-out files written to
+    /// disk; they're written in the clear when unset.
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    /// Keeps the history of restarts across runs; only the current run is
+    /// known when unset.
+    #[serde(default)]
+    pub uptime: Option<UptimeConfig>,
+    /// Keeps the exposed samples over time for export; off when unset.
+    #[serde(default)]
+    pub history: Option<HistoryConfig>,
+    #[serde(default)]
+    pub recording_rules: Vec<RecordingRule>,
+    #[serde(default)]
+    pub synthetic: Vec<SyntheticMetric>,
+    #[serde(default)]
+    pub slos: Vec<SloConfig>,
+    #[serde(default)]
+    pub reports: Vec<ReportConfig>,
+    #[serde(default)]
+    pub event_webhooks: Vec<EventWebhookConfig>,
+    #[serde(default)]
+    pub notification_channels: HashMap<String, NotificationChannelConfig>,
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    #[serde(default)]
+    pub vault: Option<VaultConfig>,
+    /// Role-based access control of the API, off when unset.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Where the values set by a file or the environment came from, keyed by
+    /// dotted path, e.g. `server.port` to `file:config/default.toml` or `env`.
+    /// Filled in by `load_and_check`.
+    #[serde(skip)]
+    pub origins: BTreeMap<String, String>,
+}
+
+/// The identities allowed in and their roles. Every request but health
+/// checks needs a role granting the route's permission.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthConfig {
+    /// Keys presented in the `X-API-Key` header.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    /// JWTs presented as `Authorization: Bearer` tokens.
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    /// Roles by the common name of TLS client certificates, verified against
+    /// `server.tls.client_ca_path`.
+    #[serde(default)]
+    pub client_certificates: BTreeMap<String, Vec<Role>>,
+    /// Roles of requests presenting no credentials.
+    #[serde(default)]
+    pub anonymous_roles: Vec<Role>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Pushes metrics.
+    Pusher,
+    /// Scrapes and reads the API.
+    Reader,
+    /// Everything, including `/api/admin` and changing schemas and metadata.
+    Admin,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    /// Who the key belongs to, as logged and named in errors.
+    pub name: String,
+    pub key: String,
+    pub roles: Vec<Role>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JwtConfig {
+    #[serde(default)]
+    pub algorithm: JwtAlgorithm,
+    /// The shared secret of the HS algorithms.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// The PEM public key of the RS and ES algorithms.
+    #[serde(default)]
+    pub public_key_path: Option<String>,
+    /// Required `iss` claim, when set.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Required `aud` claim, when set.
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Claim listing the roles, as an array or a space-separated string.
+    #[serde(default = "default_jwt_roles_claim")]
+    pub roles_claim: String,
+}
+
+fn default_jwt_roles_claim() -> String {
+    "roles".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    #[default]
+    HS256,
+    HS384,
+    HS512,
+    RS256,
+    RS384,
+    RS512,
+    ES256,
+    ES384,
+}
+
+impl JwtAlgorithm {
+    /// Whether tokens are signed with a shared secret rather than a key pair.
+    pub fn is_symmetric(self) -> bool {
+        matches!(
+            self,
+            JwtAlgorithm::HS256 | JwtAlgorithm::HS384 | JwtAlgorithm::HS512
+        )
+    }
+}
+
+/// Where settings set to `vault:<path>#<field>` are fetched from, once at
+/// startup.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VaultConfig {
+    /// e.g. `https://vault.internal:8200`
+    pub address: String,
+    pub token: String,
+    /// Mount of the KV version 2 secrets engine.
+    #[serde(default = "default_vault_mount")]
+    pub mount: String,
+}
+
+fn default_vault_mount() -> String {
+    "secret".to_string()
+}
+
+/// A single problem found while validating the configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    pub field: String,
+    pub origin: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.origin {
+            Some(origin) => write!(f, "field `{}` in {}: {}", self.field, origin, self.message),
+            None => write!(f, "field `{}`: {}", self.field, self.message),
+        }
+    }
+}
+
+/// The configuration profile, `config/<RUN_MODE>` layered over `config/default`.
+pub fn run_mode() -> String {
+    env::var("RUN_MODE").unwrap_or_else(|_| "development".into())
 }
 
 impl AppConfig {
     pub fn load() -> Result<Self, ServerError> {
-        let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+        let (app_config, issues) = Self::load_and_check()?;
+
+        if !issues.is_empty() {
+            return Err(issues_to_error(&issues));
+        }
+
+        Ok(app_config)
+    }
+
+    /// Loads the configuration without rejecting it, returning every validation
+    /// issue annotated with the file or environment variable the value came from.
+    pub fn load_and_check() -> Result<(Self, Vec<ConfigIssue>), ServerError> {
+        let run_mode = run_mode();
 
         let config_builder = Config::builder()
             .add_source(File::with_name("config/default"))
@@ -39,13 +1722,1001 @@ impl AppConfig {
         let config = config_builder
             .build()
             .map_err(|e| ServerError::ConfigurationError(e.to_string()))?;
+        let (config, secret_origins) = secrets::resolve_secrets(config)?;
 
-        let app_config: AppConfig = config
+        let mut app_config: AppConfig = config
+            .clone()
             .try_deserialize()
             .map_err(|e| ServerError::ConfigurationError(e.to_string()))?;
+        app_config.server.scrape.load_web_config()?;
+        if let Ok(values) = config.collect() {
+            collect_origins("", &values, &mut app_config.origins);
+        }
+        app_config.origins.extend(secret_origins);
 
-        Ok(app_config)
+        let issues: Vec<ConfigIssue> = app_config
+            .issues()
+            .into_iter()
+            .map(|issue| ConfigIssue {
+                origin: value_origin(&config, &issue.field),
+                ..issue
+            })
+            .collect();
+
+        Ok((app_config, issues))
+    }
+
+    pub fn validate(&self) -> Result<(), ServerError> {
+        let issues = self.issues();
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues_to_error(&issues))
+        }
     }
+
+    /// Collects every validation problem instead of stopping at the first one,
+    /// so `check-config` can report them all in a single run.
+    pub fn issues(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |field: &str, message: String| {
+            issues.push(ConfigIssue {
+                field: field.to_string(),
+                origin: None,
+                message,
+            })
+        };
+
+        if self.server.host.trim().is_empty() {
+            issue("server.host", "must not be empty".to_string());
+        }
+
+        if self.server.port == 0 {
+            issue("server.port", "must be between 1 and 65535".to_string());
+        }
+
+        if self.server.workers == 0 {
+            issue("server.workers", "must be greater than 0".to_string());
+        }
+
+        for (i, route) in self.server.timeouts.routes.iter().enumerate() {
+            if !route.path.starts_with('/') {
+                issue(
+                    &format!("server.timeouts.routes[{}]", i),
+                    format!("path '{}' must start with '/'", route.path),
+                );
+            }
+        }
+
+        let shedding = &self.server.load_shedding;
+        for (class, limit) in [
+            ("ingest", &shedding.ingest),
+            ("scrape", &shedding.scrape),
+            ("admin", &shedding.admin),
+        ] {
+            if limit.max_concurrent == Some(0) {
+                issue(
+                    &format!("server.load_shedding.{}.max_concurrent", class),
+                    "must be greater than 0".to_string(),
+                );
+            }
+        }
+        if let Some(tls) = &self.server.tls {
+            for (field, path) in [
+                ("server.tls.cert_path", &tls.cert_path),
+                ("server.tls.key_path", &tls.key_path),
+            ] {
+                if path.is_empty() {
+                    issue(field, "must not be empty".to_string());
+                }
+            }
+            if let Some(acme) = &tls.acme {
+                if acme.domain.is_empty() {
+                    issue("server.tls.acme.domain", "must not be empty".to_string());
+                }
+                if acme.account_dir.is_empty() {
+                    issue(
+                        "server.tls.acme.account_dir",
+                        "must not be empty".to_string(),
+                    );
+                }
+                let url = &acme.directory_url;
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    issue(
+                        "server.tls.acme.directory_url",
+                        format!("'{}' is not an http(s) URL", url),
+                    );
+                }
+                if acme.challenge_port == self.server.port {
+                    issue(
+                        "server.tls.acme.challenge_port",
+                        "must differ from server.port".to_string(),
+                    );
+                }
+            }
+        }
+        if self.server.scrape.max_concurrent == Some(0) {
+            issue(
+                "server.scrape.max_concurrent",
+                "must be greater than 0".to_string(),
+            );
+        }
+        for (user, hash) in &self.server.scrape.basic_auth_users {
+            if user.is_empty() || user.contains(':') {
+                issue(
+                    "server.scrape.basic_auth_users",
+                    format!("'{}' is not a valid user name", user),
+                );
+            }
+            if !is_bcrypt_hash(hash) {
+                issue(
+                    "server.scrape.basic_auth_users",
+                    format!("the password of '{}' is not a bcrypt hash", user),
+                );
+            }
+        }
+
+        if let Some(signing) = &self.server.signing {
+            for (source, secret) in &signing.secrets {
+                if secret.len() < 16 {
+                    issue(
+                        "server.signing.secrets",
+                        format!("the secret of '{}' must be at least 16 characters", source),
+                    );
+                }
+            }
+            if signing.max_age_secs == 0 {
+                issue(
+                    "server.signing.max_age_secs",
+                    "must be greater than 0".to_string(),
+                );
+            }
+        }
+
+        if let Some(lockout) = &self.server.lockout {
+            if lockout.max_failures == 0 {
+                issue(
+                    "server.lockout.max_failures",
+                    "must be greater than 0".to_string(),
+                );
+            }
+            if lockout.lockout_secs == 0 {
+                issue(
+                    "server.lockout.lockout_secs",
+                    "must be greater than 0".to_string(),
+                );
+            }
+            if lockout.max_lockout_secs < lockout.lockout_secs {
+                issue(
+                    "server.lockout.max_lockout_secs",
+                    "must be at least lockout_secs".to_string(),
+                );
+            }
+            if lockout.max_lockout_secs > MAX_LOCKOUT_SECS {
+                issue(
+                    "server.lockout.max_lockout_secs",
+                    format!("must be at most {}", MAX_LOCKOUT_SECS),
+                );
+            }
+        }
+
+        if !self.metrics.prometheus_endpoint.starts_with('/') {
+            issue(
+                "metrics.prometheus_endpoint",
+                format!(
+                    "'{}' must be an absolute path starting with '/'",
+                    self.metrics.prometheus_endpoint
+                ),
+            );
+        }
+
+        for (field, value) in [
+            ("metrics.metrics_prefix", &self.metrics.metrics_prefix),
+            ("metrics.metrics_namespace", &self.metrics.metrics_namespace),
+        ] {
+            if validate_metric_name(value).is_err() {
+                issue(
+                    field,
+                    format!("'{}' must match [a-zA-Z_:][a-zA-Z0-9_:]*", value),
+                );
+            }
+        }
+
+        if self.metrics.max_label_value_length == 0 {
+            issue(
+                "metrics.max_label_value_length",
+                "must be greater than 0".to_string(),
+            );
+        }
+
+        for (i, rule) in self.metrics.rename_rules.iter().enumerate() {
+            let field = format!("metrics.rename_rules[{}]", i);
+            if rule.from == rule.to {
+                issue(&field, format!("renames '{}' to itself", rule.from));
+            }
+            if !self.metrics.utf8_names && validate_metric_name(&rule.to).is_err() {
+                issue(
+                    &field,
+                    format!("target '{}' must match [a-zA-Z_:][a-zA-Z0-9_:]*", rule.to),
+                );
+            }
+            if let Err(e) = validate_label_names(&rule.labels) {
+                issue(&field, e.to_string());
+            }
+        }
+
+        for (i, topk) in self.metrics.topk.iter().enumerate() {
+            let field = format!("metrics.topk[{}]", i);
+            let label = HashMap::from([(topk.label.clone(), String::new())]);
+            if let Err(e) = validate_label_names(&label) {
+                issue(&field, e.to_string());
+            }
+            if topk.k == 0 || topk.width == 0 || topk.depth == 0 {
+                issue(
+                    &field,
+                    "k, width and depth must be greater than 0".to_string(),
+                );
+            }
+        }
+
+        if let Some(anomaly) = &self.metrics.anomaly {
+            if !(anomaly.alpha > 0.0 && anomaly.alpha <= 1.0) {
+                issue(
+                    "metrics.anomaly.alpha",
+                    "must be greater than 0 and at most 1".to_string(),
+                );
+            }
+            if anomaly.threshold <= 0.0 {
+                issue(
+                    "metrics.anomaly.threshold",
+                    "must be greater than 0".to_string(),
+                );
+            }
+            if anomaly.state_ttl_secs == 0 {
+                issue(
+                    "metrics.anomaly.state_ttl_secs",
+                    "must be greater than 0".to_string(),
+                );
+            }
+            self.check_notify_targets("metrics.anomaly.notify", &anomaly.notify, &mut issue);
+        }
+        if let Some(coalescing) = &self.metrics.write_coalescing {
+            if coalescing.max_delay_ms > 1_000 {
+                issue(
+                    "metrics.write_coalescing.max_delay_ms",
+                    "must be at most 1000".to_string(),
+                );
+            }
+            if coalescing.max_batch == 0 {
+                issue(
+                    "metrics.write_coalescing.max_batch",
+                    "must be greater than 0".to_string(),
+                );
+            }
+        }
+        if let Some(drift) = &self.metrics.schema_drift {
+            if drift.state_ttl_secs == 0 {
+                issue(
+                    "metrics.schema_drift.state_ttl_secs",
+                    "must be greater than 0".to_string(),
+                );
+            }
+            self.check_notify_targets("metrics.schema_drift.notify", &drift.notify, &mut issue);
+        }
+        if let Some(StoreConfig::Redis { url, key_prefix }) = &self.metrics.store {
+            if url.is_empty() {
+                issue("metrics.store.url", "must not be empty".to_string());
+            }
+            if key_prefix.is_empty() {
+                issue("metrics.store.key_prefix", "must not be empty".to_string());
+            }
+        }
+
+        if let Some(target_labels) = &self.metrics.target_labels {
+            if let Some(name) = &target_labels.instance_header
+                && !is_header_name(name)
+            {
+                issue(
+                    "metrics.target_labels.instance_header",
+                    format!("'{}' is not a valid header name", name),
+                );
+            }
+            for proxy in &target_labels.trusted_proxies {
+                if IpNetwork::parse(proxy).is_none() {
+                    issue(
+                        "metrics.target_labels.trusted_proxies",
+                        format!("'{}' is not an address or CIDR range", proxy),
+                    );
+                }
+            }
+        }
+
+        match self.metrics.series_ttl_secs {
+            Some(0) => issue(
+                "metrics.series_ttl_secs",
+                "must be greater than 0, leave it unset to keep series forever".to_string(),
+            ),
+            // Series are only expired by the compaction pass
+            Some(ttl) if ttl < self.metrics.compaction_interval_secs => issue(
+                "metrics.series_ttl_secs",
+                format!(
+                    "{}s is shorter than metrics.compaction_interval_secs ({}s), series would outlive it",
+                    ttl, self.metrics.compaction_interval_secs
+                ),
+            ),
+            _ => {}
+        }
+
+        if self.metrics.dedup_window_secs == Some(0) {
+            issue(
+                "metrics.dedup_window_secs",
+                "must be greater than 0, leave it unset to keep duplicates".to_string(),
+            );
+        }
+
+        if self.metrics.max_clock_skew_secs == Some(0) {
+            issue(
+                "metrics.max_clock_skew_secs",
+                "must be greater than 0, leave it unset to keep timestamps as pushed".to_string(),
+            );
+        }
+
+        if self.metrics.max_memory_bytes == Some(0) {
+            issue(
+                "metrics.max_memory_bytes",
+                "must be greater than 0, leave it unset for no limit".to_string(),
+            );
+        }
+
+        if self.metrics.compaction_interval_secs == 0 {
+            issue(
+                "metrics.compaction_interval_secs",
+                "must be greater than 0".to_string(),
+            );
+        }
+
+        for (name, mapping) in &self.metrics.enums {
+            let field = format!("metrics.enums.{}", name);
+            if mapping.values.is_empty() {
+                issue(&field, "values cannot be empty".to_string());
+            }
+            let mut seen = HashSet::new();
+            if let Some(duplicate) = mapping.values.iter().find(|value| !seen.insert(*value)) {
+                issue(&field, format!("'{}' is listed twice", duplicate));
+            }
+            let state_label = HashMap::from([(name.clone(), String::new())]);
+            if mapping.kind == EnumKind::StateSet && validate_label_names(&state_label).is_err() {
+                issue(
+                    &field,
+                    format!("stateset '{}' must be a valid label name", name),
+                );
+            }
+        }
+
+        for (name, rule) in &self.metrics.value_rules {
+            let field = format!("metrics.value_rules.{}", name);
+            match (rule.from, rule.to) {
+                (Some(from), Some(to)) if from.quantity() != to.quantity() => issue(
+                    &field,
+                    format!(
+                        "cannot convert {} units to {} units",
+                        from.quantity(),
+                        to.quantity()
+                    ),
+                ),
+                (Some(_), None) | (None, Some(_)) => {
+                    issue(&field, "from and to must be set together".to_string())
+                }
+                _ => {}
+            }
+            if rule.precision.is_some_and(|precision| precision > 15) {
+                issue(&field, "precision must be at most 15".to_string());
+            }
+        }
+
+        for (source, sampling) in &self.metrics.sampling {
+            let field = format!("metrics.sampling.{}", source);
+            if sampling.batch_one_in == Some(0) {
+                issue(&field, "batch_one_in must be at least 1".to_string());
+            }
+            for (name, probability) in &sampling.metrics {
+                if !(0.0..=1.0).contains(probability) {
+                    issue(
+                        &field,
+                        format!(
+                            "probability of '{}' must be between 0 and 1, got {}",
+                            name, probability
+                        ),
+                    );
+                }
+            }
+        }
+
+        for (i, schema) in self.metrics.schemas.iter().enumerate() {
+            if let Err(e) = validate_schema(schema) {
+                issue(&format!("metrics.schemas[{}]", i), e.to_string());
+            }
+        }
+
+        if let Err(e) = validate_label_names(&self.metrics.self_metrics_labels) {
+            issue("metrics.self_metrics_labels", e.to_string());
+        }
+
+        if let Err(e) = validate_label_names(&self.metrics.external_labels) {
+            issue("metrics.external_labels", e.to_string());
+        }
+
+        if let Some(enrichment) = &self.enrichment {
+            if enrichment.by_source {
+                if !enrichment.key_label.is_empty() {
+                    issue(
+                        "enrichment.key_label",
+                        "can't be set along with by_source".to_string(),
+                    );
+                }
+            } else {
+                let key_label = HashMap::from([(enrichment.key_label.clone(), String::new())]);
+                if let Err(e) = validate_label_names(&key_label) {
+                    issue("enrichment.key_label", e.to_string());
+                }
+            }
+            if enrichment.refresh_interval_secs == 0 {
+                issue(
+                    "enrichment.refresh_interval_secs",
+                    "must be greater than 0".to_string(),
+                );
+            }
+        }
+
+        if let Err(e) = validate_label_names(&self.discovery.labels) {
+            issue("discovery.labels", e.to_string());
+        }
+
+        let mut group_names = HashSet::new();
+        for (i, group) in self.exposition_groups.iter().enumerate() {
+            let field = format!("exposition_groups[{}]", i);
+            if group.name.is_empty() || group.name.contains('/') {
+                issue(&field, format!("invalid group name '{}'", group.name));
+            } else if !group_names.insert(&group.name) {
+                issue(
+                    &field,
+                    format!("duplicate exposition group '{}'", group.name),
+                );
+            }
+            if group.metrics.is_empty() {
+                issue(&field, "metrics must not be empty".to_string());
+            }
+            for pattern in &group.metrics {
+                if let Err(e) = compile_pattern(pattern) {
+                    issue(&field, e.to_string());
+                }
+            }
+        }
+
+        for (i, sink) in self.sinks.iter().enumerate() {
+            let field = format!("sinks[{}]", i);
+            if sink.interval_secs == 0 {
+                issue(&field, "interval_secs must be greater than 0".to_string());
+            }
+            match &sink.kind {
+                SinkKind::Pushgateway { url, job } => {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        issue(&field, format!("'{}' is not an http(s) URL", url));
+                    }
+                    if job.is_empty() {
+                        issue(&field, "job must not be empty".to_string());
+                    }
+                }
+                SinkKind::Graphite { address, .. } => {
+                    if !address.contains(':') {
+                        issue(&field, format!("'{}' must be in host:port form", address));
+                    }
+                }
+                SinkKind::RemoteWrite { url, spill } => {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        issue(&field, format!("'{}' is not an http(s) URL", url));
+                    }
+                    if let Some(spill) = spill {
+                        if spill.directory.is_empty() {
+                            issue(&field, "spill.directory must not be empty".to_string());
+                        }
+                        if spill.max_bytes == 0 {
+                            issue(&field, "spill.max_bytes must be greater than 0".to_string());
+                        }
+                        if let Some(key) = &spill.encryption_key
+                            && hex::decode(key).is_none_or(|key| key.len() != 32)
+                        {
+                            issue(
+                                &field,
+                                "spill.encryption_key must be 64 hex digits".to_string(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        for (i, fanout) in self.fanout.iter().enumerate() {
+            let field = format!("fanout[{}]", i);
+            if fanout.queue_size == 0 {
+                issue(&field, "queue_size must be greater than 0".to_string());
+            }
+            if self.fanout[..i]
+                .iter()
+                .any(|previous| previous.kind == fanout.kind)
+            {
+                issue(&field, "duplicates an earlier destination".to_string());
+            }
+            match &fanout.kind {
+                FanoutKind::Registry => {}
+                FanoutKind::RemoteWrite { url } => {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        issue(&field, format!("'{}' is not an http(s) URL", url));
+                    }
+                }
+                FanoutKind::Kafka { brokers, topic } => {
+                    if brokers.is_empty() || topic.is_empty() {
+                        issue(&field, "brokers and topic must not be empty".to_string());
+                    }
+                }
+                FanoutKind::File { path } => {
+                    if path.is_empty() {
+                        issue(&field, "path must not be empty".to_string());
+                    }
+                }
+            }
+        }
+
+        if let Some(nats) = &self.nats {
+            if nats.stream.is_empty() {
+                issue("nats.stream", "must not be empty".to_string());
+            }
+            if nats.subject.is_empty() {
+                issue("nats.subject", "must not be empty".to_string());
+            }
+            if nats.max_deliver == 0 || nats.max_deliver < -1 {
+                issue(
+                    "nats.max_deliver",
+                    "must be greater than 0, or -1 for unlimited".to_string(),
+                );
+            }
+        }
+
+        if let Some(amqp) = &self.amqp {
+            if !amqp.url.starts_with("amqp://") && !amqp.url.starts_with("amqps://") {
+                issue("amqp.url", format!("'{}' is not an amqp(s) URL", amqp.url));
+            }
+            if amqp.queue.is_empty() {
+                issue("amqp.queue", "must not be empty".to_string());
+            }
+            if amqp.prefetch == 0 {
+                issue("amqp.prefetch", "must be greater than 0".to_string());
+            }
+        }
+
+        match &self.dead_letter {
+            Some(DeadLetterConfig::File { path }) if path.is_empty() => {
+                issue("dead_letter.path", "must not be empty".to_string());
+            }
+            Some(DeadLetterConfig::Kafka { brokers, topic }) => {
+                if brokers.is_empty() {
+                    issue("dead_letter.brokers", "must not be empty".to_string());
+                }
+                if topic.is_empty() {
+                    issue("dead_letter.topic", "must not be empty".to_string());
+                }
+            }
+            Some(DeadLetterConfig::S3 {
+                bucket,
+                access_key_id,
+                secret_access_key,
+                ..
+            }) => {
+                if bucket.is_empty() {
+                    issue("dead_letter.bucket", "must not be empty".to_string());
+                }
+                if access_key_id.is_some() != secret_access_key.is_some() {
+                    issue(
+                        "dead_letter.access_key_id",
+                        "must be set together with secret_access_key".to_string(),
+                    );
+                }
+            }
+            _ => {}
+        }
+        if let Some(vault) = &self.vault {
+            if !vault.address.starts_with("http://") && !vault.address.starts_with("https://") {
+                issue(
+                    "vault.address",
+                    format!("'{}' is not an http(s) URL", vault.address),
+                );
+            }
+            if vault.token.is_empty() {
+                issue("vault.token", "must not be empty".to_string());
+            }
+        }
+        if let Some(auth) = &self.auth {
+            let mut names = HashSet::new();
+            for (i, api_key) in auth.api_keys.iter().enumerate() {
+                let field = format!("auth.api_keys[{}]", i);
+                if api_key.name.is_empty() || !names.insert(api_key.name.as_str()) {
+                    issue(
+                        &field,
+                        format!("name '{}' must be unique and not empty", api_key.name),
+                    );
+                }
+                if api_key.key.len() < 16 {
+                    issue(&field, "key must be at least 16 characters".to_string());
+                }
+                if api_key.roles.is_empty() {
+                    issue(&field, "roles must not be empty".to_string());
+                }
+            }
+            if let Some(jwt) = &auth.jwt {
+                if jwt.algorithm.is_symmetric() {
+                    if jwt.secret.as_deref().is_none_or(str::is_empty) {
+                        issue(
+                            "auth.jwt.secret",
+                            format!("must be set for {:?}", jwt.algorithm),
+                        );
+                    }
+                } else if jwt.public_key_path.is_none() {
+                    issue(
+                        "auth.jwt.public_key_path",
+                        format!("must be set for {:?}", jwt.algorithm),
+                    );
+                }
+            }
+            let verifies_clients = self
+                .server
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.client_ca_path.is_some());
+            if !auth.client_certificates.is_empty() && !verifies_clients {
+                issue(
+                    "auth.client_certificates",
+                    "requires server.tls.client_ca_path".to_string(),
+                );
+            }
+        }
+
+        if let Some(capture) = &self.capture {
+            if capture.dir.is_empty() {
+                issue("capture.dir", "must not be empty".to_string());
+            }
+            if capture.batches_per_source == 0 {
+                issue(
+                    "capture.batches_per_source",
+                    "must be greater than 0".to_string(),
+                );
+            }
+        }
+
+        if let Some(uptime) = &self.uptime {
+            if uptime.dir.is_empty() {
+                issue("uptime.dir", "must not be empty".to_string());
+            }
+            if uptime.max_runs == 0 {
+                issue("uptime.max_runs", "must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(history) = &self.history {
+            if history.interval_secs == 0 {
+                issue(
+                    "history.interval_secs",
+                    "must be greater than 0".to_string(),
+                );
+            }
+            if history.retention_secs < history.interval_secs {
+                issue(
+                    "history.retention_secs",
+                    "must be at least history.interval_secs".to_string(),
+                );
+            }
+            if history.max_samples == 0 {
+                issue("history.max_samples", "must be greater than 0".to_string());
+            }
+        }
+
+        for (i, rule) in self.recording_rules.iter().enumerate() {
+            let field = format!("recording_rules[{}]", i);
+            if let Err(e) = validate_metric_name(&rule.record) {
+                issue(&field, e.to_string());
+            }
+            if let Err(e) = Expr::parse(&rule.expr) {
+                issue(&field, e.to_string());
+            }
+            if let Err(e) = validate_label_names(&rule.labels) {
+                issue(&field, e.to_string());
+            }
+            if rule.interval_secs == 0 {
+                issue(&field, "interval_secs must be greater than 0".to_string());
+            }
+        }
+
+        for (i, synthetic) in self.synthetic.iter().enumerate() {
+            let field = format!("synthetic[{}]", i);
+            if let Err(e) = validate_metric_name(&synthetic.name) {
+                issue(&field, e.to_string());
+            }
+            if let Err(e) = validate_label_names(&synthetic.labels) {
+                issue(&field, e.to_string());
+            }
+            if synthetic.interval_secs == 0 {
+                issue(&field, "interval_secs must be greater than 0".to_string());
+            }
+            match synthetic.pattern {
+                SyntheticPattern::Sine {
+                    amplitude,
+                    offset,
+                    period_secs,
+                } => {
+                    if !(amplitude.is_finite() && offset.is_finite()) {
+                        issue(&field, "amplitude and offset must be finite".to_string());
+                    }
+                    if period_secs == 0 {
+                        issue(&field, "period_secs must be greater than 0".to_string());
+                    }
+                }
+                SyntheticPattern::Step {
+                    low,
+                    high,
+                    period_secs,
+                } => {
+                    if !(low.is_finite() && high.is_finite()) {
+                        issue(&field, "low and high must be finite".to_string());
+                    }
+                    if period_secs == 0 {
+                        issue(&field, "period_secs must be greater than 0".to_string());
+                    }
+                }
+                SyntheticPattern::Random { min, max } => {
+                    if !(min.is_finite() && max.is_finite() && min <= max) {
+                        issue(
+                            &field,
+                            "min and max must be finite, min at most max".to_string(),
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut slo_names = HashSet::new();
+        for (i, slo) in self.slos.iter().enumerate() {
+            let field = format!("slos[{}]", i);
+            if slo.name.is_empty() {
+                issue(&field, "name must not be empty".to_string());
+            } else if !slo_names.insert(&slo.name) {
+                issue(&field, format!("duplicate SLO '{}'", slo.name));
+            }
+            if !(slo.objective > 0.0 && slo.objective < 1.0) {
+                issue(&field, "objective must be between 0 and 1".to_string());
+            }
+            if slo.window_secs == 0 || slo.interval_secs == 0 {
+                issue(
+                    &field,
+                    "window_secs and interval_secs must be greater than 0".to_string(),
+                );
+            }
+            if let SloIndicator::Ratio { good, total } = &slo.indicator {
+                for expr in [good, total] {
+                    if let Err(e) = Expr::parse(expr) {
+                        issue(&field, e.to_string());
+                    }
+                }
+            }
+            if let Some(alert) = &slo.alert {
+                if alert.burn_rate <= 0.0 {
+                    issue(&field, "alert.burn_rate must be greater than 0".to_string());
+                }
+                self.check_notify_targets(&field, &alert.notify, &mut issue);
+            }
+        }
+
+        for (i, window) in self.maintenance_windows.iter().enumerate() {
+            let field = format!("maintenance_windows[{}]", i);
+            if let Err(e) = SeriesSelector::parse(&window.matchers) {
+                issue(&field, e.to_string());
+            }
+            if window.end <= window.start {
+                issue(&field, "end must be after start".to_string());
+            }
+        }
+
+        let mut report_names = HashSet::new();
+        for (i, report) in self.reports.iter().enumerate() {
+            let field = format!("reports[{}]", i);
+            if report.name.is_empty() {
+                issue(&field, "name must not be empty".to_string());
+            } else if !report_names.insert(&report.name) {
+                issue(&field, format!("duplicate report '{}'", report.name));
+            }
+            if let Err(e) = parse_schedule(&report.schedule) {
+                issue(&field, e.to_string());
+            }
+            if cfg!(not(feature = "csv")) && report.format == ReportFormat::Csv {
+                issue(&field, "CSV reports require the `csv` feature".to_string());
+            }
+            if report.sections.is_empty() {
+                issue(&field, "sections must not be empty".to_string());
+            }
+            if report.channels.is_empty() {
+                issue(&field, "channels must not be empty".to_string());
+            }
+            for channel in &report.channels {
+                if !self.notification_channels.contains_key(channel) {
+                    issue(
+                        &field,
+                        format!("unknown notification channel '{}'", channel),
+                    );
+                }
+            }
+        }
+
+        let mut plugin_names = HashSet::new();
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            let field = format!("plugins[{}]", i);
+            if plugin.name.is_empty() {
+                issue(&field, "name must not be empty".to_string());
+            } else if !plugin_names.insert(&plugin.name) {
+                issue(&field, format!("duplicate plugin '{}'", plugin.name));
+            }
+            if plugin.path.is_empty() {
+                issue(&field, "path must not be empty".to_string());
+            }
+            if plugin.fuel == 0 {
+                issue(&field, "fuel must be greater than 0".to_string());
+            }
+        }
+
+        let mut script_names = HashSet::new();
+        for (i, script) in self.scripts.iter().enumerate() {
+            let field = format!("scripts[{}]", i);
+            if script.name.is_empty() {
+                issue(&field, "name must not be empty".to_string());
+            } else if !script_names.insert(&script.name) {
+                issue(&field, format!("duplicate script '{}'", script.name));
+            }
+            if script.path.is_empty() {
+                issue(&field, "path must not be empty".to_string());
+            }
+            if script.max_operations == 0 || script.timeout_ms == 0 {
+                issue(
+                    &field,
+                    "max_operations and timeout_ms must be greater than 0".to_string(),
+                );
+            }
+        }
+
+        let mut webhook_names = HashSet::new();
+        for (i, webhook) in self.event_webhooks.iter().enumerate() {
+            let field = format!("event_webhooks[{}]", i);
+            if webhook.name.is_empty() {
+                issue(&field, "name must not be empty".to_string());
+            } else if !webhook_names.insert(&webhook.name) {
+                issue(&field, format!("duplicate webhook '{}'", webhook.name));
+            }
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                issue(&field, format!("'{}' is not an http(s) URL", webhook.url));
+            }
+        }
+
+        for (name, channel) in &self.notification_channels {
+            let field = format!("notification_channels.{}", name);
+            let url = match channel {
+                NotificationChannelConfig::Slack { webhook_url, .. } => Some(webhook_url),
+                NotificationChannelConfig::Pagerduty { url, .. } => Some(url),
+                NotificationChannelConfig::Webhook { url, .. } => Some(url),
+                NotificationChannelConfig::Email { to, .. } => {
+                    if to.is_empty() {
+                        issue(&field, "to must not be empty".to_string());
+                    }
+                    None
+                }
+            };
+            if let Some(url) = url
+                && !url.starts_with("http://")
+                && !url.starts_with("https://")
+            {
+                issue(&field, format!("'{}' is not an http(s) URL", url));
+            }
+        }
+
+        if let Err(e) = validate_histogram_buckets(&self.metrics.histogram_buckets) {
+            issue("metrics.histogram_buckets", e.to_string());
+        }
+
+        if let Err(e) = validate_histogram_buckets(&self.metrics.timer_buckets) {
+            issue("metrics.timer_buckets", e.to_string());
+        }
+
+        issues
+    }
+
+    fn check_notify_targets(
+        &self,
+        field: &str,
+        targets: &[NotifyTarget],
+        issue: &mut impl FnMut(&str, String),
+    ) {
+        for target in targets {
+            if !self.notification_channels.contains_key(&target.channel) {
+                issue(
+                    field,
+                    format!("unknown notification channel '{}'", target.channel),
+                );
+            }
+        }
+    }
+}
+
+fn value_origin(config: &Config, field: &str) -> Option<String> {
+    let (section, key) = match field.rsplit_once('.') {
+        Some((section, key)) => (Some(section), key),
+        None => (None, field),
+    };
+    // Indexed fields such as `rename_rules[0]` report the origin of the whole list
+    let key = key.split('[').next().unwrap_or(key);
+    let table = match section {
+        Some(section) => config.get_table(section).ok()?,
+        None => config.collect().ok()?,
+    };
+    table.get(key)?.origin().map(|origin| origin.to_string())
+}
+
+/// Records the origin of every value set by a file or the environment. Lists
+/// are recorded whole, like the origins of validation issues.
+fn collect_origins(
+    prefix: &str,
+    values: &config::Map<String, Value>,
+    origins: &mut BTreeMap<String, String>,
+) {
+    for (key, value) in values {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        if let ValueKind::Table(table) = &value.kind {
+            collect_origins(&path, table, origins);
+        } else if let Some(origin) = value.origin() {
+            // The environment source's origin is "the environment"
+            let origin = if origin == "the environment" {
+                "env".to_string()
+            } else {
+                format!("file:{}", origin)
+            };
+            origins.insert(path, origin);
+        }
+    }
+}
+
+/// A bcrypt hash in modular crypt format, e.g. `$2y$10$` followed by the
+/// salt and hash in 53 characters, as `htpasswd -B` writes.
+fn is_bcrypt_hash(hash: &str) -> bool {
+    let mut parts = hash.split('$');
+    matches!(
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()),
+        (Some(""), Some("2a" | "2b" | "2x" | "2y"), Some(cost), Some(rest), None)
+            if cost.len() == 2
+                && cost.parse::<u32>().is_ok_and(|cost| (4..=31).contains(&cost))
+                && rest.len() == 53
+                && rest.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'/')
+    )
+}
+
+/// An HTTP header name: a non-empty token (RFC 9110).
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn issues_to_error(issues: &[ConfigIssue]) -> ServerError {
+    let details: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+    ServerError::ConfigurationError(details.join("; "))
 }
 
 impl Default for AppConfig {
@@ -55,12 +2726,81 @@ impl Default for AppConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8080,
                 workers: num_cpus::get(),
+                timeouts: TimeoutConfig::default(),
+                load_shedding: LoadSheddingConfig::default(),
+                scrape: ScrapeConfig::default(),
+                signing: None,
+                lockout: None,
+                tls: None,
+                reuse_port: false,
+                shutdown_timeout_secs: default_shutdown_timeout_secs(),
+                maintenance_retry_after_secs: default_maintenance_retry_after_secs(),
             },
             metrics: MetricsConfig {
                 prometheus_endpoint: "/metrics".to_string(),
                 metrics_prefix: "app".to_string(),
                 metrics_namespace: "metrics_server".to_string(),
+                histogram_buckets: default_histogram_buckets(),
+                timer_buckets: default_timer_buckets(),
+                negative_counter_policy: NegativeCounterPolicy::default(),
+                non_finite_policy: NonFinitePolicy::default(),
+                max_label_value_length: default_max_label_value_length(),
+                label_value_policy: LabelValuePolicy::default(),
+                canonicalize_labels: true,
+                utf8_names: false,
+                rename_rules: Vec::new(),
+                self_metrics_labels: HashMap::new(),
+                external_labels: HashMap::new(),
+                target_labels: None,
+                schemas: Vec::new(),
+                unknown_metric_policy: UnknownMetricPolicy::default(),
+                reject_unregistered_sources: false,
+                sources_path: None,
+                ingest_mode: IngestMode::default(),
+                source_ingest_modes: HashMap::new(),
+                dedup_window_secs: None,
+                source_dedup_window_secs: HashMap::new(),
+                max_clock_skew_secs: None,
+                source_max_clock_skew_secs: HashMap::new(),
+                sampling: HashMap::new(),
+                series_ttl_secs: None,
+                compaction_interval_secs: default_compaction_interval_secs(),
+                max_memory_bytes: None,
+                tombstone_grace_secs: default_tombstone_grace_secs(),
+                enums: HashMap::new(),
+                value_rules: HashMap::new(),
+                derived_rates: false,
+                topk: Vec::new(),
+                anomaly: None,
+                schema_drift: None,
+                write_coalescing: None,
+                store: None,
             },
+            enrichment: None,
+            plugins: Vec::new(),
+            scripts: Vec::new(),
+            kubernetes: KubernetesConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            exposition_groups: Vec::new(),
+            sinks: Vec::new(),
+            fanout: Vec::new(),
+            nats: None,
+            amqp: None,
+            dead_letter: None,
+            capture: None,
+            encryption: None,
+            uptime: None,
+            history: None,
+            recording_rules: Vec::new(),
+            synthetic: Vec::new(),
+            slos: Vec::new(),
+            reports: Vec::new(),
+            event_webhooks: Vec::new(),
+            notification_channels: HashMap::new(),
+            maintenance_windows: Vec::new(),
+            vault: None,
+            auth: None,
+            origins: BTreeMap::new(),
         }
     }
 }
@@ -0,0 +1,280 @@
+//! Secrets kept out of the configuration: a secret setting can be read from
+//! a file by suffixing its name with `_file`, or set to `vault:<path>#<field>`
+//! to be fetched from Vault's KV store at startup. Other settings, such as
+//! paths, are left as they are.
+
+use super::VaultConfig;
+use crate::errors::ServerError;
+use config::{Config, Map, Source, Value, ValueKind};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+const FILE_SUFFIX: &str = "_file";
+const VAULT_PREFIX: &str = "vault:";
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings holding secrets wherever they appear.
+const SECRET_SETTINGS: &[&str] = &[
+    "access_key_id",
+    "api_key",
+    "encryption_key",
+    "key",
+    "password",
+    "routing_key",
+    "secret",
+    "secret_access_key",
+    "token",
+    "url",
+    "webhook_url",
+];
+
+/// Maps whose every entry is a secret.
+const SECRET_MAPS: &[&str] = &["basic_auth_users", "headers", "secrets", "source_keys"];
+
+/// Whether `name`, set in the table at `prefix`, is a secret.
+fn is_secret(prefix: &str, name: &str) -> bool {
+    let parent = prefix.rsplit('.').next().unwrap_or_default();
+    let parent = parent.split('[').next().unwrap_or_default();
+    SECRET_SETTINGS.contains(&name) || SECRET_MAPS.contains(&parent)
+}
+
+/// Resolves the secrets `config` refers to, returning it with their values
+/// set and, by setting, where each came from: `secret_file:<path>` or
+/// `vault:<path>`.
+pub fn resolve_secrets(config: Config) -> Result<(Config, BTreeMap<String, String>), ServerError> {
+    let mut origins = BTreeMap::new();
+
+    // Files first, so the Vault token itself can be kept in one
+    let mut files = Vec::new();
+    collect_files("", &collect(&config)?, &mut files)?;
+    let config = if files.is_empty() {
+        config
+    } else {
+        let mut builder = Config::builder().add_source(config);
+        for (setting, path) in files {
+            let secret = read_secret_file(&setting, &path)?;
+            builder = builder
+                .set_override(&setting, secret)
+                .map_err(|e| ServerError::ConfigurationError(e.to_string()))?;
+            origins.insert(setting, format!("secret_file:{}", path));
+        }
+        build(builder)?
+    };
+
+    let mut references = Vec::new();
+    collect_vault_references("", &collect(&config)?, &mut references);
+    if references.is_empty() {
+        return Ok((config, origins));
+    }
+    let vault: Option<VaultConfig> = config
+        .get("vault")
+        .map_err(|e| ServerError::ConfigurationError(format!("vault: {}", e)))?;
+    let Some(vault) = vault else {
+        return Err(ServerError::ConfigurationError(format!(
+            "{}: refers to Vault, but [vault] isn't configured",
+            references[0].0
+        )));
+    };
+
+    let secrets = fetch_from_vault(vault, references.clone())?;
+    let mut builder = Config::builder().add_source(config);
+    for ((setting, path, _), secret) in references.into_iter().zip(secrets) {
+        builder = builder
+            .set_override(&setting, secret)
+            .map_err(|e| ServerError::ConfigurationError(e.to_string()))?;
+        origins.insert(setting, format!("vault:{}", path));
+    }
+    Ok((build(builder)?, origins))
+}
+
+/// Reads the secret `setting` from the file at `path`, without its trailing
+/// newlines.
+pub fn read_secret_file(setting: &str, path: &str) -> Result<String, ServerError> {
+    let secret = std::fs::read_to_string(path).map_err(|e| {
+        ServerError::ConfigurationError(format!(
+            "{}{}: can't read {}: {}",
+            setting, FILE_SUFFIX, path, e
+        ))
+    })?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn collect(config: &Config) -> Result<Map<String, Value>, ServerError> {
+    config
+        .collect()
+        .map_err(|e| ServerError::ConfigurationError(e.to_string()))
+}
+
+fn build(
+    builder: config::ConfigBuilder<config::builder::DefaultState>,
+) -> Result<Config, ServerError> {
+    builder
+        .build()
+        .map_err(|e| ServerError::ConfigurationError(e.to_string()))
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// The secret settings set through `<name>_file`, with the path of their file.
+fn collect_files(
+    prefix: &str,
+    values: &Map<String, Value>,
+    files: &mut Vec<(String, String)>,
+) -> Result<(), ServerError> {
+    for (key, value) in values {
+        let path = join(prefix, key);
+        match &value.kind {
+            ValueKind::Table(table) => collect_files(&path, table, files)?,
+            ValueKind::Array(items) => collect_files_in_array(&path, items, files)?,
+            ValueKind::String(file) => {
+                let Some(name) = key
+                    .strip_suffix(FILE_SUFFIX)
+                    .filter(|name| !name.is_empty() && is_secret(prefix, name))
+                else {
+                    continue;
+                };
+                if values.contains_key(name) {
+                    return Err(ServerError::ConfigurationError(format!(
+                        "{}: set either {} or {}{}, not both",
+                        join(prefix, name),
+                        name,
+                        name,
+                        FILE_SUFFIX
+                    )));
+                }
+                files.push((join(prefix, name), file.clone()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn collect_files_in_array(
+    prefix: &str,
+    items: &[Value],
+    files: &mut Vec<(String, String)>,
+) -> Result<(), ServerError> {
+    for (i, item) in items.iter().enumerate() {
+        let path = format!("{}[{}]", prefix, i);
+        match &item.kind {
+            ValueKind::Table(table) => collect_files(&path, table, files)?,
+            ValueKind::Array(items) => collect_files_in_array(&path, items, files)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The secret settings set to `vault:<path>#<field>`, with the path and field.
+fn collect_vault_references(
+    prefix: &str,
+    values: &Map<String, Value>,
+    references: &mut Vec<(String, String, String)>,
+) {
+    for (key, value) in values {
+        let secret = is_secret(prefix, key);
+        collect_vault_reference(join(prefix, key), secret, value, references);
+    }
+}
+
+fn collect_vault_reference(
+    setting: String,
+    secret: bool,
+    value: &Value,
+    references: &mut Vec<(String, String, String)>,
+) {
+    match &value.kind {
+        ValueKind::Table(table) => collect_vault_references(&setting, table, references),
+        ValueKind::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let setting = format!("{}[{}]", setting, i);
+                collect_vault_reference(setting, secret, item, references);
+            }
+        }
+        ValueKind::String(s) if secret => {
+            if let Some(reference) = s.strip_prefix(VAULT_PREFIX) {
+                let (path, field) = reference.rsplit_once('#').unwrap_or((reference, ""));
+                references.push((setting, path.to_string(), field.to_string()));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Fetches the referenced fields, in order. Configuration loads
+/// synchronously, possibly from within a runtime, so the requests run on a
+/// thread with a runtime of its own.
+fn fetch_from_vault(
+    vault: VaultConfig,
+    references: Vec<(String, String, String)>,
+) -> Result<Vec<String>, ServerError> {
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?
+            .block_on(fetch_secrets(&vault, &references))
+    })
+    .join()
+    .map_err(|_| ServerError::ConfigurationError("vault: fetching secrets panicked".into()))?
+}
+
+async fn fetch_secrets(
+    vault: &VaultConfig,
+    references: &[(String, String, String)],
+) -> Result<Vec<String>, ServerError> {
+    let client = reqwest::Client::builder()
+        .timeout(VAULT_TIMEOUT)
+        .build()
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+    let mut secrets: HashMap<&str, serde_json::Map<String, JsonValue>> = HashMap::new();
+    let mut values = Vec::with_capacity(references.len());
+    for (setting, path, field) in references {
+        let error = |message: String| {
+            ServerError::ConfigurationError(format!("{}: vault:{}: {}", setting, path, message))
+        };
+        if field.is_empty() {
+            return Err(error("expected vault:<path>#<field>".to_string()));
+        }
+
+        if !secrets.contains_key(path.as_str()) {
+            let url = format!(
+                "{}/v1/{}/data/{}",
+                vault.address.trim_end_matches('/'),
+                vault.mount.trim_matches('/'),
+                path.trim_start_matches('/')
+            );
+            let response: JsonValue = client
+                .get(&url)
+                .header("X-Vault-Token", &vault.token)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| error(e.to_string()))?
+                .json()
+                .await
+                .map_err(|e| error(e.to_string()))?;
+            let JsonValue::Object(data) = response["data"]["data"].clone() else {
+                return Err(error("not a KV version 2 secret".to_string()));
+            };
+            secrets.insert(path, data);
+        }
+
+        let value = match secrets[path.as_str()].get(field) {
+            Some(JsonValue::String(value)) => value.clone(),
+            Some(value) => value.to_string(),
+            None => return Err(error(format!("has no field '{}'", field))),
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
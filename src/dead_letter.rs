@@ -0,0 +1,163 @@
+pub mod file;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+pub use file::FileDeadLetterWriter;
+
+use crate::config::DeadLetterConfig;
+use crate::encryption::Keyring;
+use crate::errors::ServerError;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Dead letters waiting for their writer; more are dropped with a warning.
+pub const DEAD_LETTER_QUEUE_SIZE: usize = 1024;
+
+/// A rejected payload together with why it was rejected, so producers can
+/// fix and replay it instead of losing the data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub timestamp: DateTime<Utc>,
+    pub source: String,
+    pub reason: String,
+    pub error: String,
+    pub payload: serde_json::Value,
+}
+
+impl DeadLetter {
+    pub fn new(source: &str, payload: serde_json::Value, error: &ServerError) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            source: source.to_string(),
+            reason: error.reason().to_string(),
+            error: error.to_string(),
+            payload,
+        }
+    }
+}
+
+/// A destination rejected payloads are forwarded to.
+pub trait DeadLetterWriter: Send + Sync {
+    fn write<'a>(&'a self, letter: &'a DeadLetter) -> BoxFuture<'a, Result<(), ServerError>>;
+}
+
+enum Queued {
+    Letter(DeadLetter),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Hands dead letters to their writer on a task of its own, so a slow
+/// destination doesn't hold up the requests that rejected them.
+pub struct DeadLetterQueue {
+    sender: mpsc::Sender<Queued>,
+    // Taken by the first `send`, which spawns the writing task
+    worker: Mutex<Option<Worker>>,
+}
+
+struct Worker {
+    receiver: mpsc::Receiver<Queued>,
+    writer: Arc<dyn DeadLetterWriter>,
+}
+
+impl DeadLetterQueue {
+    pub fn new(writer: Arc<dyn DeadLetterWriter>) -> Self {
+        let (sender, receiver) = mpsc::channel(DEAD_LETTER_QUEUE_SIZE);
+        Self {
+            sender,
+            worker: Mutex::new(Some(Worker { receiver, writer })),
+        }
+    }
+
+    /// Queues `letter` for writing, dropping it if the queue is full.
+    pub fn send(&self, letter: DeadLetter) {
+        self.spawn();
+        if let Err(e) = self.sender.try_send(Queued::Letter(letter))
+            && let Queued::Letter(letter) = e.into_inner()
+        {
+            warn!(
+                "Dead-letter queue full, dropping the letter from {}",
+                letter.source
+            );
+        }
+    }
+
+    /// Waits for the letters queued so far to be written, e.g. at shutdown.
+    pub async fn flush(&self) {
+        self.spawn();
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Queued::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    fn spawn(&self) {
+        let worker = match self.worker.lock() {
+            Ok(mut worker) => worker.take(),
+            Err(_) => return,
+        };
+        let Some(Worker {
+            mut receiver,
+            writer,
+        }) = worker
+        else {
+            return;
+        };
+        tokio::spawn(async move {
+            while let Some(queued) = receiver.recv().await {
+                match queued {
+                    Queued::Letter(letter) => {
+                        if let Err(e) = writer.write(&letter).await {
+                            warn!("Failed to write dead letter from {}: {}", letter.source, e);
+                        }
+                    }
+                    Queued::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// The writer of `config`. Dead letters written to a local file are
+/// encrypted with the key of their source in `keyring`.
+pub fn build_writer(
+    config: &DeadLetterConfig,
+    keyring: Arc<Keyring>,
+) -> Result<Arc<dyn DeadLetterWriter>, ServerError> {
+    match config {
+        DeadLetterConfig::File { path } => Ok(Arc::new(
+            FileDeadLetterWriter::new(path).with_keyring(keyring),
+        )),
+        #[cfg(feature = "kafka")]
+        DeadLetterConfig::Kafka { brokers, topic } => {
+            Ok(Arc::new(kafka::KafkaDeadLetterWriter::new(brokers, topic)?))
+        }
+        #[cfg(feature = "s3")]
+        DeadLetterConfig::S3 {
+            bucket,
+            prefix,
+            region,
+            endpoint,
+            access_key_id,
+            secret_access_key,
+        } => Ok(Arc::new(s3::S3DeadLetterWriter::new(
+            bucket,
+            prefix,
+            region.as_deref(),
+            endpoint.as_deref(),
+            access_key_id.as_deref().zip(secret_access_key.as_deref()),
+        )?)),
+        #[allow(unreachable_patterns)]
+        other => Err(ServerError::ConfigurationError(format!(
+            "Dead-letter destination {:?} requires building with the matching feature",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,56 @@
+use super::{DeadLetter, DeadLetterWriter};
+use crate::encryption::Keyring;
+use crate::errors::ServerError;
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Appends dead letters as JSON lines to a local file.
+pub struct FileDeadLetterWriter {
+    path: PathBuf,
+    keyring: Arc<Keyring>,
+    lock: Mutex<()>,
+}
+
+impl FileDeadLetterWriter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            keyring: Arc::default(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Seals each line with the key of the dead letter's source.
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+}
+
+impl DeadLetterWriter for FileDeadLetterWriter {
+    fn write<'a>(&'a self, letter: &'a DeadLetter) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(letter)?;
+            line.push(b'\n');
+            let line = self.keyring.seal_line(&letter.source, line)?;
+
+            // One writer at a time so concurrent lines never interleave
+            let _guard = self.lock.lock().await;
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            file.write_all(&line)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            file.flush()
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))
+        })
+    }
+}
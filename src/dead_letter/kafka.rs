@@ -0,0 +1,45 @@
+use super::{DeadLetter, DeadLetterWriter};
+use crate::errors::ServerError;
+use futures::future::BoxFuture;
+use rdkafka::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::time::Duration;
+
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Produces dead letters to a Kafka topic, keyed by the batch source.
+pub struct KafkaDeadLetterWriter {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaDeadLetterWriter {
+    pub fn new(brokers: &str, topic: &str) -> Result<Self, ServerError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create()
+            .map_err(|e| ServerError::ConfigurationError(format!("Kafka producer: {}", e)))?;
+
+        Ok(Self {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+impl DeadLetterWriter for KafkaDeadLetterWriter {
+    fn write<'a>(&'a self, letter: &'a DeadLetter) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let payload = serde_json::to_vec(letter)?;
+            let record = FutureRecord::to(&self.topic)
+                .key(&letter.source)
+                .payload(&payload);
+
+            self.producer
+                .send(record, QUEUE_TIMEOUT)
+                .await
+                .map(|_| ())
+                .map_err(|(e, _)| ServerError::InternalError(Box::new(e)))
+        })
+    }
+}
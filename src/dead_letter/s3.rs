@@ -0,0 +1,73 @@
+use super::{DeadLetter, DeadLetterWriter};
+use crate::errors::ServerError;
+use futures::future::BoxFuture;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Stores every dead letter as its own JSON object under
+/// `<prefix>/<date>/<timestamp>-<sequence>.json`. Credentials come from the
+/// usual `AWS_*` environment variables unless given.
+pub struct S3DeadLetterWriter {
+    store: AmazonS3,
+    prefix: String,
+    sequence: AtomicU64,
+}
+
+impl S3DeadLetterWriter {
+    pub fn new(
+        bucket: &str,
+        prefix: &str,
+        region: Option<&str>,
+        endpoint: Option<&str>,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<Self, ServerError> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Some(region) = region {
+            builder = builder.with_region(region);
+        }
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some((access_key_id, secret_access_key)) = credentials {
+            builder = builder
+                .with_access_key_id(access_key_id)
+                .with_secret_access_key(secret_access_key);
+        }
+
+        let store = builder
+            .build()
+            .map_err(|e| ServerError::ConfigurationError(format!("S3 dead-letter store: {}", e)))?;
+
+        Ok(Self {
+            store,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    fn object_path(&self, letter: &DeadLetter) -> Path {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        Path::from(format!(
+            "{}/{}/{}-{}.json",
+            self.prefix,
+            letter.timestamp.format("%Y/%m/%d"),
+            letter.timestamp.timestamp_micros(),
+            sequence
+        ))
+    }
+}
+
+impl DeadLetterWriter for S3DeadLetterWriter {
+    fn write<'a>(&'a self, letter: &'a DeadLetter) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let body = serde_json::to_vec(letter)?;
+            self.store
+                .put(&self.object_path(letter), PutPayload::from(body))
+                .await
+                .map(|_| ())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))
+        })
+    }
+}
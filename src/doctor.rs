@@ -0,0 +1,406 @@
+//! `rustic-insights doctor`: checks a deployment before it goes live, from
+//! the configuration to the port it listens on, the directories it writes
+//! to and the upstreams it forwards to.
+
+use crate::config::{
+    AppConfig, ConfigIssue, DeadLetterConfig, EnrichmentProvider, FanoutKind,
+    NotificationChannelConfig, ServerConfig, SinkKind, StoreConfig,
+};
+use crate::listener::bind_listener;
+use chrono::Utc;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Certificates of upstreams expiring sooner are reported as a warning.
+const CERT_EXPIRY_WARNING_DAYS: i64 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Works today, but needs attention, e.g. a certificate about to expire.
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Pass => "PASS",
+            Outcome::Warn => "WARN",
+            Outcome::Fail => "FAIL",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Runs every check that applies to `config`, reporting `issues`, the ones
+/// `AppConfig::load_and_check` found with the files they come from.
+pub async fn run_checks(config: &AppConfig, issues: &[ConfigIssue]) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    if issues.is_empty() {
+        checks.push(Check::new("config", Outcome::Pass, "valid"));
+    }
+    for issue in issues {
+        checks.push(Check::new("config", Outcome::Fail, issue.to_string()));
+    }
+
+    checks.push(check_listener(&config.server));
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.server.tls {
+        checks.push(check_server_certificate(tls));
+    }
+
+    for (name, dir) in directories(config) {
+        checks.push(check_directory(name, &dir));
+    }
+
+    for (name, address) in upstreams(config) {
+        checks.push(check_upstream(name, &address).await);
+    }
+
+    checks
+}
+
+fn check_listener(server: &ServerConfig) -> Check {
+    let name = "listener";
+    let addr = match (server.host.as_str(), server.port).to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => addr,
+            None => {
+                return Check::new(
+                    name,
+                    Outcome::Fail,
+                    format!("{}:{} did not resolve", server.host, server.port),
+                );
+            }
+        },
+        Err(e) => {
+            return Check::new(
+                name,
+                Outcome::Fail,
+                format!("{}:{}: {}", server.host, server.port, e),
+            );
+        }
+    };
+    match bind_listener(addr, server.reuse_port) {
+        Ok(_) => Check::new(name, Outcome::Pass, format!("can bind {}", addr)),
+        Err(e) => Check::new(name, Outcome::Fail, format!("can't bind {}: {}", addr, e)),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn check_server_certificate(tls: &crate::config::TlsConfig) -> Check {
+    let name = "server.tls";
+    let key = match crate::tls::CertificateReloader::load(tls) {
+        Ok(reloader) => reloader.current(),
+        Err(e) => return Check::new(name, Outcome::Fail, e.to_string()),
+    };
+    let days = key
+        .end_entity_cert()
+        .map_err(|e| e.to_string())
+        .and_then(|der| {
+            x509_parser::parse_x509_certificate(der)
+                .map(|(_, certificate)| certificate.validity().not_after.timestamp())
+                .map_err(|e| e.to_string())
+        })
+        .map(|not_after| (not_after - Utc::now().timestamp()).div_euclid(86_400));
+    match days {
+        Ok(days) if days < 0 => Check::new(name, Outcome::Fail, "certificate expired"),
+        Ok(days) if days < CERT_EXPIRY_WARNING_DAYS => Check::new(
+            name,
+            Outcome::Warn,
+            format!("certificate expires in {} days", days),
+        ),
+        Ok(days) => Check::new(
+            name,
+            Outcome::Pass,
+            format!("certificate valid for {} more days", days),
+        ),
+        Err(e) => Check::new(name, Outcome::Fail, e),
+    }
+}
+
+/// The directories the server writes to, named after their setting.
+fn directories(config: &AppConfig) -> Vec<(String, PathBuf)> {
+    let parent = |path: &str| {
+        Path::new(path)
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    };
+
+    let mut dirs = Vec::new();
+    if let Some(capture) = &config.capture {
+        dirs.push(("capture.dir".to_string(), PathBuf::from(&capture.dir)));
+    }
+    if let Some(uptime) = &config.uptime {
+        dirs.push(("uptime.dir".to_string(), PathBuf::from(&uptime.dir)));
+    }
+    for (i, sink) in config.sinks.iter().enumerate() {
+        if let SinkKind::RemoteWrite {
+            spill: Some(spill), ..
+        } = &sink.kind
+        {
+            dirs.push((
+                format!("sinks[{}].spill.directory", i),
+                PathBuf::from(&spill.directory),
+            ));
+        }
+    }
+    for (i, fanout) in config.fanout.iter().enumerate() {
+        if let FanoutKind::File { path } = &fanout.kind {
+            dirs.push((format!("fanout[{}].path", i), parent(path)));
+        }
+    }
+    if let Some(path) = config
+        .history
+        .as_ref()
+        .and_then(|history| history.annotations_path.as_ref())
+    {
+        dirs.push(("history.annotations_path".to_string(), parent(path)));
+    }
+    if let Some(DeadLetterConfig::File { path }) = &config.dead_letter {
+        dirs.push(("dead_letter.path".to_string(), parent(path)));
+    }
+    dirs
+}
+
+/// Creates the directory if needed and writes a probe file into it.
+fn check_directory(name: String, dir: &Path) -> Check {
+    let probe = dir.join(".rustic-insights-doctor");
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&probe, b"probe"))
+        .and_then(|()| std::fs::remove_file(&probe));
+    match result {
+        Ok(()) => Check::new(
+            name,
+            Outcome::Pass,
+            format!("{} is writable", dir.display()),
+        ),
+        Err(e) => Check::new(
+            name,
+            Outcome::Fail,
+            format!("{} is not writable: {}", dir.display(), e),
+        ),
+    }
+}
+
+/// The upstreams the server connects to, named after their setting, as URLs
+/// or `host:port` addresses.
+fn upstreams(config: &AppConfig) -> Vec<(String, String)> {
+    let brokers = |name: &str, brokers: &str| -> Vec<(String, String)> {
+        brokers
+            .split(',')
+            .map(|broker| (name.to_string(), broker.trim().to_string()))
+            .collect()
+    };
+
+    let mut upstreams = Vec::new();
+    for (i, sink) in config.sinks.iter().enumerate() {
+        let address = match &sink.kind {
+            SinkKind::Pushgateway { url, .. } | SinkKind::RemoteWrite { url, .. } => url,
+            SinkKind::Graphite { address, .. } => address,
+        };
+        upstreams.push((format!("sinks[{}]", i), address.clone()));
+    }
+    for (i, fanout) in config.fanout.iter().enumerate() {
+        match &fanout.kind {
+            FanoutKind::RemoteWrite { url } => {
+                upstreams.push((format!("fanout[{}]", i), url.clone()))
+            }
+            FanoutKind::Kafka { brokers: list, .. } => {
+                upstreams.extend(brokers(&format!("fanout[{}]", i), list))
+            }
+            FanoutKind::Registry | FanoutKind::File { .. } => {}
+        }
+    }
+    match &config.dead_letter {
+        Some(DeadLetterConfig::Kafka { brokers: list, .. }) => {
+            upstreams.extend(brokers("dead_letter", list))
+        }
+        Some(DeadLetterConfig::S3 {
+            endpoint: Some(endpoint),
+            ..
+        }) => upstreams.push(("dead_letter".to_string(), endpoint.clone())),
+        _ => {}
+    }
+    if let Some(StoreConfig::Redis { url, .. }) = &config.metrics.store {
+        upstreams.push(("metrics.store".to_string(), url.clone()));
+    }
+    if let Some(nats) = &config.nats {
+        upstreams.extend(brokers("nats", &nats.url));
+    }
+    if let Some(amqp) = &config.amqp {
+        upstreams.push(("amqp".to_string(), amqp.url.clone()));
+    }
+    if let Some(EnrichmentProvider::Http { url }) = config
+        .enrichment
+        .as_ref()
+        .map(|enrichment| &enrichment.provider)
+    {
+        upstreams.push(("enrichment".to_string(), url.clone()));
+    }
+    let mut channels: Vec<_> = config.notification_channels.iter().collect();
+    channels.sort_by_key(|(name, _)| name.as_str());
+    for (name, channel) in channels {
+        let address = match channel {
+            NotificationChannelConfig::Slack { webhook_url, .. } => webhook_url.clone(),
+            NotificationChannelConfig::Pagerduty { url, .. }
+            | NotificationChannelConfig::Webhook { url, .. } => url.clone(),
+            NotificationChannelConfig::Email {
+                smtp_host,
+                smtp_port,
+                ..
+            } => format!("{}:{}", smtp_host, smtp_port),
+        };
+        upstreams.push((format!("notification_channels.{}", name), address));
+    }
+    upstreams
+}
+
+/// Connects to the upstream, and for TLS schemes checks its certificate is
+/// valid for the host and not about to expire.
+async fn check_upstream(name: String, address: &str) -> Check {
+    let Some((host, port, tls)) = parse_address(address) else {
+        return Check::new(
+            name,
+            Outcome::Fail,
+            format!("can't tell the host and port of '{}'", address),
+        );
+    };
+    let target = format!("{}:{}", host, port);
+
+    let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&target)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            return Check::new(
+                name,
+                Outcome::Fail,
+                format!("{} unreachable: {}", target, e),
+            );
+        }
+        Err(_) => {
+            return Check::new(
+                name,
+                Outcome::Fail,
+                format!("{} unreachable: timed out", target),
+            );
+        }
+    };
+    if !tls {
+        return Check::new(name, Outcome::Pass, format!("{} reachable", target));
+    }
+
+    match check_certificate(&host, stream).await {
+        Ok(days) if days < CERT_EXPIRY_WARNING_DAYS => Check::new(
+            name,
+            Outcome::Warn,
+            format!("{} reachable, certificate expires in {} days", target, days),
+        ),
+        Ok(days) => Check::new(
+            name,
+            Outcome::Pass,
+            format!(
+                "{} reachable, certificate valid for {} more days",
+                target, days
+            ),
+        ),
+        Err(e) => Check::new(
+            name,
+            Outcome::Fail,
+            format!("{} has an invalid certificate: {}", target, e),
+        ),
+    }
+}
+
+/// Host, port and whether the connection uses TLS, for a URL or a
+/// `host:port` address.
+fn parse_address(address: &str) -> Option<(String, u16, bool)> {
+    let (scheme, rest) = match address.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+        None => (None, address),
+    };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    let (default_port, tls) = match scheme.as_deref() {
+        Some("https") => (Some(443), true),
+        Some("http") => (Some(80), false),
+        Some("amqps") => (Some(5671), true),
+        Some("amqp") => (Some(5672), false),
+        Some("rediss") => (Some(6380), true),
+        Some("redis") => (Some(6379), false),
+        Some("tls") => (Some(4222), true),
+        Some("nats") => (Some(4222), false),
+        _ => (None, false),
+    };
+
+    // IPv6 literals are bracketed, e.g. `[::1]:8080`
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port.parse().ok()?)),
+        _ => (authority, None),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.or(default_port)?, tls))
+}
+
+/// Days until the certificate the host presents expires, after checking it
+/// chains to one of the Mozilla root CAs and is valid for the host.
+async fn check_certificate(host: &str, stream: TcpStream) -> Result<i64, String> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let server_name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+    let tls = tokio::time::timeout(CONNECT_TIMEOUT, connector.connect(server_name, stream))
+        .await
+        .map_err(|_| "handshake timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let der = tls
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .ok_or_else(|| "no certificate presented".to_string())?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(der).map_err(|e| e.to_string())?;
+    let not_after = certificate.validity().not_after.timestamp();
+    Ok((not_after - Utc::now().timestamp()).div_euclid(86_400))
+}
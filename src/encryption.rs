@@ -0,0 +1,219 @@
+//! AES-256-GCM encryption of the metric data written to disk, so captures,
+//! dead letters, fan-out files and spilled payloads don't leak it from the
+//! volume. Each source can have its own key, so one tenant's records can't
+//! be read with another's.
+
+use crate::config::EncryptionConfig;
+use crate::errors::ServerError;
+use crate::utils::hex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Leads a sealed JSON line, so readers can tell it from a record in the
+/// clear without parsing it.
+const SEALED_PREFIX: &str = "{\"sealed\":";
+
+/// Authenticated along with a sealed line, followed by its source, so lines
+/// can't be moved between sources.
+const LINE_CONTEXT: &[u8] = b"RISEAL1";
+
+/// A JSON line sealed under the key of its source, which is left readable to
+/// pick the key to open it with.
+#[derive(Serialize, Deserialize)]
+struct SealedLine<'a> {
+    sealed: Cow<'a, str>,
+    source: Cow<'a, str>,
+}
+
+#[cfg(feature = "encryption")]
+pub use aes::Cipher;
+
+#[cfg(feature = "encryption")]
+mod aes {
+    use super::crypto_error;
+    use crate::errors::ServerError;
+    use crate::utils::hex;
+    use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    /// An AES-256-GCM key. Sealed data is the random nonce followed by the
+    /// ciphertext and its tag.
+    pub struct Cipher {
+        key: LessSafeKey,
+        rng: SystemRandom,
+    }
+
+    impl Cipher {
+        /// A cipher with a key of 64 hex digits, e.g. from `openssl rand -hex 32`.
+        pub fn new(hex_key: &str) -> Result<Self, ServerError> {
+            let invalid =
+                || ServerError::ConfigurationError("encryption key must be 64 hex digits".into());
+            let key = hex::decode(hex_key).ok_or_else(invalid)?;
+            let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| invalid())?;
+            Ok(Self {
+                key: LessSafeKey::new(key),
+                rng: SystemRandom::new(),
+            })
+        }
+
+        /// Encrypts `payload`, authenticating `context` along with it.
+        pub fn seal(&self, context: &[u8], payload: &[u8]) -> Result<Vec<u8>, ServerError> {
+            let mut nonce = [0u8; NONCE_LEN];
+            self.rng
+                .fill(&mut nonce)
+                .map_err(|_| crypto_error("no randomness for the nonce"))?;
+            let mut sealed = payload.to_vec();
+            self.key
+                .seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::from(context),
+                    &mut sealed,
+                )
+                .map_err(|_| crypto_error("encryption failed"))?;
+            Ok([&nonce[..], &sealed].concat())
+        }
+
+        /// Decrypts what `seal` returned for the same `context`.
+        pub fn open(&self, context: &[u8], sealed: &[u8]) -> Result<Vec<u8>, ServerError> {
+            if sealed.len() < NONCE_LEN {
+                return Err(crypto_error("truncated payload"));
+            }
+            let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+            let nonce = Nonce::try_assume_unique_for_key(nonce)
+                .map_err(|_| crypto_error("truncated payload"))?;
+            let mut payload = ciphertext.to_vec();
+            let len = self
+                .key
+                .open_in_place(nonce, Aad::from(context), &mut payload)
+                .map_err(|_| crypto_error("wrong key or corrupted payload"))?
+                .len();
+            payload.truncate(len);
+            Ok(payload)
+        }
+    }
+}
+
+/// Stands in for the cipher when built without the `encryption` feature, so
+/// configuring a key is an error rather than data written in the clear.
+#[cfg(not(feature = "encryption"))]
+pub enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub fn new(_hex_key: &str) -> Result<Self, ServerError> {
+        Err(ServerError::ConfigurationError(
+            "encryption keys require building with the `encryption` feature".into(),
+        ))
+    }
+
+    pub fn seal(&self, _context: &[u8], _payload: &[u8]) -> Result<Vec<u8>, ServerError> {
+        match *self {}
+    }
+
+    pub fn open(&self, _context: &[u8], _sealed: &[u8]) -> Result<Vec<u8>, ServerError> {
+        match *self {}
+    }
+}
+
+pub(crate) fn crypto_error(message: &str) -> ServerError {
+    ServerError::InternalError(format!("encryption: {}", message).into())
+}
+
+/// The keys records are sealed with: one per source that has its own, and a
+/// default for the others. Records of a source without a key are written in
+/// the clear.
+#[derive(Default)]
+pub struct Keyring {
+    default: Option<Cipher>,
+    sources: HashMap<String, Cipher>,
+}
+
+impl Keyring {
+    pub fn new(config: Option<&EncryptionConfig>) -> Result<Self, ServerError> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        let sources = config
+            .source_keys
+            .iter()
+            .map(|(source, key)| {
+                let cipher = Cipher::new(key).map_err(|e| {
+                    ServerError::ConfigurationError(format!(
+                        "encryption.source_keys.{}: {}",
+                        source, e
+                    ))
+                })?;
+                Ok((source.clone(), cipher))
+            })
+            .collect::<Result<_, ServerError>>()?;
+        Ok(Self {
+            default: config.key.as_deref().map(Cipher::new).transpose()?,
+            sources,
+        })
+    }
+
+    /// A keyring sealing every source's records with `cipher`.
+    pub fn with_default(cipher: Cipher) -> Self {
+        Self {
+            default: Some(cipher),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Seals the records of `source` with `cipher` rather than the default.
+    pub fn with_source(mut self, source: &str, cipher: Cipher) -> Self {
+        self.sources.insert(source.to_string(), cipher);
+        self
+    }
+
+    fn cipher(&self, source: &str) -> Option<&Cipher> {
+        self.sources.get(source).or(self.default.as_ref())
+    }
+
+    /// Seals a JSON line, ending in a newline, of `source` under its key, or
+    /// returns it as is when the source has none.
+    pub fn seal_line(&self, source: &str, line: Vec<u8>) -> Result<Vec<u8>, ServerError> {
+        let Some(cipher) = self.cipher(source) else {
+            return Ok(line);
+        };
+        let record = line.strip_suffix(b"\n").unwrap_or(&line);
+        let sealed = cipher.seal(&line_context(source), record)?;
+        let mut sealed = serde_json::to_vec(&SealedLine {
+            sealed: hex::encode(&sealed).into(),
+            source: source.into(),
+        })?;
+        sealed.push(b'\n');
+        Ok(sealed)
+    }
+
+    /// The JSON line a sealed line holds, or `line` itself if it's in the
+    /// clear.
+    pub fn open_line<'a>(&self, line: &'a str) -> Result<Cow<'a, str>, ServerError> {
+        if !is_sealed(line) {
+            return Ok(Cow::Borrowed(line));
+        }
+        let sealed: SealedLine = serde_json::from_str(line)?;
+        let Some(cipher) = self.cipher(&sealed.source) else {
+            return Err(crypto_error(&format!(
+                "no key for the records of {}",
+                sealed.source
+            )));
+        };
+        let bytes =
+            hex::decode(&sealed.sealed).ok_or_else(|| crypto_error("sealed record isn't hex"))?;
+        let record = cipher.open(&line_context(&sealed.source), &bytes)?;
+        String::from_utf8(record)
+            .map(Cow::Owned)
+            .map_err(|_| crypto_error("sealed record isn't UTF-8"))
+    }
+}
+
+/// Whether `line` was sealed by `Keyring::seal_line`.
+pub fn is_sealed(line: &str) -> bool {
+    line.trim_start().starts_with(SEALED_PREFIX)
+}
+
+fn line_context(source: &str) -> Vec<u8> {
+    [LINE_CONTEXT, source.as_bytes()].concat()
+}
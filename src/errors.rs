@@ -1,4 +1,11 @@
-use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+#[cfg(feature = "server")]
+use crate::api::request_id::current_request_id;
+#[cfg(feature = "server")]
+use actix_web::{
+    HttpResponse, ResponseError,
+    http::{StatusCode, header},
+};
+#[cfg(feature = "server")]
 use serde::Serialize;
 use thiserror::Error;
 
@@ -10,9 +17,57 @@ pub enum ServerError {
     #[error("Failed to process metrics: {0}")]
     MetricsProcessingError(String),
 
+    #[error("Counter '{name}' cannot decrease: received negative increment {value}")]
+    CounterDecreaseError { name: String, value: f64 },
+
+    #[error("Metric '{name}' has a non-finite value {value}")]
+    NonFiniteValue { name: String, value: f64 },
+
     #[error("Failed to register metric: {0}")]
     MetricRegistrationError(String),
 
+    #[error("Schema violation: {0}")]
+    SchemaViolation(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// Answered with a challenge for HTTP basic authentication.
+    #[error("Unauthorized: {0}")]
+    CredentialsRequired(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    /// Answered with a 415 and the supported types in `Accept-Post`.
+    #[error("Unsupported media type {received}, expected one of: {}", supported.join(", "))]
+    UnsupportedMediaType {
+        received: String,
+        supported: Vec<&'static str>,
+    },
+
+    #[error("Memory budget exceeded: {0}")]
+    MemoryBudgetExceeded(String),
+
+    #[error("Service unavailable: {message}")]
+    Unavailable {
+        message: String,
+        retry_after_secs: u64,
+    },
+
+    /// Answered with a 429 and a `Retry-After`.
+    #[error("Too many requests: {message}")]
+    TooManyRequests {
+        message: String,
+        retry_after_secs: u64,
+    },
+
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+
     #[error("Configuration error: {0}")]
     ConfigurationError(String),
 
@@ -23,18 +78,71 @@ pub enum ServerError {
     SerializationError(#[from] serde_json::Error),
 }
 
+impl ServerError {
+    /// Short machine-readable category, used as a label value on self-metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ServerError::ValidationError(_) => "validation",
+            ServerError::MetricsProcessingError(_) => "processing",
+            ServerError::CounterDecreaseError { .. } => "counter_decrease",
+            ServerError::NonFiniteValue { .. } => "non_finite",
+            ServerError::MetricRegistrationError(_) => "registration",
+            ServerError::SchemaViolation(_) => "schema",
+            ServerError::Unauthorized(_) | ServerError::CredentialsRequired(_) => "unauthorized",
+            ServerError::Forbidden(_) => "forbidden",
+            ServerError::NotFound(_) => "not_found",
+            ServerError::UnsupportedMediaType { .. } => "unsupported_media_type",
+            ServerError::MemoryBudgetExceeded(_) => "memory_budget",
+            ServerError::Unavailable { .. } => "unavailable",
+            ServerError::TooManyRequests { .. } => "too_many_requests",
+            ServerError::Timeout(_) => "timeout",
+            ServerError::ConfigurationError(_) => "configuration",
+            ServerError::InternalError(_) => "internal",
+            ServerError::SerializationError(_) => "serialization",
+        }
+    }
+
+    /// Whether resending the same data later may succeed. Problems with the
+    /// data itself are permanent, internal failures and timeouts are transient.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ServerError::InternalError(_)
+                | ServerError::Timeout(_)
+                | ServerError::Unavailable { .. }
+        )
+    }
+}
+
+#[cfg(feature = "server")]
 #[derive(Serialize)]
 struct ErrorResponse {
     status: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
+#[cfg(feature = "server")]
 impl ResponseError for ServerError {
     fn status_code(&self) -> StatusCode {
         match self {
             ServerError::ValidationError(_) => StatusCode::BAD_REQUEST,
             ServerError::MetricsProcessingError(_) => StatusCode::BAD_REQUEST,
+            ServerError::CounterDecreaseError { .. } => StatusCode::BAD_REQUEST,
+            ServerError::NonFiniteValue { .. } => StatusCode::BAD_REQUEST,
             ServerError::MetricRegistrationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServerError::SchemaViolation(_) => StatusCode::BAD_REQUEST,
+            ServerError::Unauthorized(_) | ServerError::CredentialsRequired(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            ServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ServerError::MemoryBudgetExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ServerError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ServerError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ServerError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
             ServerError::ConfigurationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ServerError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ServerError::SerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -45,8 +153,28 @@ impl ResponseError for ServerError {
         let error_response = ErrorResponse {
             status: self.status_code().to_string(),
             message: self.to_string(),
+            request_id: current_request_id(),
         };
 
-        HttpResponse::build(self.status_code()).json(error_response)
+        let mut builder = HttpResponse::build(self.status_code());
+        if let ServerError::Unavailable {
+            retry_after_secs, ..
+        }
+        | ServerError::TooManyRequests {
+            retry_after_secs, ..
+        } = self
+        {
+            builder.insert_header((header::RETRY_AFTER, retry_after_secs.to_string()));
+        }
+        if let ServerError::UnsupportedMediaType { supported, .. } = self {
+            builder.insert_header(("Accept-Post", supported.join(", ")));
+        }
+        if let ServerError::CredentialsRequired(_) = self {
+            builder.insert_header((
+                header::WWW_AUTHENTICATE,
+                r#"Basic realm="rustic-insights", charset="UTF-8""#,
+            ));
+        }
+        builder.json(error_response)
     }
 }
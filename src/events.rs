@@ -0,0 +1,358 @@
+//! Ingestion lifecycle events (a family registered, a source seen, series
+//! rejected for the memory budget, a batch rejected) POSTed as JSON to the
+//! `event_webhooks`, retried with backoff and tracked for the admin API.
+//!
+//! Each webhook has a bounded queue and one task sending its events in
+//! order, so a slow receiver only delays its own events and drops them once
+//! its queue is full.
+
+use crate::config::{EventWebhookConfig, LifecycleEventKind};
+use crate::errors::ServerError;
+use crate::metrics::MetricType;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long one attempt may take, so a receiver that never answers can't
+/// hold its webhook's queue.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events waiting per webhook before new ones are dropped.
+const QUEUE_SIZE: usize = 1_000;
+/// Sources remembered for `SourceSeen`; past it they are forgotten all at
+/// once, and announced again on their next batch.
+const MAX_SEEN_SOURCES: usize = 10_000;
+
+/// Deliveries kept for `GET /api/admin/webhooks/deliveries`, the oldest
+/// are forgotten first.
+const MAX_DELIVERIES: usize = 1_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    FamilyRegistered {
+        source: String,
+        /// The exposed name of the family.
+        family: String,
+        metric_type: MetricType,
+    },
+    SourceSeen {
+        source: String,
+    },
+    SeriesLimitReached {
+        source: String,
+        /// Metrics of the batch rejected for the budget.
+        rejected: usize,
+        message: String,
+    },
+    BatchRejected {
+        source: String,
+        reason: String,
+    },
+}
+
+impl LifecycleEvent {
+    pub fn kind(&self) -> LifecycleEventKind {
+        match self {
+            LifecycleEvent::FamilyRegistered { .. } => LifecycleEventKind::FamilyRegistered,
+            LifecycleEvent::SourceSeen { .. } => LifecycleEventKind::SourceSeen,
+            LifecycleEvent::SeriesLimitReached { .. } => LifecycleEventKind::SeriesLimitReached,
+            LifecycleEvent::BatchRejected { .. } => LifecycleEventKind::BatchRejected,
+        }
+    }
+}
+
+/// What a webhook receives. `id` stays the same across retries, so
+/// receivers can drop duplicates.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventPayload {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: LifecycleEvent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Being sent or waiting to be retried.
+    Pending,
+    Delivered,
+    /// Retries ran out.
+    Failed,
+}
+
+/// Where the delivery of one event to one webhook stands.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryRecord {
+    pub event_id: String,
+    pub webhook: String,
+    pub event: LifecycleEventKind,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+struct Webhook {
+    config: EventWebhookConfig,
+    client: reqwest::Client,
+    sender: Sender<Arc<EventPayload>>,
+    // Taken by the first event, which spawns the delivery task
+    receiver: Mutex<Option<Receiver<Arc<EventPayload>>>>,
+}
+
+impl Webhook {
+    fn wants(&self, kind: LifecycleEventKind) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&kind)
+    }
+
+    async fn send(&self, payload: &EventPayload) -> Result<(), ServerError> {
+        let mut request = self.client.post(&self.config.url).json(payload);
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+
+        request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+/// The configured `event_webhooks`. Events are delivered in the background,
+/// so firing one never holds up ingestion.
+#[derive(Default)]
+pub struct EventWebhooks {
+    webhooks: Vec<Arc<Webhook>>,
+    seen_sources: RwLock<HashSet<String>>,
+    deliveries: Arc<Mutex<VecDeque<DeliveryRecord>>>,
+}
+
+impl EventWebhooks {
+    pub fn new(configs: &[EventWebhookConfig]) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Failed to set up the webhook client, sending without a timeout: {}",
+                    e
+                );
+                reqwest::Client::new()
+            });
+        let webhooks = configs
+            .iter()
+            .map(|config| {
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                Arc::new(Webhook {
+                    config: config.clone(),
+                    client: client.clone(),
+                    sender,
+                    receiver: Mutex::new(Some(receiver)),
+                })
+            })
+            .collect();
+        Self {
+            webhooks,
+            ..Self::default()
+        }
+    }
+
+    /// Whether a webhook takes events of `kind`, to skip building the
+    /// events nobody listens to.
+    pub fn wants(&self, kind: LifecycleEventKind) -> bool {
+        self.webhooks.iter().any(|webhook| webhook.wants(kind))
+    }
+
+    /// Fires `SourceSeen` for the first batch of `source`.
+    pub fn observe_source(&self, source: &str) {
+        if !self.wants(LifecycleEventKind::SourceSeen) {
+            return;
+        }
+        let seen = self
+            .seen_sources
+            .read()
+            .map(|seen| seen.contains(source))
+            .unwrap_or(true);
+        if seen {
+            return;
+        }
+        let new = self.seen_sources.write().is_ok_and(|mut seen| {
+            if seen.len() >= MAX_SEEN_SOURCES {
+                seen.clear();
+            }
+            seen.insert(source.to_string())
+        });
+        if new {
+            self.emit(LifecycleEvent::SourceSeen {
+                source: source.to_string(),
+            });
+        }
+    }
+
+    /// Queues `event` for every webhook taking its kind. Needs a tokio
+    /// runtime.
+    pub fn emit(&self, event: LifecycleEvent) {
+        let kind = event.kind();
+        let payload = Arc::new(EventPayload {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            event,
+        });
+
+        for webhook in self.webhooks.iter().filter(|webhook| webhook.wants(kind)) {
+            self.spawn_delivery(webhook);
+            self.track(DeliveryRecord {
+                event_id: payload.id.clone(),
+                webhook: webhook.config.name.clone(),
+                event: kind,
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                created_at: payload.timestamp,
+                updated_at: payload.timestamp,
+            });
+            if let Err(TrySendError::Full(_) | TrySendError::Closed(_)) =
+                webhook.sender.try_send(payload.clone())
+            {
+                warn!(
+                    "Dropping event {} for webhook {}: its queue is full",
+                    payload.id, webhook.config.name
+                );
+                record_attempt(
+                    &self.deliveries,
+                    &payload.id,
+                    &webhook.config.name,
+                    DeliveryStatus::Failed,
+                    0,
+                    Some("queue full".to_string()),
+                );
+            }
+        }
+    }
+
+    /// Spawns the task delivering the events of `webhook`, unless it runs.
+    fn spawn_delivery(&self, webhook: &Arc<Webhook>) {
+        let Some(mut receiver) = webhook
+            .receiver
+            .lock()
+            .ok()
+            .and_then(|mut receiver| receiver.take())
+        else {
+            return;
+        };
+        let webhook = webhook.clone();
+        let deliveries = self.deliveries.clone();
+        tokio::spawn(async move {
+            while let Some(payload) = receiver.recv().await {
+                deliver(&webhook, &payload, &deliveries).await;
+            }
+        });
+    }
+
+    fn track(&self, record: DeliveryRecord) {
+        let Ok(mut deliveries) = self.deliveries.lock() else {
+            return;
+        };
+        deliveries.push_back(record);
+        while deliveries.len() > MAX_DELIVERIES {
+            deliveries.pop_front();
+        }
+    }
+
+    /// The deliveries kept, most recent first, optionally of one webhook or
+    /// in one status.
+    pub fn deliveries(
+        &self,
+        webhook: Option<&str>,
+        status: Option<DeliveryStatus>,
+    ) -> Vec<DeliveryRecord> {
+        let Ok(deliveries) = self.deliveries.lock() else {
+            return Vec::new();
+        };
+        deliveries
+            .iter()
+            .rev()
+            .filter(|record| webhook.is_none_or(|webhook| record.webhook == webhook))
+            .filter(|record| status.is_none_or(|status| record.status == status))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Sends `payload`, retrying failures with exponential backoff, and records
+/// each attempt.
+async fn deliver(
+    webhook: &Webhook,
+    payload: &EventPayload,
+    deliveries: &Mutex<VecDeque<DeliveryRecord>>,
+) {
+    let name = &webhook.config.name;
+    let mut attempt = 0;
+    loop {
+        let result = webhook.send(payload).await;
+        attempt += 1;
+        let retry = result.is_err() && attempt <= webhook.config.max_retries;
+        let status = match &result {
+            Ok(()) => DeliveryStatus::Delivered,
+            Err(_) if retry => DeliveryStatus::Pending,
+            Err(_) => DeliveryStatus::Failed,
+        };
+        let error = result.as_ref().err().map(|e| e.to_string());
+        record_attempt(deliveries, &payload.id, name, status, attempt, error);
+
+        match result {
+            Ok(()) => {
+                debug!("Sent event {} to webhook {}", payload.id, name);
+                return;
+            }
+            Err(e) if retry => {
+                let backoff = INITIAL_BACKOFF
+                    .saturating_mul(2_u32.saturating_pow(attempt - 1))
+                    .min(MAX_BACKOFF);
+                debug!("Retrying webhook {} in {:?}: {}", name, backoff, e);
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to send event {} to webhook {} after {} attempts: {}",
+                    payload.id, name, attempt, e
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Updates the delivery of event `event_id` to `webhook`, if still kept.
+fn record_attempt(
+    deliveries: &Mutex<VecDeque<DeliveryRecord>>,
+    event_id: &str,
+    webhook: &str,
+    status: DeliveryStatus,
+    attempts: u32,
+    last_error: Option<String>,
+) {
+    if let Ok(mut deliveries) = deliveries.lock()
+        && let Some(record) = deliveries
+            .iter_mut()
+            .rev()
+            .find(|record| record.event_id == event_id && record.webhook == webhook)
+    {
+        record.status = status;
+        record.attempts = attempts;
+        record.last_error = last_error;
+        record.updated_at = Utc::now();
+    }
+}
@@ -0,0 +1,46 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub healthy: bool,
+    pub detail: String,
+}
+
+/// Health of the background components (sinks, forwarders, ...) the server
+/// depends on. Readiness requires every registered component to be healthy,
+/// while liveness only reflects that the process is serving requests.
+#[derive(Default)]
+pub struct HealthChecks {
+    components: RwLock<BTreeMap<String, ComponentHealth>>,
+}
+
+impl HealthChecks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, component: &str, healthy: bool, detail: impl Into<String>) {
+        if let Ok(mut components) = self.components.write() {
+            components.insert(
+                component.to_string(),
+                ComponentHealth {
+                    healthy,
+                    detail: detail.into(),
+                },
+            );
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, ComponentHealth> {
+        self.components
+            .read()
+            .map(|components| components.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.snapshot().values().all(|component| component.healthy)
+    }
+}
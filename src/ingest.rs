@@ -0,0 +1,10 @@
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod collectd;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod ndjson;
+pub mod prometheus;
+
+pub use collectd::CollectdAdapter;
+pub use prometheus::PrometheusTextAdapter;
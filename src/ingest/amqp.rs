@@ -0,0 +1,137 @@
+use crate::api::handlers::AppState;
+use crate::config::AmqpConfig;
+use crate::errors::ServerError;
+use crate::metrics::MetricsBatch;
+use futures::StreamExt;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions, BasicRejectOptions,
+    QueueDeclareOptions,
+};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{Connection, ConnectionProperties};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+const HEALTH_COMPONENT: &str = "amqp";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Consumes `MetricsBatch` JSON messages from a RabbitMQ queue. Invalid
+/// payloads are rejected without requeueing, so the broker routes them to the
+/// queue's dead-letter exchange; transient failures are requeued.
+pub fn spawn_consumer(state: Arc<AppState>, config: AmqpConfig) {
+    state
+        .health
+        .set(HEALTH_COMPONENT, false, "connecting to AMQP broker");
+
+    tokio::spawn(async move {
+        loop {
+            match consume(&state, &config).await {
+                Ok(()) => warn!("AMQP consumer was cancelled, reconnecting"),
+                Err(e) => {
+                    error!("AMQP consumer failed: {}", e);
+                    state.health.set(HEALTH_COMPONENT, false, e.to_string());
+                }
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+fn internal(e: lapin::Error) -> ServerError {
+    ServerError::InternalError(Box::new(e))
+}
+
+async fn consume(state: &AppState, config: &AmqpConfig) -> Result<(), ServerError> {
+    let connection = Connection::connect(&config.url, ConnectionProperties::default())
+        .await
+        .map_err(internal)?;
+    let channel = connection.create_channel().await.map_err(internal)?;
+
+    channel
+        .basic_qos(config.prefetch, BasicQosOptions::default())
+        .await
+        .map_err(internal)?;
+
+    // Only declare the queue when we own its dead-letter topology, otherwise
+    // it is expected to exist with whatever arguments the operator chose.
+    if let Some(exchange) = &config.dead_letter_exchange {
+        let mut arguments = FieldTable::default();
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString(exchange.as_str().into()),
+        );
+        channel
+            .queue_declare(
+                &config.queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                arguments,
+            )
+            .await
+            .map_err(internal)?;
+    }
+
+    let mut consumer = channel
+        .basic_consume(
+            &config.queue,
+            &config.consumer_tag,
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .map_err(internal)?;
+
+    state.health.set(
+        HEALTH_COMPONENT,
+        true,
+        format!("consuming {}", config.queue),
+    );
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery.map_err(internal)?;
+
+        let result = match serde_json::from_slice::<MetricsBatch>(&delivery.data) {
+            Ok(batch) => match state.metrics_collector.ingest(batch).await {
+                Ok(response) => {
+                    debug!("Processed {} metrics from AMQP", response.processed);
+                    delivery.acker.ack(BasicAckOptions::default()).await
+                }
+                Err(e) if e.is_retryable() => {
+                    warn!("Failed to process AMQP batch, requeueing: {}", e);
+                    delivery
+                        .acker
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            ..Default::default()
+                        })
+                        .await
+                }
+                Err(e) => {
+                    warn!("Dead-lettering rejected AMQP batch: {}", e);
+                    delivery
+                        .acker
+                        .reject(BasicRejectOptions { requeue: false })
+                        .await
+                }
+            },
+            Err(e) => {
+                warn!("Dead-lettering malformed AMQP payload: {}", e);
+                let payload = String::from_utf8_lossy(&delivery.data).into_owned();
+                state
+                    .metrics_collector
+                    .dead_letter("amqp", payload.into(), &e.into());
+                delivery
+                    .acker
+                    .reject(BasicRejectOptions { requeue: false })
+                    .await
+            }
+        };
+
+        result.map_err(internal)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use crate::metrics::target::TargetLabels;
+use crate::metrics::{GaugeOperation, Metric, MetricType, MetricValue, MetricsBatch};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The source of batches converted from collectd when the push names none.
+pub const COLLECTD_SOURCE: &str = "collectd";
+
+/// One value list as posted by collectd's `write_http` plugin in JSON format.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CollectdValueList {
+    pub values: Vec<Option<f64>>,
+    pub dstypes: Vec<String>,
+    pub dsnames: Vec<String>,
+    #[serde(default)]
+    pub time: Option<f64>,
+    pub host: String,
+    pub plugin: String,
+    #[serde(default)]
+    pub plugin_instance: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    #[serde(default)]
+    pub type_instance: String,
+}
+
+/// Converts collectd value lists into metric batches, naming metrics like the
+/// collectd_exporter does (`collectd_<plugin>_<type>[_<dsname>]`).
+///
+/// collectd counters and derives are cumulative while registry counters take
+/// increments, so the adapter remembers the last value of every series of
+/// each source and forwards the difference (or the full value after a reset).
+/// A value is only remembered once its write succeeded, so the increment of
+/// a rejected batch is forwarded again with the next one.
+#[derive(Default)]
+pub struct CollectdAdapter {
+    last_values: Mutex<HashMap<String, LastValue>>,
+}
+
+struct LastValue {
+    value: f64,
+    pushed: Instant,
+}
+
+/// The cumulative values read from a payload, by series, to remember once
+/// they're written.
+#[derive(Default)]
+pub struct PendingValues(HashMap<String, f64>);
+
+/// The readings of one series in a payload, folded into a single metric.
+struct Reading {
+    name: String,
+    metric_type: MetricType,
+    help: String,
+    labels: HashMap<String, String>,
+    value: f64,
+    timestamp: Option<i64>,
+}
+
+impl CollectdAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the series not pushed for `ttl`, as the registry expires
+    /// them, so a series pushed again starts over from its full value.
+    pub fn expire(&self, ttl: Duration) -> usize {
+        let mut last_values = self.last_values.lock().unwrap_or_else(|e| e.into_inner());
+        let before = last_values.len();
+        last_values.retain(|_, last| last.pushed.elapsed() < ttl);
+        before - last_values.len()
+    }
+
+    /// The batch of `value_lists`, with one metric per series. Buffered
+    /// payloads can carry several readings of a series: gauges keep the
+    /// latest, counters add up the increments of all of them.
+    ///
+    /// `target` labels, if any, are attached to every metric. The values
+    /// returned are remembered by `commit`.
+    pub fn to_batch(
+        &self,
+        source: &str,
+        value_lists: Vec<CollectdValueList>,
+        target: Option<&TargetLabels>,
+    ) -> (MetricsBatch, PendingValues) {
+        let mut readings: HashMap<String, Reading> = HashMap::new();
+        let mut pending = PendingValues::default();
+        let last_values = self.last_values.lock().unwrap_or_else(|e| e.into_inner());
+
+        for list in value_lists {
+            // Seconds, with a fractional part
+            let timestamp = list.time.map(|time| (time * 1000.0) as i64);
+
+            for ((value, dstype), dsname) in list
+                .values
+                .iter()
+                .zip(list.dstypes.iter())
+                .zip(list.dsnames.iter())
+            {
+                // collectd sends null for values it couldn't read
+                let Some(value) = *value else {
+                    continue;
+                };
+
+                let name = metric_name(&list, dstype, dsname);
+                let mut labels = labels(&list);
+                if let Some(target) = target {
+                    target.apply_labels(&mut labels);
+                }
+                let key = series_key(&name, &labels);
+
+                let (metric_type, value) = match dstype.as_str() {
+                    "gauge" => (MetricType::Gauge, value),
+                    "absolute" => (MetricType::Counter, value),
+                    _ => {
+                        // A series read twice in a payload counts from its first reading
+                        let last_key = format!("{}\n{}", source, key);
+                        let previous = pending
+                            .0
+                            .get(&last_key)
+                            .copied()
+                            .or_else(|| last_values.get(&last_key).map(|last| last.value));
+                        pending.0.insert(last_key, value);
+                        let increment = match previous {
+                            Some(previous) if previous <= value => value - previous,
+                            _ => value,
+                        };
+                        (MetricType::Counter, increment)
+                    }
+                };
+
+                if let Some(reading) = readings.get_mut(&key) {
+                    match metric_type {
+                        MetricType::Counter => reading.value += value,
+                        _ => reading.value = value,
+                    }
+                    reading.timestamp = timestamp;
+                    continue;
+                }
+                let help = format!("collectd {}/{} {}", list.plugin, list.type_name, dsname);
+                readings.insert(
+                    key,
+                    Reading {
+                        name,
+                        metric_type,
+                        help,
+                        labels,
+                        value,
+                        timestamp,
+                    },
+                );
+            }
+        }
+
+        let metrics = readings
+            .into_values()
+            .map(|reading| Metric {
+                name: reading.name,
+                metric_type: reading.metric_type,
+                help: reading.help,
+                labels: reading.labels,
+                value: MetricValue {
+                    value: reading.value,
+                    timestamp: reading.timestamp,
+                    enum_value: None,
+                    histogram: None,
+                }
+                .into(),
+                operation: GaugeOperation::Set,
+            })
+            .collect();
+        let batch = MetricsBatch {
+            metrics,
+            source: source.to_string(),
+        };
+        (batch, pending)
+    }
+
+    /// Remembers the values of the `accepted` metrics of a batch from
+    /// `source`, leaving those of the rejected ones to be forwarded again.
+    pub fn commit(&self, source: &str, mut pending: PendingValues, accepted: &[Metric]) {
+        let mut last_values = self.last_values.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        for metric in accepted {
+            let key = format!("{}\n{}", source, series_key(&metric.name, &metric.labels));
+            if let Some(value) = pending.0.remove(&key) {
+                last_values.insert(key, LastValue { value, pushed: now });
+            }
+        }
+    }
+}
+
+fn metric_name(list: &CollectdValueList, dstype: &str, dsname: &str) -> String {
+    let mut name = format!("collectd_{}", list.plugin);
+    if list.plugin != list.type_name {
+        name.push('_');
+        name.push_str(&list.type_name);
+    }
+    if dsname != "value" {
+        name.push('_');
+        name.push_str(dsname);
+    }
+    if dstype != "gauge" {
+        name.push_str("_total");
+    }
+
+    sanitize(&name)
+}
+
+fn labels(list: &CollectdValueList) -> HashMap<String, String> {
+    let mut labels = HashMap::from([("host".to_string(), list.host.clone())]);
+    if !list.plugin_instance.is_empty() {
+        labels.insert("plugin_instance".to_string(), list.plugin_instance.clone());
+    }
+    if !list.type_instance.is_empty() {
+        labels.insert("type_instance".to_string(), list.type_instance.clone());
+    }
+    labels
+}
+
+fn series_key(name: &str, labels: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<_> = labels.iter().collect();
+    pairs.sort();
+    format!("{}{:?}", name, pairs)
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,100 @@
+use crate::api::handlers::AppState;
+use crate::config::NatsConfig;
+use crate::errors::ServerError;
+use crate::metrics::MetricsBatch;
+use async_nats::jetstream::{self, AckKind, consumer::AckPolicy, consumer::pull};
+use futures::StreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+const HEALTH_COMPONENT: &str = "nats";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const REDELIVERY_DELAY: Duration = Duration::from_secs(10);
+
+/// Consumes `MetricsBatch` JSON messages from a JetStream subject through a
+/// durable pull consumer, so unacknowledged batches are replayed after a restart.
+pub fn spawn_consumer(state: Arc<AppState>, config: NatsConfig) {
+    state
+        .health
+        .set(HEALTH_COMPONENT, false, "connecting to NATS");
+
+    tokio::spawn(async move {
+        loop {
+            match consume(&state, &config).await {
+                Ok(()) => warn!("NATS message stream ended, reconnecting"),
+                Err(e) => {
+                    error!("NATS consumer failed: {}", e);
+                    state.health.set(HEALTH_COMPONENT, false, e.to_string());
+                }
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+fn internal<E: std::error::Error + Send + Sync + 'static>(e: E) -> ServerError {
+    ServerError::InternalError(Box::new(e))
+}
+
+async fn consume(state: &AppState, config: &NatsConfig) -> Result<(), ServerError> {
+    let client = async_nats::connect(&config.url).await.map_err(internal)?;
+    let context = jetstream::new(client);
+    let stream = context.get_stream(&config.stream).await.map_err(internal)?;
+
+    let consumer: jetstream::consumer::PullConsumer = stream
+        .get_or_create_consumer(
+            &config.consumer,
+            pull::Config {
+                durable_name: Some(config.consumer.clone()),
+                filter_subject: config.subject.clone(),
+                ack_policy: AckPolicy::Explicit,
+                max_deliver: config.max_deliver,
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(internal)?;
+
+    let mut messages = consumer.messages().await.map_err(internal)?;
+    state.health.set(
+        HEALTH_COMPONENT,
+        true,
+        format!("consuming {}", config.subject),
+    );
+
+    while let Some(message) = messages.next().await {
+        let message = message.map_err(internal)?;
+
+        let ack = match serde_json::from_slice::<MetricsBatch>(&message.payload) {
+            Ok(batch) => match state.metrics_collector.ingest(batch).await {
+                Ok(response) => {
+                    debug!("Processed {} metrics from NATS", response.processed);
+                    AckKind::Ack
+                }
+                Err(e) if e.is_retryable() => {
+                    warn!("Failed to process NATS batch, requesting redelivery: {}", e);
+                    AckKind::Nak(Some(REDELIVERY_DELAY))
+                }
+                Err(e) => {
+                    warn!("Rejected NATS batch: {}", e);
+                    AckKind::Term
+                }
+            },
+            Err(e) => {
+                warn!("Discarding malformed NATS payload: {}", e);
+                let payload = String::from_utf8_lossy(&message.payload).into_owned();
+                state
+                    .metrics_collector
+                    .dead_letter("nats", payload.into(), &e.into());
+                AckKind::Term
+            }
+        };
+
+        message.ack_with(ack).await.map_err(|e| {
+            ServerError::MetricsProcessingError(format!("Failed to acknowledge message: {}", e))
+        })?;
+    }
+
+    Ok(())
+}
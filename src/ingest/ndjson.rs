@@ -0,0 +1,206 @@
+use crate::errors::ServerError;
+use crate::metrics::target::TargetLabels;
+use crate::metrics::{Metric, MetricError, MetricsBatch, MetricsCollector, MetricsResponse};
+use futures::{Stream, StreamExt};
+use std::fmt::Display;
+use tracing::debug;
+
+/// Metrics read from a stream are processed in batches of this size, so memory
+/// stays bounded however long the stream is.
+pub const NDJSON_CHUNK_SIZE: usize = 1000;
+
+/// Lines longer than this are reported and skipped rather than buffered.
+pub const NDJSON_MAX_LINE_BYTES: usize = 1_048_576;
+
+/// Ingests a stream of newline-delimited `Metric` objects as it arrives.
+///
+/// Every chunk of `NDJSON_CHUNK_SIZE` metrics is validated and processed as a
+/// batch from `source`, so strict mode applies per chunk. Unreadable lines are
+/// reported with their line number and dead-lettered, and don't stop the stream;
+/// lines over `NDJSON_MAX_LINE_BYTES` are reported without being dead-lettered.
+/// `target` labels, if any, are attached to every metric.
+pub async fn ingest_stream<S, B, E>(
+    collector: &MetricsCollector,
+    source: &str,
+    target: Option<&TargetLabels>,
+    stream: S,
+) -> Result<MetricsResponse, ServerError>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut response = MetricsResponse {
+        mode: collector.ingest_mode(source),
+        ..Default::default()
+    };
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut pending: Vec<Metric> = Vec::with_capacity(NDJSON_CHUNK_SIZE);
+    let mut line_number = 0;
+    let mut read = 0;
+    // Set while dropping the rest of a line over `NDJSON_MAX_LINE_BYTES`
+    let mut skipping = false;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            ServerError::ValidationError(format!("Failed to read metrics stream: {}", e))
+        })?;
+        // What's left of the previous chunks holds no newline
+        let mut scanned = buffer.len();
+        buffer.extend_from_slice(chunk.as_ref());
+
+        let mut start = 0;
+        while let Some(offset) = buffer[scanned..].iter().position(|b| *b == b'\n') {
+            let end = scanned + offset;
+            line_number += 1;
+            if skipping {
+                skipping = false;
+            } else if end - start > NDJSON_MAX_LINE_BYTES {
+                response.push_error(line_too_long(line_number));
+            } else {
+                read += parse_line(
+                    collector,
+                    source,
+                    &buffer[start..end],
+                    line_number,
+                    &mut pending,
+                    &mut response,
+                )
+                .await;
+            }
+            start = end + 1;
+            scanned = start;
+
+            if pending.len() >= NDJSON_CHUNK_SIZE {
+                process_chunk(
+                    collector,
+                    source,
+                    target,
+                    std::mem::take(&mut pending),
+                    &mut response,
+                )
+                .await;
+            }
+        }
+        buffer.drain(..start);
+
+        if buffer.len() > NDJSON_MAX_LINE_BYTES {
+            if !skipping {
+                response.push_error(line_too_long(line_number + 1));
+                skipping = true;
+            }
+            buffer.clear();
+        }
+    }
+
+    // The last line doesn't need a trailing newline
+    if skipping {
+        line_number += 1;
+    } else if !buffer.is_empty() {
+        line_number += 1;
+        read += parse_line(
+            collector,
+            source,
+            &buffer,
+            line_number,
+            &mut pending,
+            &mut response,
+        )
+        .await;
+    }
+    if !pending.is_empty() {
+        process_chunk(collector, source, target, pending, &mut response).await;
+    }
+
+    debug!(
+        "Read {} metrics from a stream of {} lines, processed {}",
+        read, line_number, response.processed
+    );
+
+    if read == 0 && response.errors.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Stream contains no metrics".to_string(),
+        ));
+    }
+    if response.processed == 0 {
+        return Err(ServerError::MetricsProcessingError(format!(
+            "No metrics could be processed: {}",
+            response.error_summary()
+        )));
+    }
+    if !response.errors.is_empty() {
+        response.status = "partial_success".to_string();
+    }
+
+    Ok(response)
+}
+
+/// Parses one line into `pending`, returning how many metrics it held.
+async fn parse_line(
+    collector: &MetricsCollector,
+    source: &str,
+    line: &[u8],
+    line_number: usize,
+    pending: &mut Vec<Metric>,
+    response: &mut MetricsResponse,
+) -> usize {
+    let line = line.trim_ascii();
+    if line.is_empty() {
+        return 0;
+    }
+
+    match serde_json::from_slice::<Metric>(line) {
+        Ok(metric) => {
+            pending.push(metric);
+            1
+        }
+        Err(e) => {
+            let error = ServerError::from(e);
+            let payload = String::from_utf8_lossy(line).into_owned();
+            collector.dead_letter(source, payload.into(), &error);
+            response.push_error(MetricError::new(
+                format!("line {}: {}", line_number, error),
+                false,
+            ));
+            0
+        }
+    }
+}
+
+fn line_too_long(line_number: usize) -> MetricError {
+    MetricError::new(
+        format!(
+            "line {}: longer than {} bytes",
+            line_number, NDJSON_MAX_LINE_BYTES
+        ),
+        false,
+    )
+}
+
+async fn process_chunk(
+    collector: &MetricsCollector,
+    source: &str,
+    target: Option<&TargetLabels>,
+    mut metrics: Vec<Metric>,
+    response: &mut MetricsResponse,
+) {
+    if let Some(target) = target {
+        metrics.iter_mut().for_each(|metric| target.apply(metric));
+    }
+    let batch = MetricsBatch {
+        metrics,
+        source: source.to_string(),
+    };
+
+    if let Err(e) = collector.validate(&batch).await {
+        response.push_error(MetricError::from(&e));
+        return;
+    }
+
+    let report = collector.process_batch_report(batch).await;
+    response.processed += report.processed;
+    response.sampled_out += report.sampled_out;
+    response.deduplicated += report.deduplicated;
+    response.extend_errors(report.error_details);
+}
@@ -0,0 +1,755 @@
+//! The Prometheus text exposition format, as pushed by agents that scrape
+//! exporters and forward their output.
+//!
+//! Agent output is often slightly broken (a truncated last line, an
+//! unescaped quote in a label value), so in `ParseMode::Lenient` malformed
+//! lines are skipped and reported with their line number rather than failing
+//! the whole payload; `ParseMode::Strict` rejects it at the first one. The
+//! parser is fuzzed (see `fuzz/`) and must not panic on any input.
+
+use crate::errors::ServerError;
+use crate::metrics::histogram::{Bucket, BucketCounts};
+use crate::metrics::target::TargetLabels;
+use crate::metrics::{
+    GaugeOperation, Metric, MetricError, MetricType, MetricValue, MetricsBatch, MetricsCollector,
+    MetricsResponse,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// What to do with a line that can't be parsed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Reject the whole payload.
+    #[default]
+    Strict,
+    /// Skip the line and report it, keeping the rest of the payload.
+    Lenient,
+}
+
+/// A line that couldn't be parsed, numbered from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// The `# TYPE` of a family; families without one are untyped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    Untyped,
+}
+
+impl TextType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "counter" => Some(TextType::Counter),
+            "gauge" => Some(TextType::Gauge),
+            "histogram" => Some(TextType::Histogram),
+            "summary" => Some(TextType::Summary),
+            "untyped" | "unknown" => Some(TextType::Untyped),
+            _ => None,
+        }
+    }
+
+    /// The sample name suffixes of the family's series besides its own name.
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            TextType::Histogram => &["_bucket", "_sum", "_count"],
+            TextType::Summary => &["_sum", "_count"],
+            // OpenMetrics names the family without the suffix of its samples
+            TextType::Counter => &["_total"],
+            _ => &[],
+        }
+    }
+}
+
+/// One sample line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextSample {
+    /// The sample's own name, e.g. `http_duration_seconds_bucket`.
+    pub name: String,
+    pub labels: HashMap<String, String>,
+    pub value: f64,
+    /// Milliseconds since the epoch.
+    pub timestamp_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextFamily {
+    pub name: String,
+    pub text_type: TextType,
+    pub help: String,
+    pub samples: Vec<TextSample>,
+}
+
+/// The families of a payload, in order of appearance, with the lines
+/// skipped in lenient mode.
+#[derive(Debug, Default)]
+pub struct ParsedText {
+    pub families: Vec<TextFamily>,
+    pub errors: Vec<LineError>,
+}
+
+/// Parses a text exposition. In strict mode, the first malformed line is
+/// returned as the error; in lenient mode, this never fails.
+pub fn parse_text(input: &[u8], mode: ParseMode) -> Result<ParsedText, LineError> {
+    let mut parser = Parser::default();
+
+    for (index, line) in input.split(|b| *b == b'\n').enumerate() {
+        let line_number = index + 1;
+        let result = std::str::from_utf8(line)
+            .map_err(|_| "not valid UTF-8".to_string())
+            .and_then(|line| parser.line(line));
+        if let Err(message) = result {
+            let error = LineError {
+                line: line_number,
+                message,
+            };
+            match mode {
+                ParseMode::Strict => return Err(error),
+                ParseMode::Lenient => parser.parsed.errors.push(error),
+            }
+        }
+    }
+
+    Ok(parser.parsed)
+}
+
+#[derive(Default)]
+struct Parser {
+    parsed: ParsedText,
+    /// Index of each family in `parsed.families`, by name.
+    families: HashMap<String, usize>,
+}
+
+impl Parser {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        match line.strip_prefix('#') {
+            Some(comment) => self.comment(comment),
+            None => self.sample(line),
+        }
+    }
+
+    /// `# HELP` and `# TYPE` lines; other comments are ignored.
+    fn comment(&mut self, comment: &str) -> Result<(), String> {
+        let mut words = comment.trim_start().splitn(3, [' ', '\t']);
+        let keyword = words.next().unwrap_or_default();
+        if keyword != "HELP" && keyword != "TYPE" {
+            return Ok(());
+        }
+        let name = words
+            .next()
+            .filter(|name| is_metric_name(name))
+            .ok_or_else(|| format!("# {} without a valid metric name", keyword))?;
+        let rest = words.next().unwrap_or_default().trim();
+
+        if keyword == "HELP" {
+            let help = unescape(rest, false)?;
+            let index = self.family(name, TextType::Untyped);
+            self.parsed.families[index].help = help;
+            return Ok(());
+        }
+
+        let text_type =
+            TextType::parse(rest).ok_or_else(|| format!("unknown metric type '{}'", rest))?;
+        match self.families.get(name) {
+            Some(&index) => {
+                let family = &mut self.parsed.families[index];
+                if !family.samples.is_empty() || family.text_type != TextType::Untyped {
+                    return Err(format!(
+                        "# TYPE of '{}' after its samples or repeated",
+                        name
+                    ));
+                }
+                family.text_type = text_type;
+            }
+            None => {
+                self.family(name, text_type);
+            }
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self, line: &str) -> Result<(), String> {
+        let name_end = line
+            .find(|c: char| c == '{' || c.is_whitespace())
+            .unwrap_or(line.len());
+        let name = &line[..name_end];
+        if !is_metric_name(name) {
+            return Err(format!("invalid metric name '{}'", name));
+        }
+
+        let mut rest = &line[name_end..];
+        let labels = match rest.strip_prefix('{') {
+            Some(inner) => {
+                let (labels, after) = parse_labels(inner)?;
+                rest = after;
+                labels
+            }
+            None => HashMap::new(),
+        };
+
+        let mut fields = rest.split_whitespace();
+        let value = fields
+            .next()
+            .ok_or_else(|| format!("'{}' has no value", name))?;
+        let value = parse_value(value).ok_or_else(|| format!("invalid value '{}'", value))?;
+        let timestamp_ms = fields
+            .next()
+            .map(|timestamp| {
+                timestamp
+                    .parse::<i64>()
+                    .map_err(|_| format!("invalid timestamp '{}'", timestamp))
+            })
+            .transpose()?;
+        if fields.next().is_some() {
+            return Err("unexpected text after the timestamp".to_string());
+        }
+
+        let index = self.family_of(name);
+        let family = &mut self.parsed.families[index];
+        if family.text_type == TextType::Histogram && name.ends_with("_bucket") {
+            match labels.get("le") {
+                Some(le) if parse_value(le).is_some_and(|le| !le.is_nan()) => {}
+                _ => return Err(format!("bucket of '{}' without a valid le", family.name)),
+            }
+        }
+        family.samples.push(TextSample {
+            name: name.to_string(),
+            labels,
+            value,
+            timestamp_ms,
+        });
+        Ok(())
+    }
+
+    /// The family a sample belongs to: its own, or the histogram or summary
+    /// its name is a series of.
+    fn family_of(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.families.get(name) {
+            return index;
+        }
+        for (base, &index) in name
+            .rmatch_indices('_')
+            .filter_map(|(at, _)| Some((&name[..at], self.families.get(&name[..at])?)))
+        {
+            let text_type = self.parsed.families[index].text_type;
+            if text_type.suffixes().contains(&&name[base.len()..]) {
+                return index;
+            }
+        }
+        self.family(name, TextType::Untyped)
+    }
+
+    fn family(&mut self, name: &str, text_type: TextType) -> usize {
+        if let Some(&index) = self.families.get(name) {
+            return index;
+        }
+        self.parsed.families.push(TextFamily {
+            name: name.to_string(),
+            text_type,
+            help: String::new(),
+            samples: Vec::new(),
+        });
+        let index = self.parsed.families.len() - 1;
+        self.families.insert(name.to_string(), index);
+        index
+    }
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses `name="value",...}` (the opening brace already consumed),
+/// returning the labels and what follows the closing brace.
+fn parse_labels(mut input: &str) -> Result<(HashMap<String, String>, &str), String> {
+    let mut labels = HashMap::new();
+    loop {
+        input = input.trim_start();
+        if let Some(rest) = input.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+
+        let (name, rest) = input
+            .split_once('=')
+            .ok_or_else(|| "unterminated label set".to_string())?;
+        let name = name.trim();
+        if !is_label_name(name) {
+            return Err(format!("invalid label name '{}'", name));
+        }
+        let rest = rest
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| format!("value of label '{}' isn't quoted", name))?;
+        let end =
+            closing_quote(rest).ok_or_else(|| format!("unterminated value of label '{}'", name))?;
+        let value = unescape(&rest[..end], true)?;
+        if labels.insert(name.to_string(), value).is_some() {
+            return Err(format!("duplicate label '{}'", name));
+        }
+
+        input = rest[end + 1..].trim_start();
+        if let Some(rest) = input.strip_prefix(',') {
+            input = rest;
+        } else if !input.starts_with('}') {
+            return Err("expected ',' or '}' after a label".to_string());
+        }
+    }
+}
+
+/// The byte offset of the first unescaped `"`.
+fn closing_quote(input: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (at, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(at),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Resolves `\\`, `\n` and, in label values, `\"`.
+fn unescape(input: &str, quotes: bool) -> Result<String, String> {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => output.push('\\'),
+            Some('n') => output.push('\n'),
+            Some('"') if quotes => output.push('"'),
+            Some(other) => {
+                // Prometheus keeps unknown escapes in HELP text as they are
+                if quotes {
+                    return Err(format!("invalid escape '\\{}'", other));
+                }
+                output.push('\\');
+                output.push(other);
+            }
+            None => return Err("trailing backslash".to_string()),
+        }
+    }
+    Ok(output)
+}
+
+/// A float in Go's syntax, as Prometheus writes them.
+fn parse_value(value: &str) -> Option<f64> {
+    match value {
+        "NaN" => Some(f64::NAN),
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        // Rust would also read "inf" and "infinity"
+        _ if value
+            .bytes()
+            .any(|b| b.is_ascii_alphabetic() && b != b'e' && b != b'E') =>
+        {
+            None
+        }
+        _ => value.parse().ok(),
+    }
+}
+
+/// The most series whose last total is remembered; the oldest are forgotten
+/// beyond it, as if expired.
+const MAX_TRACKED_SERIES: usize = 100_000;
+
+/// Converts parsed families into metric batches.
+///
+/// Exposed counters and histograms are cumulative while the registry takes
+/// increments, so like `CollectdAdapter` this remembers the last value of
+/// every series per source and forwards the difference, or the full value
+/// after a reset. A total is only remembered once its write succeeded, so
+/// the increment of a rejected batch is forwarded again with the next one.
+#[derive(Default)]
+pub struct PrometheusTextAdapter {
+    last_totals: Mutex<HashMap<String, LastTotal>>,
+}
+
+struct LastTotal {
+    total: Total,
+    pushed: Instant,
+}
+
+#[derive(Clone)]
+enum Total {
+    Counter(f64),
+    Histogram(BucketCounts),
+}
+
+/// The totals read from a batch, by series, to remember once it's written.
+#[derive(Default)]
+pub struct PendingTotals(HashMap<String, Total>);
+
+impl PrometheusTextAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the series not pushed for `ttl`, as the registry expires
+    /// them, so a series pushed again starts over from its full value.
+    pub fn expire(&self, ttl: Duration) -> usize {
+        let mut last = self.last_totals.lock().unwrap_or_else(|e| e.into_inner());
+        let before = last.len();
+        last.retain(|_, total| total.pushed.elapsed() < ttl);
+        before - last.len()
+    }
+
+    /// The batch of `families`, with an error for each family that can't be
+    /// converted. Summaries are refused: their quantiles can't be merged.
+    ///
+    /// `target` labels, if any, are attached to every metric. The totals
+    /// returned are remembered by `commit`.
+    pub fn to_batch(
+        &self,
+        source: &str,
+        families: Vec<TextFamily>,
+        target: Option<&TargetLabels>,
+    ) -> (MetricsBatch, Vec<MetricError>, PendingTotals) {
+        let mut metrics = Vec::new();
+        let mut errors = Vec::new();
+        let mut pending = PendingTotals::default();
+        let last = self.last_totals.lock().unwrap_or_else(|e| e.into_inner());
+        // A series sampled twice in a payload counts from its first sample
+        let previous = |pending: &PendingTotals, key: &str| {
+            pending
+                .0
+                .get(key)
+                .or_else(|| last.get(key).map(|last| &last.total))
+                .cloned()
+        };
+
+        for family in families {
+            let help = if family.help.is_empty() {
+                family.name.clone()
+            } else {
+                family.help.clone()
+            };
+            match family.text_type {
+                TextType::Counter => {
+                    for sample in family.samples {
+                        let mut metric = metric(
+                            sample.name,
+                            MetricType::Counter,
+                            &help,
+                            sample.labels,
+                            target,
+                        );
+                        let key = series_key(source, &metric.name, &metric.labels);
+                        let increment = match previous(&pending, &key) {
+                            Some(Total::Counter(previous)) if previous <= sample.value => {
+                                sample.value - previous
+                            }
+                            _ => sample.value,
+                        };
+                        pending.0.insert(key, Total::Counter(sample.value));
+                        metric.value = value(increment, sample.timestamp_ms).into();
+                        metrics.push(metric);
+                    }
+                }
+                TextType::Gauge | TextType::Untyped => {
+                    for sample in family.samples {
+                        let mut metric =
+                            metric(sample.name, MetricType::Gauge, &help, sample.labels, target);
+                        metric.value = value(sample.value, sample.timestamp_ms).into();
+                        metrics.push(metric);
+                    }
+                }
+                TextType::Histogram => {
+                    for (labels, counts, timestamp_ms) in histograms(&family) {
+                        if let Err(e) = counts.validate() {
+                            errors.push(MetricError::new(
+                                format!("histogram '{}': {}", family.name, e),
+                                false,
+                            ));
+                            continue;
+                        }
+                        let mut metric = metric(
+                            family.name.clone(),
+                            MetricType::Histogram,
+                            &help,
+                            labels,
+                            target,
+                        );
+                        let key = series_key(source, &metric.name, &metric.labels);
+                        let increment = match previous(&pending, &key) {
+                            Some(Total::Histogram(previous)) => {
+                                histogram_increment(&previous, &counts)
+                            }
+                            _ => counts.clone(),
+                        };
+                        pending.0.insert(key, Total::Histogram(counts));
+                        metric.value = MetricValue {
+                            value: increment.sum,
+                            timestamp: timestamp_ms,
+                            enum_value: None,
+                            histogram: Some(Box::new(increment)),
+                        }
+                        .into();
+                        metrics.push(metric);
+                    }
+                }
+                TextType::Summary => errors.push(MetricError::new(
+                    format!(
+                        "summary '{}' skipped: quantiles can't be merged, push it as JSON",
+                        family.name
+                    ),
+                    false,
+                )),
+            }
+        }
+
+        let batch = MetricsBatch {
+            metrics,
+            source: source.to_string(),
+        };
+        (batch, errors, pending)
+    }
+
+    /// Remembers the totals of the `accepted` metrics of a batch from
+    /// `source`, leaving those of the rejected ones to be forwarded again.
+    pub fn commit(&self, source: &str, mut pending: PendingTotals, accepted: &[Metric]) {
+        let mut last = self.last_totals.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        for metric in accepted {
+            let key = series_key(source, &metric.name, &metric.labels);
+            if let Some(total) = pending.0.remove(&key) {
+                last.insert(key, LastTotal { total, pushed: now });
+            }
+        }
+        if last.len() > MAX_TRACKED_SERIES {
+            // Forget the oldest eighth at once rather than one per new series
+            let mut pushed: Vec<Instant> = last.values().map(|last| last.pushed).collect();
+            let keep = MAX_TRACKED_SERIES - MAX_TRACKED_SERIES / 8;
+            let (_, cutoff, _) = pushed.select_nth_unstable_by(last.len() - keep, |a, b| b.cmp(a));
+            let cutoff = *cutoff;
+            last.retain(|_, last| last.pushed > cutoff);
+        }
+    }
+}
+
+/// The difference between two readings of a histogram, or `counts` in full
+/// when it was reset or its buckets changed.
+fn histogram_increment(previous: &BucketCounts, counts: &BucketCounts) -> BucketCounts {
+    let monotonic = previous.count <= counts.count
+        && previous.buckets.len() == counts.buckets.len()
+        && previous
+            .buckets
+            .iter()
+            .zip(&counts.buckets)
+            .all(|(previous, bucket)| previous.le == bucket.le && previous.count <= bucket.count);
+    if !monotonic {
+        return counts.clone();
+    }
+    BucketCounts {
+        buckets: counts
+            .buckets
+            .iter()
+            .zip(&previous.buckets)
+            .map(|(bucket, previous)| Bucket {
+                le: bucket.le,
+                count: bucket.count - previous.count,
+            })
+            .collect(),
+        sum: counts.sum - previous.sum,
+        count: counts.count - previous.count,
+    }
+}
+
+/// Groups the samples of a histogram family by series.
+fn histograms(family: &TextFamily) -> Vec<(HashMap<String, String>, BucketCounts, Option<i64>)> {
+    let mut series: Vec<(HashMap<String, String>, BucketCounts, Option<i64>)> = Vec::new();
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    for sample in &family.samples {
+        let mut labels = sample.labels.clone();
+        let le = labels.remove("le");
+        let index = *indexes
+            .entry(series_key("", "", &labels))
+            .or_insert_with(|| {
+                let counts = BucketCounts {
+                    buckets: Vec::new(),
+                    sum: 0.0,
+                    count: 0.0,
+                };
+                series.push((labels, counts, None));
+                series.len() - 1
+            });
+        let (_, counts, timestamp_ms) = &mut series[index];
+        *timestamp_ms = timestamp_ms.or(sample.timestamp_ms);
+
+        let suffix = &sample.name[family.name.len().min(sample.name.len())..];
+        match suffix {
+            "_bucket" => {
+                let Some(le) = le.as_deref().and_then(parse_value) else {
+                    continue;
+                };
+                if le == f64::INFINITY {
+                    counts.count = counts.count.max(sample.value);
+                } else {
+                    counts.buckets.push(Bucket {
+                        le,
+                        count: sample.value,
+                    });
+                }
+            }
+            "_sum" => counts.sum = sample.value,
+            "_count" => counts.count = sample.value,
+            _ => {}
+        }
+    }
+    for (_, counts, _) in &mut series {
+        counts.buckets.sort_by(|a, b| a.le.total_cmp(&b.le));
+    }
+    series
+}
+
+/// A metric of the series, with `target` labels attached, for the caller to
+/// set the value of.
+fn metric(
+    name: String,
+    metric_type: MetricType,
+    help: &str,
+    labels: HashMap<String, String>,
+    target: Option<&TargetLabels>,
+) -> Metric {
+    let mut metric = Metric {
+        name,
+        metric_type,
+        help: help.to_string(),
+        labels,
+        value: value(0.0, None).into(),
+        operation: GaugeOperation::Set,
+    };
+    if let Some(target) = target {
+        target.apply(&mut metric);
+    }
+    metric
+}
+
+fn value(value: f64, timestamp_ms: Option<i64>) -> MetricValue {
+    MetricValue {
+        value,
+        timestamp: timestamp_ms,
+        enum_value: None,
+        histogram: None,
+    }
+}
+
+fn series_key(source: &str, name: &str, labels: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<_> = labels.iter().collect();
+    pairs.sort();
+    format!("{}/{}{:?}", source, name, pairs)
+}
+
+/// Parses and ingests a text exposition as a batch from `source`.
+///
+/// Malformed lines are dead-lettered and, in lenient mode, reported in the
+/// response's errors with their line number; `target` labels, if any, are
+/// attached to every metric.
+pub async fn ingest_text(
+    collector: &MetricsCollector,
+    adapter: &PrometheusTextAdapter,
+    source: &str,
+    target: Option<&TargetLabels>,
+    body: &[u8],
+    mode: ParseMode,
+) -> Result<MetricsResponse, ServerError> {
+    let parsed = match parse_text(body, mode) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let error = ServerError::ValidationError(e.to_string());
+            let payload = String::from_utf8_lossy(body).into_owned();
+            collector.dead_letter(source, payload.into(), &error);
+            return Err(error);
+        }
+    };
+
+    let mut response = MetricsResponse {
+        mode: collector.ingest_mode(source),
+        ..Default::default()
+    };
+    let lines: Vec<&[u8]> = match parsed.errors.is_empty() {
+        true => Vec::new(),
+        false => body.split(|b| *b == b'\n').collect(),
+    };
+    for error in &parsed.errors {
+        let line = lines.get(error.line - 1).copied().unwrap_or_default();
+        let payload = String::from_utf8_lossy(line).into_owned();
+        let error = ServerError::ValidationError(error.to_string());
+        collector.dead_letter(source, payload.into(), &error);
+        response.push_error(MetricError::from(&error));
+    }
+
+    let (batch, errors, pending) = adapter.to_batch(source, parsed.families, target);
+    response.extend_errors(errors);
+    debug!(
+        "Parsed {} metrics from a text exposition, skipped {} lines",
+        batch.metrics.len(),
+        parsed.errors.len()
+    );
+
+    if batch.metrics.is_empty() {
+        return Err(ServerError::ValidationError(
+            if response.errors.is_empty() {
+                "Payload contains no metrics".to_string()
+            } else {
+                format!("No metrics could be read: {}", response.error_summary())
+            },
+        ));
+    }
+
+    collector.validate(&batch).await?;
+    let (report, accepted) = collector.process_batch_accepted(batch).await;
+    adapter.commit(source, pending, &accepted.metrics);
+    response.processed = report.processed;
+    response.sampled_out = report.sampled_out;
+    response.deduplicated = report.deduplicated;
+    response.errors.extend(report.errors);
+
+    if response.processed == 0 {
+        return Err(ServerError::MetricsProcessingError(format!(
+            "No metrics could be processed: {}",
+            response.error_summary()
+        )));
+    }
+    if !response.errors.is_empty() {
+        response.status = "partial_success".to_string();
+    }
+    Ok(response)
+}
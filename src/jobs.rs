@@ -0,0 +1,152 @@
+use crate::metrics::{MetricError, MetricsResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Finished jobs kept for status lookups; the oldest are forgotten first.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+/// How long a job may run before it is presumed lost, e.g. to a panicked task, and failed.
+pub const JOB_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    pub source: String,
+    pub submitted: usize,
+    pub processed: usize,
+    pub errors: Vec<String>,
+    /// The same errors with their retryability.
+    pub error_details: Vec<MetricError>,
+    pub submitted_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Tracks batches ingested in the background with `?async=true`.
+#[derive(Default)]
+pub struct JobTracker {
+    jobs: RwLock<HashMap<String, JobStatus>>,
+    finished: RwLock<VecDeque<String>>,
+}
+
+impl JobTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a running job for a batch of `submitted` metrics and returns its ID.
+    pub fn start(&self, source: &str, submitted: usize) -> String {
+        let id = Uuid::new_v4().to_string();
+        let status = JobStatus {
+            id: id.clone(),
+            state: JobState::Running,
+            source: source.to_string(),
+            submitted,
+            processed: 0,
+            errors: Vec::new(),
+            error_details: Vec::new(),
+            submitted_at: Utc::now(),
+            finished_at: None,
+        };
+
+        if let Ok(mut jobs) = self.jobs.write() {
+            jobs.insert(id.clone(), status);
+        }
+        id
+    }
+
+    /// Fails the jobs that have been running for longer than `timeout`, so they are
+    /// forgotten like finished ones, and returns how many there were. Run on the
+    /// compaction timer. The task may still be writing, so the batch isn't retryable.
+    pub fn expire(&self, timeout: Duration) -> usize {
+        let Ok(mut jobs) = self.jobs.write() else {
+            return 0;
+        };
+        let now = Utc::now();
+        let cutoff = now - timeout;
+        let mut expired = Vec::new();
+        for job in jobs.values_mut() {
+            if job.state == JobState::Running && job.submitted_at <= cutoff {
+                job.state = JobState::Failed;
+                let error = MetricError::new(
+                    format!("Job did not finish within {}s", timeout.as_secs()),
+                    false,
+                );
+                job.errors = vec![error.message.clone()];
+                job.error_details = vec![error];
+                job.finished_at = Some(now);
+                expired.push(job.id.clone());
+            }
+        }
+
+        let count = expired.len();
+        for id in expired {
+            self.forget_finished(&mut jobs, id);
+        }
+        count
+    }
+
+    /// Records the outcome of a job. A batch where nothing could be processed fails.
+    pub fn finish(&self, id: &str, response: MetricsResponse) {
+        let Ok(mut jobs) = self.jobs.write() else {
+            return;
+        };
+        let Some(job) = jobs
+            .get_mut(id)
+            .filter(|job| job.state == JobState::Running)
+        else {
+            return;
+        };
+
+        job.state = if response.processed == 0 && !response.errors.is_empty() {
+            JobState::Failed
+        } else {
+            JobState::Completed
+        };
+        job.processed = response.processed;
+        job.errors = response.errors;
+        job.error_details = response.error_details;
+        job.finished_at = Some(Utc::now());
+        self.forget_finished(&mut jobs, id.to_string());
+    }
+
+    /// Queues a finished job to be forgotten, dropping the oldest beyond `MAX_FINISHED_JOBS`.
+    fn forget_finished(&self, jobs: &mut HashMap<String, JobStatus>, id: String) {
+        if let Ok(mut finished) = self.finished.write() {
+            finished.push_back(id);
+            while finished.len() > MAX_FINISHED_JOBS {
+                if let Some(expired) = finished.pop_front() {
+                    jobs.remove(&expired);
+                }
+            }
+        }
+    }
+
+    /// Jobs still being processed.
+    pub fn running(&self) -> usize {
+        self.jobs
+            .read()
+            .map(|jobs| {
+                jobs.values()
+                    .filter(|job| job.state == JobState::Running)
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        self.jobs.read().ok()?.get(id).cloned()
+    }
+}
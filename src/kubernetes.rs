@@ -0,0 +1,37 @@
+use crate::config::KubernetesConfig;
+use std::collections::HashMap;
+use std::env;
+use tracing::debug;
+
+const NAMESPACE_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// Reads the pod identity exposed through the downward API (environment
+/// variables set from `fieldRef`), falling back to the hostname for the pod
+/// name and the service account mount for the namespace.
+pub fn downward_api_labels(config: &KubernetesConfig) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+
+    let pod = env::var(&config.pod_name_env)
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok());
+    let namespace = env::var(&config.namespace_env).ok().or_else(|| {
+        std::fs::read_to_string(NAMESPACE_FILE)
+            .ok()
+            .map(|namespace| namespace.trim().to_string())
+    });
+    let node = env::var(&config.node_name_env).ok();
+
+    for (label, value) in [("pod", pod), ("namespace", namespace), ("node", node)] {
+        match value.filter(|value| !value.is_empty()) {
+            Some(value) => {
+                labels.insert(label.to_string(), value);
+            }
+            None => debug!(
+                "Kubernetes {} is not available from the downward API",
+                label
+            ),
+        }
+    }
+
+    labels
+}
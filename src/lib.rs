@@ -1,14 +1,43 @@
 pub mod api;
+pub mod build_info;
+pub mod capture;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod config;
+pub mod dead_letter;
+#[cfg(feature = "server")]
+pub mod doctor;
+pub mod encryption;
 pub mod errors;
+pub mod events;
+pub mod health;
+pub mod ingest;
+pub mod jobs;
+pub mod kubernetes;
+#[cfg(feature = "server")]
+pub mod listener;
 pub mod metrics;
+pub mod mode;
+pub mod notify;
+pub mod replay;
+pub mod reports;
+#[cfg(feature = "server")]
+pub mod service;
+pub mod sinks;
+#[cfg(feature = "test_support")]
+pub mod test_support;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod uptime;
 pub mod utils;
 
+#[cfg(feature = "server")]
 pub use api::configure_routes;
+#[cfg(feature = "server")]
 pub use api::handlers::AppState;
 pub use config::AppConfig;
 pub use errors::ServerError;
 pub use metrics::{
-    Metric, MetricType, MetricValue, MetricsBatch, MetricsCollector, MetricsRegistry,
-    MetricsResponse,
+    GaugeOperation, Metric, MetricType, MetricValue, MetricsBatch, MetricsCollector,
+    MetricsRegistry, MetricsResponse,
 };
@@ -0,0 +1,124 @@
+use crate::config::ServerConfig;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use tracing::info;
+
+/// First descriptor passed by systemd socket activation (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// Whether `SO_REUSEPORT` can be set on this platform.
+pub const REUSE_PORT_SUPPORTED: bool = cfg!(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos"))
+));
+
+/// Returns the listener to serve on, in order of preference: the descriptor
+/// given with `--inherit-listener`, a socket passed by systemd socket
+/// activation, or a freshly bound socket (with `SO_REUSEPORT` when
+/// `server.reuse_port` is set, so a new process can bind next to the old one).
+pub fn acquire_listener(config: &ServerConfig, inherit_fd: Option<i32>) -> io::Result<TcpListener> {
+    if let Some(fd) = inherit_fd {
+        info!("Serving on inherited listener fd {}", fd);
+        return inherited_listener(fd);
+    }
+
+    #[cfg(unix)]
+    if let Some(listener) = systemd_listener()? {
+        info!("Serving on socket passed by systemd");
+        return Ok(listener);
+    }
+
+    let addr = (config.host.as_str(), config.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}:{} did not resolve", config.host, config.port),
+            )
+        })?;
+    bind_listener(addr, config.reuse_port)
+}
+
+/// Binds a listener, optionally with `SO_REUSEPORT` so the kernel balances
+/// new connections across every process bound to the address while an old
+/// instance drains.
+pub fn bind_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Takes ownership of a listening socket inherited from the parent process,
+/// e.g. from a supervisor handing the old instance's socket to its successor.
+#[cfg(unix)]
+pub fn inherited_listener(fd: i32) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid listener fd {}", fd),
+        ));
+    }
+    // SAFETY: the descriptor is handed to this process for it to own and is
+    // not used anywhere else; `check_listener` rejects anything that isn't a
+    // TCP stream socket.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    check_listener(socket)
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener(fd: i32) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot inherit listener fd {}: only supported on unix", fd),
+    ))
+}
+
+/// The first socket passed through `LISTEN_FDS`, if systemd started this
+/// process for it (`LISTEN_PID` names this process).
+#[cfg(unix)]
+fn systemd_listener() -> io::Result<Option<TcpListener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    if !for_us || count < 1 {
+        return Ok(None);
+    }
+    inherited_listener(SD_LISTEN_FDS_START).map(Some)
+}
+
+#[cfg(unix)]
+fn check_listener(socket: Socket) -> io::Result<TcpListener> {
+    let is_tcp = socket.local_addr()?.as_socket().is_some();
+    if !is_tcp || socket.r#type()? != Type::STREAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "inherited descriptor is not a TCP stream socket",
+        ));
+    }
+    // A no-op for sockets already listening, and makes a bound socket handed
+    // over before `listen` usable.
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
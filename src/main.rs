@@ -1,49 +1,475 @@
 use rustic_insights::{
-    AppConfig, AppState, MetricsCollector, MetricsRegistry, api::configure_routes,
+    AppConfig, AppState, MetricsCollector,
+    api::auth::authorize,
+    api::configure_routes,
+    api::lockout::enforce_lockouts,
+    api::request_id::propagate_request_id,
+    api::shedding::shed_load,
+    api::signing::verify_signatures,
+    api::timeout::enforce_timeouts,
+    config::EncryptionConfig,
+    config::secrets::read_secret_file,
+    doctor,
+    encryption::Keyring,
+    errors::ServerError,
+    kubernetes::downward_api_labels,
+    listener::acquire_listener,
+    metrics::compaction::spawn_compaction,
+    metrics::history::spawn_history_sampling,
+    metrics::rules::spawn_recording_rules,
+    metrics::slo::spawn_slo_evaluation,
+    metrics::synthetic::spawn_synthetic,
+    replay::{self, ReplayOptions},
+    reports::spawn_reports,
+    service, sinks,
+    uptime::UptimeHistory,
 };
 
 use actix_web::{App, HttpServer, middleware, web};
+use std::path::Path;
+use std::process;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tracing::{Level, info};
+use tracing::{Level, error, info};
 use tracing_subscriber::FmtSubscriber;
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+// Behind the heap stats and profiles of `/api/admin/debug/pprof/heap`
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+fn main() -> std::io::Result<()> {
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::INFO)
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set up the logger");
 
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("check-config") => return check_config(),
+        Some("doctor") => return actix_web::rt::System::new().block_on(doctor()),
+        Some("replay") => return actix_web::rt::System::new().block_on(replay(&args[2..])),
+        #[cfg(windows)]
+        Some("service") => {
+            return service::windows::run(|| actix_web::rt::System::new().block_on(serve(None)));
+        }
+        _ => {}
+    }
+    let inherit_fd = inherit_listener_flag(&args[1..]);
+
+    actix_web::rt::System::new().block_on(serve(inherit_fd))
+}
+
+async fn serve(inherit_fd: Option<i32>) -> std::io::Result<()> {
     info!("Starting metrics server");
 
-    let config = AppConfig::load().expect("Failed to load configuration");
+    let mut config = load_config();
     let server_config = config.server.clone();
 
-    let metrics_registry = MetricsRegistry::new(config.metrics.clone());
-    let metrics_collector = MetricsCollector::new(metrics_registry);
+    if config.kubernetes.enabled {
+        let labels = downward_api_labels(&config.kubernetes);
+        info!("Kubernetes mode enabled, self-metric labels: {:?}", labels);
+        config.metrics.self_metrics_labels.extend(labels);
+    }
 
-    let app_state = Arc::new(AppState {
-        metrics_collector,
-        start_time: SystemTime::now(),
-        version: env!("CARGO_PKG_VERSION").to_string(),
+    let metrics_collector = MetricsCollector::from_config(&config).unwrap_or_else(|e| {
+        error!("Failed to set up metrics ingestion: {}", e);
+        process::exit(1);
     });
 
-    info!(
-        "Starting HTTP server at {}:{}",
-        server_config.host, server_config.port
-    );
+    let app_state = AppState::new(config.clone(), metrics_collector, env!("CARGO_PKG_VERSION"));
+    let app_state = match config.uptime.clone() {
+        Some(uptime) => {
+            let started_at = app_state.start_time.into();
+            let dir = uptime.dir.clone();
+            let opened =
+                tokio::task::spawn_blocking(move || UptimeHistory::open(&uptime, started_at))
+                    .await
+                    .unwrap_or_else(|e| Err(ServerError::InternalError(Box::new(e))));
+            match opened {
+                Ok(history) => app_state.with_uptime(history),
+                Err(e) => {
+                    error!("Failed to load the uptime history from {}: {}", dir, e);
+                    app_state
+                }
+            }
+        }
+        None => app_state,
+    };
+    let app_state = Arc::new(app_state);
 
-    HttpServer::new(move || {
+    sinks::spawn_exporters(app_state.clone());
+    if let Some(fanout) = &app_state.fanout {
+        fanout.spawn();
+    }
+    app_state.uptime.clone().spawn_heartbeat();
+    spawn_compaction(app_state.clone());
+    spawn_history_sampling(app_state.clone());
+    spawn_recording_rules(app_state.clone());
+    spawn_slo_evaluation(app_state.clone());
+    spawn_synthetic(app_state.clone());
+    spawn_reports(app_state.clone());
+
+    if let Some(nats) = config.nats.clone() {
+        #[cfg(feature = "nats")]
+        rustic_insights::ingest::nats::spawn_consumer(app_state.clone(), nats);
+        #[cfg(not(feature = "nats"))]
+        tracing::warn!(
+            "Ignoring NATS source for stream {}: built without the `nats` feature",
+            nats.stream
+        );
+    }
+
+    if let Some(amqp) = config.amqp.clone() {
+        #[cfg(feature = "amqp")]
+        rustic_insights::ingest::amqp::spawn_consumer(app_state.clone(), amqp);
+        #[cfg(not(feature = "amqp"))]
+        tracing::warn!(
+            "Ignoring AMQP source for queue {}: built without the `amqp` feature",
+            amqp.queue
+        );
+    }
+
+    let listener = acquire_listener(&server_config, inherit_fd).unwrap_or_else(|e| {
+        error!("Failed to set up the HTTP listener: {}", e);
+        process::exit(1);
+    });
+    let address = listener.local_addr()?;
+    let _ = app_state.listen_addresses.set(vec![address]);
+
+    let state = app_state.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(app_state.clone()))
+            .wrap(middleware::from_fn(enforce_timeouts))
+            .wrap(middleware::from_fn(shed_load))
+            .wrap(middleware::from_fn(verify_signatures))
+            .wrap(middleware::from_fn(authorize))
+            .wrap(middleware::from_fn(enforce_lockouts))
+            .wrap(middleware::from_fn(propagate_request_id))
             .wrap(tracing_actix_web::TracingLogger::default())
             .wrap(middleware::Compress::default())
             .wrap(middleware::NormalizePath::trim())
             .configure(configure_routes)
+    });
+    #[cfg(feature = "tls")]
+    let server = server.on_connect(store_client_certificate);
+    let server = match &server_config.tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => {
+            let tls_config = tls_server_config(tls, &server_config.host, &state).await?;
+            info!("Starting HTTPS server at {}", address);
+            server.listen_rustls_0_23(listener, tls_config)?
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(_) => {
+            error!("server.tls is set, but this build lacks the `tls` feature");
+            process::exit(1);
+        }
+        None => {
+            info!("Starting HTTP server at {}", address);
+            server.listen(listener)?
+        }
+    };
+    let server = server
+        .workers(server_config.workers)
+        .shutdown_timeout(server_config.shutdown_timeout_secs)
+        .run();
+
+    state.mode.attach_server(server.handle());
+    service::notify_ready(&server.handle());
+    let result = server.await;
+    service::notify_stopping();
+    state.metrics_collector.flush_dead_letters().await;
+    let uptime = state.uptime.clone();
+    let recorded = tokio::task::spawn_blocking(move || uptime.record_shutdown())
+        .await
+        .unwrap_or_else(|e| Err(ServerError::InternalError(Box::new(e))));
+    if let Err(e) = recorded {
+        error!("Failed to record the shutdown in the uptime history: {}", e);
+    }
+    result
+}
+
+/// Loads the served certificate and keeps it current, first provisioning it
+/// when `server.tls.acme` is set.
+#[cfg(feature = "tls")]
+async fn tls_server_config(
+    tls: &rustic_insights::config::TlsConfig,
+    #[cfg_attr(not(feature = "acme"), allow(unused_variables))] host: &str,
+    state: &AppState,
+) -> std::io::Result<rustls::ServerConfig> {
+    use rustic_insights::tls::CertificateReloader;
+
+    let self_metrics = state.metrics_collector.self_metrics().clone();
+    #[cfg(feature = "acme")]
+    let acme = match &tls.acme {
+        Some(acme) => Some(provision_certificate(tls, acme, host).await?),
+        None => None,
+    };
+    #[cfg(not(feature = "acme"))]
+    if tls.acme.is_some() {
+        error!("server.tls.acme is set, but this build lacks the `acme` feature");
+        process::exit(1);
+    }
+
+    let reloader = CertificateReloader::load(tls)
+        .map(Arc::new)
+        .unwrap_or_else(|e| {
+            error!("Failed to load the TLS certificate: {}", e);
+            process::exit(1);
+        });
+    let tls_config = reloader.server_config().unwrap_or_else(|e| {
+        error!("Failed to set up TLS: {}", e);
+        process::exit(1);
+    });
+    #[cfg(feature = "acme")]
+    if let Some(acme) = acme {
+        acme.spawn_renewal(reloader.clone(), self_metrics.clone());
+    }
+    reloader.spawn_watcher(self_metrics);
+    Ok(tls_config)
+}
+
+/// Keeps the certificate a client authenticated with for `auth` to map to
+/// an identity.
+#[cfg(feature = "tls")]
+fn store_client_certificate(connection: &dyn std::any::Any, data: &mut actix_web::dev::Extensions) {
+    use actix_tls::accept::rustls_0_23::TlsStream;
+    use rustic_insights::api::auth::ClientCertificate;
+
+    if let Some(stream) = connection.downcast_ref::<TlsStream<actix_web::rt::net::TcpStream>>()
+        && let Some(certificate) = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+    {
+        data.insert(ClientCertificate(certificate.to_vec()));
+    }
+}
+
+/// Starts the listener answering ACME challenges, then provisions the
+/// certificate unless a current one is already there.
+#[cfg(feature = "acme")]
+async fn provision_certificate(
+    tls: &rustic_insights::config::TlsConfig,
+    acme: &rustic_insights::config::AcmeConfig,
+    host: &str,
+) -> std::io::Result<Arc<rustic_insights::tls::acme::AcmeClient>> {
+    use rustic_insights::listener::{REUSE_PORT_SUPPORTED, bind_listener};
+    use rustic_insights::tls::acme::{AcmeClient, configure_challenge_routes};
+    use std::net::ToSocketAddrs;
+
+    let client = AcmeClient::new(tls, acme)
+        .map(Arc::new)
+        .unwrap_or_else(|e| {
+            error!("Failed to set up the ACME client: {}", e);
+            process::exit(1);
+        });
+    let challenges = client.challenges();
+    let address = (host, acme.challenge_port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{}:{} did not resolve", host, acme.challenge_port),
+            )
+        })?;
+    // The instance a restart replaces still answers on the port until it stops
+    let listener = bind_listener(address, REUSE_PORT_SUPPORTED)?;
+    let challenge_server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(challenges.clone()))
+            .configure(configure_challenge_routes)
     })
-    .bind(format!("{}:{}", server_config.host, server_config.port))?
-    .workers(server_config.workers)
-    .run()
-    .await
+    .listen(listener)?
+    .workers(1)
+    .run();
+    actix_web::rt::spawn(challenge_server);
+    info!(
+        "Answering ACME challenges at {}:{}",
+        host, acme.challenge_port
+    );
+
+    if client.needs_renewal() {
+        info!("Requesting a certificate for {}", acme.domain);
+        // An existing certificate still gets served if this fails
+        if let Err(e) = client.provision().await {
+            error!(
+                "Failed to provision a certificate for {}: {}",
+                acme.domain, e
+            );
+        }
+    }
+    Ok(client)
+}
+
+/// `--inherit-listener <fd>`: serve on a listening socket passed down by the
+/// process starting this one instead of binding `server.host:server.port`.
+fn inherit_listener_flag(args: &[String]) -> Option<i32> {
+    let position = args.iter().position(|arg| arg == "--inherit-listener")?;
+    match args.get(position + 1).map(|fd| fd.parse::<i32>()) {
+        Some(Ok(fd)) if fd >= 0 => Some(fd),
+        _ => {
+            eprintln!("usage: rustic-insights [--inherit-listener <fd>]");
+            process::exit(2);
+        }
+    }
+}
+
+fn load_config() -> AppConfig {
+    let (config, issues) = AppConfig::load_and_check().unwrap_or_else(|e| {
+        error!("Failed to load configuration: {}", e);
+        process::exit(1);
+    });
+
+    if !issues.is_empty() {
+        for issue in &issues {
+            error!("Invalid configuration: {}", issue);
+        }
+        process::exit(1);
+    }
+
+    config
+}
+
+fn check_config() -> std::io::Result<()> {
+    match AppConfig::load_and_check() {
+        Ok((_, issues)) if issues.is_empty() => {
+            println!("Configuration OK");
+            Ok(())
+        }
+        Ok((_, issues)) => {
+            eprintln!("Found {} configuration problem(s):", issues.len());
+            for issue in &issues {
+                eprintln!("  - {}", issue);
+            }
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// `doctor`: checks the configuration, the listening port, the directories
+/// written to and the upstreams forwarded to, exiting with 1 if any fails.
+async fn doctor() -> std::io::Result<()> {
+    let (config, issues) = match AppConfig::load_and_check() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("FAIL  config: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let checks = doctor::run_checks(&config, &issues).await;
+    for check in &checks {
+        println!("{}  {}: {}", check.outcome, check.name, check.detail);
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.outcome == doctor::Outcome::Fail)
+        .count();
+    println!("{} checks, {} failed", checks.len(), failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// `replay <path> [--target <url>] [--speed <factor>] [--key-file <path>]
+/// [--source-key-file <source>=<path>]...`: re-sends a JSON-lines capture of
+/// batches or dead letters to a server, opening encrypted lines with the
+/// keys they were sealed with. Keys are read from files, or from
+/// `APP__ENCRYPTION__*` like the server's, never from the command line.
+async fn replay(args: &[String]) -> std::io::Result<()> {
+    let usage = "usage: rustic-insights replay <path> [--target <url>] [--speed <factor>] \
+                 [--key-file <path>] [--source-key-file <source>=<path>]...";
+    let Some(path) = args.first() else {
+        eprintln!("{}", usage);
+        process::exit(2);
+    };
+
+    let mut options = ReplayOptions {
+        target: "http://localhost:8080".to_string(),
+        speed: 1.0,
+        keyring: Keyring::default(),
+    };
+    let mut keys = match EncryptionConfig::from_env() {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        let value = flags.next();
+        match (flag.as_str(), value) {
+            ("--target", Some(target)) => options.target = target.clone(),
+            ("--speed", Some(speed)) => match speed.parse::<f64>() {
+                Ok(speed) if speed >= 0.0 => options.speed = speed,
+                _ => {
+                    eprintln!("--speed must be a non-negative number, got '{}'", speed);
+                    process::exit(2);
+                }
+            },
+            ("--key-file", Some(file)) => keys.key = Some(read_key_file("key", file)),
+            ("--source-key-file", Some(source_file)) => match source_file.split_once('=') {
+                Some((source, file)) => {
+                    let key = read_key_file(&format!("source_keys.{}", source), file);
+                    keys.source_keys.insert(source.to_string(), key);
+                }
+                None => {
+                    eprintln!(
+                        "--source-key-file must be <source>=<path>, got '{}'",
+                        source_file
+                    );
+                    process::exit(2);
+                }
+            },
+            _ => {
+                eprintln!("{}", usage);
+                process::exit(2);
+            }
+        }
+    }
+    options.keyring = match Keyring::new(Some(&keys)) {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    match replay::replay(Path::new(path), &options).await {
+        Ok(summary) => {
+            println!(
+                "Replayed {} batches ({} metrics), {} failed, {} skipped",
+                summary.batches, summary.metrics, summary.failed, summary.skipped
+            );
+            if summary.failed > 0 {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Replay failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// The key in the file at `path`, exiting if it can't be read.
+fn read_key_file(setting: &str, path: &str) -> String {
+    match read_secret_file(setting, path) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
 }
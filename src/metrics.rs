@@ -1,7 +1,47 @@
+pub mod annotations;
+pub mod anomaly;
+pub mod cardinality;
+pub mod catalog;
+pub mod coalescing;
 pub mod collector;
+pub mod compaction;
+pub mod dashboard;
+pub mod dedup;
+pub mod drift;
+pub mod enrichment;
+pub mod export;
+pub mod exposition;
+pub mod groups;
+pub mod handles;
+pub mod histogram;
+pub mod history;
+pub mod interner;
+pub mod listing;
+pub mod memory;
+pub mod plugins;
+pub mod query;
+pub mod rates;
+pub mod registrations;
 pub mod registry;
+pub mod rules;
+pub mod sampling;
+pub mod schema;
+pub mod scripts;
+pub mod selector;
+pub mod self_metrics;
+pub mod skew;
+pub mod slo;
+pub mod sources;
+pub mod store;
+pub mod synthetic;
+pub mod target;
+pub mod tombstones;
+pub mod topk;
 pub mod types;
 
 pub use collector::MetricsCollector;
 pub use registry::MetricsRegistry;
-pub use types::{Metric, MetricType, MetricValue, MetricsBatch, MetricsResponse};
+pub use types::{
+    GaugeOperation, Metric, MetricError, MetricSamples, MetricType, MetricV2, MetricValue,
+    MetricsBatch, MetricsBatchV2, MetricsResponse, PushedTimestamp, SampleValue, TimestampUnit,
+};
@@ -0,0 +1,201 @@
+//! Events such as deploys posted to `POST /api/annotations`, kept for as long
+//! as the sample history so they can be laid over the metrics they explain,
+//! in Grafana or an export. With `history.annotations_path` they're saved
+//! along with it, so a restart doesn't lose them.
+
+use crate::errors::ServerError;
+use crate::utils::fs::write_json;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Annotations kept; the oldest are forgotten first.
+const MAX_ANNOTATIONS: usize = 10_000;
+
+/// How long annotations are kept without a `history` retention to follow.
+pub const DEFAULT_RETENTION_SECS: u64 = 7 * 24 * 3600;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub id: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    pub tags: Vec<String>,
+    /// The source the event concerns, e.g. the service deployed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub time: DateTime<Utc>,
+    /// The end of an event spanning a range, such as a rollout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_end: Option<DateTime<Utc>>,
+}
+
+impl Annotation {
+    fn end(&self) -> DateTime<Utc> {
+        self.time_end.unwrap_or(self.time)
+    }
+}
+
+/// What `POST /api/annotations` takes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewAnnotation {
+    pub title: String,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub source: Option<String>,
+    /// When the event started, now when unset.
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub time_end: Option<DateTime<Utc>>,
+}
+
+/// Which annotations to return; every set filter must match.
+#[derive(Debug, Clone, Default)]
+pub struct AnnotationFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Annotations carrying all of these tags.
+    pub tags: Vec<String>,
+    pub source: Option<String>,
+}
+
+impl AnnotationFilter {
+    fn matches(&self, annotation: &Annotation) -> bool {
+        self.from.is_none_or(|from| annotation.end() >= from)
+            && self.to.is_none_or(|to| annotation.time <= to)
+            && self.tags.iter().all(|tag| annotation.tags.contains(tag))
+            && self
+                .source
+                .as_ref()
+                .is_none_or(|source| annotation.source.as_ref() == Some(source))
+    }
+}
+
+pub struct Annotations {
+    retention: Duration,
+    annotations: RwLock<VecDeque<Annotation>>,
+    /// Where the annotations are saved, `None` when only kept in memory.
+    path: Option<PathBuf>,
+    // Held from the change to the write, so saves land in order
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Annotations {
+    pub fn new(retention_secs: u64) -> Self {
+        Self {
+            // Clamped, as a retention beyond chrono's range means forever
+            retention: i64::try_from(retention_secs)
+                .ok()
+                .and_then(Duration::try_seconds)
+                .unwrap_or(Duration::MAX),
+            annotations: RwLock::new(VecDeque::new()),
+            path: None,
+            saving: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Annotations saved to `path`, starting with those it already holds.
+    pub fn open(retention_secs: u64, path: impl Into<PathBuf>) -> Result<Self, ServerError> {
+        let path = path.into();
+        let saved: Vec<Annotation> = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(ServerError::InternalError(Box::new(e))),
+        };
+        let annotations = Self {
+            path: Some(path),
+            ..Self::new(retention_secs)
+        };
+        {
+            let mut held = annotations
+                .annotations
+                .write()
+                .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+            held.extend(saved);
+            held.make_contiguous()
+                .sort_by_key(|annotation| annotation.time);
+            annotations.expire(&mut held);
+        }
+        Ok(annotations)
+    }
+
+    pub async fn add(&self, new: NewAnnotation) -> Result<Annotation, ServerError> {
+        if new.title.trim().is_empty() {
+            return Err(ServerError::ValidationError(
+                "title must not be empty".to_string(),
+            ));
+        }
+        let time = new.time.unwrap_or_else(Utc::now);
+        if new.time_end.is_some_and(|end| end < time) {
+            return Err(ServerError::ValidationError(
+                "time_end must not be before time".to_string(),
+            ));
+        }
+
+        let annotation = Annotation {
+            id: Uuid::new_v4().to_string(),
+            title: new.title,
+            text: new.text,
+            tags: new.tags,
+            source: new.source,
+            time,
+            time_end: new.time_end,
+        };
+        let _saving = self.saving.lock().await;
+        let contents = {
+            let mut annotations = self
+                .annotations
+                .write()
+                .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+            // Kept in time order, events are often posted after the fact
+            let at = annotations.partition_point(|other| other.time <= annotation.time);
+            annotations.insert(at, annotation.clone());
+            self.expire(&mut annotations);
+            match self.path {
+                Some(_) => Some(serde_json::to_vec(&*annotations)?),
+                None => None,
+            }
+        };
+        if let (Some(path), Some(contents)) = (self.path.clone(), contents) {
+            tokio::task::spawn_blocking(move || write_json(&path, &contents))
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))??;
+        }
+        Ok(annotation)
+    }
+
+    /// Forgets the annotations past the retention, and the oldest beyond
+    /// `MAX_ANNOTATIONS`.
+    fn expire(&self, annotations: &mut VecDeque<Annotation>) {
+        let oldest = Utc::now().checked_sub_signed(self.retention);
+        while annotations.len() > MAX_ANNOTATIONS
+            || annotations
+                .front()
+                .is_some_and(|front| oldest.is_some_and(|oldest| front.end() < oldest))
+        {
+            annotations.pop_front();
+        }
+    }
+
+    /// The annotations matching `filter`, oldest first.
+    pub fn query(&self, filter: &AnnotationFilter) -> Vec<Annotation> {
+        self.annotations
+            .read()
+            .map(|annotations| {
+                annotations
+                    .iter()
+                    .filter(|annotation| filter.matches(annotation))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,194 @@
+use crate::config::AnomalyConfig;
+use crate::metrics::types::{GaugeOperation, Metric, MetricType};
+use crate::notify::{Alert, Severity};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+type SeriesKey = (String, BTreeMap<String, String>);
+
+/// A gauge value outside the band expected from the series' recent values.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Anomaly {
+    pub metric: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    pub expected: f64,
+    /// Distance from `expected` in standard deviations.
+    pub score: f64,
+    pub detected_at: DateTime<Utc>,
+}
+
+impl Anomaly {
+    pub fn alert(&self, severity: Severity) -> Alert {
+        let values = [
+            ("metric", self.metric.clone()),
+            ("value", self.value.to_string()),
+            ("expected", format!("{:.3}", self.expected)),
+            ("score", format!("{:.1}", self.score)),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+
+        Alert {
+            rule: "anomaly".to_string(),
+            severity,
+            summary: format!(
+                "{} is {} ({:.1} standard deviations from {:.3})",
+                self.metric, self.value, self.score, self.expected
+            ),
+            labels: self.labels.clone(),
+            values,
+        }
+    }
+}
+
+/// Exponentially weighted mean and variance of one series.
+struct Band {
+    samples: usize,
+    mean: f64,
+    variance: f64,
+    updated: Instant,
+}
+
+impl Band {
+    fn new() -> Self {
+        Self {
+            samples: 0,
+            mean: 0.0,
+            variance: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn update(&mut self, value: f64, alpha: f64) {
+        self.updated = Instant::now();
+        if self.samples == 0 {
+            self.mean = value;
+        } else {
+            let diff = value - self.mean;
+            let increment = alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        }
+        self.samples += 1;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    bands: HashMap<SeriesKey, Band>,
+    active: HashMap<SeriesKey, Anomaly>,
+}
+
+/// Flags gauges set to values more than `threshold` standard deviations away
+/// from their EWMA, keeping the last anomaly of each series until a value
+/// within the band is set again. Series not set within `state_ttl_secs` are
+/// forgotten by `expire`.
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    state: Mutex<State>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn config(&self) -> &AnomalyConfig {
+        &self.config
+    }
+
+    /// Checks a written metric, returning the anomalies it starts. Only gauges
+    /// set to absolute values are considered, since increments say nothing
+    /// about the level.
+    pub fn observe(&self, metric: &Metric) -> Vec<Anomaly> {
+        if metric.metric_type != MetricType::Gauge || metric.operation != GaugeOperation::Set {
+            return Vec::new();
+        }
+        if !self.config.metrics.is_empty() && !self.config.metrics.contains(&metric.name) {
+            return Vec::new();
+        }
+
+        let key = (
+            metric.name.clone(),
+            metric.labels.clone().into_iter().collect(),
+        );
+        let mut detected = Vec::new();
+        {
+            let Ok(mut state) = self.state.lock() else {
+                return Vec::new();
+            };
+            for sample in metric.value.as_slice() {
+                let band = state.bands.entry(key.clone()).or_insert_with(Band::new);
+                let deviation = band.variance.sqrt();
+                let score = (sample.value - band.mean).abs() / deviation;
+                let anomaly = (band.samples >= self.config.min_samples
+                    && deviation > 0.0
+                    && score > self.config.threshold)
+                    .then(|| Anomaly {
+                        metric: key.0.clone(),
+                        labels: key.1.clone(),
+                        value: sample.value,
+                        expected: band.mean,
+                        score,
+                        detected_at: Utc::now(),
+                    });
+                band.update(sample.value, self.config.alpha);
+
+                match anomaly {
+                    Some(anomaly) => {
+                        if !state.active.contains_key(&key) {
+                            detected.push(anomaly.clone());
+                        }
+                        state.active.insert(key.clone(), anomaly);
+                    }
+                    None => {
+                        state.active.remove(&key);
+                    }
+                }
+            }
+        }
+
+        for anomaly in &detected {
+            info!(
+                "Anomaly in {}: {} is {:.1} standard deviations from {}",
+                anomaly.metric, anomaly.value, anomaly.score, anomaly.expected
+            );
+        }
+        detected
+    }
+
+    /// Forgets the series not set within `state_ttl_secs`, with their
+    /// anomaly, returning how many there were. Run on the compaction timer.
+    pub fn expire(&self) -> usize {
+        let Ok(mut state) = self.state.lock() else {
+            return 0;
+        };
+        let ttl = Duration::from_secs(self.config.state_ttl_secs);
+        let before = state.bands.len();
+        state.bands.retain(|_, band| band.updated.elapsed() < ttl);
+        let State { bands, active } = &mut *state;
+        active.retain(|key, _| bands.contains_key(key));
+        before - state.bands.len()
+    }
+
+    /// Current anomalies, most recent first.
+    pub fn anomalies(&self) -> Vec<Anomaly> {
+        let Ok(state) = self.state.lock() else {
+            return Vec::new();
+        };
+        let mut anomalies: Vec<Anomaly> = state.active.values().cloned().collect();
+        anomalies.sort_by_key(|anomaly| Reverse(anomaly.detected_at));
+        anomalies
+    }
+}
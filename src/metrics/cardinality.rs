@@ -0,0 +1,94 @@
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricCardinality {
+    pub name: String,
+    pub series: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LabelCardinality {
+    pub name: String,
+    pub distinct_values: usize,
+}
+
+/// Where the registry's series come from, to hunt down cardinality explosions.
+#[derive(Debug, Clone, Serialize)]
+pub struct CardinalityReport {
+    pub total_series: usize,
+    /// The `limit` families with the most series.
+    pub metrics: Vec<MetricCardinality>,
+    /// The `limit` label names with the most distinct values across all families.
+    pub label_keys: Vec<LabelCardinality>,
+    /// Series pushed by each source.
+    pub sources: BTreeMap<String, usize>,
+}
+
+/// Counts time series the way Prometheus stores them: a histogram contributes
+/// one series per bucket (including `+Inf`) plus `_sum` and `_count`.
+pub fn cardinality_report(
+    families: &[MetricFamily],
+    sources: BTreeMap<String, usize>,
+    limit: usize,
+) -> CardinalityReport {
+    let mut metrics = Vec::with_capacity(families.len());
+    let mut label_values: HashMap<&str, HashSet<&str>> = HashMap::new();
+
+    for family in families {
+        let series_per_child = match family.get_field_type() {
+            MetricType::HISTOGRAM => family
+                .get_metric()
+                .first()
+                .map(|metric| metric.get_histogram().get_bucket().len() + 3)
+                .unwrap_or(0),
+            MetricType::SUMMARY => family
+                .get_metric()
+                .first()
+                .map(|metric| metric.get_summary().get_quantile().len() + 2)
+                .unwrap_or(0),
+            _ => 1,
+        };
+
+        metrics.push(MetricCardinality {
+            name: family.get_name().to_string(),
+            series: family.get_metric().len() * series_per_child,
+        });
+
+        for metric in family.get_metric() {
+            for pair in metric.get_label() {
+                label_values
+                    .entry(pair.get_name())
+                    .or_default()
+                    .insert(pair.get_value());
+            }
+        }
+    }
+
+    let total_series = metrics.iter().map(|metric| metric.series).sum();
+
+    metrics.sort_by(|a, b| b.series.cmp(&a.series).then_with(|| a.name.cmp(&b.name)));
+    metrics.truncate(limit);
+
+    let mut label_keys: Vec<LabelCardinality> = label_values
+        .into_iter()
+        .map(|(name, values)| LabelCardinality {
+            name: name.to_string(),
+            distinct_values: values.len(),
+        })
+        .collect();
+    label_keys.sort_by(|a, b| {
+        b.distinct_values
+            .cmp(&a.distinct_values)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    label_keys.truncate(limit);
+
+    CardinalityReport {
+        total_series,
+        metrics,
+        label_keys,
+        sources,
+    }
+}
@@ -0,0 +1,116 @@
+use crate::errors::ServerError;
+use crate::metrics::types::{Metric, MetricType};
+use crate::utils::validation::validate_utf8_metric_name;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// What is known about a metric: the description and type it was first
+/// ingested with, and who to contact about it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MetricMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metric_type: Option<MetricType>,
+    #[serde(flatten)]
+    pub ownership: Ownership,
+}
+
+/// Ownership fields set through `POST /api/metadata/{name}`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Ownership {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runbook_url: Option<String>,
+}
+
+/// Metadata of every metric seen since startup, keyed by metric name
+/// (without the configured prefix).
+#[derive(Default)]
+pub struct MetadataCatalog {
+    entries: RwLock<BTreeMap<String, MetricMetadata>>,
+}
+
+impl MetadataCatalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the description and type of an ingested metric, unless they
+    /// are already known.
+    pub fn observe(&self, metric: &Metric) {
+        let known = self
+            .entries
+            .read()
+            .is_ok_and(|entries| entries.get(&metric.name).is_some_and(|e| e.help.is_some()));
+        if known {
+            return;
+        }
+
+        if let Ok(mut entries) = self.entries.write() {
+            let entry = entries.entry(metric.name.clone()).or_default();
+            entry.help.get_or_insert_with(|| metric.help.clone());
+            entry
+                .metric_type
+                .get_or_insert_with(|| metric.metric_type.clone());
+        }
+    }
+
+    /// Replaces the ownership fields of a metric, which need not have been
+    /// ingested yet.
+    pub fn set_ownership(
+        &self,
+        name: &str,
+        ownership: Ownership,
+    ) -> Result<MetricMetadata, ServerError> {
+        validate_utf8_metric_name(name)?;
+        validate_ownership(&ownership)?;
+
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+        let entry = entries.entry(name.to_string()).or_default();
+        entry.ownership = ownership;
+        Ok(entry.clone())
+    }
+
+    pub fn get(&self, name: &str) -> Option<MetricMetadata> {
+        self.entries.read().ok()?.get(name).cloned()
+    }
+
+    pub fn list(&self) -> BTreeMap<String, MetricMetadata> {
+        self.entries
+            .read()
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+}
+
+fn validate_ownership(ownership: &Ownership) -> Result<(), ServerError> {
+    for (field, value) in [("owner", &ownership.owner), ("team", &ownership.team)] {
+        if value.as_ref().is_some_and(|value| value.trim().is_empty()) {
+            return Err(ServerError::ValidationError(format!(
+                "{} must not be empty",
+                field
+            )));
+        }
+    }
+
+    if let Some(url) = &ownership.runbook_url {
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|e| ServerError::ValidationError(format!("invalid runbook_url: {}", e)))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(ServerError::ValidationError(format!(
+                "runbook_url must be an http(s) URL, got '{}'",
+                url
+            )));
+        }
+    }
+
+    Ok(())
+}
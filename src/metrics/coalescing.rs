@@ -0,0 +1,197 @@
+//! Coalescing of bursts of writes to the same family. A writer finding no
+//! other at work on the family applies its update right away and leads it;
+//! when writers queued up behind it meanwhile, it then waits up to
+//! `max_delay_ms` for the rest of the burst to queue, and applies the queued
+//! updates in runs, so the family is resolved and its indexes locked once per
+//! run rather than once per write. A writer with no company is never delayed,
+//! so neither are the metrics of a batch written one after another.
+
+use crate::config::WriteCoalescingConfig;
+use crate::errors::ServerError;
+use crate::metrics::types::Metric;
+use prometheus::IntCounter;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::sync::{Notify, oneshot};
+
+type WriteResult = Result<(), ServerError>;
+
+pub struct WriteCoalescer {
+    max_delay: Duration,
+    max_batch: usize,
+    queues: RwLock<HashMap<String, Arc<FamilyQueue>>>,
+    coalesced: IntCounter,
+}
+
+#[derive(Default)]
+struct FamilyQueue {
+    state: Mutex<QueueState>,
+    // Signalled when `max_batch` writes are queued
+    full: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    leading: bool,
+    pending: Vec<Pending>,
+}
+
+struct Pending {
+    metric: Metric,
+    done: oneshot::Sender<WriteResult>,
+}
+
+impl WriteCoalescer {
+    /// Counts the writes applied by another writer's run in `coalesced`.
+    pub fn new(config: &WriteCoalescingConfig, coalesced: IntCounter) -> Self {
+        Self {
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            max_batch: config.max_batch.max(1),
+            queues: RwLock::new(HashMap::new()),
+            coalesced,
+        }
+    }
+
+    fn queue(&self, family: &str) -> Arc<FamilyQueue> {
+        if let Ok(queues) = self.queues.read()
+            && let Some(queue) = queues.get(family)
+        {
+            return queue.clone();
+        }
+        match self.queues.write() {
+            Ok(mut queues) => queues.entry(family.to_string()).or_default().clone(),
+            Err(_) => Arc::default(),
+        }
+    }
+
+    /// Forgets the queue of an unregistered family.
+    pub fn forget(&self, family: &str) {
+        if let Ok(mut queues) = self.queues.write() {
+            queues.remove(family);
+        }
+    }
+
+    /// Writes `metric` to `family` with `apply`, which applies a run of
+    /// updates to the family and returns the result of each.
+    pub async fn write<F>(&self, family: &str, metric: &Metric, apply: F) -> WriteResult
+    where
+        F: Fn(&[&Metric]) -> Vec<WriteResult>,
+    {
+        let queue = self.queue(family);
+        let queued = {
+            let Ok(mut state) = queue.state.lock() else {
+                return apply_one(&apply, metric);
+            };
+            if state.leading {
+                let (done, result) = oneshot::channel();
+                state.pending.push(Pending {
+                    metric: metric.clone(),
+                    done,
+                });
+                if state.pending.len() >= self.max_batch {
+                    // Only wakes a leader already waiting, leaving no permit
+                    // to cut a later burst short
+                    queue.full.notify_waiters();
+                }
+                Some(result)
+            } else {
+                state.leading = true;
+                None
+            }
+        };
+        if let Some(result) = queued {
+            return result.await.unwrap_or_else(|_| {
+                Err(ServerError::MetricsProcessingError(format!(
+                    "Coalesced write to '{}' was dropped",
+                    family
+                )))
+            });
+        }
+
+        // Applies what queued up, even if this future is dropped while waiting
+        let leader = Leader {
+            queue: &queue,
+            apply: &apply,
+            max_batch: self.max_batch,
+            coalesced: &self.coalesced,
+        };
+        let result = apply_one(&apply, metric);
+        // Lets writers ready to run queue up behind this one
+        tokio::task::yield_now().await;
+        let full = queue.full.notified();
+        tokio::pin!(full);
+        // Registered before counting, so a writer filling the run meanwhile
+        // still wakes it
+        full.as_mut().enable();
+        let pending = leader.pending();
+        if pending > 0 && pending < self.max_batch {
+            // Give the burst a moment to gather the rest
+            let _ = tokio::time::timeout(self.max_delay, full).await;
+        }
+        drop(leader);
+        result
+    }
+}
+
+fn apply_one<F>(apply: &F, metric: &Metric) -> WriteResult
+where
+    F: Fn(&[&Metric]) -> Vec<WriteResult>,
+{
+    apply(&[metric]).pop().unwrap_or(Ok(()))
+}
+
+struct Leader<'a, F>
+where
+    F: Fn(&[&Metric]) -> Vec<WriteResult>,
+{
+    queue: &'a FamilyQueue,
+    apply: &'a F,
+    max_batch: usize,
+    coalesced: &'a IntCounter,
+}
+
+impl<F> Leader<'_, F>
+where
+    F: Fn(&[&Metric]) -> Vec<WriteResult>,
+{
+    fn pending(&self) -> usize {
+        self.queue
+            .state
+            .lock()
+            .map_or(0, |state| state.pending.len())
+    }
+}
+
+impl<F> Drop for Leader<'_, F>
+where
+    F: Fn(&[&Metric]) -> Vec<WriteResult>,
+{
+    fn drop(&mut self) {
+        loop {
+            let run: Vec<Pending> = {
+                // Queued writers wait on this run, so a poisoned lock is no
+                // reason to leave them hanging
+                let mut state = self
+                    .queue
+                    .state
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if state.pending.is_empty() {
+                    state.leading = false;
+                    return;
+                }
+                let len = state.pending.len().min(self.max_batch);
+                state.pending.drain(..len).collect()
+            };
+
+            let metrics: Vec<&Metric> = run.iter().map(|pending| &pending.metric).collect();
+            let results = (self.apply)(&metrics);
+            self.coalesced.inc_by(run.len() as u64);
+            for (pending, result) in run.into_iter().zip(results) {
+                // The writer may have given up waiting
+                let _ = pending.done.send(result);
+            }
+        }
+    }
+}
@@ -1,75 +1,1232 @@
+use crate::api::models::{NormalizeResponse, NormalizedMetric, Validate};
+use crate::config::{
+    AppConfig, EnumKind, IngestMode, LabelValuePolicy, LifecycleEventKind, MetricsConfig,
+    NegativeCounterPolicy, NonFinitePolicy, RenameRule, UnknownMetricPolicy,
+};
+use crate::dead_letter::{self, DeadLetter, DeadLetterQueue, DeadLetterWriter};
+use crate::encryption::Keyring;
 use crate::errors::ServerError;
-use crate::metrics::registry::MetricsRegistry;
-use crate::metrics::types::{Metric, MetricsBatch, MetricsResponse};
-use tracing::{debug, error, instrument};
+use crate::events::{EventWebhooks, LifecycleEvent};
+use crate::metrics::anomaly::{Anomaly, AnomalyDetector};
+use crate::metrics::cardinality::{CardinalityReport, cardinality_report};
+use crate::metrics::catalog::MetadataCatalog;
+use crate::metrics::compaction::CompactionStats;
+use crate::metrics::dedup::Deduplicator;
+use crate::metrics::drift::DriftDetector;
+use crate::metrics::enrichment::LabelEnricher;
+use crate::metrics::interner::Interner;
+use crate::metrics::plugins::Plugins;
+use crate::metrics::registrations::{IssuedToken, SourceRegistrations, SourceRequest};
+use crate::metrics::registry::{MetricsRegistry, PendingSeries, WriteUndo};
+use crate::metrics::sampling::Sampler;
+use crate::metrics::schema::{SchemaMatch, SchemaRegistry};
+use crate::metrics::scripts::Scripts;
+use crate::metrics::self_metrics::SelfMetrics;
+use crate::metrics::skew::SkewCorrector;
+use crate::metrics::store::{self, MetricsStore, Snapshot, StoreWrite};
+use crate::metrics::tombstones::{Tombstone, Tombstones};
+use crate::metrics::topk::{TopKReport, TopKTracker};
+use crate::metrics::types::{Metric, MetricError, MetricType, MetricsBatch, MetricsResponse};
+use crate::notify::{Notifier, Silences};
+use crate::sinks::fanout::BatchFanout;
+use crate::utils::validation::{
+    sanitize_label_value, validate_counter_increment, validate_label_values,
+};
+use chrono::Utc;
+use prometheus::proto::MetricFamily;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, instrument, warn};
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+/// How long the families read from a shared store serve further scrapes.
+const STORE_SNAPSHOT_REUSE: Duration = Duration::from_secs(1);
+
+/// The `source` label of the self-metrics for sources neither registered
+/// nor configured.
+pub const OTHER_SOURCE: &str = "other";
 
 pub struct MetricsCollector {
     registry: MetricsRegistry,
+    rename_rules: HashMap<String, RenameRule>,
+    topk: TopKTracker,
+    anomalies: Option<AnomalyDetector>,
+    drift: Option<DriftDetector>,
+    sampler: Sampler,
+    dedup: Deduplicator,
+    skew: SkewCorrector,
+    plugins: Plugins,
+    scripts: Scripts,
+    notifier: Arc<Notifier>,
+    events: Arc<EventWebhooks>,
+    dead_letter: Option<DeadLetterQueue>,
+    keyring: Arc<Keyring>,
+    store: Option<Arc<dyn MetricsStore>>,
+    fanout: Option<Arc<BatchFanout>>,
+    // Set once `SeriesLimitReached` fired, until compaction frees series
+    series_limit_reached: AtomicBool,
+    // The families last read from the store, with when
+    store_snapshot: tokio::sync::Mutex<Option<(Instant, Vec<MetricFamily>)>>,
 }
 
 impl MetricsCollector {
     pub fn new(registry: MetricsRegistry) -> Self {
-        Self { registry }
+        let rename_rules = registry
+            .config()
+            .rename_rules
+            .iter()
+            .map(|rule| (rule.from.clone(), rule.clone()))
+            .collect();
+        let topk = TopKTracker::new(&registry.config().topk);
+        let anomalies = registry.config().anomaly.clone().map(AnomalyDetector::new);
+        let drift = registry
+            .config()
+            .schema_drift
+            .clone()
+            .map(DriftDetector::new);
+        let sampler = Sampler::new(&registry.config().sampling);
+        let dedup = Deduplicator::new(
+            registry.config().dedup_window_secs,
+            &registry.config().source_dedup_window_secs,
+        );
+        let skew = SkewCorrector::new(
+            registry.config().max_clock_skew_secs,
+            &registry.config().source_max_clock_skew_secs,
+        );
+
+        Self {
+            registry,
+            rename_rules,
+            topk,
+            anomalies,
+            drift,
+            sampler,
+            dedup,
+            skew,
+            plugins: Plugins::default(),
+            scripts: Scripts::default(),
+            notifier: Arc::new(Notifier::default()),
+            events: Arc::new(EventWebhooks::default()),
+            dead_letter: None,
+            keyring: Arc::default(),
+            store: None,
+            fanout: None,
+            series_limit_reached: AtomicBool::new(false),
+            store_snapshot: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// The collector `config` describes, with its label enrichment, plugins,
+    /// scripts, notification channels, event webhooks, encryption keys,
+    /// dead-letter destination and shared store. This is all the server sets up around
+    /// ingestion, so applications can embed the engine without it; enrichment
+    /// is refreshed on a background task, which needs a tokio runtime.
+    pub fn from_config(config: &AppConfig) -> Result<Self, ServerError> {
+        let mut registry = MetricsRegistry::new(config.metrics.clone())?;
+        if let Some(enrichment) = config.enrichment.clone() {
+            let enricher = Arc::new(LabelEnricher::new(enrichment));
+            enricher.clone().spawn_refresh();
+            registry = registry.with_enricher(enricher);
+        }
+
+        let notifier = Notifier::new(&config.notification_channels)?
+            .with_silences(Silences::new(&config.maintenance_windows)?);
+        let mut collector = Self::new(registry)
+            .with_notifier(Arc::new(notifier))
+            .with_events(Arc::new(EventWebhooks::new(&config.event_webhooks)))
+            .with_plugins(Plugins::load(&config.plugins)?)
+            .with_scripts(Scripts::load(&config.scripts)?);
+        let keyring = Arc::new(Keyring::new(config.encryption.as_ref())?);
+        if let Some(dead_letter) = &config.dead_letter {
+            collector = collector
+                .with_dead_letter(dead_letter::build_writer(dead_letter, keyring.clone())?);
+        }
+        collector = collector.with_keyring(keyring);
+        if let Some(shared) = &config.metrics.store {
+            collector = collector.with_store(store::build_store(shared)?);
+        }
+        Ok(collector)
+    }
+
+    /// Forwards rejected batches and metrics to `writer`, in the background.
+    pub fn with_dead_letter(mut self, writer: Arc<dyn DeadLetterWriter>) -> Self {
+        self.dead_letter = Some(DeadLetterQueue::new(writer));
+        self
+    }
+
+    /// Whether rejected payloads are dead-lettered.
+    pub fn has_dead_letter(&self) -> bool {
+        self.dead_letter.is_some()
+    }
+
+    /// Waits for the dead letters queued so far to be written.
+    pub async fn flush_dead_letters(&self) {
+        if let Some(queue) = &self.dead_letter {
+            queue.flush().await;
+        }
+    }
+
+    /// Encrypts what is written to disk for each source, such as captures and
+    /// fan-out files, with the keys of `keyring`.
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    /// The keys records of each source are encrypted with on disk.
+    pub fn keyring(&self) -> &Arc<Keyring> {
+        &self.keyring
+    }
+
+    /// Mirrors every write to `store` and exposes what it holds, so replicas
+    /// sharing it serve the same registry.
+    pub fn with_store(mut self, store: Arc<dyn MetricsStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Hands the metrics every batch wrote to the destinations of `fanout`,
+    /// whichever path it was ingested through, or every batch instead of
+    /// writing it when the registry isn't one of them.
+    pub fn with_fanout(mut self, fanout: Arc<BatchFanout>) -> Self {
+        self.fanout = Some(fanout);
+        self
+    }
+
+    /// Sends alerts raised by ingestion, such as anomalies, through `notifier`.
+    pub fn with_notifier(mut self, notifier: Arc<Notifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Runs `plugins` on every pushed metric before the ingestion policies.
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    /// Runs `scripts` on every pushed metric, after the plugins.
+    pub fn with_scripts(mut self, scripts: Scripts) -> Self {
+        self.scripts = scripts;
+        self
+    }
+
+    /// Sends ingestion lifecycle events to the webhooks of `events`.
+    pub fn with_events(mut self, events: Arc<EventWebhooks>) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn self_metrics(&self) -> &SelfMetrics {
+        self.registry.self_metrics()
+    }
+
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn events(&self) -> &EventWebhooks {
+        &self.events
+    }
+
+    /// Validates and processes a batch received through any ingestion path.
+    pub async fn ingest(&self, batch: MetricsBatch) -> Result<MetricsResponse, ServerError> {
+        self.validate(&batch).await?;
+        self.process_batch(batch).await
+    }
+
+    /// Validates a batch as a whole, dead-lettering it when rejected.
+    pub async fn validate(&self, batch: &MetricsBatch) -> Result<(), ServerError> {
+        let result = self
+            .registrations()
+            .check_registered(&batch.source)
+            .and_then(|()| batch.validate(self.config()));
+        if let Err(e) = result {
+            self.batch_rejected(&batch.source, e.to_string());
+            if self.dead_letter.is_some() {
+                let payload = serde_json::to_value(batch)?;
+                self.dead_letter(&batch.source, payload, &e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn batch_rejected(&self, source: &str, reason: String) {
+        if self.events.wants(LifecycleEventKind::BatchRejected) {
+            self.events.emit(LifecycleEvent::BatchRejected {
+                source: source.to_string(),
+                reason,
+            });
+        }
+    }
+
+    /// Queues a rejected payload for the configured dead-letter writer, if any.
+    /// Failing to write it is logged but never fails ingestion.
+    pub fn dead_letter(&self, source: &str, payload: serde_json::Value, error: &ServerError) {
+        if let Some(queue) = &self.dead_letter {
+            queue.send(DeadLetter::new(source, payload, error));
+        }
     }
 
-    #[instrument(skip(self, batch), fields(source = %batch.source))]
     pub async fn process_batch(&self, batch: MetricsBatch) -> Result<MetricsResponse, ServerError> {
-        let mut response = MetricsResponse::default();
-        let total_metrics = batch.metrics.len();
+        self.process_batch_report(batch).await.into_result()
+    }
+
+    /// The `source` label of the self-metrics: registered sources and those
+    /// named in the per-source settings keep theirs, the others share
+    /// `OTHER_SOURCE`, so clients can't add label values without bound.
+    fn source_label<'a>(&self, source: &'a str) -> &'a str {
+        let config = self.config();
+        let known = self.registrations().is_registered(source)
+            || config.source_ingest_modes.contains_key(source)
+            || config.sampling.contains_key(source)
+            || config.source_dedup_window_secs.contains_key(source)
+            || config.source_max_clock_skew_secs.contains_key(source);
+        if known { source } else { OTHER_SOURCE }
+    }
+
+    /// The ingestion mode for batches from `source`, falling back to the global mode.
+    pub fn ingest_mode(&self, source: &str) -> IngestMode {
+        let config = self.config();
+        config
+            .source_ingest_modes
+            .get(source)
+            .copied()
+            .unwrap_or(config.ingest_mode)
+    }
+
+    /// Processes every metric of a batch, reporting per-metric errors instead
+    /// of failing the batch when nothing could be processed.
+    pub async fn process_batch_report(&self, batch: MetricsBatch) -> MetricsResponse {
+        self.process_metrics(batch, None).await
+    }
+
+    /// Like `process_batch_report`, also returning the metrics that were
+    /// written, as pushed, for the destinations mirroring the registry.
+    pub async fn process_batch_accepted(
+        &self,
+        batch: MetricsBatch,
+    ) -> (MetricsResponse, MetricsBatch) {
+        let source = batch.source.clone();
+        let mut accepted = Vec::new();
+        let response = self.process_metrics(batch, Some(&mut accepted)).await;
+        let accepted = MetricsBatch {
+            metrics: accepted,
+            source,
+        };
+        (response, accepted)
+    }
+
+    /// Processes a batch, adding the metrics written to `accepted` as they
+    /// were pushed, and handing them to the fan-out destinations.
+    #[instrument(skip(self, batch, accepted), fields(source = %batch.source))]
+    async fn process_metrics(
+        &self,
+        mut batch: MetricsBatch,
+        accepted: Option<&mut Vec<Metric>>,
+    ) -> MetricsResponse {
+        let mode = self.ingest_mode(&batch.source);
+        let mut response = MetricsResponse {
+            mode,
+            ..Default::default()
+        };
 
         debug!(
-            "Processing batch of {} metrics from {}",
-            total_metrics, batch.source
+            "Processing batch of {} metrics from {} in {:?} mode",
+            batch.metrics.len(),
+            batch.source,
+            mode
         );
 
-        for metric in batch.metrics {
-            match self.process_metric(metric).await {
+        self.registry.self_metrics().batches_total.inc();
+        self.events.observe_source(&batch.source);
+        if let Some(pushed_at) = self.registrations().record_push(&batch.source) {
+            self.registry
+                .self_metrics()
+                .source_last_push_timestamp_seconds
+                .with_label_values(&[&batch.source])
+                .set(pushed_at.timestamp() as f64);
+        }
+
+        if let Some(fanout) = self
+            .fanout
+            .as_ref()
+            .filter(|fanout| !fanout.writes_registry())
+        {
+            // Nothing is written here, the destinations get the batch as pushed
+            response.processed = batch.metrics.len();
+            fanout.deliver(batch);
+            return response;
+        }
+
+        response.sampled_out = if self.sampler.keep_batch(&batch.source) {
+            self.sampler
+                .sample_metrics(&batch.source, &mut batch.metrics)
+        } else {
+            std::mem::take(&mut batch.metrics).len()
+        };
+        if response.sampled_out > 0 {
+            debug!(
+                "Sampled out {} metrics from {}",
+                response.sampled_out, batch.source
+            );
+            self.registry
+                .self_metrics()
+                .sampled_out_total
+                .with_label_values(&[self.source_label(&batch.source)])
+                .inc_by(response.sampled_out as u64);
+        }
+
+        let deduplicated = self.dedup.deduplicate(&batch.source, &mut batch.metrics);
+        response.deduplicated = deduplicated.dropped;
+        if response.deduplicated > 0 {
+            debug!(
+                "Dropped {} duplicate samples from {}",
+                response.deduplicated, batch.source
+            );
+            self.registry
+                .self_metrics()
+                .deduplicated_samples_total
+                .with_label_values(&[self.source_label(&batch.source)])
+                .inc_by(response.deduplicated as u64);
+        }
+
+        let received_ms = Utc::now().timestamp_millis();
+        if let Some(skew) = self
+            .skew
+            .observe(&batch.source, &mut batch.metrics, received_ms)
+        {
+            let self_metrics = self.registry.self_metrics();
+            let source = self.source_label(&batch.source);
+            self_metrics
+                .source_clock_skew_seconds
+                .with_label_values(&[source])
+                .set(skew.offset_ms as f64 / 1_000.0);
+            if skew.corrected {
+                debug!(
+                    "Shifted timestamps from {} by {}ms of clock skew",
+                    batch.source, -skew.offset_ms
+                );
+                self_metrics
+                    .clock_skew_corrections_total
+                    .with_label_values(&[source])
+                    .inc();
+            }
+        }
+
+        if let Some(drift) = &self.drift {
+            let config = drift.config();
+            for metric in &batch.metrics {
+                let Some(change) = drift.observe(&batch.source, metric) else {
+                    continue;
+                };
+                self.registry
+                    .self_metrics()
+                    .schema_drifts_total
+                    .with_label_values(&[&batch.source, &metric.name])
+                    .inc();
+                self.notifier
+                    .notify(&config.notify, &change.alert(config.severity));
+            }
+        }
+
+        let metrics = match mode {
+            IngestMode::Lenient => std::mem::take(&mut batch.metrics),
+            IngestMode::Strict => match self.prepare_strict(&batch).await {
+                Ok(prepared) => prepared,
+                Err(errors) => {
+                    for index in 0..batch.metrics.len() {
+                        self.dedup.forget(deduplicated.keys(index));
+                    }
+                    response.status = "rejected".to_string();
+                    response.extend_errors(errors);
+                    self.batch_rejected(&batch.source, response.error_summary());
+                    return response;
+                }
+            },
+        };
+
+        let fanout = self
+            .fanout
+            .as_ref()
+            .filter(|fanout| fanout.has_destinations());
+        let mut written = Vec::new();
+        // The strict writes applied so far, undone if a later one fails
+        let mut applied = Vec::new();
+        let mut over_budget = None;
+        for (index, metric) in metrics.into_iter().enumerate() {
+            let original = self.dead_letter.is_some().then(|| metric.clone());
+            // Strict batches are written as prepared
+            let pushed = (accepted.is_some() || fanout.is_some()).then(|| match mode {
+                IngestMode::Lenient => metric.clone(),
+                IngestMode::Strict => batch.metrics[index].clone(),
+            });
+            let result = match mode {
+                IngestMode::Lenient => self.process_metric(&batch.source, metric).await,
+                IngestMode::Strict => {
+                    let undo = self.registry.undo_point(&metric);
+                    let result = self.write_metric(&batch.source, &metric).await;
+                    if result.is_ok() {
+                        applied.push((metric, undo));
+                    }
+                    result
+                }
+            };
+            match result {
                 Ok(_) => {
+                    written.extend(pushed);
                     response.processed += 1;
                 }
                 Err(e) => {
-                    error!("Failed to process metric: {}", e);
-                    response.errors.push(e.to_string());
+                    self.dedup.forget(deduplicated.keys(index));
+                    self.reject_metric(&batch.source, original, &e).await;
+                    response.push_error(MetricError::from(&e));
+                    if let ServerError::MemoryBudgetExceeded(message) = e {
+                        let (rejected, _) = over_budget.get_or_insert((0, message));
+                        *rejected += 1;
+                    }
+                    if mode == IngestMode::Strict {
+                        break;
+                    }
                 }
             }
         }
 
+        // Fired when the budget is first hit, not for every batch hitting it
+        if let Some((rejected, message)) = over_budget
+            && !self.series_limit_reached.swap(true, Ordering::Relaxed)
+            && self.events.wants(LifecycleEventKind::SeriesLimitReached)
+        {
+            self.events.emit(LifecycleEvent::SeriesLimitReached {
+                source: batch.source.clone(),
+                rejected,
+                message,
+            });
+        }
+
+        // Admitted as a whole, a strict batch can still fail to be written,
+        // e.g. by the shared store or new series of concurrent batches
+        if mode == IngestMode::Strict && !response.errors.is_empty() {
+            self.undo_writes(&batch.source, applied).await;
+            for index in 0..batch.metrics.len() {
+                self.dedup.forget(deduplicated.keys(index));
+            }
+            response.processed = 0;
+            response.status = "rejected".to_string();
+            self.batch_rejected(&batch.source, response.error_summary());
+            return response;
+        }
+        self.registry
+            .self_metrics()
+            .ingested_metrics_total
+            .inc_by(response.processed as u64);
+
+        // Destinations don't receive what the registry rejected
+        if let Some(fanout) = fanout
+            && !written.is_empty()
+        {
+            let metrics = match accepted {
+                Some(_) => written.clone(),
+                None => std::mem::take(&mut written),
+            };
+            fanout.deliver(MetricsBatch {
+                metrics,
+                source: batch.source.clone(),
+            });
+        }
+        if let Some(accepted) = accepted {
+            accepted.append(&mut written);
+        }
+
         if !response.errors.is_empty() {
             response.status = "partial_success".to_string();
-
             if response.processed == 0 {
-                return Err(ServerError::MetricsProcessingError(
-                    "Failed to process any metrics in the batch".to_string(),
-                ));
+                self.batch_rejected(&batch.source, response.error_summary());
             }
         }
 
-        Ok(response)
+        response
+    }
+
+    /// Prepares and checks every metric before anything is written, the new
+    /// series of the batch against the memory budget together, so a strict
+    /// batch is either applied as a whole or not at all.
+    async fn prepare_strict(&self, batch: &MetricsBatch) -> Result<Vec<Metric>, Vec<MetricError>> {
+        let mut prepared = Vec::with_capacity(batch.metrics.len());
+        let mut errors = Vec::new();
+        let mut pending = PendingSeries::default();
+
+        for original in &batch.metrics {
+            let mut metric = original.clone();
+            let result = match self.prepare_metric(&batch.source, &mut metric, true).await {
+                Ok(()) => match self.registry.check_compatible(&metric).await {
+                    Ok(()) => self.registry.admit_pending(&metric, &mut pending),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => prepared.push(metric),
+                Err(e) => {
+                    self.reject_metric(&batch.source, Some(original.clone()), &e)
+                        .await;
+                    errors.push(MetricError::from(&e));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(prepared)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Undoes the writes of a strict batch, last first. A shared store only
+    /// takes back the updates adding to a series, a set being left as written.
+    async fn undo_writes(&self, source: &str, applied: Vec<(Metric, WriteUndo)>) {
+        for (metric, undo) in applied.into_iter().rev() {
+            if let Some(store) = &self.store
+                && let Some(write) = self.registry.store_write(source, &metric)
+                && let Some(update) = write.update.inverse()
+                && let Err(e) = store.write(&StoreWrite { update, ..write }).await
+            {
+                warn!(
+                    "Failed to undo a write of {} in the shared store: {}",
+                    metric.name, e
+                );
+            }
+            self.registry.undo(&metric, undo).await;
+        }
+    }
+
+    async fn reject_metric(&self, source: &str, original: Option<Metric>, e: &ServerError) {
+        error!("Failed to process metric: {}", e);
+        self.registry
+            .self_metrics()
+            .rejected_metrics_total
+            .with_label_values(&[e.reason()])
+            .inc();
+        if let Some(payload) = original.and_then(|m| serde_json::to_value(m).ok()) {
+            self.dead_letter(source, payload, e);
+        }
     }
 
     #[instrument(skip(self, metric), fields(name = %metric.name, type = ?metric.metric_type))]
-    async fn process_metric(&self, metric: Metric) -> Result<(), ServerError> {
-        match self.registry.update_metric(&metric).await {
+    async fn process_metric(&self, source: &str, mut metric: Metric) -> Result<(), ServerError> {
+        self.prepare_metric(source, &mut metric, true).await?;
+        self.write_metric(source, &metric).await
+    }
+
+    /// Writes a metric derived on the server, such as a recording rule result,
+    /// without applying the ingestion policies meant for pushed metrics.
+    pub async fn record(&self, source: &str, metric: &Metric) -> Result<(), ServerError> {
+        self.write_metric(source, metric).await
+    }
+
+    /// Writes an already prepared metric, registering its family on first use.
+    /// With a shared store the write is admitted locally, then written to the
+    /// store, so a write it refuses, and the client retries, isn't applied
+    /// locally twice, and one refused locally isn't counted by the store. A
+    /// family registered for a write that fails is unregistered again.
+    async fn write_metric(&self, source: &str, metric: &Metric) -> Result<(), ServerError> {
+        // Every sample was dropped by an ingestion policy
+        if metric.value.as_slice().is_empty() {
+            return Ok(());
+        }
+
+        let mut registered = false;
+        let written = self
+            .write_registering(source, metric, &mut registered)
+            .await;
+        if registered {
+            match &written {
+                Ok(()) if self.events.wants(LifecycleEventKind::FamilyRegistered) => {
+                    self.events.emit(LifecycleEvent::FamilyRegistered {
+                        source: source.to_string(),
+                        family: self.registry.exposed_name(metric),
+                        metric_type: metric.metric_type.clone(),
+                    });
+                }
+                Ok(()) => {}
+                Err(_) => {
+                    self.registry.discard_family(metric).await;
+                }
+            }
+        }
+        written?;
+
+        self.registry.record_source(source, metric).await;
+        self.topk.observe(metric);
+        if let Some(anomalies) = &self.anomalies {
+            let config = anomalies.config();
+            for anomaly in anomalies.observe(metric) {
+                self.notifier
+                    .notify(&config.notify, &anomaly.alert(config.severity));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `metric` of `source` to the registry and the shared store,
+    /// setting `registered` when its family was registered for it.
+    async fn write_registering(
+        &self,
+        source: &str,
+        metric: &Metric,
+        registered: &mut bool,
+    ) -> Result<(), ServerError> {
+        if let Some(store) = &self.store {
+            self.registry.check_compatible(metric).await?;
+            // The write needs the family's label keys and buckets
+            let write = match self.registry.store_write(source, metric) {
+                Some(write) => Some(write),
+                None => {
+                    *registered |= self.registry.register_metric(metric).await?;
+                    self.registry.store_write(source, metric)
+                }
+            };
+            self.registry.admit(metric)?;
+            if let Some(write) = write {
+                store.write(&write).await?;
+            }
+        }
+
+        match self.registry.update_metric(metric).await {
             Ok(_) => {
                 debug!("Updated existing metric: {}", metric.name);
-                Ok(())
             }
             Err(_) => {
                 debug!("Metric not found, attempting to register: {}", metric.name);
-                self.registry.register_metric(&metric).await?;
-
-                self.registry.update_metric(&metric).await?;
+                *registered |= self.registry.register_metric(metric).await?;
+                self.registry.update_metric(metric).await?;
                 debug!("Registered and updated new metric: {}", metric.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Shows how a batch would be ingested without writing anything: each
+    /// metric after the ingestion policies, with the name it would be exposed
+    /// under, or why it would be rejected. Sampling doesn't apply.
+    pub async fn normalize(&self, batch: &MetricsBatch) -> Result<NormalizeResponse, ServerError> {
+        batch.validate(self.config())?;
+
+        let mut response = NormalizeResponse {
+            source: batch.source.clone(),
+            metrics: Vec::with_capacity(batch.metrics.len()),
+            errors: Vec::new(),
+        };
+        for (index, original) in batch.metrics.iter().enumerate() {
+            let mut metric = original.clone();
+            let result = match self.prepare_metric(&batch.source, &mut metric, false).await {
+                Ok(()) => self.registry.check_compatible(&metric).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => response.metrics.push(NormalizedMetric {
+                    exposed_name: self.registry.exposed_name(&metric),
+                    metric,
+                }),
+                Err(e) => response.errors.push(MetricError::new(
+                    format!("metric {}: {}", index, e),
+                    e.is_retryable(),
+                )),
+            }
+        }
+        Ok(response)
+    }
+
+    /// Applies the configured ingestion policies, possibly rewriting the
+    /// metric. `record_stats` is false for dry runs, which leave the
+    /// self-metrics alone.
+    async fn prepare_metric(
+        &self,
+        source: &str,
+        metric: &mut Metric,
+        record_stats: bool,
+    ) -> Result<(), ServerError> {
+        self.registrations().check_name(source, &metric.name)?;
+        if self.apply_transforms(source, metric, record_stats).await? {
+            return Ok(());
+        }
+        // A transform may rename the metric out of the source's prefixes
+        self.registrations().check_name(source, &metric.name)?;
+        self.apply_enum_mapping(metric)?;
+        // And so may a rename rule
+        if self.apply_rename_rules(metric) {
+            self.registrations().check_name(source, &metric.name)?;
+        }
+        self.canonicalize_labels(metric, record_stats);
+        self.apply_value_rule(metric);
+        self.apply_schema(metric, record_stats)?;
+        self.apply_label_value_policy(metric)?;
+        self.apply_non_finite_policy(metric, record_stats)?;
+        self.apply_counter_policy(metric)?;
+        Ok(())
+    }
+
+    /// Runs the plugins then the scripts on a blocking thread, returning
+    /// whether one dropped the metric, which is then left without samples.
+    async fn apply_transforms(
+        &self,
+        source: &str,
+        metric: &mut Metric,
+        record_stats: bool,
+    ) -> Result<bool, ServerError> {
+        if self.plugins.is_empty() && self.scripts.is_empty() {
+            return Ok(false);
+        }
+        let (plugins, scripts) = (self.plugins.clone(), self.scripts.clone());
+        let (owned_source, mut owned) = (source.to_string(), metric.clone());
+        let (transformed, dropped_by) = tokio::task::spawn_blocking(move || {
+            let dropped_by = match plugins.apply(&owned_source, &mut owned)? {
+                Some(plugin) => Some(("plugin", plugin)),
+                None => scripts
+                    .apply(&owned_source, &mut owned)?
+                    .map(|script| ("script", script)),
+            };
+            Ok::<_, ServerError>((owned, dropped_by))
+        })
+        .await
+        .map_err(|e| ServerError::InternalError(Box::new(e)))??;
+        *metric = transformed;
+        let Some((reason, by)) = dropped_by else {
+            return Ok(false);
+        };
+        let dropped = metric.value.retain(|_| false);
+        if record_stats {
+            debug!(
+                "The {} {} dropped {} from {}",
+                reason, by, metric.name, source
+            );
+            self.registry
+                .self_metrics()
+                .dropped_samples_total
+                .with_label_values(&[reason])
+                .inc_by(dropped as u64);
+        }
+        Ok(true)
+    }
+
+    fn apply_schema(&self, metric: &Metric, record_stats: bool) -> Result<(), ServerError> {
+        let schemas = self.registry.schemas();
+        if schemas.check(metric)? == SchemaMatch::Unknown
+            && schemas.unknown_policy() == UnknownMetricPolicy::Flag
+            && record_stats
+        {
+            warn!("Metric {} has no declared schema", metric.name);
+            self.registry.self_metrics().unknown_metrics_total.inc();
+        }
+        Ok(())
+    }
+
+    /// Turns a value name pushed for a metric into a numbered gauge or a
+    /// stateset, as configured in `metrics.enums`.
+    fn apply_enum_mapping(&self, metric: &mut Metric) -> Result<(), ServerError> {
+        let samples = metric.value.as_slice();
+        if samples.iter().all(|sample| sample.enum_value.is_none()) {
+            return Ok(());
+        }
+
+        let mapping = self
+            .registry
+            .config()
+            .enums
+            .get(&metric.name)
+            .ok_or_else(|| {
+                ServerError::ValidationError(format!(
+                    "Metric '{}' has a non-numeric value but no enum mapping",
+                    metric.name
+                ))
+            })?;
+        if !matches!(metric.metric_type, MetricType::Gauge | MetricType::StateSet) {
+            return Err(ServerError::ValidationError(format!(
+                "Metric '{}' is a {:?}, only gauges and statesets take value names",
+                metric.name, metric.metric_type
+            )));
+        }
+        if mapping.kind == EnumKind::StateSet && samples.len() > 1 {
+            return Err(ServerError::ValidationError(format!(
+                "Stateset '{}' takes a single sample",
+                metric.name
+            )));
+        }
+
+        for sample in metric.value.as_mut_slice() {
+            let Some(name) = sample.enum_value.take() else {
+                continue;
+            };
+            let position = mapping
+                .values
+                .iter()
+                .position(|value| *value == name)
+                .ok_or_else(|| {
+                    ServerError::ValidationError(format!(
+                        "'{}' is not a known value of metric '{}'",
+                        name, metric.name
+                    ))
+                })?;
+
+            match mapping.kind {
+                EnumKind::Gauge => sample.value = position as f64,
+                EnumKind::StateSet => {
+                    metric.labels.insert(metric.name.clone(), name);
+                    sample.value = 1.0;
+                }
+            }
+        }
+
+        metric.metric_type = match mapping.kind {
+            EnumKind::Gauge => MetricType::Gauge,
+            EnumKind::StateSet => MetricType::StateSet,
+        };
+        Ok(())
+    }
+
+    /// Renames the metric by its rule, if it has one, returning whether it did.
+    fn apply_rename_rules(&self, metric: &mut Metric) -> bool {
+        let Some(rule) = self.rename_rules.get(&metric.name) else {
+            return false;
+        };
+        debug!("Renaming metric {} to {}", metric.name, rule.to);
+        metric.name = rule.to.clone();
+
+        for (key, value) in &rule.labels {
+            metric
+                .labels
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        true
+    }
+
+    /// Normalizes label values to NFC. Label names are sorted by the registry
+    /// already, so equivalent label sets map to one series.
+    fn canonicalize_labels(&self, metric: &mut Metric, record_stats: bool) {
+        if !self.registry.config().canonicalize_labels {
+            return;
+        }
+
+        let mut rewritten = false;
+        for value in metric.labels.values_mut() {
+            if !is_nfc(value) {
+                *value = value.nfc().collect();
+                rewritten = true;
+            }
+        }
+        if rewritten && record_stats {
+            debug!("Canonicalized label values of {}", metric.name);
+            self.registry
+                .self_metrics()
+                .canonicalized_metrics_total
+                .inc();
+        }
+    }
+
+    /// Converts the metric's values to its configured unit and precision.
+    fn apply_value_rule(&self, metric: &mut Metric) {
+        let Some(rule) = self.registry.config().value_rules.get(&metric.name) else {
+            return;
+        };
+        for sample in metric.value.as_mut_slice() {
+            sample.value = rule.apply(sample.value);
+            // Bounds are only converted, rounding could merge buckets
+            if let Some(histogram) = sample.histogram.as_mut() {
+                histogram.sum = sample.value;
+                for bucket in &mut histogram.buckets {
+                    bucket.le = rule.convert(bucket.le);
+                }
+            }
+        }
+    }
+
+    fn apply_label_value_policy(&self, metric: &mut Metric) -> Result<(), ServerError> {
+        let config = self.registry.config();
+
+        match config.label_value_policy {
+            LabelValuePolicy::Reject => {
+                validate_label_values(&metric.labels, config.max_label_value_length)
+            }
+            LabelValuePolicy::Sanitize => {
+                for value in metric.labels.values_mut() {
+                    *value = sanitize_label_value(value, config.max_label_value_length);
+                }
                 Ok(())
             }
         }
     }
 
+    fn apply_non_finite_policy(
+        &self,
+        metric: &mut Metric,
+        record_stats: bool,
+    ) -> Result<(), ServerError> {
+        match self.registry.config().non_finite_policy {
+            NonFinitePolicy::Reject => {
+                if let Some(sample) = metric
+                    .value
+                    .as_slice()
+                    .iter()
+                    .find(|s| !s.value.is_finite())
+                {
+                    return Err(ServerError::NonFiniteValue {
+                        name: metric.name.clone(),
+                        value: sample.value,
+                    });
+                }
+            }
+            NonFinitePolicy::Drop => {
+                let dropped = metric.value.retain(|sample| sample.value.is_finite());
+                if dropped > 0 && record_stats {
+                    debug!("Dropped {} non-finite samples of {}", dropped, metric.name);
+                    self.registry
+                        .self_metrics()
+                        .dropped_samples_total
+                        .with_label_values(&["non_finite"])
+                        .inc_by(dropped as u64);
+                }
+            }
+            NonFinitePolicy::Clamp => {
+                for sample in metric.value.as_mut_slice() {
+                    if sample.value.is_nan() {
+                        sample.value = 0.0;
+                    } else if !sample.value.is_finite() {
+                        sample.value = sample.value.clamp(f64::MIN, f64::MAX);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_counter_policy(&self, metric: &mut Metric) -> Result<(), ServerError> {
+        if metric.metric_type != MetricType::Counter {
+            return Ok(());
+        }
+
+        for sample in metric.value.as_mut_slice() {
+            if let Err(e) = validate_counter_increment(&metric.name, sample.value) {
+                match self.registry.config().negative_counter_policy {
+                    NegativeCounterPolicy::Reject => return Err(e),
+                    NegativeCounterPolicy::Clamp => {
+                        warn!("Clamping negative counter increment to zero: {}", e);
+                        sample.value = 0.0;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_metrics(&self) -> Result<String, ServerError> {
         self.registry.gather()
     }
 
+    pub fn gather_families(&self) -> Vec<MetricFamily> {
+        self.registry.gather_families()
+    }
+
+    /// The families served to scrapers and sinks: the shared store's when one
+    /// is set, as other replicas write to it too, the local registry's otherwise.
+    pub async fn gather_exposed(&self) -> Result<Snapshot, ServerError> {
+        let Some(store) = &self.store else {
+            return Ok(Snapshot {
+                families: self.gather_families(),
+            });
+        };
+
+        // Scrapes arriving together, e.g. from several Prometheus replicas,
+        // share one read of the store
+        let mut last = self.store_snapshot.lock().await;
+        let families = match last.as_ref() {
+            Some((read_at, families)) if read_at.elapsed() < STORE_SNAPSHOT_REUSE => {
+                families.clone()
+            }
+            _ => {
+                let families = store.gather().await?.families;
+                *last = Some((Instant::now(), families.clone()));
+                families
+            }
+        };
+        drop(last);
+        Ok(Snapshot {
+            families: self.registry.with_local_families(families),
+        })
+    }
+
+    pub fn get_metrics_utf8(&self) -> Result<String, ServerError> {
+        self.registry.gather_utf8()
+    }
+
+    /// Compacts the local registry and, when one is set, the shared store,
+    /// which is purged of the same tombstoned series and expires its own.
+    pub async fn compact(&self) -> CompactionStats {
+        let due = self.registry.tombstones().take_due(Utc::now());
+        let stats = self.registry.compact(&due).await;
+        if let Some(anomalies) = &self.anomalies {
+            anomalies.expire();
+        }
+        if let Some(drift) = &self.drift {
+            drift.expire();
+        }
+        if stats.expired_series > 0 || stats.purged_series > 0 {
+            self.series_limit_reached.store(false, Ordering::Relaxed);
+        }
+        if let Some(store) = &self.store {
+            if let Err(e) = self.purge_store(store.as_ref(), &due).await {
+                warn!(
+                    "Failed to purge tombstoned series from the shared store: {}",
+                    e
+                );
+                self.registry.tombstones().restore(due);
+            }
+            if let Some(ttl) = self.config().series_ttl_secs
+                && let Err(e) = store.expire(ttl).await
+            {
+                warn!("Failed to expire series in the shared store: {}", e);
+            }
+        }
+        stats
+    }
+
+    async fn purge_store(
+        &self,
+        store: &dyn MetricsStore,
+        due: &[Tombstone],
+    ) -> Result<(), ServerError> {
+        if due.is_empty() {
+            return Ok(());
+        }
+        for family in store.gather_series().await? {
+            let name = family.get_name();
+            for metric in family.get_metric() {
+                let labels: Vec<(String, String)> = metric
+                    .get_label()
+                    .iter()
+                    .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+                    .collect();
+                let pairs = labels
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()));
+                if due
+                    .iter()
+                    .any(|tombstone| tombstone.matches(name, pairs.clone()))
+                {
+                    store.remove(name, &labels).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The label values with the highest counts for a metric listed in `metrics.topk`.
+    pub fn topk(&self, metric: &str) -> Option<TopKReport> {
+        self.topk.report(metric)
+    }
+
+    /// Gauges currently deviating from their trend, empty unless `metrics.anomaly` is set.
+    pub fn anomalies(&self) -> Vec<Anomaly> {
+        self.anomalies
+            .as_ref()
+            .map(AnomalyDetector::anomalies)
+            .unwrap_or_default()
+    }
+
+    /// See `MetricsRegistry::cardinality`; with a shared store, counts the
+    /// series of every replica in it.
+    pub async fn cardinality(&self, limit: usize) -> Result<CardinalityReport, ServerError> {
+        let Some(store) = &self.store else {
+            return Ok(self.registry.cardinality(limit));
+        };
+        let families = store.gather_series().await?;
+        let sources = store.source_series_counts().await?;
+        Ok(cardinality_report(&families, sources, limit))
+    }
+
+    /// See `MetricsRegistry::stamp_sample_times`.
+    pub fn stamp_sample_times(&self, families: &mut [MetricFamily]) {
+        self.registry.stamp_sample_times(families)
+    }
+
+    /// The families holding series pushed by `source`, from the shared store
+    /// when one is set, or `None` if it never pushed any.
+    pub async fn gather_source_families(
+        &self,
+        source: &str,
+    ) -> Result<Option<Vec<MetricFamily>>, ServerError> {
+        let Some(store) = &self.store else {
+            return Ok(self.registry.gather_source_families(source));
+        };
+        let families = store.gather_source(source).await?;
+        Ok(families.map(|families| self.registry.expose(families)))
+    }
+
+    pub fn encode(&self, families: &[MetricFamily], utf8: bool) -> Result<String, ServerError> {
+        self.registry.encode(families, utf8)
+    }
+
+    pub fn schemas(&self) -> &SchemaRegistry {
+        self.registry.schemas()
+    }
+
+    pub fn catalog(&self) -> &MetadataCatalog {
+        self.registry.catalog()
+    }
+
+    pub fn registrations(&self) -> &SourceRegistrations {
+        self.registry.registrations()
+    }
+
+    pub fn tombstones(&self) -> &Tombstones {
+        self.registry.tombstones()
+    }
+
+    pub fn interner(&self) -> &Interner {
+        self.registry.interner()
+    }
+
+    /// Registers a source and exposes its expected push interval next to
+    /// the time of its last push, so dead sources can be alerted on.
+    pub async fn register_source(
+        &self,
+        request: SourceRequest,
+    ) -> Result<IssuedToken, ServerError> {
+        let issued = self.registrations().register(request).await?;
+        let interval = &self.registry.self_metrics().source_push_interval_seconds;
+        match issued.status.push_interval_secs {
+            Some(secs) => interval
+                .with_label_values(&[&issued.status.source])
+                .set(secs as f64),
+            None => {
+                let _ = interval.remove_label_values(&[&issued.status.source]);
+            }
+        }
+        Ok(issued)
+    }
+
+    pub async fn unregister_source(&self, source: &str) -> bool {
+        let self_metrics = self.registry.self_metrics();
+        let _ = self_metrics
+            .source_push_interval_seconds
+            .remove_label_values(&[source]);
+        let _ = self_metrics
+            .source_last_push_timestamp_seconds
+            .remove_label_values(&[source]);
+        self.registrations().remove(source).await
+    }
+
+    pub fn config(&self) -> &MetricsConfig {
+        self.registry.config()
+    }
+
     pub async fn get_metrics_count(&self) -> Result<usize, ServerError> {
-        self.registry.get_metrics_count().await
+        match &self.store {
+            Some(store) => store.family_count().await,
+            None => self.registry.get_metrics_count().await,
+        }
     }
 }
@@ -0,0 +1,44 @@
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use serde::Serialize;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::time::Duration;
+#[cfg(feature = "server")]
+use tracing::{debug, info};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CompactionStats {
+    pub expired_series: usize,
+    pub purged_series: usize,
+    pub reclaimed_families: usize,
+}
+
+/// Periodically purges tombstoned series, expires stale series when
+/// `metrics.series_ttl_secs` is set, and unregisters emptied families.
+#[cfg(feature = "server")]
+pub fn spawn_compaction(state: Arc<AppState>) {
+    let period = Duration::from_secs(state.metrics_collector.config().compaction_interval_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let stats = state.metrics_collector.compact().await;
+            if let Some(ttl) = state.metrics_collector.config().series_ttl_secs {
+                state.collectd.expire(Duration::from_secs(ttl));
+                state.prometheus_text.expire(Duration::from_secs(ttl));
+            }
+            state.jobs.expire(crate::jobs::JOB_TIMEOUT);
+            if stats == CompactionStats::default() {
+                debug!("Compaction found nothing to reclaim");
+            } else {
+                info!(
+                    "Compaction expired {} series, purged {} tombstoned series and reclaimed {} families",
+                    stats.expired_series, stats.purged_series, stats.reclaimed_families
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,207 @@
+//! A Grafana dashboard generated from the families a source pushed, ready to
+//! import: one panel per family, with the query its type calls for.
+
+use crate::utils::hex;
+use prometheus::proto::{MetricFamily, MetricType};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+/// Quantiles charted for every histogram, with their legend and query ID.
+const QUANTILES: [(&str, &str, &str); 3] = [
+    ("0.5", "p50", "A"),
+    ("0.95", "p95", "B"),
+    ("0.99", "p99", "C"),
+];
+
+/// Panels per dashboard row.
+const PANELS_PER_ROW: usize = 2;
+
+/// The dashboard for `families`, pushed by `source`. With `job`, every query
+/// is restricted to it, for a Prometheus scraping `/metrics/source/{source}`
+/// as a job of its own.
+pub fn grafana_dashboard(source: &str, families: &[MetricFamily], job: Option<&str>) -> Value {
+    let mut families: Vec<&MetricFamily> = families.iter().collect();
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+    let panels: Vec<Value> = families
+        .iter()
+        .enumerate()
+        .map(|(index, family)| panel(index, family, job))
+        .collect();
+
+    json!({
+        "uid": uid(source),
+        "title": format!("{} metrics", source),
+        "description": format!("Metrics pushed by {}, generated by rustic-insights", source),
+        "tags": ["rustic-insights", source],
+        "timezone": "browser",
+        "editable": true,
+        "schemaVersion": 39,
+        "time": { "from": "now-6h", "to": "now" },
+        "refresh": "30s",
+        "templating": {
+            "list": [{
+                "name": "datasource",
+                "label": "Data source",
+                "type": "datasource",
+                "query": "prometheus",
+            }],
+        },
+        "panels": panels,
+    })
+}
+
+/// Stable across generations and releases, so re-importing replaces the
+/// dashboard.
+fn uid(source: &str) -> String {
+    format!(
+        "ri-{}",
+        hex::encode(&Sha256::digest(source.as_bytes())[..8])
+    )
+}
+
+/// `value` as a quoted PromQL string.
+fn quote(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+fn panel(index: usize, family: &MetricFamily, job: Option<&str>) -> Value {
+    let name = family.get_name();
+    let labels = label_names(family);
+    let by = if labels.is_empty() {
+        String::new()
+    } else {
+        format!(" by ({})", labels.join(", "))
+    };
+    let legend = if labels.is_empty() {
+        name.to_string()
+    } else {
+        labels
+            .iter()
+            .map(|label| format!("{{{{{}}}}}", label))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let selector = |name: &str| match job {
+        Some(job) => format!("{}{{job={}}}", name, quote(job)),
+        None => name.to_string(),
+    };
+
+    let (targets, unit) = match family.get_field_type() {
+        MetricType::COUNTER => (
+            vec![target(
+                "A",
+                format!("sum{} (rate({}[$__rate_interval]))", by, selector(name)),
+                &legend,
+            )],
+            rate_unit(name),
+        ),
+        MetricType::HISTOGRAM => {
+            let le_by = labels
+                .iter()
+                .map(String::as_str)
+                .chain(["le"])
+                .collect::<Vec<_>>()
+                .join(", ");
+            let targets = QUANTILES
+                .iter()
+                .map(|(quantile, percentile, ref_id)| {
+                    target(
+                        ref_id,
+                        format!(
+                            "histogram_quantile({}, sum by ({}) (rate({}[$__rate_interval])))",
+                            quantile,
+                            le_by,
+                            selector(&format!("{}_bucket", name))
+                        ),
+                        &format!("{} {}", percentile, legend),
+                    )
+                })
+                .collect();
+            (targets, value_unit(name))
+        }
+        MetricType::SUMMARY => (
+            vec![target(
+                "A",
+                format!(
+                    "sum{} (rate({}[$__rate_interval])) / sum{} (rate({}[$__rate_interval]))",
+                    by,
+                    selector(&format!("{}_sum", name)),
+                    by,
+                    selector(&format!("{}_count", name))
+                ),
+                &format!("mean {}", legend),
+            )],
+            value_unit(name),
+        ),
+        MetricType::GAUGE | MetricType::UNTYPED => {
+            (vec![target("A", selector(name), &legend)], value_unit(name))
+        }
+    };
+
+    json!({
+        "id": index + 1,
+        "type": "timeseries",
+        "title": name,
+        "description": family.get_help(),
+        "datasource": { "type": "prometheus", "uid": "${datasource}" },
+        "gridPos": {
+            "x": (index % PANELS_PER_ROW) * 12,
+            "y": (index / PANELS_PER_ROW) * 8,
+            "w": 12,
+            "h": 8,
+        },
+        "fieldConfig": { "defaults": { "unit": unit }, "overrides": [] },
+        "options": { "legend": { "displayMode": "list", "placement": "bottom" } },
+        "targets": targets,
+    })
+}
+
+fn target(ref_id: &str, expr: String, legend: &str) -> Value {
+    json!({
+        "refId": ref_id,
+        "datasource": { "type": "prometheus", "uid": "${datasource}" },
+        "expr": expr,
+        "legendFormat": legend,
+    })
+}
+
+/// The label names of the family's series, sorted.
+fn label_names(family: &MetricFamily) -> Vec<String> {
+    let names: BTreeSet<&str> = family
+        .get_metric()
+        .iter()
+        .flat_map(|metric| metric.get_label().iter().map(|pair| pair.get_name()))
+        .collect();
+    names.into_iter().map(str::to_string).collect()
+}
+
+/// The Grafana unit of a value, from the base unit suffix of its name.
+fn value_unit(name: &str) -> &'static str {
+    let name = name.strip_suffix("_total").unwrap_or(name);
+    if name.ends_with("_seconds") {
+        "s"
+    } else if name.ends_with("_bytes") {
+        "bytes"
+    } else if name.ends_with("_ratio") {
+        "percentunit"
+    } else {
+        "short"
+    }
+}
+
+/// The Grafana unit of a counter's per-second rate.
+fn rate_unit(name: &str) -> &'static str {
+    match value_unit(name) {
+        "bytes" => "Bps",
+        "s" => "s",
+        _ => "ops",
+    }
+}
@@ -0,0 +1,179 @@
+use crate::metrics::types::{GaugeOperation, Metric, MetricType, MetricValue};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Samples remembered before the expired ones are swept.
+const MIN_SWEEP_LEN: usize = 1_024;
+
+/// Drops the samples a source pushes again within its `metrics.dedup_window_secs`:
+/// same name, labels, value and timestamp. Only timestamped samples setting a
+/// value are deduplicated: the same counter or gauge increment pushed twice,
+/// or a histogram pushed already bucketed, may well be two increments.
+pub struct Deduplicator {
+    window: Option<Duration>,
+    source_windows: HashMap<String, Duration>,
+    seen: Mutex<Seen>,
+}
+
+struct Seen {
+    /// When each remembered sample stops counting as a duplicate, by key.
+    expiries: HashMap<u128, Instant>,
+    sweep_at_len: usize,
+}
+
+impl Deduplicator {
+    pub fn new(window_secs: Option<u64>, source_window_secs: &HashMap<String, u64>) -> Self {
+        Self {
+            window: window_secs.map(Duration::from_secs),
+            source_windows: source_window_secs
+                .iter()
+                .map(|(source, secs)| (source.clone(), Duration::from_secs(*secs)))
+                .collect(),
+            seen: Mutex::new(Seen {
+                expiries: HashMap::new(),
+                sweep_at_len: MIN_SWEEP_LEN,
+            }),
+        }
+    }
+
+    fn window(&self, source: &str) -> Option<Duration> {
+        self.source_windows
+            .get(source)
+            .copied()
+            .or(self.window)
+            .filter(|window| !window.is_zero())
+    }
+
+    /// Drops the samples of `metrics` already pushed for `source` within its
+    /// window, or repeated within the batch, and the metrics left without any.
+    /// The samples kept are remembered in the same step, so concurrent resends
+    /// can't both get through; `forget` releases those of a metric whose write
+    /// failed, so a retried write isn't dropped.
+    pub fn deduplicate(&self, source: &str, metrics: &mut Vec<Metric>) -> Deduplicated {
+        let mut deduplicated = Deduplicated::default();
+        let Some(window) = self.window(source) else {
+            return deduplicated;
+        };
+        let Ok(mut seen) = self.seen.lock() else {
+            return deduplicated;
+        };
+        let now = Instant::now();
+        let expiry = now + window;
+        if seen.expiries.len() >= seen.sweep_at_len {
+            seen.expiries.retain(|_, expiry| *expiry > now);
+            seen.sweep_at_len = (seen.expiries.len() * 2).max(MIN_SWEEP_LEN);
+        }
+
+        let mut batch = HashSet::new();
+        metrics.retain_mut(|metric| {
+            let mut keys = Vec::new();
+            let removed = if sets_value(metric) {
+                let labels = sorted_labels(&metric.labels);
+                metric.value.retain(|sample| {
+                    let Some(timestamp) = sample.timestamp else {
+                        return true;
+                    };
+                    let key = sample_key(source, &metric.name, &labels, sample, timestamp);
+
+                    let written = seen.expiries.get(&key).is_some_and(|expiry| *expiry > now);
+                    if written || !batch.insert(key) {
+                        return false;
+                    }
+                    seen.expiries.insert(key, expiry);
+                    keys.push(key);
+                    true
+                })
+            } else {
+                0
+            };
+            deduplicated.dropped += removed;
+            let kept = removed == 0 || !metric.value.as_slice().is_empty();
+            if kept {
+                deduplicated.keys.push(keys);
+            }
+            kept
+        });
+        deduplicated
+    }
+
+    /// Forgets the samples of a metric that wasn't written, with the keys
+    /// `deduplicate` returned for it.
+    pub fn forget(&self, keys: &[u128]) {
+        if keys.is_empty() {
+            return;
+        }
+        let Ok(mut seen) = self.seen.lock() else {
+            return;
+        };
+        for key in keys {
+            seen.expiries.remove(key);
+        }
+    }
+}
+
+/// What `Deduplicator::deduplicate` dropped, and the keys of the samples of
+/// each metric it kept, in order.
+#[derive(Debug, Default)]
+pub struct Deduplicated {
+    pub dropped: usize,
+    keys: Vec<Vec<u128>>,
+}
+
+impl Deduplicated {
+    /// The keys to forget if the `index`th metric kept isn't written.
+    pub fn keys(&self, index: usize) -> &[u128] {
+        self.keys.get(index).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+/// Whether pushing the metric twice sets the same value, rather than adding
+/// it twice.
+fn sets_value(metric: &Metric) -> bool {
+    match metric.metric_type {
+        MetricType::Counter | MetricType::Histogram | MetricType::Timer => false,
+        MetricType::Gauge => metric.operation == GaugeOperation::Set,
+        MetricType::Info | MetricType::StateSet | MetricType::Summary => true,
+    }
+}
+
+/// The first 128 bits of a SHA-256 over the sample and what it's a sample
+/// of, each field prefixed by its length so they can't run into each other.
+fn sample_key(
+    source: &str,
+    name: &str,
+    labels: &[(&str, &str)],
+    sample: &MetricValue,
+    timestamp: i64,
+) -> u128 {
+    fn field(hasher: &mut Sha256, bytes: &[u8]) {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    }
+
+    let mut hasher = Sha256::new();
+    field(&mut hasher, source.as_bytes());
+    field(&mut hasher, name.as_bytes());
+    for (label, value) in labels {
+        field(&mut hasher, label.as_bytes());
+        field(&mut hasher, value.as_bytes());
+    }
+    field(&mut hasher, &sample.value.to_bits().to_le_bytes());
+    field(&mut hasher, &timestamp.to_le_bytes());
+    match &sample.enum_value {
+        Some(state) => field(&mut hasher, state.as_bytes()),
+        None => hasher.update([0xff]),
+    }
+    let digest = hasher.finalize();
+    u128::from_le_bytes(digest[..16].try_into().expect("SHA-256 is 32 bytes"))
+}
+
+fn sorted_labels(labels: &HashMap<String, String>) -> Vec<(&str, &str)> {
+    let mut labels: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    labels.sort_unstable();
+    labels
+}
@@ -0,0 +1,184 @@
+use crate::config::SchemaDriftConfig;
+use crate::metrics::types::Metric;
+use crate::notify::{Alert, Severity};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Instant;
+use tracing::warn;
+
+/// A source pushing a metric with other label names than it did before.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SchemaDrift {
+    pub source: String,
+    pub metric: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub detected_at: DateTime<Utc>,
+}
+
+impl SchemaDrift {
+    pub fn alert(&self, severity: Severity) -> Alert {
+        let labels = [("source", &self.source), ("metric", &self.metric)]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        let values = [
+            ("added", self.added.join(",")),
+            ("removed", self.removed.join(",")),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+
+        Alert {
+            rule: "schema_drift".to_string(),
+            severity,
+            summary: format!(
+                "{} changed the labels of {}: added [{}], removed [{}]",
+                self.source,
+                self.metric,
+                self.added.join(", "),
+                self.removed.join(", ")
+            ),
+            labels,
+            values,
+        }
+    }
+}
+
+/// What a source pushed a metric with: the label names it settled on, and
+/// the others it pushed since, each with when it was last pushed, in
+/// seconds since the detector started.
+struct Schemas {
+    baseline: BTreeSet<String>,
+    baseline_seen: AtomicU64,
+    variants: HashMap<BTreeSet<String>, AtomicU64>,
+}
+
+/// Remembers the label names each source pushes every metric with, as
+/// pushed, and reports when they change: usually a bad deploy upstream.
+/// Changes are reported against the baseline, the label names first pushed,
+/// once for each other set of names, so a source alternating between two
+/// sets is reported once. A baseline not pushed within `state_ttl_secs` is
+/// replaced by the most recently pushed other names, and `expire` forgets
+/// the names not pushed since.
+pub struct DriftDetector {
+    config: SchemaDriftConfig,
+    started: Instant,
+    // (source, metric name) -> label names
+    schemas: RwLock<HashMap<(String, String), Schemas>>,
+}
+
+impl DriftDetector {
+    pub fn new(config: SchemaDriftConfig) -> Self {
+        Self {
+            config,
+            started: Instant::now(),
+            schemas: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &SchemaDriftConfig {
+        &self.config
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Checks the label names of a pushed metric against its source's
+    /// baseline, returning the drift if they differ in a way not reported yet.
+    pub fn observe(&self, source: &str, metric: &Metric) -> Option<SchemaDrift> {
+        let now = self.now();
+        let same = |known: &BTreeSet<String>| {
+            known.len() == metric.labels.len()
+                && metric.labels.keys().all(|name| known.contains(name))
+        };
+        let key = (source.to_string(), metric.name.clone());
+
+        if let Ok(schemas) = self.schemas.read()
+            && let Some(known) = schemas.get(&key)
+        {
+            if same(&known.baseline) {
+                known.baseline_seen.store(now, Ordering::Relaxed);
+                return None;
+            }
+            if let Some(seen) = known.variants.iter().find(|(names, _)| same(names)) {
+                seen.1.store(now, Ordering::Relaxed);
+                return None;
+            }
+        }
+
+        let mut schemas = self.schemas.write().ok()?;
+        let names: BTreeSet<String> = metric.labels.keys().cloned().collect();
+        let known = match schemas.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(Schemas {
+                    baseline: names,
+                    baseline_seen: AtomicU64::new(now),
+                    variants: HashMap::new(),
+                });
+                return None;
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+        };
+        // Raced with another push of the same names
+        if known.baseline == names || known.variants.contains_key(&names) {
+            return None;
+        }
+
+        let drift = SchemaDrift {
+            source: source.to_string(),
+            metric: metric.name.clone(),
+            added: names.difference(&known.baseline).cloned().collect(),
+            removed: known.baseline.difference(&names).cloned().collect(),
+            detected_at: Utc::now(),
+        };
+        known.variants.insert(names, AtomicU64::new(now));
+        warn!(
+            "Source {} changed the labels of {}: added {:?}, removed {:?}",
+            drift.source, drift.metric, drift.added, drift.removed
+        );
+        Some(drift)
+    }
+
+    /// Forgets the label names not pushed within `state_ttl_secs`, replacing
+    /// a stale baseline with the most recently pushed other names, and
+    /// returns how many metrics of a source were forgotten altogether. Run on
+    /// the compaction timer.
+    pub fn expire(&self) -> usize {
+        let Ok(mut schemas) = self.schemas.write() else {
+            return 0;
+        };
+        let now = self.now();
+        let fresh = |seen: &AtomicU64| {
+            now.saturating_sub(seen.load(Ordering::Relaxed)) < self.config.state_ttl_secs
+        };
+
+        let before = schemas.len();
+        schemas.retain(|_, known| {
+            known.variants.retain(|_, seen| fresh(seen));
+            if fresh(&known.baseline_seen) {
+                return true;
+            }
+            let latest = known
+                .variants
+                .iter()
+                .max_by_key(|(_, seen)| seen.load(Ordering::Relaxed))
+                .map(|(names, _)| names.clone());
+            let Some(latest) = latest else {
+                return false;
+            };
+            if let Some(seen) = known.variants.remove(&latest) {
+                known.baseline = latest;
+                known.baseline_seen = seen;
+            }
+            true
+        });
+        before - schemas.len()
+    }
+}
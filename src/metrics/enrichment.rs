@@ -0,0 +1,225 @@
+use crate::config::{EnrichmentConfig, EnrichmentProvider};
+use crate::errors::ServerError;
+use crate::metrics::sources::{SeriesKey, SourceIndex};
+use prometheus::proto::{LabelPair, MetricFamily};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Key label value, or source -> extra labels to attach to its series.
+pub type EnrichmentTable = HashMap<String, HashMap<String, String>>;
+
+/// Merges labels looked up from external metadata into exposed series at
+/// scrape time. The lookup table is refreshed periodically from the
+/// configured provider; series never store the extra labels themselves.
+pub struct LabelEnricher {
+    config: EnrichmentConfig,
+    table: RwLock<EnrichmentTable>,
+    client: reqwest::Client,
+}
+
+impl LabelEnricher {
+    pub fn new(config: EnrichmentConfig) -> Self {
+        Self {
+            config,
+            table: RwLock::new(HashMap::new()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn set_table(&self, table: EnrichmentTable) {
+        if let Ok(mut current) = self.table.write() {
+            *current = table;
+        }
+    }
+
+    pub async fn refresh(&self) -> Result<(), ServerError> {
+        let table = match &self.config.provider {
+            EnrichmentProvider::File { path } => {
+                let content = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+                serde_json::from_str(&content)?
+            }
+            EnrichmentProvider::Http { url } => self
+                .client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?
+                .json()
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?,
+            EnrichmentProvider::Kubernetes { namespace } => {
+                fetch_pod_labels(namespace.as_deref()).await?
+            }
+        };
+
+        debug!("Loaded enrichment metadata for {} keys", table.len());
+        self.set_table(table);
+        Ok(())
+    }
+
+    /// Refreshes the table on the configured interval for the lifetime of the process.
+    pub fn spawn_refresh(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.refresh_interval_secs));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.refresh().await {
+                    warn!("Failed to refresh enrichment metadata: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Adds the labels looked up for each series of `families`, by its key
+    /// label or by the sources `sources` says pushed it.
+    pub fn enrich(&self, families: &mut [MetricFamily], sources: &SourceIndex) {
+        let Ok(table) = self.table.read() else {
+            return;
+        };
+        if table.is_empty() {
+            return;
+        }
+        let owners = match self.config.by_source {
+            true => sources.sources_by_series(),
+            false => HashMap::new(),
+        };
+
+        for family in families.iter_mut() {
+            let owned = owners.get(family.get_name());
+            for metric in family.mut_metric().iter_mut() {
+                let extras: Vec<&HashMap<String, String>> = match owned {
+                    Some(owned) => {
+                        let key: SeriesKey = metric
+                            .get_label()
+                            .iter()
+                            .map(|pair| (Arc::from(pair.get_name()), Arc::from(pair.get_value())))
+                            .collect();
+                        owned
+                            .get(&key)
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|source| table.get(source))
+                            .collect()
+                    }
+                    None if self.config.by_source => continue,
+                    None => metric
+                        .get_label()
+                        .iter()
+                        .find(|pair| pair.get_name() == self.config.key_label)
+                        .and_then(|pair| table.get(pair.get_value()))
+                        .into_iter()
+                        .collect(),
+                };
+                if extras.is_empty() {
+                    continue;
+                }
+
+                let mut labels: Vec<LabelPair> = metric.take_label().into_iter().collect();
+                for (name, value) in extras.into_iter().flatten() {
+                    // Labels pushed by the client always win over enrichment,
+                    // and the first source's over those of the others
+                    if labels.iter().any(|pair| pair.get_name() == name) {
+                        continue;
+                    }
+                    let mut pair = LabelPair::new();
+                    pair.set_name(name.clone());
+                    pair.set_value(value.clone());
+                    labels.push(pair);
+                }
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                metric.set_label(labels.into());
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    metadata: PodMetadata,
+}
+
+#[derive(Deserialize)]
+struct PodMetadata {
+    name: String,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+/// Lists pods through the in-cluster API server using the mounted service
+/// account and keys their labels by pod name.
+async fn fetch_pod_labels(namespace: Option<&str>) -> Result<EnrichmentTable, ServerError> {
+    let read = |file: &str| {
+        std::fs::read(format!("{}/{}", SERVICE_ACCOUNT_DIR, file))
+            .map_err(|e| ServerError::InternalError(Box::new(e)))
+    };
+
+    let token = String::from_utf8_lossy(&read("token")?).trim().to_string();
+    let namespace = match namespace {
+        Some(namespace) => namespace.to_string(),
+        None => String::from_utf8_lossy(&read("namespace")?)
+            .trim()
+            .to_string(),
+    };
+    let ca = reqwest::Certificate::from_pem(&read("ca.crt")?)
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+    let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+        ServerError::ConfigurationError("KUBERNETES_SERVICE_HOST is not set".to_string())
+    })?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .build()
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+    let pods: PodList = client
+        .get(format!(
+            "https://{}:{}/api/v1/namespaces/{}/pods",
+            host, port, namespace
+        ))
+        .bearer_auth(token)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?
+        .json()
+        .await
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+    Ok(pods
+        .items
+        .into_iter()
+        .map(|pod| {
+            let labels = pod
+                .metadata
+                .labels
+                .into_iter()
+                .map(|(name, value)| (sanitize_label_name(&name), value))
+                .collect();
+            (pod.metadata.name, labels)
+        })
+        .collect())
+}
+
+/// Kubernetes label keys such as `app.kubernetes.io/name` aren't valid
+/// Prometheus label names, so every invalid character becomes `_`.
+fn sanitize_label_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,302 @@
+//! The sample history as CSV or Parquet, one row per sample with a column
+//! per label name, so it loads straight into a dataframe.
+
+use crate::errors::ServerError;
+use crate::metrics::history::{Snapshot, label_names};
+#[cfg(any(feature = "csv", feature = "parquet"))]
+use crate::sinks::Sample;
+use chrono::DateTime;
+use serde::Deserialize;
+use std::io::{self, Write};
+use tokio::sync::mpsc;
+
+/// Columns every row has; labels of these names get a `label_` prefix.
+const FIXED_COLUMNS: [&str; 3] = ["timestamp", "name", "value"];
+
+/// Bytes buffered before a chunk of the response is sent.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Only with the `csv` feature.
+    #[default]
+    Csv,
+    /// Only with the `parquet` feature.
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// The column names of the rows, the label ones after the fixed ones.
+pub fn columns(labels: &[String]) -> Vec<String> {
+    FIXED_COLUMNS
+        .iter()
+        .map(|column| column.to_string())
+        .chain(labels.iter().map(|label| {
+            if FIXED_COLUMNS.contains(&label.as_str()) {
+                format!("label_{}", label)
+            } else {
+                label.clone()
+            }
+        }))
+        .collect()
+}
+
+/// Writes the samples named `name` (all of them without it) in `snapshots`
+/// to `writer` in `format`.
+#[cfg_attr(
+    not(any(feature = "csv", feature = "parquet")),
+    allow(unused_variables)
+)]
+pub fn write_history<W: Write + Send>(
+    format: ExportFormat,
+    snapshots: &[Snapshot],
+    name: Option<&str>,
+    writer: W,
+) -> Result<(), ServerError> {
+    let labels = label_names(snapshots, name);
+    let rows = snapshots.iter().flat_map(|snapshot| {
+        snapshot.matching(name).map(move |sample| {
+            // Samples pushed with a timestamp are exported as of it
+            let timestamp = sample
+                .timestamp_ms
+                .and_then(DateTime::from_timestamp_millis)
+                .unwrap_or(snapshot.timestamp);
+            (timestamp, sample)
+        })
+    });
+    match format {
+        #[cfg(feature = "csv")]
+        ExportFormat::Csv => csv_format::write_csv(rows, &labels, writer),
+        #[cfg(not(feature = "csv"))]
+        ExportFormat::Csv => Err(ServerError::ValidationError(
+            "CSV export requires the `csv` feature".to_string(),
+        )),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => parquet_format::write_parquet(rows, &labels, writer),
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => Err(ServerError::ValidationError(
+            "Parquet export requires the `parquet` feature".to_string(),
+        )),
+    }
+}
+
+#[cfg(any(feature = "csv", feature = "parquet"))]
+fn label_value<'a>(sample: &'a Sample, label: &str) -> Option<&'a str> {
+    sample
+        .labels
+        .iter()
+        .find(|(name, _)| name == label)
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(feature = "csv")]
+mod csv_format {
+    use super::{columns, label_value};
+    use crate::errors::ServerError;
+    use crate::sinks::Sample;
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use std::io::Write;
+
+    pub(super) fn write_csv<'a, W: Write>(
+        rows: impl Iterator<Item = (DateTime<Utc>, &'a Sample)>,
+        labels: &[String],
+        writer: W,
+    ) -> Result<(), ServerError> {
+        let mut csv = csv::Writer::from_writer(writer);
+        let fail = |e: csv::Error| ServerError::InternalError(Box::new(e));
+
+        csv.write_record(columns(labels)).map_err(fail)?;
+        for (timestamp, sample) in rows {
+            let fixed = [
+                timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+                sample.name.clone(),
+                sample.value.to_string(),
+            ];
+            let labels = labels
+                .iter()
+                .map(|label| label_value(sample, label).unwrap_or_default().to_string());
+            csv.write_record(fixed.into_iter().chain(labels))
+                .map_err(fail)?;
+        }
+        csv.flush()
+            .map_err(|e| ServerError::InternalError(Box::new(e)))
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_format {
+    use super::{columns, label_value};
+    use crate::errors::ServerError;
+    use crate::sinks::Sample;
+    use chrono::{DateTime, Utc};
+    use parquet::basic::{LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+    use parquet::errors::ParquetError;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::format::MilliSeconds;
+    use parquet::schema::types::Type;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    /// Rows per row group, bounding what is buffered before it is written.
+    const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+    fn fail(e: ParquetError) -> ServerError {
+        ServerError::InternalError(Box::new(e))
+    }
+
+    fn schema(labels: &[String]) -> Result<Type, ParquetError> {
+        let names = columns(labels);
+        let mut fields = vec![
+            Type::primitive_type_builder(&names[0], PhysicalType::INT64)
+                .with_repetition(Repetition::REQUIRED)
+                .with_logical_type(Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: true,
+                    unit: TimeUnit::MILLIS(MilliSeconds {}),
+                }))
+                .build()?,
+            Type::primitive_type_builder(&names[1], PhysicalType::BYTE_ARRAY)
+                .with_repetition(Repetition::REQUIRED)
+                .with_logical_type(Some(LogicalType::String))
+                .build()?,
+            Type::primitive_type_builder(&names[2], PhysicalType::DOUBLE)
+                .with_repetition(Repetition::REQUIRED)
+                .build()?,
+        ];
+        for name in &names[3..] {
+            fields.push(
+                Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(Some(LogicalType::String))
+                    .build()?,
+            );
+        }
+        Type::group_type_builder("sample")
+            .with_fields(fields.into_iter().map(Arc::new).collect())
+            .build()
+    }
+
+    pub(super) fn write_parquet<'a, W: Write + Send>(
+        rows: impl Iterator<Item = (DateTime<Utc>, &'a Sample)>,
+        labels: &[String],
+        writer: W,
+    ) -> Result<(), ServerError> {
+        let schema = Arc::new(schema(labels).map_err(fail)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let mut file = SerializedFileWriter::new(writer, schema, properties).map_err(fail)?;
+
+        let mut rows = rows.peekable();
+        while rows.peek().is_some() {
+            let group: Vec<_> = rows.by_ref().take(ROW_GROUP_SIZE).collect();
+            let mut row_group = file.next_row_group().map_err(fail)?;
+
+            let timestamps: Vec<i64> = group.iter().map(|(t, _)| t.timestamp_millis()).collect();
+            let names: Vec<ByteArray> = group
+                .iter()
+                .map(|(_, sample)| ByteArray::from(sample.name.as_str()))
+                .collect();
+            let values: Vec<f64> = group.iter().map(|(_, sample)| sample.value).collect();
+
+            let mut column = 0;
+            while let Some(mut writer) = row_group.next_column().map_err(fail)? {
+                match column {
+                    0 => writer
+                        .typed::<Int64Type>()
+                        .write_batch(&timestamps, None, None),
+                    1 => writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(&names, None, None),
+                    2 => writer
+                        .typed::<DoubleType>()
+                        .write_batch(&values, None, None),
+                    _ => {
+                        let label = &labels[column - 3];
+                        let mut present = Vec::new();
+                        let levels: Vec<i16> = group
+                            .iter()
+                            .map(|(_, sample)| match label_value(sample, label) {
+                                Some(value) => {
+                                    present.push(ByteArray::from(value));
+                                    1
+                                }
+                                None => 0,
+                            })
+                            .collect();
+                        writer
+                            .typed::<ByteArrayType>()
+                            .write_batch(&present, Some(&levels), None)
+                    }
+                }
+                .map_err(fail)?;
+                writer.close().map_err(fail)?;
+                column += 1;
+            }
+            row_group.close().map_err(fail)?;
+        }
+        file.close().map_err(fail)?;
+        Ok(())
+    }
+}
+
+/// A `Write` sending what is written as chunks of a streamed response.
+pub struct ChunkWriter {
+    sender: mpsc::Sender<Result<Vec<u8>, ServerError>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    pub fn new(sender: mpsc::Sender<Result<Vec<u8>, ServerError>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Ends the response with `error` rather than a truncated body that
+    /// looks complete. Must be called from a blocking thread.
+    pub fn fail(self, error: ServerError) {
+        let _ = self.sender.blocking_send(Err(error));
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client went away"))
+    }
+}
+
+/// Sends blocking, so it must be written to from a blocking thread.
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
@@ -0,0 +1,120 @@
+use crate::utils::validation::is_legacy_metric_name;
+use prometheus::proto::{LabelPair, MetricFamily};
+use std::collections::HashMap;
+
+const SAMPLE_SUFFIXES: [&str; 4] = ["_bucket", "_sum", "_count", "_total"];
+
+/// Escapes a UTF-8 metric name with the Prometheus "values" escaping scheme, so it
+/// can be registered with the legacy-only prometheus crate and still be decoded by
+/// scrapers that don't negotiate UTF-8 names.
+///
+/// Legacy-valid names are returned unchanged; otherwise the result is `U__`
+/// followed by the name where `_` becomes `__` and every other invalid
+/// character becomes `_<hex code point>_`, e.g. `http.requests` becomes
+/// `U__http_2e_requests`.
+pub fn escape_metric_name(name: &str) -> String {
+    if is_legacy_metric_name(name) {
+        return name.to_string();
+    }
+
+    let mut escaped = String::from("U__");
+    for (i, c) in name.chars().enumerate() {
+        if c == '_' {
+            escaped.push_str("__");
+        } else if c.is_ascii_alphabetic() || c == ':' || (i > 0 && c.is_ascii_digit()) {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("_{:x}_", c as u32));
+        }
+    }
+
+    escaped
+}
+
+/// Rewrites an escaped text exposition so the families listed in `names`
+/// (escaped name -> original UTF-8 name) use the quoted syntax of the
+/// Prometheus 1.0.0 text format, e.g. `{"http.requests",code="200"} 1`.
+pub fn quote_utf8_names(exposition: &str, names: &HashMap<String, String>) -> String {
+    if names.is_empty() {
+        return exposition.to_string();
+    }
+
+    let mut quoted = String::with_capacity(exposition.len());
+    for line in exposition.lines() {
+        quoted.push_str(&quote_line(line, names));
+        quoted.push('\n');
+    }
+
+    quoted
+}
+
+fn quote_line(line: &str, names: &HashMap<String, String>) -> String {
+    for directive in ["# HELP ", "# TYPE "] {
+        if let Some(rest) = line.strip_prefix(directive) {
+            let (name, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            return match names.get(name) {
+                Some(original) => format!("{}{} {}", directive, quote(original), tail),
+                None => line.to_string(),
+            };
+        }
+    }
+
+    if line.starts_with('#') {
+        return line.to_string();
+    }
+
+    let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+    let (name, rest) = line.split_at(name_end);
+
+    let Some(original) = original_sample_name(name, names) else {
+        return line.to_string();
+    };
+
+    match rest.strip_prefix('{') {
+        Some(labels) if labels.starts_with('}') => format!("{{{}{}", quote(&original), labels),
+        Some(labels) => format!("{{{},{}", quote(&original), labels),
+        None => format!("{{{}}}{}", quote(&original), rest),
+    }
+}
+
+fn original_sample_name(name: &str, names: &HashMap<String, String>) -> Option<String> {
+    if let Some(original) = names.get(name) {
+        return Some(original.clone());
+    }
+
+    SAMPLE_SUFFIXES.iter().find_map(|suffix| {
+        let family = name.strip_suffix(suffix)?;
+        names
+            .get(family)
+            .map(|original| format!("{}{}", original, suffix))
+    })
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Adds `labels` to every series, like Prometheus external labels: a label
+/// the series already has keeps its own value.
+pub fn attach_labels(families: &mut [MetricFamily], labels: &HashMap<String, String>) {
+    if labels.is_empty() {
+        return;
+    }
+
+    for family in families.iter_mut() {
+        for metric in family.mut_metric().iter_mut() {
+            let mut pairs: Vec<LabelPair> = metric.take_label().into_iter().collect();
+            for (name, value) in labels {
+                if pairs.iter().any(|pair| pair.get_name() == name) {
+                    continue;
+                }
+                let mut pair = LabelPair::new();
+                pair.set_name(name.clone());
+                pair.set_value(value.clone());
+                pairs.push(pair);
+            }
+            pairs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+            metric.set_label(pairs.into());
+        }
+    }
+}
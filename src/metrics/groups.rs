@@ -0,0 +1,53 @@
+//! Named subsets of the exposition served at `/metrics/group/{name}`, so
+//! scrapers with different purposes, such as billing and infrastructure,
+//! each pull only the families they need.
+
+use crate::config::ExpositionGroupConfig;
+use crate::errors::ServerError;
+use prometheus::proto::MetricFamily;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// A family name pattern, anchored at both ends like Prometheus regexes.
+pub fn compile_pattern(pattern: &str) -> Result<Regex, ServerError> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| ServerError::ValidationError(format!("Invalid pattern '{}': {}", pattern, e)))
+}
+
+#[derive(Debug, Default)]
+pub struct ExpositionGroups {
+    groups: HashMap<String, Vec<Regex>>,
+}
+
+impl ExpositionGroups {
+    pub fn new(configs: &[ExpositionGroupConfig]) -> Result<Self, ServerError> {
+        let groups = configs
+            .iter()
+            .map(|group| {
+                let patterns = group
+                    .metrics
+                    .iter()
+                    .map(|pattern| compile_pattern(pattern))
+                    .collect::<Result<_, _>>()?;
+                Ok((group.name.clone(), patterns))
+            })
+            .collect::<Result<_, ServerError>>()?;
+        Ok(Self { groups })
+    }
+
+    /// The families of `name`, those whose exposed name matches one of its
+    /// patterns, or `None` for an unknown group.
+    pub fn filter(&self, name: &str, families: Vec<MetricFamily>) -> Option<Vec<MetricFamily>> {
+        let patterns = self.groups.get(name)?;
+        Some(
+            families
+                .into_iter()
+                .filter(|family| {
+                    patterns
+                        .iter()
+                        .any(|pattern| pattern.is_match(family.get_name()))
+                })
+                .collect(),
+        )
+    }
+}
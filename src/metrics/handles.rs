@@ -0,0 +1,200 @@
+//! Handles on the series written to each family, found by a hash of the
+//! pushed label values, so updating a known series neither allocates nor goes
+//! through `with_label_values`.
+
+use crate::metrics::interner::SeriesValues;
+use prometheus::{Counter, Gauge};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+/// The hash of a series' label values, in its family's label order.
+pub fn series_hash<'a>(values: impl IntoIterator<Item = &'a str>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for value in values {
+        value.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+pub enum SeriesMetric {
+    Counter(Counter),
+    Gauge(Gauge),
+    /// Histograms are updated through their family by label values.
+    Histogram,
+}
+
+/// A series admitted to the memory budget.
+pub struct SeriesHandle {
+    pub values: SeriesValues,
+    pub metric: SeriesMetric,
+    /// Its estimated memory, given back to the budget when it is removed.
+    pub bytes: u64,
+    // The newest timestamp of the samples written to it, in Unix
+    // milliseconds, `i64::MIN` if none carried one
+    sample_ms: AtomicI64,
+}
+
+impl SeriesHandle {
+    pub fn new(values: SeriesValues, metric: SeriesMetric, bytes: u64) -> Self {
+        Self {
+            values,
+            metric,
+            bytes,
+            sample_ms: AtomicI64::new(i64::MIN),
+        }
+    }
+
+    /// Records the timestamp of a sample written; a late one doesn't make
+    /// the series older.
+    pub fn record_sample_ms(&self, ms: i64) {
+        self.sample_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    /// The newest timestamp of the samples written, if any carried one.
+    pub fn sample_ms(&self) -> Option<i64> {
+        Some(self.sample_ms.load(Ordering::Relaxed)).filter(|ms| *ms != i64::MIN)
+    }
+}
+
+/// The handles of a family's series, by `series_hash`. Writes through a
+/// handle and the removal of its series take the same lock, so no write lands
+/// in a series no longer exported.
+#[derive(Default)]
+pub struct SeriesHandles {
+    handles: RwLock<HashMap<u64, Arc<SeriesHandle>>>,
+    // For statesets, the hash of the label values but the state -> the
+    // series of the state currently set
+    states: Mutex<HashMap<u64, Arc<SeriesHandle>>>,
+}
+
+impl SeriesHandles {
+    /// Applies `write` to the series with `values`, hashing to `hash`, with
+    /// the handle `resolve` creates on its first write.
+    pub fn write<'a, E>(
+        &self,
+        hash: u64,
+        values: impl IntoIterator<Item = &'a str> + Clone,
+        resolve: impl FnOnce() -> Result<SeriesHandle, E>,
+        write: impl FnOnce(&Arc<SeriesHandle>),
+    ) -> Result<Arc<SeriesHandle>, E> {
+        {
+            let handles = self.handles.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(handle) = lookup(&handles, hash, values.clone()) {
+                write(handle);
+                return Ok(handle.clone());
+            }
+        }
+
+        let mut handles = self.handles.write().unwrap_or_else(PoisonError::into_inner);
+        // Another writer may have resolved it since the read lock was released
+        let handle = match lookup(&handles, hash, values) {
+            Some(handle) => handle.clone(),
+            None => {
+                let handle = Arc::new(resolve()?);
+                handles.insert(hash, handle.clone());
+                handle
+            }
+        };
+        write(&handle);
+        Ok(handle)
+    }
+
+    /// The handle of the series with `values`, if it was written to.
+    pub fn get<'a>(
+        &self,
+        values: impl IntoIterator<Item = &'a str> + Clone,
+    ) -> Option<Arc<SeriesHandle>> {
+        let handles = self.handles.read().unwrap_or_else(PoisonError::into_inner);
+        lookup(&handles, series_hash(values.clone()), values).cloned()
+    }
+
+    /// Sets the state of the stateset series `handle`, its label at
+    /// `state_index`, to 1 and the state set before it to 0, without going
+    /// through the family's other series.
+    pub fn set_state(&self, state_index: usize, handle: &Arc<SeriesHandle>) {
+        let group = |handle| stateset_group(handle, state_index);
+        let hash = series_hash(group(handle));
+
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        if let SeriesMetric::Gauge(gauge) = &handle.metric {
+            gauge.set(1.0);
+        }
+        if let Some(previous) = states.insert(hash, handle.clone())
+            && !Arc::ptr_eq(&previous, handle)
+            // Another group may share the hash
+            && group(&previous).eq(group(handle))
+            && let SeriesMetric::Gauge(gauge) = &previous.metric
+        {
+            gauge.set(0.0);
+        }
+    }
+
+    /// The series of the state currently set in the group of the stateset
+    /// series with `values`, whose state is at `state_index`.
+    pub fn active_state(&self, state_index: usize, values: &[&str]) -> Option<Arc<SeriesHandle>> {
+        let group = values
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != state_index)
+            .map(|(_, value)| *value);
+        let states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states
+            .get(&series_hash(group.clone()))
+            .filter(|active| stateset_group(active, state_index).eq(group))
+            .cloned()
+    }
+
+    /// Forgets the handle of the series with `values` while `remove` removes
+    /// the series, so writing to it again recreates it. Returns the handle
+    /// forgotten, if any, with the result of `remove`.
+    pub fn remove<R>(
+        &self,
+        values: &[Arc<str>],
+        remove: impl FnOnce() -> R,
+    ) -> (Option<Arc<SeriesHandle>>, R) {
+        let hash = series_hash(values.iter().map(|value| &**value));
+        let mut handles = self.handles.write().unwrap_or_else(PoisonError::into_inner);
+        let removed = handles
+            .get(&hash)
+            .is_some_and(|handle| handle.values == values)
+            .then(|| handles.remove(&hash))
+            .flatten();
+        if let Some(removed) = &removed {
+            self.states
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|_, active| !Arc::ptr_eq(active, removed));
+        }
+        (removed, remove())
+    }
+
+    /// The estimated memory of every series with a handle.
+    pub fn bytes(&self) -> u64 {
+        let handles = self.handles.read().unwrap_or_else(PoisonError::into_inner);
+        handles.values().map(|handle| handle.bytes).sum()
+    }
+}
+
+/// The handle at `hash`, if it is the series with `values`: another series
+/// may share the hash.
+fn lookup<'h, 'a>(
+    handles: &'h HashMap<u64, Arc<SeriesHandle>>,
+    hash: u64,
+    values: impl IntoIterator<Item = &'a str>,
+) -> Option<&'h Arc<SeriesHandle>> {
+    handles
+        .get(&hash)
+        .filter(|handle| handle.values.iter().map(|value| &**value).eq(values))
+}
+
+/// The label values of a stateset series but its state.
+fn stateset_group(handle: &SeriesHandle, state_index: usize) -> impl Iterator<Item = &str> {
+    handle
+        .values
+        .iter()
+        .enumerate()
+        .filter(move |(i, _)| *i != state_index)
+        .map(|(_, value)| &**value)
+}
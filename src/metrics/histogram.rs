@@ -0,0 +1,280 @@
+use crate::metrics::interner::SeriesValues;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, LabelPair, MetricFamily, MetricType};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A histogram aggregated by the client, pushed as the counts observed over
+/// the push interval, in cumulative buckets with the client's own bounds.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BucketCounts {
+    pub buckets: Vec<Bucket>,
+    pub sum: f64,
+    pub count: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Bucket {
+    pub le: f64,
+    /// Observations less than or equal to `le`.
+    pub count: f64,
+}
+
+impl BucketCounts {
+    /// Checks that the bounds increase and the cumulative counts don't
+    /// decrease nor exceed `count`.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.sum.is_finite() && self.count.is_finite() && self.count >= 0.0) {
+            return Err("sum and count must be finite, count non-negative".to_string());
+        }
+        let mut previous: Option<&Bucket> = None;
+        for bucket in &self.buckets {
+            if bucket.le.is_nan() || !bucket.count.is_finite() || bucket.count < 0.0 {
+                return Err(format!("invalid bucket le={}", bucket.le));
+            }
+            if let Some(previous) = previous {
+                if bucket.le <= previous.le {
+                    return Err("bucket bounds must increase".to_string());
+                }
+                if bucket.count < previous.count {
+                    return Err("cumulative bucket counts must not decrease".to_string());
+                }
+            }
+            previous = Some(bucket);
+        }
+        if previous.is_some_and(|last| last.count > self.count) {
+            return Err("bucket counts exceed count".to_string());
+        }
+        Ok(())
+    }
+
+    /// Redistributes the counts over `bounds`, interpolating linearly within
+    /// the pushed buckets (the first one starting at 0, as `histogram_quantile`
+    /// assumes). Returns the count of each bucket of `bounds`, plus the
+    /// observations above the last bound.
+    pub fn remap(&self, bounds: &[f64]) -> Vec<f64> {
+        let mut counts = Vec::with_capacity(bounds.len() + 1);
+        let mut below = 0.0;
+        for &bound in bounds {
+            let cumulative = self.cumulative_at(bound).min(self.count);
+            counts.push((cumulative - below).max(0.0));
+            below = below.max(cumulative);
+        }
+        counts.push((self.count - below).max(0.0));
+        counts
+    }
+
+    fn cumulative_at(&self, x: f64) -> f64 {
+        let mut lower = (0.0_f64, 0.0_f64);
+        for bucket in &self.buckets {
+            if x >= bucket.le {
+                lower = (bucket.le, bucket.count);
+                continue;
+            }
+            if bucket.le.is_infinite() || x <= lower.0 {
+                return lower.1;
+            }
+            let fraction = (x - lower.0) / (bucket.le - lower.0);
+            return lower.1 + (bucket.count - lower.1) * fraction;
+        }
+        // Above the last pushed bound: only the observations in +Inf are left
+        lower.1
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Series {
+    /// Non-cumulative counts per bound, the last one for +Inf.
+    counts: Vec<f64>,
+    sum: f64,
+    count: f64,
+}
+
+/// A histogram family with fixed bucket bounds, taking single observations
+/// as well as pushed `BucketCounts` remapped onto its bounds. Counts are kept
+/// as floats since remapping splits buckets, and rounded when exposed.
+/// Observations of existing series only take the read lock of the family,
+/// each series having its own lock.
+#[derive(Clone)]
+pub struct HistogramFamily {
+    desc: Desc,
+    bounds: Arc<Vec<f64>>,
+    series: Arc<RwLock<HashMap<SeriesValues, Mutex<Series>>>>,
+}
+
+impl HistogramFamily {
+    pub fn new(
+        name: &str,
+        help: &str,
+        label_names: &[&str],
+        mut bounds: Vec<f64>,
+    ) -> Result<Self, String> {
+        if label_names.contains(&"le") {
+            return Err("`le` is not allowed as label name in histograms".to_string());
+        }
+        if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("histogram buckets must be in increasing order".to_string());
+        }
+        if bounds.last().is_some_and(|bound| bound.is_infinite()) {
+            bounds.pop();
+        }
+
+        let label_names = label_names.iter().map(|name| name.to_string()).collect();
+        let desc = Desc::new(
+            name.to_string(),
+            help.to_string(),
+            label_names,
+            HashMap::new(),
+        )
+        .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            desc,
+            bounds: Arc::new(bounds),
+            series: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    pub fn bounds(&self) -> &[f64] {
+        &self.bounds
+    }
+
+    pub fn observe(&self, label_values: &[Arc<str>], value: f64) {
+        let index = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.update(label_values, |series| {
+            series.counts[index] += 1.0;
+            series.sum += value;
+            series.count += 1.0;
+        });
+    }
+
+    /// Adds client-aggregated counts, remapped onto this family's bounds.
+    pub fn merge(&self, label_values: &[Arc<str>], pushed: &BucketCounts) {
+        let counts = pushed.remap(&self.bounds);
+        self.update(label_values, |series| {
+            for (total, count) in series.counts.iter_mut().zip(&counts) {
+                *total += count;
+            }
+            series.sum += pushed.sum;
+            series.count += pushed.count;
+        });
+    }
+
+    /// Takes observations back from an existing series, as non-cumulative
+    /// counts per bound of this family, e.g. those of an undone write.
+    pub fn retract(&self, label_values: &[Arc<str>], counts: &[f64], sum: f64, count: f64) {
+        if let Ok(series) = self.series.read()
+            && let Some(existing) = series.get(label_values)
+            && let Ok(mut existing) = existing.lock()
+        {
+            for (total, retracted) in existing.counts.iter_mut().zip(counts) {
+                *total -= retracted;
+            }
+            existing.sum -= sum;
+            existing.count -= count;
+        }
+    }
+
+    /// Removes one series, returning whether it existed.
+    pub fn remove_label_values(&self, label_values: &[Arc<str>]) -> bool {
+        self.series
+            .write()
+            .is_ok_and(|mut series| series.remove(label_values).is_some())
+    }
+
+    fn update(&self, label_values: &[Arc<str>], apply: impl FnOnce(&mut Series)) {
+        if let Ok(series) = self.series.read()
+            && let Some(existing) = series.get(label_values)
+        {
+            if let Ok(mut existing) = existing.lock() {
+                apply(&mut existing);
+            }
+            return;
+        }
+
+        let Ok(mut series) = self.series.write() else {
+            return;
+        };
+        let entry = series.entry(label_values.to_vec()).or_insert_with(|| {
+            Mutex::new(Series {
+                counts: vec![0.0; self.bounds.len() + 1],
+                ..Series::default()
+            })
+        });
+        if let Ok(entry) = entry.get_mut() {
+            apply(entry);
+        }
+    }
+}
+
+impl Collector for HistogramFamily {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let Ok(series) = self.series.read() else {
+            return Vec::new();
+        };
+        // Sorted like the series of the prometheus vectors
+        let sorted: BTreeMap<_, _> = series
+            .iter()
+            .filter_map(|(values, series)| Some((values, series.lock().ok()?.clone())))
+            .collect();
+
+        let metrics: Vec<proto::Metric> = sorted
+            .into_iter()
+            .map(|(values, series)| {
+                let mut cumulative = 0.0;
+                let buckets: Vec<proto::Bucket> = self
+                    .bounds
+                    .iter()
+                    .zip(&series.counts)
+                    .map(|(bound, count)| {
+                        cumulative += count;
+                        let mut bucket = proto::Bucket::new();
+                        bucket.set_upper_bound(*bound);
+                        bucket.set_cumulative_count(cumulative.round() as u64);
+                        bucket
+                    })
+                    .collect();
+
+                let mut histogram = proto::Histogram::new();
+                histogram.set_bucket(buckets.into());
+                histogram.set_sample_sum(series.sum);
+                histogram.set_sample_count(series.count.round() as u64);
+
+                let mut labels: Vec<LabelPair> = self
+                    .desc
+                    .variable_labels
+                    .iter()
+                    .zip(values)
+                    .map(|(name, value)| {
+                        let mut pair = LabelPair::new();
+                        pair.set_name(name.clone());
+                        pair.set_value(value.to_string());
+                        pair
+                    })
+                    .collect();
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+                let mut metric = proto::Metric::new();
+                metric.set_label(labels.into());
+                metric.set_histogram(histogram);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::new();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
@@ -0,0 +1,162 @@
+//! Snapshots of the exposed samples taken at a fixed interval, kept for the
+//! configured retention so `GET /api/export` can hand the history of pushed
+//! metrics to analysts without a TSDB in between.
+
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use crate::config::HistoryConfig;
+use crate::sinks::{Sample, samples};
+use chrono::{DateTime, Utc};
+use prometheus::proto::MetricFamily;
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "server")]
+use std::time::Duration;
+#[cfg(feature = "server")]
+use tracing::warn;
+
+/// The samples exposed at one instant.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub timestamp: DateTime<Utc>,
+    pub samples: Arc<Vec<Sample>>,
+    /// The name of each family with the range of its samples.
+    families: Arc<Vec<(String, Range<usize>)>>,
+}
+
+impl Snapshot {
+    /// The samples of the series named `name`; the name of a histogram or
+    /// summary family also selects its `_bucket`, `_sum` and `_count`
+    /// samples, but not those of another family that happens to share the
+    /// prefix, such as a gauge named `<name>_count`.
+    pub fn matching<'a>(&'a self, name: Option<&'a str>) -> impl Iterator<Item = &'a Sample> {
+        self.families.iter().flat_map(move |(family, range)| {
+            let whole_family = name.is_none_or(|name| family == name);
+            self.samples[range.clone()]
+                .iter()
+                .filter(move |sample| whole_family || name == Some(sample.name.as_str()))
+        })
+    }
+}
+
+pub struct SampleHistory {
+    config: HistoryConfig,
+    snapshots: RwLock<VecDeque<Snapshot>>,
+}
+
+impl SampleHistory {
+    pub fn new(config: &HistoryConfig) -> Self {
+        Self {
+            config: config.clone(),
+            snapshots: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    /// Records the samples of `families` as of `timestamp`, forgetting the
+    /// snapshots past the retention or beyond `max_samples`.
+    pub fn record(&self, families: &[MetricFamily], timestamp: DateTime<Utc>) {
+        let mut flattened = Vec::new();
+        let mut ranges = Vec::with_capacity(families.len());
+        for family in families {
+            let start = flattened.len();
+            flattened.extend(samples(std::slice::from_ref(family)));
+            ranges.push((family.get_name().to_string(), start..flattened.len()));
+        }
+        let snapshot = Snapshot {
+            timestamp,
+            samples: Arc::new(flattened),
+            families: Arc::new(ranges),
+        };
+        let Ok(mut snapshots) = self.snapshots.write() else {
+            return;
+        };
+        snapshots.push_back(snapshot);
+
+        // Beyond chrono's range, the retention keeps everything
+        let oldest = i64::try_from(self.config.retention_secs)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .and_then(|retention| timestamp.checked_sub_signed(retention));
+        while snapshots
+            .front()
+            .is_some_and(|front| oldest.is_some_and(|oldest| front.timestamp < oldest))
+        {
+            snapshots.pop_front();
+        }
+        let mut held: usize = snapshots.iter().map(|s| s.samples.len()).sum();
+        while held > self.config.max_samples && snapshots.len() > 1 {
+            if let Some(front) = snapshots.pop_front() {
+                held -= front.samples.len();
+            }
+        }
+    }
+
+    /// The snapshots taken between `start` and `end` inclusive, oldest first.
+    pub fn range(&self, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Vec<Snapshot> {
+        let Ok(snapshots) = self.snapshots.read() else {
+            return Vec::new();
+        };
+        snapshots
+            .iter()
+            .filter(|s| start.is_none_or(|start| s.timestamp >= start))
+            .filter(|s| end.is_none_or(|end| s.timestamp <= end))
+            .cloned()
+            .collect()
+    }
+
+    /// Snapshots currently held.
+    pub fn len(&self) -> usize {
+        self.snapshots.read().map(|s| s.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The label names of the samples named `name` in `snapshots`, sorted.
+pub fn label_names(snapshots: &[Snapshot], name: Option<&str>) -> Vec<String> {
+    let mut names = BTreeSet::new();
+    for snapshot in snapshots {
+        for sample in snapshot.matching(name) {
+            for (label, _) in &sample.labels {
+                if !names.contains(label) {
+                    names.insert(label.clone());
+                }
+            }
+        }
+    }
+    names.into_iter().collect()
+}
+
+/// Snapshots the exposed registry every `history.interval_secs`.
+#[cfg(feature = "server")]
+pub fn spawn_history_sampling(state: Arc<AppState>) {
+    let Some(history) = &state.history else {
+        return;
+    };
+    let period = Duration::from_secs(history.config().interval_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let Some(history) = &state.history else {
+                return;
+            };
+            match state.metrics_collector.gather_exposed().await {
+                Ok(mut snapshot) => {
+                    let collector = &state.metrics_collector;
+                    collector.stamp_sample_times(&mut snapshot.families);
+                    history.record(&snapshot.families, Utc::now())
+                }
+                Err(e) => warn!("Failed to gather metrics for the sample history: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,64 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// The interned label values of a series, in its family's label order.
+pub type SeriesValues = Vec<Arc<str>>;
+
+/// Shares the label names and values repeated across series and pushes, so
+/// the registry's per-series indexes hold reference-counted strings instead
+/// of a fresh copy of every label for every series.
+#[derive(Default)]
+pub struct Interner {
+    strings: RwLock<HashSet<Arc<str>>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&self, value: &str) -> Arc<str> {
+        if let Ok(strings) = self.strings.read()
+            && let Some(interned) = strings.get(value)
+        {
+            return interned.clone();
+        }
+
+        let Ok(mut strings) = self.strings.write() else {
+            return Arc::from(value);
+        };
+        // Another writer may have added it since the read lock was released
+        if let Some(interned) = strings.get(value) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = Arc::from(value);
+        strings.insert(interned.clone());
+        interned
+    }
+
+    pub fn intern_all(&self, values: &[&str]) -> Vec<Arc<str>> {
+        values.iter().map(|value| self.intern(value)).collect()
+    }
+
+    /// Forgets the strings nothing refers to anymore, e.g. after compaction
+    /// removed the last series using them, returning how many were dropped.
+    pub fn purge(&self) -> usize {
+        let Ok(mut strings) = self.strings.write() else {
+            return 0;
+        };
+        let before = strings.len();
+        strings.retain(|interned| Arc::strong_count(interned) > 1);
+        before - strings.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings
+            .read()
+            .map(|strings| strings.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,161 @@
+//! The exposed series as JSON, a page at a time, for registries too large
+//! to list in one response.
+//!
+//! Series are ordered by name then labels, and a page's cursor is the last
+//! series it holds, so paging stays consistent while series are written or
+//! expire between requests: none is returned twice, and only those created
+//! behind the cursor are missed.
+
+use crate::errors::ServerError;
+use crate::utils::hex;
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Series per page when the request doesn't set a `limit`.
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Largest `limit` honoured; larger ones are lowered to it.
+pub const MAX_PAGE_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeriesEntry {
+    /// The family name in the exposition, with the prefix and namespace.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub metric_type: String,
+    pub labels: BTreeMap<String, String>,
+    /// The value of a counter or gauge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// The observations of a histogram or summary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_sum: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeriesPage {
+    pub series: Vec<SeriesEntry>,
+    /// Series matching the filters, across all pages.
+    pub total: usize,
+    /// Passed as `cursor` to get the next page; absent on the last one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// The page of at most `limit` series of `families` following `cursor`,
+/// among those whose name starts with `prefix`.
+pub fn list_series(
+    families: &[MetricFamily],
+    prefix: Option<&str>,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<SeriesPage, ServerError> {
+    let after = cursor.map(decode_cursor).transpose()?;
+    let after = after
+        .as_ref()
+        .map(|(name, labels)| (name.as_str(), as_refs(labels)));
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+
+    let mut total = 0;
+    let mut candidates = Vec::new();
+    for family in families {
+        if prefix.is_some_and(|prefix| !family.get_name().starts_with(prefix)) {
+            continue;
+        }
+        for metric in family.get_metric() {
+            total += 1;
+            let mut labels: Vec<(&str, &str)> = metric
+                .get_label()
+                .iter()
+                .map(|pair| (pair.get_name(), pair.get_value()))
+                .collect();
+            labels.sort_unstable();
+
+            let key = (family.get_name(), labels);
+            if after.as_ref().is_some_and(|after| key <= *after) {
+                continue;
+            }
+            candidates.push((key, family, metric));
+        }
+    }
+
+    candidates.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let more = candidates.len() > limit;
+    candidates.truncate(limit);
+
+    let next_cursor = more
+        .then(|| candidates.last())
+        .flatten()
+        .map(|((name, labels), _, _)| encode_cursor(name, labels));
+    let series = candidates
+        .into_iter()
+        .map(|((name, labels), family, metric)| {
+            let mut entry = SeriesEntry {
+                name: name.to_string(),
+                metric_type: type_name(family.get_field_type()).to_string(),
+                labels: labels
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                value: None,
+                sample_count: None,
+                sample_sum: None,
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => entry.value = Some(metric.get_counter().get_value()),
+                MetricType::GAUGE => entry.value = Some(metric.get_gauge().get_value()),
+                MetricType::UNTYPED => entry.value = Some(metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    entry.sample_count = Some(histogram.get_sample_count());
+                    entry.sample_sum = Some(histogram.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    entry.sample_count = Some(summary.get_sample_count());
+                    entry.sample_sum = Some(summary.get_sample_sum());
+                }
+            }
+            entry
+        })
+        .collect();
+
+    Ok(SeriesPage {
+        series,
+        total,
+        next_cursor,
+    })
+}
+
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+    }
+}
+
+type CursorKey = (String, Vec<(String, String)>);
+
+fn as_refs(labels: &[(String, String)]) -> Vec<(&str, &str)> {
+    labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+/// Opaque to clients: the hex of the series key as JSON.
+fn encode_cursor(name: &str, labels: &[(&str, &str)]) -> String {
+    hex::encode(&serde_json::to_vec(&(name, labels)).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Result<CursorKey, ServerError> {
+    hex::decode(cursor)
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| ServerError::ValidationError(format!("Invalid cursor '{}'", cursor)))
+}
@@ -0,0 +1,89 @@
+use crate::errors::ServerError;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Rough cost of a series besides its label values: the vector entry, its
+/// hashed key and the atomic value(s) behind it.
+const SERIES_OVERHEAD_BYTES: u64 = 192;
+/// Per-bucket cost of a histogram series.
+const BUCKET_BYTES: u64 = 16;
+
+/// Estimates the memory held by pushed series (per series: a fixed overhead,
+/// the label names and values, and the buckets of histograms), and refuses
+/// new series once `metrics.max_memory_bytes` would be exceeded. Each series'
+/// estimate is kept on its handle, and given back when compaction or
+/// tombstones remove the series.
+pub struct MemoryAccounting {
+    max_bytes: Option<u64>,
+    bytes: AtomicU64,
+}
+
+impl MemoryAccounting {
+    pub fn new(max_bytes: Option<u64>) -> Self {
+        Self {
+            max_bytes,
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Estimated bytes held by all series.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn estimate(label_keys: &[String], label_values: &[Arc<str>], buckets: usize) -> u64 {
+        let labels: usize = label_keys.iter().map(String::len).sum::<usize>()
+            + label_values.iter().map(|value| value.len()).sum::<usize>();
+        SERIES_OVERHEAD_BYTES + labels as u64 + buckets as u64 * BUCKET_BYTES
+    }
+
+    /// Checks that a new series costing `cost` would be admitted, without
+    /// accounting for it.
+    pub fn check(&self, family: &str, cost: u64) -> Result<(), ServerError> {
+        match self.max_bytes {
+            Some(max) => fits(family, cost, self.bytes(), max).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    /// Accounts for a new series costing `cost`, failing when it doesn't fit
+    /// in the budget.
+    pub fn admit(&self, family: &str, cost: u64) -> Result<(), ServerError> {
+        let Some(max) = self.max_bytes else {
+            self.bytes.fetch_add(cost, Ordering::Relaxed);
+            return Ok(());
+        };
+        // Concurrent new series can't both take the last of the budget
+        let mut used = self.bytes();
+        loop {
+            let total = fits(family, cost, used, max)?;
+            match self.bytes.compare_exchange_weak(
+                used,
+                total,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    /// Gives back the estimate of a removed series.
+    pub fn release(&self, cost: u64) {
+        self.bytes.fetch_sub(cost, Ordering::Relaxed);
+    }
+}
+
+/// The bytes in use once a new series costing `cost` is added, if that fits
+/// in `max`.
+fn fits(family: &str, cost: u64, used: u64, max: u64) -> Result<u64, ServerError> {
+    let total = used + cost;
+    if total > max {
+        return Err(ServerError::MemoryBudgetExceeded(format!(
+            "new series of '{}' needs ~{} bytes, {} of {} in use",
+            family, cost, used, max
+        )));
+    }
+    Ok(total)
+}
@@ -0,0 +1,242 @@
+//! Filter plugins compiled to WebAssembly and run on every pushed metric, so
+//! operators can drop, relabel or rescale metrics without forking the crate.
+//!
+//! A plugin exports its `memory`, `alloc(len: i32) -> i32` and
+//! `filter(ptr: i32, len: i32) -> i64`, which is called with the metric as
+//! JSON written at `ptr`. It returns 0 to keep the metric as it is, a
+//! negative value to drop it, or `ptr << 32 | len` of the JSON of the metric
+//! to write instead. An optional `dealloc(ptr: i32, len: i32)` is called for
+//! both buffers once the host is done with them. Plugins import nothing.
+//!
+//! Calls block for as long as their fuel lasts, so the collector runs them on
+//! tokio's blocking threads, each call on an instance of its own.
+
+use crate::config::PluginConfig;
+use crate::errors::ServerError;
+use crate::metrics::types::Metric;
+#[cfg(feature = "wasm")]
+use std::sync::Arc;
+
+/// The configured plugins, run in order. Clones share the plugins.
+#[derive(Default, Clone)]
+pub struct Plugins {
+    #[cfg(feature = "wasm")]
+    plugins: Arc<Vec<wasm::Plugin>>,
+}
+
+impl Plugins {
+    /// Compiles and instantiates every plugin, failing on the first that
+    /// can't be loaded.
+    pub fn load(configs: &[PluginConfig]) -> Result<Self, ServerError> {
+        #[cfg(feature = "wasm")]
+        {
+            let engine = wasm::engine()?;
+            let plugins = configs
+                .iter()
+                .map(|config| wasm::Plugin::load(&engine, config))
+                .collect::<Result<_, _>>()?;
+            Ok(Self {
+                plugins: Arc::new(plugins),
+            })
+        }
+        #[cfg(not(feature = "wasm"))]
+        match configs.first() {
+            Some(config) => Err(ServerError::ConfigurationError(format!(
+                "Plugin '{}' requires building with the `wasm` feature",
+                config.name
+            ))),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        #[cfg(feature = "wasm")]
+        return self.plugins.is_empty();
+        #[cfg(not(feature = "wasm"))]
+        true
+    }
+
+    /// Runs the plugins filtering `source` on `metric`, returning the name of
+    /// the plugin that dropped it, if one did. This blocks while they run.
+    #[cfg_attr(not(feature = "wasm"), allow(unused_variables))]
+    pub fn apply(&self, source: &str, metric: &mut Metric) -> Result<Option<String>, ServerError> {
+        #[cfg(feature = "wasm")]
+        for plugin in self.plugins.iter() {
+            if !plugin.filters(source) {
+                continue;
+            }
+            match plugin.call(metric)? {
+                wasm::Outcome::Keep => {}
+                wasm::Outcome::Drop => return Ok(Some(plugin.name().to_string())),
+                wasm::Outcome::Replace(replacement) => *metric = replacement,
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use super::{Metric, PluginConfig, ServerError};
+    use std::sync::Mutex;
+    use tracing::warn;
+    use wasmtime::{
+        Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+    };
+
+    pub(super) enum Outcome {
+        Keep,
+        Drop,
+        Replace(Metric),
+    }
+
+    pub(super) fn engine() -> Result<Engine, ServerError> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).map_err(|e| ServerError::ConfigurationError(format!("{:#}", e)))
+    }
+
+    struct Instantiated {
+        store: Store<StoreLimits>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        dealloc: Option<TypedFunc<(i32, i32), ()>>,
+        filter: TypedFunc<(i32, i32), i64>,
+    }
+
+    /// Instances kept for the next calls; concurrent calls beyond them
+    /// instantiate the module again.
+    const MAX_IDLE_INSTANCES: usize = 8;
+
+    pub(super) struct Plugin {
+        config: PluginConfig,
+        module: Module,
+        // Taken by a call and put back once it returns; dropped after a trap,
+        // which may leave the plugin's memory inconsistent
+        idle: Mutex<Vec<Instantiated>>,
+    }
+
+    impl Plugin {
+        pub(super) fn load(engine: &Engine, config: &PluginConfig) -> Result<Self, ServerError> {
+            let fail = |e: wasmtime::Error| {
+                ServerError::ConfigurationError(format!("plugin '{}': {:#}", config.name, e))
+            };
+            let module = Module::from_file(engine, &config.path).map_err(fail)?;
+            // Fail at startup rather than on the first metric
+            let instance = instantiate(&module, config).map_err(fail)?;
+            Ok(Self {
+                config: config.clone(),
+                module,
+                idle: Mutex::new(vec![instance]),
+            })
+        }
+
+        pub(super) fn name(&self) -> &str {
+            &self.config.name
+        }
+
+        pub(super) fn filters(&self, source: &str) -> bool {
+            self.config.sources.is_empty() || self.config.sources.iter().any(|s| s == source)
+        }
+
+        pub(super) fn call(&self, metric: &Metric) -> Result<Outcome, ServerError> {
+            let fail = |e: String| {
+                ServerError::MetricsProcessingError(format!("plugin '{}': {}", self.name(), e))
+            };
+            let input = serde_json::to_vec(metric)?;
+            let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            let mut instance = match idle {
+                Some(instance) => instance,
+                None => {
+                    instantiate(&self.module, &self.config).map_err(|e| fail(format!("{:#}", e)))?
+                }
+            };
+
+            let result = filter(&mut instance, self.config.fuel, &input);
+            match &result {
+                Ok(_) => {
+                    let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+                    if idle.len() < MAX_IDLE_INSTANCES {
+                        idle.push(instance);
+                    }
+                }
+                Err(e) => warn!(
+                    "Plugin {} failed, dropping its instance: {:#}",
+                    self.name(),
+                    e
+                ),
+            }
+            match result.map_err(|e| fail(format!("{:#}", e)))? {
+                Returned::Keep => Ok(Outcome::Keep),
+                Returned::Drop => Ok(Outcome::Drop),
+                Returned::Json(json) => serde_json::from_slice(&json)
+                    .map(Outcome::Replace)
+                    .map_err(|e| fail(format!("invalid metric returned: {}", e))),
+            }
+        }
+    }
+
+    fn instantiate(module: &Module, config: &PluginConfig) -> wasmtime::Result<Instantiated> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(config.max_memory_bytes)
+            .build();
+        let mut store = Store::new(module.engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(config.fuel)?;
+
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("no exported memory"))?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let dealloc = instance.get_typed_func(&mut store, "dealloc").ok();
+        let filter = instance.get_typed_func(&mut store, "filter")?;
+        Ok(Instantiated {
+            store,
+            memory,
+            alloc,
+            dealloc,
+            filter,
+        })
+    }
+
+    enum Returned {
+        Keep,
+        Drop,
+        Json(Vec<u8>),
+    }
+
+    /// Runs the plugin's `filter` on the JSON of a metric.
+    fn filter(instance: &mut Instantiated, fuel: u64, input: &[u8]) -> wasmtime::Result<Returned> {
+        let Instantiated {
+            store,
+            memory,
+            alloc,
+            dealloc,
+            filter,
+        } = instance;
+        store.set_fuel(fuel)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut *store, len)?;
+        memory.write(&mut *store, ptr as u32 as usize, input)?;
+        let result = filter.call(&mut *store, (ptr, len))?;
+        if let Some(dealloc) = dealloc {
+            dealloc.call(&mut *store, (ptr, len))?;
+        }
+
+        if result == 0 {
+            return Ok(Returned::Keep);
+        }
+        if result < 0 {
+            return Ok(Returned::Drop);
+        }
+        let (ptr, len) = ((result >> 32) as u32, result as u32);
+        let mut output = vec![0; len as usize];
+        memory.read(&*store, ptr as usize, &mut output)?;
+        if let Some(dealloc) = dealloc {
+            dealloc.call(&mut *store, (ptr as i32, len as i32))?;
+        }
+        Ok(Returned::Json(output))
+    }
+}
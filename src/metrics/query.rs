@@ -0,0 +1,395 @@
+use crate::errors::ServerError;
+use crate::metrics::selector::SeriesSelector;
+use crate::sinks::Sample;
+use std::collections::BTreeMap;
+
+/// A small subset of PromQL evaluated against the current registry contents:
+/// instant selectors, `sum`/`avg`/`min`/`max`/`count` aggregations with an
+/// optional `by (...)` clause, number literals and `+ - * /` arithmetic with
+/// one-to-one matching between vectors. There are no range vectors or functions.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Selector(SeriesSelector),
+    Aggregate {
+        op: AggregateOp,
+        by: Vec<String>,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A series in a query result. Like in PromQL, results don't keep the metric name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuerySample {
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryValue {
+    Scalar(f64),
+    Vector(Vec<QuerySample>),
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Self, ServerError> {
+        let mut parser = Parser {
+            input,
+            tokens: tokenize(input)?,
+            position: 0,
+        };
+        let expr = parser.expr()?;
+        if parser.position < parser.tokens.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(expr)
+    }
+
+    pub fn evaluate(&self, samples: &[Sample]) -> QueryValue {
+        match self {
+            Expr::Number(value) => QueryValue::Scalar(*value),
+            Expr::Selector(selector) => QueryValue::Vector(
+                samples
+                    .iter()
+                    .filter(|sample| {
+                        let labels = sample.labels.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                        selector.matches(&sample.name, labels)
+                    })
+                    .map(|sample| QuerySample {
+                        labels: sample.labels.iter().cloned().collect(),
+                        value: sample.value,
+                    })
+                    .collect(),
+            ),
+            Expr::Aggregate { op, by, expr } => {
+                let input = match expr.evaluate(samples) {
+                    QueryValue::Scalar(value) => vec![QuerySample {
+                        labels: BTreeMap::new(),
+                        value,
+                    }],
+                    QueryValue::Vector(vector) => vector,
+                };
+                QueryValue::Vector(aggregate(*op, by, input))
+            }
+            Expr::Binary { op, lhs, rhs } => match (lhs.evaluate(samples), rhs.evaluate(samples)) {
+                (QueryValue::Scalar(a), QueryValue::Scalar(b)) => {
+                    QueryValue::Scalar(op.apply(a, b))
+                }
+                (QueryValue::Vector(vector), QueryValue::Scalar(b)) => {
+                    QueryValue::Vector(map_values(vector, |a| op.apply(a, b)))
+                }
+                (QueryValue::Scalar(a), QueryValue::Vector(vector)) => {
+                    QueryValue::Vector(map_values(vector, |b| op.apply(a, b)))
+                }
+                (QueryValue::Vector(lhs), QueryValue::Vector(rhs)) => QueryValue::Vector(
+                    lhs.into_iter()
+                        .filter_map(|a| {
+                            let b = rhs.iter().find(|b| b.labels == a.labels)?;
+                            Some(QuerySample {
+                                value: op.apply(a.value, b.value),
+                                labels: a.labels,
+                            })
+                        })
+                        .collect(),
+                ),
+            },
+        }
+    }
+}
+
+impl BinaryOp {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+        }
+    }
+}
+
+fn map_values(vector: Vec<QuerySample>, f: impl Fn(f64) -> f64) -> Vec<QuerySample> {
+    vector
+        .into_iter()
+        .map(|sample| QuerySample {
+            value: f(sample.value),
+            labels: sample.labels,
+        })
+        .collect()
+}
+
+fn aggregate(op: AggregateOp, by: &[String], input: Vec<QuerySample>) -> Vec<QuerySample> {
+    let mut groups: BTreeMap<BTreeMap<String, String>, Vec<f64>> = BTreeMap::new();
+    for sample in input {
+        let key = sample
+            .labels
+            .into_iter()
+            .filter(|(name, _)| by.contains(name))
+            .collect();
+        groups.entry(key).or_default().push(sample.value);
+    }
+
+    groups
+        .into_iter()
+        .map(|(labels, values)| {
+            let value = match op {
+                AggregateOp::Sum => values.iter().sum(),
+                AggregateOp::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                AggregateOp::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+                AggregateOp::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                AggregateOp::Count => values.len() as f64,
+            };
+            QuerySample { labels, value }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    /// A selector's `{...}` matchers, kept raw for `SeriesSelector::parse`.
+    Matchers(String),
+    Punct(char),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ServerError> {
+    let invalid = |reason: String| {
+        ServerError::ValidationError(format!("Invalid expression '{}': {}", input, reason))
+    };
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '.') {
+                number.push(c);
+            }
+            let value = number
+                .parse()
+                .map_err(|_| invalid(format!("'{}' is not a number", number)))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() || c == '_' || c == ':' {
+            let mut ident = String::new();
+            while let Some(c) =
+                chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == ':')
+            {
+                ident.push(c);
+            }
+            tokens.push(Token::Ident(ident));
+        } else if c == '{' {
+            let mut matchers = String::new();
+            let mut quote = None;
+            loop {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| invalid("unbalanced braces".to_string()))?;
+                matchers.push(c);
+                match (quote, c) {
+                    (Some(_), '\\') => {
+                        if let Some(escaped) = chars.next() {
+                            matchers.push(escaped);
+                        }
+                    }
+                    (Some(q), c) if c == q => quote = None,
+                    (None, '"' | '\'') => quote = Some(c),
+                    (None, '}') => break,
+                    _ => {}
+                }
+            }
+            tokens.push(Token::Matchers(matchers));
+        } else if "()+-*/,".contains(c) {
+            chars.next();
+            tokens.push(Token::Punct(c));
+        } else {
+            return Err(invalid(format!("unexpected character '{}'", c)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &str) -> ServerError {
+        ServerError::ValidationError(format!("Invalid expression '{}': {}", self.input, reason))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), ServerError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", punct)))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ServerError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                BinaryOp::Add
+            } else if self.eat('-') {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.term()?;
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ServerError> {
+        let mut lhs = self.operand()?;
+        loop {
+            let op = if self.eat('*') {
+                BinaryOp::Mul
+            } else if self.eat('/') {
+                BinaryOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.operand()?;
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+    }
+
+    fn operand(&mut self) -> Result<Expr, ServerError> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Punct('(')) => {
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(Token::Punct('-')) => Ok(Expr::Binary {
+                op: BinaryOp::Mul,
+                lhs: Box::new(Expr::Number(-1.0)),
+                rhs: Box::new(self.operand()?),
+            }),
+            Some(Token::Matchers(matchers)) => {
+                Ok(Expr::Selector(SeriesSelector::parse(&matchers)?))
+            }
+            Some(Token::Ident(ident)) => match aggregate_op(&ident) {
+                Some(op) if self.aggregation_follows() => self.aggregate(op),
+                _ => {
+                    let mut selector = ident;
+                    if let Some(Token::Matchers(matchers)) = self.peek().cloned() {
+                        self.position += 1;
+                        selector.push_str(&matchers);
+                    }
+                    Ok(Expr::Selector(SeriesSelector::parse(&selector)?))
+                }
+            },
+            _ => Err(self.error("expected a number, selector or aggregation")),
+        }
+    }
+
+    fn aggregation_follows(&self) -> bool {
+        matches!(self.peek(), Some(Token::Punct('(')) | Some(Token::Ident(_)))
+    }
+
+    fn aggregate(&mut self, op: AggregateOp) -> Result<Expr, ServerError> {
+        let mut by = self.by_clause()?;
+        self.expect('(')?;
+        let expr = self.expr()?;
+        self.expect(')')?;
+        if by.is_empty() {
+            by = self.by_clause()?;
+        }
+
+        Ok(Expr::Aggregate {
+            op,
+            by,
+            expr: Box::new(expr),
+        })
+    }
+
+    fn by_clause(&mut self) -> Result<Vec<String>, ServerError> {
+        if self.peek() != Some(&Token::Ident("by".to_string())) {
+            return Ok(Vec::new());
+        }
+        self.position += 1;
+        self.expect('(')?;
+
+        let mut labels = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Ident(label)) => labels.push(label),
+                Some(Token::Punct(')')) if labels.is_empty() => return Ok(labels),
+                _ => return Err(self.error("expected a label name in 'by'")),
+            }
+            if self.eat(')') {
+                return Ok(labels);
+            }
+            self.expect(',')?;
+        }
+    }
+}
+
+fn aggregate_op(ident: &str) -> Option<AggregateOp> {
+    match ident {
+        "sum" => Some(AggregateOp::Sum),
+        "avg" => Some(AggregateOp::Avg),
+        "min" => Some(AggregateOp::Min),
+        "max" => Some(AggregateOp::Max),
+        "count" => Some(AggregateOp::Count),
+        _ => None,
+    }
+}
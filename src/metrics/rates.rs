@@ -0,0 +1,174 @@
+use prometheus::proto::{Gauge, Metric, MetricFamily, MetricType};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const RATE_SUFFIX: &str = ":rate_1m";
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// Writes closer together than this replace each other in the history
+const RESOLUTION: Duration = Duration::from_secs(1);
+
+/// The history of one counter series, found by a hash of its family and
+/// labels so that writes to a known series don't allocate.
+struct SeriesHistory {
+    family: String,
+    labels: Vec<(String, String)>,
+    samples: VecDeque<(Instant, f64)>,
+    // Whether the last `derive` found the series
+    present: bool,
+}
+
+impl SeriesHistory {
+    fn is<'a>(&self, family: &str, mut labels: impl Iterator<Item = (&'a str, &'a str)>) -> bool {
+        self.family == family
+            && self
+                .labels
+                .iter()
+                .all(|(name, value)| labels.next() == Some((name.as_str(), value.as_str())))
+            && labels.next().is_none()
+    }
+}
+
+/// Keeps about a minute of values per counter series so scrapes can expose a
+/// `<counter>:rate_1m` gauge with the per-second rate over that minute.
+#[derive(Default)]
+pub struct RateTracker {
+    // Hash of the family and labels -> the series sharing it
+    history: Mutex<HashMap<u64, Vec<SeriesHistory>>>,
+}
+
+impl RateTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the current value of a counter series after a write, with its
+    /// labels sorted by name as they are exposed.
+    pub fn observe<'a>(
+        &self,
+        family: &str,
+        labels: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+        value: f64,
+    ) {
+        if let Ok(mut history) = self.history.lock() {
+            let series = series_history(&mut history, family, labels);
+            record(&mut series.samples, Instant::now(), value);
+        }
+    }
+
+    /// Rate gauges for every counter series in `families`. Series that no
+    /// longer exist are forgotten.
+    pub fn derive(&self, families: &[MetricFamily]) -> Vec<MetricFamily> {
+        let Ok(mut history) = self.history.lock() else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut derived = Vec::new();
+
+        for family in families {
+            if family.get_field_type() != MetricType::COUNTER {
+                continue;
+            }
+
+            let mut rates = Vec::with_capacity(family.get_metric().len());
+            for metric in family.get_metric() {
+                let mut labels: Vec<(&str, &str)> = metric
+                    .get_label()
+                    .iter()
+                    .map(|pair| (pair.get_name(), pair.get_value()))
+                    .collect();
+                labels.sort();
+                let value = metric.get_counter().get_value();
+
+                let series = series_history(&mut history, family.get_name(), labels.into_iter());
+                series.present = true;
+                record(&mut series.samples, now, value);
+
+                let mut gauge = Gauge::new();
+                gauge.set_value(rate(&series.samples, now, value));
+                let mut rate_metric = Metric::new();
+                rate_metric.set_label(metric.get_label().to_vec().into());
+                rate_metric.set_gauge(gauge);
+                rates.push(rate_metric);
+            }
+
+            let mut rate_family = MetricFamily::new();
+            rate_family.set_name(format!("{}{}", family.get_name(), RATE_SUFFIX));
+            rate_family.set_help(format!(
+                "Per-second rate of {} over the last minute",
+                family.get_name()
+            ));
+            rate_family.set_field_type(MetricType::GAUGE);
+            rate_family.set_metric(rates.into());
+            derived.push(rate_family);
+        }
+
+        history.retain(|_, bucket| {
+            bucket.retain_mut(|series| std::mem::take(&mut series.present));
+            !bucket.is_empty()
+        });
+        derived
+    }
+}
+
+/// The history of a series, created on its first value.
+fn series_history<'h, 'a>(
+    history: &'h mut HashMap<u64, Vec<SeriesHistory>>,
+    family: &str,
+    labels: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+) -> &'h mut SeriesHistory {
+    let mut hasher = DefaultHasher::new();
+    family.hash(&mut hasher);
+    for label in labels.clone() {
+        label.hash(&mut hasher);
+    }
+    let bucket = history.entry(hasher.finish()).or_default();
+
+    match bucket
+        .iter()
+        .position(|series| series.is(family, labels.clone()))
+    {
+        Some(index) => &mut bucket[index],
+        None => {
+            bucket.push(SeriesHistory {
+                family: family.to_string(),
+                labels: labels
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                samples: VecDeque::new(),
+                present: false,
+            });
+            let last = bucket.len() - 1;
+            &mut bucket[last]
+        }
+    }
+}
+
+fn record(samples: &mut VecDeque<(Instant, f64)>, now: Instant, value: f64) {
+    if let Some(&(last_seen, last_value)) = samples.back() {
+        if value < last_value {
+            // The series was reset, e.g. expired and written again
+            samples.clear();
+        } else if samples.len() > 1 && now.duration_since(last_seen) < RESOLUTION {
+            samples.pop_back();
+        }
+    }
+    samples.push_back((now, value));
+
+    // Keep the newest sample from before the window as the base of the rate
+    while samples.len() > 1 && now.duration_since(samples[1].0) >= RATE_WINDOW {
+        samples.pop_front();
+    }
+}
+
+fn rate(samples: &VecDeque<(Instant, f64)>, now: Instant, current: f64) -> f64 {
+    let Some(&(since, base)) = samples.front() else {
+        return 0.0;
+    };
+    let elapsed = now.duration_since(since).as_secs_f64();
+    if elapsed <= 0.0 {
+        return 0.0;
+    }
+    (current - base) / elapsed
+}
@@ -0,0 +1,325 @@
+use crate::errors::ServerError;
+use crate::utils::fs::write_json;
+use crate::utils::hex;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tracing::error;
+use uuid::Uuid;
+
+/// Body of `POST /api/admin/sources`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceRequest {
+    pub source: String,
+    /// How often the source is expected to push, for dead-source alerting.
+    #[serde(default)]
+    pub push_interval_secs: Option<u64>,
+    /// Metric names the source may push must start with one of these; any
+    /// name is allowed when empty.
+    #[serde(default)]
+    pub allowed_prefixes: Vec<String>,
+}
+
+/// A registered source as reported by the API; the token is only returned
+/// once, when it is issued.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    pub source: String,
+    pub push_interval_secs: Option<u64>,
+    pub allowed_prefixes: Vec<String>,
+    pub registered_at: DateTime<Utc>,
+    pub last_push: Option<DateTime<Utc>>,
+    /// No push within the expected interval (since registration if the
+    /// source never pushed).
+    pub overdue: bool,
+}
+
+/// Response of `POST /api/admin/sources`.
+#[derive(Debug, Serialize)]
+pub struct IssuedToken {
+    #[serde(flatten)]
+    pub status: SourceStatus,
+    pub token: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Registration {
+    #[serde(flatten)]
+    request: SourceRequest,
+    /// Hex SHA-256 of the issued token, so the tokens can't be read back
+    /// from memory or from `metrics.sources_path`.
+    token_sha256: String,
+    registered_at: DateTime<Utc>,
+    #[serde(skip)]
+    last_push: Option<DateTime<Utc>>,
+}
+
+impl Registration {
+    fn status(&self, now: DateTime<Utc>) -> SourceStatus {
+        let overdue = self.request.push_interval_secs.is_some_and(|interval| {
+            let since = self.last_push.unwrap_or(self.registered_at);
+            (now - since).num_seconds() > i64::try_from(interval).unwrap_or(i64::MAX)
+        });
+        SourceStatus {
+            source: self.request.source.clone(),
+            push_interval_secs: self.request.push_interval_secs,
+            allowed_prefixes: self.request.allowed_prefixes.clone(),
+            registered_at: self.registered_at,
+            last_push: self.last_push,
+            overdue,
+        }
+    }
+}
+
+/// Sources registered through `POST /api/admin/sources`, each with the API
+/// token it must present and the metric names it may push. Without a file
+/// to keep them in, registrations are lost on restart.
+pub struct SourceRegistrations {
+    sources: RwLock<BTreeMap<String, Registration>>,
+    reject_unregistered: bool,
+    path: Option<PathBuf>,
+    /// Held while a change is saved, so the file is written in the order
+    /// registrations change.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl SourceRegistrations {
+    pub fn new(reject_unregistered: bool) -> Self {
+        Self {
+            sources: RwLock::new(BTreeMap::new()),
+            reject_unregistered,
+            path: None,
+            saving: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Registrations kept in `path`, loading those saved by a previous run.
+    pub fn open(reject_unregistered: bool, path: impl Into<PathBuf>) -> Result<Self, ServerError> {
+        let path = path.into();
+        let sources = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<Vec<Registration>>(&content)?
+                .into_iter()
+                .map(|registration| (registration.request.source.clone(), registration))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(ServerError::InternalError(Box::new(e))),
+        };
+        Ok(Self {
+            sources: RwLock::new(sources),
+            reject_unregistered,
+            path: Some(path),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// The registrations to save, `None` when they're only kept in memory.
+    fn contents(
+        &self,
+        sources: &BTreeMap<String, Registration>,
+    ) -> Result<Option<Vec<u8>>, ServerError> {
+        if self.path.is_none() {
+            return Ok(None);
+        }
+        let registrations: Vec<&Registration> = sources.values().collect();
+        Ok(Some(serde_json::to_vec_pretty(&registrations)?))
+    }
+
+    /// Writes the registrations on the blocking pool, once the lock on them
+    /// is released.
+    async fn save(&self, contents: Option<Vec<u8>>) -> Result<(), ServerError> {
+        let (Some(path), Some(contents)) = (self.path.clone(), contents) else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || write_json(&path, &contents))
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?
+    }
+
+    /// Registers a source, or replaces its registration, issuing a new token.
+    /// Tokens previously issued to the source stop working.
+    pub async fn register(&self, request: SourceRequest) -> Result<IssuedToken, ServerError> {
+        validate_request(&request)?;
+
+        let token = format!("ri_{}", Uuid::new_v4().simple());
+        let registration = Registration {
+            token_sha256: token_digest(&token),
+            registered_at: Utc::now(),
+            last_push: None,
+            request,
+        };
+        let issued = IssuedToken {
+            status: registration.status(registration.registered_at),
+            token,
+        };
+
+        let _saving = self.saving.lock().await;
+        let source = registration.request.source.clone();
+        let (previous, contents) = {
+            let mut sources = self
+                .sources
+                .write()
+                .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+            let previous = sources.insert(source.clone(), registration);
+            (previous, self.contents(&sources))
+        };
+        let saved = match contents {
+            Ok(contents) => self.save(contents).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            if let Ok(mut sources) = self.sources.write() {
+                match previous {
+                    Some(previous) => sources.insert(source, previous),
+                    None => sources.remove(&source),
+                };
+            }
+            return Err(e);
+        }
+        Ok(issued)
+    }
+
+    pub async fn remove(&self, source: &str) -> bool {
+        let _saving = self.saving.lock().await;
+        let contents = {
+            let Ok(mut sources) = self.sources.write() else {
+                return false;
+            };
+            if sources.remove(source).is_none() {
+                return false;
+            }
+            self.contents(&sources)
+        };
+        let saved = match contents {
+            Ok(contents) => self.save(contents).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            error!("Failed to save the source registrations: {}", e);
+        }
+        true
+    }
+
+    pub fn list(&self) -> Vec<SourceStatus> {
+        let now = Utc::now();
+        self.sources
+            .read()
+            .map(|sources| sources.values().map(|r| r.status(now)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_registered(&self, source: &str) -> bool {
+        self.sources
+            .read()
+            .is_ok_and(|sources| sources.contains_key(source))
+    }
+
+    /// Rejects unregistered sources when configured to.
+    pub fn check_registered(&self, source: &str) -> Result<(), ServerError> {
+        let registered = self
+            .sources
+            .read()
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?
+            .contains_key(source);
+        if !registered && self.reject_unregistered {
+            return Err(ServerError::Unauthorized(format!(
+                "source '{}' is not registered",
+                source
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks the token presented for a source over HTTP; unregistered
+    /// sources need none.
+    pub fn authorize(&self, source: &str, token: Option<&str>) -> Result<(), ServerError> {
+        let sources = self
+            .sources
+            .read()
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+        let Some(registration) = sources.get(source) else {
+            return Ok(());
+        };
+
+        match token {
+            Some(token)
+                if constant_time_eq(
+                    token_digest(token).as_bytes(),
+                    registration.token_sha256.as_bytes(),
+                ) =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(ServerError::Unauthorized(format!(
+                "invalid token for source '{}'",
+                source
+            ))),
+            None => Err(ServerError::Unauthorized(format!(
+                "source '{}' requires a token",
+                source
+            ))),
+        }
+    }
+
+    /// Checks a metric name against the prefixes the source may push.
+    pub fn check_name(&self, source: &str, name: &str) -> Result<(), ServerError> {
+        let Ok(sources) = self.sources.read() else {
+            return Ok(());
+        };
+        let Some(registration) = sources.get(source) else {
+            return Ok(());
+        };
+
+        let prefixes = &registration.request.allowed_prefixes;
+        if prefixes.is_empty() || prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+            return Ok(());
+        }
+        Err(ServerError::ValidationError(format!(
+            "source '{}' may not push '{}', allowed prefixes: {}",
+            source,
+            name,
+            prefixes.join(", ")
+        )))
+    }
+
+    /// Records a push from a registered source, returning its time, or `None`
+    /// for unregistered sources.
+    pub fn record_push(&self, source: &str) -> Option<DateTime<Utc>> {
+        let mut sources = self.sources.write().ok()?;
+        let registration = sources.get_mut(source)?;
+        let now = Utc::now();
+        registration.last_push = Some(now);
+        Some(now)
+    }
+}
+
+fn validate_request(request: &SourceRequest) -> Result<(), ServerError> {
+    if request.source.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Source cannot be empty".to_string(),
+        ));
+    }
+    if request.push_interval_secs == Some(0) {
+        return Err(ServerError::ValidationError(
+            "push_interval_secs must be positive".to_string(),
+        ));
+    }
+    if request.allowed_prefixes.iter().any(String::is_empty) {
+        return Err(ServerError::ValidationError(
+            "allowed_prefixes must not contain empty prefixes".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn token_digest(token: &str) -> String {
+    hex::encode(&Sha256::digest(token.as_bytes()))
+}
+
+/// Compares without returning early, so response times don't reveal how
+/// much of a token was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
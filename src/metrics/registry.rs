@@ -1,39 +1,183 @@
 use crate::config::MetricsConfig;
 use crate::errors::ServerError;
-use crate::metrics::types::{Metric, MetricType};
-use prometheus::{
-    CounterVec, Encoder, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder,
-};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::metrics::cardinality::{CardinalityReport, cardinality_report};
+use crate::metrics::catalog::MetadataCatalog;
+use crate::metrics::coalescing::WriteCoalescer;
+use crate::metrics::compaction::CompactionStats;
+use crate::metrics::enrichment::LabelEnricher;
+use crate::metrics::exposition::{escape_metric_name, quote_utf8_names};
+use crate::metrics::handles::{SeriesHandle, SeriesHandles, SeriesMetric, series_hash};
+use crate::metrics::histogram::HistogramFamily;
+use crate::metrics::interner::{Interner, SeriesValues};
+use crate::metrics::memory::MemoryAccounting;
+use crate::metrics::rates::RateTracker;
+use crate::metrics::registrations::SourceRegistrations;
+use crate::metrics::schema::SchemaRegistry;
+use crate::metrics::self_metrics::SelfMetrics;
+use crate::metrics::sources::SourceIndex;
+use crate::metrics::store::{StoreUpdate, StoreWrite, StoredKind};
+use crate::metrics::tombstones::{Tombstone, Tombstones};
+use crate::metrics::types::{GaugeOperation, Metric, MetricType, MetricValue};
+use crate::utils::validation::validate_counter_increment;
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{CounterVec, Encoder, GaugeVec, Opts, Registry, TextEncoder};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Families by full name. Writes replace the whole map, so the hot path reads
+/// a snapshot without locking; registrations are serialized by `registering`.
+type FamilyMap<T> = ArcSwap<HashMap<String, T>>;
+
+/// When a series was last pushed to.
+#[derive(Debug, Clone, Copy)]
+struct LastPush {
+    written: Instant,
+    /// The newest timestamp of the samples of the last push, in Unix
+    /// milliseconds, if they carried any.
+    sample_ms: Option<i64>,
+}
+
+impl LastPush {
+    /// How long ago the series was pushed to, by its samples' own time
+    /// when they carried one.
+    fn age(&self, now: Instant, now_ms: i64) -> Duration {
+        match self.sample_ms {
+            Some(ms) => Duration::from_millis(now_ms.saturating_sub(ms).max(0) as u64),
+            None => now.duration_since(self.written),
+        }
+    }
+}
+
+/// The new series of a batch admitted so far with `admit_pending`, so each
+/// counts once against the memory budget however many of its metrics write it.
+#[derive(Default)]
+pub struct PendingSeries {
+    series: HashSet<(String, SeriesValues)>,
+    bytes: u64,
+}
+
+/// What undoing a write needs to know of the series before it, noted by
+/// `undo_point` for the writes of a strict batch.
+pub struct WriteUndo {
+    new_family: bool,
+    new_series: bool,
+    /// The value of a gauge series before a write setting it.
+    gauge: Option<f64>,
+    /// The series of the state set before in a stateset series' group.
+    state: Option<Arc<SeriesHandle>>,
+}
 
 pub struct MetricsRegistry {
     registry: Arc<Registry>,
-    counters: Arc<RwLock<HashMap<String, CounterVec>>>,
-    gauges: Arc<RwLock<HashMap<String, GaugeVec>>>,
-    histograms: Arc<RwLock<HashMap<String, HistogramVec>>>,
-    label_keys: RwLock<HashMap<String, Vec<String>>>,
+    counters: FamilyMap<CounterVec>,
+    gauges: FamilyMap<GaugeVec>,
+    histograms: FamilyMap<HistogramFamily>,
+    label_keys: FamilyMap<Vec<String>>,
+    handles: FamilyMap<Arc<SeriesHandles>>,
+    registering: Mutex<()>,
+    // Escaped full name -> original UTF-8 full name, only used when utf8_names is enabled
+    utf8_names: StdRwLock<HashMap<String, String>>,
+    enricher: Option<Arc<LabelEnricher>>,
+    self_metrics: SelfMetrics,
+    schemas: SchemaRegistry,
+    catalog: MetadataCatalog,
+    registrations: SourceRegistrations,
+    tombstones: Tombstones,
+    sources: SourceIndex,
+    memory: MemoryAccounting,
+    // Label names and values shared by the per-series indexes below and above
+    interner: Interner,
+    // Full name -> label values -> last push, only tracked when series expire
+    series_seen: StdRwLock<HashMap<String, HashMap<SeriesValues, LastPush>>>,
+    // Set by the first pushed sample with a timestamp
+    timestamped: AtomicBool,
+    // Counter history behind the `:rate_1m` gauges, only kept when derived_rates is enabled
+    rates: Option<RateTracker>,
+    coalescer: Option<WriteCoalescer>,
     config: MetricsConfig,
 }
 
 impl MetricsRegistry {
-    pub fn new(config: MetricsConfig) -> Self {
-        Self {
-            registry: Arc::new(Registry::new()),
-            counters: Arc::new(RwLock::new(HashMap::new())),
-            gauges: Arc::new(RwLock::new(HashMap::new())),
-            histograms: Arc::new(RwLock::new(HashMap::new())),
-            label_keys: RwLock::new(HashMap::new()),
-            config,
+    pub fn new(config: MetricsConfig) -> Result<Self, ServerError> {
+        let registry = Registry::new();
+        let self_metrics = SelfMetrics::register(&registry, &config.self_metrics_labels)?;
+        let coalescer = config.write_coalescing.as_ref().map(|coalescing| {
+            WriteCoalescer::new(coalescing, self_metrics.coalesced_writes_total.clone())
+        });
+        let registrations = match &config.sources_path {
+            Some(path) => SourceRegistrations::open(config.reject_unregistered_sources, path)?,
+            None => SourceRegistrations::new(config.reject_unregistered_sources),
+        };
+        for status in registrations.list() {
+            if let Some(secs) = status.push_interval_secs {
+                self_metrics
+                    .source_push_interval_seconds
+                    .with_label_values(&[&status.source])
+                    .set(secs as f64);
+            }
         }
+
+        Ok(Self {
+            registry: Arc::new(registry),
+            counters: FamilyMap::default(),
+            gauges: FamilyMap::default(),
+            histograms: FamilyMap::default(),
+            label_keys: FamilyMap::default(),
+            handles: FamilyMap::default(),
+            registering: Mutex::new(()),
+            utf8_names: StdRwLock::new(HashMap::new()),
+            enricher: None,
+            self_metrics,
+            schemas: SchemaRegistry::new(&config.schemas, config.unknown_metric_policy),
+            catalog: MetadataCatalog::new(),
+            registrations,
+            tombstones: Tombstones::new(),
+            sources: SourceIndex::new(),
+            memory: MemoryAccounting::new(config.max_memory_bytes),
+            interner: Interner::new(),
+            series_seen: StdRwLock::new(HashMap::new()),
+            timestamped: AtomicBool::new(false),
+            rates: config.derived_rates.then(RateTracker::new),
+            coalescer,
+            config,
+        })
     }
 
-    pub async fn register_metric(&self, metric: &Metric) -> Result<(), ServerError> {
-        let full_name = format!(
-            "{}_{}_{}",
-            self.config.metrics_prefix, self.config.metrics_namespace, metric.name
-        );
+    pub fn self_metrics(&self) -> &SelfMetrics {
+        &self.self_metrics
+    }
+
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.schemas
+    }
+
+    pub fn catalog(&self) -> &MetadataCatalog {
+        &self.catalog
+    }
+
+    pub fn registrations(&self) -> &SourceRegistrations {
+        &self.registrations
+    }
+
+    pub fn with_enricher(mut self, enricher: Arc<LabelEnricher>) -> Self {
+        self.enricher = Some(enricher);
+        self
+    }
+
+    pub fn config(&self) -> &MetricsConfig {
+        &self.config
+    }
+
+    /// Registers the family of `metric`, returning whether it is new.
+    pub async fn register_metric(&self, metric: &Metric) -> Result<bool, ServerError> {
+        let _registering = self.registering.lock().await;
+        let full_name = self.full_name(&metric.family_name());
 
         let mut label_keys: Vec<String> = metric.labels.keys().cloned().collect();
         label_keys.sort();
@@ -42,16 +186,14 @@ impl MetricsRegistry {
 
         match metric.metric_type {
             MetricType::Counter => {
-                self.register_counter(&full_name, &metric.help, label_keys_str)
-                    .await?;
+                self.register_counter(&full_name, &metric.help, label_keys_str)?;
             }
-            MetricType::Gauge => {
-                self.register_gauge(&full_name, &metric.help, label_keys_str)
-                    .await?;
+            MetricType::Gauge | MetricType::Info | MetricType::StateSet => {
+                self.register_gauge(&full_name, &metric.help, label_keys_str)?;
             }
-            MetricType::Histogram => {
-                self.register_histogram(&full_name, &metric.help, label_keys_str)
-                    .await?;
+            MetricType::Histogram | MetricType::Timer => {
+                let buckets = self.buckets_for(metric);
+                self.register_histogram(&full_name, &metric.help, label_keys_str, buckets)?;
             }
             MetricType::Summary => {
                 return Err(ServerError::MetricRegistrationError(
@@ -60,79 +202,937 @@ impl MetricsRegistry {
             }
         }
 
-        let mut label_keys_map = self.label_keys.write().await;
-        label_keys_map.insert(full_name, label_keys);
+        let raw_name = self.raw_full_name(&metric.family_name());
+        if raw_name != full_name {
+            self.utf8_names
+                .write()
+                .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?
+                .insert(full_name.clone(), raw_name);
+        }
 
-        Ok(())
+        // Failed updates of known families come back here. They keep the label
+        // keys their vec was created with, even when pushed with others, and
+        // with them the handles resolved in that label order
+        let new = !self.label_keys.load().contains_key(&full_name);
+        if new {
+            // Before the label keys, which writers look up first
+            insert_family(&self.handles, full_name.clone(), Arc::default());
+            insert_family(&self.label_keys, full_name, label_keys);
+        }
+        self.catalog.observe(metric);
+
+        Ok(new)
     }
 
-    pub async fn update_metric(&self, metric: &Metric) -> Result<(), ServerError> {
-        let full_name = format!(
+    /// The buckets the histogram family of `metric` is registered with.
+    fn buckets_for(&self, metric: &Metric) -> Vec<f64> {
+        let default_buckets = if metric.metric_type == MetricType::Timer {
+            &self.config.timer_buckets
+        } else {
+            &self.config.histogram_buckets
+        };
+        self.schemas
+            .get(&metric.name)
+            .and_then(|schema| schema.buckets)
+            .unwrap_or_else(|| default_buckets.clone())
+    }
+
+    fn raw_full_name(&self, name: &str) -> String {
+        format!(
             "{}_{}_{}",
-            self.config.metrics_prefix, self.config.metrics_namespace, metric.name
-        );
+            self.config.metrics_prefix, self.config.metrics_namespace, name
+        )
+    }
 
-        let label_keys_map = self.label_keys.read().await;
-        let label_keys = label_keys_map.get(&full_name).ok_or_else(|| {
-            ServerError::MetricsProcessingError(format!("Metric '{}' not registered", full_name))
-        })?;
+    /// The name `metric` is exposed under, with the prefix and namespace.
+    pub fn exposed_name(&self, metric: &Metric) -> String {
+        self.raw_full_name(&metric.family_name())
+    }
 
-        let label_values: Vec<&str> = label_keys
-            .iter()
-            .map(|key| metric.labels.get(key).map(|v| v.as_str()).unwrap_or(""))
-            .collect();
+    /// The name the family is registered under, escaped when it isn't a legacy
+    /// Prometheus name and UTF-8 names are enabled.
+    fn full_name(&self, name: &str) -> String {
+        let raw_name = self.raw_full_name(name);
+        if self.config.utf8_names {
+            escape_metric_name(&raw_name)
+        } else {
+            raw_name
+        }
+    }
+
+    pub async fn update_metric(&self, metric: &Metric) -> Result<(), ServerError> {
+        let full_name = self.full_name(&metric.family_name());
+        match &self.coalescer {
+            Some(coalescer) => {
+                coalescer
+                    .write(&full_name, metric, |metrics| {
+                        let mut results = Vec::with_capacity(metrics.len());
+                        self.apply_updates(&full_name, metrics, |result| results.push(result));
+                        results
+                    })
+                    .await
+            }
+            None => {
+                let mut applied = Ok(());
+                self.apply_updates(&full_name, &[metric], |result| applied = result);
+                applied
+            }
+        }
+    }
+
+    /// Applies a run of updates to the family `full_name`, passing the result
+    /// of each to `done`, and takes the last-push index lock once for the run.
+    fn apply_updates(
+        &self,
+        full_name: &str,
+        metrics: &[&Metric],
+        mut done: impl FnMut(Result<(), ServerError>),
+    ) {
+        let label_keys_map = self.label_keys.load();
+        let handles_map = self.handles.load();
+        let (Some(label_keys), Some(handles)) =
+            (label_keys_map.get(full_name), handles_map.get(full_name))
+        else {
+            for _ in metrics {
+                done(Err(ServerError::MetricsProcessingError(format!(
+                    "Metric '{}' not registered",
+                    full_name
+                ))));
+            }
+            return;
+        };
+
+        // Only allocated when series are tracked
+        let mut written = Vec::new();
+        for metric in metrics {
+            let handle = match self.apply_update(full_name, label_keys, handles, metric) {
+                Ok(handle) => handle,
+                Err(e) => {
+                    done(Err(e));
+                    continue;
+                }
+            };
+            done(Ok(()));
+
+            let sample_ms = metric
+                .value
+                .as_slice()
+                .iter()
+                .filter_map(|sample| sample.timestamp)
+                .max();
+            if let Some(ms) = sample_ms {
+                handle.record_sample_ms(ms);
+                self.timestamped.store(true, Ordering::Relaxed);
+            }
+            if self.config.series_ttl_secs.is_some() {
+                written.push((handle, sample_ms));
+            }
+        }
+
+        if !written.is_empty()
+            && let Ok(mut seen) = self.series_seen.write()
+        {
+            let now = Instant::now();
+            let family = match seen.get_mut(full_name) {
+                Some(family) => family,
+                None => seen.entry(full_name.to_string()).or_default(),
+            };
+            for (handle, sample_ms) in written {
+                let last = match family.get_mut(handle.values.as_slice()) {
+                    Some(last) => last,
+                    None => family.entry(handle.values.clone()).or_insert(LastPush {
+                        written: now,
+                        sample_ms: None,
+                    }),
+                };
+                last.written = now;
+                // A late sample doesn't make the series older
+                last.sample_ms =
+                    sample_ms.map(|ms| last.sample_ms.map_or(ms, |newest| newest.max(ms)));
+            }
+        }
+    }
+
+    /// Applies one update, returning the handle of the series it wrote to.
+    fn apply_update(
+        &self,
+        full_name: &str,
+        label_keys: &[String],
+        handles: &SeriesHandles,
+        metric: &Metric,
+    ) -> Result<Arc<SeriesHandle>, ServerError> {
+        let samples = metric.value.as_slice();
+        let label = |key: &String| metric.labels.get(key).map_or("", String::as_str);
+        let hash = series_hash(label_keys.iter().map(label));
+        // Resolves the series on its first write, once admitted to the budget
+        let write = |buckets: usize,
+                     metric: &dyn Fn(&[&str]) -> SeriesMetric,
+                     write: &dyn Fn(&Arc<SeriesHandle>)| {
+            handles.write(
+                hash,
+                label_keys.iter().map(label),
+                || {
+                    let label_values: Vec<&str> = label_keys.iter().map(label).collect();
+                    let values = self.interner.intern_all(&label_values);
+                    let bytes = MemoryAccounting::estimate(label_keys, &values, buckets);
+                    self.admit_series(full_name, bytes)?;
+                    Ok::<_, ServerError>(SeriesHandle::new(values, metric(&label_values), bytes))
+                },
+                write,
+            )
+        };
 
         match metric.metric_type {
             MetricType::Counter => {
-                let counters = self.counters.read().await;
-                if let Some(counter) = counters.get(&full_name) {
-                    let c = counter.with_label_values(&label_values);
-                    c.inc_by(metric.value.value);
-                } else {
+                validate_increments(metric)?;
+
+                let counters = self.counters.load();
+                let Some(counter) = counters.get(full_name) else {
                     return Err(ServerError::MetricsProcessingError(format!(
                         "Counter '{}' not registered",
                         full_name
                     )));
+                };
+                let handle = write(
+                    0,
+                    &|values| SeriesMetric::Counter(counter.with_label_values(values)),
+                    &|handle| {
+                        if let SeriesMetric::Counter(c) = &handle.metric {
+                            for sample in samples {
+                                c.inc_by(sample.value);
+                            }
+                        }
+                    },
+                )?;
+                if let (SeriesMetric::Counter(c), Some(rates)) = (&handle.metric, &self.rates) {
+                    // The label keys are sorted, like the exposed labels
+                    let labels = label_keys
+                        .iter()
+                        .zip(&handle.values)
+                        .map(|(key, value)| (key.as_str(), &**value));
+                    rates.observe(full_name, labels, c.get());
                 }
+                Ok(handle)
             }
-            MetricType::Gauge => {
-                let gauges = self.gauges.read().await;
-                if let Some(gauge) = gauges.get(&full_name) {
-                    let g = gauge.with_label_values(&label_values);
-                    g.set(metric.value.value);
-                } else {
+            MetricType::Gauge | MetricType::Info | MetricType::StateSet => {
+                let gauges = self.gauges.load();
+                let Some(gauge) = gauges.get(full_name) else {
                     return Err(ServerError::MetricsProcessingError(format!(
                         "Gauge '{}' not registered",
                         full_name
                     )));
-                }
+                };
+                write(
+                    0,
+                    &|values| SeriesMetric::Gauge(gauge.with_label_values(values)),
+                    &|handle| {
+                        let SeriesMetric::Gauge(g) = &handle.metric else {
+                            return;
+                        };
+                        match (&metric.metric_type, metric.operation) {
+                            (MetricType::Info, _) => g.set(1.0),
+                            (MetricType::StateSet, _) => {
+                                match label_keys.iter().position(|key| *key == metric.name) {
+                                    Some(state_index) => handles.set_state(state_index, handle),
+                                    None => g.set(1.0),
+                                }
+                            }
+                            (_, operation) => {
+                                for sample in samples {
+                                    match operation {
+                                        GaugeOperation::Set => g.set(sample.value),
+                                        GaugeOperation::Inc | GaugeOperation::Add => {
+                                            g.add(sample.value)
+                                        }
+                                        GaugeOperation::Dec => g.sub(sample.value),
+                                    }
+                                }
+                            }
+                        }
+                    },
+                )
             }
-            MetricType::Histogram => {
-                let histograms = self.histograms.read().await;
-                if let Some(histogram) = histograms.get(&full_name) {
-                    let h = histogram.with_label_values(&label_values);
-                    h.observe(metric.value.value);
-                } else {
+            MetricType::Histogram | MetricType::Timer => {
+                let histograms = self.histograms.load();
+                let Some(histogram) = histograms.get(full_name) else {
                     return Err(ServerError::MetricsProcessingError(format!(
                         "Histogram '{}' not registered",
                         full_name
                     )));
+                };
+                write(
+                    histogram.bounds().len() + 1,
+                    &|_| SeriesMetric::Histogram,
+                    &|handle| {
+                        for sample in samples {
+                            match &sample.histogram {
+                                Some(pushed) => histogram.merge(&handle.values, pushed),
+                                None => histogram.observe(&handle.values, sample.value),
+                            }
+                        }
+                    },
+                )
+            }
+            MetricType::Summary => Err(ServerError::MetricsProcessingError(
+                "Summary metrics are not supported yet".to_string(),
+            )),
+        }
+    }
+
+    /// Runs the checks a write of `metric` to its registered family must pass
+    /// locally, without writing anything: its counter increments and, for a
+    /// new series, the memory budget. With a shared store they run before the
+    /// store is written, so a write refused here isn't counted elsewhere.
+    pub fn admit(&self, metric: &Metric) -> Result<(), ServerError> {
+        validate_increments(metric)?;
+        let full_name = self.full_name(&metric.family_name());
+        if !self.label_keys.load().contains_key(&full_name) {
+            return Err(ServerError::MetricsProcessingError(format!(
+                "Metric '{}' not registered",
+                full_name
+            )));
+        }
+        match self.new_series(metric) {
+            Some((_, bytes)) => self.memory.check(&full_name, bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the checks of `admit` for a metric of a batch checked as a whole
+    /// before any of it is written, its family possibly not registered yet:
+    /// the new series of the metrics admitted before it into `pending` count
+    /// against the memory budget too.
+    pub fn admit_pending(
+        &self,
+        metric: &Metric,
+        pending: &mut PendingSeries,
+    ) -> Result<(), ServerError> {
+        validate_increments(metric)?;
+        let Some((series, bytes)) = self.new_series(metric) else {
+            return Ok(());
+        };
+        if pending.series.contains(&series) {
+            return Ok(());
+        }
+        self.memory.check(&series.0, pending.bytes + bytes)?;
+        pending.series.insert(series);
+        pending.bytes += bytes;
+        Ok(())
+    }
+
+    /// The family and label values of the series `metric` writes to, with
+    /// its estimated memory, if it doesn't exist yet. The label keys of a
+    /// family not registered yet are those it would be registered with.
+    fn new_series(&self, metric: &Metric) -> Option<((String, SeriesValues), u64)> {
+        let full_name = self.full_name(&metric.family_name());
+        let label_keys_map = self.label_keys.load();
+        let label_keys = match label_keys_map.get(&full_name) {
+            Some(label_keys) => label_keys.clone(),
+            None => {
+                let mut label_keys: Vec<String> = metric.labels.keys().cloned().collect();
+                label_keys.sort();
+                label_keys
+            }
+        };
+        let label_values: Vec<&str> = label_keys
+            .iter()
+            .map(|key| metric.labels.get(key).map_or("", String::as_str))
+            .collect();
+        let known = self
+            .handles
+            .load()
+            .get(&full_name)
+            .and_then(|handles| handles.get(label_values.iter().copied()));
+        if known.is_some() {
+            return None;
+        }
+
+        let buckets = match self.histograms.load().get(&full_name) {
+            Some(histogram) => histogram.bounds().len() + 1,
+            None if matches!(
+                metric.metric_type,
+                MetricType::Histogram | MetricType::Timer
+            ) =>
+            {
+                let bounds = self.buckets_for(metric);
+                bounds.iter().filter(|bound| bound.is_finite()).count() + 1
+            }
+            None => 0,
+        };
+        let values = self.interner.intern_all(&label_values);
+        let bytes = MemoryAccounting::estimate(&label_keys, &values, buckets);
+        Some(((full_name, values), bytes))
+    }
+
+    /// Notes what undoing the write of `metric` about to be applied needs.
+    pub fn undo_point(&self, metric: &Metric) -> WriteUndo {
+        let full_name = self.full_name(&metric.family_name());
+        let mut undo = WriteUndo {
+            new_family: false,
+            new_series: true,
+            gauge: None,
+            state: None,
+        };
+        let label_keys_map = self.label_keys.load();
+        let handles_map = self.handles.load();
+        let (Some(label_keys), Some(handles)) =
+            (label_keys_map.get(&full_name), handles_map.get(&full_name))
+        else {
+            undo.new_family = true;
+            return undo;
+        };
+
+        let label_values: Vec<&str> = label_keys
+            .iter()
+            .map(|key| metric.labels.get(key).map_or("", String::as_str))
+            .collect();
+        if metric.metric_type == MetricType::StateSet
+            && let Some(state_index) = label_keys.iter().position(|key| *key == metric.name)
+        {
+            undo.state = handles.active_state(state_index, &label_values);
+        }
+        let Some(handle) = handles.get(label_values.iter().copied()) else {
+            return undo;
+        };
+        undo.new_series = false;
+        if let SeriesMetric::Gauge(gauge) = &handle.metric
+            && (metric.metric_type != MetricType::Gauge || metric.operation == GaugeOperation::Set)
+        {
+            undo.gauge = Some(gauge.get());
+        }
+        undo
+    }
+
+    /// Undoes the write of `metric` noted by `undo`: the series, or family,
+    /// it created is removed, and its samples are taken back from a series
+    /// that existed. Counters only go up, so they are reset to their value
+    /// less the samples, losing an increment landing in between.
+    pub async fn undo(&self, metric: &Metric, undo: WriteUndo) {
+        let full_name = self.full_name(&metric.family_name());
+        let label_keys_map = self.label_keys.load();
+        let handles_map = self.handles.load();
+        let (Some(label_keys), Some(handles)) =
+            (label_keys_map.get(&full_name), handles_map.get(&full_name))
+        else {
+            return;
+        };
+        let label_values: Vec<&str> = label_keys
+            .iter()
+            .map(|key| metric.labels.get(key).map_or("", String::as_str))
+            .collect();
+
+        if let Some(previous) = &undo.state
+            && let Some(state_index) = label_keys.iter().position(|key| *key == metric.name)
+        {
+            handles.set_state(state_index, previous);
+        }
+        if undo.new_series {
+            let values = self.interner.intern_all(&label_values);
+            self.remove_series(&full_name, &values);
+            if let Ok(mut seen) = self.series_seen.write()
+                && let Some(series) = seen.get_mut(&full_name)
+            {
+                series.remove(&values);
+                if series.is_empty() {
+                    seen.remove(&full_name);
                 }
             }
-            MetricType::Summary => {
-                return Err(ServerError::MetricsProcessingError(
-                    "Summary metrics are not supported yet".to_string(),
-                ));
+            if undo.new_family {
+                self.unregister_empty_family(&full_name).await;
+            }
+            return;
+        }
+
+        let Some(handle) = handles.get(label_values.iter().copied()) else {
+            return;
+        };
+        let samples = metric.value.as_slice();
+        let total: f64 = samples.iter().map(|sample| sample.value).sum();
+        match &handle.metric {
+            SeriesMetric::Counter(counter) => {
+                let value = counter.get();
+                counter.reset();
+                counter.inc_by((value - total).max(0.0));
+            }
+            SeriesMetric::Gauge(gauge) => match (undo.gauge, metric.operation) {
+                (Some(value), _) => gauge.set(value),
+                (None, GaugeOperation::Dec) => gauge.add(total),
+                (None, _) => gauge.sub(total),
+            },
+            SeriesMetric::Histogram => {
+                if let Some(histogram) = self.histograms.load().get(&full_name) {
+                    let (counts, sum, count) = observed(histogram.bounds(), samples);
+                    histogram.retract(&handle.values, &counts, sum, count);
+                }
             }
         }
+    }
 
+    /// The write a shared store needs to mirror `metric` for `source`, once
+    /// its family is registered; the samples are folded into a single update.
+    pub fn store_write(&self, source: &str, metric: &Metric) -> Option<StoreWrite> {
+        let family = self.full_name(&metric.family_name());
+        let label_keys = self.label_keys.load().get(&family)?.clone();
+        let labels = label_keys
+            .into_iter()
+            .map(|key| {
+                let value = metric.labels.get(&key).cloned().unwrap_or_default();
+                (key, value)
+            })
+            .collect();
+
+        let samples = metric.value.as_slice();
+        let (kind, bounds, update) = match metric.metric_type {
+            MetricType::Counter => (
+                StoredKind::Counter,
+                Vec::new(),
+                StoreUpdate::Add(samples.iter().map(|sample| sample.value).sum()),
+            ),
+            MetricType::Info => (StoredKind::Gauge, Vec::new(), StoreUpdate::Set(1.0)),
+            MetricType::StateSet => (
+                StoredKind::Gauge,
+                Vec::new(),
+                StoreUpdate::State {
+                    state_label: metric.name.clone(),
+                },
+            ),
+            MetricType::Gauge => {
+                let update = match metric.operation {
+                    GaugeOperation::Set => StoreUpdate::Set(samples.last()?.value),
+                    GaugeOperation::Inc | GaugeOperation::Add => {
+                        StoreUpdate::Add(samples.iter().map(|sample| sample.value).sum())
+                    }
+                    GaugeOperation::Dec => {
+                        StoreUpdate::Add(-samples.iter().map(|sample| sample.value).sum::<f64>())
+                    }
+                };
+                (StoredKind::Gauge, Vec::new(), update)
+            }
+            MetricType::Histogram | MetricType::Timer => {
+                let bounds = self.histograms.load().get(&family)?.bounds().to_vec();
+                let (counts, sum, count) = observed(&bounds, samples);
+                let update = StoreUpdate::Observe { counts, sum, count };
+                (StoredKind::Histogram, bounds, update)
+            }
+            MetricType::Summary => return None,
+        };
+
+        Some(StoreWrite {
+            family,
+            source: source.to_string(),
+            help: metric.help.clone(),
+            kind,
+            bounds,
+            labels,
+            update,
+        })
+    }
+
+    fn admit_series(&self, family: &str, bytes: u64) -> Result<(), ServerError> {
+        self.memory.admit(family, bytes)?;
+        self.record_memory();
         Ok(())
     }
 
+    fn record_memory(&self) {
+        self.self_metrics
+            .registry_memory_bytes
+            .set(self.memory.bytes() as i64);
+    }
+
+    /// Purges the series matched by `due`, the tombstones whose grace period
+    /// is over, and removes series not written for `series_ttl_secs`, then
+    /// unregisters the families left without any series so they stop
+    /// occupying the registry, and drops the interned labels no series uses
+    /// anymore.
+    pub async fn compact(&self, due: &[Tombstone]) -> CompactionStats {
+        let mut stats = CompactionStats::default();
+        self.purge_tombstoned(due, &mut stats).await;
+        if let Some(ttl) = self.config.series_ttl_secs {
+            self.expire_series(ttl, &mut stats).await;
+        }
+        self.interner.purge();
+        self.record_compaction(&stats);
+        stats
+    }
+
+    async fn expire_series(&self, ttl: u64, stats: &mut CompactionStats) {
+        let now = Instant::now();
+        let now_ms = Utc::now().timestamp_millis();
+        let expired = |last: &LastPush| last.age(now, now_ms).as_secs() >= ttl;
+
+        let families: Vec<String> = self.label_keys.load().keys().cloned().collect();
+        let candidates: Vec<(String, SeriesValues)> = {
+            let Ok(seen) = self.series_seen.read() else {
+                return;
+            };
+            seen.iter()
+                .flat_map(|(family, series)| {
+                    series
+                        .iter()
+                        .filter(|(_, last)| expired(last))
+                        .map(|(values, _)| (family.clone(), values.clone()))
+                })
+                .collect()
+        };
+
+        // Pushed to since they were listed, the series are kept
+        for (family, values) in &candidates {
+            let Ok(mut seen) = self.series_seen.write() else {
+                return;
+            };
+            let Some(series) = seen.get_mut(family) else {
+                continue;
+            };
+            if !series.get(values).is_some_and(expired) {
+                continue;
+            }
+            series.remove(values);
+            if series.is_empty() {
+                seen.remove(family);
+            }
+            if self.remove_series(family, values) {
+                stats.expired_series += 1;
+            }
+        }
+
+        for family in families {
+            let tracked = self
+                .series_seen
+                .read()
+                .map_or(true, |seen| seen.contains_key(&family));
+            if !tracked && self.unregister_empty_family(&family).await {
+                stats.reclaimed_families += 1;
+            }
+        }
+    }
+
+    fn record_compaction(&self, stats: &CompactionStats) {
+        self.self_metrics
+            .expired_series_total
+            .inc_by(stats.expired_series as u64);
+        self.self_metrics
+            .purged_series_total
+            .inc_by(stats.purged_series as u64);
+        self.self_metrics
+            .reclaimed_families_total
+            .inc_by(stats.reclaimed_families as u64);
+    }
+
+    /// Removes the series matched by `due`, unregistering the families they
+    /// leave empty.
+    async fn purge_tombstoned(&self, due: &[Tombstone], stats: &mut CompactionStats) {
+        if due.is_empty() {
+            return;
+        }
+
+        let mut touched = HashSet::new();
+        for family in self.registry.gather() {
+            let name = family.get_name();
+            for metric in family.get_metric() {
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|pair| (pair.get_name(), pair.get_value()));
+                if !due
+                    .iter()
+                    .any(|tombstone| tombstone.matches(name, labels.clone()))
+                {
+                    continue;
+                }
+
+                // Exposed labels are sorted by name, like the registered label keys
+                let values: Vec<&str> = metric.get_label().iter().map(|p| p.get_value()).collect();
+                let values = self.interner.intern_all(&values);
+                if self.remove_series(name, &values) {
+                    stats.purged_series += 1;
+                    touched.insert(name.to_string());
+                    if let Ok(mut seen) = self.series_seen.write()
+                        && let Some(series) = seen.get_mut(name)
+                    {
+                        series.remove(&values);
+                    }
+                }
+            }
+        }
+
+        for family in touched {
+            if self.unregister_empty_family(&family).await {
+                stats.reclaimed_families += 1;
+            }
+        }
+    }
+
+    /// Removes one series of a family, and forgets which sources pushed it.
+    fn remove_series(&self, family: &str, values: &[Arc<str>]) -> bool {
+        let values_str: Vec<&str> = values.iter().map(|value| &**value).collect();
+        let remove = || {
+            if let Some(counter) = self.counters.load().get(family) {
+                counter.remove_label_values(&values_str).is_ok()
+            } else if let Some(gauge) = self.gauges.load().get(family) {
+                gauge.remove_label_values(&values_str).is_ok()
+            } else if let Some(histogram) = self.histograms.load().get(family) {
+                histogram.remove_label_values(values)
+            } else {
+                false
+            }
+        };
+        let removed = match self.handles.load().get(family) {
+            Some(handles) => {
+                let (handle, removed) = handles.remove(values, remove);
+                if let Some(handle) = handle {
+                    self.memory.release(handle.bytes);
+                    self.record_memory();
+                }
+                removed
+            }
+            None => remove(),
+        };
+
+        if let Some(keys) = self.label_keys.load().get(family) {
+            let key = keys
+                .iter()
+                .zip(values)
+                .map(|(key, value)| (self.interner.intern(key), value.clone()))
+                .collect();
+            self.sources.remove(family, &key);
+        }
+        removed
+    }
+
+    fn has_series(&self, family: &str) -> bool {
+        let collected = if let Some(counter) = self.counters.load().get(family) {
+            counter.collect()
+        } else if let Some(gauge) = self.gauges.load().get(family) {
+            gauge.collect()
+        } else if let Some(histogram) = self.histograms.load().get(family) {
+            histogram.collect()
+        } else {
+            return false;
+        };
+        collected
+            .iter()
+            .any(|family| !family.get_metric().is_empty())
+    }
+
+    pub fn tombstones(&self) -> &Tombstones {
+        &self.tombstones
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    /// Unregisters the family of `metric` if it has no series, rolling back
+    /// its registration when its first write was rejected.
+    pub async fn discard_family(&self, metric: &Metric) -> bool {
+        let full_name = self.full_name(&metric.family_name());
+        self.unregister_empty_family(&full_name).await
+    }
+
+    /// Unregisters a family if it has no series left, checked once no other
+    /// family can be registered or unregistered.
+    async fn unregister_empty_family(&self, family: &str) -> bool {
+        let _registering = self.registering.lock().await;
+        if self.has_series(family) {
+            return false;
+        }
+        let collector: Box<dyn Collector> =
+            if let Some(counter) = remove_family(&self.counters, family) {
+                Box::new(counter)
+            } else if let Some(gauge) = remove_family(&self.gauges, family) {
+                Box::new(gauge)
+            } else if let Some(histogram) = remove_family(&self.histograms, family) {
+                Box::new(histogram)
+            } else {
+                return false;
+            };
+
+        remove_family(&self.label_keys, family);
+        if let Some(handles) = remove_family(&self.handles, family) {
+            self.memory.release(handles.bytes());
+            self.record_memory();
+        }
+        if let Some(coalescer) = &self.coalescer {
+            coalescer.forget(family);
+        }
+        if let Ok(mut names) = self.utf8_names.write() {
+            names.remove(family);
+        }
+
+        match self.registry.unregister(collector) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Failed to unregister family {}: {}", family, e);
+                false
+            }
+        }
+    }
+
+    /// Checks that a metric could be written without registering anything,
+    /// i.e. its family isn't already registered with another type.
+    pub async fn check_compatible(&self, metric: &Metric) -> Result<(), ServerError> {
+        let full_name = self.full_name(&metric.family_name());
+
+        let registered = if self.counters.load().contains_key(&full_name) {
+            Some(MetricType::Counter)
+        } else if self.gauges.load().contains_key(&full_name) {
+            Some(MetricType::Gauge)
+        } else if self.histograms.load().contains_key(&full_name) {
+            Some(MetricType::Histogram)
+        } else {
+            None
+        };
+
+        match registered {
+            _ if metric.metric_type == MetricType::Summary => {
+                Err(ServerError::MetricRegistrationError(
+                    "Summary metrics are not supported yet".to_string(),
+                ))
+            }
+            Some(metric_type) if metric_type != metric.metric_type.registered_as() => {
+                Err(ServerError::MetricRegistrationError(format!(
+                    "Metric '{}' is already registered as a {:?}",
+                    full_name, metric_type
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn gather_families(&self) -> Vec<MetricFamily> {
+        let mut metric_families = self.registry.gather();
+
+        if let Some(rates) = &self.rates {
+            let derived = rates.derive(&metric_families);
+            metric_families.extend(derived);
+            metric_families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        }
+
+        if let Some(enricher) = &self.enricher {
+            enricher.enrich(&mut metric_families, &self.sources);
+        }
+
+        self.tombstones.hide(metric_families)
+    }
+
+    /// Stamps the series of `families` pushed with timestamps with the newest
+    /// of them, e.g. for the sample history to record when the samples were
+    /// taken rather than when they were gathered.
+    pub fn stamp_sample_times(&self, families: &mut [MetricFamily]) {
+        if !self.timestamped.load(Ordering::Relaxed) {
+            return;
+        }
+        let label_keys = self.label_keys.load();
+        let handles = self.handles.load();
+        for family in families {
+            let (Some(series), Some(keys)) = (
+                handles.get(family.get_name()),
+                label_keys.get(family.get_name()),
+            ) else {
+                continue;
+            };
+            for metric in family.mut_metric().iter_mut() {
+                // Enrichment may have added labels the series isn't keyed by
+                let labels = metric.get_label();
+                let values = keys.iter().map(|key| {
+                    labels
+                        .iter()
+                        .find(|pair| pair.get_name() == key)
+                        .map_or("", |pair| pair.get_value())
+                });
+                let ms = series.get(values).and_then(|handle| handle.sample_ms());
+                if let Some(ms) = ms {
+                    metric.set_timestamp_ms(ms);
+                }
+            }
+        }
+    }
+
+    /// Families read from a shared store, with this replica's own metrics
+    /// (the `rustic_insights_*` self metrics) added, enriched and with the
+    /// tombstoned series hidden like `gather_families`. Derived rates need
+    /// the history of local writes, so they aren't exposed from a store.
+    pub fn with_local_families(&self, mut shared: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        let pushed = self.label_keys.load();
+        let shared_names: HashSet<String> = shared
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        shared.extend(self.registry.gather().into_iter().filter(|family| {
+            !pushed.contains_key(family.get_name()) && !shared_names.contains(family.get_name())
+        }));
+        shared.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        if let Some(enricher) = &self.enricher {
+            enricher.enrich(&mut shared, &self.sources);
+        }
+
+        self.tombstones.hide(shared)
+    }
+
+    pub fn sources(&self) -> &SourceIndex {
+        &self.sources
+    }
+
+    /// Records that `source` pushed the series `metric` was written to.
+    pub async fn record_source(&self, source: &str, metric: &Metric) {
+        let full_name = self.full_name(&metric.family_name());
+        let label_keys = self.label_keys.load();
+        let Some(keys) = label_keys.get(&full_name) else {
+            return;
+        };
+
+        // Same label values update_metric writes, in the sorted order they're exposed in
+        let key = keys
+            .iter()
+            .map(|key| {
+                let value = metric.labels.get(key).map(String::as_str).unwrap_or("");
+                (self.interner.intern(key), self.interner.intern(value))
+            })
+            .collect();
+        self.sources.record(source, &full_name, key);
+    }
+
+    /// Series counts by family, label name and source, from the registry's own
+    /// series (before scrape-time enrichment).
+    pub fn cardinality(&self, limit: usize) -> CardinalityReport {
+        cardinality_report(&self.registry.gather(), self.sources.series_counts(), limit)
+    }
+
+    /// The families holding series pushed by `source`, or `None` if it never pushed any.
+    pub fn gather_source_families(&self, source: &str) -> Option<Vec<MetricFamily>> {
+        let metric_families = self.sources.filter(source, self.registry.gather())?;
+        Some(self.expose(metric_families))
+    }
+
+    /// Enriches pushed families and hides their tombstoned series, as scrapers see them.
+    pub fn expose(&self, mut metric_families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        if let Some(enricher) = &self.enricher {
+            enricher.enrich(&mut metric_families, &self.sources);
+        }
+
+        self.tombstones.hide(metric_families)
+    }
+
     pub fn gather(&self) -> Result<String, ServerError> {
+        self.encode(&self.gather_families(), false)
+    }
+
+    /// Like `gather`, but with UTF-8 names quoted for scrapers that negotiated
+    /// `escaping=allow-utf-8`.
+    pub fn gather_utf8(&self) -> Result<String, ServerError> {
+        self.encode(&self.gather_families(), true)
+    }
+
+    /// Encodes families in the text exposition format, optionally quoting UTF-8 names.
+    pub fn encode(
+        &self,
+        metric_families: &[MetricFamily],
+        utf8: bool,
+    ) -> Result<String, ServerError> {
         let mut buffer = Vec::new();
         let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
 
         if metric_families.is_empty() {
             tracing::warn!("No metrics were gathered from the registry");
@@ -140,28 +1140,38 @@ impl MetricsRegistry {
         }
 
         encoder
-            .encode(&metric_families, &mut buffer)
+            .encode(metric_families, &mut buffer)
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+
+        let exposition = String::from_utf8(buffer)
             .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+        if !utf8 {
+            return Ok(exposition);
+        }
 
-        String::from_utf8(buffer).map_err(|e| ServerError::MetricsProcessingError(e.to_string()))
+        let names = self
+            .utf8_names
+            .read()
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+
+        Ok(quote_utf8_names(&exposition, &names))
     }
 
     pub async fn get_metrics_count(&self) -> Result<usize, ServerError> {
-        let counters_count = self.counters.read().await.len();
-        let gauges_count = self.gauges.read().await.len();
-        let histograms_count = self.histograms.read().await.len();
+        let counters_count = self.counters.load().len();
+        let gauges_count = self.gauges.load().len();
+        let histograms_count = self.histograms.load().len();
 
         Ok(counters_count + gauges_count + histograms_count)
     }
 
-    async fn register_counter(
+    fn register_counter(
         &self,
         name: &str,
         help: &str,
         label_names: Vec<&str>,
     ) -> Result<(), ServerError> {
-        let mut counters = self.counters.write().await;
-        if !counters.contains_key(name) {
+        if !self.counters.load().contains_key(name) {
             let opts = Opts::new(name, help);
             let counter = CounterVec::new(opts, &label_names)
                 .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
@@ -170,19 +1180,18 @@ impl MetricsRegistry {
                 .register(Box::new(counter.clone()))
                 .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
 
-            counters.insert(name.to_string(), counter);
+            insert_family(&self.counters, name.to_string(), counter);
         }
         Ok(())
     }
 
-    async fn register_gauge(
+    fn register_gauge(
         &self,
         name: &str,
         help: &str,
         label_names: Vec<&str>,
     ) -> Result<(), ServerError> {
-        let mut gauges = self.gauges.write().await;
-        if !gauges.contains_key(name) {
+        if !self.gauges.load().contains_key(name) {
             let opts = Opts::new(name, help);
             let gauge = GaugeVec::new(opts, &label_names)
                 .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
@@ -191,29 +1200,81 @@ impl MetricsRegistry {
                 .register(Box::new(gauge.clone()))
                 .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
 
-            gauges.insert(name.to_string(), gauge);
+            insert_family(&self.gauges, name.to_string(), gauge);
         }
         Ok(())
     }
 
-    async fn register_histogram(
+    fn register_histogram(
         &self,
         name: &str,
         help: &str,
         label_names: Vec<&str>,
+        buckets: Vec<f64>,
     ) -> Result<(), ServerError> {
-        let mut histograms = self.histograms.write().await;
-        if !histograms.contains_key(name) {
-            let opts = HistogramOpts::new(name, help);
-            let histogram = HistogramVec::new(opts, &label_names)
-                .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
+        if !self.histograms.load().contains_key(name) {
+            let histogram = HistogramFamily::new(name, help, &label_names, buckets)
+                .map_err(ServerError::MetricRegistrationError)?;
 
             self.registry
                 .register(Box::new(histogram.clone()))
                 .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
 
-            histograms.insert(name.to_string(), histogram);
+            insert_family(&self.histograms, name.to_string(), histogram);
         }
         Ok(())
     }
 }
+
+/// Copy-on-write insert, only called while holding `registering`.
+fn insert_family<T: Clone>(map: &FamilyMap<T>, name: String, family: T) {
+    let mut families = HashMap::clone(&map.load());
+    families.insert(name, family);
+    map.store(Arc::new(families));
+}
+
+/// Copy-on-write removal, only called while holding `registering`.
+fn remove_family<T: Clone>(map: &FamilyMap<T>, name: &str) -> Option<T> {
+    let mut families = HashMap::clone(&map.load());
+    let family = families.remove(name)?;
+    map.store(Arc::new(families));
+    Some(family)
+}
+
+/// Checks the increments of a counter metric.
+fn validate_increments(metric: &Metric) -> Result<(), ServerError> {
+    if metric.metric_type == MetricType::Counter {
+        for sample in metric.value.as_slice() {
+            validate_counter_increment(&metric.name, sample.value)?;
+        }
+    }
+    Ok(())
+}
+
+/// The observations of histogram samples as non-cumulative counts per bound
+/// of `bounds` (the last one for +Inf), with their sum and count.
+fn observed(bounds: &[f64], samples: &[MetricValue]) -> (Vec<f64>, f64, f64) {
+    let mut counts = vec![0.0; bounds.len() + 1];
+    let (mut sum, mut count) = (0.0, 0.0);
+    for sample in samples {
+        match &sample.histogram {
+            Some(pushed) => {
+                for (total, remapped) in counts.iter_mut().zip(pushed.remap(bounds)) {
+                    *total += remapped;
+                }
+                sum += pushed.sum;
+                count += pushed.count;
+            }
+            None => {
+                let index = bounds
+                    .iter()
+                    .position(|bound| sample.value <= *bound)
+                    .unwrap_or(bounds.len());
+                counts[index] += 1.0;
+                sum += sample.value;
+                count += 1.0;
+            }
+        }
+    }
+    (counts, sum, count)
+}
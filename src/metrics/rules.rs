@@ -0,0 +1,103 @@
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use crate::config::RecordingRule;
+use crate::errors::ServerError;
+use crate::metrics::collector::MetricsCollector;
+use crate::metrics::query::{Expr, QuerySample, QueryValue};
+use crate::metrics::types::{GaugeOperation, Metric, MetricType, MetricValue};
+use crate::sinks::samples;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::time::Duration;
+#[cfg(feature = "server")]
+use tracing::{debug, warn};
+
+/// Source recorded for series written by recording rules.
+pub const RULES_SOURCE: &str = "recording_rules";
+
+/// Evaluates `expr` against the current series and writes each result as a
+/// gauge named after `rule.record`, returning the number of series written.
+pub async fn evaluate_rule(
+    collector: &MetricsCollector,
+    rule: &RecordingRule,
+    expr: &Expr,
+) -> Result<usize, ServerError> {
+    let families = collector.gather_exposed().await?.families;
+    let results = match expr.evaluate(&samples(&families)) {
+        QueryValue::Scalar(value) => vec![QuerySample {
+            labels: BTreeMap::new(),
+            value,
+        }],
+        QueryValue::Vector(vector) => vector,
+    };
+
+    // Every series of a family needs the same label names, so labels missing
+    // from some results are written as empty, which Prometheus treats as absent
+    let label_names: BTreeSet<&String> = results
+        .iter()
+        .flat_map(|sample| sample.labels.keys())
+        .chain(rule.labels.keys())
+        .collect();
+
+    for sample in &results {
+        let labels = label_names
+            .iter()
+            .map(|&name| {
+                let value = rule
+                    .labels
+                    .get(name)
+                    .or_else(|| sample.labels.get(name))
+                    .cloned()
+                    .unwrap_or_default();
+                (name.clone(), value)
+            })
+            .collect();
+
+        let metric = Metric {
+            name: rule.record.clone(),
+            metric_type: MetricType::Gauge,
+            help: format!("Recording rule: {}", rule.expr),
+            labels,
+            value: MetricValue {
+                value: sample.value,
+                timestamp: None,
+                enum_value: None,
+                histogram: None,
+            }
+            .into(),
+            operation: GaugeOperation::Set,
+        };
+        collector.record(RULES_SOURCE, &metric).await?;
+    }
+
+    Ok(results.len())
+}
+
+/// Evaluates every configured recording rule on its own interval.
+#[cfg(feature = "server")]
+pub fn spawn_recording_rules(state: Arc<AppState>) {
+    for rule in state.config.recording_rules.clone() {
+        // Expressions were checked when the configuration was validated
+        let expr = match Expr::parse(&rule.expr) {
+            Ok(expr) => expr,
+            Err(e) => {
+                warn!("Skipping recording rule {}: {}", rule.record, e);
+                continue;
+            }
+        };
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(rule.interval_secs));
+            loop {
+                interval.tick().await;
+                match evaluate_rule(&state.metrics_collector, &rule, &expr).await {
+                    Ok(count) => debug!("Recording rule {} wrote {} series", rule.record, count),
+                    Err(e) => warn!("Failed to evaluate recording rule {}: {}", rule.record, e),
+                }
+            }
+        });
+    }
+}
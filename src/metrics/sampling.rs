@@ -0,0 +1,63 @@
+use crate::config::SamplingConfig;
+use crate::metrics::types::Metric;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Applies `metrics.sampling` to the batches of the configured sources:
+/// every `batch_one_in`-th batch is kept, and within kept batches each listed
+/// metric with its configured probability.
+pub struct Sampler {
+    sources: HashMap<String, SourceSampler>,
+}
+
+struct SourceSampler {
+    config: SamplingConfig,
+    batches: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(config: &HashMap<String, SamplingConfig>) -> Self {
+        let sources = config
+            .iter()
+            .map(|(source, config)| {
+                let sampler = SourceSampler {
+                    config: config.clone(),
+                    batches: AtomicU64::new(0),
+                };
+                (source.clone(), sampler)
+            })
+            .collect();
+        Self { sources }
+    }
+
+    /// Whether the next batch from `source` is kept.
+    pub fn keep_batch(&self, source: &str) -> bool {
+        let Some(sampler) = self.sources.get(source) else {
+            return true;
+        };
+        match sampler.config.batch_one_in {
+            Some(n) if n > 1 => sampler.batches.fetch_add(1, Ordering::Relaxed) % n == 0,
+            _ => true,
+        }
+    }
+
+    /// Drops the metrics of a kept batch that lose their draw, returning how many.
+    pub fn sample_metrics(&self, source: &str, metrics: &mut Vec<Metric>) -> usize {
+        let Some(sampler) = self.sources.get(source) else {
+            return 0;
+        };
+        if sampler.config.metrics.is_empty() {
+            return 0;
+        }
+
+        let before = metrics.len();
+        metrics.retain(|metric| {
+            sampler
+                .config
+                .metrics
+                .get(&metric.name)
+                .is_none_or(|probability| fastrand::f64() < *probability)
+        });
+        before - metrics.len()
+    }
+}
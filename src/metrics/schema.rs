@@ -0,0 +1,122 @@
+use crate::config::{MetricSchema, UnknownMetricPolicy};
+use crate::errors::ServerError;
+use crate::metrics::types::{Metric, MetricType};
+use crate::utils::validation::{
+    validate_histogram_buckets, validate_label_names, validate_utf8_metric_name,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+/// Whether a checked metric had a declared schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaMatch {
+    Known,
+    Unknown,
+}
+
+/// Declared metric schemas, seeded from the configuration and extended at
+/// runtime through `POST /api/schemas`.
+pub struct SchemaRegistry {
+    schemas: RwLock<BTreeMap<String, MetricSchema>>,
+    unknown_policy: UnknownMetricPolicy,
+}
+
+impl SchemaRegistry {
+    pub fn new(schemas: &[MetricSchema], unknown_policy: UnknownMetricPolicy) -> Self {
+        Self {
+            schemas: RwLock::new(
+                schemas
+                    .iter()
+                    .map(|schema| (schema.name.clone(), schema.clone()))
+                    .collect(),
+            ),
+            unknown_policy,
+        }
+    }
+
+    pub fn unknown_policy(&self) -> UnknownMetricPolicy {
+        self.unknown_policy
+    }
+
+    /// Adds or replaces a schema. Buckets only apply to histograms registered
+    /// after the schema was declared.
+    pub fn register(&self, schema: MetricSchema) -> Result<(), ServerError> {
+        validate_schema(&schema)?;
+        self.schemas
+            .write()
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?
+            .insert(schema.name.clone(), schema);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<MetricSchema> {
+        self.schemas.read().ok()?.get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<MetricSchema> {
+        self.schemas
+            .read()
+            .map(|schemas| schemas.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Checks a metric against its declared schema. Metrics without one are
+    /// rejected only when the unknown-metric policy says so.
+    pub fn check(&self, metric: &Metric) -> Result<SchemaMatch, ServerError> {
+        let Some(schema) = self.get(&metric.name) else {
+            if self.unknown_policy == UnknownMetricPolicy::Reject {
+                return Err(ServerError::SchemaViolation(format!(
+                    "metric '{}' has no declared schema",
+                    metric.name
+                )));
+            }
+            return Ok(SchemaMatch::Unknown);
+        };
+
+        if schema.metric_type != metric.metric_type {
+            return Err(ServerError::SchemaViolation(format!(
+                "metric '{}' must be a {:?}, got {:?}",
+                metric.name, schema.metric_type, metric.metric_type
+            )));
+        }
+
+        let missing: Vec<&str> = schema
+            .required_labels
+            .iter()
+            .filter(|label| !metric.labels.contains_key(*label))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(ServerError::SchemaViolation(format!(
+                "metric '{}' is missing required labels: {}",
+                metric.name,
+                missing.join(", ")
+            )));
+        }
+
+        Ok(SchemaMatch::Known)
+    }
+}
+
+pub fn validate_schema(schema: &MetricSchema) -> Result<(), ServerError> {
+    validate_utf8_metric_name(&schema.name)?;
+
+    let labels: HashMap<String, String> = schema
+        .required_labels
+        .iter()
+        .map(|label| (label.clone(), String::new()))
+        .collect();
+    validate_label_names(&labels)?;
+
+    if let Some(buckets) = &schema.buckets {
+        if schema.metric_type.registered_as() != MetricType::Histogram {
+            return Err(ServerError::ValidationError(format!(
+                "buckets are only allowed for histograms, '{}' is a {:?}",
+                schema.name, schema.metric_type
+            )));
+        }
+        validate_histogram_buckets(buckets)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Transform scripts in Rhai, a lighter way than plugins to customize
+//! ingestion. A script defines `transform(metric)`, called with the metric as
+//! a map shaped like its JSON (`metric.labels.env`, `metric.value.value`), and
+//! returns the metric to write, possibly modified, or `()` to drop it.
+//!
+//! Scripts can't reach the filesystem or the network, `eval` is disabled,
+//! and every call is bounded in operations and time. Like plugins, they run
+//! on tokio's blocking threads.
+
+use crate::config::ScriptConfig;
+use crate::errors::ServerError;
+use crate::metrics::types::Metric;
+#[cfg(feature = "rhai")]
+use std::sync::Arc;
+
+/// The configured scripts, run in order. Clones share the scripts.
+#[derive(Default, Clone)]
+pub struct Scripts {
+    #[cfg(feature = "rhai")]
+    scripts: Arc<Vec<rhai_engine::Script>>,
+}
+
+impl Scripts {
+    /// Compiles every script, failing on the first that doesn't compile or
+    /// lacks a `transform(metric)` function.
+    pub fn load(configs: &[ScriptConfig]) -> Result<Self, ServerError> {
+        #[cfg(feature = "rhai")]
+        {
+            let scripts = configs
+                .iter()
+                .map(rhai_engine::Script::load)
+                .collect::<Result<_, _>>()?;
+            Ok(Self {
+                scripts: Arc::new(scripts),
+            })
+        }
+        #[cfg(not(feature = "rhai"))]
+        match configs.first() {
+            Some(config) => Err(ServerError::ConfigurationError(format!(
+                "Script '{}' requires building with the `rhai` feature",
+                config.name
+            ))),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        #[cfg(feature = "rhai")]
+        return self.scripts.is_empty();
+        #[cfg(not(feature = "rhai"))]
+        true
+    }
+
+    /// Runs the scripts transforming `source` on `metric`, returning the name
+    /// of the script that dropped it, if one did. This blocks while they run.
+    #[cfg_attr(not(feature = "rhai"), allow(unused_variables))]
+    pub fn apply(&self, source: &str, metric: &mut Metric) -> Result<Option<String>, ServerError> {
+        #[cfg(feature = "rhai")]
+        for script in self.scripts.iter() {
+            if !script.transforms(source) {
+                continue;
+            }
+            match script.call(metric)? {
+                Some(transformed) => *metric = transformed,
+                None => return Ok(Some(script.name().to_string())),
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "rhai")]
+mod rhai_engine {
+    use super::{Metric, ScriptConfig, ServerError};
+    use rhai::{AST, Dynamic, Engine, Scope};
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+    use tracing::debug;
+
+    /// Operations between checks of the deadline.
+    const DEADLINE_CHECK_INTERVAL: u64 = 256;
+
+    thread_local! {
+        // When the call running on this thread must stop
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    pub(super) struct Script {
+        config: ScriptConfig,
+        engine: Engine,
+        ast: AST,
+    }
+
+    impl Script {
+        pub(super) fn load(config: &ScriptConfig) -> Result<Self, ServerError> {
+            let fail = |e: String| {
+                ServerError::ConfigurationError(format!("script '{}': {}", config.name, e))
+            };
+            let engine = engine(config);
+            let ast = engine
+                .compile_file(config.path.clone().into())
+                .map_err(|e| fail(e.to_string()))?;
+            if !ast
+                .iter_functions()
+                .any(|f| f.name == "transform" && f.params.len() == 1)
+            {
+                return Err(fail("no transform(metric) function".to_string()));
+            }
+            Ok(Self {
+                config: config.clone(),
+                engine,
+                ast,
+            })
+        }
+
+        pub(super) fn name(&self) -> &str {
+            &self.config.name
+        }
+
+        pub(super) fn transforms(&self, source: &str) -> bool {
+            self.config.sources.is_empty() || self.config.sources.iter().any(|s| s == source)
+        }
+
+        /// The transformed metric, or `None` when the script drops it.
+        pub(super) fn call(&self, metric: &Metric) -> Result<Option<Metric>, ServerError> {
+            let fail = |e: String| {
+                ServerError::MetricsProcessingError(format!("script '{}': {}", self.name(), e))
+            };
+            let argument = rhai::serde::to_dynamic(metric).map_err(|e| fail(e.to_string()))?;
+
+            let timeout = Duration::from_millis(self.config.timeout_ms);
+            DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + timeout)));
+            let result = self.engine.call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &self.ast,
+                "transform",
+                (argument,),
+            );
+            DEADLINE.with(|deadline| deadline.set(None));
+
+            let returned = result.map_err(|e| fail(e.to_string()))?;
+            if returned.is_unit() {
+                return Ok(None);
+            }
+            rhai::serde::from_dynamic(&returned)
+                .map(Some)
+                .map_err(|e| fail(format!("invalid metric returned: {}", e)))
+        }
+    }
+
+    fn engine(config: &ScriptConfig) -> Engine {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(config.max_operations)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(64 * 1024)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            .disable_symbol("eval");
+
+        let name = config.name.clone();
+        engine.on_print(move |text| debug!("Script {}: {}", name, text));
+        let name = config.name.clone();
+        engine
+            .on_debug(move |text, _, position| debug!("Script {} at {}: {}", name, position, text));
+        engine.on_progress(|operations| {
+            if operations % DEADLINE_CHECK_INTERVAL != 0 {
+                return None;
+            }
+            let expired = DEADLINE
+                .with(|deadline| deadline.get())
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            expired.then(|| Dynamic::from("timed out"))
+        });
+        engine
+    }
+}
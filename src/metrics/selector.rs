@@ -0,0 +1,228 @@
+use crate::errors::ServerError;
+use crate::metrics::exposition::escape_metric_name;
+use prometheus::proto::MetricFamily;
+use regex::Regex;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+impl fmt::Display for MatchOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MatchOp::Equal => "=",
+            MatchOp::NotEqual => "!=",
+            MatchOp::Regex => "=~",
+            MatchOp::NotRegex => "!~",
+        })
+    }
+}
+
+/// A single `label<op>"value"` matcher. A missing label matches as the empty string.
+#[derive(Debug, Clone)]
+pub struct LabelMatcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl LabelMatcher {
+    pub fn new(name: &str, op: MatchOp, value: &str) -> Result<Self, ServerError> {
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => Some(
+                // Like Prometheus, regex matchers are anchored at both ends
+                Regex::new(&format!("^(?:{})$", value)).map_err(|e| {
+                    ServerError::ValidationError(format!("Invalid regex '{}': {}", value, e))
+                })?,
+            ),
+            MatchOp::Equal | MatchOp::NotEqual => None,
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            op,
+            value: value.to_string(),
+            regex,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match (&self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::Regex, Some(regex)) => regex.is_match(value),
+            (MatchOp::NotRegex, Some(regex)) => !regex.is_match(value),
+            _ => false,
+        }
+    }
+}
+
+/// A Prometheus series selector such as `http_requests_total{code=~"5..",job!="batch"}`.
+/// The metric name, if any, is kept as a `__name__` matcher.
+#[derive(Debug, Clone)]
+pub struct SeriesSelector {
+    pub matchers: Vec<LabelMatcher>,
+}
+
+impl SeriesSelector {
+    pub fn parse(input: &str) -> Result<Self, ServerError> {
+        let invalid = |reason: &str| {
+            ServerError::ValidationError(format!("Invalid selector '{}': {}", input, reason))
+        };
+
+        let input = input.trim();
+        let (name, rest) = match input.find('{') {
+            Some(start) => (input[..start].trim(), &input[start..]),
+            None => (input, ""),
+        };
+
+        let mut matchers = Vec::new();
+        if !name.is_empty() {
+            matchers.push(LabelMatcher::new("__name__", MatchOp::Equal, name)?);
+        }
+
+        if !rest.is_empty() {
+            let body = rest
+                .strip_prefix('{')
+                .and_then(|rest| rest.strip_suffix('}'))
+                .ok_or_else(|| invalid("unbalanced braces"))?;
+            matchers.extend(parse_matchers(body).map_err(|reason| invalid(&reason))?);
+        }
+
+        if matchers.is_empty() {
+            return Err(invalid("at least one matcher is required"));
+        }
+        if matchers.iter().all(|m| m.matches("")) {
+            return Err(invalid(
+                "at least one matcher must not match the empty string",
+            ));
+        }
+
+        Ok(Self { matchers })
+    }
+
+    /// Whether a series with the given family name and labels is selected.
+    pub fn matches<'a>(
+        &self,
+        name: &str,
+        labels: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    ) -> bool {
+        self.matchers.iter().all(|matcher| {
+            let value = if matcher.name == "__name__" {
+                name
+            } else {
+                labels
+                    .clone()
+                    .find(|(label, _)| *label == matcher.name)
+                    .map(|(_, value)| value)
+                    .unwrap_or("")
+            };
+            matcher.matches(value)
+        })
+    }
+}
+
+fn parse_matchers(body: &str) -> Result<Vec<LabelMatcher>, String> {
+    let mut matchers = Vec::new();
+    let mut chars = body.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if name.is_empty() {
+            return Err("expected a label name".to_string());
+        }
+
+        let op = match (chars.next(), chars.peek()) {
+            (Some('='), Some('~')) => MatchOp::Regex,
+            (Some('='), _) => MatchOp::Equal,
+            (Some('!'), Some('=')) => MatchOp::NotEqual,
+            (Some('!'), Some('~')) => MatchOp::NotRegex,
+            _ => return Err(format!("expected a match operator after '{}'", name)),
+        };
+        if op != MatchOp::Equal {
+            chars.next();
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let quote = chars
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| format!("expected a quoted value for '{}'", name))?;
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c) => value.push(c),
+                    None => return Err("unterminated escape".to_string()),
+                },
+                Some(c) if c == quote => break,
+                Some(c) => value.push(c),
+                None => return Err(format!("unterminated value for '{}'", name)),
+            }
+        }
+
+        matchers.push(LabelMatcher::new(&name, op, &value).map_err(|e| e.to_string())?);
+    }
+
+    Ok(matchers)
+}
+
+/// Keeps the families listed in `names` (all of them when empty) and, when
+/// selectors are given, only the series matched by at least one of them.
+pub fn filter_families(
+    families: Vec<MetricFamily>,
+    names: &[String],
+    selectors: &[SeriesSelector],
+) -> Vec<MetricFamily> {
+    families
+        .into_iter()
+        .filter(|family| {
+            names.is_empty()
+                || names.iter().any(|name| {
+                    family.get_name() == name || family.get_name() == escape_metric_name(name)
+                })
+        })
+        .filter_map(|mut family| {
+            if selectors.is_empty() {
+                return Some(family);
+            }
+
+            let name = family.get_name().to_string();
+            let metrics: Vec<_> = family
+                .take_metric()
+                .into_iter()
+                .filter(|metric| {
+                    let labels = metric
+                        .get_label()
+                        .iter()
+                        .map(|pair| (pair.get_name(), pair.get_value()));
+                    selectors
+                        .iter()
+                        .any(|selector| selector.matches(&name, labels.clone()))
+                })
+                .collect();
+
+            if metrics.is_empty() {
+                return None;
+            }
+            family.set_metric(metrics.into());
+            Some(family)
+        })
+        .collect()
+}
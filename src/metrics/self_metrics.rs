@@ -0,0 +1,245 @@
+use crate::errors::ServerError;
+use prometheus::{GaugeVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use std::collections::HashMap;
+
+/// Metrics describing the server itself, exposed alongside pushed metrics
+/// under the fixed `rustic_insights_` prefix.
+#[derive(Clone)]
+pub struct SelfMetrics {
+    pub batches_total: IntCounter,
+    pub ingested_metrics_total: IntCounter,
+    pub rejected_metrics_total: IntCounterVec,
+    pub unknown_metrics_total: IntCounter,
+    pub canonicalized_metrics_total: IntCounter,
+    pub dropped_samples_total: IntCounterVec,
+    pub sampled_out_total: IntCounterVec,
+    pub deduplicated_samples_total: IntCounterVec,
+    pub source_clock_skew_seconds: GaugeVec,
+    pub clock_skew_corrections_total: IntCounterVec,
+    pub schema_drifts_total: IntCounterVec,
+    pub fanout_batches_total: IntCounterVec,
+    pub shed_requests_total: IntCounterVec,
+    pub tls_reloads_total: IntCounterVec,
+    pub acme_renewals_total: IntCounterVec,
+    pub auth_failures_total: IntCounterVec,
+    pub source_last_push_timestamp_seconds: GaugeVec,
+    pub source_push_interval_seconds: GaugeVec,
+    pub expired_series_total: IntCounter,
+    pub purged_series_total: IntCounter,
+    pub reclaimed_families_total: IntCounter,
+    pub registry_memory_bytes: IntGauge,
+    pub coalesced_writes_total: IntCounter,
+    pub spill_quarantined_payloads_total: IntCounterVec,
+}
+
+impl SelfMetrics {
+    pub fn register(
+        registry: &Registry,
+        const_labels: &HashMap<String, String>,
+    ) -> Result<Self, ServerError> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(format!("rustic_insights_{}", name), help).const_labels(const_labels.clone())
+        };
+
+        let self_metrics = Self {
+            batches_total: IntCounter::with_opts(opts(
+                "batches_total",
+                "Metric batches received for processing",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            ingested_metrics_total: IntCounter::with_opts(opts(
+                "ingested_metrics_total",
+                "Metrics successfully written to the registry",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            rejected_metrics_total: IntCounterVec::new(
+                opts(
+                    "rejected_metrics_total",
+                    "Metrics rejected during processing",
+                ),
+                &["reason"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            unknown_metrics_total: IntCounter::with_opts(opts(
+                "unknown_metrics_total",
+                "Metrics accepted without a declared schema",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            canonicalized_metrics_total: IntCounter::with_opts(opts(
+                "canonicalized_metrics_total",
+                "Metrics whose label values were rewritten to canonical form, merging them with equivalent series",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            dropped_samples_total: IntCounterVec::new(
+                opts(
+                    "dropped_samples_total",
+                    "Samples discarded at ingest by a drop policy",
+                ),
+                &["reason"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            sampled_out_total: IntCounterVec::new(
+                opts(
+                    "sampled_out_total",
+                    "Metrics discarded by the sampling configured for their source",
+                ),
+                &["source"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            deduplicated_samples_total: IntCounterVec::new(
+                opts(
+                    "deduplicated_samples_total",
+                    "Samples dropped as pushed again within the dedup window of their source",
+                ),
+                &["source"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            source_clock_skew_seconds: GaugeVec::new(
+                opts(
+                    "source_clock_skew_seconds",
+                    "Offset of each source's clock from the server's in its last timestamped batch, positive when ahead",
+                ),
+                &["source"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            clock_skew_corrections_total: IntCounterVec::new(
+                opts(
+                    "clock_skew_corrections_total",
+                    "Batches whose timestamps were shifted for a clock skew beyond the maximum of their source",
+                ),
+                &["source"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            schema_drifts_total: IntCounterVec::new(
+                opts(
+                    "schema_drifts_total",
+                    "Changes of the label names a source pushes a metric with",
+                ),
+                &["source", "metric"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            fanout_batches_total: IntCounterVec::new(
+                opts(
+                    "fanout_batches_total",
+                    "Batches handed to each fan-out destination, by outcome (delivered, failed, dropped)",
+                ),
+                &["sink", "outcome"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            shed_requests_total: IntCounterVec::new(
+                opts(
+                    "shed_requests_total",
+                    "Requests answered with a 503 because their route class was at its concurrency limit",
+                ),
+                &["class"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            tls_reloads_total: IntCounterVec::new(
+                opts(
+                    "tls_reloads_total",
+                    "Reloads of the served TLS certificate after its files changed, by outcome (success, failure)",
+                ),
+                &["outcome"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            acme_renewals_total: IntCounterVec::new(
+                opts(
+                    "acme_renewals_total",
+                    "Renewals of the served certificate from the ACME CA, by outcome (success, failure)",
+                ),
+                &["outcome"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            auth_failures_total: IntCounterVec::new(
+                opts(
+                    "auth_failures_total",
+                    "Requests answered with a 401, by the credential presented (api_key, basic, bearer, none)",
+                ),
+                &["credential"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            source_last_push_timestamp_seconds: GaugeVec::new(
+                opts(
+                    "source_last_push_timestamp_seconds",
+                    "Unix time of the last batch from each registered source",
+                ),
+                &["source"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            source_push_interval_seconds: GaugeVec::new(
+                opts(
+                    "source_push_interval_seconds",
+                    "Expected push interval of each registered source",
+                ),
+                &["source"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            expired_series_total: IntCounter::with_opts(opts(
+                "expired_series_total",
+                "Series removed after not being written for the series TTL",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            purged_series_total: IntCounter::with_opts(opts(
+                "purged_series_total",
+                "Tombstoned series removed once their grace period was over",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            reclaimed_families_total: IntCounter::with_opts(opts(
+                "reclaimed_families_total",
+                "Metric families unregistered by compaction after losing all their series",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            registry_memory_bytes: IntGauge::with_opts(opts(
+                "registry_memory_bytes",
+                "Estimated memory held by pushed series, checked against metrics.max_memory_bytes",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            coalesced_writes_total: IntCounter::with_opts(opts(
+                "coalesced_writes_total",
+                "Writes queued behind a concurrent write to the same family and applied in its run",
+            ))
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+            spill_quarantined_payloads_total: IntCounterVec::new(
+                opts(
+                    "spill_quarantined_payloads_total",
+                    "Spilled payloads moved to quarantine because they couldn't be read",
+                ),
+                &["sink"],
+            )
+            .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?,
+        };
+
+        for collector in [
+            Box::new(self_metrics.batches_total.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(self_metrics.ingested_metrics_total.clone()),
+            Box::new(self_metrics.rejected_metrics_total.clone()),
+            Box::new(self_metrics.unknown_metrics_total.clone()),
+            Box::new(self_metrics.canonicalized_metrics_total.clone()),
+            Box::new(self_metrics.dropped_samples_total.clone()),
+            Box::new(self_metrics.sampled_out_total.clone()),
+            Box::new(self_metrics.deduplicated_samples_total.clone()),
+            Box::new(self_metrics.source_clock_skew_seconds.clone()),
+            Box::new(self_metrics.clock_skew_corrections_total.clone()),
+            Box::new(self_metrics.schema_drifts_total.clone()),
+            Box::new(self_metrics.fanout_batches_total.clone()),
+            Box::new(self_metrics.shed_requests_total.clone()),
+            Box::new(self_metrics.tls_reloads_total.clone()),
+            Box::new(self_metrics.acme_renewals_total.clone()),
+            Box::new(self_metrics.auth_failures_total.clone()),
+            Box::new(self_metrics.source_last_push_timestamp_seconds.clone()),
+            Box::new(self_metrics.source_push_interval_seconds.clone()),
+            Box::new(self_metrics.expired_series_total.clone()),
+            Box::new(self_metrics.purged_series_total.clone()),
+            Box::new(self_metrics.reclaimed_families_total.clone()),
+            Box::new(self_metrics.registry_memory_bytes.clone()),
+            Box::new(self_metrics.coalesced_writes_total.clone()),
+            Box::new(self_metrics.spill_quarantined_payloads_total.clone()),
+        ] {
+            registry
+                .register(collector)
+                .map_err(|e| ServerError::MetricRegistrationError(e.to_string()))?;
+        }
+
+        Ok(self_metrics)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::metrics::types::Metric;
+use std::collections::HashMap;
+
+/// The clock offset of a batch, measured from its newest sample timestamp
+/// against the time the batch was received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// Positive when the source's clock is ahead of the server's.
+    pub offset_ms: i64,
+    /// Whether the timestamps of the batch were shifted by `offset_ms`.
+    pub corrected: bool,
+}
+
+/// Measures the clock skew of the sources pushing timestamped samples and,
+/// beyond `metrics.max_clock_skew_secs`, shifts their timestamps back to the
+/// server's clock, keeping the intervals between them.
+pub struct SkewCorrector {
+    max_skew_ms: Option<i64>,
+    source_max_skew_ms: HashMap<String, i64>,
+}
+
+impl SkewCorrector {
+    pub fn new(max_skew_secs: Option<u64>, source_max_skew_secs: &HashMap<String, u64>) -> Self {
+        let millis = |secs: u64| i64::try_from(secs.saturating_mul(1_000)).unwrap_or(i64::MAX);
+        Self {
+            max_skew_ms: max_skew_secs.map(millis),
+            source_max_skew_ms: source_max_skew_secs
+                .iter()
+                .map(|(source, secs)| (source.clone(), millis(*secs)))
+                .collect(),
+        }
+    }
+
+    fn max_skew_ms(&self, source: &str) -> Option<i64> {
+        self.source_max_skew_ms
+            .get(source)
+            .copied()
+            .or(self.max_skew_ms)
+            .filter(|max| *max > 0)
+    }
+
+    /// The skew of `source` on `metrics` received at `received_ms`, in Unix
+    /// milliseconds, correcting their timestamps when it's too large. `None`
+    /// when no sample has a timestamp.
+    pub fn observe(
+        &self,
+        source: &str,
+        metrics: &mut [Metric],
+        received_ms: i64,
+    ) -> Option<ClockSkew> {
+        let newest = metrics
+            .iter()
+            .flat_map(|metric| metric.value.as_slice())
+            .filter_map(|sample| sample.timestamp)
+            .max()?;
+        let offset_ms = newest.saturating_sub(received_ms);
+        let corrected = self
+            .max_skew_ms(source)
+            .is_some_and(|max| offset_ms.saturating_abs() > max);
+        if corrected {
+            for sample in metrics
+                .iter_mut()
+                .flat_map(|metric| metric.value.as_mut_slice())
+            {
+                if let Some(timestamp) = &mut sample.timestamp {
+                    *timestamp = timestamp.saturating_sub(offset_ms);
+                }
+            }
+        }
+        Some(ClockSkew {
+            offset_ms,
+            corrected,
+        })
+    }
+}
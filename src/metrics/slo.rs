@@ -0,0 +1,361 @@
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use crate::config::{SloConfig, SloIndicator};
+use crate::errors::ServerError;
+use crate::metrics::collector::MetricsCollector;
+use crate::metrics::query::{Expr, QueryValue};
+use crate::metrics::types::{GaugeOperation, Metric, MetricType, MetricValue};
+use crate::notify::Alert;
+use crate::sinks::{Sample, samples};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+#[cfg(feature = "server")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(feature = "server")]
+use tracing::debug;
+use tracing::warn;
+
+/// Source recorded for the burn rate and error budget series.
+pub const SLO_SOURCE: &str = "slo";
+/// Windows burn rates are reported over, next to the whole SLO window.
+const BURN_RATE_WINDOWS: [(&str, Duration); 2] = [
+    ("5m", Duration::from_secs(300)),
+    ("1h", Duration::from_secs(3600)),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SloStatus {
+    pub name: String,
+    pub objective: f64,
+    /// Good and total events over the SLO window.
+    pub good: f64,
+    pub total: f64,
+    /// How fast the error budget is spent, 1 meaning exactly on budget, by window.
+    pub burn_rates: BTreeMap<String, f64>,
+    /// Fraction of the window's error budget left, negative once overspent.
+    pub error_budget_remaining: f64,
+}
+
+enum Indicator {
+    Ratio { good: Expr, total: Expr },
+    Latency { histogram: String, threshold: f64 },
+}
+
+/// Cumulative good and total counts of one SLO, sampled on every evaluation.
+struct Tracker {
+    config: SloConfig,
+    indicator: Indicator,
+    history: Mutex<VecDeque<(Instant, f64, f64)>>,
+    last: Mutex<Option<SloStatus>>,
+    // Whether the burn rate alert fired on the previous evaluation
+    burning: AtomicBool,
+}
+
+/// Evaluates the SLOs listed in `slos` and keeps their latest status.
+pub struct SloTracker {
+    trackers: Vec<Tracker>,
+}
+
+impl SloTracker {
+    pub fn new(configs: &[SloConfig]) -> Self {
+        let now = Instant::now();
+        let trackers = configs
+            .iter()
+            .filter_map(|config| {
+                let indicator = match &config.indicator {
+                    SloIndicator::Ratio { good, total } => {
+                        // Expressions were checked when the configuration was validated
+                        match (Expr::parse(good), Expr::parse(total)) {
+                            (Ok(good), Ok(total)) => Indicator::Ratio { good, total },
+                            (Err(e), _) | (_, Err(e)) => {
+                                warn!("Skipping SLO {}: {}", config.name, e);
+                                return None;
+                            }
+                        }
+                    }
+                    SloIndicator::Latency {
+                        histogram,
+                        threshold,
+                    } => Indicator::Latency {
+                        histogram: histogram.clone(),
+                        threshold: *threshold,
+                    },
+                };
+
+                Some(Tracker {
+                    config: config.clone(),
+                    indicator,
+                    // Counters start from zero when the server starts
+                    history: Mutex::new(VecDeque::from([(now, 0.0, 0.0)])),
+                    last: Mutex::new(None),
+                    burning: AtomicBool::new(false),
+                })
+            })
+            .collect();
+
+        Self { trackers }
+    }
+
+    /// Samples every SLO, records its derived metrics and returns the new statuses.
+    pub async fn evaluate(
+        &self,
+        collector: &MetricsCollector,
+    ) -> Result<Vec<SloStatus>, ServerError> {
+        let mut statuses = Vec::with_capacity(self.trackers.len());
+        for tracker in &self.trackers {
+            statuses.push(tracker.evaluate_and_record(collector).await?);
+        }
+        Ok(statuses)
+    }
+
+    /// The latest status of each SLO evaluated at least once.
+    pub fn statuses(&self) -> Vec<SloStatus> {
+        self.trackers
+            .iter()
+            .filter_map(|tracker| tracker.last.lock().ok()?.clone())
+            .collect()
+    }
+}
+
+impl Tracker {
+    async fn evaluate_and_record(
+        &self,
+        collector: &MetricsCollector,
+    ) -> Result<SloStatus, ServerError> {
+        let families = collector.gather_exposed().await?.families;
+        let status = self.evaluate(&samples(&families));
+        record_status(collector, &status).await?;
+        self.check_alert(collector, &status);
+        Ok(status)
+    }
+
+    /// Notifies once when both short windows start burning faster than the
+    /// alert's threshold, and again only after they have recovered.
+    fn check_alert(&self, collector: &MetricsCollector, status: &SloStatus) {
+        let Some(alert) = &self.config.alert else {
+            return;
+        };
+        let burning = BURN_RATE_WINDOWS
+            .iter()
+            .all(|(window, _)| status.burn_rates.get(*window) > Some(&alert.burn_rate));
+        let was_burning = self.burning.swap(burning, Ordering::Relaxed);
+        if !burning || was_burning {
+            return;
+        }
+
+        let values = status
+            .burn_rates
+            .iter()
+            .map(|(window, rate)| (format!("burn_rate_{}", window), format!("{:.2}", rate)))
+            .chain([(
+                "error_budget_remaining".to_string(),
+                format!("{:.3}", status.error_budget_remaining),
+            )])
+            .collect();
+        let notification = Alert {
+            rule: format!("slo:{}", status.name),
+            severity: alert.severity,
+            summary: format!(
+                "SLO {} is burning its error budget {:.1}x too fast ({:.1}% left)",
+                status.name,
+                status.burn_rates.get("1h").copied().unwrap_or_default(),
+                status.error_budget_remaining * 100.0
+            ),
+            labels: BTreeMap::from([("slo".to_string(), status.name.clone())]),
+            values,
+        };
+        collector.notifier().notify(&alert.notify, &notification);
+    }
+
+    fn evaluate(&self, samples: &[Sample]) -> SloStatus {
+        let (good, total) = self.counts(samples);
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_secs);
+        let budget = 1.0 - self.config.objective;
+
+        let mut burn_rates = BTreeMap::new();
+        let (window_good, window_total) = match self.history.lock() {
+            Ok(mut history) => {
+                history.push_back((now, good, total));
+                // Keep the newest sample from before the window as its base
+                while history.len() > 1 && now.duration_since(history[1].0) >= window {
+                    history.pop_front();
+                }
+
+                for (name, duration) in BURN_RATE_WINDOWS {
+                    let (good, total) = increase(&history, now, duration);
+                    burn_rates.insert(name.to_string(), error_ratio(good, total) / budget);
+                }
+                increase(&history, now, window)
+            }
+            Err(_) => (0.0, 0.0),
+        };
+
+        let spent = error_ratio(window_good, window_total) / budget;
+        burn_rates.insert("window".to_string(), spent);
+        let status = SloStatus {
+            name: self.config.name.clone(),
+            objective: self.config.objective,
+            good: window_good,
+            total: window_total,
+            burn_rates,
+            error_budget_remaining: 1.0 - spent,
+        };
+
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some(status.clone());
+        }
+        status
+    }
+
+    fn counts(&self, samples: &[Sample]) -> (f64, f64) {
+        match &self.indicator {
+            Indicator::Ratio { good, total } => {
+                (sum(good.evaluate(samples)), sum(total.evaluate(samples)))
+            }
+            Indicator::Latency {
+                histogram,
+                threshold,
+            } => {
+                let bucket = format!("{}_bucket", histogram);
+                let count = format!("{}_count", histogram);
+                // Each series' largest bucket within the threshold holds its good observations
+                let mut good: HashMap<Vec<(String, String)>, (f64, f64)> = HashMap::new();
+                let mut total = 0.0;
+                for sample in samples {
+                    if sample.name == count {
+                        total += sample.value;
+                    } else if sample.name == bucket {
+                        let le = sample
+                            .labels
+                            .iter()
+                            .find(|(name, _)| name == "le")
+                            .and_then(|(_, le)| le.parse::<f64>().ok());
+                        let Some(le) = le.filter(|le| le <= threshold) else {
+                            continue;
+                        };
+                        let series = sample
+                            .labels
+                            .iter()
+                            .filter(|(name, _)| name != "le")
+                            .cloned()
+                            .collect();
+                        let entry = good.entry(series).or_insert((f64::NEG_INFINITY, 0.0));
+                        if le > entry.0 {
+                            *entry = (le, sample.value);
+                        }
+                    }
+                }
+                (good.values().map(|(_, value)| value).sum(), total)
+            }
+        }
+    }
+}
+
+fn sum(value: QueryValue) -> f64 {
+    match value {
+        QueryValue::Scalar(value) => value,
+        QueryValue::Vector(vector) => vector.iter().map(|sample| sample.value).sum(),
+    }
+}
+
+/// Good and total events since the newest sample at least `duration` old,
+/// or since the oldest one.
+fn increase(
+    history: &VecDeque<(Instant, f64, f64)>,
+    now: Instant,
+    duration: Duration,
+) -> (f64, f64) {
+    let Some(&(_, good, total)) = history.back() else {
+        return (0.0, 0.0);
+    };
+    let base = history
+        .iter()
+        .rev()
+        .find(|(at, _, _)| now.duration_since(*at) >= duration)
+        .or(history.front());
+    match base {
+        // Counters may have been reset, e.g. by compaction
+        Some(&(_, base_good, base_total)) => {
+            ((good - base_good).max(0.0), (total - base_total).max(0.0))
+        }
+        None => (0.0, 0.0),
+    }
+}
+
+fn error_ratio(good: f64, total: f64) -> f64 {
+    if total <= 0.0 {
+        return 0.0;
+    }
+    ((total - good) / total).clamp(0.0, 1.0)
+}
+
+async fn record_status(
+    collector: &MetricsCollector,
+    status: &SloStatus,
+) -> Result<(), ServerError> {
+    let gauge = |name: &str, help: &str, labels: HashMap<String, String>, value: f64| Metric {
+        name: name.to_string(),
+        metric_type: MetricType::Gauge,
+        help: help.to_string(),
+        labels,
+        value: MetricValue {
+            value,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
+    };
+
+    for (window, burn_rate) in &status.burn_rates {
+        let labels = HashMap::from([
+            ("slo".to_string(), status.name.clone()),
+            ("window".to_string(), window.clone()),
+        ]);
+        let metric = gauge(
+            "slo_burn_rate",
+            "Rate the SLO error budget is spent at, 1 being on budget",
+            labels,
+            *burn_rate,
+        );
+        collector.record(SLO_SOURCE, &metric).await?;
+    }
+
+    let labels = HashMap::from([("slo".to_string(), status.name.clone())]);
+    let metric = gauge(
+        "slo_error_budget_remaining",
+        "Fraction of the SLO error budget left in its window",
+        labels,
+        status.error_budget_remaining,
+    );
+    collector.record(SLO_SOURCE, &metric).await
+}
+
+/// Evaluates every configured SLO on its own interval.
+#[cfg(feature = "server")]
+pub fn spawn_slo_evaluation(state: Arc<AppState>) {
+    for index in 0..state.slos.trackers.len() {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let tracker = &state.slos.trackers[index];
+            let period = Duration::from_secs(tracker.config.interval_secs);
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match tracker.evaluate_and_record(&state.metrics_collector).await {
+                    Ok(status) => debug!(
+                        "SLO {} has {:.3} of its error budget left",
+                        status.name, status.error_budget_remaining
+                    ),
+                    Err(e) => warn!("Failed to evaluate SLO {}: {}", tracker.config.name, e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use prometheus::proto::MetricFamily;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+/// A series within a family: its interned label pairs sorted by name, as exposed.
+pub type SeriesKey = Vec<(Arc<str>, Arc<str>)>;
+
+/// Remembers which source pushed each series, so the exposition can be split
+/// per source.
+#[derive(Default)]
+pub struct SourceIndex {
+    // Source -> family name -> series
+    series: RwLock<HashMap<String, HashMap<String, HashSet<SeriesKey>>>>,
+}
+
+impl SourceIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, source: &str, family: &str, key: SeriesKey) {
+        // Most writes are to series the source already pushed
+        if let Ok(series) = self.series.read()
+            && series
+                .get(source)
+                .and_then(|families| families.get(family))
+                .is_some_and(|keys| keys.contains(&key))
+        {
+            return;
+        }
+
+        let Ok(mut series) = self.series.write() else {
+            return;
+        };
+
+        // Avoid allocating the source and family names on the hot path
+        let families = match series.get_mut(source) {
+            Some(families) => families,
+            None => series.entry(source.to_string()).or_default(),
+        };
+        match families.get_mut(family) {
+            Some(keys) => {
+                keys.insert(key);
+            }
+            None => {
+                families.insert(family.to_string(), HashSet::from([key]));
+            }
+        }
+    }
+
+    /// Forgets an expired series for every source that pushed it.
+    pub fn remove(&self, family: &str, key: &SeriesKey) {
+        let Ok(mut series) = self.series.write() else {
+            return;
+        };
+
+        for families in series.values_mut() {
+            if let Some(keys) = families.get_mut(family) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    families.remove(family);
+                }
+            }
+        }
+        series.retain(|_, families| !families.is_empty());
+    }
+
+    /// Number of series pushed by each source.
+    pub fn series_counts(&self) -> BTreeMap<String, usize> {
+        self.series
+            .read()
+            .map(|series| {
+                series
+                    .iter()
+                    .map(|(source, families)| {
+                        (source.clone(), families.values().map(HashSet::len).sum())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The sources that pushed each series, sorted, by family.
+    pub fn sources_by_series(&self) -> HashMap<String, HashMap<SeriesKey, Vec<String>>> {
+        let mut owners: HashMap<String, HashMap<SeriesKey, Vec<String>>> = HashMap::new();
+        let Ok(series) = self.series.read() else {
+            return owners;
+        };
+        for (source, families) in series.iter() {
+            for (family, keys) in families {
+                let family = owners.entry(family.clone()).or_default();
+                for key in keys {
+                    family.entry(key.clone()).or_default().push(source.clone());
+                }
+            }
+        }
+        for sources in owners.values_mut().flat_map(HashMap::values_mut) {
+            sources.sort();
+        }
+        owners
+    }
+
+    /// Keeps only the series pushed by `source`, or `None` for an unknown source.
+    pub fn filter(&self, source: &str, families: Vec<MetricFamily>) -> Option<Vec<MetricFamily>> {
+        let series = self.series.read().ok()?;
+        let owned = series.get(source)?;
+
+        Some(
+            families
+                .into_iter()
+                .filter_map(|mut family| {
+                    let keys = owned.get(family.get_name())?;
+                    let metrics: Vec<_> = family
+                        .take_metric()
+                        .into_iter()
+                        .filter(|metric| {
+                            let key: SeriesKey = metric
+                                .get_label()
+                                .iter()
+                                .map(|pair| {
+                                    (Arc::from(pair.get_name()), Arc::from(pair.get_value()))
+                                })
+                                .collect();
+                            keys.contains(&key)
+                        })
+                        .collect();
+
+                    if metrics.is_empty() {
+                        return None;
+                    }
+                    family.set_metric(metrics.into());
+                    Some(family)
+                })
+                .collect(),
+        )
+    }
+}
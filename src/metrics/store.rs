@@ -0,0 +1,151 @@
+#[cfg(feature = "redis")]
+pub mod redis;
+
+use crate::config::StoreConfig;
+use crate::errors::ServerError;
+use futures::future::BoxFuture;
+use prometheus::proto::MetricFamily;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// How a family is stored, i.e. the type it is registered as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoredKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl StoredKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StoredKind::Counter => "counter",
+            StoredKind::Gauge => "gauge",
+            StoredKind::Histogram => "histogram",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "counter" => Some(StoredKind::Counter),
+            "gauge" => Some(StoredKind::Gauge),
+            "histogram" => Some(StoredKind::Histogram),
+            _ => None,
+        }
+    }
+}
+
+/// What a write does to its series, with the samples of a metric folded
+/// into a single update.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreUpdate {
+    /// Adds to a counter, or moves a gauge up or down.
+    Add(f64),
+    Set(f64),
+    /// Sets a stateset series to 1 and every other state of it to 0.
+    State {
+        state_label: String,
+    },
+    /// Adds observations, as non-cumulative counts per bound of the family
+    /// (the last one for +Inf).
+    Observe {
+        counts: Vec<f64>,
+        sum: f64,
+        count: f64,
+    },
+}
+
+impl StoreUpdate {
+    /// The update taking this one back, if there is one: a set overwrites a
+    /// value the store doesn't keep.
+    pub fn inverse(&self) -> Option<StoreUpdate> {
+        match self {
+            StoreUpdate::Add(value) => Some(StoreUpdate::Add(-value)),
+            StoreUpdate::Observe { counts, sum, count } => Some(StoreUpdate::Observe {
+                counts: counts.iter().map(|count| -count).collect(),
+                sum: -sum,
+                count: -count,
+            }),
+            StoreUpdate::Set(_) | StoreUpdate::State { .. } => None,
+        }
+    }
+}
+
+/// A write to one series, as the registry is about to apply it locally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreWrite {
+    /// The source of the batch, for the per-source views.
+    pub source: String,
+    /// The name the family is registered under.
+    pub family: String,
+    pub help: String,
+    pub kind: StoredKind,
+    /// Bucket bounds of histogram families, empty otherwise.
+    pub bounds: Vec<f64>,
+    /// Every label of the family, sorted by name.
+    pub labels: Vec<(String, String)>,
+    pub update: StoreUpdate,
+}
+
+/// The families read back from a store.
+pub struct Snapshot {
+    pub families: Vec<MetricFamily>,
+}
+
+/// A registry shared by several gateway replicas, so pushes load balanced
+/// across them add up to one view. Writes are applied to the store before
+/// the local registry and must be atomic, since replicas write the same
+/// series concurrently. Every read of pushed series goes to the store.
+pub trait MetricsStore: Send + Sync {
+    fn write<'a>(&'a self, write: &'a StoreWrite) -> BoxFuture<'a, Result<(), ServerError>>;
+
+    /// Removes one series, given with every label of its family sorted by
+    /// name, and the family once it has no series left.
+    fn remove<'a>(
+        &'a self,
+        family: &'a str,
+        labels: &'a [(String, String)],
+    ) -> BoxFuture<'a, Result<bool, ServerError>>;
+
+    /// Removes the series no replica wrote for `ttl_secs`, returning how many.
+    fn expire(&self, ttl_secs: u64) -> BoxFuture<'_, Result<usize, ServerError>>;
+
+    /// Every stored family, sorted by name, series sorted like the local registry's.
+    fn gather(&self) -> BoxFuture<'_, Result<Snapshot, ServerError>>;
+
+    /// Every stored family like `gather`, but with only the labels of its
+    /// series, and the buckets of histograms without their counts, for what
+    /// needs to know which series there are rather than their values.
+    fn gather_series(&self) -> BoxFuture<'_, Result<Vec<MetricFamily>, ServerError>> {
+        Box::pin(async move { Ok(self.gather().await?.families) })
+    }
+
+    /// The number of stored families.
+    fn family_count(&self) -> BoxFuture<'_, Result<usize, ServerError>> {
+        Box::pin(async move { Ok(self.gather().await?.families.len()) })
+    }
+
+    /// The stored families holding series written for `source`, with only
+    /// those series, or `None` if it never wrote any.
+    fn gather_source<'a>(
+        &'a self,
+        source: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<MetricFamily>>, ServerError>>;
+
+    /// The number of stored series written for each source.
+    fn source_series_counts(&self) -> BoxFuture<'_, Result<BTreeMap<String, usize>, ServerError>>;
+}
+
+pub fn build_store(config: &StoreConfig) -> Result<Arc<dyn MetricsStore>, ServerError> {
+    match config {
+        #[cfg(feature = "redis")]
+        StoreConfig::Redis { url, key_prefix } => {
+            Ok(Arc::new(redis::RedisStore::new(url, key_prefix)?))
+        }
+        #[allow(unreachable_patterns)]
+        other => Err(ServerError::ConfigurationError(format!(
+            "Metrics store {:?} requires building with the matching feature",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,671 @@
+use super::{MetricsStore, Snapshot, StoreUpdate, StoreWrite, StoredKind};
+use crate::errors::ServerError;
+use ::redis::aio::ConnectionManager;
+use ::redis::{Client, ErrorKind, RedisError, Script};
+use futures::future::BoxFuture;
+use prometheus::proto::{self, LabelPair, MetricFamily, MetricType};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::OnceCell;
+
+/// Registers the family on first write, refusing writes of another type or
+/// with other buckets, then applies the update and records when and for
+/// which source the series was written. Running it as one script makes
+/// concurrent writes from several replicas atomic.
+const WRITE_SCRIPT: &str = r#"
+local kind = redis.call('HGET', KEYS[2], 'kind')
+if not kind then
+  redis.call('HSET', KEYS[2], 'kind', ARGV[2], 'help', ARGV[3], 'bounds', ARGV[4])
+  redis.call('SADD', KEYS[1], ARGV[1])
+elseif kind ~= ARGV[2] then
+  return 'registered as a ' .. kind
+elseif redis.call('HGET', KEYS[2], 'bounds') ~= ARGV[4] then
+  return 'registered with other buckets'
+end
+
+local op, series = ARGV[7], ARGV[8]
+if op == 'add' then
+  redis.call('HINCRBYFLOAT', KEYS[3], series, ARGV[9])
+elseif op == 'set' then
+  redis.call('HSET', KEYS[3], series, ARGV[9])
+elseif op == 'state' then
+  -- ARGV[9] is the series labels without the state label, mapped to the
+  -- state currently set in the states hash
+  local previous = redis.call('HGET', KEYS[5], ARGV[9])
+  if previous and previous ~= series and redis.call('HEXISTS', KEYS[3], previous) == 1 then
+    redis.call('HSET', KEYS[3], previous, '0')
+  end
+  redis.call('HSET', KEYS[5], ARGV[9], series)
+  redis.call('HSET', KEYS[3], series, '1')
+elseif op == 'observe' then
+  -- ARGV[9] and ARGV[10] are the sum and count, then the count of each bucket
+  redis.call('HINCRBYFLOAT', KEYS[3], series .. '\nsum', ARGV[9])
+  redis.call('HINCRBYFLOAT', KEYS[3], series .. '\ncount', ARGV[10])
+  for i = 11, #ARGV do
+    redis.call('HINCRBYFLOAT', KEYS[3], series .. '\n' .. (i - 11), ARGV[i])
+  end
+end
+
+redis.call('HSET', KEYS[4], series, ARGV[5])
+if redis.call('SADD', KEYS[6], ARGV[1] .. '\n' .. series) == 1 then
+  redis.call('SADD', KEYS[8], ARGV[6])
+  local origins = redis.call('HGET', KEYS[7], series) or ''
+  redis.call('HSET', KEYS[7], series, origins .. ARGV[6] .. '\n')
+end
+
+return 'ok'
+"#;
+
+/// How many times the sources of a series are read again when another
+/// source writes it while it's removed.
+const REMOVE_ATTEMPTS: usize = 3;
+
+/// Removes a series with its histogram fields and the per-source entries
+/// pointing to it, then the family if it has no series left. With a cutoff
+/// in ARGV[3], only a series not written since then is removed, so an
+/// expiry racing with a write keeps it. The sources that wrote the series
+/// are read beforehand and passed from ARGV[4] on, their keys from KEYS[8]
+/// on; if another source wrote it meanwhile, nothing is removed and -1 is
+/// returned for the caller to read them again.
+const REMOVE_SCRIPT: &str = r#"
+local family, series = ARGV[1], ARGV[2]
+local seen = redis.call('HGET', KEYS[4], series)
+if ARGV[3] ~= '' and seen and tonumber(seen) >= tonumber(ARGV[3]) then
+  return 0
+end
+
+local source_keys = {}
+for i = 4, #ARGV do
+  source_keys[ARGV[i]] = KEYS[i + 4]
+end
+local origins = redis.call('HGET', KEYS[6], series)
+if origins then
+  for source in string.gmatch(origins, '[^\n]+') do
+    if not source_keys[source] then
+      return -1
+    end
+  end
+end
+
+local removed = redis.call('HDEL', KEYS[3], series)
+if redis.call('HGET', KEYS[2], 'kind') == 'histogram' then
+  local bounds = cjson.decode(redis.call('HGET', KEYS[2], 'bounds') or '[]')
+  local fields = {series .. '\nsum', series .. '\ncount'}
+  for i = 0, #bounds do
+    fields[#fields + 1] = series .. '\n' .. i
+  end
+  removed = removed + redis.call('HDEL', KEYS[3], unpack(fields))
+end
+redis.call('HDEL', KEYS[4], series)
+
+if origins then
+  for source in string.gmatch(origins, '[^\n]+') do
+    local key = source_keys[source]
+    redis.call('SREM', key, family .. '\n' .. series)
+    if redis.call('SCARD', key) == 0 then
+      redis.call('SREM', KEYS[7], source)
+    end
+  end
+  redis.call('HDEL', KEYS[6], series)
+end
+
+if redis.call('HLEN', KEYS[3]) == 0 then
+  redis.call('DEL', KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6])
+  redis.call('SREM', KEYS[1], family)
+end
+if removed > 0 then
+  return 1
+end
+return 0
+"#;
+
+/// A `MetricsStore` in Redis. Keys share the `{<prefix>}` hash tag, so they
+/// land on one slot of a cluster:
+///
+/// - `{prefix}:families`: the set of family names
+/// - `{prefix}:meta:<family>`: a hash with the family's kind, help and bounds
+/// - `{prefix}:series:<family>`: a hash from the series labels, as a JSON
+///   array of name/value pairs, to the value; histogram series have one
+///   field per bucket, `sum` and `count`, suffixed after a newline
+/// - `{prefix}:seen:<family>`: a hash from the series to the unix seconds
+///   of its last write, for expiry
+/// - `{prefix}:states:<family>`: for statesets, a hash from the series
+///   labels without the state label to the series of the current state
+/// - `{prefix}:origin:<family>`: a hash from the series to the sources
+///   that wrote it, one per line
+/// - `{prefix}:source:<source>`: the set of series written for a source,
+///   as the family and series separated by a newline
+/// - `{prefix}:sources`: the set of sources with series
+pub struct RedisStore {
+    client: Client,
+    // Connected on first use, so the server starts while Redis is unreachable
+    connection: OnceCell<ConnectionManager>,
+    script: Script,
+    remove_script: Script,
+    prefix: String,
+}
+
+impl RedisStore {
+    pub fn new(url: &str, key_prefix: &str) -> Result<Self, ServerError> {
+        let client = Client::open(url)
+            .map_err(|e| ServerError::ConfigurationError(format!("Redis store: {}", e)))?;
+
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            script: Script::new(WRITE_SCRIPT),
+            remove_script: Script::new(REMOVE_SCRIPT),
+            prefix: format!("{{{}}}", key_prefix),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, ServerError> {
+        self.connection
+            .get_or_try_init(|| self.client.get_connection_manager())
+            .await
+            .cloned()
+            .map_err(unavailable)
+    }
+
+    fn key(&self, kind: &str, family: Option<&str>) -> String {
+        match family {
+            Some(family) => format!("{}:{}:{}", self.prefix, kind, family),
+            None => format!("{}:{}", self.prefix, kind),
+        }
+    }
+
+    /// Runs the remove script for a series, encoded as in the series hash,
+    /// with the keys of the sources that wrote it, so that every key it
+    /// touches is declared as Redis Cluster requires.
+    async fn remove_series(
+        &self,
+        connection: &mut ConnectionManager,
+        family: &str,
+        series: &str,
+        cutoff: Option<u64>,
+    ) -> Result<bool, ServerError> {
+        for _ in 0..REMOVE_ATTEMPTS {
+            let origins: Option<String> = ::redis::cmd("HGET")
+                .arg(self.key("origin", Some(family)))
+                .arg(series)
+                .query_async(connection)
+                .await
+                .map_err(unavailable)?;
+            let sources: Vec<&str> = origins.as_deref().unwrap_or_default().lines().collect();
+
+            let mut invocation = self.remove_script.prepare_invoke();
+            invocation
+                .key(self.key("families", None))
+                .key(self.key("meta", Some(family)))
+                .key(self.key("series", Some(family)))
+                .key(self.key("seen", Some(family)))
+                .key(self.key("states", Some(family)))
+                .key(self.key("origin", Some(family)))
+                .key(self.key("sources", None))
+                .arg(family)
+                .arg(series)
+                .arg(cutoff.map(|cutoff| cutoff.to_string()).unwrap_or_default());
+            for source in &sources {
+                invocation
+                    .key(self.key("source", Some(source)))
+                    .arg(*source);
+            }
+
+            let removed: i64 = invocation
+                .invoke_async(connection)
+                .await
+                .map_err(unavailable)?;
+            if removed >= 0 {
+                return Ok(removed > 0);
+            }
+            // Written by another source since its sources were read
+        }
+        Err(ServerError::Unavailable {
+            message: format!(
+                "metrics store: series of '{}' kept being written by new sources while removed",
+                family
+            ),
+            retry_after_secs: 1,
+        })
+    }
+
+    /// The meta and series hashes of `names`, decoded, keeping the series
+    /// `keep` accepts.
+    async fn read_families(
+        &self,
+        connection: &mut ConnectionManager,
+        names: &[String],
+        keep: impl Fn(&str, &str) -> bool,
+    ) -> Result<Vec<MetricFamily>, ServerError> {
+        let mut pipe = ::redis::pipe();
+        for name in names {
+            pipe.hgetall(self.key("meta", Some(name)))
+                .hgetall(self.key("series", Some(name)));
+        }
+        let hashes: Vec<HashMap<String, String>> =
+            pipe.query_async(connection).await.map_err(unavailable)?;
+
+        Ok(names
+            .iter()
+            .zip(hashes.chunks(2))
+            .filter_map(|(name, hashes)| {
+                decode_family(name, &hashes[0], &hashes[1], |series| keep(name, series))
+            })
+            .collect())
+    }
+}
+
+impl MetricsStore for RedisStore {
+    fn write<'a>(&'a self, write: &'a StoreWrite) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let series = serde_json::to_string(&write.labels)?;
+
+            let mut invocation = self.script.prepare_invoke();
+            invocation
+                .key(self.key("families", None))
+                .key(self.key("meta", Some(&write.family)))
+                .key(self.key("series", Some(&write.family)))
+                .key(self.key("seen", Some(&write.family)))
+                .key(self.key("states", Some(&write.family)))
+                .key(self.key("source", Some(&write.source)))
+                .key(self.key("origin", Some(&write.family)))
+                .key(self.key("sources", None))
+                .arg(&write.family)
+                .arg(write.kind.as_str())
+                .arg(&write.help)
+                .arg(serde_json::to_string(&write.bounds)?)
+                .arg(unix_seconds(SystemTime::now()))
+                .arg(&write.source);
+            match &write.update {
+                StoreUpdate::Add(value) => {
+                    invocation.arg("add").arg(&series).arg(*value);
+                }
+                StoreUpdate::Set(value) => {
+                    invocation.arg("set").arg(&series).arg(*value);
+                }
+                StoreUpdate::State { state_label } => {
+                    let others: Vec<&(String, String)> = write
+                        .labels
+                        .iter()
+                        .filter(|(name, _)| name != state_label)
+                        .collect();
+                    invocation
+                        .arg("state")
+                        .arg(&series)
+                        .arg(serde_json::to_string(&others)?);
+                }
+                StoreUpdate::Observe { counts, sum, count } => {
+                    invocation
+                        .arg("observe")
+                        .arg(&series)
+                        .arg(*sum)
+                        .arg(*count)
+                        .arg(counts.as_slice());
+                }
+            }
+
+            let outcome: String = invocation
+                .invoke_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+            if outcome != "ok" {
+                return Err(ServerError::MetricRegistrationError(format!(
+                    "Metric '{}' is {} in the shared store",
+                    write.family, outcome
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        family: &'a str,
+        labels: &'a [(String, String)],
+    ) -> BoxFuture<'a, Result<bool, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let series = serde_json::to_string(labels)?;
+            self.remove_series(&mut connection, family, &series, None)
+                .await
+        })
+    }
+
+    fn expire(&self, ttl_secs: u64) -> BoxFuture<'_, Result<usize, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let cutoff = unix_seconds(SystemTime::now()).saturating_sub(ttl_secs);
+            let names: Vec<String> = ::redis::cmd("SMEMBERS")
+                .arg(self.key("families", None))
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+
+            let mut pipe = ::redis::pipe();
+            for name in &names {
+                pipe.hgetall(self.key("seen", Some(name)));
+            }
+            let seen: Vec<HashMap<String, u64>> = pipe
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+
+            let mut expired = 0;
+            for (name, seen) in names.iter().zip(seen) {
+                for (series, last_write) in seen {
+                    if last_write < cutoff
+                        && self
+                            .remove_series(&mut connection, name, &series, Some(cutoff))
+                            .await?
+                    {
+                        expired += 1;
+                    }
+                }
+            }
+            Ok(expired)
+        })
+    }
+
+    fn gather(&self) -> BoxFuture<'_, Result<Snapshot, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let mut names: Vec<String> = ::redis::cmd("SMEMBERS")
+                .arg(self.key("families", None))
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+            names.sort();
+
+            let families = self
+                .read_families(&mut connection, &names, |_, _| true)
+                .await?;
+            Ok(Snapshot { families })
+        })
+    }
+
+    fn gather_series(&self) -> BoxFuture<'_, Result<Vec<MetricFamily>, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let mut names: Vec<String> = ::redis::cmd("SMEMBERS")
+                .arg(self.key("families", None))
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+            names.sort();
+
+            // The seen hash has a field per series, unlike the series hash
+            // with one per bucket of histograms, and no values to decode
+            let mut pipe = ::redis::pipe();
+            for name in &names {
+                pipe.hgetall(self.key("meta", Some(name)))
+                    .hkeys(self.key("seen", Some(name)));
+            }
+            let replies: Vec<::redis::Value> = pipe
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+            let mut families = Vec::with_capacity(names.len());
+            for (name, replies) in names.iter().zip(replies.chunks(2)) {
+                let meta: HashMap<String, String> =
+                    ::redis::from_redis_value(&replies[0]).map_err(unavailable)?;
+                let series: BTreeSet<String> =
+                    ::redis::from_redis_value(&replies[1]).map_err(unavailable)?;
+                families.extend(decode_series(name, &meta, series));
+            }
+            Ok(families)
+        })
+    }
+
+    fn family_count(&self) -> BoxFuture<'_, Result<usize, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            ::redis::cmd("SCARD")
+                .arg(self.key("families", None))
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)
+        })
+    }
+
+    fn gather_source<'a>(
+        &'a self,
+        source: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<MetricFamily>>, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let members: HashSet<String> = ::redis::cmd("SMEMBERS")
+                .arg(self.key("source", Some(source)))
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+            if members.is_empty() {
+                return Ok(None);
+            }
+
+            let names: BTreeSet<String> = members
+                .iter()
+                .filter_map(|member| Some(member.split_once('\n')?.0.to_string()))
+                .collect();
+            let names: Vec<String> = names.into_iter().collect();
+            let families = self
+                .read_families(&mut connection, &names, |name, series| {
+                    members.contains(&format!("{}\n{}", name, series))
+                })
+                .await?;
+            Ok(Some(families))
+        })
+    }
+
+    fn source_series_counts(&self) -> BoxFuture<'_, Result<BTreeMap<String, usize>, ServerError>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let sources: Vec<String> = ::redis::cmd("SMEMBERS")
+                .arg(self.key("sources", None))
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+
+            let mut pipe = ::redis::pipe();
+            for source in &sources {
+                pipe.scard(self.key("source", Some(source)));
+            }
+            let counts: Vec<usize> = pipe
+                .query_async(&mut connection)
+                .await
+                .map_err(unavailable)?;
+            Ok(sources.into_iter().zip(counts).collect())
+        })
+    }
+}
+
+/// Redis unreachable, or busy, is worth retrying later; an error Redis
+/// answered with, such as a failing script, isn't.
+fn unavailable(e: RedisError) -> ServerError {
+    let transient = e.is_io_error()
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+        || e.is_timeout()
+        || e.is_cluster_error()
+        || e.kind() == ErrorKind::BusyLoadingError;
+    if !transient {
+        return ServerError::MetricsProcessingError(format!("metrics store: {}", e));
+    }
+    ServerError::Unavailable {
+        message: format!("metrics store: {}", e),
+        retry_after_secs: 1,
+    }
+}
+
+#[derive(Default)]
+struct StoredHistogram {
+    counts: Vec<f64>,
+    sum: f64,
+    count: f64,
+}
+
+fn decode_family(
+    name: &str,
+    meta: &HashMap<String, String>,
+    fields: &HashMap<String, String>,
+    keep: impl Fn(&str) -> bool,
+) -> Option<MetricFamily> {
+    let kind = StoredKind::parse(meta.get("kind")?)?;
+    let bounds: Vec<f64> = serde_json::from_str(meta.get("bounds")?).ok()?;
+
+    let metrics: Vec<proto::Metric> = if kind == StoredKind::Histogram {
+        // Series labels -> buckets, sum and count
+        let mut series: BTreeMap<Vec<(String, String)>, StoredHistogram> = BTreeMap::new();
+        for (field, value) in fields {
+            let Some((labels, part)) = field.rsplit_once('\n') else {
+                continue;
+            };
+            if !keep(labels) {
+                continue;
+            }
+            let (Ok(labels), Ok(value)) = (serde_json::from_str(labels), value.parse::<f64>())
+            else {
+                continue;
+            };
+            let histogram = series.entry(labels).or_default();
+            match part {
+                "sum" => histogram.sum = value,
+                "count" => histogram.count = value,
+                bucket => {
+                    if let Ok(index) = bucket.parse::<usize>() {
+                        if histogram.counts.len() <= index {
+                            histogram.counts.resize(index + 1, 0.0);
+                        }
+                        histogram.counts[index] = value;
+                    }
+                }
+            }
+        }
+        series
+            .into_iter()
+            .map(|(labels, histogram)| {
+                let mut cumulative = 0.0;
+                let buckets: Vec<proto::Bucket> = bounds
+                    .iter()
+                    .enumerate()
+                    .map(|(index, bound)| {
+                        cumulative += histogram.counts.get(index).copied().unwrap_or(0.0);
+                        let mut bucket = proto::Bucket::new();
+                        bucket.set_upper_bound(*bound);
+                        bucket.set_cumulative_count(cumulative.round() as u64);
+                        bucket
+                    })
+                    .collect();
+
+                let mut stored = proto::Histogram::new();
+                stored.set_bucket(buckets.into());
+                stored.set_sample_sum(histogram.sum);
+                stored.set_sample_count(histogram.count.round() as u64);
+
+                let mut metric = labeled_metric(labels);
+                metric.set_histogram(stored);
+                metric
+            })
+            .collect()
+    } else {
+        let series: BTreeMap<Vec<(String, String)>, f64> = fields
+            .iter()
+            .filter(|(field, _)| keep(field))
+            .filter_map(|(field, value)| {
+                Some((serde_json::from_str(field).ok()?, value.parse().ok()?))
+            })
+            .collect();
+        series
+            .into_iter()
+            .map(|(labels, value)| {
+                let mut metric = labeled_metric(labels);
+                if kind == StoredKind::Counter {
+                    let mut counter = proto::Counter::new();
+                    counter.set_value(value);
+                    metric.set_counter(counter);
+                } else {
+                    let mut gauge = proto::Gauge::new();
+                    gauge.set_value(value);
+                    metric.set_gauge(gauge);
+                }
+                metric
+            })
+            .collect()
+    };
+
+    Some(family_of(name, meta, kind, metrics))
+}
+
+/// A family with the labels of `series`, as encoded in the seen hash, and
+/// the buckets of histograms, but no values.
+fn decode_series(
+    name: &str,
+    meta: &HashMap<String, String>,
+    series: BTreeSet<String>,
+) -> Option<MetricFamily> {
+    let kind = StoredKind::parse(meta.get("kind")?)?;
+    let bounds: Vec<f64> = serde_json::from_str(meta.get("bounds")?).ok()?;
+
+    let metrics = series
+        .iter()
+        .filter_map(|series| serde_json::from_str(series).ok())
+        .map(|labels| {
+            let mut metric = labeled_metric(labels);
+            if kind == StoredKind::Histogram {
+                let buckets: Vec<proto::Bucket> = bounds
+                    .iter()
+                    .map(|bound| {
+                        let mut bucket = proto::Bucket::new();
+                        bucket.set_upper_bound(*bound);
+                        bucket
+                    })
+                    .collect();
+                let mut histogram = proto::Histogram::new();
+                histogram.set_bucket(buckets.into());
+                metric.set_histogram(histogram);
+            }
+            metric
+        })
+        .collect();
+    Some(family_of(name, meta, kind, metrics))
+}
+
+fn family_of(
+    name: &str,
+    meta: &HashMap<String, String>,
+    kind: StoredKind,
+    metrics: Vec<proto::Metric>,
+) -> MetricFamily {
+    let mut family = MetricFamily::new();
+    family.set_name(name.to_string());
+    family.set_help(meta.get("help").cloned().unwrap_or_default());
+    family.set_field_type(match kind {
+        StoredKind::Counter => MetricType::COUNTER,
+        StoredKind::Gauge => MetricType::GAUGE,
+        StoredKind::Histogram => MetricType::HISTOGRAM,
+    });
+    family.set_metric(metrics.into());
+    family
+}
+
+fn labeled_metric(labels: Vec<(String, String)>) -> proto::Metric {
+    let labels: Vec<LabelPair> = labels
+        .into_iter()
+        .map(|(name, value)| {
+            let mut pair = LabelPair::new();
+            pair.set_name(name);
+            pair.set_value(value);
+            pair
+        })
+        .collect();
+
+    let mut metric = proto::Metric::new();
+    metric.set_label(labels.into());
+    metric
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,86 @@
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use crate::config::{SyntheticMetric, SyntheticPattern};
+use crate::errors::ServerError;
+use crate::metrics::collector::MetricsCollector;
+use crate::metrics::types::{GaugeOperation, Metric, MetricType, MetricValue};
+use std::f64::consts::TAU;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "server")]
+use tracing::warn;
+
+/// Source recorded for series written by the synthetic generator.
+pub const SYNTHETIC_SOURCE: &str = "synthetic";
+
+impl SyntheticPattern {
+    /// The value at `secs` since the Unix epoch.
+    pub fn value_at(&self, secs: f64) -> f64 {
+        match *self {
+            SyntheticPattern::Sine {
+                amplitude,
+                offset,
+                period_secs,
+            } => offset + amplitude * (TAU * secs / period_secs as f64).sin(),
+            SyntheticPattern::Step {
+                low,
+                high,
+                period_secs,
+            } => {
+                if ((secs / period_secs as f64) as u64).is_multiple_of(2) {
+                    low
+                } else {
+                    high
+                }
+            }
+            SyntheticPattern::Random { min, max } => min + (max - min) * fastrand::f64(),
+        }
+    }
+}
+
+/// Writes the value of a synthetic gauge at `secs` since the Unix epoch.
+pub async fn write_synthetic(
+    collector: &MetricsCollector,
+    synthetic: &SyntheticMetric,
+    secs: f64,
+) -> Result<(), ServerError> {
+    let metric = Metric {
+        name: synthetic.name.clone(),
+        metric_type: MetricType::Gauge,
+        help: "Synthetic demo series".to_string(),
+        labels: synthetic.labels.clone(),
+        value: MetricValue {
+            value: synthetic.pattern.value_at(secs),
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
+    };
+    collector.record(SYNTHETIC_SOURCE, &metric).await
+}
+
+/// Writes every configured synthetic gauge on its own interval.
+#[cfg(feature = "server")]
+pub fn spawn_synthetic(state: Arc<AppState>) {
+    for synthetic in state.config.synthetic.clone() {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(synthetic.interval_secs));
+            loop {
+                interval.tick().await;
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                if let Err(e) = write_synthetic(&state.metrics_collector, &synthetic, secs).await {
+                    warn!("Failed to write synthetic metric {}: {}", synthetic.name, e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,84 @@
+use crate::config::TargetLabelsConfig;
+use crate::metrics::types::Metric;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// The `job` and `instance` labels of one push, attached to its metrics the
+/// way Prometheus attaches target labels to the series of a scrape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetLabels {
+    labels: Vec<(&'static str, String)>,
+    honor_labels: bool,
+}
+
+impl TargetLabels {
+    /// `instance` is the client address, or the configured header's value.
+    pub fn new(config: &TargetLabelsConfig, source: &str, instance: Option<String>) -> Self {
+        let mut labels = Vec::new();
+        if config.job {
+            labels.push(("job", source.to_string()));
+        }
+        if config.instance
+            && let Some(instance) = instance
+        {
+            labels.push(("instance", instance));
+        }
+        Self {
+            labels,
+            honor_labels: config.honor_labels,
+        }
+    }
+
+    /// Attaches the labels. Unless `honor_labels` is set, a pushed `job` or
+    /// `instance` is kept as `exported_*` even when it matches, so a series'
+    /// label names don't depend on the values pushed.
+    pub fn apply(&self, metric: &mut Metric) {
+        self.apply_labels(&mut metric.labels);
+    }
+
+    /// `apply` for the labels of a metric still being built.
+    pub fn apply_labels(&self, labels: &mut HashMap<String, String>) {
+        for (name, value) in &self.labels {
+            if self.honor_labels && labels.contains_key(*name) {
+                continue;
+            }
+            if let Some(pushed) = labels.insert(name.to_string(), value.clone()) {
+                labels.insert(format!("exported_{}", name), pushed);
+            }
+        }
+    }
+}
+
+/// An address or a CIDR range, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl IpNetwork {
+    pub fn parse(network: &str) -> Option<Self> {
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse().ok()?)),
+            None => (network, None),
+        };
+        let address: IpAddr = address.parse().ok()?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Self { address, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
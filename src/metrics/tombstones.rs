@@ -0,0 +1,140 @@
+use crate::errors::ServerError;
+use crate::metrics::selector::SeriesSelector;
+use chrono::{DateTime, Duration, Utc};
+use prometheus::proto::MetricFamily;
+use serde::Serialize;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Series soft-deleted through `POST /api/admin/tombstone`: hidden from the
+/// exposition right away, and removed from the registry once `purge_at` passes.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tombstone {
+    pub id: String,
+    pub matchers: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub purge_at: DateTime<Utc>,
+    #[serde(skip)]
+    selectors: Vec<SeriesSelector>,
+}
+
+impl Tombstone {
+    /// Whether a series with the given family name and labels is tombstoned.
+    pub fn matches<'a>(
+        &self,
+        name: &str,
+        labels: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    ) -> bool {
+        self.selectors
+            .iter()
+            .any(|selector| selector.matches(name, labels.clone()))
+    }
+}
+
+#[derive(Default)]
+pub struct Tombstones {
+    tombstones: RwLock<Vec<Tombstone>>,
+}
+
+impl Tombstones {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tombstones the series matched by any of the selectors, purging them
+    /// after `grace`.
+    pub fn add(&self, matchers: &[String], grace: Duration) -> Result<Tombstone, ServerError> {
+        if matchers.is_empty() {
+            return Err(ServerError::ValidationError(
+                "at least one matcher is required".to_string(),
+            ));
+        }
+        let selectors = matchers
+            .iter()
+            .map(|matcher| SeriesSelector::parse(matcher))
+            .collect::<Result<_, _>>()?;
+
+        let now = Utc::now();
+        let purge_at = now.checked_add_signed(grace).ok_or_else(|| {
+            ServerError::ValidationError("Tombstone grace period is too large".to_string())
+        })?;
+        let tombstone = Tombstone {
+            id: Uuid::new_v4().to_string(),
+            matchers: matchers.to_vec(),
+            created_at: now,
+            purge_at,
+            selectors,
+        };
+
+        self.tombstones
+            .write()
+            .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?
+            .push(tombstone.clone());
+        Ok(tombstone)
+    }
+
+    /// Tombstones waiting to be purged.
+    pub fn list(&self) -> Vec<Tombstone> {
+        self.tombstones
+            .read()
+            .map(|tombstones| tombstones.clone())
+            .unwrap_or_default()
+    }
+
+    /// Removes and returns the tombstones whose grace period is over.
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<Tombstone> {
+        let Ok(mut tombstones) = self.tombstones.write() else {
+            return Vec::new();
+        };
+        let (due, pending) = tombstones
+            .drain(..)
+            .partition(|tombstone| tombstone.purge_at <= now);
+        *tombstones = pending;
+        due
+    }
+
+    /// Puts back tombstones `take_due` returned but that couldn't be purged,
+    /// to retry on the next compaction.
+    pub fn restore(&self, due: Vec<Tombstone>) {
+        if let Ok(mut tombstones) = self.tombstones.write() {
+            tombstones.extend(due);
+        }
+    }
+
+    /// Drops the tombstoned series from gathered families, and the families
+    /// left without any series.
+    pub fn hide(&self, families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        let Ok(tombstones) = self.tombstones.read() else {
+            return families;
+        };
+        if tombstones.is_empty() {
+            return families;
+        }
+
+        families
+            .into_iter()
+            .filter_map(|mut family| {
+                let name = family.get_name().to_string();
+                let metrics: Vec<_> = family
+                    .take_metric()
+                    .into_iter()
+                    .filter(|metric| {
+                        let labels = metric
+                            .get_label()
+                            .iter()
+                            .map(|pair| (pair.get_name(), pair.get_value()));
+                        !tombstones
+                            .iter()
+                            .any(|tombstone| tombstone.matches(&name, labels.clone()))
+                    })
+                    .collect();
+
+                if metrics.is_empty() {
+                    return None;
+                }
+                family.set_metric(metrics.into());
+                Some(family)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config::TopKConfig;
+use crate::metrics::types::{Metric, MetricType};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+
+/// Approximate counts in a fixed `depth` x `width` table: every item adds to
+/// one cell per row and its estimate is the smallest of those cells, which
+/// can only overestimate.
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    cells: Vec<f64>,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        Self {
+            width,
+            depth,
+            cells: vec![0.0; width * depth],
+        }
+    }
+
+    /// Adds `weight` to `item` and returns its new estimate.
+    pub fn add(&mut self, item: &str, weight: f64) -> f64 {
+        let mut estimate = f64::INFINITY;
+        for row in 0..self.depth {
+            let cell = self.cell(row, item);
+            self.cells[cell] += weight;
+            estimate = estimate.min(self.cells[cell]);
+        }
+        estimate
+    }
+
+    pub fn estimate(&self, item: &str) -> f64 {
+        (0..self.depth)
+            .map(|row| self.cells[self.cell(row, item)])
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    fn cell(&self, row: usize, item: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        item.hash(&mut hasher);
+        row * self.width + (hasher.finish() as usize % self.width)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeavyHitter {
+    pub value: String,
+    pub count: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopKReport {
+    pub metric: String,
+    pub label: String,
+    pub top: Vec<HeavyHitter>,
+}
+
+/// The `k` label values with the highest estimated counts, keeping only the
+/// sketch and `k` candidates instead of a counter per value.
+pub struct HeavyHitters {
+    k: usize,
+    sketch: CountMinSketch,
+    candidates: HashMap<String, f64>,
+}
+
+impl HeavyHitters {
+    pub fn new(config: &TopKConfig) -> Self {
+        Self {
+            k: config.k,
+            sketch: CountMinSketch::new(config.width, config.depth),
+            candidates: HashMap::with_capacity(config.k + 1),
+        }
+    }
+
+    pub fn add(&mut self, value: &str, weight: f64) {
+        let estimate = self.sketch.add(value, weight);
+        if let Some(count) = self.candidates.get_mut(value) {
+            *count = estimate;
+            return;
+        }
+        if self.candidates.len() < self.k {
+            self.candidates.insert(value.to_string(), estimate);
+            return;
+        }
+
+        let smallest = self
+            .candidates
+            .iter()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(value, count)| (value.clone(), *count));
+        if let Some((smallest, count)) = smallest
+            && estimate > count
+        {
+            self.candidates.remove(&smallest);
+            self.candidates.insert(value.to_string(), estimate);
+        }
+    }
+
+    /// Candidates by descending count.
+    pub fn top(&self) -> Vec<HeavyHitter> {
+        let mut top: Vec<HeavyHitter> = self
+            .candidates
+            .iter()
+            .map(|(value, count)| HeavyHitter {
+                value: value.clone(),
+                count: *count,
+            })
+            .collect();
+        top.sort_by(|a, b| b.count.total_cmp(&a.count).then(a.value.cmp(&b.value)));
+        top
+    }
+}
+
+/// Heavy hitters for every metric listed in `metrics.topk`.
+pub struct TopKTracker {
+    trackers: HashMap<String, (String, Mutex<HeavyHitters>)>,
+}
+
+impl TopKTracker {
+    pub fn new(configs: &[TopKConfig]) -> Self {
+        let trackers = configs
+            .iter()
+            .map(|config| {
+                let hitters = Mutex::new(HeavyHitters::new(config));
+                (config.metric.clone(), (config.label.clone(), hitters))
+            })
+            .collect();
+
+        Self { trackers }
+    }
+
+    /// Counts a written metric. Counters weigh by their increments, other
+    /// types by their number of samples.
+    pub fn observe(&self, metric: &Metric) {
+        let Some((label, hitters)) = self.trackers.get(&metric.name) else {
+            return;
+        };
+        let Some(value) = metric.labels.get(label) else {
+            return;
+        };
+
+        let samples = metric.value.as_slice();
+        let weight = match metric.metric_type {
+            MetricType::Counter => samples.iter().map(|sample| sample.value).sum(),
+            _ => samples.len() as f64,
+        };
+        if let Ok(mut hitters) = hitters.lock() {
+            hitters.add(value, weight);
+        }
+    }
+
+    /// The current heavy hitters of `metric`, or `None` if it isn't tracked.
+    pub fn report(&self, metric: &str) -> Option<TopKReport> {
+        let (label, hitters) = self.trackers.get(metric)?;
+        let top = hitters.lock().ok()?.top();
+
+        Some(TopKReport {
+            metric: metric.to_string(),
+            label: label.clone(),
+            top,
+        })
+    }
+}
@@ -1,5 +1,11 @@
-use serde::{Deserialize, Serialize};
+use crate::config::IngestMode;
+use crate::errors::ServerError;
+use crate::metrics::histogram::BucketCounts;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
@@ -8,12 +14,231 @@ pub enum MetricType {
     Gauge,
     Histogram,
     Summary,
+    /// A duration in seconds, stored as a histogram with `timer_buckets`.
+    Timer,
+    /// OpenMetrics info: a constant 1 gauge whose labels carry the metadata.
+    Info,
+    /// OpenMetrics stateset: the label named after the metric holds the
+    /// current state, exposed as 1 while every other state seen is reset to 0.
+    #[serde(rename = "stateset")]
+    StateSet,
+}
+
+impl MetricType {
+    /// The type the family is registered as in the registry.
+    pub fn registered_as(&self) -> MetricType {
+        match self {
+            MetricType::Timer => MetricType::Histogram,
+            MetricType::Info | MetricType::StateSet => MetricType::Gauge,
+            other => other.clone(),
+        }
+    }
+}
+
+/// How a gauge value is applied: `set` replaces the current value, `inc` and
+/// `dec` move it up or down by `value`, and `add` adds a signed `value`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GaugeOperation {
+    #[default]
+    Set,
+    Inc,
+    Dec,
+    Add,
+}
+
+/// The unit of an integer or fractional `timestamp`, seconds when unset.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TimestampUnit {
+    #[default]
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "us")]
+    Microseconds,
+    #[serde(rename = "ns")]
+    Nanoseconds,
+}
+
+/// A timestamp as pushed: a number in its `timestamp_unit`, or an RFC 3339
+/// string such as `2026-01-01T00:00:00.250Z`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PushedTimestamp {
+    Integer(i64),
+    Fractional(f64),
+    Rfc3339(DateTime<Utc>),
+}
+
+impl PushedTimestamp {
+    /// Unix milliseconds; `unit` only applies to numbers.
+    pub fn to_millis(self, unit: Option<TimestampUnit>) -> i64 {
+        let unit = unit.unwrap_or_default();
+        match self {
+            PushedTimestamp::Integer(value) => match unit {
+                TimestampUnit::Seconds => value.saturating_mul(1_000),
+                TimestampUnit::Milliseconds => value,
+                TimestampUnit::Microseconds => value.div_euclid(1_000),
+                TimestampUnit::Nanoseconds => value.div_euclid(1_000_000),
+            },
+            PushedTimestamp::Fractional(value) => {
+                let millis = match unit {
+                    TimestampUnit::Seconds => value * 1e3,
+                    TimestampUnit::Milliseconds => value,
+                    TimestampUnit::Microseconds => value / 1e3,
+                    TimestampUnit::Nanoseconds => value / 1e6,
+                };
+                // Saturates at the bounds, NaN becomes 0
+                millis.floor() as i64
+            }
+            PushedTimestamp::Rfc3339(time) => time.timestamp_millis(),
+        }
+    }
+}
+
+/// Written as RFC 3339, which says its unit and round-trips through
+/// `RawMetricValue`.
+fn serialize_timestamp<S: Serializer>(
+    timestamp: &Option<i64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match timestamp.and_then(DateTime::<Utc>::from_timestamp_millis) {
+        Some(time) => serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        None => serializer.serialize_none(),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "RawMetricValue")]
 pub struct MetricValue {
     pub value: f64,
+    /// Unix milliseconds.
+    #[serde(serialize_with = "serialize_timestamp")]
     pub timestamp: Option<i64>,
+    /// Set when a string was pushed as the value; it is mapped to a number or
+    /// a state through `metrics.enums` before the metric is written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enum_value: Option<String>,
+    /// Set when a histogram aggregated by the client was pushed; `value` then
+    /// holds its sum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Box<BucketCounts>>,
+}
+
+impl MetricValue {
+    /// A sample taken at `timestamp`, in Unix milliseconds.
+    pub fn new(value: SampleValue, timestamp: Option<i64>) -> Self {
+        let (value, enum_value) = match value {
+            SampleValue::Number(value) => (value, None),
+            SampleValue::Bool(value) => (if value { 1.0 } else { 0.0 }, None),
+            // JSON has no literal for these, clients send the exposition spelling
+            SampleValue::Enum(name) => match name.as_str() {
+                "NaN" => (f64::NAN, None),
+                "+Inf" | "Inf" => (f64::INFINITY, None),
+                "-Inf" => (f64::NEG_INFINITY, None),
+                _ => (0.0, Some(name)),
+            },
+            SampleValue::Histogram(histogram) => {
+                return Self {
+                    value: histogram.sum,
+                    timestamp,
+                    enum_value: None,
+                    histogram: Some(Box::new(histogram)),
+                };
+            }
+        };
+
+        Self {
+            value,
+            timestamp,
+            enum_value,
+            histogram: None,
+        }
+    }
+}
+
+/// A single sample, or a list of samples collected over the push interval
+/// (e.g. histogram observations) that are applied in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetricSamples {
+    One(MetricValue),
+    Many(Vec<MetricValue>),
+}
+
+impl MetricSamples {
+    pub fn as_slice(&self) -> &[MetricValue] {
+        match self {
+            MetricSamples::One(value) => std::slice::from_ref(value),
+            MetricSamples::Many(values) => values,
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [MetricValue] {
+        match self {
+            MetricSamples::One(value) => std::slice::from_mut(value),
+            MetricSamples::Many(values) => values,
+        }
+    }
+
+    /// Keeps the samples `keep` returns true for, returning how many were removed.
+    pub fn retain(&mut self, keep: impl FnMut(&MetricValue) -> bool) -> usize {
+        let mut values = match std::mem::replace(self, MetricSamples::Many(Vec::new())) {
+            MetricSamples::One(value) => vec![value],
+            MetricSamples::Many(values) => values,
+        };
+        let before = values.len();
+        values.retain(keep);
+        let removed = before - values.len();
+        *self = match values.len() {
+            1 => MetricSamples::One(values.remove(0)),
+            _ => MetricSamples::Many(values),
+        };
+        removed
+    }
+}
+
+impl From<MetricValue> for MetricSamples {
+    fn from(value: MetricValue) -> Self {
+        MetricSamples::One(value)
+    }
+}
+
+/// A pushed value: agents reporting statuses send booleans (coerced to 1/0)
+/// or value names instead of numbers, and histograms may be pushed already
+/// bucketed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum SampleValue {
+    Number(f64),
+    Bool(bool),
+    Enum(String),
+    Histogram(BucketCounts),
+}
+
+#[derive(Deserialize)]
+struct RawMetricValue {
+    value: SampleValue,
+    #[serde(default)]
+    timestamp: Option<PushedTimestamp>,
+    #[serde(default)]
+    timestamp_unit: Option<TimestampUnit>,
+    /// As serialized by `MetricValue`, e.g. in dead letters.
+    #[serde(default)]
+    histogram: Option<Box<BucketCounts>>,
+}
+
+impl From<RawMetricValue> for MetricValue {
+    fn from(raw: RawMetricValue) -> Self {
+        let timestamp = raw
+            .timestamp
+            .map(|timestamp| timestamp.to_millis(raw.timestamp_unit));
+        match raw.histogram {
+            Some(histogram) => Self::new(SampleValue::Histogram(*histogram), timestamp),
+            None => Self::new(raw.value, timestamp),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,7 +247,28 @@ pub struct Metric {
     pub metric_type: MetricType,
     pub help: String,
     pub labels: HashMap<String, String>,
-    pub value: MetricValue,
+    pub value: MetricSamples,
+    /// Only meaningful for gauges.
+    #[serde(default)]
+    pub operation: GaugeOperation,
+}
+
+impl Metric {
+    /// The family name before prefixing; timers get a `_seconds` suffix and
+    /// info metrics an `_info` suffix unless the client already added it.
+    pub fn family_name(&self) -> Cow<'_, str> {
+        let suffix = match self.metric_type {
+            MetricType::Timer => "_seconds",
+            MetricType::Info => "_info",
+            _ => return Cow::Borrowed(&self.name),
+        };
+
+        if self.name.ends_with(suffix) {
+            Cow::Borrowed(&self.name)
+        } else {
+            Cow::Owned(format!("{}{}", self.name, suffix))
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,11 +277,145 @@ pub struct MetricsBatch {
     pub source: String,
 }
 
+/// A metric in the `/api/v2` ingestion format: the value is inlined, and help
+/// text and labels are optional.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricV2 {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub metric_type: MetricType,
+    #[serde(default)]
+    pub help: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    pub value: SampleValue,
+    #[serde(default)]
+    pub timestamp: Option<PushedTimestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_unit: Option<TimestampUnit>,
+    #[serde(default)]
+    pub operation: GaugeOperation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsBatchV2 {
+    pub metrics: Vec<MetricV2>,
+    pub source: String,
+}
+
+impl From<MetricV2> for Metric {
+    fn from(metric: MetricV2) -> Self {
+        Self {
+            help: metric.help.unwrap_or_else(|| metric.name.clone()),
+            name: metric.name,
+            metric_type: metric.metric_type,
+            labels: metric.labels,
+            value: MetricValue::new(
+                metric.value,
+                metric
+                    .timestamp
+                    .map(|timestamp| timestamp.to_millis(metric.timestamp_unit)),
+            )
+            .into(),
+            operation: metric.operation,
+        }
+    }
+}
+
+impl From<MetricsBatchV2> for MetricsBatch {
+    fn from(batch: MetricsBatchV2) -> Self {
+        Self {
+            metrics: batch.metrics.into_iter().map(Metric::from).collect(),
+            source: batch.source,
+        }
+    }
+}
+
+/// Why a metric (or line, or chunk) of a batch wasn't ingested.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MetricError {
+    pub message: String,
+    /// Whether resending it later may succeed: false for invalid data,
+    /// true for transient registry or persistence failures.
+    pub retryable: bool,
+}
+
+impl MetricError {
+    pub fn new(message: impl Into<String>, retryable: bool) -> Self {
+        Self {
+            message: message.into(),
+            retryable,
+        }
+    }
+}
+
+impl From<&ServerError> for MetricError {
+    fn from(error: &ServerError) -> Self {
+        Self::new(error.to_string(), error.is_retryable())
+    }
+}
+
+impl fmt::Display for MetricError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricsResponse {
     pub processed: usize,
     pub status: String,
     pub errors: Vec<String>,
+    /// The same errors with their retryability, for the clients that resend.
+    #[serde(default)]
+    pub error_details: Vec<MetricError>,
+    /// Metrics discarded by the source's sampling configuration.
+    #[serde(default)]
+    pub sampled_out: usize,
+    /// Samples dropped as duplicates within the source's dedup window.
+    #[serde(default)]
+    pub deduplicated: usize,
+    #[serde(default)]
+    pub mode: IngestMode,
+    /// The `X-Request-Id` of the request that carried the batch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl MetricsResponse {
+    /// Reports an error in both `errors` and `error_details`.
+    pub fn push_error(&mut self, error: MetricError) {
+        self.errors.push(error.message.clone());
+        self.error_details.push(error);
+    }
+
+    pub fn extend_errors(&mut self, errors: impl IntoIterator<Item = MetricError>) {
+        for error in errors {
+            self.push_error(error);
+        }
+    }
+
+    /// Every error message, for wrapping in a batch-level error.
+    pub fn error_summary(&self) -> String {
+        self.errors.join("; ")
+    }
+
+    /// Fails a batch of which no metric could be processed.
+    pub fn into_result(self) -> Result<Self, ServerError> {
+        if self.processed == 0 && !self.errors.is_empty() {
+            if self.mode == IngestMode::Strict {
+                return Err(ServerError::ValidationError(format!(
+                    "Batch rejected in strict mode: {}",
+                    self.error_summary()
+                )));
+            }
+            return Err(ServerError::MetricsProcessingError(
+                "Failed to process any metrics in the batch".to_string(),
+            ));
+        }
+
+        Ok(self)
+    }
 }
 
 impl Default for MetricsResponse {
@@ -44,6 +424,11 @@ impl Default for MetricsResponse {
             processed: 0,
             status: "success".to_string(),
             errors: Vec::new(),
+            error_details: Vec::new(),
+            sampled_out: 0,
+            deduplicated: 0,
+            mode: IngestMode::default(),
+            request_id: None,
         }
     }
 }
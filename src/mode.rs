@@ -0,0 +1,89 @@
+#[cfg(feature = "server")]
+use actix_web::dev::ServerHandle;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use std::sync::OnceLock;
+#[cfg(feature = "server")]
+use std::sync::atomic::{AtomicU8, Ordering};
+#[cfg(feature = "server")]
+use tracing::info;
+
+/// How the server treats new work, switched at runtime for maintenance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperatingMode {
+    #[default]
+    Normal,
+    /// Ingestion is refused with a 503, scrapes and queries are served.
+    ReadOnly,
+    /// Like `read_only`, and new connections are no longer accepted while
+    /// in-flight requests and background jobs finish.
+    Drain,
+}
+
+#[cfg(feature = "server")]
+impl OperatingMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => OperatingMode::ReadOnly,
+            2 => OperatingMode::Drain,
+            _ => OperatingMode::Normal,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OperatingMode::Normal => 0,
+            OperatingMode::ReadOnly => 1,
+            OperatingMode::Drain => 2,
+        }
+    }
+}
+
+/// The current operating mode, and the server whose accept loop `drain` pauses.
+#[cfg(feature = "server")]
+#[derive(Default)]
+pub struct ModeSwitch {
+    mode: AtomicU8,
+    server: OnceLock<ServerHandle>,
+}
+
+#[cfg(feature = "server")]
+impl ModeSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets `drain` stop the server from accepting connections. Without a
+    /// server, e.g. in tests, draining only refuses ingestion.
+    pub fn attach_server(&self, server: ServerHandle) {
+        let _ = self.server.set(server);
+    }
+
+    pub fn get(&self) -> OperatingMode {
+        OperatingMode::from_u8(self.mode.load(Ordering::Relaxed))
+    }
+
+    pub fn accepts_ingestion(&self) -> bool {
+        self.get() == OperatingMode::Normal
+    }
+
+    /// Switches mode, pausing or resuming the accept loop when entering or
+    /// leaving `drain`.
+    pub async fn set(&self, mode: OperatingMode) {
+        let previous = OperatingMode::from_u8(self.mode.swap(mode.as_u8(), Ordering::Relaxed));
+        if previous == mode {
+            return;
+        }
+        info!("Operating mode changed from {:?} to {:?}", previous, mode);
+
+        let Some(server) = self.server.get() else {
+            return;
+        };
+        if mode == OperatingMode::Drain {
+            server.pause().await;
+        } else if previous == OperatingMode::Drain {
+            server.resume().await;
+        }
+    }
+}
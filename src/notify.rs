@@ -0,0 +1,292 @@
+#[cfg(feature = "email")]
+pub mod email;
+pub mod pagerduty;
+pub mod silence;
+pub mod slack;
+pub mod webhook;
+
+pub use pagerduty::PagerDutyChannel;
+pub use silence::{Silence, Silences};
+pub use slack::SlackChannel;
+pub use webhook::WebhookChannel;
+
+use crate::config::{NotificationChannelConfig, NotifyTarget};
+use crate::errors::ServerError;
+use crate::reports::RenderedReport;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Notifications waiting to be sent; more are dropped with a warning.
+pub const NOTIFICATION_QUEUE_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+/// An alert raised by a rule, before it is rendered for a channel.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub severity: Severity,
+    /// The message sent when the target has no template.
+    pub summary: String,
+    pub labels: BTreeMap<String, String>,
+    /// Extra values templates can refer to, e.g. the offending value.
+    pub values: BTreeMap<String, String>,
+}
+
+impl Alert {
+    /// Renders `template`, where `{{name}}` is replaced by `rule`, `severity`,
+    /// `summary` or the value or label of that name, in that order.
+    pub fn render(&self, template: Option<&str>) -> Notification {
+        let message = match template {
+            Some(template) => render_template(template, |name| match name {
+                "rule" => Some(self.rule.clone()),
+                "severity" => serde_json::to_value(self.severity)
+                    .ok()
+                    .and_then(|value| value.as_str().map(str::to_string)),
+                "summary" => Some(self.summary.clone()),
+                _ => self
+                    .values
+                    .get(name)
+                    .or_else(|| self.labels.get(name))
+                    .cloned(),
+            }),
+            None => self.summary.clone(),
+        };
+
+        Notification {
+            rule: self.rule.clone(),
+            severity: self.severity,
+            message,
+            labels: self.labels.clone(),
+        }
+    }
+}
+
+/// What a channel receives: an alert with its message rendered.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Replaces every `{{name}}` in `template` with `lookup(name)`, or nothing
+/// when the name is unknown.
+pub fn render_template(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        rendered.push_str(&lookup(name).unwrap_or_default());
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Somewhere alerts are delivered, such as a chat room or a pager.
+pub trait NotificationChannel: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn send<'a>(&'a self, notification: &'a Notification)
+    -> BoxFuture<'a, Result<(), ServerError>>;
+
+    /// Sends a scheduled report; channels without a richer format send the
+    /// rendered body as an informational notification.
+    fn send_report<'a>(
+        &'a self,
+        report: &'a RenderedReport,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let notification = Notification {
+                rule: report.name.clone(),
+                severity: Severity::Info,
+                message: report.body.clone(),
+                labels: BTreeMap::new(),
+            };
+            self.send(&notification).await
+        })
+    }
+}
+
+pub fn build_channel(
+    name: &str,
+    config: &NotificationChannelConfig,
+) -> Result<Arc<dyn NotificationChannel>, ServerError> {
+    match config {
+        NotificationChannelConfig::Slack {
+            webhook_url,
+            channel,
+        } => Ok(Arc::new(SlackChannel::new(
+            name,
+            webhook_url,
+            channel.as_deref(),
+        ))),
+        NotificationChannelConfig::Pagerduty { routing_key, url } => {
+            Ok(Arc::new(PagerDutyChannel::new(name, routing_key, url)))
+        }
+        NotificationChannelConfig::Webhook { url, headers } => {
+            Ok(Arc::new(WebhookChannel::new(name, url, headers.clone())))
+        }
+        #[cfg(feature = "email")]
+        NotificationChannelConfig::Email {
+            smtp_host,
+            smtp_port,
+            starttls,
+            username,
+            password,
+            from,
+            to,
+        } => Ok(Arc::new(email::EmailChannel::new(
+            name,
+            email::SmtpSettings {
+                host: smtp_host.clone(),
+                port: *smtp_port,
+                starttls: *starttls,
+                username: username.clone(),
+                password: password.clone(),
+            },
+            from,
+            to,
+        )?)),
+        #[allow(unreachable_patterns)]
+        other => Err(ServerError::ConfigurationError(format!(
+            "Notification channel {:?} requires building with the matching feature",
+            other
+        ))),
+    }
+}
+
+struct Outgoing {
+    channel: Arc<dyn NotificationChannel>,
+    notification: Notification,
+}
+
+/// The configured channels, keyed by the name alert rules refer to them by.
+/// Notifications are queued and sent one at a time by a task of their own.
+pub struct Notifier {
+    channels: HashMap<String, Arc<dyn NotificationChannel>>,
+    silences: Silences,
+    sender: mpsc::Sender<Outgoing>,
+    // Taken by the first `notify`, which spawns the sending task
+    receiver: Mutex<Option<mpsc::Receiver<Outgoing>>>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel(NOTIFICATION_QUEUE_SIZE);
+        Self {
+            channels: HashMap::new(),
+            silences: Silences::default(),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+}
+
+impl Notifier {
+    pub fn new(configs: &HashMap<String, NotificationChannelConfig>) -> Result<Self, ServerError> {
+        let mut notifier = Self::default();
+        for (name, config) in configs {
+            notifier = notifier.with_channel(name, build_channel(name, config)?);
+        }
+        Ok(notifier)
+    }
+
+    pub fn with_channel(mut self, name: &str, channel: Arc<dyn NotificationChannel>) -> Self {
+        self.channels.insert(name.to_string(), channel);
+        self
+    }
+
+    pub fn with_silences(mut self, silences: Silences) -> Self {
+        self.silences = silences;
+        self
+    }
+
+    pub fn silences(&self) -> &Silences {
+        &self.silences
+    }
+
+    pub fn channel(&self, name: &str) -> Option<Arc<dyn NotificationChannel>> {
+        self.channels.get(name).cloned()
+    }
+
+    /// Queues `alert` for every target. Delivery failures, and alerts that
+    /// don't fit in the queue, are logged and never affect the rule that
+    /// raised the alert.
+    pub fn notify(&self, targets: &[NotifyTarget], alert: &Alert) {
+        if !targets.is_empty() && self.silences.is_silenced(alert) {
+            debug!("Alert {} is silenced", alert.rule);
+            return;
+        }
+
+        for target in targets {
+            let Some(channel) = self.channels.get(&target.channel).cloned() else {
+                warn!(
+                    "Alert {} targets unknown notification channel {}",
+                    alert.rule, target.channel
+                );
+                continue;
+            };
+
+            self.spawn();
+            let notification = alert.render(target.template.as_deref());
+            if self
+                .sender
+                .try_send(Outgoing {
+                    channel,
+                    notification,
+                })
+                .is_err()
+            {
+                warn!(
+                    "Notification queue full, dropping {} alert for {}",
+                    alert.rule, target.channel
+                );
+            }
+        }
+    }
+
+    fn spawn(&self) {
+        let receiver = match self.receiver.lock() {
+            Ok(mut receiver) => receiver.take(),
+            Err(_) => return,
+        };
+        let Some(mut receiver) = receiver else {
+            return;
+        };
+        tokio::spawn(async move {
+            while let Some(Outgoing {
+                channel,
+                notification,
+            }) = receiver.recv().await
+            {
+                match channel.send(&notification).await {
+                    Ok(()) => debug!("Sent {} alert to {}", notification.rule, channel.name()),
+                    Err(e) => warn!(
+                        "Failed to send {} alert to {}: {}",
+                        notification.rule,
+                        channel.name(),
+                        e
+                    ),
+                }
+            }
+        });
+    }
+}
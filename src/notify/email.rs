@@ -0,0 +1,135 @@
+use crate::config::ReportFormat;
+use crate::errors::ServerError;
+use crate::notify::{Notification, NotificationChannel};
+use crate::reports::RenderedReport;
+use futures::future::BoxFuture;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub starttls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Mails notifications through an SMTP relay.
+pub struct EmailChannel {
+    name: String,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl EmailChannel {
+    pub fn new(
+        name: &str,
+        smtp: SmtpSettings,
+        from: &str,
+        to: &[String],
+    ) -> Result<Self, ServerError> {
+        let mailbox = |address: &str| {
+            address.parse::<Mailbox>().map_err(|e| {
+                ServerError::ConfigurationError(format!("Invalid address '{}': {}", address, e))
+            })
+        };
+
+        let mut builder = if smtp.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)
+                .map_err(|e| ServerError::ConfigurationError(e.to_string()))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+        }
+        .port(smtp.port);
+        if let (Some(username), Some(password)) = (smtp.username, smtp.password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            from: mailbox(from)?,
+            to: to
+                .iter()
+                .map(|address| mailbox(address))
+                .collect::<Result<_, _>>()?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl NotificationChannel for EmailChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let message = self
+                .message(format!(
+                    "[{:?}] {}",
+                    notification.severity, notification.rule
+                ))
+                .body(notification.message.clone())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+
+    /// Mails an HTML report as the message body, a CSV one as an attachment.
+    fn send_report<'a>(
+        &'a self,
+        report: &'a RenderedReport,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let builder = self.message(report.subject.clone());
+            let message = match report.format {
+                ReportFormat::Html => builder.singlepart(SinglePart::html(report.body.clone())),
+                ReportFormat::Csv => {
+                    let content_type = ContentType::parse(report.content_type())
+                        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+                    builder.multipart(
+                        MultiPart::mixed()
+                            .singlepart(SinglePart::plain(format!(
+                                "The {} report is attached.",
+                                report.name
+                            )))
+                            .singlepart(
+                                Attachment::new(report.file_name())
+                                    .body(report.body.clone(), content_type),
+                            ),
+                    )
+                }
+            }
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+}
+
+impl EmailChannel {
+    fn message(&self, subject: String) -> lettre::message::MessageBuilder {
+        let mut builder = Message::builder().from(self.from.clone()).subject(subject);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        builder
+    }
+}
@@ -0,0 +1,69 @@
+use crate::errors::ServerError;
+use crate::notify::{Notification, NotificationChannel, Severity};
+use futures::future::BoxFuture;
+use serde_json::json;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Triggers incidents through the PagerDuty Events API v2.
+pub struct PagerDutyChannel {
+    name: String,
+    routing_key: String,
+    url: String,
+    client: reqwest::Client,
+}
+
+impl PagerDutyChannel {
+    pub fn new(name: &str, routing_key: &str, url: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            routing_key: routing_key.to_string(),
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl NotificationChannel for PagerDutyChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            // Repeated alerts for the same rule and series update one incident
+            let mut hasher = DefaultHasher::new();
+            notification.rule.hash(&mut hasher);
+            notification.labels.hash(&mut hasher);
+            let severity = match notification.severity {
+                Severity::Info => "info",
+                Severity::Warning => "warning",
+                Severity::Critical => "critical",
+            };
+
+            let event = json!({
+                "routing_key": self.routing_key,
+                "event_action": "trigger",
+                "dedup_key": format!("{}-{:x}", notification.rule, hasher.finish()),
+                "payload": {
+                    "summary": notification.message,
+                    "source": "rustic-insights",
+                    "severity": severity,
+                    "custom_details": notification.labels,
+                },
+            });
+
+            self.client
+                .post(&self.url)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::MaintenanceWindow;
+use crate::errors::ServerError;
+use crate::metrics::selector::SeriesSelector;
+use crate::notify::Alert;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Suppresses the alerts matching `matchers` between `starts_at` and `ends_at`.
+/// Matchers are a series selector over the alert labels, whose metric name,
+/// if any, is matched against the rule, e.g. `anomaly{service="checkout"}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Silence {
+    pub id: String,
+    pub matchers: String,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub comment: String,
+    #[serde(skip)]
+    selector: SeriesSelector,
+}
+
+impl Silence {
+    pub fn new(
+        id: String,
+        matchers: &str,
+        starts_at: DateTime<Utc>,
+        ends_at: DateTime<Utc>,
+        comment: &str,
+    ) -> Result<Self, ServerError> {
+        if ends_at <= starts_at {
+            return Err(ServerError::ValidationError(
+                "A silence must end after it starts".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            id,
+            matchers: matchers.to_string(),
+            starts_at,
+            ends_at,
+            comment: comment.to_string(),
+            selector: SeriesSelector::parse(matchers)?,
+        })
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.starts_at <= now && now < self.ends_at
+    }
+
+    pub fn matches(&self, alert: &Alert) -> bool {
+        let labels = alert.labels.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        self.selector.matches(&alert.rule, labels)
+    }
+}
+
+/// Silences created through the admin API plus the configured maintenance windows.
+pub struct Silences {
+    silences: RwLock<Vec<Silence>>,
+}
+
+impl Silences {
+    pub fn new(windows: &[MaintenanceWindow]) -> Result<Self, ServerError> {
+        let silences = windows
+            .iter()
+            .enumerate()
+            .map(|(i, window)| {
+                Silence::new(
+                    format!("maintenance-{}", i),
+                    &window.matchers,
+                    window.start,
+                    window.end,
+                    &window.comment,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            silences: RwLock::new(silences),
+        })
+    }
+
+    /// Silences matching alerts from now on, for `duration`.
+    pub fn add(
+        &self,
+        matchers: &str,
+        duration: Duration,
+        comment: &str,
+    ) -> Result<Silence, ServerError> {
+        let now = Utc::now();
+        let ends_at = now.checked_add_signed(duration).ok_or_else(|| {
+            ServerError::ValidationError("Silence duration is too large".to_string())
+        })?;
+        let silence = Silence::new(Uuid::new_v4().to_string(), matchers, now, ends_at, comment)?;
+
+        if let Ok(mut silences) = self.silences.write() {
+            silences.push(silence.clone());
+        }
+        Ok(silence)
+    }
+
+    /// Removes a silence, returning whether it existed.
+    pub fn remove(&self, id: &str) -> bool {
+        let Ok(mut silences) = self.silences.write() else {
+            return false;
+        };
+        let count = silences.len();
+        silences.retain(|silence| silence.id != id);
+        silences.len() != count
+    }
+
+    /// Silences that are active or yet to start; expired ones are dropped.
+    pub fn list(&self) -> Vec<Silence> {
+        let now = Utc::now();
+        let Ok(mut silences) = self.silences.write() else {
+            return Vec::new();
+        };
+        silences.retain(|silence| silence.ends_at > now);
+        silences.clone()
+    }
+
+    pub fn is_silenced(&self, alert: &Alert) -> bool {
+        let now = Utc::now();
+        self.silences.read().is_ok_and(|silences| {
+            silences
+                .iter()
+                .any(|silence| silence.is_active(now) && silence.matches(alert))
+        })
+    }
+}
+
+impl Default for Silences {
+    fn default() -> Self {
+        Self {
+            silences: RwLock::new(Vec::new()),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::errors::ServerError;
+use crate::notify::{Notification, NotificationChannel};
+use futures::future::BoxFuture;
+use serde_json::json;
+
+/// Posts messages to a Slack incoming webhook.
+pub struct SlackChannel {
+    name: String,
+    webhook_url: String,
+    channel: Option<String>,
+    client: reqwest::Client,
+}
+
+impl SlackChannel {
+    pub fn new(name: &str, webhook_url: &str, channel: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            webhook_url: webhook_url.to_string(),
+            channel: channel.map(str::to_string),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl NotificationChannel for SlackChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let mut body = json!({ "text": notification.message });
+            // Webhooks post to the channel they were created for unless overridden
+            if let Some(channel) = &self.channel {
+                body["channel"] = json!(channel);
+            }
+
+            self.client
+                .post(&self.webhook_url)
+                .json(&body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,76 @@
+use crate::errors::ServerError;
+use crate::notify::{Notification, NotificationChannel};
+use crate::reports::RenderedReport;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+
+/// POSTs each notification as JSON to an arbitrary endpoint.
+pub struct WebhookChannel {
+    name: String,
+    url: String,
+    headers: HashMap<String, String>,
+    client: reqwest::Client,
+}
+
+impl WebhookChannel {
+    pub fn new(name: &str, url: &str, headers: HashMap<String, String>) -> Self {
+        Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            headers,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl NotificationChannel for WebhookChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let mut request = self.client.post(&self.url).json(notification);
+            for (name, value) in &self.headers {
+                request = request.header(name, value);
+            }
+
+            request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+
+    /// POSTs the report body as is, with its content type.
+    fn send_report<'a>(
+        &'a self,
+        report: &'a RenderedReport,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, report.content_type())
+                .header("X-Report-Name", &report.name)
+                .body(report.body.clone());
+            for (name, value) in &self.headers {
+                request = request.header(name, value);
+            }
+
+            request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,208 @@
+use crate::capture::CapturedPayload;
+use crate::dead_letter::DeadLetter;
+use crate::encryption::{self, Keyring};
+use crate::errors::ServerError;
+use crate::metrics::{Metric, MetricsBatch};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{info, warn};
+
+/// One line of a capture: a dead-letter record, a body recorded by
+/// `capture`, or a `MetricsBatch` with an optional `timestamp` of when it was
+/// originally received.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CaptureLine {
+    DeadLetter(DeadLetter),
+    Payload(CapturedPayload),
+    Batch {
+        #[serde(default)]
+        timestamp: Option<DateTime<Utc>>,
+        #[serde(flatten)]
+        batch: MetricsBatch,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayRecord {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub batch: MetricsBatch,
+}
+
+/// Parses a capture line. Dead letters are replayed as the batch, or the
+/// single metric, they carry; malformed payloads can't be parsed, and only
+/// captured bodies are replayed as they are. Sealed lines are opened with
+/// `Keyring::open_line` first.
+pub fn parse_record(line: &str) -> Result<ReplayRecord, ServerError> {
+    into_record(parse_line(line)?)
+}
+
+fn into_record(line: CaptureLine) -> Result<ReplayRecord, ServerError> {
+    match line {
+        CaptureLine::Batch { timestamp, batch } => Ok(ReplayRecord { timestamp, batch }),
+        CaptureLine::Payload(payload) => Ok(ReplayRecord {
+            timestamp: Some(payload.timestamp),
+            batch: payload.batch().map_err(|e| {
+                ServerError::ValidationError(format!("captured body doesn't parse: {}", e))
+            })?,
+        }),
+        CaptureLine::DeadLetter(letter) => {
+            let batch = if letter.payload.get("metrics").is_some() {
+                serde_json::from_value(letter.payload)?
+            } else if letter.payload.get("name").is_some() {
+                MetricsBatch {
+                    metrics: vec![serde_json::from_value::<Metric>(letter.payload)?],
+                    source: letter.source,
+                }
+            } else {
+                return Err(ServerError::ValidationError(format!(
+                    "dead letter from {} has no replayable payload ({})",
+                    letter.source, letter.reason
+                )));
+            };
+
+            Ok(ReplayRecord {
+                timestamp: Some(letter.timestamp),
+                batch,
+            })
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<CaptureLine, ServerError> {
+    if encryption::is_sealed(line) {
+        return Err(ServerError::ValidationError(
+            "line is encrypted; open it with the key of its source first".into(),
+        ));
+    }
+    Ok(serde_json::from_str(line)?)
+}
+
+/// What a line replays: a batch, or a captured body sent as it was received
+/// so bodies that didn't parse fail again.
+enum Replayed {
+    Batch(ReplayRecord),
+    Payload(CapturedPayload),
+}
+
+impl Replayed {
+    fn parse(line: &str) -> Result<Self, ServerError> {
+        match parse_line(line)? {
+            CaptureLine::Payload(payload) => Ok(Self::Payload(payload)),
+            line => into_record(line).map(Self::Batch),
+        }
+    }
+}
+
+pub struct ReplayOptions {
+    /// Base URL of the server receiving the batches.
+    pub target: String,
+    /// Playback speed relative to the recorded timestamps, e.g. 10 for ten
+    /// times faster; 0 sends every batch without waiting.
+    pub speed: f64,
+    /// Opens the lines sealed with `encryption` keys.
+    pub keyring: Keyring,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub batches: usize,
+    pub metrics: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Replays a JSON-lines capture against `options.target`, pacing batches by
+/// their recorded timestamps.
+pub async fn replay(path: &Path, options: &ReplayOptions) -> Result<ReplaySummary, ServerError> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+    let mut lines = BufReader::new(file).lines();
+    let client = reqwest::Client::new();
+    let target = options.target.trim_end_matches('/');
+
+    let mut summary = ReplaySummary::default();
+    let mut origin: Option<(DateTime<Utc>, Instant)> = None;
+    let mut line_number = 0;
+
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?
+    {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let replayed = match options
+            .keyring
+            .open_line(&line)
+            .and_then(|line| Replayed::parse(&line))
+        {
+            Ok(replayed) => replayed,
+            Err(e) => {
+                warn!("Skipping line {}: {}", line_number, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let timestamp = match &replayed {
+            Replayed::Batch(record) => record.timestamp,
+            Replayed::Payload(payload) => Some(payload.timestamp),
+        };
+
+        if let (Some(timestamp), true) = (timestamp, options.speed > 0.0) {
+            let (first, started) = *origin.get_or_insert((timestamp, Instant::now()));
+            let offset = (timestamp - first).to_std().unwrap_or_default();
+            let due = started + offset.div_f64(options.speed);
+            tokio::time::sleep_until(due.into()).await;
+        }
+
+        let (request, metrics) = match replayed {
+            Replayed::Batch(record) => (
+                client
+                    .post(format!("{}/api/metrics", target))
+                    .json(&record.batch),
+                record.batch.metrics.len(),
+            ),
+            Replayed::Payload(payload) => {
+                let metrics = payload.batch().map_or(0, |batch| batch.metrics.len());
+                let content_type = payload
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("application/json");
+                let request = client
+                    .post(format!("{}/api/v{}/metrics", target, payload.version))
+                    .header(reqwest::header::CONTENT_TYPE, content_type)
+                    .body(payload.body.clone());
+                (request, metrics)
+            }
+        };
+        let result = request
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                summary.batches += 1;
+                summary.metrics += metrics;
+            }
+            Err(e) => {
+                warn!("Failed to replay line {}: {}", line_number, e);
+                summary.failed += 1;
+            }
+        }
+    }
+
+    info!(
+        "Replayed {} batches ({} metrics), {} failed, {} skipped",
+        summary.batches, summary.metrics, summary.failed, summary.skipped
+    );
+    Ok(summary)
+}
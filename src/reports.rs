@@ -0,0 +1,396 @@
+//! Summaries of the registry (the families with the most series, SLO status
+//! and source health) rendered as HTML or CSV on a cron schedule and sent
+//! to notification channels, e.g. for a weekly ops review.
+
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use crate::config::{ReportConfig, ReportFormat, ReportSection};
+use crate::errors::ServerError;
+use crate::metrics::MetricsCollector;
+use crate::metrics::cardinality::MetricCardinality;
+use crate::metrics::slo::{SloStatus, SloTracker};
+use crate::notify::Notifier;
+use chrono::{DateTime, SecondsFormat, Utc};
+use croner::Cron;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use tracing::error;
+use tracing::{debug, warn};
+
+/// Parses a report schedule: five cron fields, or six with seconds first.
+pub fn parse_schedule(schedule: &str) -> Result<Cron, ServerError> {
+    Cron::new(schedule)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| ServerError::ValidationError(format!("schedule '{}': {}", schedule, e)))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceHealth {
+    pub source: String,
+    pub series: usize,
+    pub registered: bool,
+    pub last_push: Option<DateTime<Utc>>,
+    /// A registered source that didn't push within its interval.
+    pub overdue: bool,
+}
+
+/// The data of a report, with only the configured sections set.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub name: String,
+    pub generated_at: DateTime<Utc>,
+    pub total_series: usize,
+    pub top_metrics: Option<Vec<MetricCardinality>>,
+    pub slos: Option<Vec<SloStatus>>,
+    pub sources: Option<Vec<SourceHealth>>,
+}
+
+impl Report {
+    pub async fn collect(
+        config: &ReportConfig,
+        collector: &MetricsCollector,
+        slos: &SloTracker,
+    ) -> Result<Self, ServerError> {
+        let wants = |section| config.sections.contains(&section);
+        let cardinality = collector.cardinality(config.top_metrics).await?;
+
+        let sources = wants(ReportSection::SourceHealth).then(|| {
+            let registrations = collector.registrations().list();
+            let names: BTreeSet<&String> = registrations
+                .iter()
+                .map(|status| &status.source)
+                .chain(cardinality.sources.keys())
+                .collect();
+            names
+                .into_iter()
+                .map(|name| {
+                    let registration = registrations.iter().find(|status| status.source == *name);
+                    SourceHealth {
+                        source: name.clone(),
+                        series: cardinality.sources.get(name).copied().unwrap_or_default(),
+                        registered: registration.is_some(),
+                        last_push: registration.and_then(|status| status.last_push),
+                        overdue: registration.is_some_and(|status| status.overdue),
+                    }
+                })
+                .collect()
+        });
+
+        Ok(Self {
+            name: config.name.clone(),
+            generated_at: Utc::now(),
+            total_series: cardinality.total_series,
+            top_metrics: wants(ReportSection::TopMetrics).then(|| cardinality.metrics.clone()),
+            slos: wants(ReportSection::SloStatus).then(|| slos.statuses()),
+            sources,
+        })
+    }
+
+    pub fn render(&self, format: ReportFormat) -> Result<RenderedReport, ServerError> {
+        let body = match format {
+            ReportFormat::Html => self.to_html(),
+            ReportFormat::Csv => self.to_csv()?,
+        };
+        Ok(RenderedReport {
+            name: self.name.clone(),
+            subject: format!(
+                "{} report, {}",
+                self.name,
+                self.generated_at.format("%Y-%m-%d %H:%M UTC")
+            ),
+            format,
+            body,
+        })
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{name}</title>\
+             <style>table{{border-collapse:collapse}}td,th{{border:1px solid #ccc;padding:4px 8px;text-align:left}}</style>\
+             </head><body>\n<h1>{name}</h1>\n<p>Generated at {at}, {total} series.</p>\n",
+            name = escape(&self.name),
+            at = self.generated_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            total = self.total_series,
+        );
+
+        if let Some(metrics) = &self.top_metrics {
+            table(
+                &mut html,
+                "Top metrics",
+                &["Metric", "Series"],
+                metrics
+                    .iter()
+                    .map(|metric| vec![metric.name.clone(), metric.series.to_string()]),
+            );
+        }
+        if let Some(slos) = &self.slos {
+            table(
+                &mut html,
+                "SLO status",
+                &["SLO", "Objective", "Good", "Total", "Error budget left"],
+                slos.iter().map(|slo| {
+                    vec![
+                        slo.name.clone(),
+                        slo.objective.to_string(),
+                        slo.good.to_string(),
+                        slo.total.to_string(),
+                        format!("{:.1}%", slo.error_budget_remaining * 100.0),
+                    ]
+                }),
+            );
+        }
+        if let Some(sources) = &self.sources {
+            table(
+                &mut html,
+                "Source health",
+                &["Source", "Series", "Registered", "Last push", "Overdue"],
+                sources.iter().map(|source| {
+                    vec![
+                        source.source.clone(),
+                        source.series.to_string(),
+                        source.registered.to_string(),
+                        source
+                            .last_push
+                            .map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true))
+                            .unwrap_or_default(),
+                        source.overdue.to_string(),
+                    ]
+                }),
+            );
+        }
+
+        html.push_str("</body></html>\n");
+        html
+    }
+
+    /// One `section,name,field,value` row per figure, a shape that loads
+    /// into a spreadsheet whatever the sections.
+    fn to_csv(&self) -> Result<String, ServerError> {
+        let mut rows: Vec<[String; 4]> = vec![[
+            "report".to_string(),
+            self.name.clone(),
+            "total_series".to_string(),
+            self.total_series.to_string(),
+        ]];
+        let mut row = |section: &str, name: &str, field: &str, value: String| {
+            rows.push([
+                section.to_string(),
+                name.to_string(),
+                field.to_string(),
+                value,
+            ])
+        };
+
+        for metric in self.top_metrics.iter().flatten() {
+            row(
+                "top_metrics",
+                &metric.name,
+                "series",
+                metric.series.to_string(),
+            );
+        }
+        for slo in self.slos.iter().flatten() {
+            row(
+                "slo_status",
+                &slo.name,
+                "objective",
+                slo.objective.to_string(),
+            );
+            row("slo_status", &slo.name, "good", slo.good.to_string());
+            row("slo_status", &slo.name, "total", slo.total.to_string());
+            row(
+                "slo_status",
+                &slo.name,
+                "error_budget_remaining",
+                slo.error_budget_remaining.to_string(),
+            );
+        }
+        for source in self.sources.iter().flatten() {
+            row(
+                "source_health",
+                &source.source,
+                "series",
+                source.series.to_string(),
+            );
+            row(
+                "source_health",
+                &source.source,
+                "registered",
+                source.registered.to_string(),
+            );
+            if let Some(last_push) = source.last_push {
+                row(
+                    "source_health",
+                    &source.source,
+                    "last_push",
+                    last_push.to_rfc3339_opts(SecondsFormat::Secs, true),
+                );
+            }
+            row(
+                "source_health",
+                &source.source,
+                "overdue",
+                source.overdue.to_string(),
+            );
+        }
+
+        write_csv(rows)
+    }
+}
+
+#[cfg(feature = "csv")]
+fn write_csv(rows: Vec<[String; 4]>) -> Result<String, ServerError> {
+    let fail = |e: csv::Error| ServerError::InternalError(Box::new(e));
+    let mut csv = csv::Writer::from_writer(Vec::new());
+    csv.write_record(["section", "name", "field", "value"])
+        .map_err(fail)?;
+    for row in rows {
+        csv.write_record(row).map_err(fail)?;
+    }
+    let bytes = csv
+        .into_inner()
+        .map_err(|e| ServerError::InternalError(Box::new(e.into_error())))?;
+    String::from_utf8(bytes).map_err(|e| ServerError::InternalError(Box::new(e)))
+}
+
+#[cfg(not(feature = "csv"))]
+fn write_csv(_rows: Vec<[String; 4]>) -> Result<String, ServerError> {
+    Err(ServerError::ConfigurationError(
+        "CSV reports require the `csv` feature".to_string(),
+    ))
+}
+
+fn table(
+    html: &mut String,
+    title: &str,
+    headers: &[&str],
+    rows: impl Iterator<Item = Vec<String>>,
+) {
+    let _ = write!(html, "<h2>{}</h2>\n<table>\n<tr>", title);
+    for header in headers {
+        let _ = write!(html, "<th>{}</th>", header);
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(&cell));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A report as sent to a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedReport {
+    pub name: String,
+    pub subject: String,
+    pub format: ReportFormat,
+    pub body: String,
+}
+
+impl RenderedReport {
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            ReportFormat::Html => "text/html; charset=utf-8",
+            ReportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn file_name(&self) -> String {
+        let extension = match self.format {
+            ReportFormat::Html => "html",
+            ReportFormat::Csv => "csv",
+        };
+        format!(
+            "{}-{}.{}",
+            self.name,
+            Utc::now().format("%Y-%m-%d"),
+            extension
+        )
+    }
+}
+
+/// Sends `report` to every channel of `config`, returning how many took it.
+/// Failures are logged, one channel failing doesn't stop the others.
+pub async fn deliver(notifier: &Notifier, config: &ReportConfig, report: &RenderedReport) -> usize {
+    let mut delivered = 0;
+    for name in &config.channels {
+        let Some(channel) = notifier.channel(name) else {
+            warn!(
+                "Report {} targets unknown notification channel {}",
+                report.name, name
+            );
+            continue;
+        };
+        match channel.send_report(report).await {
+            Ok(()) => {
+                debug!("Sent report {} to {}", report.name, name);
+                delivered += 1;
+            }
+            Err(e) => warn!("Failed to send report {} to {}: {}", report.name, name, e),
+        }
+    }
+    delivered
+}
+
+/// Renders and sends every configured report on its schedule.
+#[cfg(feature = "server")]
+pub fn spawn_reports(state: Arc<AppState>) {
+    for (index, config) in state.config.reports.iter().enumerate() {
+        let schedule = match parse_schedule(&config.schedule) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                error!("Not scheduling report {}: {}", config.name, e);
+                continue;
+            }
+        };
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let config = &state.config.reports[index];
+            loop {
+                let now = Utc::now();
+                let next = match schedule.find_next_occurrence(&now, false) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        warn!("Report {} has no next run: {}", config.name, e);
+                        return;
+                    }
+                };
+                tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+                let report = Report::collect(config, &state.metrics_collector, &state.slos).await;
+                match report.and_then(|report| report.render(config.format)) {
+                    Ok(rendered) => {
+                        deliver(state.metrics_collector.notifier(), config, &rendered).await;
+                    }
+                    Err(e) => warn!("Failed to render report {}: {}", config.name, e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,26 @@
+#[cfg(target_os = "linux")]
+pub mod systemd;
+#[cfg(windows)]
+pub mod windows;
+
+use actix_web::dev::ServerHandle;
+
+/// Tells the platform service manager that the server is accepting requests:
+/// `READY=1` plus watchdog pings under systemd, the `Running` state under the
+/// Windows service control manager. A no-op when not run by either.
+pub fn notify_ready(server: &ServerHandle) {
+    #[cfg(target_os = "linux")]
+    systemd::notify_ready();
+    #[cfg(windows)]
+    windows::notify_running(server.clone());
+    #[cfg(not(windows))]
+    let _ = server;
+}
+
+/// Tells the platform service manager that the server is shutting down.
+pub fn notify_stopping() {
+    #[cfg(target_os = "linux")]
+    systemd::notify_stopping();
+    #[cfg(windows)]
+    windows::notify_stopping();
+}
@@ -0,0 +1,77 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Sends a state such as `READY=1` to the socket named by `NOTIFY_SOCKET`.
+/// Returns `false` when the process wasn't started by systemd with
+/// `Type=notify`.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => {
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?
+        }
+        None => socket.send_to(state.as_bytes(), &path)?,
+    };
+    Ok(true)
+}
+
+/// How often systemd expects `WATCHDOG=1`: half of `WATCHDOG_USEC`, as
+/// `sd_watchdog_enabled(3)` recommends, when the watchdog targets this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+
+    env::var("WATCHDOG_USEC")
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(|usec| Duration::from_micros(usec / 2))
+}
+
+pub(crate) fn notify_ready() {
+    match notify("READY=1") {
+        Ok(true) => debug!("Notified systemd of readiness"),
+        Ok(false) => return,
+        Err(e) => {
+            warn!("Failed to notify systemd of readiness: {}", e);
+            return;
+        }
+    }
+
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    info!(
+        "Pinging the systemd watchdog every {}ms",
+        interval.as_millis()
+    );
+    // Pings come from the server's runtime, so a wedged runtime stops them
+    // and systemd restarts the process.
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = notify("WATCHDOG=1") {
+                warn!("Failed to ping the systemd watchdog: {}", e);
+            }
+        }
+    });
+}
+
+pub(crate) fn notify_stopping() {
+    if let Err(e) = notify("STOPPING=1") {
+        warn!("Failed to notify systemd of shutdown: {}", e);
+    }
+}
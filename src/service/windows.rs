@@ -0,0 +1,122 @@
+use actix_web::dev::ServerHandle;
+use std::ffi::OsString;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{error, warn};
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// Name the service is registered under with `sc.exe create`.
+pub const SERVICE_NAME: &str = "rustic-insights";
+
+/// Hint given to the service control manager for start and stop transitions.
+const PENDING_WAIT_HINT: Duration = Duration::from_secs(30);
+
+static SERVE: OnceLock<fn() -> io::Result<()>> = OnceLock::new();
+static STATUS: OnceLock<ServiceStatusHandle> = OnceLock::new();
+static SERVER: Mutex<Option<ServerHandle>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hands the process over to the service control manager, which runs `serve`
+/// on its own thread. Returns once the service has stopped.
+pub fn run(serve: fn() -> io::Result<()>) -> io::Result<()> {
+    let _ = SERVE.set(serve);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(io::Error::other)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let handler = |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            notify_stopping();
+            if let Some(server) = SERVER.lock().ok().and_then(|server| server.clone()) {
+                // Stopping is requested when called; completion isn't awaited
+                // here so the control handler returns promptly.
+                drop(server.stop(true));
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+
+    match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(status) => {
+            let _ = STATUS.set(status);
+        }
+        Err(e) => {
+            error!("Failed to register the service control handler: {}", e);
+            return;
+        }
+    }
+
+    set_status(ServiceState::StartPending, 0);
+
+    // Services start in the system directory; configuration is looked up
+    // relative to the binary like when it's run from its install directory.
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+        && let Err(e) = std::env::set_current_dir(&dir)
+    {
+        warn!("Failed to switch to {}: {}", dir.display(), e);
+    }
+
+    let exit_code = match SERVE.get().map(|serve| serve()) {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => {
+            error!("Service failed: {}", e);
+            1
+        }
+        None => 1,
+    };
+    set_status(ServiceState::Stopped, exit_code);
+}
+
+pub(crate) fn notify_running(server: ServerHandle) {
+    if let Ok(mut current) = SERVER.lock() {
+        *current = Some(server);
+    }
+    set_status(ServiceState::Running, 0);
+}
+
+pub(crate) fn notify_stopping() {
+    set_status(ServiceState::StopPending, 0);
+}
+
+fn set_status(state: ServiceState, exit_code: u32) {
+    // Not running under the service control manager
+    let Some(status) = STATUS.get() else {
+        return;
+    };
+
+    let (controls_accepted, wait_hint) = match state {
+        ServiceState::Running => (
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            Duration::default(),
+        ),
+        ServiceState::StartPending | ServiceState::StopPending => {
+            (ServiceControlAccept::empty(), PENDING_WAIT_HINT)
+        }
+        _ => (ServiceControlAccept::empty(), Duration::default()),
+    };
+
+    let result = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    });
+    if let Err(e) = result {
+        warn!("Failed to report service state {:?}: {}", state, e);
+    }
+}
@@ -0,0 +1,208 @@
+pub mod fanout;
+pub mod graphite;
+pub mod pushgateway;
+pub mod remote_write;
+pub mod spill;
+
+pub use graphite::GraphiteSink;
+pub use pushgateway::PushgatewaySink;
+pub use remote_write::RemoteWriteSink;
+pub use spill::SpillQueue;
+
+#[cfg(feature = "server")]
+use crate::api::handlers::AppState;
+use crate::config::{SinkConfig, SinkKind};
+use crate::encryption::Cipher;
+use crate::errors::ServerError;
+#[cfg(feature = "server")]
+use crate::metrics::exposition::attach_labels;
+use crate::metrics::self_metrics::SelfMetrics;
+use futures::future::BoxFuture;
+use prometheus::proto::{MetricFamily, MetricType};
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::time::Duration;
+#[cfg(feature = "server")]
+use tracing::{debug, error, warn};
+
+/// A destination the registry is periodically exported to, for long-term
+/// storage that isn't scraping the gateway.
+pub trait EgressSink: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn export<'a>(&'a self, families: &'a [MetricFamily])
+    -> BoxFuture<'a, Result<(), ServerError>>;
+}
+
+/// A single flattened sample, the common denominator of non-Prometheus sinks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// When the sample was taken, in Unix milliseconds, if its series was
+    /// stamped with it.
+    pub timestamp_ms: Option<i64>,
+}
+
+/// Flattens metric families into samples the way the text exposition does:
+/// histograms become `_bucket` (with `le`), `_sum` and `_count` samples.
+pub fn samples(families: &[MetricFamily]) -> Vec<Sample> {
+    let mut samples = Vec::new();
+
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let labels: Vec<(String, String)> = metric
+                .get_label()
+                .iter()
+                .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+                .collect();
+            let timestamp_ms = metric.has_timestamp_ms().then(|| metric.get_timestamp_ms());
+            let mut push = |name: String, labels: Vec<(String, String)>, value: f64| {
+                samples.push(Sample {
+                    name,
+                    labels,
+                    value,
+                    timestamp_ms,
+                })
+            };
+
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    push(name.to_string(), labels, metric.get_counter().get_value())
+                }
+                MetricType::GAUGE => push(name.to_string(), labels, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => {
+                    push(name.to_string(), labels, metric.get_untyped().get_value())
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let mut bucket_labels = labels.clone();
+                        bucket_labels
+                            .push(("le".to_string(), bucket.get_upper_bound().to_string()));
+                        push(
+                            format!("{}_bucket", name),
+                            bucket_labels,
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    let mut inf_labels = labels.clone();
+                    inf_labels.push(("le".to_string(), "+Inf".to_string()));
+                    push(
+                        format!("{}_bucket", name),
+                        inf_labels,
+                        histogram.get_sample_count() as f64,
+                    );
+                    push(
+                        format!("{}_sum", name),
+                        labels.clone(),
+                        histogram.get_sample_sum(),
+                    );
+                    push(
+                        format!("{}_count", name),
+                        labels,
+                        histogram.get_sample_count() as f64,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    push(
+                        format!("{}_sum", name),
+                        labels.clone(),
+                        summary.get_sample_sum(),
+                    );
+                    push(
+                        format!("{}_count", name),
+                        labels,
+                        summary.get_sample_count() as f64,
+                    );
+                }
+            }
+        }
+    }
+
+    samples
+}
+
+pub fn build_sink(
+    config: &SinkConfig,
+    self_metrics: &SelfMetrics,
+) -> Result<Box<dyn EgressSink>, ServerError> {
+    Ok(match &config.kind {
+        SinkKind::Pushgateway { url, job } => Box::new(PushgatewaySink::new(url, job)),
+        SinkKind::Graphite {
+            address,
+            prefix,
+            tagged,
+        } => Box::new(GraphiteSink::new(address, prefix.as_deref(), *tagged)),
+        SinkKind::RemoteWrite { url, spill } => {
+            let sink = RemoteWriteSink::new(url);
+            match spill {
+                Some(spill) => {
+                    let quarantined = self_metrics
+                        .spill_quarantined_payloads_total
+                        .with_label_values(&[sink.name()]);
+                    let mut queue = SpillQueue::open(&spill.directory, spill.max_bytes)?
+                        .with_quarantine_counter(quarantined);
+                    if let Some(key) = &spill.encryption_key {
+                        queue = queue.with_cipher(Cipher::new(key)?);
+                        if spill.read_plaintext {
+                            queue = queue.with_plaintext_fallback();
+                        }
+                    }
+                    Box::new(sink.with_spill(queue))
+                }
+                None => Box::new(sink),
+            }
+        }
+    })
+}
+
+/// Spawns one export loop per configured sink. Each sink reports its last
+/// export result to the health checks, which gates readiness.
+#[cfg(feature = "server")]
+pub fn spawn_exporters(state: Arc<AppState>) {
+    for config in state.config.sinks.clone() {
+        let sink = match build_sink(&config, state.metrics_collector.self_metrics()) {
+            Ok(sink) => sink,
+            Err(e) => {
+                error!("Failed to set up sink {:?}: {}", config.kind, e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let component = format!("sink:{}", sink.name());
+        state
+            .health
+            .set(&component, true, "no export attempted yet");
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+            loop {
+                interval.tick().await;
+                let mut families = match state.metrics_collector.gather_exposed().await {
+                    Ok(snapshot) => snapshot.families,
+                    Err(e) => {
+                        warn!("Failed to gather metrics for {}: {}", sink.name(), e);
+                        state.health.set(&component, false, e.to_string());
+                        continue;
+                    }
+                };
+                attach_labels(&mut families, &state.config.metrics.external_labels);
+                match sink.export(&families).await {
+                    Ok(()) => {
+                        debug!("Exported {} families to {}", families.len(), sink.name());
+                        state.health.set(&component, true, "last export succeeded");
+                    }
+                    Err(e) => {
+                        warn!("Failed to export metrics to {}: {}", sink.name(), e);
+                        state.health.set(&component, false, e.to_string());
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,412 @@
+#[cfg(feature = "kafka")]
+pub mod kafka;
+
+use crate::capture::encode_line;
+use crate::config::{FanoutConfig, FanoutKind, MetricsConfig};
+use crate::encryption::Keyring;
+use crate::errors::ServerError;
+use crate::health::HealthChecks;
+use crate::metrics::exposition::attach_labels;
+use crate::metrics::self_metrics::SelfMetrics;
+use crate::metrics::{MetricsBatch, MetricsCollector, MetricsRegistry};
+use crate::sinks::remote_write::{RemoteWriteSink, encode_write_request};
+use crate::sinks::samples;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+use tracing::{debug, error, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// How long a `remote_write` destination keeps aggregating a series not
+/// pushed again, unless `metrics.series_ttl_secs` is set.
+const SINK_SERIES_TTL_SECS: u64 = 3600;
+/// The memory a `remote_write` destination's series may take, unless
+/// `metrics.max_memory_bytes` is set.
+const SINK_MAX_MEMORY_BYTES: u64 = 256 * 1024 * 1024;
+
+/// A destination accepted batches are delivered to. Delivery is split in
+/// two so a retry resends the same payload instead of encoding the batch
+/// again, which aggregating destinations can't do twice.
+pub trait BatchSink: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn encode<'a>(&'a self, batch: &'a MetricsBatch)
+    -> BoxFuture<'a, Result<Vec<u8>, ServerError>>;
+
+    fn send<'a>(
+        &'a self,
+        batch: &'a MetricsBatch,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), ServerError>>;
+}
+
+/// What became of a batch handed to a destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    Queued,
+    /// The destination's queue was full.
+    Dropped,
+}
+
+/// The destination of `kind`. Batches appended to a local file are
+/// encrypted with the key of their source in `keyring`.
+pub fn build_batch_sink(
+    kind: &FanoutKind,
+    metrics: &MetricsConfig,
+    keyring: Arc<Keyring>,
+) -> Result<Box<dyn BatchSink>, ServerError> {
+    match kind {
+        FanoutKind::RemoteWrite { url } => Ok(Box::new(RemoteWriteBatchSink::new(url, metrics)?)),
+        FanoutKind::File { path } => Ok(Box::new(FileBatchSink::new(path).with_keyring(keyring))),
+        #[cfg(feature = "kafka")]
+        FanoutKind::Kafka { brokers, topic } => {
+            Ok(Box::new(kafka::KafkaBatchSink::new(brokers, topic)?))
+        }
+        #[allow(unreachable_patterns)]
+        other => Err(ServerError::ConfigurationError(format!(
+            "Fan-out destination {:?} requires building with the matching feature",
+            other
+        ))),
+    }
+}
+
+struct SinkQueue {
+    name: String,
+    sender: Sender<Arc<MetricsBatch>>,
+}
+
+struct Worker {
+    sink: Box<dyn BatchSink>,
+    receiver: Receiver<Arc<MetricsBatch>>,
+    max_retries: u32,
+}
+
+/// Delivers every accepted batch to the configured destinations. Each one
+/// has its own bounded queue and delivery task, so an unreachable endpoint
+/// only fills its own queue; its health is reported as `fanout:<name>`.
+pub struct BatchFanout {
+    registry: bool,
+    queues: Vec<SinkQueue>,
+    // Taken by `spawn`
+    workers: Mutex<Vec<Worker>>,
+    health: Arc<HealthChecks>,
+    self_metrics: SelfMetrics,
+}
+
+impl BatchFanout {
+    /// Sets up the destinations of `configs`, sealing file lines with the
+    /// keys of `keyring`. One that can't be set up is logged and left out,
+    /// like the egress sinks.
+    pub fn new(
+        configs: &[FanoutConfig],
+        metrics: &MetricsConfig,
+        health: Arc<HealthChecks>,
+        self_metrics: SelfMetrics,
+        keyring: Arc<Keyring>,
+    ) -> Self {
+        let mut queues = Vec::new();
+        let mut workers = Vec::new();
+        for config in configs {
+            if config.kind == FanoutKind::Registry {
+                continue;
+            }
+            let sink = match build_batch_sink(&config.kind, metrics, keyring.clone()) {
+                Ok(sink) => sink,
+                Err(e) => {
+                    error!(
+                        "Failed to set up fan-out destination {:?}: {}",
+                        config.kind, e
+                    );
+                    continue;
+                }
+            };
+            let (sender, receiver) = mpsc::channel(config.queue_size);
+            queues.push(SinkQueue {
+                name: sink.name().to_string(),
+                sender,
+            });
+            workers.push(Worker {
+                sink,
+                receiver,
+                max_retries: config.max_retries,
+            });
+        }
+
+        Self {
+            registry: configs
+                .iter()
+                .any(|config| config.kind == FanoutKind::Registry),
+            queues,
+            workers: Mutex::new(workers),
+            health,
+            self_metrics,
+        }
+    }
+
+    /// Whether batches are written to the local registry.
+    pub fn writes_registry(&self) -> bool {
+        self.registry
+    }
+
+    /// Whether there's a destination besides the local registry.
+    pub fn has_destinations(&self) -> bool {
+        !self.queues.is_empty()
+    }
+
+    /// Queues `batch` for every destination. A full queue drops the batch for
+    /// that destination only.
+    pub fn deliver(&self, batch: MetricsBatch) -> BTreeMap<String, Delivery> {
+        let batch = Arc::new(batch);
+        self.queues
+            .iter()
+            .map(|queue| {
+                let delivery = match queue.sender.try_send(batch.clone()) {
+                    Ok(()) => Delivery::Queued,
+                    Err(TrySendError::Full(_) | TrySendError::Closed(_)) => {
+                        warn!("Dropping batch from {} for {}", batch.source, queue.name);
+                        self.health.set(
+                            &component(&queue.name),
+                            false,
+                            "queue full, dropping batches",
+                        );
+                        self.self_metrics
+                            .fanout_batches_total
+                            .with_label_values(&[queue.name.as_str(), "dropped"])
+                            .inc();
+                        Delivery::Dropped
+                    }
+                };
+                (queue.name.clone(), delivery)
+            })
+            .collect()
+    }
+
+    /// Spawns the delivery task of every destination; later calls do nothing.
+    pub fn spawn(&self) {
+        let workers = match self.workers.lock() {
+            Ok(mut workers) => std::mem::take(&mut *workers),
+            Err(_) => return,
+        };
+        for worker in workers {
+            let component = component(worker.sink.name());
+            self.health.set(&component, true, "no batch delivered yet");
+            tokio::spawn(run(
+                worker,
+                component,
+                self.health.clone(),
+                self.self_metrics.clone(),
+            ));
+        }
+    }
+}
+
+fn component(name: &str) -> String {
+    format!("fanout:{}", name)
+}
+
+async fn run(
+    mut worker: Worker,
+    component: String,
+    health: Arc<HealthChecks>,
+    self_metrics: SelfMetrics,
+) {
+    while let Some(batch) = worker.receiver.recv().await {
+        let sink = worker.sink.as_ref();
+        let outcome = match deliver(sink, &batch, worker.max_retries).await {
+            Ok(()) => {
+                debug!("Delivered batch from {} to {}", batch.source, sink.name());
+                health.set(&component, true, "last delivery succeeded");
+                "delivered"
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to deliver batch from {} to {}: {}",
+                    batch.source,
+                    sink.name(),
+                    e
+                );
+                health.set(&component, false, e.to_string());
+                "failed"
+            }
+        };
+        self_metrics
+            .fanout_batches_total
+            .with_label_values(&[sink.name(), outcome])
+            .inc();
+    }
+}
+
+/// Encodes the batch once and sends it, retrying transient failures with
+/// exponential backoff.
+async fn deliver(
+    sink: &dyn BatchSink,
+    batch: &MetricsBatch,
+    max_retries: u32,
+) -> Result<(), ServerError> {
+    let payload = sink.encode(batch).await?;
+    let mut attempt = 0;
+    loop {
+        match sink.send(batch, &payload).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is_retryable() && attempt < max_retries => {
+                let backoff = INITIAL_BACKOFF
+                    .saturating_mul(2_u32.saturating_pow(attempt))
+                    .min(MAX_BACKOFF);
+                debug!("Retrying {} in {:?}: {}", sink.name(), backoff, e);
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Forwards the series a batch wrote to a remote_write endpoint. Pushed
+/// counters are increments, so batches go through a registry of the sink's
+/// own, and the series of the batch's source are sent with their totals.
+/// That registry is bounded in memory and compacted as the gateway's is, so
+/// series no longer pushed are forgotten.
+pub struct RemoteWriteBatchSink {
+    name: String,
+    collector: MetricsCollector,
+    sink: RemoteWriteSink,
+    external_labels: HashMap<String, String>,
+    compaction_interval: Duration,
+    last_compaction: Mutex<Instant>,
+}
+
+impl RemoteWriteBatchSink {
+    pub fn new(url: &str, metrics: &MetricsConfig) -> Result<Self, ServerError> {
+        Ok(Self {
+            name: format!("remote_write:{}", url),
+            // The registrations belong to the gateway's own registry
+            collector: MetricsCollector::new(MetricsRegistry::new(MetricsConfig {
+                sources_path: None,
+                series_ttl_secs: metrics.series_ttl_secs.or(Some(SINK_SERIES_TTL_SECS)),
+                max_memory_bytes: metrics.max_memory_bytes.or(Some(SINK_MAX_MEMORY_BYTES)),
+                ..metrics.clone()
+            })?),
+            sink: RemoteWriteSink::new(url),
+            external_labels: metrics.external_labels.clone(),
+            compaction_interval: Duration::from_secs(metrics.compaction_interval_secs),
+            last_compaction: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Compacts the sink's registry when an interval went by since it last was.
+    async fn compact_if_due(&self) {
+        {
+            let mut last = self
+                .last_compaction
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if last.elapsed() < self.compaction_interval {
+                return;
+            }
+            *last = Instant::now();
+        }
+        let stats = self.collector.compact().await;
+        debug!(
+            "Expired {} series aggregated for {}",
+            stats.expired_series, self.name
+        );
+    }
+}
+
+impl BatchSink for RemoteWriteBatchSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn encode<'a>(
+        &'a self,
+        batch: &'a MetricsBatch,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ServerError>> {
+        Box::pin(async move {
+            self.compact_if_due().await;
+            self.collector.process_batch(batch.clone()).await?;
+            let mut families = self
+                .collector
+                .gather_source_families(&batch.source)
+                .await?
+                .unwrap_or_default();
+            attach_labels(&mut families, &self.external_labels);
+            encode_write_request(&samples(&families), Utc::now().timestamp_millis())
+        })
+    }
+
+    fn send<'a>(
+        &'a self,
+        _batch: &'a MetricsBatch,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(self.sink.send_payload(payload.to_vec()))
+    }
+}
+
+/// Appends batches as JSON lines to a local file, in the format `replay` reads.
+pub struct FileBatchSink {
+    name: String,
+    path: PathBuf,
+    keyring: Arc<Keyring>,
+}
+
+impl FileBatchSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            name: format!("file:{}", path.display()),
+            path,
+            keyring: Arc::default(),
+        }
+    }
+
+    /// Seals each line with the key of the batch's source.
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+}
+
+impl BatchSink for FileBatchSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn encode<'a>(
+        &'a self,
+        batch: &'a MetricsBatch,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ServerError>> {
+        Box::pin(async move { self.keyring.seal_line(&batch.source, encode_line(batch)?) })
+    }
+
+    fn send<'a>(
+        &'a self,
+        _batch: &'a MetricsBatch,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        // Only this sink's task writes the file, so lines never interleave
+        Box::pin(async move {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            file.write_all(payload)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            file.flush()
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use super::BatchSink;
+use crate::errors::ServerError;
+use crate::metrics::MetricsBatch;
+use futures::future::BoxFuture;
+use rdkafka::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::time::Duration;
+
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Produces batches as JSON to a Kafka topic, keyed by the batch source so
+/// the batches of a source stay in order within a partition.
+pub struct KafkaBatchSink {
+    name: String,
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaBatchSink {
+    pub fn new(brokers: &str, topic: &str) -> Result<Self, ServerError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create()
+            .map_err(|e| ServerError::ConfigurationError(format!("Kafka producer: {}", e)))?;
+
+        Ok(Self {
+            name: format!("kafka:{}", topic),
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+impl BatchSink for KafkaBatchSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn encode<'a>(
+        &'a self,
+        batch: &'a MetricsBatch,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ServerError>> {
+        Box::pin(async move { Ok(serde_json::to_vec(batch)?) })
+    }
+
+    fn send<'a>(
+        &'a self,
+        batch: &'a MetricsBatch,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let record = FutureRecord::to(&self.topic)
+                .key(&batch.source)
+                .payload(payload);
+
+            self.producer
+                .send(record, QUEUE_TIMEOUT)
+                .await
+                .map(|_| ())
+                .map_err(|(e, _)| ServerError::InternalError(Box::new(e)))
+        })
+    }
+}
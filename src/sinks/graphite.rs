@@ -0,0 +1,95 @@
+use crate::errors::ServerError;
+use crate::sinks::{EgressSink, Sample, samples};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use prometheus::proto::MetricFamily;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// Writes samples to a Graphite/Carbon endpoint with the plaintext protocol.
+pub struct GraphiteSink {
+    name: String,
+    address: String,
+    prefix: Option<String>,
+    tagged: bool,
+}
+
+impl GraphiteSink {
+    pub fn new(address: &str, prefix: Option<&str>, tagged: bool) -> Self {
+        Self {
+            name: format!("graphite:{}", address),
+            address: address.to_string(),
+            prefix: prefix.map(|prefix| prefix.to_string()),
+            tagged,
+        }
+    }
+
+    /// Renders a sample as a plaintext line. Tagged mode uses the Graphite 1.1
+    /// `name;tag=value` syntax, otherwise label values become path segments.
+    /// Samples pushed with a timestamp keep it; `now` (Unix seconds) stamps
+    /// the rest.
+    pub fn line(&self, sample: &Sample, now: i64) -> String {
+        let mut path = match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, sample.name),
+            None => sample.name.clone(),
+        };
+
+        for (name, value) in &sample.labels {
+            if self.tagged {
+                path.push_str(&format!(";{}={}", name, sanitize(value)));
+            } else {
+                path.push_str(&format!(".{}", sanitize(value)));
+            }
+        }
+
+        let timestamp = sample
+            .timestamp_ms
+            .map(|ms| ms.div_euclid(1000))
+            .unwrap_or(now);
+        format!("{} {} {}\n", path, sample.value, timestamp)
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ' ' | ';' | '~' | '.' | '=' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl EgressSink for GraphiteSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn export<'a>(
+        &'a self,
+        families: &'a [MetricFamily],
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let now = Utc::now().timestamp();
+            let payload: String = samples(families)
+                .iter()
+                .map(|sample| self.line(sample, now))
+                .collect();
+
+            let mut stream = TcpStream::connect(&self.address)
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            stream
+                .write_all(payload.as_bytes())
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            stream
+                .shutdown()
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+}
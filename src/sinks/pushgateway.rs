@@ -0,0 +1,54 @@
+use crate::errors::ServerError;
+use crate::sinks::EgressSink;
+use futures::future::BoxFuture;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+
+/// Replaces the metrics of a grouping key on a classic Prometheus Pushgateway.
+pub struct PushgatewaySink {
+    name: String,
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl PushgatewaySink {
+    pub fn new(url: &str, job: &str) -> Self {
+        Self {
+            name: format!("pushgateway:{}", job),
+            endpoint: format!("{}/metrics/job/{}", url.trim_end_matches('/'), job),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl EgressSink for PushgatewaySink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn export<'a>(
+        &'a self,
+        families: &'a [MetricFamily],
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let mut body = Vec::new();
+            let encoder = TextEncoder::new();
+            encoder
+                .encode(families, &mut body)
+                .map_err(|e| ServerError::MetricsProcessingError(e.to_string()))?;
+
+            // PUT replaces every metric of the grouping key, so series removed
+            // from the registry disappear from the Pushgateway as well
+            self.client
+                .put(&self.endpoint)
+                .header("Content-Type", encoder.format_type())
+                .body(body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,195 @@
+use crate::errors::ServerError;
+use crate::sinks::spill::SpillQueue;
+use crate::sinks::{EgressSink, Sample, samples};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use prometheus::proto::MetricFamily;
+use reqwest::StatusCode;
+use tracing::{info, warn};
+
+/// Forwards the registry to a Prometheus remote_write endpoint (Mimir,
+/// Thanos receive, VictoriaMetrics, ...). With a spill queue, payloads that
+/// can't be delivered are kept on disk and sent, oldest first, once the
+/// endpoint is reachable again.
+pub struct RemoteWriteSink {
+    name: String,
+    url: String,
+    client: reqwest::Client,
+    spill: Option<SpillQueue>,
+}
+
+/// Why a payload wasn't delivered: transient failures are worth spilling
+/// and retrying, rejections would fail the same way again.
+enum SendError {
+    Transient(ServerError),
+    Rejected(ServerError),
+}
+
+impl RemoteWriteSink {
+    pub fn new(url: &str) -> Self {
+        Self {
+            name: format!("remote_write:{}", url),
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+            spill: None,
+        }
+    }
+
+    pub fn with_spill(mut self, spill: SpillQueue) -> Self {
+        self.spill = Some(spill);
+        self
+    }
+
+    async fn send(&self, payload: Vec<u8>) -> Result<(), SendError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/x-protobuf")
+            .header("Content-Encoding", "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| SendError::Transient(ServerError::InternalError(Box::new(e))))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let error =
+            ServerError::InternalError(format!("remote_write endpoint answered {}", status).into());
+        // Receivers answer 429 and 5xx for overload and outages, other 4xx
+        // for payloads they will never accept
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(SendError::Transient(error))
+        } else {
+            Err(SendError::Rejected(error))
+        }
+    }
+
+    /// Sends an encoded `WriteRequest`. Payloads the endpoint rejects come
+    /// back as validation errors, so callers don't retry them.
+    pub async fn send_payload(&self, payload: Vec<u8>) -> Result<(), ServerError> {
+        self.send(payload).await.map_err(|e| match e {
+            SendError::Transient(e) => e,
+            SendError::Rejected(e) => ServerError::ValidationError(e.to_string()),
+        })
+    }
+
+    /// Sends spilled payloads oldest first, stopping at the first transient
+    /// failure. Rejected payloads are dropped so they can't block the queue,
+    /// as unreadable ones are quarantined by it.
+    async fn drain(&self, spill: &SpillQueue) -> Result<(), ServerError> {
+        let mut drained = 0;
+        while let Some((seq, payload)) = spill.peek().await? {
+            match self.send(payload).await {
+                Ok(()) => drained += 1,
+                Err(SendError::Rejected(e)) => {
+                    warn!("Dropping spilled payload rejected by {}: {}", self.name, e);
+                }
+                Err(SendError::Transient(e)) => return Err(e),
+            }
+            spill.remove(seq).await?;
+        }
+
+        if drained > 0 {
+            info!("Drained {} spilled payloads to {}", drained, self.name);
+        }
+        Ok(())
+    }
+}
+
+impl EgressSink for RemoteWriteSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn export<'a>(
+        &'a self,
+        families: &'a [MetricFamily],
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let payload = encode_write_request(&samples(families), Utc::now().timestamp_millis())?;
+
+            let Some(spill) = &self.spill else {
+                return self.send(payload).await.map_err(|e| match e {
+                    SendError::Transient(e) | SendError::Rejected(e) => e,
+                });
+            };
+
+            // Older data goes first so receivers see every series in order
+            let result = match self.drain(spill).await {
+                Ok(()) => self.send(payload.clone()).await,
+                Err(e) => Err(SendError::Transient(e)),
+            };
+            match result {
+                Ok(()) => Ok(()),
+                Err(SendError::Rejected(e)) => Err(e),
+                Err(SendError::Transient(e)) => {
+                    spill.push(&payload).await?;
+                    warn!(
+                        "Spilled {} bytes for {} ({} payloads, {} bytes queued)",
+                        payload.len(),
+                        self.name,
+                        spill.len().await,
+                        spill.bytes().await
+                    );
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// Encodes samples as a snappy-compressed `prometheus.WriteRequest`, every
+/// sample stamped with `timestamp_ms`.
+pub fn encode_write_request(samples: &[Sample], timestamp_ms: i64) -> Result<Vec<u8>, ServerError> {
+    let mut request = Vec::new();
+    for sample in samples {
+        let mut labels: Vec<(&str, &str)> = sample
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        labels.push(("__name__", sample.name.as_str()));
+        labels.sort_unstable();
+
+        let mut series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut series, 1, &label);
+        }
+        let mut point = Vec::new();
+        put_key(&mut point, 1, 1);
+        point.extend_from_slice(&sample.value.to_le_bytes());
+        put_key(&mut point, 2, 0);
+        put_varint(&mut point, timestamp_ms as u64);
+        put_bytes(&mut series, 2, &point);
+
+        put_bytes(&mut request, 1, &series);
+    }
+
+    snap::raw::Encoder::new()
+        .compress_vec(&request)
+        .map_err(|e| ServerError::InternalError(Box::new(e)))
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
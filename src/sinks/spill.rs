@@ -0,0 +1,320 @@
+use crate::encryption::{Cipher, crypto_error};
+use crate::errors::ServerError;
+use prometheus::IntCounter;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const SPILL_EXTENSION: &str = "spill";
+
+/// Subdirectory of the spill that payloads which can't be read are moved to.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Holds the sequence number the spill may use up to, so numbers aren't
+/// reused after a restart even once the spill has been drained.
+const SEQUENCE_FILE: &str = "sequence";
+
+/// Sequence numbers reserved at a time, so the high-water mark is written
+/// once per this many payloads rather than with every one.
+const SEQUENCE_BLOCK: u64 = 1024;
+
+/// Leads encrypted payloads, followed by the nonce and the sealed payload.
+/// The payload's sequence number is authenticated along with it, so spill
+/// files can't be reordered or swapped.
+const ENCRYPTED_MAGIC: &[u8] = b"RISPILL1";
+
+fn additional_data(seq: u64) -> Vec<u8> {
+    [ENCRYPTED_MAGIC, &seq.to_be_bytes()].concat()
+}
+
+/// A disk-backed FIFO of encoded payloads, one file per payload, bounded by
+/// total size. When full, the oldest payloads are dropped to make room, so
+/// an outage longer than the spill can hold loses its start rather than its
+/// end. Files left by a previous run are picked up on open. Payloads that
+/// can't be read are moved to a `quarantine` subdirectory, so they don't
+/// hold back the ones behind them; files moved back are only picked up by
+/// the next open.
+pub struct SpillQueue {
+    dir: PathBuf,
+    max_bytes: u64,
+    cipher: Option<Cipher>,
+    /// Whether payloads spilled in the clear are still read with a cipher.
+    read_plaintext: bool,
+    quarantined: Option<IntCounter>,
+    state: Mutex<SpillState>,
+}
+
+#[derive(Default)]
+struct SpillState {
+    /// Sequence number and size of each spilled payload, oldest first.
+    entries: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next_seq: u64,
+    /// The high-water mark in the sequence file: numbers below it may have
+    /// been used, by this run or a previous one.
+    reserved: u64,
+}
+
+impl SpillQueue {
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self, ServerError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(io_error)?;
+
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&dir).map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SPILL_EXTENSION) {
+                continue;
+            }
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let len = entry.metadata().map_err(io_error)?.len();
+            entries.push((seq, len));
+        }
+        entries.sort_unstable();
+
+        let reserved = read_sequence(&dir.join(SEQUENCE_FILE))?;
+        let state = SpillState {
+            bytes: entries.iter().map(|(_, len)| len).sum(),
+            next_seq: entries.last().map_or(0, |(seq, _)| seq + 1).max(reserved),
+            reserved,
+            entries: entries.into(),
+        };
+        if !state.entries.is_empty() {
+            info!(
+                "Found {} spilled payloads ({} bytes) in {}",
+                state.entries.len(),
+                state.bytes,
+                dir.display()
+            );
+        }
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            cipher: None,
+            read_plaintext: false,
+            quarantined: None,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Encrypts the payloads spilled from now on. Those already spilled in
+    /// the clear are dropped unread, as they may have been planted in the
+    /// directory, unless `with_plaintext_fallback` is set.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Still reads the payloads spilled in the clear once a cipher is set, to
+    /// send those spilled before encryption was enabled.
+    pub fn with_plaintext_fallback(mut self) -> Self {
+        self.read_plaintext = true;
+        self
+    }
+
+    /// Counts the payloads moved to quarantine.
+    pub fn with_quarantine_counter(mut self, counter: IntCounter) -> Self {
+        self.quarantined = Some(counter);
+        self
+    }
+
+    /// Appends a payload, dropping the oldest ones if it wouldn't fit.
+    pub async fn push(&self, payload: &[u8]) -> Result<(), ServerError> {
+        let mut state = self.state.lock().await;
+        let seq = state.next_seq;
+        let sealed;
+        let payload = match &self.cipher {
+            Some(cipher) => {
+                sealed = [
+                    ENCRYPTED_MAGIC,
+                    &cipher.seal(&additional_data(seq), payload)?,
+                ]
+                .concat();
+                &sealed[..]
+            }
+            None => payload,
+        };
+        let len = payload.len() as u64;
+        if len > self.max_bytes {
+            return Err(ServerError::ValidationError(format!(
+                "payload of {} bytes exceeds the spill limit of {} bytes",
+                len, self.max_bytes
+            )));
+        }
+
+        while state.bytes + len > self.max_bytes {
+            let Some((seq, dropped)) = state.entries.pop_front() else {
+                break;
+            };
+            state.bytes -= dropped;
+            warn!(
+                "Spill in {} is full, dropping the oldest payload ({} bytes)",
+                self.dir.display(),
+                dropped
+            );
+            remove_file(&self.path(seq)).await?;
+        }
+
+        if seq >= state.reserved {
+            let reserved = seq + SEQUENCE_BLOCK;
+            write_sequence(&self.dir.join(SEQUENCE_FILE), reserved).await?;
+            state.reserved = reserved;
+        }
+        tokio::fs::write(self.path(seq), payload)
+            .await
+            .map_err(io_error)?;
+        state.entries.push_back((seq, len));
+        state.bytes += len;
+        state.next_seq += 1;
+        Ok(())
+    }
+
+    /// The oldest payload with the sequence number to `remove` it with once
+    /// it has been delivered.
+    pub async fn peek(&self) -> Result<Option<(u64, Vec<u8>)>, ServerError> {
+        loop {
+            let Some(&(seq, _)) = self.state.lock().await.entries.front() else {
+                return Ok(None);
+            };
+            let payload = match tokio::fs::read(self.path(seq)).await {
+                Ok(payload) => payload,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    warn!("Spilled payload {} disappeared", self.path(seq).display());
+                    self.remove(seq).await?;
+                    continue;
+                }
+                Err(e) => return Err(io_error(e)),
+            };
+            let encrypted = payload.starts_with(ENCRYPTED_MAGIC);
+            let payload = match &self.cipher {
+                Some(cipher) if encrypted => {
+                    match cipher.open(&additional_data(seq), &payload[ENCRYPTED_MAGIC.len()..]) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            self.quarantine(seq, &e).await?;
+                            continue;
+                        }
+                    }
+                }
+                Some(_) if self.read_plaintext => payload,
+                Some(_) => {
+                    warn!(
+                        "Dropping unencrypted spilled payload {}",
+                        self.path(seq).display()
+                    );
+                    self.remove(seq).await?;
+                    continue;
+                }
+                None if encrypted => {
+                    let e = crypto_error("payload is encrypted, but no key is configured");
+                    self.quarantine(seq, &e).await?;
+                    continue;
+                }
+                None => payload,
+            };
+            return Ok(Some((seq, payload)));
+        }
+    }
+
+    /// Moves an unreadable payload out of the queue, keeping the file for
+    /// inspection, e.g. to move it back once the right key is configured.
+    /// A file already quarantined under the same name is kept, the payload
+    /// going to `<seq>.<n>.spill` instead.
+    async fn quarantine(&self, seq: u64, error: &ServerError) -> Result<(), ServerError> {
+        let dir = self.dir.join(QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&dir).await.map_err(io_error)?;
+        let path = self.path(seq);
+        let mut quarantined = dir.join(path.file_name().unwrap_or_default());
+        let mut copy = 0;
+        while tokio::fs::try_exists(&quarantined)
+            .await
+            .map_err(io_error)?
+        {
+            copy += 1;
+            quarantined = dir.join(format!("{:020}.{}.{}", seq, copy, SPILL_EXTENSION));
+        }
+        warn!(
+            "Quarantining spilled payload {} to {}: {}",
+            path.display(),
+            quarantined.display(),
+            error
+        );
+        match tokio::fs::rename(&path, &quarantined).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(io_error(e)),
+            _ => {}
+        }
+        if let Some(counter) = &self.quarantined {
+            counter.inc();
+        }
+        self.remove(seq).await
+    }
+
+    pub async fn remove(&self, seq: u64) -> Result<(), ServerError> {
+        let mut state = self.state.lock().await;
+        let Some(position) = state.entries.iter().position(|(s, _)| *s == seq) else {
+            return Ok(());
+        };
+        if let Some((_, len)) = state.entries.remove(position) {
+            state.bytes -= len;
+        }
+        remove_file(&self.path(seq)).await
+    }
+
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.entries.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    pub async fn bytes(&self) -> u64 {
+        self.state.lock().await.bytes
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, SPILL_EXTENSION))
+    }
+}
+
+/// The high-water mark of the sequence file, 0 when there is none yet.
+fn read_sequence(path: &Path) -> Result<u64, ServerError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => content.trim().parse().map_err(|_| {
+            ServerError::InternalError(
+                format!("{} doesn't hold a sequence number", path.display()).into(),
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(io_error(e)),
+    }
+}
+
+/// Replaces the sequence file, so a crash leaves either mark whole.
+async fn write_sequence(path: &Path, reserved: u64) -> Result<(), ServerError> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, reserved.to_string())
+        .await
+        .map_err(io_error)?;
+    tokio::fs::rename(&tmp, path).await.map_err(io_error)
+}
+
+async fn remove_file(path: &Path) -> Result<(), ServerError> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(io_error(e)),
+        _ => Ok(()),
+    }
+}
+
+fn io_error(e: std::io::Error) -> ServerError {
+    ServerError::InternalError(Box::new(e))
+}
@@ -0,0 +1,221 @@
+//! Helpers for services that push to rustic-insights and want to check what
+//! they pushed in their own integration tests, without copying the server
+//! setup. Enabled with the `test_support` feature.
+
+use crate::api::auth::authorize;
+use crate::api::configure_routes;
+use crate::api::handlers::AppState;
+use crate::api::lockout::enforce_lockouts;
+use crate::api::request_id::propagate_request_id;
+use crate::api::shedding::shed_load;
+use crate::api::signing::verify_signatures;
+use crate::api::timeout::enforce_timeouts;
+use crate::config::AppConfig;
+use crate::encryption::Keyring;
+use crate::errors::ServerError;
+use crate::metrics::{Metric, MetricsBatch, MetricsCollector, MetricsRegistry, MetricsResponse};
+use crate::replay::parse_record;
+use actix_web::dev::ServerHandle;
+use actix_web::{App, HttpServer, middleware, web};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A server listening on an ephemeral localhost port.
+pub struct TestServer {
+    pub url: String,
+    pub state: Arc<AppState>,
+    handle: ServerHandle,
+}
+
+impl TestServer {
+    pub fn client(&self) -> TestClient {
+        TestClient::new(&self.url)
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await;
+    }
+}
+
+/// Starts a server with the default configuration on the current tokio runtime.
+pub async fn spawn_test_server() -> std::io::Result<TestServer> {
+    spawn_test_server_with_config(AppConfig::default()).await
+}
+
+pub async fn spawn_test_server_with_config(config: AppConfig) -> std::io::Result<TestServer> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let url = format!("http://{}", address);
+
+    let registry = MetricsRegistry::new(config.metrics.clone()).map_err(std::io::Error::other)?;
+    let state = Arc::new(AppState::new(
+        config,
+        MetricsCollector::new(registry),
+        env!("CARGO_PKG_VERSION"),
+    ));
+    let _ = state.listen_addresses.set(vec![address]);
+
+    if let Some(fanout) = &state.fanout {
+        fanout.spawn();
+    }
+
+    let app_state = state.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(app_state.clone()))
+            .wrap(middleware::from_fn(enforce_timeouts))
+            .wrap(middleware::from_fn(shed_load))
+            .wrap(middleware::from_fn(verify_signatures))
+            .wrap(middleware::from_fn(authorize))
+            .wrap(middleware::from_fn(enforce_lockouts))
+            .wrap(middleware::from_fn(propagate_request_id))
+            .configure(configure_routes)
+    })
+    .listen(listener)?
+    .workers(1)
+    .disable_signals()
+    .run();
+
+    let handle = server.handle();
+    state.mode.attach_server(handle.clone());
+    tokio::spawn(server);
+
+    Ok(TestServer { url, state, handle })
+}
+
+/// A thin HTTP client for the ingestion and exposition endpoints.
+pub struct TestClient {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl TestClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Posts a batch to `/api/metrics`, failing on any non-success status.
+    pub async fn push(&self, batch: &MetricsBatch) -> Result<MetricsResponse, ServerError> {
+        let response = self
+            .client
+            .post(format!("{}/api/metrics", self.base_url))
+            .json(batch)
+            .send()
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ServerError::MetricsProcessingError(format!(
+                "push failed with HTTP {}: {}",
+                status, body
+            )));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))
+    }
+
+    pub async fn push_metric(
+        &self,
+        source: &str,
+        metric: Metric,
+    ) -> Result<MetricsResponse, ServerError> {
+        let batch = MetricsBatch {
+            metrics: vec![metric],
+            source: source.to_string(),
+        };
+        self.push(&batch).await
+    }
+
+    /// The text exposition served on `/metrics`.
+    pub async fn scrape(&self) -> Result<String, ServerError> {
+        self.client
+            .get(format!("{}/metrics", self.base_url))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?
+            .text()
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))
+    }
+}
+
+/// The value of a series in a text exposition. `series` is written as exposed,
+/// with the full prefixed name and labels in alphabetical order, e.g.
+/// `app_metrics_server_requests{service="api"}`.
+pub fn metric_value(exposition: &str, series: &str) -> Option<f64> {
+    exposition.lines().find_map(|line| {
+        let value = line.strip_prefix(series)?.strip_prefix(' ')?;
+        value.split_whitespace().next()?.parse().ok()
+    })
+}
+
+/// Panics unless `series` is exposed with `expected`, listing the series of
+/// the same family to make mismatched labels easy to spot.
+#[track_caller]
+pub fn assert_metric_value(exposition: &str, series: &str, expected: f64) {
+    match metric_value(exposition, series) {
+        Some(value) if value == expected => {}
+        Some(value) => panic!("{} is {}, expected {}", series, value, expected),
+        None => {
+            let family = series.split('{').next().unwrap_or(series);
+            let similar: Vec<&str> = exposition
+                .lines()
+                .filter(|line| line.starts_with(family))
+                .collect();
+            panic!(
+                "{} is not exposed, series of the family:\n{}",
+                series,
+                similar.join("\n")
+            );
+        }
+    }
+}
+
+/// Loads the batches recorded by `capture` in `dir`, oldest first, e.g. to
+/// push a payload reported to break the server from a regression test.
+/// Only the fixtures of `source` are loaded when given.
+pub fn load_fixtures(
+    dir: impl AsRef<Path>,
+    source: Option<&str>,
+) -> Result<Vec<MetricsBatch>, ServerError> {
+    load_encrypted_fixtures(dir, source, &Keyring::default())
+}
+
+/// Like `load_fixtures`, for fixtures captured with `encryption` keys,
+/// opened with those of `keyring`.
+pub fn load_encrypted_fixtures(
+    dir: impl AsRef<Path>,
+    source: Option<&str>,
+    keyring: &Keyring,
+) -> Result<Vec<MetricsBatch>, ServerError> {
+    let entries =
+        std::fs::read_dir(dir.as_ref()).map_err(|e| ServerError::InternalError(Box::new(e)))?;
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?
+            .path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let content =
+            std::fs::read_to_string(&path).map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        let record = parse_record(&keyring.open_line(content.trim())?)?;
+        if source.is_none_or(|source| record.batch.source == source) {
+            records.push((record.timestamp, path, record.batch));
+        }
+    }
+
+    records.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    Ok(records.into_iter().map(|(_, _, batch)| batch).collect())
+}
@@ -0,0 +1,197 @@
+//! HTTPS with certificates reloaded in place: the served certificate is
+//! picked per handshake, so replacing it takes effect on new connections
+//! without a restart.
+
+#[cfg(feature = "acme")]
+pub mod acme;
+
+use crate::config::TlsConfig;
+use crate::errors::ServerError;
+use crate::metrics::self_metrics::SelfMetrics;
+use arc_swap::ArcSwap;
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// The certificate of `server.tls`, reloaded when its files change.
+#[derive(Debug)]
+pub struct CertificateReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /// The CA client certificates are verified against, when requested
+    client_ca_path: Option<PathBuf>,
+    reload_interval: Option<Duration>,
+    current: ArcSwap<CertifiedKey>,
+    /// The PEM files `current` was loaded from, to tell when they change
+    loaded: Mutex<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CertificateReloader {
+    /// Loads the certificate, failing when the files are missing or the key
+    /// doesn't match the certificate.
+    pub fn load(config: &TlsConfig) -> Result<Self, ServerError> {
+        let cert_path = PathBuf::from(&config.cert_path);
+        let key_path = PathBuf::from(&config.key_path);
+        let (cert_pem, key_pem) = read_pem(&cert_path, &key_path)?;
+        let key = certified_key(&cert_pem, &key_pem)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            client_ca_path: config.client_ca_path.as_ref().map(PathBuf::from),
+            reload_interval: (config.reload_interval_secs > 0)
+                .then(|| Duration::from_secs(config.reload_interval_secs)),
+            current: ArcSwap::from_pointee(key),
+            loaded: Mutex::new((cert_pem, key_pem)),
+        })
+    }
+
+    /// The certificate served to new connections.
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.load_full()
+    }
+
+    /// A server configuration serving whatever certificate is current. With
+    /// `client_ca_path` set, clients may present a certificate signed by that
+    /// CA; connections without one are still accepted, for `auth` to decide.
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig, ServerError> {
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| ServerError::ConfigurationError(e.to_string()))?;
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(client_roots(path)?),
+                    provider,
+                )
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| {
+                    ServerError::ConfigurationError(format!("{}: {}", path.display(), e))
+                })?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(builder.with_cert_resolver(self.clone()))
+    }
+
+    /// Reloads the certificate if its files changed, returning whether it
+    /// did. A certificate that fails to load leaves the current one served.
+    pub fn reload(&self) -> Result<bool, ServerError> {
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        let (cert_pem, key_pem) = read_pem(&self.cert_path, &self.key_path)?;
+        if loaded.0 == cert_pem && loaded.1 == key_pem {
+            return Ok(false);
+        }
+
+        self.current
+            .store(Arc::new(certified_key(&cert_pem, &key_pem)?));
+        *loaded = (cert_pem, key_pem);
+        Ok(true)
+    }
+
+    /// Runs `swap`, which replaces the certificate and key files, so that no
+    /// reload reads one of them before the other is replaced.
+    pub fn replace_files<T>(&self, swap: impl FnOnce() -> T) -> T {
+        let _loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        swap()
+    }
+
+    /// Periodically reloads the certificate, unless reloading is disabled.
+    pub fn spawn_watcher(self: Arc<Self>, self_metrics: SelfMetrics) {
+        let Some(period) = self.reload_interval else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match self.reload() {
+                    Ok(false) => debug!("TLS certificate unchanged"),
+                    Ok(true) => {
+                        info!(
+                            "Reloaded the TLS certificate from {}",
+                            self.cert_path.display()
+                        );
+                        self_metrics
+                            .tls_reloads_total
+                            .with_label_values(&["success"])
+                            .inc();
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to reload the TLS certificate, still serving the previous one: {}",
+                            e
+                        );
+                        self_metrics
+                            .tls_reloads_total
+                            .with_label_values(&["failure"])
+                            .inc();
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertificateReloader {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn read_pem(cert_path: &Path, key_path: &Path) -> Result<(Vec<u8>, Vec<u8>), ServerError> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| {
+            ServerError::ConfigurationError(format!("can't read {}: {}", path.display(), e))
+        })
+    };
+    Ok((read(cert_path)?, read(key_path)?))
+}
+
+fn client_roots(path: &Path) -> Result<RootCertStore, ServerError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        ServerError::ConfigurationError(format!("invalid client CA {}: {}", path.display(), e))
+    };
+    let pem = std::fs::read(path).map_err(|e| invalid(&e))?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut &pem[..]) {
+        roots
+            .add(cert.map_err(|e| invalid(&e))?)
+            .map_err(|e| invalid(&e))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid(&"no certificate found"));
+    }
+    Ok(roots)
+}
+
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey, ServerError> {
+    let invalid = |what: &str, e: &dyn std::fmt::Display| {
+        ServerError::ConfigurationError(format!("invalid TLS {}: {}", what, e))
+    };
+
+    let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid("certificate", &e))?;
+    if certs.is_empty() {
+        return Err(invalid("certificate", &"no certificate found"));
+    }
+    let key = rustls_pemfile::private_key(&mut &key_pem[..])
+        .map_err(|e| invalid("key", &e))?
+        .ok_or_else(|| invalid("key", &"no private key found"))?;
+    let key = any_supported_type(&key).map_err(|e| invalid("key", &e))?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(|e| invalid("key", &e))?;
+    Ok(certified)
+}
@@ -0,0 +1,543 @@
+//! A minimal ACME (RFC 8555) client provisioning the served certificate from
+//! a CA such as Let's Encrypt, proving control of the domain with HTTP-01
+//! challenges answered by a dedicated plain HTTP listener.
+
+use crate::config::{AcmeConfig, TlsConfig};
+use crate::errors::ServerError;
+use crate::metrics::self_metrics::SelfMetrics;
+use crate::tls::CertificateReloader;
+use actix_web::{HttpResponse, web};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use rcgen::{CertificateParams, KeyPair};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info};
+
+/// How often the certificate is checked for being due for renewal.
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often, and how many times, pending authorizations and orders are
+/// polled before giving up.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+
+const ACCOUNT_KEY_FILE: &str = "account.der";
+
+/// Key authorizations by token, for the challenges in progress.
+#[derive(Debug, Default)]
+pub struct Challenges(RwLock<HashMap<String, String>>);
+
+impl Challenges {
+    fn insert(&self, token: &str, key_authorization: String) {
+        if let Ok(mut challenges) = self.0.write() {
+            challenges.insert(token.to_string(), key_authorization);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut challenges) = self.0.write() {
+            challenges.clear();
+        }
+    }
+
+    fn get(&self, token: &str) -> Option<String> {
+        self.0.read().ok()?.get(token).cloned()
+    }
+}
+
+/// Routes of the challenge listener; it needs `Challenges` as app data.
+pub fn configure_challenge_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route(
+        "/.well-known/acme-challenge/{token}",
+        web::get().to(answer_challenge),
+    );
+}
+
+async fn answer_challenge(
+    token: web::Path<String>,
+    challenges: web::Data<Arc<Challenges>>,
+) -> HttpResponse {
+    match challenges.get(&token) {
+        Some(key_authorization) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(key_authorization),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+pub struct AcmeClient {
+    config: AcmeConfig,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    http: reqwest::Client,
+    challenges: Arc<Challenges>,
+}
+
+impl AcmeClient {
+    pub fn new(tls: &TlsConfig, config: &AcmeConfig) -> Result<Self, ServerError> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .user_agent(concat!("rustic-insights/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        Ok(Self {
+            config: config.clone(),
+            cert_path: PathBuf::from(&tls.cert_path),
+            key_path: PathBuf::from(&tls.key_path),
+            http,
+            challenges: Arc::new(Challenges::default()),
+        })
+    }
+
+    /// The challenges to serve on the challenge listener.
+    pub fn challenges(&self) -> Arc<Challenges> {
+        self.challenges.clone()
+    }
+
+    /// Whether the certificate is missing, unreadable or expires within
+    /// `renew_before_days`.
+    pub fn needs_renewal(&self) -> bool {
+        match days_until_expiry(&self.cert_path) {
+            Some(days) => days < i64::from(self.config.renew_before_days),
+            None => true,
+        }
+    }
+
+    /// Orders a certificate for the domain and writes it, with its key, to
+    /// the TLS certificate and key paths.
+    pub async fn provision(&self) -> Result<(), ServerError> {
+        self.provision_for(None).await
+    }
+
+    /// Provisions the certificate, replacing the files `reloader` serves
+    /// from together so it never loads a key with the wrong certificate.
+    async fn provision_for(
+        &self,
+        reloader: Option<&CertificateReloader>,
+    ) -> Result<(), ServerError> {
+        let result = self.order_certificate().await;
+        self.challenges.clear();
+        let (cert_pem, key_pem) = result?;
+
+        let key_tmp = stage_private(&self.key_path, key_pem.as_bytes())?;
+        let cert_tmp = stage_private(&self.cert_path, cert_pem.as_bytes())?;
+        let swap = || {
+            std::fs::rename(&key_tmp, &self.key_path)
+                .and_then(|()| std::fs::rename(&cert_tmp, &self.cert_path))
+                .map_err(|e| ServerError::InternalError(Box::new(e)))
+        };
+        match reloader {
+            Some(reloader) => reloader.replace_files(swap)?,
+            None => swap()?,
+        }
+        info!(
+            "Provisioned a certificate for {} from {}",
+            self.config.domain, self.config.directory_url
+        );
+        Ok(())
+    }
+
+    /// Periodically renews the certificate when due, then has `reloader`
+    /// serve it.
+    pub fn spawn_renewal(
+        self: Arc<Self>,
+        reloader: Arc<CertificateReloader>,
+        self_metrics: SelfMetrics,
+    ) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RENEWAL_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !self.needs_renewal() {
+                    continue;
+                }
+                let result = match self.provision_for(Some(&reloader)).await {
+                    Ok(()) => reloader.reload().map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                let outcome = match result {
+                    Ok(()) => "success",
+                    Err(e) => {
+                        error!(
+                            "Failed to renew the certificate for {}: {}",
+                            self.config.domain, e
+                        );
+                        "failure"
+                    }
+                };
+                self_metrics
+                    .acme_renewals_total
+                    .with_label_values(&[outcome])
+                    .inc();
+            }
+        });
+    }
+
+    /// Runs an order to completion, returning the certificate chain and its
+    /// private key as PEM.
+    async fn order_certificate(&self) -> Result<(String, String), ServerError> {
+        let mut session = Session::start(self).await?;
+
+        let directory = session.directory.clone();
+        let reply = session
+            .post(
+                &directory.new_order,
+                Some(json!({
+                    "identifiers": [{ "type": "dns", "value": self.config.domain }],
+                })),
+            )
+            .await?;
+        let order_url = reply
+            .location
+            .clone()
+            .ok_or_else(|| acme_error("the new order has no location"))?;
+        let order: Order = reply.json()?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(&mut session, authorization_url).await?;
+        }
+
+        let key = KeyPair::generate().map_err(|e| acme_error(e.to_string()))?;
+        let csr = CertificateParams::new(vec![self.config.domain.clone()])
+            .and_then(|params| params.serialize_request(&key))
+            .map_err(|e| acme_error(e.to_string()))?;
+        session
+            .post(
+                &order.finalize,
+                Some(json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) })),
+            )
+            .await?;
+
+        let order: Order = session
+            .poll(&order_url, &["pending", "ready", "processing"])
+            .await?;
+        let certificate_url = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => return Err(acme_error(format!("the order is {}", status))),
+        };
+        let chain = session.post(&certificate_url, None).await?.body;
+        let chain = String::from_utf8(chain).map_err(|e| acme_error(e.to_string()))?;
+        Ok((chain, key.serialize_pem()))
+    }
+
+    /// Completes the HTTP-01 challenge of a pending authorization.
+    async fn authorize(&self, session: &mut Session<'_>, url: &str) -> Result<(), ServerError> {
+        let authorization: Authorization = session.post(url, None).await?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == "http-01")
+            .ok_or_else(|| acme_error("the CA offers no http-01 challenge"))?;
+
+        let key_authorization = format!("{}.{}", challenge.token, session.thumbprint);
+        self.challenges.insert(&challenge.token, key_authorization);
+        session.post(&challenge.url, Some(json!({}))).await?;
+
+        let authorization: Authorization = session.poll(url, &["pending"]).await?;
+        if authorization.status != "valid" {
+            let detail = authorization
+                .challenges
+                .into_iter()
+                .find_map(|challenge| challenge.error)
+                .map_or_else(String::new, |problem| format!(": {}", problem));
+            return Err(acme_error(format!(
+                "the authorization of {} is {}{}",
+                self.config.domain, authorization.status, detail
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Value>,
+}
+
+struct Reply {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, ServerError> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// An account with the CA, signing requests with its key.
+struct Session<'a> {
+    http: &'a reqwest::Client,
+    directory: Directory,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    /// The account URL, once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    /// Fetches the directory and registers the account, or finds the one
+    /// already registered with the account key.
+    async fn start(client: &'a AcmeClient) -> Result<Self, ServerError> {
+        let directory: Directory = client
+            .http
+            .get(&client.config.directory_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?
+            .json()
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+
+        let rng = SystemRandom::new();
+        let key = account_key(Path::new(&client.config.account_dir), &rng)?;
+        // The public key is uncompressed: 0x04, then x and y
+        let point = key.public_key().as_ref();
+        let (x, y) = (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..]),
+        );
+        // Members in lexicographic order, as RFC 7638 thumbprints require
+        let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint = URL_SAFE_NO_PAD.encode(digest(&SHA256, thumbprint.as_bytes()));
+
+        let mut session = Self {
+            http: &client.http,
+            directory: directory.clone(),
+            rng,
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+        let reply = session
+            .post(
+                &directory.new_account,
+                Some(json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": client.config.contact,
+                })),
+            )
+            .await?;
+        session.kid = Some(
+            reply
+                .location
+                .ok_or_else(|| acme_error("the account has no location"))?,
+        );
+        Ok(session)
+    }
+
+    /// A signed POST, or POST-as-GET without a payload, retried once when
+    /// the CA rejects the nonce.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<Reply, ServerError> {
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(serde_json::to_vec(&payload)?),
+            None => String::new(),
+        };
+
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&protected)?);
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| acme_error("failed to sign the request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(serde_json::to_vec(&body)?)
+                .send()
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            self.nonce = header(&response, "replay-nonce");
+            let location = header(&response, LOCATION.as_str());
+            let status = response.status();
+            let body = response
+                .bytes()
+                .await
+                .map_err(|e| ServerError::InternalError(Box::new(e)))?
+                .to_vec();
+            if status.is_success() {
+                return Ok(Reply { location, body });
+            }
+
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(acme_error(format!(
+                "{} answered {}: {}",
+                url,
+                status,
+                problem["detail"].as_str().map_or_else(
+                    || String::from_utf8_lossy(&body).into_owned(),
+                    str::to_string
+                )
+            )));
+        }
+    }
+
+    /// Polls a resource until its status is no longer one of `pending`.
+    async fn poll<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        pending: &[&str],
+    ) -> Result<T, ServerError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let reply = self.post(url, None).await?;
+            let status: Value = reply.json()?;
+            if !pending.contains(&status["status"].as_str().unwrap_or_default()) {
+                return reply.json();
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(acme_error(format!("{} is still pending", url)))
+    }
+
+    async fn new_nonce(&self) -> Result<String, ServerError> {
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+        header(&response, "replay-nonce").ok_or_else(|| acme_error("the CA sent no nonce"))
+    }
+}
+
+fn header(response: &reqwest::Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Loads the account key from `dir`, generating it on first use.
+fn account_key(dir: &Path, rng: &SystemRandom) -> Result<EcdsaKeyPair, ServerError> {
+    let path = dir.join(ACCOUNT_KEY_FILE);
+    let pkcs8 = match std::fs::read(&path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| acme_error("failed to generate the account key"))?;
+            std::fs::create_dir_all(dir).map_err(|e| ServerError::InternalError(Box::new(e)))?;
+            write_private(&path, pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(ServerError::InternalError(Box::new(e))),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|e| acme_error(format!("invalid account key {}: {}", path.display(), e)))
+}
+
+/// Days until the first certificate in the PEM file expires.
+fn days_until_expiry(path: &Path) -> Option<i64> {
+    let pem = std::fs::read(path).ok()?;
+    let der = rustls_pemfile::certs(&mut &pem[..]).next()?.ok()?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(&der).ok()?;
+    let not_after = certificate.validity().not_after.timestamp();
+    Some((not_after - Utc::now().timestamp()).div_euclid(86_400))
+}
+
+/// Writes through a temporary file readable only by the owner, so the
+/// certificate reloader never sees a partial file.
+fn write_private(path: &Path, content: &[u8]) -> Result<(), ServerError> {
+    let tmp = stage_private(path, content)?;
+    std::fs::rename(&tmp, path).map_err(|e| ServerError::InternalError(Box::new(e)))
+}
+
+/// Writes `content` to a temporary file next to `path`, readable only by
+/// the owner, creating the directory if needed, and returns the file to
+/// rename to `path`.
+fn stage_private(path: &Path, content: &[u8]) -> Result<PathBuf, ServerError> {
+    use std::io::Write;
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent).map_err(|e| ServerError::InternalError(Box::new(e)))?;
+    }
+    // The key and certificate may only differ by their extension
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(content))
+        .map_err(|e| ServerError::InternalError(Box::new(e)))?;
+    Ok(tmp)
+}
+
+fn acme_error(message: impl Into<String>) -> ServerError {
+    ServerError::InternalError(format!("ACME: {}", message.into()).into())
+}
@@ -0,0 +1,262 @@
+//! The history of runs kept across restarts. A lock file per run marks it
+//! as running: it's removed on a clean shutdown, so one still present at
+//! startup whose process is gone means that run crashed. The run being
+//! replaced by a `reuse_port` or `--inherit-listener` restart is still
+//! alive and keeps its lock file.
+
+use crate::config::UptimeConfig;
+use crate::errors::ServerError;
+use crate::utils::fs::write_json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// How often the lock file records that the server is still running, which
+/// bounds how much uptime a crashed run is credited short of.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a lock file may go without a heartbeat before its run is
+/// presumed dead, where it can't be told whether its process is alive.
+const STALE_AFTER: Duration = Duration::from_secs(3 * HEARTBEAT_INTERVAL.as_secs());
+
+const HISTORY_FILE: &str = "history.json";
+/// Lock files are named `running.<run id>.lock`.
+const LOCK_PREFIX: &str = "running.";
+const LOCK_EXTENSION: &str = "lock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    CleanShutdown,
+    /// The run ended without shutting down; it's credited with the uptime
+    /// of its last heartbeat.
+    Crash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PastRun {
+    pub started_at: DateTime<Utc>,
+    pub stopped_at: DateTime<Utc>,
+    pub reason: StopReason,
+}
+
+impl PastRun {
+    pub fn uptime_seconds(&self) -> u64 {
+        (self.stopped_at - self.started_at).num_seconds().max(0) as u64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RunLock {
+    started_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    pid: u32,
+}
+
+/// The current run and, when `uptime` is configured, the past ones.
+pub struct UptimeHistory {
+    dir: Option<PathBuf>,
+    /// The lock file of the current run, in `dir`
+    lock_file: String,
+    max_runs: usize,
+    started_at: DateTime<Utc>,
+    /// Oldest first
+    runs: Mutex<Vec<PastRun>>,
+}
+
+impl UptimeHistory {
+    /// A history only knowing the current run, kept nowhere.
+    pub fn new(started_at: DateTime<Utc>) -> Self {
+        Self {
+            dir: None,
+            lock_file: String::new(),
+            max_runs: 0,
+            started_at,
+            runs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Loads the history kept in `config.dir`, recording as crashed the runs
+    /// that left their lock file behind and are no longer alive, and adds a
+    /// lock file for the current run. Does blocking file I/O.
+    pub fn open(config: &UptimeConfig, started_at: DateTime<Utc>) -> Result<Self, ServerError> {
+        let dir = PathBuf::from(&config.dir);
+        std::fs::create_dir_all(&dir).map_err(internal)?;
+
+        let mut crashed = Vec::new();
+        for entry in std::fs::read_dir(&dir).map_err(internal)? {
+            let path = entry.map_err(internal)?.path();
+            if !is_lock_file(&path) {
+                continue;
+            }
+            let Some(lock) = read_json::<RunLock>(&path)? else {
+                continue;
+            };
+            if is_alive(&lock) {
+                debug!(
+                    "The run started at {} (pid {}) is still running",
+                    lock.started_at, lock.pid
+                );
+                continue;
+            }
+            warn!(
+                "The run started at {} (pid {}) didn't shut down cleanly",
+                lock.started_at, lock.pid
+            );
+            crashed.push(PastRun {
+                started_at: lock.started_at,
+                stopped_at: lock.last_seen,
+                reason: StopReason::Crash,
+            });
+            std::fs::remove_file(&path).map_err(internal)?;
+        }
+        crashed.sort_by_key(|run| run.started_at);
+
+        let history = Self {
+            dir: Some(dir),
+            lock_file: format!("{}{}.{}", LOCK_PREFIX, Uuid::new_v4(), LOCK_EXTENSION),
+            max_runs: config.max_runs,
+            started_at,
+            runs: Mutex::new(Vec::new()),
+        };
+        history.append(crashed)?;
+        history.heartbeat()?;
+        Ok(history)
+    }
+
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    /// Whether past runs are kept across restarts.
+    pub fn is_persisted(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// The past runs, oldest first.
+    pub fn runs(&self) -> Vec<PastRun> {
+        self.runs
+            .lock()
+            .map(|runs| runs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Records in the lock file that the current run is still up.
+    pub fn heartbeat(&self) -> Result<(), ServerError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let lock = RunLock {
+            started_at: self.started_at,
+            last_seen: Utc::now(),
+            pid: std::process::id(),
+        };
+        write_json(&dir.join(&self.lock_file), &serde_json::to_vec(&lock)?)
+    }
+
+    /// Adds the current run to the history as shut down cleanly and releases
+    /// its lock file.
+    pub fn record_shutdown(&self) -> Result<(), ServerError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        self.append(vec![PastRun {
+            started_at: self.started_at,
+            stopped_at: Utc::now(),
+            reason: StopReason::CleanShutdown,
+        }])?;
+        std::fs::remove_file(dir.join(&self.lock_file)).map_err(internal)
+    }
+
+    /// Periodically refreshes the lock file of a persisted history, on a
+    /// blocking thread.
+    pub fn spawn_heartbeat(self: Arc<Self>) {
+        if !self.is_persisted() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let history = self.clone();
+                let recorded = tokio::task::spawn_blocking(move || history.heartbeat())
+                    .await
+                    .unwrap_or_else(|e| Err(ServerError::InternalError(Box::new(e))));
+                match recorded {
+                    Ok(()) => debug!("Recorded uptime heartbeat"),
+                    Err(e) => warn!("Failed to record uptime heartbeat: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Adds `runs` to the history file and reloads the past runs from it.
+    /// The file is read again first, as the run a restart replaces may still
+    /// be adding to it.
+    fn append(&self, runs: Vec<PastRun>) -> Result<(), ServerError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let path = dir.join(HISTORY_FILE);
+        let mut all: Vec<PastRun> = read_json(&path)?.unwrap_or_default();
+        all.extend(runs);
+        let excess = all.len().saturating_sub(self.max_runs);
+        all.drain(..excess);
+        write_json(&path, &serde_json::to_vec(&all)?)?;
+        if let Ok(mut runs) = self.runs.lock() {
+            *runs = all;
+        }
+        Ok(())
+    }
+}
+
+fn is_lock_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == LOCK_EXTENSION)
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(LOCK_PREFIX))
+}
+
+/// Whether the run holding `lock` is still running. A lock of this process
+/// is one a previous run left behind before its pid was reused.
+fn is_alive(lock: &RunLock) -> bool {
+    let fresh = (Utc::now() - lock.last_seen)
+        .to_std()
+        .map_or(true, |age| age < STALE_AFTER);
+    fresh && lock.pid != std::process::id() && process_exists(lock.pid)
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists and may be
+    // signalled, without sending anything.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a portable check, a lock kept fresh by its heartbeat is trusted.
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+fn internal(e: std::io::Error) -> ServerError {
+    ServerError::InternalError(Box::new(e))
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>, ServerError> {
+    match std::fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(internal(e)),
+    }
+}
@@ -1,3 +1,9 @@
+pub mod fs;
+pub mod hex;
 pub mod validation;
 
-pub use validation::{validate_label_names, validate_metric_name, validate_non_empty};
+pub use validation::{
+    is_legacy_label_name, is_legacy_metric_name, is_reserved_label_name, sanitize_label_value,
+    validate_counter_increment, validate_histogram_buckets, validate_label_names,
+    validate_label_values, validate_metric_name, validate_non_empty, validate_utf8_metric_name,
+};
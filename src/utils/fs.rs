@@ -0,0 +1,13 @@
+//! Files the server keeps its own state in.
+
+use crate::errors::ServerError;
+use std::path::Path;
+
+/// Writes through a temporary file next to `path`, so a crash mid-write
+/// leaves the previous content intact.
+pub fn write_json(path: &Path, content: &[u8]) -> Result<(), ServerError> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| ServerError::InternalError(Box::new(e)))
+}
@@ -1,13 +1,41 @@
 use crate::errors::ServerError;
-use regex::Regex;
 use std::collections::HashMap;
 use tracing::warn;
 
-pub fn validate_metric_name(name: &str) -> Result<(), ServerError> {
+/// Checks a name byte by byte against `[a-zA-Z_][a-zA-Z0-9_]*`, with colons
+/// allowed anywhere when `allow_colon` is set, which is far cheaper than a
+/// regex on the ingestion path.
+fn matches_name_grammar(name: &str, allow_colon: bool) -> bool {
+    let allowed =
+        |byte: u8| byte.is_ascii_alphabetic() || byte == b'_' || (allow_colon && byte == b':');
+    match name.as_bytes().split_first() {
+        Some((&first, rest)) => {
+            allowed(first)
+                && rest
+                    .iter()
+                    .all(|&byte| allowed(byte) || byte.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+pub fn is_legacy_metric_name(name: &str) -> bool {
     // Prometheus metric names must match [a-zA-Z_:][a-zA-Z0-9_:]*
-    let re = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+    matches_name_grammar(name, true)
+}
 
-    if !re.is_match(name) {
+pub fn is_legacy_label_name(name: &str) -> bool {
+    // Prometheus label names must match [a-zA-Z_][a-zA-Z0-9_]*
+    matches_name_grammar(name, false)
+}
+
+/// Label names Prometheus reserves for histogram buckets and summary quantiles.
+pub fn is_reserved_label_name(name: &str) -> bool {
+    name == "le" || name == "quantile"
+}
+
+pub fn validate_metric_name(name: &str) -> Result<(), ServerError> {
+    if !is_legacy_metric_name(name) {
         warn!("Invalid metric name: {}", name);
         return Err(ServerError::ValidationError(format!(
             "Invalid metric name: {}. Must match [a-zA-Z_:][a-zA-Z0-9_:]*",
@@ -18,12 +46,29 @@ pub fn validate_metric_name(name: &str) -> Result<(), ServerError> {
     Ok(())
 }
 
-pub fn validate_label_names(labels: &HashMap<String, String>) -> Result<(), ServerError> {
-    // Prometheus label names must match [a-zA-Z_][a-zA-Z0-9_]*
-    let re = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+/// Validates a metric name under the Prometheus 3.x rules, which allow any UTF-8
+/// characters as long as the name is quoted in the exposition.
+pub fn validate_utf8_metric_name(name: &str) -> Result<(), ServerError> {
+    if name.is_empty() {
+        return Err(ServerError::ValidationError(
+            "Metric name cannot be empty".to_string(),
+        ));
+    }
 
-    for (key, _) in labels {
-        if !re.is_match(key) {
+    if name.chars().any(char::is_control) {
+        warn!("Control character in metric name: {:?}", name);
+        return Err(ServerError::ValidationError(format!(
+            "Invalid metric name: {:?}. Must not contain control characters",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn validate_label_names(labels: &HashMap<String, String>) -> Result<(), ServerError> {
+    for key in labels.keys() {
+        if !is_legacy_label_name(key) {
             warn!("Invalid label name: {}", key);
             return Err(ServerError::ValidationError(format!(
                 "Invalid label name: {}. Must match [a-zA-Z_][a-zA-Z0-9_]*",
@@ -31,8 +76,7 @@ pub fn validate_label_names(labels: &HashMap<String, String>) -> Result<(), Serv
             )));
         }
 
-        // Check reserved label names
-        if key == "le" || key == "quantile" {
+        if is_reserved_label_name(key) {
             warn!("Reserved label name used: {}", key);
             return Err(ServerError::ValidationError(format!(
                 "'{}' is a reserved label name in Prometheus",
@@ -44,6 +88,58 @@ pub fn validate_label_names(labels: &HashMap<String, String>) -> Result<(), Serv
     Ok(())
 }
 
+pub fn validate_label_values(
+    labels: &HashMap<String, String>,
+    max_length: usize,
+) -> Result<(), ServerError> {
+    for (key, value) in labels {
+        if value.chars().count() > max_length {
+            warn!("Label value too long for label: {}", key);
+            return Err(ServerError::ValidationError(format!(
+                "Value of label '{}' exceeds the maximum length of {} characters",
+                key, max_length
+            )));
+        }
+
+        if value.chars().any(char::is_control) {
+            warn!("Control character in value of label: {}", key);
+            return Err(ServerError::ValidationError(format!(
+                "Value of label '{}' contains control characters",
+                key
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Truncates the value to `max_length` characters and escapes control characters
+/// (e.g. a newline becomes the two characters `\n`).
+pub fn sanitize_label_value(value: &str, max_length: usize) -> String {
+    let mut sanitized = String::with_capacity(value.len().min(max_length));
+    let mut length = 0;
+
+    for c in value.chars() {
+        if c.is_control() {
+            for escaped in c.escape_default() {
+                if length == max_length {
+                    return sanitized;
+                }
+                sanitized.push(escaped);
+                length += 1;
+            }
+        } else {
+            if length == max_length {
+                return sanitized;
+            }
+            sanitized.push(c);
+            length += 1;
+        }
+    }
+
+    sanitized
+}
+
 pub fn validate_non_empty(value: &str, field_name: &str) -> Result<(), ServerError> {
     if value.is_empty() {
         warn!("Empty value for field: {}", field_name);
@@ -55,3 +151,39 @@ pub fn validate_non_empty(value: &str, field_name: &str) -> Result<(), ServerErr
 
     Ok(())
 }
+
+pub fn validate_counter_increment(name: &str, value: f64) -> Result<(), ServerError> {
+    // Counters are monotonic, a negative increment would corrupt the running total
+    if value < 0.0 {
+        warn!("Negative increment {} for counter: {}", value, name);
+        return Err(ServerError::CounterDecreaseError {
+            name: name.to_string(),
+            value,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn validate_histogram_buckets(buckets: &[f64]) -> Result<(), ServerError> {
+    if buckets.is_empty() {
+        return Err(ServerError::ValidationError(
+            "must contain at least one bucket".to_string(),
+        ));
+    }
+
+    if buckets.iter().any(|b| !b.is_finite()) {
+        return Err(ServerError::ValidationError(
+            "bucket bounds must be finite numbers (+Inf is added implicitly)".to_string(),
+        ));
+    }
+
+    if let Some(pair) = buckets.windows(2).find(|pair| pair[0] >= pair[1]) {
+        return Err(ServerError::ValidationError(format!(
+            "bucket bounds must be strictly increasing, but {} is followed by {}",
+            pair[0], pair[1]
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,647 @@
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use rustic_insights::config::{
+    AppConfig, EncryptionConfig, LifecycleEventKind, NotificationChannelConfig, SinkKind, secrets,
+};
+use serde_json::json;
+
+#[test]
+fn test_default_config_is_valid() {
+    let config = AppConfig::default();
+    assert!(config.validate().is_ok());
+    assert!(config.issues().is_empty());
+}
+
+#[test]
+fn test_invalid_server_settings() {
+    let mut config = AppConfig::default();
+    config.server.port = 0;
+    config.server.workers = 0;
+
+    let issues = config.issues();
+    let fields: Vec<&str> = issues.iter().map(|i| i.field.as_str()).collect();
+    assert_eq!(fields, vec!["server.port", "server.workers"]);
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_invalid_metrics_prefix() {
+    let mut config = AppConfig::default();
+    config.metrics.metrics_prefix = "1-bad prefix".to_string();
+
+    let issues = config.issues();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].field, "metrics.metrics_prefix");
+}
+
+#[test]
+fn test_non_monotonic_buckets() {
+    let mut config = AppConfig::default();
+    config.metrics.histogram_buckets = vec![0.1, 0.5, 0.5, 1.0];
+
+    let issues = config.issues();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].field, "metrics.histogram_buckets");
+    assert!(issues[0].message.contains("strictly increasing"));
+}
+
+#[test]
+fn test_series_ttl_sanity() {
+    let mut config = AppConfig::default();
+    config.metrics.series_ttl_secs = Some(0);
+    let issues = config.issues();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].field, "metrics.series_ttl_secs");
+
+    config.metrics.compaction_interval_secs = 300;
+    config.metrics.series_ttl_secs = Some(60);
+    let issues = config.issues();
+    assert_eq!(issues.len(), 1);
+    assert!(
+        issues[0]
+            .message
+            .contains("compaction_interval_secs (300s)")
+    );
+
+    config.metrics.compaction_interval_secs = 30;
+    assert!(config.issues().is_empty());
+    config.metrics.series_ttl_secs = Some(300);
+    assert!(config.validate().is_ok());
+}
+
+fn parse_config(toml: &str) -> AppConfig {
+    ::config::Config::builder()
+        .add_source(::config::File::from_str(toml, ::config::FileFormat::Toml))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap()
+}
+
+#[test]
+fn test_sink_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [[sinks]]
+        type = "pushgateway"
+        url = "http://pushgateway:9091"
+
+        [[sinks]]
+        type = "graphite"
+        address = "carbon"
+        interval_secs = 0
+        "#,
+    );
+
+    assert_eq!(config.sinks.len(), 2);
+    assert_eq!(config.sinks[0].interval_secs, 30);
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "interval_secs must be greater than 0".to_string(),
+            "'carbon' must be in host:port form".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_nats_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [nats]
+        url = "nats://localhost:4222"
+        stream = "METRICS"
+        subject = ""
+        "#,
+    );
+
+    let nats = config.nats.as_ref().unwrap();
+    assert_eq!(nats.consumer, "rustic-insights");
+    assert_eq!(nats.max_deliver, 10);
+
+    let fields: Vec<String> = config.issues().into_iter().map(|i| i.field).collect();
+    assert_eq!(fields, vec!["nats.subject".to_string()]);
+}
+
+#[test]
+fn test_amqp_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [amqp]
+        url = "http://rabbitmq:5672"
+        queue = "metrics"
+        "#,
+    );
+
+    let amqp = config.amqp.as_ref().unwrap();
+    assert_eq!(amqp.prefetch, 50);
+    assert!(amqp.dead_letter_exchange.is_none());
+
+    let fields: Vec<String> = config.issues().into_iter().map(|i| i.field).collect();
+    assert_eq!(fields, vec!["amqp.url".to_string()]);
+}
+
+#[test]
+fn test_notification_channels_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [metrics.anomaly]
+        notify = [{ channel = "pager" }, { channel = "chat", template = "{{summary}}" }]
+
+        [notification_channels.pager]
+        type = "pagerduty"
+        routing_key = "key"
+
+        [notification_channels.mail]
+        type = "email"
+        smtp_host = "smtp.internal"
+        from = "alerts@example.com"
+        to = []
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "unknown notification channel 'chat'".to_string(),
+            "to must not be empty".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_reports_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [[reports]]
+        name = "weekly"
+        schedule = "0 8 * * MON"
+        format = "csv"
+        channels = ["ops"]
+
+        [[reports]]
+        name = "weekly"
+        schedule = "every monday"
+        sections = []
+        channels = ["chat"]
+
+        [notification_channels.ops]
+        type = "webhook"
+        url = "http://reports.internal/ops"
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[0], "duplicate report 'weekly'");
+    assert!(messages[1].starts_with("Invalid input: schedule 'every monday'"));
+    assert_eq!(messages[2], "sections must not be empty");
+    assert_eq!(messages[3], "unknown notification channel 'chat'");
+    assert_eq!(config.reports[0].sections.len(), 3);
+}
+
+#[test]
+fn test_event_webhooks_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [[event_webhooks]]
+        name = "audit"
+        url = "https://audit.internal/events"
+        events = ["family_registered", "source_seen"]
+
+        [[event_webhooks]]
+        name = "audit"
+        url = "audit.internal"
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        [
+            "duplicate webhook 'audit'",
+            "'audit.internal' is not an http(s) URL"
+        ]
+    );
+    assert_eq!(
+        config.event_webhooks[0].events,
+        [
+            LifecycleEventKind::FamilyRegistered,
+            LifecycleEventKind::SourceSeen
+        ]
+    );
+    assert!(config.event_webhooks[1].events.is_empty());
+    assert_eq!(config.event_webhooks[1].max_retries, 5);
+}
+
+#[test]
+fn test_exposition_groups_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [[exposition_groups]]
+        name = "billing"
+        metrics = ["app_test_invoices_.*"]
+
+        [[exposition_groups]]
+        name = "billing"
+        metrics = ["app_test_(payments"]
+
+        [[exposition_groups]]
+        name = "infra/hosts"
+        metrics = []
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(messages.len(), 4, "{:?}", messages);
+    assert_eq!(messages[0], "duplicate exposition group 'billing'");
+    assert!(messages[1].contains("Invalid pattern 'app_test_(payments'"));
+    assert_eq!(messages[2], "invalid group name 'infra/hosts'");
+    assert_eq!(messages[3], "metrics must not be empty");
+}
+
+#[test]
+fn test_value_rules_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [metrics.value_rules.payload_size]
+        from = "ms"
+        to = "MiB"
+        precision = 20
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "cannot convert time units to size units".to_string(),
+            "precision must be at most 15".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_invalid_trusted_proxies() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [metrics.target_labels]
+        instance_header = "X-Forwarded-For"
+        trusted_proxies = ["10.0.0.0/8", "fd00::1", "10.0.0.0/33", "proxy.internal"]
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "'10.0.0.0/33' is not an address or CIDR range".to_string(),
+            "'proxy.internal' is not an address or CIDR range".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_sampling_config() {
+    let config = parse_config(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [metrics.sampling.chatty]
+        batch_one_in = 0
+        metrics = { debug_events = 1.5 }
+        "#,
+    );
+
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "batch_one_in must be at least 1".to_string(),
+            "probability of 'debug_events' must be between 0 and 1, got 1.5".to_string(),
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn test_secrets_from_files_and_vault() {
+    let dir = std::env::temp_dir().join(format!("secrets-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("webhook"),
+        "https://hooks.slack.com/services/T0/B0/xyz\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("pushgateway"), "http://pushgateway:9091").unwrap();
+    std::fs::write(dir.join("vault-token"), "s.token\n").unwrap();
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/v1/kv/data/metrics/pager",
+            web::get().to(|req: HttpRequest| async move {
+                if req.headers().get("X-Vault-Token").unwrap() != "s.token" {
+                    return HttpResponse::Forbidden().finish();
+                }
+                HttpResponse::Ok().json(json!({
+                    "data": { "data": { "routing_key": "R0UT1NG" }, "metadata": {} }
+                }))
+            }),
+        )
+    })
+    .listen(listener)
+    .unwrap()
+    .workers(1)
+    .disable_signals()
+    .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let toml = format!(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+
+        [vault]
+        address = "http://{address}"
+        token_file = "{dir}/vault-token"
+        mount = "kv"
+
+        [[sinks]]
+        type = "pushgateway"
+        url_file = "{dir}/pushgateway"
+
+        [notification_channels.chat]
+        type = "slack"
+        webhook_url_file = "{dir}/webhook"
+
+        [notification_channels.pager]
+        type = "pagerduty"
+        routing_key = "vault:metrics/pager#routing_key"
+        "#,
+        address = address,
+        dir = dir.display(),
+    );
+    let resolve = |toml: String| {
+        web::block(move || {
+            let config = ::config::Config::builder()
+                .add_source(::config::File::from_str(&toml, ::config::FileFormat::Toml))
+                .build()
+                .unwrap();
+            secrets::resolve_secrets(config)
+        })
+    };
+
+    let (config, origins) = resolve(toml.clone()).await.unwrap().unwrap();
+    let config: AppConfig = config.try_deserialize().unwrap();
+    assert_eq!(config.vault.unwrap().token, "s.token");
+    match &config.sinks[0].kind {
+        SinkKind::Pushgateway { url, .. } => assert_eq!(url, "http://pushgateway:9091"),
+        other => panic!("unexpected sink {:?}", other),
+    }
+    match &config.notification_channels["chat"] {
+        NotificationChannelConfig::Slack { webhook_url, .. } => {
+            assert_eq!(webhook_url, "https://hooks.slack.com/services/T0/B0/xyz")
+        }
+        other => panic!("unexpected channel {:?}", other),
+    }
+    match &config.notification_channels["pager"] {
+        NotificationChannelConfig::Pagerduty { routing_key, .. } => {
+            assert_eq!(routing_key, "R0UT1NG")
+        }
+        other => panic!("unexpected channel {:?}", other),
+    }
+    assert_eq!(
+        origins["notification_channels.pager.routing_key"],
+        "vault:metrics/pager"
+    );
+    assert_eq!(
+        origins["sinks[0].url"],
+        format!("secret_file:{}/pushgateway", dir.display())
+    );
+
+    // A missing field, or a setting given both inline and from a file
+    let missing = toml.replace("#routing_key", "#integration_key");
+    let error = resolve(missing).await.unwrap().unwrap_err().to_string();
+    assert!(
+        error.contains("has no field 'integration_key'"),
+        "{}",
+        error
+    );
+    let both = toml.replace("mount = \"kv\"", "mount = \"kv\"\ntoken = \"inline\"");
+    let error = resolve(both).await.unwrap().unwrap_err().to_string();
+    assert!(
+        error.contains("set either token or token_file"),
+        "{}",
+        error
+    );
+
+    // Other settings are left as they are, even when they look like a reference
+    let plain = toml.replace(
+        "metrics_namespace = \"test\"",
+        "metrics_namespace = \"vault:not/a#secret\"\nsources_file = \"/nonexistent\"",
+    );
+    let (config, origins) = resolve(plain).await.unwrap().unwrap();
+    assert!(!origins.contains_key("metrics.sources"), "{:?}", origins);
+    let config: AppConfig = config.try_deserialize().unwrap();
+    assert_eq!(config.metrics.metrics_namespace, "vault:not/a#secret");
+
+    handle.stop(false).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_encryption_keys_from_env() {
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const BILLING_KEY: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let path = std::env::temp_dir().join(format!("encryption-key-{}", std::process::id()));
+    std::fs::write(&path, format!("{}\n", KEY)).unwrap();
+
+    let keys = EncryptionConfig::from_vars([
+        (
+            "APP__ENCRYPTION__KEY_FILE".to_string(),
+            path.display().to_string(),
+        ),
+        (
+            "APP__ENCRYPTION__SOURCE_KEYS__BILLING".to_string(),
+            BILLING_KEY.to_string(),
+        ),
+        ("HOME".to_string(), "/root".to_string()),
+    ])
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(keys.key.as_deref(), Some(KEY));
+    assert_eq!(keys.source_keys["billing"], BILLING_KEY);
+
+    // Without any, records are opened as they were written: in the clear
+    let keys = EncryptionConfig::from_vars([]).unwrap();
+    assert!(keys.key.is_none() && keys.source_keys.is_empty());
+
+    let error = EncryptionConfig::from_vars([(
+        "APP__ENCRYPTION__KEY_FILE".to_string(),
+        "/nonexistent/key".to_string(),
+    )])
+    .unwrap_err()
+    .to_string();
+    assert!(
+        error.contains("encryption.key_file: can't read"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_scrape_web_config() {
+    let path = std::env::temp_dir().join(format!("web-config-{}.yml", std::process::id()));
+    std::fs::write(
+        &path,
+        "basic_auth_users:\n  \
+           Prometheus: $2y$10$mDwo.lAisC94iLAyP81MCesa29IzH37oigHC/42V2pdJlUprsJPze\n  \
+           grafana: not-a-hash\n",
+    )
+    .unwrap();
+
+    // The file is read as a web config, not as a `<name>_file` secret
+    let toml = format!(
+        r#"
+        [server]
+        host = "127.0.0.1"
+        port = 8080
+        workers = 1
+
+        [server.scrape]
+        web_config_path = "{}"
+
+        [metrics]
+        prometheus_endpoint = "/metrics"
+        metrics_prefix = "app"
+        metrics_namespace = "test"
+        "#,
+        path.display()
+    );
+    let config = ::config::Config::builder()
+        .add_source(::config::File::from_str(&toml, ::config::FileFormat::Toml))
+        .build()
+        .unwrap();
+    let (config, origins) = secrets::resolve_secrets(config).unwrap();
+    assert!(origins.is_empty(), "{:?}", origins);
+    let mut config: AppConfig = config.try_deserialize().unwrap();
+    config.server.scrape.load_web_config().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let users: Vec<&String> = config.server.scrape.basic_auth_users.keys().collect();
+    assert_eq!(users, vec!["Prometheus", "grafana"]);
+    let messages: Vec<String> = config.issues().into_iter().map(|i| i.message).collect();
+    assert_eq!(
+        messages,
+        vec!["the password of 'grafana' is not a bcrypt hash".to_string()]
+    );
+}
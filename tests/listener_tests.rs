@@ -0,0 +1,31 @@
+use rustic_insights::listener::{bind_listener, inherited_listener};
+
+#[test]
+fn test_reuse_port_allows_second_listener() {
+    let first = bind_listener("127.0.0.1:0".parse().unwrap(), true).unwrap();
+    let addr = first.local_addr().unwrap();
+
+    let second = bind_listener(addr, true).unwrap();
+    assert_eq!(second.local_addr().unwrap(), addr);
+
+    drop(first);
+    assert!(bind_listener(addr, false).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_inherited_listener() {
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::fd::IntoRawFd;
+
+    let listener = bind_listener("127.0.0.1:0".parse().unwrap(), false).unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+
+    let inherited = inherited_listener(listener.into_raw_fd()).unwrap();
+    assert_eq!(inherited.local_addr().unwrap(), addr);
+    let _client = TcpStream::connect(addr).unwrap();
+    assert!(inherited.accept().is_ok());
+
+    let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert!(inherited_listener(udp.into_raw_fd()).is_err());
+}
@@ -1,8 +1,38 @@
+use futures::future::BoxFuture;
+use prometheus::proto::MetricFamily;
 use rustic_insights::{
-    config::AppConfig,
-    metrics::{Metric, MetricType, MetricValue, MetricsBatch, MetricsCollector, MetricsRegistry},
+    ServerError,
+    api::models::Validate,
+    config::{
+        AnomalyConfig, AppConfig, EnrichmentConfig, EnrichmentProvider, EnumKind, EnumMapping,
+        HistoryConfig, IngestMode, LabelValuePolicy, MetricSchema, NegativeCounterPolicy,
+        NonFinitePolicy, NotifyTarget, PluginConfig, RecordingRule, RenameRule, SamplingConfig,
+        SchemaDriftConfig, ScriptConfig, SyntheticMetric, SyntheticPattern, Unit,
+        UnknownMetricPolicy, ValueRule, WriteCoalescingConfig,
+    },
+    dead_letter::{DeadLetter, FileDeadLetterWriter},
+    ingest::collectd::CollectdValueList,
+    ingest::prometheus::{ParseMode, TextType, parse_text},
+    ingest::{CollectdAdapter, PrometheusTextAdapter},
+    metrics::enrichment::LabelEnricher,
+    metrics::export::{ExportFormat, write_history},
+    metrics::histogram::{Bucket, BucketCounts},
+    metrics::history::SampleHistory,
+    metrics::query::Expr,
+    metrics::registrations::SourceRequest,
+    metrics::rules::evaluate_rule,
+    metrics::store::{MetricsStore, Snapshot, StoreUpdate, StoreWrite, StoredKind},
+    metrics::synthetic::write_synthetic,
+    metrics::{
+        GaugeOperation, Metric, MetricError, MetricSamples, MetricType, MetricV2, MetricValue,
+        MetricsBatch, MetricsCollector, MetricsRegistry, SampleValue,
+    },
+    notify::{Notification, NotificationChannel, Notifier, Severity},
 };
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 fn create_test_metric(
     name: &str,
@@ -25,13 +55,17 @@ fn create_test_metric(
         value: MetricValue {
             value,
             timestamp: None,
-        },
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
     }
 }
 
 fn create_test_registry() -> MetricsRegistry {
     let config = AppConfig::default();
-    MetricsRegistry::new(config.metrics.clone())
+    MetricsRegistry::new(config.metrics.clone()).unwrap()
 }
 
 #[tokio::test]
@@ -100,6 +134,291 @@ async fn test_update_counter() {
     assert!(metrics_data.contains("test_counter"));
 }
 
+#[tokio::test]
+async fn test_timer_registered_as_seconds_histogram() {
+    let collector = MetricsCollector::new(create_test_registry());
+    let batch = MetricsBatch {
+        metrics: vec![create_test_metric(
+            "request_duration",
+            MetricType::Timer,
+            0.003,
+            None,
+        )],
+        source: "test".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("# TYPE app_metrics_server_request_duration_seconds histogram"));
+    assert!(output.contains(
+        "request_duration_seconds_bucket{instance=\"test_instance\",service=\"test_service\",le=\"0.0025\"} 0"
+    ));
+    assert!(output.contains(
+        "request_duration_seconds_bucket{instance=\"test_instance\",service=\"test_service\",le=\"0.005\"} 1"
+    ));
+
+    let negative = create_test_metric("request_duration", MetricType::Timer, -1.0, None);
+    assert!(negative.validate(&AppConfig::default().metrics).is_err());
+}
+
+#[test]
+fn test_batch_validation() {
+    let config = AppConfig::default().metrics;
+    let labels = |key: &str| Some(HashMap::from([(key.to_string(), "a".to_string())]));
+    let batch = |metrics: Vec<Metric>| MetricsBatch {
+        metrics,
+        source: "test_app".to_string(),
+    };
+
+    let valid = batch(vec![
+        create_test_metric(
+            "http:requests_total",
+            MetricType::Counter,
+            1.0,
+            labels("_path2"),
+        ),
+        create_test_metric(
+            "http:requests_total",
+            MetricType::Counter,
+            1.0,
+            labels("method"),
+        ),
+    ]);
+    assert!(valid.validate(&config).is_ok());
+
+    // Names may start with a digit and use letters of any script
+    for (name, label) in [("2xx_responses", "2nd"), ("résumé", "méthode")] {
+        let metric = create_test_metric(name, MetricType::Counter, 1.0, labels(label));
+        assert!(
+            batch(vec![metric]).validate(&config).is_ok(),
+            "{} {}",
+            name,
+            label
+        );
+    }
+
+    for (name, label) in [
+        ("http-requests", "path"),
+        ("requests", "http.method"),
+        ("requests", "le"),
+    ] {
+        let invalid = batch(vec![
+            create_test_metric("requests", MetricType::Counter, 1.0, None),
+            create_test_metric(name, MetricType::Counter, 1.0, labels(label)),
+        ]);
+        assert!(invalid.validate(&config).is_err(), "{} {}", name, label);
+    }
+
+    let duplicate = batch(vec![
+        create_test_metric("requests", MetricType::Counter, 1.0, labels("path")),
+        create_test_metric("requests", MetricType::Counter, 2.0, labels("path")),
+    ]);
+    let err = duplicate.validate(&config).unwrap_err();
+    assert!(err.to_string().contains("Duplicate metric"));
+}
+
+#[tokio::test]
+async fn test_info_and_stateset_metrics() {
+    let collector = MetricsCollector::new(create_test_registry());
+    let build = create_test_metric(
+        "build",
+        MetricType::Info,
+        0.0,
+        Some(HashMap::from([(
+            "version".to_string(),
+            "1.4.2".to_string(),
+        )])),
+    );
+    let state = |service: &str, value: &str| {
+        create_test_metric(
+            "service_state",
+            MetricType::StateSet,
+            1.0,
+            Some(HashMap::from([
+                ("service".to_string(), service.to_string()),
+                ("service_state".to_string(), value.to_string()),
+            ])),
+        )
+    };
+
+    // Only the states of the same service are reset
+    for metrics in [
+        vec![build, state("api", "starting"), state("worker", "stopped")],
+        vec![state("api", "running"), state("worker", "starting")],
+        vec![state("worker", "stopped"), state("api", "running")],
+    ] {
+        let batch = MetricsBatch {
+            metrics,
+            source: "test".to_string(),
+        };
+        collector.process_batch(batch).await.unwrap();
+    }
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("app_metrics_server_build_info{version=\"1.4.2\"} 1"));
+    assert!(
+        output.contains(
+            "app_metrics_server_service_state{service=\"api\",service_state=\"running\"} 1"
+        )
+    );
+    assert!(output.contains(
+        "app_metrics_server_service_state{service=\"api\",service_state=\"starting\"} 0"
+    ));
+    assert!(output.contains(
+        "app_metrics_server_service_state{service=\"worker\",service_state=\"stopped\"} 1"
+    ));
+    assert!(output.contains(
+        "app_metrics_server_service_state{service=\"worker\",service_state=\"starting\"} 0"
+    ));
+
+    let stateless = create_test_metric("service_state", MetricType::StateSet, 1.0, None);
+    assert!(stateless.validate(&AppConfig::default().metrics).is_err());
+}
+
+#[tokio::test]
+async fn test_boolean_and_enum_values() {
+    let mut config = AppConfig::default();
+    config.metrics.enums.insert(
+        "health".to_string(),
+        EnumMapping {
+            kind: EnumKind::Gauge,
+            values: vec!["ok".to_string(), "degraded".to_string(), "down".to_string()],
+        },
+    );
+    config.metrics.enums.insert(
+        "phase".to_string(),
+        EnumMapping {
+            kind: EnumKind::StateSet,
+            values: vec!["idle".to_string(), "busy".to_string()],
+        },
+    );
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let batch: MetricsBatch = serde_json::from_value(serde_json::json!({
+        "source": "agent",
+        "metrics": [
+            {"name": "up", "metric_type": "gauge", "help": "Up", "labels": {},
+             "value": {"value": true}},
+            {"name": "health", "metric_type": "gauge", "help": "Health", "labels": {},
+             "value": {"value": "degraded"}},
+            {"name": "phase", "metric_type": "gauge", "help": "Phase", "labels": {},
+             "value": {"value": "busy"}},
+            {"name": "mood", "metric_type": "gauge", "help": "Mood", "labels": {},
+             "value": {"value": "happy"}}
+        ]
+    }))
+    .unwrap();
+    let response = collector.process_batch(batch).await.unwrap();
+    assert_eq!(response.processed, 3);
+    assert!(response.errors[0].contains("no enum mapping"));
+    assert!(!response.error_details[0].retryable);
+
+    let transient = ServerError::InternalError("registry lock poisoned".into());
+    assert!(MetricError::from(&transient).retryable);
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("app_metrics_server_up 1"));
+    assert!(output.contains("app_metrics_server_health 1"));
+    assert!(output.contains("app_metrics_server_phase{phase=\"busy\"} 1"));
+}
+
+#[tokio::test]
+async fn test_canonical_label_values_merge() {
+    let collector = MetricsCollector::new(create_test_registry());
+
+    let composed = HashMap::from([("city".to_string(), "Z\u{fc}rich".to_string())]);
+    let decomposed = HashMap::from([("city".to_string(), "Zu\u{308}rich".to_string())]);
+    let batch = MetricsBatch {
+        metrics: vec![
+            create_test_metric("visits", MetricType::Counter, 1.0, Some(composed)),
+            create_test_metric("visits", MetricType::Counter, 2.0, Some(decomposed)),
+        ],
+        source: "test".to_string(),
+    };
+    let response = collector.process_batch(batch).await.unwrap();
+    assert_eq!(response.processed, 2);
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("app_metrics_server_visits{city=\"Z\u{fc}rich\"} 3"));
+    assert!(output.contains("rustic_insights_canonicalized_metrics_total 1"));
+}
+
+#[tokio::test]
+async fn test_value_rules() {
+    let mut config = AppConfig::default();
+    config.metrics.value_rules.insert(
+        "request_duration".to_string(),
+        ValueRule {
+            from: Some(Unit::Milliseconds),
+            to: Some(Unit::Seconds),
+            precision: Some(3),
+        },
+    );
+    config.metrics.value_rules.insert(
+        "heap".to_string(),
+        ValueRule {
+            from: Some(Unit::Bytes),
+            to: Some(Unit::Mebibytes),
+            precision: None,
+        },
+    );
+    config.metrics.value_rules.insert(
+        "request_latency".to_string(),
+        ValueRule {
+            from: Some(Unit::Milliseconds),
+            to: Some(Unit::Seconds),
+            precision: Some(1),
+        },
+    );
+    config.metrics.histogram_buckets = vec![0.5, 1.0, 2.0];
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let batch = MetricsBatch {
+        metrics: vec![
+            create_test_metric("request_duration", MetricType::Gauge, 1234.5678, None),
+            create_test_metric("heap", MetricType::Gauge, 3.0 * 1024.0 * 1024.0, None),
+            // Pushed in milliseconds, bounds and sum alike
+            bucketed(&[(500.0, 2.0), (1000.0, 3.0), (2000.0, 4.0)], 2345.0, 4.0),
+        ],
+        source: "test".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(
+        "app_metrics_server_request_duration{instance=\"test_instance\",service=\"test_service\"} 1.235"
+    ));
+    assert!(output.contains(
+        "app_metrics_server_heap{instance=\"test_instance\",service=\"test_service\"} 3\n"
+    ));
+    let latency = "app_metrics_server_request_latency";
+    let labels = "instance=\"test_instance\",service=\"test_service\"";
+    assert!(output.contains(&format!("{}_bucket{{{},le=\"0.5\"}} 2\n", latency, labels)));
+    assert!(output.contains(&format!("{}_bucket{{{},le=\"1\"}} 3\n", latency, labels)));
+    assert!(output.contains(&format!("{}_sum{{{}}} 2.3\n", latency, labels)));
+}
+
+#[tokio::test]
+async fn test_multi_sample_metric() {
+    let collector = MetricsCollector::new(create_test_registry());
+    let batch: MetricsBatch = serde_json::from_value(serde_json::json!({
+        "source": "agent",
+        "metrics": [
+            {"name": "latency", "metric_type": "histogram", "help": "Latency", "labels": {},
+             "value": [{"value": 0.02}, {"value": 0.2}, {"value": 2.0}]},
+            {"name": "requests", "metric_type": "counter", "help": "Requests", "labels": {},
+             "value": [{"value": 3}, {"value": 4}]}
+        ]
+    }))
+    .unwrap();
+    collector.process_batch(batch).await.unwrap();
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("app_metrics_server_latency_count 3"));
+    assert!(output.contains("app_metrics_server_latency_bucket{le=\"0.25\"} 2"));
+    assert!(output.contains("app_metrics_server_requests 7"));
+}
+
 #[tokio::test]
 async fn test_update_gauge() {
     let registry = create_test_registry();
@@ -115,6 +434,45 @@ async fn test_update_gauge() {
     assert!(metrics_data.contains("test_gauge"));
 }
 
+#[tokio::test]
+async fn test_gauge_operations() {
+    let registry = create_test_registry();
+    let gauge = |value: f64, operation: GaugeOperation| Metric {
+        operation,
+        ..create_test_metric("active_connections", MetricType::Gauge, value, None)
+    };
+
+    registry
+        .register_metric(&gauge(0.0, GaugeOperation::Set))
+        .await
+        .unwrap();
+    for (value, operation) in [
+        (10.0, GaugeOperation::Set),
+        (1.0, GaugeOperation::Inc),
+        (3.0, GaugeOperation::Dec),
+        (-2.5, GaugeOperation::Add),
+    ] {
+        registry
+            .update_metric(&gauge(value, operation))
+            .await
+            .unwrap();
+    }
+
+    let metrics_data = registry.gather().unwrap();
+    assert!(
+        metrics_data.contains(
+            "active_connections{instance=\"test_instance\",service=\"test_service\"} 5.5"
+        )
+    );
+
+    let counter = Metric {
+        operation: GaugeOperation::Inc,
+        ..create_test_metric("request_count", MetricType::Counter, 1.0, None)
+    };
+    let err = counter.validate(&AppConfig::default().metrics).unwrap_err();
+    assert!(err.to_string().contains("only supported for gauges"));
+}
+
 #[tokio::test]
 async fn test_metrics_count() {
     let registry = create_test_registry();
@@ -215,3 +573,2146 @@ async fn test_mismatched_metric_types() {
 
     assert!(result.is_err());
 }
+
+#[tokio::test]
+async fn test_negative_counter_rejected() {
+    let registry = create_test_registry();
+    let collector = MetricsCollector::new(registry);
+
+    let counter = create_test_metric("request_count", MetricType::Counter, -5.0, None);
+    let batch = MetricsBatch {
+        metrics: vec![counter],
+        source: "test_app".to_string(),
+    };
+
+    let result = collector.process_batch(batch).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_negative_counter_clamped() {
+    let mut config = AppConfig::default();
+    config.metrics.negative_counter_policy = NegativeCounterPolicy::Clamp;
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let counter = create_test_metric("request_count", MetricType::Counter, -5.0, None);
+    let batch = MetricsBatch {
+        metrics: vec![counter],
+        source: "test_app".to_string(),
+    };
+
+    let response = collector.process_batch(batch).await.unwrap();
+    assert_eq!(response.processed, 1);
+    assert!(collector.get_metrics().unwrap().contains("request_count"));
+}
+
+fn bucketed(buckets: &[(f64, f64)], sum: f64, count: f64) -> Metric {
+    let histogram = BucketCounts {
+        buckets: buckets
+            .iter()
+            .map(|&(le, count)| Bucket { le, count })
+            .collect(),
+        sum,
+        count,
+    };
+    Metric {
+        value: MetricValue::new(SampleValue::Histogram(histogram), None).into(),
+        ..create_test_metric("request_latency", MetricType::Histogram, 0.0, None)
+    }
+}
+
+#[tokio::test]
+async fn test_bucketed_histograms_remapped_to_canonical_layout() {
+    let mut config = AppConfig::default();
+    config.metrics.schemas = vec![MetricSchema {
+        name: "request_latency".to_string(),
+        metric_type: MetricType::Histogram,
+        required_labels: Vec::new(),
+        buckets: Some(vec![1.0, 2.0, 4.0]),
+    }];
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    // Two instances pushing with their own bucket layouts
+    let batch = MetricsBatch {
+        metrics: vec![
+            bucketed(&[(1.0, 2.0), (2.0, 4.0)], 5.0, 4.0),
+            bucketed(&[(0.5, 1.0), (4.0, 5.0), (f64::INFINITY, 6.0)], 10.0, 6.0),
+        ],
+        source: "test_app".to_string(),
+    };
+    let response = collector.process_batch(batch).await.unwrap();
+    assert_eq!(response.processed, 2);
+
+    let output = collector.get_metrics().unwrap();
+    let bucket = |le: &str| {
+        format!(
+            "request_latency_bucket{{instance=\"test_instance\",service=\"test_service\",le=\"{}\"}}",
+            le
+        )
+    };
+    // 2 + 1.57 and 4 + 2.71 after interpolating the second layout, rounded
+    assert!(output.contains(&format!("{} 4\n", bucket("1"))));
+    assert!(output.contains(&format!("{} 7\n", bucket("2"))));
+    assert!(output.contains(&format!("{} 9\n", bucket("4"))));
+    assert!(output.contains(&format!("{} 10\n", bucket("+Inf"))));
+    assert!(
+        output.contains(
+            "request_latency_sum{instance=\"test_instance\",service=\"test_service\"} 15"
+        )
+    );
+
+    let invalid = MetricsBatch {
+        metrics: vec![bucketed(&[(1.0, 3.0), (2.0, 2.0)], 1.0, 3.0)],
+        source: "test_app".to_string(),
+    };
+    assert!(collector.ingest(invalid).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_first_writes_register_once() {
+    let collector = Arc::new(MetricsCollector::new(create_test_registry()));
+
+    let writers: Vec<_> = (0..16)
+        .map(|i| {
+            let collector = collector.clone();
+            tokio::spawn(async move {
+                let batch = MetricsBatch {
+                    metrics: vec![create_test_metric(
+                        "concurrent_total",
+                        MetricType::Counter,
+                        1.0,
+                        Some(HashMap::from([("worker".to_string(), (i % 4).to_string())])),
+                    )],
+                    source: "test_app".to_string(),
+                };
+                collector.process_batch_report(batch).await
+            })
+        })
+        .collect();
+    for writer in writers {
+        assert!(writer.await.unwrap().errors.is_empty());
+    }
+
+    let output = collector.get_metrics().unwrap();
+    for worker in 0..4 {
+        assert!(output.contains(&format!(
+            "app_metrics_server_concurrent_total{{worker=\"{}\"}} 4",
+            worker
+        )));
+    }
+}
+
+#[tokio::test]
+async fn test_write_coalescing() {
+    let mut config = AppConfig::default();
+    config.metrics.write_coalescing = Some(WriteCoalescingConfig {
+        max_delay_ms: 200,
+        max_batch: 1000,
+    });
+    assert!(config.validate().is_ok());
+    let collector = Arc::new(MetricsCollector::new(
+        MetricsRegistry::new(config.metrics).unwrap(),
+    ));
+
+    // The first writes lead the family, the burst behind them queues up
+    let writers: Vec<_> = (0..50)
+        .map(|i| {
+            let collector = collector.clone();
+            tokio::spawn(async move {
+                let batch = MetricsBatch {
+                    metrics: vec![create_test_metric(
+                        "burst_total",
+                        MetricType::Counter,
+                        1.0,
+                        Some(HashMap::from([("worker".to_string(), (i % 2).to_string())])),
+                    )],
+                    source: "cron".to_string(),
+                };
+                collector.process_batch(batch).await
+            })
+        })
+        .collect();
+    for writer in writers {
+        assert_eq!(writer.await.unwrap().unwrap().processed, 1);
+    }
+
+    let output = collector.get_metrics().unwrap();
+    for worker in 0..2 {
+        assert!(output.contains(&format!(
+            "app_metrics_server_burst_total{{worker=\"{}\"}} 25",
+            worker
+        )));
+    }
+    let coalesced: u64 = output
+        .lines()
+        .find_map(|line| line.strip_prefix("rustic_insights_coalesced_writes_total "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(coalesced > 0);
+
+    // The metrics of one batch are written one after another, with no one to
+    // wait for
+    let started = std::time::Instant::now();
+    let batch = MetricsBatch {
+        metrics: (0..200)
+            .map(|i| {
+                create_test_metric(
+                    "burst_total",
+                    MetricType::Counter,
+                    1.0,
+                    Some(HashMap::from([("worker".to_string(), i.to_string())])),
+                )
+            })
+            .collect(),
+        source: "cron".to_string(),
+    };
+    assert_eq!(collector.process_batch(batch).await.unwrap().processed, 200);
+    assert!(started.elapsed() < std::time::Duration::from_millis(200));
+
+    let mut config = AppConfig::default();
+    config.metrics.write_coalescing = Some(WriteCoalescingConfig {
+        max_batch: 0,
+        ..WriteCoalescingConfig::default()
+    });
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_memory_budget_rejects_new_series() {
+    let mut config = AppConfig::default();
+    // Room for two series with the default test labels
+    config.metrics.max_memory_bytes = Some(500);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let metric = |name: &str, value: f64| create_test_metric(name, MetricType::Gauge, value, None);
+    let batch = MetricsBatch {
+        metrics: vec![
+            metric("first", 1.0),
+            metric("second", 2.0),
+            metric("third", 3.0),
+        ],
+        source: "test_app".to_string(),
+    };
+    let response = collector.process_batch_report(batch).await;
+    assert_eq!(response.processed, 2);
+    assert_eq!(response.errors.len(), 1);
+    assert!(response.errors[0].contains("Memory budget exceeded"));
+    // The family registered for the rejected series is rolled back
+    assert_eq!(collector.get_metrics_count().await.unwrap(), 2);
+
+    // Existing series keep updating
+    let batch = MetricsBatch {
+        metrics: vec![metric("first", 10.0)],
+        source: "test_app".to_string(),
+    };
+    let response = collector.process_batch_report(batch).await;
+    assert!(response.errors.is_empty());
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(
+        "app_metrics_server_first{instance=\"test_instance\",service=\"test_service\"} 10"
+    ));
+    assert!(!output.contains("app_metrics_server_third"));
+    assert!(output.contains("rustic_insights_registry_memory_bytes 464"));
+    assert!(output.contains("rustic_insights_rejected_metrics_total{reason=\"memory_budget\"} 1"));
+}
+
+#[tokio::test]
+async fn test_source_sampling() {
+    let mut config = AppConfig::default();
+    config.metrics.sampling.insert(
+        "chatty".to_string(),
+        SamplingConfig {
+            batch_one_in: Some(3),
+            metrics: HashMap::from([("debug_events".to_string(), 0.0)]),
+        },
+    );
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let batch = |source: &str| MetricsBatch {
+        metrics: vec![
+            create_test_metric("request_count", MetricType::Counter, 1.0, None),
+            create_test_metric("debug_events", MetricType::Counter, 1.0, None),
+        ],
+        source: source.to_string(),
+    };
+
+    let mut processed = 0;
+    let mut sampled_out = 0;
+    for _ in 0..6 {
+        let response = collector.process_batch(batch("chatty")).await.unwrap();
+        processed += response.processed;
+        sampled_out += response.sampled_out;
+    }
+    // Two batches kept, without their debug_events
+    assert_eq!(processed, 2);
+    assert_eq!(sampled_out, 10);
+
+    let response = collector.process_batch(batch("quiet")).await.unwrap();
+    assert_eq!(response.processed, 2);
+    assert_eq!(response.sampled_out, 0);
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("rustic_insights_sampled_out_total{source=\"chatty\"} 10"));
+}
+
+#[tokio::test]
+async fn test_dedup_window() {
+    let mut config = AppConfig::default();
+    config.metrics.dedup_window_secs = Some(60);
+    config
+        .metrics
+        .source_dedup_window_secs
+        .insert("raw".to_string(), 0);
+    assert!(config.validate().is_ok());
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics.clone()).unwrap());
+    collector
+        .register_source(SourceRequest {
+            source: "agent".to_string(),
+            push_interval_secs: None,
+            allowed_prefixes: Vec::new(),
+        })
+        .await
+        .unwrap();
+
+    let sample = |value: f64, timestamp: Option<i64>| MetricValue {
+        value,
+        timestamp,
+        enum_value: None,
+        histogram: None,
+    };
+    let batch = |source: &str, name: &str, metric_type: MetricType, samples: Vec<MetricValue>| {
+        let mut metric = create_test_metric(name, metric_type, 0.0, None);
+        metric.value = MetricSamples::Many(samples);
+        MetricsBatch {
+            metrics: vec![metric],
+            source: source.to_string(),
+        }
+    };
+    let gauge = |source: &str, samples| batch(source, "requests", MetricType::Gauge, samples);
+
+    let response = collector
+        .process_batch(gauge(
+            "agent",
+            vec![sample(1.0, Some(1000)), sample(1.0, Some(1000))],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.processed, 1);
+    assert_eq!(response.deduplicated, 1);
+    // Only new samples of a resent metric are applied
+    let response = collector
+        .process_batch(gauge(
+            "agent",
+            vec![sample(1.0, Some(1000)), sample(2.0, Some(1000))],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.deduplicated, 1);
+    let response = collector
+        .process_batch_report(gauge("agent", vec![sample(1.0, Some(1000))]))
+        .await;
+    assert_eq!(response.processed, 0);
+    assert!(response.errors.is_empty());
+    assert_eq!(response.deduplicated, 1);
+
+    // Of two concurrent resends, only one gets through
+    let (first, second) = tokio::join!(
+        collector.process_batch_report(gauge("agent", vec![sample(4.0, Some(2000))])),
+        collector.process_batch_report(gauge("agent", vec![sample(4.0, Some(2000))])),
+    );
+    assert_eq!(first.processed + second.processed, 1);
+    assert_eq!(first.deduplicated + second.deduplicated, 1);
+
+    // The same sample from another source, or from one opted out, counts, and
+    // so does one without a timestamp
+    for source in ["other", "raw", "raw"] {
+        let response = collector
+            .process_batch(gauge(source, vec![sample(1.0, Some(1000))]))
+            .await
+            .unwrap();
+        assert_eq!(response.deduplicated, 0);
+    }
+    let response = collector
+        .process_batch(gauge("agent", vec![sample(3.0, None), sample(3.0, None)]))
+        .await
+        .unwrap();
+    assert_eq!(response.deduplicated, 0);
+
+    // Pushing a counter increment twice increments it twice
+    for _ in 0..2 {
+        let response = collector
+            .process_batch(batch(
+                "agent",
+                "events",
+                MetricType::Counter,
+                vec![sample(1.0, Some(1000)), sample(1.0, Some(1000))],
+            ))
+            .await;
+        assert!(response.is_ok_and(|response| response.deduplicated == 0));
+    }
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(
+        r#"app_metrics_server_requests{instance="test_instance",service="test_service"} 3"#
+    ));
+    assert!(output.contains(
+        r#"app_metrics_server_events{instance="test_instance",service="test_service"} 4"#
+    ));
+    assert!(output.contains(r#"rustic_insights_deduplicated_samples_total{source="agent"} 4"#));
+
+    // A sample whose write failed isn't dropped when retried
+    let store = Arc::new(RecordingStore {
+        writes: Mutex::new(Vec::new()),
+        removed: Mutex::new(Vec::new()),
+        unavailable: AtomicBool::new(true),
+        replica: create_test_registry(),
+    });
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap())
+        .with_store(store.clone());
+    let retried = || gauge("agent", vec![sample(1.0, Some(1000))]);
+    assert!(collector.process_batch(retried()).await.is_err());
+    store.unavailable.store(false, Ordering::SeqCst);
+    let response = collector.process_batch(retried()).await.unwrap();
+    assert_eq!((response.processed, response.deduplicated), (1, 0));
+    assert_eq!(store.writes.lock().unwrap().len(), 1);
+
+    let mut config = AppConfig::default();
+    config.metrics.dedup_window_secs = Some(0);
+    assert!(config.validate().is_err());
+}
+
+fn non_finite_batch() -> MetricsBatch {
+    MetricsBatch {
+        metrics: vec![
+            create_test_metric("cpu_usage", MetricType::Gauge, f64::INFINITY, None),
+            create_test_metric("memory_usage", MetricType::Gauge, f64::NAN, None),
+        ],
+        source: "test_app".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_non_finite_value_rejected() {
+    let collector = MetricsCollector::new(create_test_registry());
+
+    let response = collector.process_batch_report(non_finite_batch()).await;
+    assert_eq!(response.processed, 0);
+    assert_eq!(response.errors.len(), 2);
+    assert!(response.errors[0].contains("non-finite"));
+    assert!(!response.error_details[0].retryable);
+}
+
+#[tokio::test]
+async fn test_non_finite_value_dropped() {
+    let mut config = AppConfig::default();
+    config.metrics.non_finite_policy = NonFinitePolicy::Drop;
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    collector.process_batch(non_finite_batch()).await.unwrap();
+
+    let output = collector.get_metrics().unwrap();
+    assert!(!output.contains("cpu_usage"));
+    assert!(output.contains("rustic_insights_dropped_samples_total{reason=\"non_finite\"} 2"));
+}
+
+#[tokio::test]
+async fn test_non_finite_value_clamped() {
+    let mut config = AppConfig::default();
+    config.metrics.non_finite_policy = NonFinitePolicy::Clamp;
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    collector.process_batch(non_finite_batch()).await.unwrap();
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(&format!("}} {}", f64::MAX)));
+    assert!(output.contains("memory_usage{instance=\"test_instance\",service=\"test_service\"} 0"));
+}
+
+#[tokio::test]
+async fn test_label_value_with_control_characters_rejected() {
+    let collector = MetricsCollector::new(create_test_registry());
+
+    let mut labels = HashMap::new();
+    labels.insert("service".to_string(), "line\nbreak".to_string());
+    let gauge = create_test_metric("memory_usage", MetricType::Gauge, 1.0, Some(labels));
+    let batch = MetricsBatch {
+        metrics: vec![gauge],
+        source: "test_app".to_string(),
+    };
+
+    assert!(collector.process_batch(batch).await.is_err());
+}
+
+#[tokio::test]
+async fn test_label_value_sanitized() {
+    let mut config = AppConfig::default();
+    config.metrics.label_value_policy = LabelValuePolicy::Sanitize;
+    config.metrics.max_label_value_length = 8;
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let mut labels = HashMap::new();
+    labels.insert("service".to_string(), "a\nvery-long-value".to_string());
+    let gauge = create_test_metric("memory_usage", MetricType::Gauge, 1.0, Some(labels));
+    let batch = MetricsBatch {
+        metrics: vec![gauge],
+        source: "test_app".to_string(),
+    };
+
+    let response = collector.process_batch(batch).await.unwrap();
+    assert_eq!(response.processed, 1);
+
+    let metrics_data = collector.get_metrics().unwrap();
+    assert!(
+        metrics_data.contains(r#"service="a\\nvery-""#),
+        "{}",
+        metrics_data
+    );
+}
+
+#[tokio::test]
+async fn test_rename_rules() {
+    let mut config = AppConfig::default();
+    config.metrics.rename_rules.push(RenameRule {
+        from: "legacy_requests".to_string(),
+        to: "http_requests".to_string(),
+        labels: HashMap::from([("migrated".to_string(), "true".to_string())]),
+    });
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let counter = create_test_metric("legacy_requests", MetricType::Counter, 1.0, None);
+    let batch = MetricsBatch {
+        metrics: vec![counter],
+        source: "test_app".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    let metrics_data = collector.get_metrics().unwrap();
+    assert!(metrics_data.contains("app_metrics_server_http_requests{"));
+    assert!(metrics_data.contains(r#"migrated="true""#));
+    assert!(!metrics_data.contains("legacy_requests"));
+
+    // A rule can't rename a metric out of the source's prefixes
+    collector
+        .register_source(SourceRequest {
+            source: "legacy".to_string(),
+            push_interval_secs: None,
+            allowed_prefixes: vec!["legacy_".to_string()],
+        })
+        .await
+        .unwrap();
+    let batch = MetricsBatch {
+        metrics: vec![create_test_metric(
+            "legacy_requests",
+            MetricType::Counter,
+            1.0,
+            None,
+        )],
+        source: "legacy".to_string(),
+    };
+    let response = collector.process_batch_report(batch).await;
+    assert_eq!(response.processed, 0);
+    assert!(response.errors[0].contains("may not push 'http_requests'"));
+}
+
+#[tokio::test]
+async fn test_scrape_time_label_enrichment() {
+    let path = std::env::temp_dir().join(format!("enrichment-{}.json", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"{"test_instance": {"team": "payments", "instance": "ignored"}}"#,
+    )
+    .unwrap();
+
+    let enricher = Arc::new(LabelEnricher::new(EnrichmentConfig {
+        key_label: "instance".to_string(),
+        by_source: false,
+        provider: EnrichmentProvider::File {
+            path: path.to_string_lossy().to_string(),
+        },
+        refresh_interval_secs: 60,
+    }));
+    enricher.refresh().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let registry = create_test_registry().with_enricher(enricher);
+    let gauge = create_test_metric("memory_usage", MetricType::Gauge, 1.0, None);
+    registry.register_metric(&gauge).await.unwrap();
+    registry.update_metric(&gauge).await.unwrap();
+
+    let metrics_data = registry.gather().unwrap();
+    assert!(
+        metrics_data.contains(r#"instance="test_instance",service="test_service",team="payments""#),
+        "{}",
+        metrics_data
+    );
+}
+
+#[tokio::test]
+async fn test_label_enrichment_by_source() {
+    let enricher = Arc::new(LabelEnricher::new(EnrichmentConfig {
+        key_label: String::new(),
+        by_source: true,
+        provider: EnrichmentProvider::File {
+            path: "unused.json".to_string(),
+        },
+        refresh_interval_secs: 60,
+    }));
+    enricher.set_table(HashMap::from([
+        (
+            "billing".to_string(),
+            HashMap::from([("team".to_string(), "payments".to_string())]),
+        ),
+        (
+            "search".to_string(),
+            HashMap::from([("team".to_string(), "discovery".to_string())]),
+        ),
+    ]));
+    let collector = MetricsCollector::new(create_test_registry().with_enricher(enricher));
+
+    let labels = |instance: &str| {
+        Some(HashMap::from([(
+            "instance".to_string(),
+            instance.to_string(),
+        )]))
+    };
+    for (source, instance) in [
+        ("billing", "billing-1"),
+        ("search", "search-1"),
+        ("batch", "batch-1"),
+    ] {
+        let batch = MetricsBatch {
+            metrics: vec![create_test_metric(
+                "memory_usage",
+                MetricType::Gauge,
+                1.0,
+                labels(instance),
+            )],
+            source: source.to_string(),
+        };
+        collector.process_batch(batch).await.unwrap();
+    }
+
+    let metrics_data = collector.get_metrics().unwrap();
+    assert!(metrics_data.contains(r#"{instance="billing-1",team="payments"} 1"#));
+    assert!(metrics_data.contains(r#"{instance="search-1",team="discovery"} 1"#));
+    // Sources missing from the metadata are left alone
+    assert!(metrics_data.contains(r#"{instance="batch-1"} 1"#));
+
+    let config = AppConfig {
+        enrichment: Some(EnrichmentConfig {
+            key_label: "instance".to_string(),
+            by_source: true,
+            provider: EnrichmentProvider::File {
+                path: "unused.json".to_string(),
+            },
+            refresh_interval_secs: 60,
+        }),
+        ..AppConfig::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_self_metrics_const_labels() {
+    let mut config = AppConfig::default();
+    config
+        .metrics
+        .self_metrics_labels
+        .insert("pod".to_string(), "gateway-0".to_string());
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let gauge = create_test_metric("memory_usage", MetricType::Gauge, 1.0, None);
+    let batch = MetricsBatch {
+        metrics: vec![gauge],
+        source: "test_app".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    let metrics_data = collector.get_metrics().unwrap();
+    assert!(metrics_data.contains(r#"rustic_insights_ingested_metrics_total{pod="gateway-0"} 1"#));
+
+    // An invalid label is an error, not a panic
+    let mut config = AppConfig::default();
+    config
+        .metrics
+        .self_metrics_labels
+        .insert("pod-name".to_string(), "gateway-0".to_string());
+    assert!(matches!(
+        MetricsRegistry::new(config.metrics),
+        Err(ServerError::MetricRegistrationError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_rejected_metrics_dead_lettered() {
+    let path = std::env::temp_dir().join(format!("dead-letter-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let collector = MetricsCollector::new(create_test_registry())
+        .with_dead_letter(Arc::new(FileDeadLetterWriter::new(&path)));
+
+    let batch = MetricsBatch {
+        metrics: vec![
+            create_test_metric("request_count", MetricType::Counter, -5.0, None),
+            create_test_metric("memory_usage", MetricType::Gauge, 1.0, None),
+        ],
+        source: "test_app".to_string(),
+    };
+    let response = collector.process_batch(batch).await.unwrap();
+    assert_eq!(response.processed, 1);
+
+    // Written in the background
+    collector.flush_dead_letters().await;
+    let content = std::fs::read_to_string(&path).unwrap();
+    let letters: Vec<DeadLetter> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].source, "test_app");
+    assert_eq!(letters[0].reason, "counter_decrease");
+    assert_eq!(letters[0].payload["name"], "request_count");
+}
+
+#[tokio::test]
+async fn test_schema_enforcement() {
+    let mut config = AppConfig::default();
+    config.metrics.unknown_metric_policy = UnknownMetricPolicy::Reject;
+    config.metrics.schemas = vec![
+        MetricSchema {
+            name: "request_count".to_string(),
+            metric_type: MetricType::Counter,
+            required_labels: vec!["service".to_string()],
+            buckets: None,
+        },
+        MetricSchema {
+            name: "request_latency".to_string(),
+            metric_type: MetricType::Histogram,
+            required_labels: Vec::new(),
+            buckets: Some(vec![0.5, 1.0]),
+        },
+    ];
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let batch = |metric: Metric| MetricsBatch {
+        metrics: vec![metric],
+        source: "test_app".to_string(),
+    };
+
+    let valid = create_test_metric("request_count", MetricType::Counter, 1.0, None);
+    assert!(collector.process_batch(batch(valid)).await.is_ok());
+
+    let wrong_type = create_test_metric("request_count", MetricType::Gauge, 1.0, None);
+    assert!(collector.process_batch(batch(wrong_type)).await.is_err());
+
+    let unlabelled = create_test_metric(
+        "request_count",
+        MetricType::Counter,
+        1.0,
+        Some(HashMap::from([("instance".to_string(), "a".to_string())])),
+    );
+    assert!(collector.process_batch(batch(unlabelled)).await.is_err());
+
+    let unknown = create_test_metric("memory_usage", MetricType::Gauge, 1.0, None);
+    assert!(collector.process_batch(batch(unknown)).await.is_err());
+
+    let latency = create_test_metric("request_latency", MetricType::Histogram, 0.7, None);
+    assert!(collector.process_batch(batch(latency)).await.is_ok());
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(
+        "request_latency_bucket{instance=\"test_instance\",service=\"test_service\",le=\"1\"} 1"
+    ));
+    assert!(!output.contains("le=\"0.1\""));
+    assert!(output.contains("rustic_insights_rejected_metrics_total{reason=\"schema\"} 3"));
+}
+
+#[tokio::test]
+async fn test_strict_mode_rejects_whole_batch() {
+    let mut config = AppConfig::default();
+    config
+        .metrics
+        .source_ingest_modes
+        .insert("strict_app".to_string(), IngestMode::Strict);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let batch = |source: &str| MetricsBatch {
+        metrics: vec![
+            create_test_metric("memory_usage", MetricType::Gauge, 1.0, None),
+            create_test_metric("request_count", MetricType::Counter, -1.0, None),
+        ],
+        source: source.to_string(),
+    };
+
+    let result = collector.process_batch(batch("strict_app")).await;
+    assert!(result.unwrap_err().to_string().contains("strict mode"));
+    assert!(!collector.get_metrics().unwrap().contains("memory_usage"));
+
+    let response = collector.process_batch(batch("lenient_app")).await.unwrap();
+    assert_eq!(response.mode, IngestMode::Lenient);
+    assert_eq!(response.status, "partial_success");
+    assert_eq!(response.processed, 1);
+    assert!(collector.get_metrics().unwrap().contains("memory_usage"));
+}
+
+#[tokio::test]
+async fn test_strict_batch_over_memory_budget() {
+    let mut config = AppConfig::default();
+    // Room for two series with the default test labels
+    config.metrics.max_memory_bytes = Some(500);
+    config
+        .metrics
+        .source_ingest_modes
+        .insert("strict_app".to_string(), IngestMode::Strict);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let metric = |name: &str, value: f64| create_test_metric(name, MetricType::Gauge, value, None);
+    let batch = MetricsBatch {
+        metrics: vec![metric("first", 1.0)],
+        source: "strict_app".to_string(),
+    };
+    assert!(
+        collector
+            .process_batch_report(batch)
+            .await
+            .errors
+            .is_empty()
+    );
+
+    // Each new series fits on its own, not both together
+    let batch = MetricsBatch {
+        metrics: vec![
+            metric("first", 10.0),
+            metric("second", 2.0),
+            metric("third", 3.0),
+        ],
+        source: "strict_app".to_string(),
+    };
+    let response = collector.process_batch_report(batch).await;
+    assert_eq!(response.status, "rejected");
+    assert_eq!(response.processed, 0);
+    assert!(response.errors[0].contains("Memory budget exceeded"));
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(
+        "app_metrics_server_first{instance=\"test_instance\",service=\"test_service\"} 1"
+    ));
+    assert!(!output.contains("app_metrics_server_second"));
+    assert!(!output.contains("app_metrics_server_third"));
+    assert!(output.contains("rustic_insights_registry_memory_bytes 232"));
+    assert_eq!(collector.get_metrics_count().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_undo_writes() {
+    let registry = MetricsRegistry::new(AppConfig::default().metrics).unwrap();
+    let write = |metric: &Metric| {
+        let registry = &registry;
+        let metric = metric.clone();
+        async move {
+            let undo = registry.undo_point(&metric);
+            registry.register_metric(&metric).await.unwrap();
+            registry.update_metric(&metric).await.unwrap();
+            undo
+        }
+    };
+
+    let counter = create_test_metric("requests", MetricType::Counter, 5.0, None);
+    let gauge = create_test_metric("temperature", MetricType::Gauge, 20.0, None);
+    let histogram = create_test_metric("latency", MetricType::Histogram, 0.2, None);
+    for metric in [&counter, &gauge, &histogram] {
+        write(metric).await;
+    }
+
+    let later = create_test_metric("requests", MetricType::Counter, 2.0, None);
+    let undo = write(&later).await;
+    registry.undo(&later, undo).await;
+    let later = create_test_metric("temperature", MetricType::Gauge, 25.0, None);
+    let undo = write(&later).await;
+    registry.undo(&later, undo).await;
+    let undo = write(&histogram).await;
+    registry.undo(&histogram, undo).await;
+    // A new family is unregistered again
+    let new = create_test_metric("queue_depth", MetricType::Gauge, 3.0, None);
+    let undo = write(&new).await;
+    registry.undo(&new, undo).await;
+
+    let output = registry.gather().unwrap();
+    let labels = "{instance=\"test_instance\",service=\"test_service\"}";
+    assert!(output.contains(&format!("app_metrics_server_requests{} 5", labels)));
+    assert!(output.contains(&format!("app_metrics_server_temperature{} 20", labels)));
+    assert!(output.contains(&format!("app_metrics_server_latency_count{} 1", labels)));
+    assert!(!output.contains("queue_depth"));
+}
+
+#[test]
+fn test_timestamp_units() {
+    let millis = 1_767_225_600_250;
+    for value in [
+        json!({"value": 1, "timestamp": 1_767_225_600.25}),
+        json!({"value": 1, "timestamp": millis, "timestamp_unit": "ms"}),
+        json!({"value": 1, "timestamp": 1_767_225_600_250_000i64, "timestamp_unit": "us"}),
+        json!({"value": 1, "timestamp": 1_767_225_600_250_000_000i64, "timestamp_unit": "ns"}),
+        json!({"value": 1, "timestamp": "2026-01-01T00:00:00.250Z"}),
+        json!({"value": 1, "timestamp": "2026-01-01T01:00:00.250+01:00"}),
+    ] {
+        let sample: MetricValue = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(sample.timestamp, Some(millis), "{}", value);
+    }
+    // Integers are seconds unless told otherwise
+    let sample: MetricValue =
+        serde_json::from_value(json!({"value": 1, "timestamp": 1_767_225_600})).unwrap();
+    assert_eq!(sample.timestamp, Some(1_767_225_600_000));
+    assert!(
+        serde_json::from_value::<MetricValue>(json!({"value": 1, "timestamp": "yesterday"}))
+            .is_err()
+    );
+
+    // Written back out as RFC 3339, which reads back the same
+    let sample: MetricValue = serde_json::from_value(json!({
+        "value": 1, "timestamp": millis, "timestamp_unit": "ms"
+    }))
+    .unwrap();
+    let written = serde_json::to_value(&sample).unwrap();
+    assert_eq!(written["timestamp"], "2026-01-01T00:00:00.250Z");
+    let read: MetricValue = serde_json::from_value(written).unwrap();
+    assert_eq!(read.timestamp, Some(millis));
+
+    let metric: MetricV2 = serde_json::from_value(json!({
+        "name": "up", "type": "gauge", "value": 1,
+        "timestamp": 1_767_225_600_250_000_000i64, "timestamp_unit": "ns"
+    }))
+    .unwrap();
+    let metric = Metric::from(metric);
+    assert_eq!(metric.value.as_slice()[0].timestamp, Some(millis));
+}
+
+#[tokio::test]
+async fn test_sample_timestamps() {
+    let mut config = AppConfig::default();
+    config.metrics.series_ttl_secs = Some(60);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let taken_at = chrono::Utc::now() - chrono::Duration::minutes(5);
+    let metric = |name: &str, timestamp: Option<i64>| {
+        let mut metric = create_test_metric(name, MetricType::Gauge, 1.0, None);
+        metric.value.as_mut_slice()[0].timestamp = timestamp;
+        metric
+    };
+    let batch = MetricsBatch {
+        metrics: vec![
+            metric("late_reading", Some(taken_at.timestamp_millis())),
+            metric("live_reading", None),
+        ],
+        source: "edge".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    // The history records when the samples were taken
+    let history = SampleHistory::new(&HistoryConfig {
+        interval_secs: 60,
+        retention_secs: 3600,
+        max_samples: 1000,
+        annotations_path: None,
+    });
+    let mut families = collector.gather_families();
+    collector.stamp_sample_times(&mut families);
+    let recorded_at = chrono::Utc::now();
+    history.record(&families, recorded_at);
+    let mut csv = Vec::new();
+    write_history(
+        ExportFormat::Csv,
+        &history.range(None, None),
+        None,
+        &mut csv,
+    )
+    .unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let row = |name: &str| {
+        csv.lines()
+            .find(|line| line.contains(name))
+            .unwrap()
+            .to_string()
+    };
+    let format = |time: chrono::DateTime<chrono::Utc>| {
+        time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    };
+    assert!(row("late_reading").starts_with(&format(taken_at)));
+    assert!(row("live_reading").starts_with(&format(recorded_at)));
+    // Stamping is kept out of the exposition
+    assert!(collector.get_metrics().unwrap().contains(
+        "app_metrics_server_late_reading{instance=\"test_instance\",service=\"test_service\"} 1\n"
+    ));
+
+    // Series go stale by their samples' time
+    let stats = collector.compact().await;
+    assert_eq!(stats.expired_series, 1);
+    let output = collector.get_metrics().unwrap();
+    assert!(!output.contains("late_reading"));
+    assert!(output.contains("live_reading"));
+}
+
+#[tokio::test]
+async fn test_clock_skew() {
+    let mut config = AppConfig::default();
+    config.metrics.series_ttl_secs = Some(60);
+    config.metrics.compaction_interval_secs = 60;
+    config.metrics.max_clock_skew_secs = Some(300);
+    config
+        .metrics
+        .source_max_clock_skew_secs
+        .insert("backfill".to_string(), 0);
+    assert!(config.validate().is_ok());
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+    collector
+        .register_source(SourceRequest {
+            source: "ahead".to_string(),
+            push_interval_secs: None,
+            allowed_prefixes: Vec::new(),
+        })
+        .await
+        .unwrap();
+
+    let now = chrono::Utc::now();
+    let batch = |source: &str, name: &str, taken_at: chrono::DateTime<chrono::Utc>| {
+        let mut metric = create_test_metric(name, MetricType::Gauge, 1.0, None);
+        metric.value.as_mut_slice()[0].timestamp = Some(taken_at.timestamp_millis());
+        MetricsBatch {
+            metrics: vec![metric],
+            source: source.to_string(),
+        }
+    };
+    for (source, name, taken_at) in [
+        ("ahead", "ahead_reading", now + chrono::Duration::hours(1)),
+        (
+            "drifting",
+            "drifting_reading",
+            now - chrono::Duration::seconds(10),
+        ),
+        (
+            "backfill",
+            "backfill_reading",
+            now - chrono::Duration::hours(1),
+        ),
+    ] {
+        collector
+            .process_batch(batch(source, name, taken_at))
+            .await
+            .unwrap();
+    }
+
+    let output = collector.get_metrics().unwrap();
+    let skew = |source: &str| -> f64 {
+        let prefix = format!(
+            "rustic_insights_source_clock_skew_seconds{{source=\"{}\"}} ",
+            source
+        );
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    assert!((skew("ahead") - 3600.0).abs() < 60.0);
+    // Sources neither registered nor configured share one label value
+    assert!((skew("other") + 10.0).abs() < 60.0);
+    assert!(!output.contains(r#"source="drifting""#));
+    assert!((skew("backfill") + 3600.0).abs() < 60.0);
+    assert!(output.contains(r#"rustic_insights_clock_skew_corrections_total{source="ahead"} 1"#));
+    assert!(!output.contains(r#"rustic_insights_clock_skew_corrections_total{source="other"}"#));
+
+    // Timestamps shifted to the server's clock, unless opted out
+    let mut families = collector.gather_families();
+    collector.stamp_sample_times(&mut families);
+    let timestamp = |name: &str| {
+        families
+            .iter()
+            .find(|family| family.get_name() == format!("app_metrics_server_{}", name))
+            .unwrap()
+            .get_metric()[0]
+            .get_timestamp_ms()
+    };
+    assert!((timestamp("ahead_reading") - now.timestamp_millis()).abs() < 60_000);
+    assert_eq!(
+        timestamp("backfill_reading"),
+        (now - chrono::Duration::hours(1)).timestamp_millis()
+    );
+
+    let stats = collector.compact().await;
+    assert_eq!(stats.expired_series, 1);
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("ahead_reading"));
+    assert!(!output.contains("backfill_reading"));
+
+    let mut config = AppConfig::default();
+    config.metrics.max_clock_skew_secs = Some(0);
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_compaction_reclaims_expired_families() {
+    let mut config = AppConfig::default();
+    config.metrics.series_ttl_secs = Some(1);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let batch = MetricsBatch {
+        metrics: vec![create_test_metric(
+            "memory_usage",
+            MetricType::Gauge,
+            1.0,
+            None,
+        )],
+        source: "test".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    let stats = collector.compact().await;
+    assert_eq!(stats.expired_series, 0);
+    assert!(collector.get_metrics().unwrap().contains("memory_usage"));
+    // The two label names and values
+    assert_eq!(collector.interner().len(), 4);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let stats = collector.compact().await;
+    assert_eq!(stats.expired_series, 1);
+    assert_eq!(stats.reclaimed_families, 1);
+    assert!(collector.interner().is_empty());
+
+    let output = collector.get_metrics().unwrap();
+    assert!(!output.contains("memory_usage"));
+    assert!(output.contains("rustic_insights_reclaimed_families_total 1"));
+
+    let batch = MetricsBatch {
+        metrics: vec![create_test_metric(
+            "memory_usage",
+            MetricType::Gauge,
+            2.0,
+            None,
+        )],
+        source: "test".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+    assert!(collector.get_metrics().unwrap().contains("memory_usage"));
+}
+
+#[tokio::test]
+async fn test_label_strings_are_interned() {
+    let collector = MetricsCollector::new(create_test_registry());
+
+    let metric = |name: &str, host: &str| {
+        let labels = HashMap::from([
+            ("region".to_string(), "eu".to_string()),
+            ("host".to_string(), host.to_string()),
+        ]);
+        create_test_metric(name, MetricType::Gauge, 1.0, Some(labels))
+    };
+    for source in ["edge", "core"] {
+        let batch = MetricsBatch {
+            metrics: vec![
+                metric("cpu_usage", "h1"),
+                metric("cpu_usage", "h2"),
+                metric("disk_usage", "h1"),
+            ],
+            source: source.to_string(),
+        };
+        collector.process_batch(batch).await.unwrap();
+    }
+
+    // Each label name and value is held once, whatever the series, family
+    // or source repeating it
+    let interner = collector.interner();
+    assert_eq!(interner.len(), 5);
+    assert!(Arc::ptr_eq(&interner.intern("eu"), &interner.intern("eu")));
+    assert_eq!(interner.len(), 5);
+    // and kept while series use it
+    assert_eq!(interner.purge(), 0);
+}
+
+#[tokio::test]
+async fn test_expired_series_written_again() {
+    let mut config = AppConfig::default();
+    config.metrics.series_ttl_secs = Some(60);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let stale = (chrono::Utc::now() - chrono::Duration::minutes(5)).timestamp_millis();
+    let metric = |region: &str, value: f64, timestamp: Option<i64>| {
+        let mut metric = create_test_metric(
+            "jobs_total",
+            MetricType::Counter,
+            value,
+            Some(HashMap::from([("region".to_string(), region.to_string())])),
+        );
+        metric.value.as_mut_slice()[0].timestamp = timestamp;
+        metric
+    };
+    let batch = |metrics: Vec<Metric>| MetricsBatch {
+        metrics,
+        source: "test".to_string(),
+    };
+    collector
+        .process_batch(batch(vec![
+            metric("eu", 1.0, Some(stale)),
+            metric("us", 1.0, None),
+        ]))
+        .await
+        .unwrap();
+    let memory = |output: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix("rustic_insights_registry_memory_bytes "))
+            .unwrap()
+            .to_string()
+    };
+    let before = memory(&collector.get_metrics().unwrap());
+
+    let stats = collector.compact().await;
+    assert_eq!(stats.expired_series, 1);
+    assert_eq!(stats.reclaimed_families, 0);
+    assert!(!collector.get_metrics().unwrap().contains("region=\"eu\""));
+
+    // The expired series starts over, and counts against the budget again
+    collector
+        .process_batch(batch(vec![
+            metric("eu", 2.0, None),
+            metric("us", 2.0, None),
+        ]))
+        .await
+        .unwrap();
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(r#"app_metrics_server_jobs_total{region="eu"} 2"#));
+    assert!(output.contains(r#"app_metrics_server_jobs_total{region="us"} 3"#));
+    assert_eq!(memory(&output), before);
+}
+
+#[tokio::test]
+async fn test_family_reregistered_with_other_labels() {
+    // Room for another short series of `jobs_total`, not for a long one
+    let mut config = AppConfig::default();
+    config.metrics.max_memory_bytes = Some(450);
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let metric = |labels: &[(&str, &str)]| {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        create_test_metric("jobs_total", MetricType::Counter, 1.0, Some(labels))
+    };
+    let batch = |metric: Metric| MetricsBatch {
+        metrics: vec![metric],
+        source: "test".to_string(),
+    };
+    collector
+        .process_batch(batch(metric(&[("region", "eu")])))
+        .await
+        .unwrap();
+
+    // The rejected series re-registers the family with its own label keys
+    let region = "x".repeat(100);
+    let rejected = collector
+        .process_batch(batch(metric(&[("region", &region), ("zone", "a")])))
+        .await;
+    assert!(rejected.is_err());
+
+    // The family keeps the keys its series were written with
+    collector
+        .process_batch(batch(metric(&[("region", "eu"), ("zone", "a")])))
+        .await
+        .unwrap();
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(r#"app_metrics_server_jobs_total{region="eu"} 2"#));
+}
+
+#[tokio::test]
+async fn test_recording_rule_aggregates_series() {
+    let collector = MetricsCollector::new(create_test_registry());
+
+    let labels = |service: &str, instance: &str| {
+        HashMap::from([
+            ("service".to_string(), service.to_string()),
+            ("instance".to_string(), instance.to_string()),
+        ])
+    };
+    let batch = MetricsBatch {
+        metrics: vec![
+            create_test_metric(
+                "queue_depth",
+                MetricType::Gauge,
+                3.0,
+                Some(labels("api", "a")),
+            ),
+            create_test_metric(
+                "queue_depth",
+                MetricType::Gauge,
+                5.0,
+                Some(labels("api", "b")),
+            ),
+            create_test_metric(
+                "queue_depth",
+                MetricType::Gauge,
+                4.0,
+                Some(labels("db", "a")),
+            ),
+        ],
+        source: "test".to_string(),
+    };
+    collector.process_batch(batch).await.unwrap();
+
+    let rule = RecordingRule {
+        record: "service:queue_depth:sum".to_string(),
+        expr: "sum by (service) (app_metrics_server_queue_depth{instance=~\"a|b\"}) * 2"
+            .to_string(),
+        labels: HashMap::from([("rule".to_string(), "depth".to_string())]),
+        interval_secs: 60,
+    };
+    let expr = Expr::parse(&rule.expr).unwrap();
+    let written = evaluate_rule(&collector, &rule, &expr).await.unwrap();
+    assert_eq!(written, 2);
+
+    let output = collector.get_metrics().unwrap();
+    assert!(
+        output.contains(
+            "app_metrics_server_service:queue_depth:sum{rule=\"depth\",service=\"api\"} 16"
+        )
+    );
+    assert!(
+        output.contains(
+            "app_metrics_server_service:queue_depth:sum{rule=\"depth\",service=\"db\"} 8"
+        )
+    );
+
+    assert!(Expr::parse("sum by (service (queue_depth)").is_err());
+}
+
+#[tokio::test]
+async fn test_synthetic_metrics() {
+    let sine = SyntheticPattern::Sine {
+        amplitude: 2.0,
+        offset: 10.0,
+        period_secs: 60,
+    };
+    assert!((sine.value_at(0.0) - 10.0).abs() < 1e-9);
+    assert!((sine.value_at(15.0) - 12.0).abs() < 1e-9);
+    assert!((sine.value_at(45.0) - 8.0).abs() < 1e-9);
+
+    let step = SyntheticPattern::Step {
+        low: 0.0,
+        high: 1.0,
+        period_secs: 30,
+    };
+    assert_eq!(step.value_at(29.0), 0.0);
+    assert_eq!(step.value_at(30.0), 1.0);
+    assert_eq!(step.value_at(60.0), 0.0);
+
+    let random = SyntheticPattern::Random { min: 5.0, max: 6.0 };
+    for _ in 0..100 {
+        assert!((5.0..=6.0).contains(&random.value_at(0.0)));
+    }
+
+    let collector = MetricsCollector::new(create_test_registry());
+    let synthetic = SyntheticMetric {
+        name: "demo_load".to_string(),
+        pattern: step,
+        labels: HashMap::from([("env".to_string(), "demo".to_string())]),
+        interval_secs: 15,
+    };
+    write_synthetic(&collector, &synthetic, 45.0).await.unwrap();
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("app_metrics_server_demo_load{env=\"demo\"} 1"));
+
+    let mut config = AppConfig::default();
+    config.synthetic.push(SyntheticMetric {
+        pattern: SyntheticPattern::Random { min: 2.0, max: 1.0 },
+        ..synthetic
+    });
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_derived_rate_gauges() {
+    let mut config = AppConfig::default();
+    config.metrics.derived_rates = true;
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+
+    let push_to = |name: &str, value| MetricsBatch {
+        metrics: vec![create_test_metric(name, MetricType::Counter, value, None)],
+        source: "test".to_string(),
+    };
+    let push = |value| push_to("requests", value);
+    let rate_of = |name: &str, output: &str| -> f64 {
+        output
+            .lines()
+            .find(|line| line.starts_with(&format!("app_metrics_server_{}:rate_1m{{", name)))
+            .and_then(|line| line.rsplit(' ').next())
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let rate = |output: String| rate_of("requests", &output);
+
+    collector.process_batch(push(5.0)).await.unwrap();
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains("# TYPE app_metrics_server_requests:rate_1m gauge"));
+    assert_eq!(rate(output), 0.0);
+
+    // Writes between scrapes count too
+    collector.process_batch(push_to("jobs", 1.0)).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+    collector.process_batch(push(10.0)).await.unwrap();
+    collector.process_batch(push_to("jobs", 1.0)).await.unwrap();
+    let output = collector.get_metrics().unwrap();
+    let per_second = rate(output.clone());
+    assert!(
+        per_second > 5.0 && per_second <= 10.0,
+        "rate was {}",
+        per_second
+    );
+    assert!(rate_of("jobs", &output) > 0.0);
+}
+
+struct RecordingChannel {
+    sent: Mutex<Vec<Notification>>,
+}
+
+impl NotificationChannel for RecordingChannel {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        self.sent.lock().unwrap().push(notification.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_anomaly_notifies_channels() {
+    let mut config = AppConfig::default();
+    config.metrics.anomaly = Some(AnomalyConfig {
+        metrics: Vec::new(),
+        alpha: 0.3,
+        threshold: 3.0,
+        min_samples: 5,
+        state_ttl_secs: 3600,
+        severity: Severity::Critical,
+        notify: vec![
+            NotifyTarget {
+                channel: "ops".to_string(),
+                template: Some(
+                    "{{metric}} on {{ service }} hit {{value}} [{{severity}}]".to_string(),
+                ),
+            },
+            NotifyTarget {
+                channel: "ops".to_string(),
+                template: None,
+            },
+        ],
+    });
+    let channel = Arc::new(RecordingChannel {
+        sent: Mutex::new(Vec::new()),
+    });
+    let notifier = Notifier::default().with_channel("ops", channel.clone());
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap())
+        .with_notifier(Arc::new(notifier));
+
+    for value in [10.0, 11.0, 10.0, 11.0, 10.0, 11.0, 100.0, 120.0] {
+        let batch = MetricsBatch {
+            metrics: vec![create_test_metric(
+                "queue_depth",
+                MetricType::Gauge,
+                value,
+                None,
+            )],
+            source: "worker".to_string(),
+        };
+        collector.process_batch(batch).await.unwrap();
+    }
+    // The clock is paused: this only lets the notifier's task send
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // The anomaly is only notified when it starts
+    let sent = channel.sent.lock().unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].severity, Severity::Critical);
+    let mut messages: Vec<&str> = sent.iter().map(|n| n.message.as_str()).collect();
+    messages.sort();
+    assert!(messages[0].starts_with("queue_depth is 100 ("));
+    assert_eq!(
+        messages[1],
+        "queue_depth on test_service hit 100 [critical]"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_anomaly_state_expires() {
+    let mut config = AppConfig::default();
+    config.metrics.anomaly = Some(AnomalyConfig {
+        metrics: Vec::new(),
+        alpha: 0.3,
+        threshold: 3.0,
+        min_samples: 5,
+        state_ttl_secs: 60,
+        severity: Severity::Warning,
+        notify: Vec::new(),
+    });
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap());
+    let push = |name: &str, value: f64| MetricsBatch {
+        metrics: vec![create_test_metric(name, MetricType::Gauge, value, None)],
+        source: "worker".to_string(),
+    };
+
+    for value in [10.0, 11.0, 10.0, 11.0, 10.0, 11.0, 100.0] {
+        collector
+            .process_batch(push("queue_depth", value))
+            .await
+            .unwrap();
+    }
+    assert_eq!(collector.anomalies().len(), 1);
+
+    tokio::time::advance(std::time::Duration::from_secs(45)).await;
+    collector.compact().await;
+    assert_eq!(collector.anomalies().len(), 1);
+    tokio::time::advance(std::time::Duration::from_secs(30)).await;
+    collector.compact().await;
+    assert!(collector.anomalies().is_empty());
+
+    // Forgotten, queue_depth starts over and needs min_samples again
+    for value in [10.0, 100.0] {
+        collector
+            .process_batch(push("queue_depth", value))
+            .await
+            .unwrap();
+    }
+    assert!(collector.anomalies().is_empty());
+}
+
+#[tokio::test]
+async fn test_collector_from_config() {
+    let mut config = AppConfig::default();
+    config.metrics.metrics_prefix = "embedded".to_string();
+    let collector = MetricsCollector::from_config(&config).unwrap();
+
+    let batch = MetricsBatch {
+        metrics: vec![create_test_metric("orders", MetricType::Counter, 2.0, None)],
+        source: "checkout".to_string(),
+    };
+    let response = collector.ingest(batch).await.unwrap();
+    assert_eq!(response.processed, 1);
+    assert!(collector.get_metrics().unwrap().contains(
+        r#"embedded_metrics_server_orders{instance="test_instance",service="test_service"} 2"#
+    ));
+}
+
+/// A plugin with a bump allocator, reset when the host frees a buffer.
+fn wat_plugin(filter: &str, data: &str) -> String {
+    format!(
+        r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 16) "{}")
+            (global $next (mut i32) (i32.const 4096))
+            (func (export "alloc") (param $len i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $next))
+                (global.set $next (i32.add (global.get $next) (local.get $len)))
+                (local.get $ptr))
+            (func (export "dealloc") (param i32 i32)
+                (global.set $next (i32.const 4096)))
+            (func (export "filter") (param i32 i32) (result i64) {}))"#,
+        data.replace('"', "\\\""),
+        filter
+    )
+}
+
+#[tokio::test]
+async fn test_wasm_plugins() {
+    let dir = std::env::temp_dir().join(format!("plugins-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let replacement = r#"{"name":"latency_seconds","metric_type":"gauge","help":"Latency","labels":{"unit":"s"},"value":{"value":0.25}}"#;
+    let plugins = [
+        ("drop", wat_plugin("(i64.const -1)", "")),
+        (
+            "rescale",
+            wat_plugin(
+                &format!("(i64.const {})", (16i64 << 32) | replacement.len() as i64),
+                replacement,
+            ),
+        ),
+        ("trap", wat_plugin("unreachable", "")),
+        ("spin", wat_plugin("(loop (br 0)) (i64.const 0)", "")),
+    ];
+
+    let mut config = AppConfig::default();
+    for (name, wat) in &plugins {
+        let path = dir.join(format!("{}.wat", name));
+        std::fs::write(&path, wat).unwrap();
+        config.plugins.push(PluginConfig {
+            name: name.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            sources: vec![format!("{}-source", name)],
+            fuel: 100_000,
+            max_memory_bytes: 1024 * 1024,
+        });
+    }
+    assert!(config.validate().is_ok());
+    let collector = MetricsCollector::from_config(&config).unwrap();
+
+    let push = |source: &str, name: &str| MetricsBatch {
+        metrics: vec![create_test_metric(name, MetricType::Gauge, 250.0, None)],
+        source: source.to_string(),
+    };
+    let response = collector
+        .process_batch_report(push("drop-source", "noise"))
+        .await;
+    assert_eq!(response.processed, 1);
+    assert!(response.errors.is_empty());
+    let response = collector
+        .process_batch_report(push("rescale-source", "latency_ms"))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response);
+    collector
+        .process_batch(push("other", "queue_depth"))
+        .await
+        .unwrap();
+
+    let exposed = collector.get_metrics().unwrap();
+    assert!(!exposed.contains("noise"));
+    assert!(!exposed.contains("latency_ms"));
+    assert!(exposed.contains(r#"app_metrics_server_latency_seconds{unit="s"} 0.25"#));
+    assert!(exposed.contains("app_metrics_server_queue_depth{"));
+
+    // A failing plugin rejects the metric, and is instantiated again after it
+    for (source, message) in [
+        ("trap-source", "plugin 'trap'"),
+        ("trap-source", "plugin 'trap'"),
+        ("spin-source", "fuel"),
+    ] {
+        let response = collector.process_batch_report(push(source, "job")).await;
+        assert_eq!(response.processed, 0);
+        assert!(response.errors[0].contains(message), "{:?}", response);
+    }
+
+    config.plugins[0].path = dir.join("missing.wasm").to_string_lossy().into_owned();
+    assert!(MetricsCollector::from_config(&config).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_rhai_scripts() {
+    let dir = std::env::temp_dir().join(format!("scripts-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let scripts = [
+        (
+            "rescale",
+            r#"
+            fn transform(metric) {
+                if metric.name.ends_with("_ms") {
+                    metric.name = metric.name.sub_string(0, metric.name.len() - 3) + "_seconds";
+                    metric.value.value /= 1000.0;
+                }
+                metric.labels.region = "eu";
+                metric
+            }
+            "#,
+        ),
+        (
+            "drop",
+            r#"fn transform(metric) { if metric.name == "noise" { return; } metric }"#,
+        ),
+        ("spin", "fn transform(metric) { loop {} }"),
+        (
+            "escape",
+            r#"fn transform(metric) { metric.name = "escaped"; metric }"#,
+        ),
+    ];
+
+    let mut config = AppConfig::default();
+    for (name, script) in &scripts {
+        let path = dir.join(format!("{}.rhai", name));
+        std::fs::write(&path, script).unwrap();
+        config.scripts.push(ScriptConfig {
+            name: name.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            sources: match *name {
+                "spin" => vec!["spin-source".to_string()],
+                "escape" => vec!["checkout".to_string()],
+                _ => Vec::new(),
+            },
+            max_operations: 100_000,
+            timeout_ms: 50,
+        });
+    }
+    assert!(config.validate().is_ok());
+    let collector = MetricsCollector::from_config(&config).unwrap();
+
+    let push = |source: &str, name: &str| MetricsBatch {
+        metrics: vec![create_test_metric(name, MetricType::Gauge, 250.0, None)],
+        source: source.to_string(),
+    };
+    for name in ["latency_ms", "noise"] {
+        let response = collector.process_batch_report(push("app", name)).await;
+        assert_eq!(response.processed, 1);
+        assert!(response.errors.is_empty(), "{:?}", response);
+    }
+
+    let exposed = collector.get_metrics().unwrap();
+    assert!(!exposed.contains("noise"));
+    assert!(!exposed.contains("latency_ms"));
+    assert!(exposed.contains(r#"app_metrics_server_latency_seconds{instance="test_instance",region="eu",service="test_service"} 0.25"#));
+
+    // Scripts running past their budget reject the metric
+    let response = collector
+        .process_batch_report(push("spin-source", "job"))
+        .await;
+    assert_eq!(response.processed, 0);
+    assert!(
+        response.errors[0].contains("script 'spin'"),
+        "{:?}",
+        response
+    );
+
+    // A renamed metric must still match the source's prefixes
+    collector
+        .register_source(SourceRequest {
+            source: "checkout".to_string(),
+            push_interval_secs: None,
+            allowed_prefixes: vec!["checkout_".to_string()],
+        })
+        .await
+        .unwrap();
+    let response = collector
+        .process_batch_report(push("checkout", "checkout_latency_ms"))
+        .await;
+    assert_eq!(response.processed, 0);
+    assert!(response.errors[0].contains("may not push 'escaped'"));
+
+    std::fs::write(dir.join("drop.rhai"), "fn filter(metric) { metric }").unwrap();
+    assert!(MetricsCollector::from_config(&config).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_schema_drift() {
+    let mut config = AppConfig::default();
+    config.metrics.schema_drift = Some(SchemaDriftConfig {
+        severity: Severity::Warning,
+        notify: vec![NotifyTarget {
+            channel: "ops".to_string(),
+            template: None,
+        }],
+        state_ttl_secs: 3600,
+    });
+    let channel = Arc::new(RecordingChannel {
+        sent: Mutex::new(Vec::new()),
+    });
+    let notifier = Notifier::default().with_channel("ops", channel.clone());
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap())
+        .with_notifier(Arc::new(notifier));
+
+    let push = |source: &str, labels: Option<HashMap<String, String>>| MetricsBatch {
+        metrics: vec![create_test_metric(
+            "queue_depth",
+            MetricType::Gauge,
+            1.0,
+            labels,
+        )],
+        source: source.to_string(),
+    };
+    let region = HashMap::from([
+        ("service".to_string(), "test_service".to_string()),
+        ("region".to_string(), "eu".to_string()),
+    ]);
+
+    collector.process_batch(push("worker", None)).await.unwrap();
+    collector.process_batch(push("worker", None)).await.unwrap();
+    // Label names are tracked per source
+    collector
+        .process_batch(push("cron", Some(region.clone())))
+        .await
+        .unwrap();
+    collector
+        .process_batch(push("worker", Some(region.clone())))
+        .await
+        .unwrap();
+    collector
+        .process_batch(push("worker", Some(region.clone())))
+        .await
+        .unwrap();
+    // Alternating between the baseline and a reported change isn't reported again
+    collector.process_batch(push("worker", None)).await.unwrap();
+    collector
+        .process_batch(push("worker", Some(region.clone())))
+        .await
+        .unwrap();
+    // The clock is paused: this only lets the notifier's task send
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    {
+        let sent = channel.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].message,
+            "worker changed the labels of queue_depth: added [region], removed [instance]"
+        );
+    }
+
+    let output = collector.get_metrics().unwrap();
+    assert!(output.contains(
+        r#"rustic_insights_schema_drifts_total{metric="queue_depth",source="worker"} 1"#
+    ));
+    assert!(
+        !output
+            .contains(r#"rustic_insights_schema_drifts_total{metric="queue_depth",source="cron"}"#)
+    );
+
+    // Once the baseline isn't pushed anymore, the new label names replace it
+    tokio::time::advance(std::time::Duration::from_secs(1800)).await;
+    collector
+        .process_batch(push("worker", Some(region)))
+        .await
+        .unwrap();
+    tokio::time::advance(std::time::Duration::from_secs(1801)).await;
+    collector.compact().await;
+    collector.process_batch(push("worker", None)).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let sent = channel.sent.lock().unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(
+        sent[1].message,
+        "worker changed the labels of queue_depth: added [instance], removed [region]"
+    );
+}
+
+type SeriesLabels = Vec<(String, String)>;
+
+/// Records the writes and serves the families of another replica.
+struct RecordingStore {
+    writes: Mutex<Vec<StoreWrite>>,
+    removed: Mutex<Vec<(String, SeriesLabels)>>,
+    unavailable: AtomicBool,
+    replica: MetricsRegistry,
+}
+
+impl MetricsStore for RecordingStore {
+    fn write<'a>(&'a self, write: &'a StoreWrite) -> BoxFuture<'a, Result<(), ServerError>> {
+        if self.unavailable.load(Ordering::SeqCst) {
+            return Box::pin(async {
+                Err(ServerError::Unavailable {
+                    message: "metrics store: connection refused".to_string(),
+                    retry_after_secs: 1,
+                })
+            });
+        }
+        self.writes.lock().unwrap().push(write.clone());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        family: &'a str,
+        labels: &'a [(String, String)],
+    ) -> BoxFuture<'a, Result<bool, ServerError>> {
+        self.removed
+            .lock()
+            .unwrap()
+            .push((family.to_string(), labels.to_vec()));
+        Box::pin(async { Ok(true) })
+    }
+
+    fn expire(&self, _ttl_secs: u64) -> BoxFuture<'_, Result<usize, ServerError>> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn gather(&self) -> BoxFuture<'_, Result<Snapshot, ServerError>> {
+        let snapshot = Snapshot {
+            families: self.replica.gather_families(),
+        };
+        Box::pin(async { Ok(snapshot) })
+    }
+
+    fn gather_source<'a>(
+        &'a self,
+        _source: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<MetricFamily>>, ServerError>> {
+        let families = self.replica.gather_families();
+        Box::pin(async { Ok(Some(families)) })
+    }
+
+    fn source_series_counts(&self) -> BoxFuture<'_, Result<BTreeMap<String, usize>, ServerError>> {
+        Box::pin(async { Ok(BTreeMap::from([("replica".to_string(), 1)])) })
+    }
+}
+
+#[tokio::test]
+async fn test_shared_store() {
+    let replica = create_test_registry();
+    let mut orders = create_test_metric("orders", MetricType::Counter, 5.0, None);
+    replica.register_metric(&orders).await.unwrap();
+    replica.update_metric(&orders).await.unwrap();
+
+    let store = Arc::new(RecordingStore {
+        writes: Mutex::new(Vec::new()),
+        removed: Mutex::new(Vec::new()),
+        unavailable: AtomicBool::new(false),
+        replica,
+    });
+    let collector = MetricsCollector::new(create_test_registry()).with_store(store.clone());
+
+    orders.value = MetricValue {
+        value: 2.0,
+        timestamp: None,
+        enum_value: None,
+        histogram: None,
+    }
+    .into();
+    let mut queue = create_test_metric("queue_depth", MetricType::Gauge, 3.0, None);
+    queue.operation = GaugeOperation::Dec;
+    let mut latency = create_test_metric("latency", MetricType::Histogram, 0.3, None);
+    latency.value = MetricSamples::Many(vec![
+        MetricValue {
+            value: 0.3,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        },
+        MetricValue {
+            value: 20.0,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        },
+    ]);
+    let batch = MetricsBatch {
+        metrics: vec![orders, queue, latency],
+        source: "checkout".to_string(),
+    };
+    collector.ingest(batch).await.unwrap();
+
+    let writes = store.writes.lock().unwrap().clone();
+    let labels = vec![
+        ("instance".to_string(), "test_instance".to_string()),
+        ("service".to_string(), "test_service".to_string()),
+    ];
+    assert_eq!(writes.len(), 3);
+    assert_eq!(writes[0].family, "app_metrics_server_orders");
+    assert_eq!(writes[0].kind, StoredKind::Counter);
+    assert_eq!(writes[0].labels, labels);
+    assert_eq!(writes[0].update, StoreUpdate::Add(2.0));
+    assert_eq!(writes[1].update, StoreUpdate::Add(-3.0));
+    let StoreUpdate::Observe { counts, sum, count } = &writes[2].update else {
+        panic!("expected observations, got {:?}", writes[2].update);
+    };
+    assert_eq!(writes[2].kind, StoredKind::Histogram);
+    assert_eq!(counts.len(), writes[2].bounds.len() + 1);
+    assert_eq!(counts.iter().sum::<f64>(), 2.0);
+    assert_eq!(counts.last(), Some(&1.0));
+    assert_eq!((*sum, *count), (20.3, 2.0));
+
+    // The exposition is the store's, plus this replica's self metrics
+    let snapshot = collector.gather_exposed().await.unwrap();
+    let exposition = collector.encode(&snapshot.families, false).unwrap();
+    assert!(exposition.contains(
+        r#"app_metrics_server_orders{instance="test_instance",service="test_service"} 5"#
+    ));
+    assert!(!exposition.contains("app_metrics_server_queue_depth"));
+    assert!(exposition.contains("rustic_insights_batches_total"));
+
+    // Per-source and cardinality reads are the store's too
+    let cardinality = collector.cardinality(10).await.unwrap();
+    assert_eq!(cardinality.sources.get("replica"), Some(&1));
+    let families = collector.gather_source_families("checkout").await.unwrap();
+    assert!(
+        families
+            .unwrap()
+            .iter()
+            .any(|family| family.get_name() == "app_metrics_server_orders")
+    );
+
+    // A write the store refuses isn't applied locally, so a retry doesn't
+    // count it twice
+    store.unavailable.store(true, Ordering::SeqCst);
+    let retried = create_test_metric("orders", MetricType::Counter, 7.0, None);
+    let batch = MetricsBatch {
+        metrics: vec![retried],
+        source: "checkout".to_string(),
+    };
+    assert!(collector.ingest(batch).await.is_err());
+    let local = collector
+        .encode(&collector.gather_families(), false)
+        .unwrap();
+    assert!(local.contains(
+        r#"app_metrics_server_orders{instance="test_instance",service="test_service"} 2"#
+    ));
+
+    // Purged tombstones are removed from the store
+    collector
+        .tombstones()
+        .add(
+            &["app_metrics_server_orders".to_string()],
+            chrono::Duration::zero(),
+        )
+        .unwrap();
+    collector.compact().await;
+    let removed = store.removed.lock().unwrap().clone();
+    assert_eq!(
+        removed,
+        vec![("app_metrics_server_orders".to_string(), labels)]
+    );
+
+    // A write refused locally, here over the memory budget, isn't written to the store
+    let mut config = AppConfig::default();
+    config.metrics.max_memory_bytes = Some(1);
+    let store = Arc::new(RecordingStore {
+        writes: Mutex::new(Vec::new()),
+        removed: Mutex::new(Vec::new()),
+        unavailable: AtomicBool::new(false),
+        replica: create_test_registry(),
+    });
+    let collector = MetricsCollector::new(MetricsRegistry::new(config.metrics).unwrap())
+        .with_store(store.clone());
+    let batch = MetricsBatch {
+        metrics: vec![create_test_metric("orders", MetricType::Counter, 1.0, None)],
+        source: "checkout".to_string(),
+    };
+    let families = collector.get_metrics_count().await.unwrap();
+    assert!(collector.ingest(batch).await.is_err());
+    assert!(store.writes.lock().unwrap().is_empty());
+    // Nor is its family left registered
+    assert_eq!(collector.get_metrics_count().await.unwrap(), families);
+}
+
+#[test]
+fn test_prometheus_text_parsing() {
+    let text = br#"# HELP http_requests_total Requests served.
+# TYPE http_requests_total counter
+http_requests_total{code="200",path="/a\"b"} 1027 1395066363000
+http_requests_total{code="500"} 3
+# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{le="0.1"} 2
+rpc_duration_seconds_bucket{le="+Inf"} 5
+rpc_duration_seconds_sum 1.5
+rpc_duration_seconds_count 5
+temperature{room="kitchen" 21
+temperature{room="hall"} NaN
+up 1 not-a-timestamp
+\xff
+free_memory 4096"#;
+
+    let error = parse_text(text, ParseMode::Strict).unwrap_err();
+    assert_eq!(error.line, 10);
+    assert!(error.to_string().starts_with("line 10:"));
+
+    let parsed = parse_text(text, ParseMode::Lenient).unwrap();
+    let lines: Vec<usize> = parsed.errors.iter().map(|e| e.line).collect();
+    assert_eq!(lines, vec![10, 12, 13]);
+
+    let names: Vec<(&str, TextType, usize)> = parsed
+        .families
+        .iter()
+        .map(|f| (f.name.as_str(), f.text_type, f.samples.len()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("http_requests_total", TextType::Counter, 2),
+            ("rpc_duration_seconds", TextType::Histogram, 4),
+            ("temperature", TextType::Untyped, 1),
+            ("free_memory", TextType::Untyped, 1),
+        ]
+    );
+    let requests = &parsed.families[0];
+    assert_eq!(requests.help, "Requests served.");
+    assert_eq!(requests.samples[0].labels["path"], "/a\"b");
+    assert_eq!(requests.samples[0].timestamp_ms, Some(1395066363000));
+    assert!(parsed.families[2].samples[0].value.is_nan());
+
+    // Well-formed payloads parse the same in both modes
+    let valid = b"# TYPE queue_depth gauge\nqueue_depth{queue=\"jobs\",} 3\n";
+    let strict = parse_text(valid, ParseMode::Strict).unwrap();
+    assert!(strict.errors.is_empty());
+    assert_eq!(strict.families[0].samples[0].labels["queue"], "jobs");
+}
+
+#[test]
+fn test_collectd_values_remembered_once_written() {
+    let adapter = CollectdAdapter::new();
+    let readings = |values: &[u64]| -> Vec<CollectdValueList> {
+        values
+            .iter()
+            .map(|value| {
+                serde_json::from_value(json!({
+                    "values": [value],
+                    "dstypes": ["derive"],
+                    "dsnames": ["value"],
+                    "host": "leeloo",
+                    "plugin": "cpu",
+                    "type": "cpu",
+                    "type_instance": "idle"
+                }))
+                .unwrap()
+            })
+            .collect()
+    };
+    let increment = |values: &[u64], commit: bool| {
+        let (batch, pending) = adapter.to_batch("collectd", readings(values), None);
+        assert_eq!(batch.metrics.len(), 1);
+        if commit {
+            adapter.commit("collectd", pending, &batch.metrics);
+        }
+        batch.metrics[0].value.as_slice()[0].value
+    };
+
+    // Readings of a series in one payload add up, across a reset too
+    assert_eq!(increment(&[100, 160, 20], false), 180.0);
+    // Nothing was written, so the full value is forwarded again
+    assert_eq!(increment(&[100, 160], true), 160.0);
+    assert_eq!(increment(&[170, 190], true), 30.0);
+
+    assert_eq!(adapter.expire(std::time::Duration::ZERO), 1);
+    assert_eq!(increment(&[200], true), 200.0);
+}
+
+#[test]
+fn test_prometheus_text_totals_remembered_once_written() {
+    let adapter = PrometheusTextAdapter::new();
+    let increments = |text: &[u8], commit: bool| {
+        let parsed = parse_text(text, ParseMode::Strict).unwrap();
+        let (batch, errors, pending) = adapter.to_batch("agent", parsed.families, None);
+        assert!(errors.is_empty());
+        if commit {
+            adapter.commit("agent", pending, &batch.metrics);
+        }
+        batch
+            .metrics
+            .iter()
+            .map(|m| m.value.as_slice()[0].value)
+            .collect::<Vec<_>>()
+    };
+
+    let text = b"# TYPE jobs_total counter\njobs_total 10\njobs_total 12\n";
+    // Within a payload a series counts from its first sample
+    assert_eq!(increments(text, false), vec![10.0, 2.0]);
+    // Nothing was written, so the full value is forwarded again
+    assert_eq!(
+        increments(b"# TYPE jobs_total counter\njobs_total 15\n", true),
+        vec![15.0]
+    );
+    assert_eq!(
+        increments(b"# TYPE jobs_total counter\njobs_total 18\n", true),
+        vec![3.0]
+    );
+
+    assert_eq!(adapter.expire(std::time::Duration::ZERO), 1);
+    assert_eq!(
+        increments(b"# TYPE jobs_total counter\njobs_total 20\n", true),
+        vec![20.0]
+    );
+}
@@ -0,0 +1,125 @@
+use rustic_insights::encryption::{Cipher, Keyring};
+use rustic_insights::replay::{ReplayOptions, ReplaySummary, parse_record, replay};
+use rustic_insights::test_support::{assert_metric_value, spawn_test_server};
+use serde_json::json;
+
+fn metric(name: &str, value: f64) -> serde_json::Value {
+    json!({
+        "name": name,
+        "metric_type": "gauge",
+        "help": "Replayed gauge",
+        "labels": {},
+        "value": {"value": value, "timestamp": null}
+    })
+}
+
+#[test]
+fn test_parse_dead_letter_record() {
+    let line = json!({
+        "timestamp": "2026-01-01T00:00:00Z",
+        "source": "billing",
+        "reason": "validation",
+        "error": "bad",
+        "payload": metric("invoices_open", 3.0)
+    })
+    .to_string();
+
+    let record = parse_record(&line).unwrap();
+    assert_eq!(record.batch.source, "billing");
+    assert_eq!(record.batch.metrics[0].name, "invoices_open");
+    assert!(record.timestamp.is_some());
+}
+
+#[test]
+fn test_sealed_lines() {
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const BILLING_KEY: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let keyring = Keyring::with_default(Cipher::new(KEY).unwrap())
+        .with_source("billing", Cipher::new(BILLING_KEY).unwrap());
+    let line = json!({"source": "billing", "metrics": [metric("invoices_open", 3.0)]}).to_string();
+
+    let sealed = keyring
+        .seal_line("billing", format!("{}\n", line).into_bytes())
+        .unwrap();
+    let sealed = String::from_utf8(sealed).unwrap();
+    assert!(!sealed.contains("invoices_open"));
+    assert!(parse_record(sealed.trim()).is_err());
+    assert_eq!(keyring.open_line(sealed.trim()).unwrap(), line);
+
+    // Other tenants' keys don't open it, nor does moving it to another source
+    let shared = Keyring::with_default(Cipher::new(KEY).unwrap());
+    assert!(shared.open_line(sealed.trim()).is_err());
+    let sealed =
+        String::from_utf8(shared.seal_line("billing", line.into_bytes()).unwrap()).unwrap();
+    let moved = sealed.replace("\"source\":\"billing\"", "\"source\":\"shipping\"");
+    assert!(shared.open_line(moved.trim()).is_err());
+    assert!(shared.open_line(sealed.trim()).is_ok());
+}
+
+#[tokio::test]
+async fn test_replay_capture() {
+    let server = spawn_test_server().await.unwrap();
+
+    let lines = [
+        json!({
+            "timestamp": "2026-01-01T00:00:00Z",
+            "source": "app",
+            "metrics": [metric("queue_depth", 5.0)]
+        }),
+        json!({
+            "timestamp": "2026-01-01T00:00:00.050Z",
+            "source": "app",
+            "reason": "processing",
+            "error": "boom",
+            "payload": metric("queue_depth", 7.0)
+        }),
+        // Captured bodies are sent as they were received, even if they
+        // didn't parse
+        json!({
+            "timestamp": "2026-01-01T00:00:00.060Z",
+            "source": "app",
+            "version": 1,
+            "content_type": "application/json",
+            "body": json!({"source": "app", "metrics": [metric("jobs_done", 2.0)]}).to_string()
+        }),
+        json!({
+            "timestamp": "2026-01-01T00:00:00.070Z",
+            "version": 1,
+            "content_type": "application/json",
+            "body": "{\"source\": \"app\", \"metrics\": ["
+        }),
+        json!({
+            "timestamp": "2026-01-01T00:00:01Z",
+            "source": "nats",
+            "reason": "serialization",
+            "error": "bad json",
+            "payload": "{not json"
+        }),
+    ];
+    let capture: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+    std::fs::write(&path, capture).unwrap();
+
+    let options = ReplayOptions {
+        target: server.url.clone(),
+        speed: 10.0,
+        keyring: Keyring::default(),
+    };
+    let summary = replay(&path, &options).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        summary,
+        ReplaySummary {
+            batches: 3,
+            metrics: 3,
+            failed: 1,
+            skipped: 1,
+        }
+    );
+    let exposition = server.client().scrape().await.unwrap();
+    assert_metric_value(&exposition, "app_metrics_server_queue_depth", 7.0);
+    assert_metric_value(&exposition, "app_metrics_server_jobs_done", 2.0);
+
+    server.stop().await;
+}
@@ -0,0 +1,33 @@
+#![cfg(target_os = "linux")]
+
+use rustic_insights::service::systemd::{notify, watchdog_interval};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+// The environment is process-wide, so every sd_notify case lives in one test.
+#[test]
+fn test_sd_notify() {
+    let dir = std::env::temp_dir().join(format!("rustic-insights-notify-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notify.sock");
+    let _ = std::fs::remove_file(&path);
+    let receiver = UnixDatagram::bind(&path).unwrap();
+
+    assert!(!notify("READY=1").unwrap());
+    assert_eq!(watchdog_interval(), None);
+
+    unsafe {
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        std::env::set_var("WATCHDOG_USEC", "2000000");
+    }
+    assert!(notify("READY=1").unwrap());
+    let mut buf = [0u8; 64];
+    let len = receiver.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    assert_eq!(watchdog_interval(), Some(Duration::from_secs(1)));
+
+    unsafe { std::env::set_var("WATCHDOG_PID", "1") };
+    assert_eq!(watchdog_interval(), None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
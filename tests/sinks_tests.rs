@@ -0,0 +1,415 @@
+use actix_web::{App, HttpResponse, HttpServer, web};
+use prometheus::IntCounter;
+use rustic_insights::{
+    config::{AppConfig, FanoutConfig, FanoutKind},
+    encryption::Cipher,
+    health::HealthChecks,
+    metrics::{GaugeOperation, Metric, MetricType, MetricValue, MetricsBatch, MetricsRegistry},
+    replay::parse_record,
+    sinks::fanout::{BatchFanout, Delivery},
+    sinks::{EgressSink, GraphiteSink, RemoteWriteSink, Sample, SpillQueue, samples},
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+fn queue_depth() -> Metric {
+    Metric {
+        name: "queue_depth".to_string(),
+        metric_type: MetricType::Gauge,
+        help: "Queue depth".to_string(),
+        labels: HashMap::from([("queue".to_string(), "emails".to_string())]),
+        value: MetricValue {
+            value: 7.0,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
+    }
+}
+
+async fn create_populated_registry() -> MetricsRegistry {
+    let registry = MetricsRegistry::new(AppConfig::default().metrics).unwrap();
+
+    let metric = queue_depth();
+    registry.register_metric(&metric).await.unwrap();
+    registry.update_metric(&metric).await.unwrap();
+
+    registry
+}
+
+#[tokio::test]
+async fn test_histogram_samples() {
+    let registry = MetricsRegistry::new(AppConfig::default().metrics).unwrap();
+    let metric = Metric {
+        name: "latency".to_string(),
+        metric_type: MetricType::Histogram,
+        help: "Latency".to_string(),
+        labels: HashMap::new(),
+        value: MetricValue {
+            value: 0.2,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
+    };
+    registry.register_metric(&metric).await.unwrap();
+    registry.update_metric(&metric).await.unwrap();
+
+    let samples = samples(&registry.gather_families());
+    let count = samples
+        .iter()
+        .find(|s| s.name == "app_metrics_server_latency_count")
+        .unwrap();
+    assert_eq!(count.value, 1.0);
+
+    let inf_bucket = samples
+        .iter()
+        .find(|s| {
+            s.name == "app_metrics_server_latency_bucket"
+                && s.labels.contains(&("le".to_string(), "+Inf".to_string()))
+        })
+        .unwrap();
+    assert_eq!(inf_bucket.value, 1.0);
+}
+
+#[tokio::test]
+async fn test_graphite_sink_export() {
+    let registry = create_populated_registry().await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let receiver = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut payload = String::new();
+        socket.read_to_string(&mut payload).await.unwrap();
+        payload
+    });
+
+    let sink = GraphiteSink::new(&address, Some("gateway"), true);
+    sink.export(&registry.gather_families()).await.unwrap();
+
+    let payload = receiver.await.unwrap();
+    assert!(
+        payload
+            .lines()
+            .any(|line| line.starts_with("gateway.app_metrics_server_queue_depth;queue=emails 7 ")),
+        "{}",
+        payload
+    );
+}
+
+#[test]
+fn test_graphite_line_timestamp() {
+    let sink = GraphiteSink::new("127.0.0.1:2003", None, true);
+    let mut sample = Sample {
+        name: "jobs".to_string(),
+        labels: vec![("queue".to_string(), "emails".to_string())],
+        value: 3.0,
+        timestamp_ms: Some(1_700_000_000_500),
+    };
+    assert_eq!(sink.line(&sample, 42), "jobs;queue=emails 3 1700000000\n");
+
+    sample.timestamp_ms = None;
+    assert_eq!(sink.line(&sample, 42), "jobs;queue=emails 3 42\n");
+}
+
+fn spill_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustic-insights-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[tokio::test]
+async fn test_spill_queue_drops_oldest() {
+    let dir = spill_dir("spill-queue");
+    let spill = SpillQueue::open(&dir, 12).unwrap();
+    spill.push(b"first").await.unwrap();
+    spill.push(b"second").await.unwrap();
+    assert!(spill.push(b"far too large").await.is_err());
+    assert_eq!(spill.len().await, 2);
+
+    // "first" makes room for "third"
+    spill.push(b"third").await.unwrap();
+    let (seq, payload) = spill.peek().await.unwrap().unwrap();
+    assert_eq!(payload, b"second");
+
+    let reopened = SpillQueue::open(&dir, 12).unwrap();
+    assert_eq!(reopened.len().await, 2);
+    assert_eq!(reopened.bytes().await, 11);
+
+    reopened.remove(seq).await.unwrap();
+    assert_eq!(reopened.peek().await.unwrap().unwrap().1, b"third");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_spill_sequence_survives_drain() {
+    let dir = spill_dir("spill-sequence");
+    let spill = SpillQueue::open(&dir, 1024).unwrap();
+    spill.push(b"first").await.unwrap();
+    let (first, _) = spill.peek().await.unwrap().unwrap();
+    spill.remove(first).await.unwrap();
+
+    // Drained, so only the high-water mark keeps the number from coming back
+    let reopened = SpillQueue::open(&dir, 1024).unwrap();
+    assert!(reopened.is_empty().await);
+    reopened.push(b"second").await.unwrap();
+    let (second, _) = reopened.peek().await.unwrap().unwrap();
+    assert!(second > first);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_spill_encryption() {
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_KEY: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let dir = spill_dir("spill-encryption");
+
+    // Spilled before encryption was enabled
+    SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .push(b"revenue_total 1")
+        .await
+        .unwrap();
+    let spill = SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .with_cipher(Cipher::new(KEY).unwrap())
+        .with_plaintext_fallback();
+    spill.push(b"revenue_total 2").await.unwrap();
+
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let stored = std::fs::read(entry.unwrap().path()).unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("revenue_total 2"));
+    }
+    let (seq, payload) = spill.peek().await.unwrap().unwrap();
+    assert_eq!(payload, b"revenue_total 1");
+    spill.remove(seq).await.unwrap();
+    assert_eq!(spill.peek().await.unwrap().unwrap().1, b"revenue_total 2");
+
+    // Unreadable without the key, or with another one: the payload is
+    // quarantined, and moving it back retries it
+    let quarantined = IntCounter::new("quarantined", "Quarantined payloads").unwrap();
+    let restore = || {
+        for entry in std::fs::read_dir(dir.join("quarantine")).unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::rename(&path, dir.join(path.file_name().unwrap())).unwrap();
+        }
+    };
+    let keyless = SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .with_quarantine_counter(quarantined.clone());
+    assert!(keyless.peek().await.unwrap().is_none());
+    restore();
+    let other = SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .with_cipher(Cipher::new(OTHER_KEY).unwrap())
+        .with_quarantine_counter(quarantined.clone());
+    assert!(other.peek().await.unwrap().is_none());
+    assert_eq!(quarantined.get(), 2);
+    restore();
+    assert!(Cipher::new("not hex").is_err());
+    assert!(Cipher::new(&KEY[..32]).is_err());
+
+    // Otherwise payloads in the clear are dropped, as anyone could have
+    // planted them
+    SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .push(b"revenue_total 3")
+        .await
+        .unwrap();
+    let spill = SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .with_cipher(Cipher::new(KEY).unwrap());
+    let (seq, payload) = spill.peek().await.unwrap().unwrap();
+    assert_eq!(payload, b"revenue_total 2");
+    spill.remove(seq).await.unwrap();
+    assert!(spill.peek().await.unwrap().is_none());
+    assert_eq!(
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("spill".as_ref()))
+            .count(),
+        0
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_spill_quarantines_unreadable_payloads() {
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let dir = spill_dir("spill-quarantine");
+    let quarantined = IntCounter::new("quarantined", "Quarantined payloads").unwrap();
+    let spill = SpillQueue::open(&dir, 1024)
+        .unwrap()
+        .with_cipher(Cipher::new(KEY).unwrap())
+        .with_quarantine_counter(quarantined.clone());
+    for payload in ["first", "second", "third", "fourth"] {
+        spill.push(payload.as_bytes()).await.unwrap();
+    }
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+
+    // A corrupted head, then two payloads swapped with each other
+    let mut corrupted = std::fs::read(&paths[0]).unwrap();
+    *corrupted.last_mut().unwrap() ^= 1;
+    std::fs::write(&paths[0], corrupted).unwrap();
+    let (second, third) = (
+        std::fs::read(&paths[1]).unwrap(),
+        std::fs::read(&paths[2]).unwrap(),
+    );
+    std::fs::write(&paths[1], third).unwrap();
+    std::fs::write(&paths[2], second).unwrap();
+
+    // The payloads behind them are still delivered
+    let (seq, payload) = spill.peek().await.unwrap().unwrap();
+    assert_eq!(payload, b"fourth");
+    assert_eq!(quarantined.get(), 3);
+    assert_eq!(
+        std::fs::read_dir(dir.join("quarantine")).unwrap().count(),
+        3
+    );
+    spill.remove(seq).await.unwrap();
+    assert!(spill.is_empty().await);
+    assert!(SpillQueue::open(&dir, 1024).unwrap().is_empty().await);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn test_remote_write_spills_while_down() {
+    let registry = create_populated_registry().await;
+    let dir = spill_dir("remote-write");
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+
+    let sink = RemoteWriteSink::new(&format!("http://{}/api/v1/write", address))
+        .with_spill(SpillQueue::open(&dir, 1024 * 1024).unwrap());
+    assert!(sink.export(&registry.gather_families()).await.is_err());
+    assert!(sink.export(&registry.gather_families()).await.is_err());
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let bodies = received.clone();
+    let server = HttpServer::new(move || {
+        let bodies = bodies.clone();
+        App::new().route(
+            "/api/v1/write",
+            web::post().to(move |body: web::Bytes| {
+                bodies.lock().unwrap().push(body.to_vec());
+                async { HttpResponse::NoContent().finish() }
+            }),
+        )
+    })
+    .bind(address)
+    .unwrap()
+    .workers(1)
+    .disable_signals()
+    .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    sink.export(&registry.gather_families()).await.unwrap();
+    handle.stop(false).await;
+
+    // Both spilled payloads were drained ahead of the current one
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 3);
+    let request = snap::raw::Decoder::new()
+        .decompress_vec(&received[0])
+        .unwrap();
+    let request = String::from_utf8_lossy(&request);
+    assert!(request.contains("__name__"));
+    assert!(request.contains("app_metrics_server_queue_depth"));
+    assert!(SpillQueue::open(&dir, 1024).unwrap().is_empty().await);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_batch_fanout() {
+    let path = std::env::temp_dir().join(format!("fanout-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+
+    let configs = vec![
+        FanoutConfig {
+            kind: FanoutKind::File {
+                path: path.to_string_lossy().into_owned(),
+            },
+            queue_size: 4,
+            max_retries: 0,
+        },
+        FanoutConfig {
+            kind: FanoutKind::RemoteWrite {
+                url: format!("http://{}/api/v1/write", address),
+            },
+            queue_size: 1,
+            max_retries: 0,
+        },
+    ];
+    let metrics = AppConfig::default().metrics;
+    let self_metrics = MetricsRegistry::new(metrics.clone())
+        .unwrap()
+        .self_metrics()
+        .clone();
+    let health = Arc::new(HealthChecks::new());
+    let fanout = BatchFanout::new(
+        &configs,
+        &metrics,
+        health.clone(),
+        self_metrics,
+        Arc::default(),
+    );
+    assert!(!fanout.writes_registry());
+
+    let batch = MetricsBatch {
+        metrics: vec![queue_depth()],
+        source: "worker".to_string(),
+    };
+    let file = format!("file:{}", path.display());
+    let remote = format!("remote_write:http://{}/api/v1/write", address);
+
+    // Nothing is delivered before the tasks run, so the smaller queue fills up
+    let first = fanout.deliver(batch.clone());
+    let second = fanout.deliver(batch);
+    assert_eq!(first[&file], Delivery::Queued);
+    assert_eq!(first[&remote], Delivery::Queued);
+    assert_eq!(second[&file], Delivery::Queued);
+    assert_eq!(second[&remote], Delivery::Dropped);
+    assert!(!health.is_ready());
+
+    fanout.spawn();
+    for _ in 0..100 {
+        let lines = std::fs::read_to_string(&path).unwrap_or_default();
+        if lines.lines().count() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let lines = std::fs::read_to_string(&path).unwrap();
+    let record = parse_record(lines.lines().next().unwrap()).unwrap();
+    assert_eq!(record.batch.source, "worker");
+
+    // The unreachable endpoint fails on its own
+    let components = health.snapshot();
+    assert!(components[&format!("fanout:{}", file)].healthy);
+    assert!(!components[&format!("fanout:{}", remote)].healthy);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,167 @@
+use rustic_insights::config::CaptureConfig;
+use rustic_insights::test_support::{
+    assert_metric_value, load_fixtures, metric_value, spawn_test_server,
+    spawn_test_server_with_config,
+};
+use rustic_insights::{AppConfig, GaugeOperation, Metric, MetricType, MetricValue};
+use std::collections::HashMap;
+
+fn gauge(name: &str, value: f64) -> Metric {
+    Metric {
+        name: name.to_string(),
+        metric_type: MetricType::Gauge,
+        help: "Captured gauge".to_string(),
+        labels: HashMap::new(),
+        value: MetricValue {
+            value,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
+    }
+}
+
+#[tokio::test]
+async fn test_push_and_scrape_through_test_server() {
+    let server = spawn_test_server().await.unwrap();
+    let client = server.client();
+
+    let metric = Metric {
+        name: "jobs_queued".to_string(),
+        metric_type: MetricType::Gauge,
+        help: "Queued jobs".to_string(),
+        labels: HashMap::from([("queue".to_string(), "emails".to_string())]),
+        value: MetricValue {
+            value: 12.0,
+            timestamp: None,
+            enum_value: None,
+            histogram: None,
+        }
+        .into(),
+        operation: GaugeOperation::Set,
+    };
+    let response = client.push_metric("worker", metric).await.unwrap();
+    assert_eq!(response.processed, 1);
+
+    let exposition = client.scrape().await.unwrap();
+    assert_metric_value(
+        &exposition,
+        r#"app_metrics_server_jobs_queued{queue="emails"}"#,
+        12.0,
+    );
+    assert_eq!(
+        metric_value(
+            &exposition,
+            r#"app_metrics_server_jobs_queued{queue="sms"}"#
+        ),
+        None
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_capture_fixtures() {
+    let dir = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let config = AppConfig {
+        capture: Some(CaptureConfig {
+            dir: dir.to_string_lossy().into_owned(),
+            batches_per_source: 2,
+        }),
+        ..AppConfig::default()
+    };
+    let server = spawn_test_server_with_config(config).await.unwrap();
+    let client = server.client();
+
+    for value in [1.0, 2.0, 3.0] {
+        client
+            .push_metric("worker", gauge("jobs_queued", value))
+            .await
+            .unwrap();
+    }
+    client
+        .push_metric("cron/daily", gauge("runs", 1.0))
+        .await
+        .unwrap();
+    // Bodies that don't parse are captured too
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/metrics", server.url))
+        .header("Content-Type", "application/json")
+        .body("{\"source\": \"worker\", \"metrics\": [")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    server.stop().await;
+
+    let mut files: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            "cron%2Fdaily.0001.json",
+            "unparsed",
+            "worker.0001.json",
+            "worker.0002.json"
+        ]
+    );
+    let unparsed = std::fs::read_to_string(dir.join("unparsed").join("0001.json")).unwrap();
+    assert!(unparsed.contains("\\\"metrics\\\": ["));
+
+    let fixtures = load_fixtures(&dir, Some("worker")).unwrap();
+    assert_eq!(fixtures.len(), 2);
+    assert_eq!(fixtures[0].metrics[0].name, "jobs_queued");
+    assert_eq!(load_fixtures(&dir, None).unwrap().len(), 3);
+
+    // The fixtures reproduce the pushes on another server
+    let server = spawn_test_server().await.unwrap();
+    let client = server.client();
+    for batch in &fixtures {
+        client.push(batch).await.unwrap();
+    }
+    let exposition = client.scrape().await.unwrap();
+    assert_metric_value(&exposition, "app_metrics_server_jobs_queued", 2.0);
+
+    server.stop().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_status_reports_listeners_and_build() {
+    let server = spawn_test_server().await.unwrap();
+
+    let status: serde_json::Value = reqwest::get(format!("{}/api/status", server.url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // The ephemeral port the server was bound to, not the configured one
+    let address = server.url.trim_start_matches("http://");
+    assert_eq!(status["listen_addresses"], serde_json::json!([address]));
+    assert_eq!(
+        status["config_profile"],
+        std::env::var("RUN_MODE").unwrap_or_else(|_| "development".into())
+    );
+    assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+    let features = status["build"]["features"].as_array().unwrap();
+    for feature in ["server", "test_support", "tls"] {
+        assert!(features.contains(&serde_json::json!(feature)), "{feature}");
+    }
+    assert_eq!(
+        features.contains(&serde_json::json!("pprof")),
+        cfg!(unix),
+        "pprof"
+    );
+    assert!(!features.contains(&serde_json::json!("kafka")));
+
+    server.stop().await;
+}
@@ -0,0 +1,375 @@
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair,
+    generate_simple_self_signed,
+};
+use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+use rustic_insights::api::auth::{Authenticator, Permission};
+use rustic_insights::config::{AcmeConfig, AuthConfig, Role, TlsConfig};
+use rustic_insights::tls::CertificateReloader;
+use rustic_insights::tls::acme::{AcmeClient, configure_challenge_routes};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn test_certificate_reload() {
+    let dir = std::env::temp_dir().join(format!("tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("tls.crt");
+    let key_path = dir.join("tls.key");
+    let config = TlsConfig {
+        cert_path: cert_path.to_string_lossy().into_owned(),
+        key_path: key_path.to_string_lossy().into_owned(),
+        reload_interval_secs: 1,
+        client_ca_path: None,
+        acme: None,
+    };
+
+    let first = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(&cert_path, first.cert.pem()).unwrap();
+    std::fs::write(&key_path, first.signing_key.serialize_pem()).unwrap();
+    let reloader = CertificateReloader::load(&config).unwrap();
+    assert_eq!(
+        reloader.current().end_entity_cert().unwrap(),
+        first.cert.der()
+    );
+    assert!(!reloader.reload().unwrap());
+
+    // Rotated, as when a Kubernetes secret is updated
+    let second = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(&cert_path, second.cert.pem()).unwrap();
+    std::fs::write(&key_path, second.signing_key.serialize_pem()).unwrap();
+    assert!(reloader.reload().unwrap());
+    assert_eq!(
+        reloader.current().end_entity_cert().unwrap(),
+        second.cert.der()
+    );
+
+    // Caught mid-rotation, with the key of another certificate
+    std::fs::write(&cert_path, first.cert.pem()).unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(
+        reloader.current().end_entity_cert().unwrap(),
+        second.cert.der()
+    );
+
+    std::fs::write(&key_path, "not a key").unwrap();
+    assert!(reloader.reload().is_err());
+    assert!(CertificateReloader::load(&config).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_client_certificates() {
+    let dir = std::env::temp_dir().join(format!("mtls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let server = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(dir.join("tls.crt"), server.cert.pem()).unwrap();
+    std::fs::write(dir.join("tls.key"), server.signing_key.serialize_pem()).unwrap();
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "Clients CA");
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+    std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+
+    let client = |name: &str| {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params
+            .signed_by(&KeyPair::generate().unwrap(), &ca)
+            .unwrap()
+    };
+
+    let mut config = TlsConfig {
+        cert_path: dir.join("tls.crt").to_string_lossy().into_owned(),
+        key_path: dir.join("tls.key").to_string_lossy().into_owned(),
+        reload_interval_secs: 0,
+        client_ca_path: Some(dir.join("ca.crt").to_string_lossy().into_owned()),
+        acme: None,
+    };
+    let reloader = Arc::new(CertificateReloader::load(&config).unwrap());
+    assert!(reloader.server_config().is_ok());
+    config.client_ca_path = Some(dir.join("tls.key").to_string_lossy().into_owned());
+    let reloader = Arc::new(CertificateReloader::load(&config).unwrap());
+    assert!(reloader.server_config().is_err());
+
+    let authenticator = Authenticator::new(&AuthConfig {
+        api_keys: Vec::new(),
+        jwt: None,
+        client_certificates: BTreeMap::from([("prometheus".to_string(), vec![Role::Reader])]),
+        anonymous_roles: Vec::new(),
+    });
+    let identity = authenticator
+        .certificate_identity(client("prometheus").der())
+        .unwrap();
+    assert_eq!(identity.name, "prometheus");
+    assert!(identity.can(Permission::Read));
+    assert!(!identity.can(Permission::Push));
+    assert!(
+        authenticator
+            .certificate_identity(client("stranger").der())
+            .is_none()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A CA issuing one certificate, which checks request signatures and
+/// validates the HTTP-01 challenge against the challenge listener.
+struct MockCa {
+    base: String,
+    challenge_url: String,
+    certificate: String,
+    /// The account key, as the JWK it registered with
+    jwk: Mutex<Option<Value>>,
+    validated: Mutex<bool>,
+    finalized: Mutex<bool>,
+}
+
+impl MockCa {
+    /// Checks the JWS signature, returning the payload.
+    fn verify(&self, body: &[u8]) -> Value {
+        let jws: Value = serde_json::from_slice(body).unwrap();
+        let decode = |field: &str| {
+            URL_SAFE_NO_PAD
+                .decode(jws[field].as_str().unwrap())
+                .unwrap()
+        };
+        let protected: Value = serde_json::from_slice(&decode("protected")).unwrap();
+        let mut jwk = self.jwk.lock().unwrap();
+        match (&protected["kid"], &*jwk) {
+            (Value::String(kid), Some(_)) => assert_eq!(kid, &format!("{}/account/1", self.base)),
+            _ => *jwk = Some(protected["jwk"].clone()),
+        }
+        let jwk = jwk.clone().unwrap();
+        assert!(protected["nonce"].is_string());
+
+        let mut point = vec![4];
+        for coordinate in ["x", "y"] {
+            point.extend(
+                URL_SAFE_NO_PAD
+                    .decode(jwk[coordinate].as_str().unwrap())
+                    .unwrap(),
+            );
+        }
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point)
+            .verify(signed.as_bytes(), &decode("signature"))
+            .expect("invalid JWS signature");
+
+        let payload = decode("payload");
+        if payload.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&payload).unwrap()
+        }
+    }
+
+    fn order(&self) -> Value {
+        let status = if *self.finalized.lock().unwrap() {
+            "valid"
+        } else {
+            "pending"
+        };
+        json!({
+            "status": status,
+            "authorizations": [format!("{}/authz/1", self.base)],
+            "finalize": format!("{}/finalize/1", self.base),
+            "certificate": format!("{}/cert/1", self.base),
+        })
+    }
+}
+
+async fn mock_ca(
+    req: HttpRequest,
+    resource: web::Path<String>,
+    body: web::Bytes,
+    ca: web::Data<Arc<MockCa>>,
+) -> HttpResponse {
+    if resource.as_str() == "directory" {
+        return HttpResponse::Ok().json(json!({
+            "newNonce": format!("{}/nonce", ca.base),
+            "newAccount": format!("{}/account", ca.base),
+            "newOrder": format!("{}/order", ca.base),
+        }));
+    }
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("Replay-Nonce", "nonce"));
+    if req.method() == actix_web::http::Method::HEAD {
+        return response.finish();
+    }
+
+    let payload = ca.verify(&body);
+    match resource.as_str() {
+        "account" => {
+            assert_eq!(payload["termsOfServiceAgreed"], true);
+            response
+                .insert_header(("Location", format!("{}/account/1", ca.base)))
+                .json(json!({ "status": "valid" }))
+        }
+        "order" => {
+            assert_eq!(payload["identifiers"][0]["value"], "metrics.example.com");
+            response
+                .insert_header(("Location", format!("{}/order/1", ca.base)))
+                .json(ca.order())
+        }
+        "authz" => {
+            let status = if *ca.validated.lock().unwrap() {
+                "valid"
+            } else {
+                "pending"
+            };
+            response.json(json!({
+                "status": status,
+                "challenges": [
+                    { "type": "dns-01", "url": format!("{}/chall/2", ca.base), "token": "dns" },
+                    { "type": "http-01", "url": format!("{}/chall/1", ca.base), "token": "token1" },
+                ],
+            }))
+        }
+        "chall" => {
+            let key_authorization = reqwest::get(&ca.challenge_url)
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            assert!(key_authorization.starts_with("token1."));
+            *ca.validated.lock().unwrap() = true;
+            response.json(json!({ "status": "processing" }))
+        }
+        "finalize" => {
+            assert!(payload["csr"].is_string());
+            *ca.finalized.lock().unwrap() = true;
+            response.json(ca.order())
+        }
+        "order_status" => response.json(ca.order()),
+        "cert" => response
+            .content_type("application/pem-certificate-chain")
+            .body(ca.certificate.clone()),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[actix_rt::test]
+async fn test_acme_provisioning() {
+    let dir = std::env::temp_dir().join(format!("acme-{}", std::process::id()));
+    let ca_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}/acme", ca_listener.local_addr().unwrap());
+    let tls = TlsConfig {
+        cert_path: dir
+            .join("certs")
+            .join("tls.crt")
+            .to_string_lossy()
+            .into_owned(),
+        key_path: dir.join("tls.key").to_string_lossy().into_owned(),
+        reload_interval_secs: 0,
+        client_ca_path: None,
+        acme: Some(AcmeConfig {
+            domain: "metrics.example.com".to_string(),
+            contact: vec!["mailto:ops@example.com".to_string()],
+            directory_url: format!("{}/directory", base),
+            account_dir: dir.join("account").to_string_lossy().into_owned(),
+            challenge_port: 0,
+            renew_before_days: 30,
+        }),
+    };
+    let client = AcmeClient::new(&tls, tls.acme.as_ref().unwrap()).unwrap();
+    assert!(client.needs_renewal());
+
+    let challenges = client.challenges();
+    let challenge_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let challenge_url = format!(
+        "http://{}/.well-known/acme-challenge/token1",
+        challenge_listener.local_addr().unwrap()
+    );
+    let challenge_server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(challenges.clone()))
+            .configure(configure_challenge_routes)
+    })
+    .listen(challenge_listener)
+    .unwrap()
+    .workers(1)
+    .disable_signals()
+    .run();
+    let challenge_handle = challenge_server.handle();
+    actix_rt::spawn(challenge_server);
+
+    let issued = generate_simple_self_signed(vec!["metrics.example.com".to_string()]).unwrap();
+    let ca = Arc::new(MockCa {
+        base,
+        challenge_url: challenge_url.clone(),
+        certificate: issued.cert.pem(),
+        jwk: Mutex::new(None),
+        validated: Mutex::new(false),
+        finalized: Mutex::new(false),
+    });
+    let state = ca.clone();
+    let ca_server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            // Orders are polled at the URL they were created under
+            .route(
+                "/acme/order/1",
+                web::post().to(
+                    |req: HttpRequest, body: web::Bytes, ca: web::Data<Arc<MockCa>>| {
+                        mock_ca(req, web::Path::from("order_status".to_string()), body, ca)
+                    },
+                ),
+            )
+            .route("/acme/{resource}", web::route().to(mock_ca))
+            .route(
+                "/acme/{resource}/{id}",
+                web::post().to(
+                    |req: HttpRequest,
+                     path: web::Path<(String, String)>,
+                     body: web::Bytes,
+                     ca: web::Data<Arc<MockCa>>| {
+                        let (resource, _) = path.into_inner();
+                        mock_ca(req, web::Path::from(resource), body, ca)
+                    },
+                ),
+            )
+    })
+    .listen(ca_listener)
+    .unwrap()
+    .workers(1)
+    .disable_signals()
+    .run();
+    let ca_handle = ca_server.handle();
+    actix_rt::spawn(ca_server);
+
+    client.provision().await.unwrap();
+    assert!(*ca.validated.lock().unwrap());
+    assert_eq!(
+        std::fs::read_to_string(&tls.cert_path).unwrap(),
+        issued.cert.pem()
+    );
+    assert!(
+        std::fs::read_to_string(&tls.key_path)
+            .unwrap()
+            .contains("PRIVATE KEY")
+    );
+    assert!(dir.join("account").join("account.der").exists());
+    assert!(!client.needs_renewal());
+
+    // Challenges are only answered while the order is in progress
+    let response = reqwest::get(&challenge_url).await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    ca_handle.stop(false).await;
+    challenge_handle.stop(false).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}